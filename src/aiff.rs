@@ -0,0 +1,136 @@
+//! Reads the PCM and float AIFF files exported by most hardware samplers and older DAWs.
+
+use anyhow::{anyhow, Result};
+use camino::Utf8Path;
+use std::convert::TryInto;
+use std::fs;
+
+/// How the sample data of an AIFF-C file is encoded.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Encoding {
+    BigEndian,
+    LittleEndian,
+    Float,
+}
+
+struct Format {
+    channels: usize,
+    frames: usize,
+    bits: usize,
+    sample_rate: u32,
+    encoding: Encoding,
+}
+
+/// Decodes an AIFF or AIFF-C file, returning its frames and sample rate. Mono files are played
+/// on both sides and channels after the first two are dropped.
+pub fn read(path: &Utf8Path) -> Result<(Vec<(f32, f32)>, u32)> {
+    let bytes = fs::read(path)?;
+    let invalid = || anyhow!("{} is not a valid AIFF file", path);
+    if bytes.len() < 12 || &bytes[0..4] != b"FORM" {
+        return Err(invalid());
+    }
+    let compressed = match &bytes[8..12] {
+        b"AIFF" => false,
+        b"AIFC" => true,
+        _ => return Err(invalid()),
+    };
+
+    let mut format = None;
+    let mut data = None;
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let id = &bytes[pos..pos + 4];
+        let size = be_u32(&bytes[pos + 4..pos + 8]) as usize;
+        // Some writers don't update the size of the last chunk, read what's there
+        let body = &bytes[pos + 8..usize::min(pos + 8 + size, bytes.len())];
+        match id {
+            b"COMM" => format = Some(parse_format(body, compressed).ok_or_else(invalid)?),
+            b"SSND" if body.len() >= 8 => {
+                let offset = be_u32(&body[0..4]) as usize;
+                data = body.get(8 + offset..);
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even size
+        pos += 8 + size + size % 2;
+    }
+    let format = format.ok_or_else(invalid)?;
+    let data = data.unwrap_or(&[]);
+    if format.channels == 0 {
+        return Err(invalid());
+    }
+
+    let size = format.bits.div_ceil(8);
+    let frame_size = size * format.channels;
+    let frames = data
+        .chunks_exact(frame_size)
+        .take(format.frames)
+        .map(|frame| {
+            let left = decode(&frame[..size], &format);
+            let right = match format.channels {
+                1 => left,
+                _ => decode(&frame[size..2 * size], &format),
+            };
+            (left, right)
+        })
+        .collect();
+    Ok((frames, format.sample_rate))
+}
+
+fn parse_format(body: &[u8], compressed: bool) -> Option<Format> {
+    if body.len() < 18 {
+        return None;
+    }
+    let encoding = match (compressed, body.get(18..22)) {
+        (false, _) => Encoding::BigEndian,
+        (true, Some(b"NONE")) | (true, Some(b"twos")) => Encoding::BigEndian,
+        (true, Some(b"sowt")) => Encoding::LittleEndian,
+        (true, Some(b"fl32")) | (true, Some(b"FL32")) => Encoding::Float,
+        _ => return None,
+    };
+    let bits = u16::from_be_bytes([body[6], body[7]]) as usize;
+    let valid = match encoding {
+        Encoding::Float => bits == 32,
+        _ => (1..=32).contains(&bits),
+    };
+    if !valid {
+        return None;
+    }
+    Some(Format {
+        channels: u16::from_be_bytes([body[0], body[1]]) as usize,
+        frames: be_u32(&body[2..6]) as usize,
+        bits,
+        sample_rate: extended(&body[8..18])? as u32,
+        encoding,
+    })
+}
+
+fn decode(sample: &[u8], format: &Format) -> f32 {
+    let mut word = [0; 4];
+    match format.encoding {
+        Encoding::Float => {
+            word.copy_from_slice(sample);
+            return f32::from_be_bytes(word);
+        }
+        // Left aligned, so the sign bit is the top one whatever the size
+        Encoding::BigEndian => word[..sample.len()].copy_from_slice(sample),
+        Encoding::LittleEndian => {
+            for (i, byte) in sample.iter().rev().enumerate() {
+                word[i] = *byte;
+            }
+        }
+    }
+    i32::from_be_bytes(word) as f32 / 2_147_483_648.0
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// The 80-bit extended float AIFF stores its sample rate in.
+fn extended(bytes: &[u8]) -> Option<f64> {
+    let exponent = (u16::from_be_bytes([bytes[0], bytes[1]]) & 0x7fff) as i32;
+    let mantissa = u64::from_be_bytes(bytes[2..10].try_into().ok()?);
+    let value = mantissa as f64 * f64::powi(2.0, exponent - 16383 - 63);
+    (value.is_finite() && value >= 1.0).then_some(value)
+}
@@ -1,25 +1,80 @@
-use crate::engine::{EngineCommand, EngineParam, EngineParams};
+use crate::audio::{realtime, AudioBackend, DeviceEvent};
+use crate::bounce::{self, BounceSettings};
+use crate::capture::{Capture, CaptureWriter};
+use crate::crash::{self, Snapshot};
+use crate::drums;
+use crate::effect::{EffectRegistry, MAX_EFFECTS};
+use crate::engine::{EngineCommand, EngineParam, EngineParams, Garbage, MAX_INSTRUMENTS};
+use crate::euclid::Euclid;
+use crate::harmony::{self, Chord, Harmony, Scale, MAX_CHORD_NOTES};
+use crate::id::{IdGen, InstrumentId, PatternId, TrackId};
 use crate::input;
 use crate::input::{CommandState, Focus, Input, InputQueue};
-use crate::param::Param;
-use crate::pattern::{Editor, Move, MAX_TRACKS};
-use crate::sampler::Sampler;
+use crate::instrument::{Instrument, Options, Registry};
+use crate::keyboard::{self, Keyboard, LiveNote};
+use crate::keymap::{Keymap, RegionEdit};
+use crate::kit::{self, KitFormat, Pad};
+use crate::lfo::{Modulated, ModulationParams, Rate, Route, Shape};
+use crate::library::{Label, Library, Query};
+use crate::midi;
+use crate::mixer::{bus_name, Mixer, Source, MIN_GAIN};
+use crate::mmap;
+use crate::monitor::Reference;
+use crate::osc;
+use crate::param::{Param, ParamDescriptor, ParamKey};
+use crate::paths::Paths;
+use crate::pattern::Step;
+use crate::pattern::{
+    Editor, LengthPolicy, Move, Section, SectionOp, Transform, MAX_EDIT_STEP, MAX_TRACKS, NOTE_CUT,
+    NOTE_OFF,
+};
+use crate::perform::Performance;
+use crate::project::{
+    ChannelConfig, EffectConfig, InstrumentConfig, ModulationConfig, Project, SendConfig,
+};
+use crate::record::{LiveTake, LoopPolicy};
+use crate::rtlog;
+use crate::sampler::{self, MemoryPolicy, Sampler, Sound, SoundEdit, ROOT_PITCH};
+use crate::sampling::Sampling;
+use crate::script::{self, Hook, Hooks};
+use crate::stretch::{self, Key, LoopInfo};
+use crate::transport::BarsBeats;
+use crate::tuner::{self, Reading, Tuner};
 use crate::ui;
 use crate::ui::editor::EditorState;
+use crate::undo::{Edit, History};
 use anyhow::{anyhow, Result};
 use camino::{Utf8Path, Utf8PathBuf};
+use hound::{SampleFormat, WavSpec};
 use ringbuf::{Consumer, Producer};
 use std::fs;
-use std::fs::DirEntry;
 use std::io;
-use std::sync::atomic::Ordering;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 use termion::{input::MouseTerminal, raw::IntoRawMode, screen::AlternateScreen};
 use tui::{backend::TermionBackend, widgets::ListState, Terminal};
 
-pub struct TrackSettings {
-    pub sample_path: Utf8PathBuf,
+pub struct InstrumentSettings {
+    pub id: InstrumentId,
+    pub kind: String,
+    pub options: Options,
     pub params: Vec<(String, Param)>,
+    pub modulation: ModulationParams,
+}
+
+impl InstrumentSettings {
+    pub fn label(&self) -> &str {
+        self.options.get("path").unwrap_or(&self.kind)
+    }
+}
+
+pub struct EffectSettings {
+    pub kind: String,
+    pub options: Options,
+    pub params: Vec<(String, Param)>,
+    pub bypass: Arc<AtomicBool>,
 }
 
 pub struct App {
@@ -29,16 +84,75 @@ pub struct App {
     pub editor: Editor,
 
     pub selected_track: usize,
-    pub instruments: Vec<Option<TrackSettings>>,
+    pub instruments: Vec<Option<InstrumentSettings>>,
+    pub registry: Registry,
+    /// Effect chain of every mixer channel.
+    pub effects: Vec<Vec<EffectSettings>>,
+    pub effect_registry: EffectRegistry,
+    instrument_ids: IdGen,
+    pub history: History,
+    pub capture: Option<CaptureWriter>,
+    /// Meter levels per mixer channel, falling back slowly after peaks.
+    pub meters: Vec<f32>,
+    /// Render load of the engine, as a fraction of its time budget, falling back slowly after
+    /// peaks.
+    pub load: f32,
+    /// Track loaded for comparison with the mix.
+    pub reference: Option<Utf8PathBuf>,
+    /// Snap the notes entered to the scale of the song.
+    pub snap_to_scale: bool,
+    /// Preview loops stretched to the song tempo and shifted to its key.
+    pub stretch_preview: bool,
+    /// Show the selected track as step sequencer lanes, one per pad.
+    pub drum_lanes: bool,
+    /// Mutes recorded during a live take, see `Action::TogglePerformance`.
+    pub performance: Option<Performance>,
+    /// Plays the selected instrument from the computer keyboard while it has the focus.
+    pub keyboard: Keyboard,
+    /// Set while notes played live are recorded, with what happens to the notes held when the
+    /// pattern loops.
+    pub record_arm: Option<LoopPolicy>,
+    /// Notes played since the song started playing with recording armed.
+    pub live_take: Option<LiveTake>,
+    /// How far recorded notes are moved onto the lines, from 0 to 1.
+    pub quantize: f32,
+    /// Lines the cursor moves down after a step is entered or deleted, 0 to stay.
+    pub edit_step: usize,
+    /// Keys pressed and OSC messages received, in order.
+    input: InputQueue,
+    /// Remote control, while listening for OSC messages.
+    pub osc: Option<osc::Server>,
+    /// Commands run when the transport starts, stops or loops.
+    hooks: Hooks,
+    /// Scripts being run, the last one innermost, so a script can't run itself.
+    scripts: Vec<Utf8PathBuf>,
+    /// Analyzes a hardware input while the tuner is on.
+    tuner: Option<Tuner>,
+    /// Last pitch found by the tuner, in the input or in a sound.
+    pub tuning: Option<Reading>,
+    /// Recording from an input into the selected slot, and the source its channel had before
+    /// being switched to the input for monitoring.
+    pub sampling: Option<Sampling>,
+    sampling_source: Source,
+    /// Output device which stopped, while the backend tries to reconnect it.
+    pub lost_device: Option<String>,
 
+    pub project_path: Option<Utf8PathBuf>,
+    pub paths: Paths,
     pub file_browser: FileBrowser,
+    /// Tags, ratings and labels of the sounds the browser starts in.
+    pub library: Library,
     pub current_line: usize,
+    /// Lines played since the start of the song, tracks with their own length play another
+    /// line than the pattern.
+    pub current_tick: usize,
     pub should_stop: bool,
     pub engine_params: EngineParams,
 
     pub focus: Focus,
     pub files: ListState,
     pub instrument_list: ListState,
+    /// Row selected in the params pane, in the params of the selected instrument.
     pub params: ListState,
     pub edit_state: EditorState,
     pub command: CommandState,
@@ -49,10 +163,13 @@ impl App {
         params: EngineParams,
         cons: Consumer<AppCommand>,
         prod: Producer<EngineCommand>,
+        paths: Paths,
     ) -> Result<Self> {
-        let file_browser = FileBrowser::with_path("./sounds")?;
-        let mut instruments = Vec::with_capacity(MAX_TRACKS);
-        for _ in 0..MAX_TRACKS {
+        // The file browser starts in the sound library.
+        let file_browser = FileBrowser::with_path(&paths.sounds)?;
+        let library = Library::open(&paths.sounds)?;
+        let mut instruments = Vec::with_capacity(MAX_INSTRUMENTS);
+        for _ in 0..MAX_INSTRUMENTS {
             instruments.push(None);
         }
 
@@ -65,10 +182,43 @@ impl App {
             editor: Editor::new(),
             selected_track: 0,
             current_line: 0,
+            current_tick: 0,
             instruments,
+            registry: Registry::new(Arc::clone(&params.sample_rate)),
+            effects: (0..params.mixer.channels.len())
+                .map(|_| Vec::new())
+                .collect(),
+            effect_registry: EffectRegistry::new(Arc::clone(&params.sample_rate)),
+            instrument_ids: IdGen::default(),
+            history: History::default(),
+            capture: None,
+            meters: vec![0.0; params.mixer.channels.len()],
+            load: 0.0,
+            reference: None,
+            snap_to_scale: false,
+            stretch_preview: false,
+            drum_lanes: false,
+            performance: None,
+            keyboard: Keyboard::new(),
+            record_arm: None,
+            live_take: None,
+            quantize: 1.0,
+            edit_step: 1,
+            input: InputQueue::new(),
+            osc: None,
+            hooks: Hooks::default(),
+            scripts: Vec::new(),
+            tuner: None,
+            tuning: None,
+            sampling: None,
+            sampling_source: Source::default(),
+            lost_device: None,
             should_stop: false,
             engine_params: params,
+            project_path: None,
+            paths,
             file_browser,
+            library,
             params: ListState::default(),
             instrument_list: ListState::default(),
             files: file_state,
@@ -80,111 +230,1556 @@ impl App {
         })
     }
 
+    /// Whether the tuner is listening to an input.
+    pub fn is_tuning(&self) -> bool {
+        self.tuner.is_some()
+    }
+
     pub fn run_commands(&mut self) {
         while let Some(update) = self.cons.pop() {
             match update {
                 AppCommand::SetCurrentTick(tick) => {
                     let pattern = self.editor.current_pattern();
                     self.current_line = tick % pattern.num_lines;
+                    self.current_tick = tick;
+                    if let Some(performance) = &mut self.performance {
+                        performance.set_tick(tick);
+                    }
+                    self.follow_take(tick);
                 }
+                AppCommand::Dispose(_) => {}
+            }
+        }
+        for (message, count) in rtlog::drain() {
+            match count {
+                1 => self.history.note(format!("audio: {}", message)),
+                _ => self
+                    .history
+                    .note(format!("audio: {} ({} times)", message, count)),
             }
         }
+        if let Some(reading) = self.tuner.as_mut().and_then(|tuner| tuner.update()) {
+            self.tuning = reading;
+        }
+        if let Some(sampling) = &mut self.sampling {
+            sampling.update();
+        }
     }
 
-    pub fn run(mut self) -> Result<()> {
-        let mut input = InputQueue::new();
+    pub fn run(mut self, audio: &mut dyn AudioBackend) -> Result<()> {
         let stdout = io::stdout().into_raw_mode()?;
         let stdout = MouseTerminal::from(stdout);
         let stdout = AlternateScreen::from(stdout);
         let backend = TermionBackend::new(stdout);
         let mut terminal = Terminal::new(backend)?;
+        self.input.listen();
+        self.update_crash_snapshot();
 
         loop {
+            if let Some(event) = audio.poll() {
+                self.device_event(event);
+                self.update_crash_snapshot();
+            }
             self.run_commands();
+            let released = self.keyboard.release_expired(Instant::now());
+            self.play_live(released)?;
+            let is_playing = self.engine_params.transport.is_playing();
+            if self.live_take.is_some() && !is_playing {
+                self.finish_take()?;
+            }
+            self.run_hooks(is_playing);
+            if self.sampling.as_ref().is_some_and(Sampling::is_done) {
+                self.take(Action::RecordSample(None))?;
+            }
             if self.should_stop {
                 return Ok(());
             }
+            self.update_meters();
             terminal.draw(|f| ui::draw(f, &mut self))?;
-            match input.next()? {
+            match self.input.next()? {
                 // TODO: don't exit on error from handle_input but print to console
-                Input::Key(key) => input::handle(key, &mut self)?,
+                Input::Key(key) => {
+                    input::handle(key, &mut self)?;
+                    self.update_crash_snapshot();
+                }
+                Input::Osc(message) => {
+                    self.remote(message);
+                    self.update_crash_snapshot();
+                }
                 Input::Tick => {}
             }
         }
     }
 
+    /// Keeps what a crash report says about the session current, after anything that may have
+    /// changed it.
+    fn update_crash_snapshot(&self) {
+        let sample_rate = self.engine_params.sample_rate.load(Ordering::Relaxed);
+        let is_playing = self.engine_params.transport.is_playing();
+        let project = format!(
+            "{}\nengine at {} Hz, {}",
+            self.project().summary(),
+            sample_rate,
+            if is_playing { "playing" } else { "stopped" }
+        );
+        crash::update(Snapshot {
+            project,
+            log: self.history.recent(crash::LOG_ENTRIES),
+        });
+    }
+
+    /// Runs the commands hooked to what the transport did since the last call. Errors are
+    /// logged, the hook stays.
+    fn run_hooks(&mut self, is_playing: bool) {
+        let commands = self
+            .hooks
+            .update(is_playing, self.current_tick, self.editor.num_lines());
+        for command in commands {
+            let result = input::parse_command(self, &command).and_then(|action| self.take(action));
+            if let Err(err) = result {
+                self.history.note(format!("hook: {}: {}", command, err));
+            }
+        }
+    }
+
+    /// Runs the action of an OSC message. Errors are logged rather than returned, a
+    /// misconfigured controller mustn't end the session.
+    fn remote(&mut self, message: Result<osc::Message>) {
+        let message = match message {
+            Ok(message) => message,
+            Err(err) => return self.history.note(format!("osc: {}", err)),
+        };
+        let result = match osc::action(&message, self) {
+            Ok(Some(action)) => self.take(action),
+            Ok(None) => Ok(()),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            self.history.note(format!("osc: {}: {}", message, err));
+        }
+    }
+
+    fn device_event(&mut self, event: DeviceEvent) {
+        match event {
+            DeviceEvent::Lost(name) => {
+                self.history.note(format!("lost audio device {}", name));
+                self.lost_device = Some(name);
+            }
+            DeviceEvent::Reconnected(name) => {
+                self.history.note(format!("audio device {}", name));
+                self.lost_device = None;
+            }
+            DeviceEvent::NoRealtime(reason) => {
+                self.history
+                    .note(format!("audio thread isn't realtime: {}", reason));
+            }
+        }
+    }
+
+    fn update_meters(&mut self) {
+        const FALLOFF: f32 = 0.8;
+        for (meter, channel) in self
+            .meters
+            .iter_mut()
+            .zip(&self.engine_params.mixer.channels)
+        {
+            *meter = f32::max(channel.take_peak(), *meter * FALLOFF);
+        }
+        let load = self.engine_params.telemetry.take_load();
+        self.load = f32::max(load, self.load * FALLOFF);
+    }
+
     pub fn take(&mut self, action: Action) -> Result<()> {
         match action {
             Action::Exit => {
+                if self.capture.is_some() {
+                    self.take(Action::Capture(None))?;
+                }
                 self.should_stop = true;
             }
             Action::LoadSound(i, path) => {
-                let sound = Sampler::load_sound(&path)?;
-                self.instruments[i] = Some(TrackSettings {
-                    sample_path: path,
-                    params: Vec::new(),
+                let mut options = Options::default();
+                options.set("path", path.as_str());
+                self.take(Action::CreateInstrument(
+                    i,
+                    String::from("sampler"),
+                    options,
+                ))?;
+            }
+            Action::CreateInstrument(i, kind, options) => {
+                let modulation = ModulationParams::default();
+                let instrument = self.registry.create(&kind, &options)?;
+                let instrument: Box<dyn Instrument> =
+                    Box::new(Modulated::new(instrument, modulation.clone()));
+                self.instruments[i] = Some(InstrumentSettings {
+                    id: InstrumentId(self.instrument_ids.next()),
+                    kind,
+                    options,
+                    params: instrument.params(),
+                    modulation,
                 });
-                self.engine_send(EngineCommand::LoadSound(i, Arc::new(sound)))?;
+                self.engine_send(EngineCommand::SetInstrument(i, Some(instrument)))?;
+                self.engine_params.mixer.channels[i].set_trim(0.0);
+                if let Some(settings) = &self.instruments[i] {
+                    let options: Vec<String> = settings
+                        .options
+                        .iter()
+                        .map(|(k, v)| format!("{}={}", k, v))
+                        .collect();
+                    let message = format!("load {} {} {}", i, settings.kind, options.join(" "));
+                    self.history.note(message.trim_end());
+                }
+            }
+            Action::SetInstrumentOption(i, key, value) => {
+                let settings = self.instruments[i]
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("no instrument on track {}", i))?;
+                let (id, kind) = (settings.id, settings.kind.clone());
+                let mut options = settings.options.clone();
+                // A new sound or a new way to find its root replaces the root played with.
+                let new_root = matches!(key.as_str(), "path" | "root");
+                options.set(key, value);
+                let mut params = param_values(&settings.params);
+                if new_root {
+                    params.retain(|(name, _)| name != "Root");
+                }
+                let modulation = modulation_config(settings);
+                let trim = &self.engine_params.mixer.channels[i].trim;
+                let trim = trim.load(Ordering::Relaxed);
+                self.take(Action::CreateInstrument(i, kind, options))?;
+                if let Some(settings) = &mut self.instruments[i] {
+                    settings.id = id;
+                    set_param_values(&mut settings.params, &params)?;
+                    set_modulation(settings, &modulation)?;
+                }
+                self.engine_params.mixer.channels[i].set_trim(trim);
+            }
+            Action::RemoveInstrument(i) => {
+                self.instruments[i] = None;
+                self.engine_params.mixer.channels[i].set_trim(0.0);
+                self.engine_send(EngineCommand::SetInstrument(i, None))?;
+                self.history.note(format!("remove {}", i));
+            }
+            Action::SetLfo(i, lfo, shape, rate) => {
+                let settings = self.instruments[i]
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("no instrument on track {}", i))?;
+                let params = settings
+                    .modulation
+                    .lfos
+                    .get(lfo)
+                    .ok_or_else(|| anyhow!("invalid LFO {}", lfo + 1))?;
+                params.set(shape, rate);
+                let message = format!("lfo {} {} {} {}", i, lfo + 1, shape.name(), rate.name());
+                self.history.note(message);
+            }
+            Action::SetRoute(i, lfo, name, depth) => {
+                let settings = self.instruments[i]
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("no instrument on track {}", i))?;
+                let target = settings
+                    .params
+                    .iter()
+                    .position(|(n, _)| n.eq_ignore_ascii_case(&name))
+                    .ok_or_else(|| anyhow!("no param {} on track {}", name, i))?;
+                match depth {
+                    Some(depth) => settings
+                        .modulation
+                        .set_route(Route { lfo, target, depth })?,
+                    None => settings.modulation.remove_route(lfo, target),
+                }
+                let depth = depth.map_or(String::from("off"), |depth| depth.to_string());
+                self.history
+                    .note(format!("mod {} {} {} {}", i, lfo + 1, name, depth));
+            }
+            Action::SetModulationRate(i, per_sample) => {
+                let settings = self.instruments[i]
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("no instrument on track {}", i))?;
+                settings
+                    .modulation
+                    .per_sample
+                    .store(per_sample, Ordering::Relaxed);
             }
             Action::PreviewSound(path) => {
-                let sound = Sampler::load_sound(&path)?;
+                let sound = match self.stretch_preview {
+                    true => self.stretched_sound(&path)?,
+                    false => Sampler::load_sound(&path)?,
+                };
                 self.engine_send(EngineCommand::PreviewSound(Arc::new(sound)))?;
             }
+            Action::SetKey(key) => {
+                self.editor.harmony.key = key;
+                self.engine_send(EngineCommand::LoadEditor(Box::new(self.editor.clone())))?;
+                let name = key.map_or(String::from("none"), |key| key.name());
+                self.history.note(format!("key {}", name));
+            }
+            Action::SetScale(scale) => {
+                self.editor.harmony.scale = scale;
+                self.engine_send(EngineCommand::LoadEditor(Box::new(self.editor.clone())))?;
+                let name = scale.map_or("key", |scale| scale.name());
+                self.history.note(format!("scale {}", name));
+            }
+            Action::SetVoicing(index, voicing) => {
+                if voicing.len() > MAX_CHORD_NOTES {
+                    return Err(anyhow!("a voicing has at most {} notes", MAX_CHORD_NOTES));
+                }
+                let semitones: Vec<String> = voicing.iter().map(|s| s.to_string()).collect();
+                self.history
+                    .note(format!("voicing {} {}", index + 1, semitones.join(" ")));
+                self.editor.harmony.voicings[index] = voicing;
+                self.engine_send(EngineCommand::LoadEditor(Box::new(self.editor.clone())))?;
+            }
+            Action::SetChord(chord) => {
+                self.edit_step(|editor| editor.set_chord(chord));
+                self.send_cursor_step()?;
+            }
+            Action::SetOffset(offset) => {
+                self.edit_step(|editor| editor.set_offset(offset));
+                self.send_cursor_step()?;
+            }
+            Action::ToggleScaleSnap => self.snap_to_scale = !self.snap_to_scale,
+            Action::ToggleStretchPreview => self.stretch_preview = !self.stretch_preview,
+            Action::ToggleDrumLanes => self.drum_lanes = !self.drum_lanes,
+            Action::RecordLive(policy) => {
+                self.record_arm = policy;
+                match policy {
+                    Some(_) => self.history.note("record armed"),
+                    None => {
+                        self.finish_take()?;
+                        self.history.note("record off");
+                    }
+                }
+            }
+            Action::SetQuantize(strength) => {
+                self.quantize = strength.clamp(0.0, 1.0);
+                self.history.note(format!("quantize {}", strength));
+            }
+            Action::Osc(port) => {
+                if let Some(mut server) = self.osc.take() {
+                    for command in server.release_all() {
+                        self.engine_send(command)?;
+                    }
+                }
+                match port {
+                    Some(port) => {
+                        self.osc = Some(osc::Server::start(port, self.input.sender())?);
+                        self.history.note(format!("osc {}", port));
+                    }
+                    None => self.history.note("osc off"),
+                }
+            }
+            Action::RemoteNote(track, pitch, velocity) => {
+                let server = self.osc.as_mut().ok_or_else(|| anyhow!("OSC is off"))?;
+                for command in server.note(track, pitch, velocity) {
+                    self.engine_send(command)?;
+                }
+            }
+            Action::RunScript(path) => {
+                if self.scripts.contains(&path) {
+                    return Err(anyhow!("script {} runs itself", path));
+                }
+                let commands = script::load(&path)?;
+                self.history.note(format!("run {}", path));
+                self.scripts.push(path.clone());
+                let result = commands.iter().try_for_each(|(line, command)| {
+                    input::parse_command(self, command)
+                        .and_then(|action| self.take(action))
+                        .map_err(|err| anyhow!("{} line {}: {}", path, line, err))
+                });
+                self.scripts.pop();
+                result?;
+            }
+            Action::AddHook(hook, command) => {
+                self.history.note(format!("on {} {}", hook, command));
+                self.hooks.add(hook, command);
+            }
+            Action::ClearHook(hook) => {
+                self.hooks.clear(hook);
+                self.history.note(format!("on {} off", hook));
+            }
+            Action::SetEditStep(lines) => {
+                self.edit_step = usize::min(lines, MAX_EDIT_STEP);
+                self.history.note(format!("step {}", self.edit_step));
+            }
+            Action::TogglePerformance => match self.performance.take() {
+                Some(performance) => {
+                    if performance.num_lines() == 0 {
+                        return Err(anyhow!("nothing was played during the take"));
+                    }
+                    let tracks = performance.arrange(self.editor.current_pattern());
+                    self.editor.add_pattern(tracks)?;
+                    self.engine_send(EngineCommand::LoadEditor(Box::new(self.editor.clone())))?;
+                    let index = self.editor.edit_index();
+                    self.history.note(format!("perform {}", index));
+                }
+                None => {
+                    let channels = &self.engine_params.mixer.channels[..MAX_TRACKS];
+                    let muted = channels
+                        .iter()
+                        .map(|channel| channel.mute.load(Ordering::Relaxed))
+                        .collect();
+                    self.performance = Some(Performance::start(muted));
+                }
+            },
+            Action::SetTuner(input) => {
+                let (tuner, tap) = match input {
+                    Some(input) => {
+                        let (tuner, tap) = Tuner::new(input);
+                        (Some(tuner), Some(Box::new(tap)))
+                    }
+                    None => (None, None),
+                };
+                self.engine_send(EngineCommand::SetTuner(tap))?;
+                self.tuner = tuner;
+                self.tuning = None;
+            }
+            Action::RecordSample(Some((input, punch))) => {
+                if self.sampling.is_some() {
+                    return Err(anyhow!("already recording a sample"));
+                }
+                let slot = self.selected_track;
+                let (sampling, tap) = Sampling::new(input, slot, punch);
+                self.engine_send(EngineCommand::SetSampling(Some(Box::new(tap))))?;
+                let channel = &self.engine_params.mixer.channels[slot];
+                self.sampling_source = channel.source();
+                channel.set_source(Source::Input(input));
+                self.sampling = Some(sampling);
+            }
+            Action::RecordSample(None) => {
+                let sampling = match self.sampling.take() {
+                    Some(sampling) => sampling,
+                    None => return Err(anyhow!("not recording a sample")),
+                };
+                self.engine_send(EngineCommand::SetSampling(None))?;
+                let slot = sampling.slot;
+                self.engine_params.mixer.channels[slot].set_source(self.sampling_source);
+                let sound = sampling
+                    .finish()
+                    .ok_or_else(|| anyhow!("nothing was recorded"))?;
+                let path = free_path(&self.paths.recordings(), "take")?;
+                write_sound(&path, &sound)?;
+                self.take(Action::LoadSound(slot, path))?;
+            }
+            Action::EditSound(edit) => {
+                let i = self.selected_track;
+                let path = match self.instruments[i]
+                    .as_ref()
+                    .filter(|settings| settings.kind == "sampler")
+                    .and_then(|settings| settings.options.get("path").ok())
+                {
+                    Some(path) => Utf8PathBuf::from(path),
+                    None => return Err(anyhow!("no sampler on track {}", i)),
+                };
+                let sound = Sampler::load_sound(&path)?.edit(edit);
+                if sound.num_frames() < sampler::MIN_FRAMES {
+                    return Err(anyhow!("nothing left of the sound after {}", edit));
+                }
+                // The original is kept, the edit is a new sound in the library.
+                let edited = free_path(&self.paths.edits(), path.file_stem().unwrap_or("sound"))?;
+                write_sound(&edited, &sound)?;
+                self.take(Action::SetInstrumentOption(
+                    i,
+                    "path".into(),
+                    edited.to_string(),
+                ))?;
+                self.history.note(format!("edit {} {}", i, edit));
+            }
+            Action::EditRegions(edit) => {
+                let i = self.selected_track;
+                let regions = match self.instruments[i]
+                    .as_ref()
+                    .filter(|settings| settings.kind == "sampler")
+                {
+                    Some(settings) => settings.options.get_or("regions", "").to_string(),
+                    None => return Err(anyhow!("no sampler on track {}", i)),
+                };
+                let mut keymap = Keymap::parse(&regions)?;
+                keymap.apply(edit)?;
+                self.take(Action::SetInstrumentOption(
+                    i,
+                    "regions".into(),
+                    keymap.to_string(),
+                ))?;
+                for event in keymap.take_events() {
+                    self.history.note(format!("region {} {}", i, event));
+                }
+            }
+            Action::TuneSound(path) => {
+                let sound = Sampler::load_sound(&path)?;
+                self.tuning = tuner::detect_sound(&sound);
+            }
+            Action::SelectPattern(index) => {
+                self.editor.select_pattern(index)?;
+                self.engine_send(EngineCommand::LoadEditor(Box::new(self.editor.clone())))?;
+                self.history.note(format!("pattern {}", index));
+            }
+            Action::ToggleHit(pad) => {
+                let pattern = self.editor.current_pattern();
+                let num_lines = pattern.num_lines;
+                let mut lanes = pattern.drum_lanes(self.selected_track);
+                lanes.toggle(pad, self.editor.cursor.line, ROOT_PITCH, num_lines);
+                let steps = lanes.to_steps(num_lines)?;
+
+                let pattern = pattern.id;
+                let track = self.editor.track_ids()[self.selected_track];
+                for (line, after) in steps.into_iter().enumerate() {
+                    let before = self.editor.step(self.selected_track, line);
+                    if before != after {
+                        self.set_step(pattern, track, line, after)?;
+                        self.history.push(Edit::SetStep {
+                            pattern,
+                            track,
+                            line,
+                            before,
+                            after,
+                        });
+                    }
+                }
+            }
+            Action::Tag(path, tags) => self.library.update(&path, |entry| {
+                entry.tags.extend(tags);
+            })?,
+            Action::Untag(path, tags) => self.library.update(&path, |entry| {
+                entry.tags.retain(|tag| !tags.contains(tag));
+            })?,
+            Action::Rate(path, rating) => {
+                self.library.update(&path, |entry| entry.rating = rating)?
+            }
+            Action::SetLabel(path, label) => {
+                self.library.update(&path, |entry| entry.label = label)?
+            }
+            Action::FindSounds(query) => {
+                let sounds = self.library.search(&query)?;
+                let title = format!("find {}", query);
+                self.file_browser.show_results(title, sounds);
+                self.files.select(Some(0));
+            }
+            Action::FindDuplicates => {
+                let sounds = self.library.duplicates()?.into_iter();
+                let sounds = sounds.flat_map(|duplicates| duplicates.sounds).collect();
+                let title = String::from("duplicates");
+                self.file_browser.show_results(title, sounds);
+                self.files.select(Some(0));
+            }
+            Action::ConsolidateDuplicates => self.consolidate_duplicates()?,
             Action::InsertNote(pitch) => {
                 let oct = self.engine_params.get(EngineParam::Octave) as u8;
-                let pitch = oct * 12 + pitch;
-                self.editor.set_pitch(pitch);
+                let mut pitch = u8::min(oct * 12 + pitch, 127);
+                if self.snap_to_scale {
+                    pitch = self.editor.harmony.snap(pitch);
+                }
+                self.edit_step(|editor| editor.set_pitch(pitch));
                 self.engine_send(EngineCommand::InputNote(self.editor.cursor, pitch))?;
-                self.take(Action::MoveCursor(Move::Down))?;
+                self.take(Action::Advance)?;
+            }
+            Action::InsertNoteOff | Action::InsertNoteCut => {
+                let pitch = match action {
+                    Action::InsertNoteCut => NOTE_CUT,
+                    _ => NOTE_OFF,
+                };
+                self.edit_step(|editor| editor.set_pitch(pitch));
+                self.send_cursor_step()?;
+                self.take(Action::Advance)?;
+            }
+            Action::PlayKey(key) => {
+                let semitone = match keyboard::semitone(key.to_ascii_lowercase()) {
+                    Some(semitone) => semitone,
+                    None => return Ok(()),
+                };
+                let oct = self.engine_params.get(EngineParam::Octave) as u8;
+                let mut pitch = u8::min(oct * 12 + semitone, 127);
+                if self.snap_to_scale {
+                    pitch = self.editor.harmony.snap(pitch);
+                }
+                let notes = self.keyboard.press(key, pitch, Instant::now());
+                self.play_live(notes)?;
+            }
+            Action::ReleaseKeys => {
+                let notes = self.keyboard.release_all();
+                self.play_live(notes)?;
             }
             Action::InsertNumber(num) => {
-                self.editor.set_number(num);
+                self.edit_step(|editor| editor.set_number(num));
                 self.engine_send(EngineCommand::InputNumber(self.editor.cursor, num))?;
             }
             Action::ChangeValue(delta) => {
-                self.editor.change_value(delta);
+                self.edit_step(|editor| editor.change_value(delta));
                 self.engine_send(EngineCommand::ChangeValue(self.editor.cursor, delta))?;
             }
             Action::DeleteNote => {
-                self.editor.delete_value();
+                self.edit_step(|editor| editor.delete_value());
                 self.engine_send(EngineCommand::DeleteValue(self.editor.cursor))?;
             }
             Action::TogglePlay => {
-                let val = self.engine_params.is_playing.load(Ordering::Relaxed);
-                self.engine_params.is_playing.store(!val, Ordering::Relaxed);
+                let action = match self.engine_params.transport.is_playing() {
+                    true => Action::Pause,
+                    false => Action::Play,
+                };
+                self.take(action)?;
+            }
+            Action::Play => {
+                self.engine_params.mixer.clear_over();
+                self.engine_params.transport.play();
+            }
+            Action::Pause => self.engine_params.transport.pause(),
+            Action::Stop => self.engine_params.transport.stop(),
+            Action::Seek(position) => {
+                let transport = &self.engine_params.transport;
+                transport.seek(transport.to_tick(position));
+            }
+            Action::SetLoop(bars) => {
+                let transport = &self.engine_params.transport;
+                let bar = |bar| {
+                    transport.to_tick(BarsBeats {
+                        bar,
+                        beat: 0,
+                        line: 0,
+                    })
+                };
+                transport.set_loop_region(bars.map(|bars| bar(bars.start)..bar(bars.end)));
             }
             Action::IncrParam(param_index) => {
-                let track = self.selected_track;
-                if let Some(track) = &mut self.instruments[track] {
-                    if let Some((_, param)) = track.params.get_mut(param_index) {
-                        param.incr();
-                    }
+                if let Some(key) = self.nth_param_key(self.selected_track, param_index) {
+                    self.edit_param(&key, |param| param.incr())
                 }
             }
             Action::DecrParam(param_index) => {
-                let track = self.selected_track;
-                if let Some(track) = &mut self.instruments[track] {
-                    if let Some((_, param)) = track.params.get_mut(param_index) {
-                        param.decr();
-                    }
+                if let Some(key) = self.nth_param_key(self.selected_track, param_index) {
+                    self.edit_param(&key, |param| param.decr())
+                }
+            }
+            Action::SetParam(i, name, value) => {
+                let key = self.param_key(i, &name)?;
+                self.edit_param(&key, |param| {
+                    let (min, max) = param.range();
+                    let _ = param.set(value.clamp(min, max));
+                });
+            }
+            Action::SetParamNormalized(i, name, value) => {
+                let key = self.param_key(i, &name)?;
+                self.edit_param(&key, |param| param.set_normalized(value));
+            }
+            Action::DescribeParam(i, name) => {
+                let key = self.param_key(i, &name)?;
+                if let Some(descriptor) = self.describe_param(&key) {
+                    self.history.note(descriptor.to_string());
                 }
             }
             Action::UpdateEngineParam(param, value) => {
+                let name = match param {
+                    EngineParam::Bpm => "bpm",
+                    EngineParam::LinesPerBeat => "lpb",
+                    EngineParam::Octave => "octave",
+                };
                 let param = match param {
                     EngineParam::Bpm => &self.engine_params.bpm,
                     EngineParam::LinesPerBeat => &self.engine_params.lines_per_beat,
                     EngineParam::Octave => &self.engine_params.octave,
                 };
                 param.store(value.parse()?, Ordering::Relaxed);
+                self.history.note(format!("{} {}", name, value));
             }
             Action::MoveCursor(cursor_move) => {
                 self.editor.move_cursor(cursor_move);
                 self.selected_track = self.editor.selected_track();
             }
+            Action::SelectTrack(track) => {
+                self.editor.select_track(track);
+                self.selected_track = self.editor.selected_track();
+            }
+            Action::ListInstruments(None) => {
+                let names: Vec<&str> = self.registry.names().collect();
+                self.history
+                    .note(format!("instruments: {}", names.join(", ")));
+            }
+            Action::ListInstruments(Some(kind)) => {
+                let factory = self
+                    .registry
+                    .get(&kind)
+                    .ok_or_else(|| anyhow!("unknown instrument type {}", kind))?;
+                let params = factory.params();
+                if params.is_empty() {
+                    self.history.note(format!("{}: no params listed", kind));
+                }
+                for param in params {
+                    self.history.note(format!("{}: {}", kind, param));
+                }
+            }
+            Action::Advance => self.editor.advance(self.edit_step),
+            Action::Bounce(path, settings) => {
+                let instruments = self.offline_instruments()?;
+                let mixer = self.offline_mixer()?;
+                let bpm = self.engine_params.get(EngineParam::Bpm);
+                let lines_per_beat = self.engine_params.get(EngineParam::LinesPerBeat);
+                bounce::bounce(
+                    &self.editor,
+                    instruments,
+                    mixer,
+                    bpm,
+                    lines_per_beat,
+                    &settings,
+                    &path,
+                )?;
+            }
+            Action::ExportKit(path, format) => {
+                let pads = self.kit_pads()?;
+                kit::export(&pads, &path, format)?;
+                self.history.note(format!("export kit {}", path));
+            }
+            Action::MoveTrack(to) => {
+                let from = self.selected_track;
+                self.apply(&Edit::MoveTrack { from, to }, false)?;
+                self.history.push(Edit::MoveTrack { from, to });
+            }
+            Action::Rearrange(op) => {
+                let pattern = self.editor.current_pattern().id;
+                let before = self.editor.rearrange(pattern, op)?;
+                self.history.push(Edit::Rearrange {
+                    pattern,
+                    op,
+                    before: Box::new(before),
+                });
+                self.engine_send(EngineCommand::LoadEditor(Box::new(self.editor.clone())))?;
+            }
+            Action::SetTrackLength(length) => {
+                let pattern = self.editor.current_pattern().id;
+                let track = self.editor.track_ids()[self.selected_track];
+                let before = self.editor.set_track_length(pattern, track, length)?;
+                self.history.push(Edit::TrackLength {
+                    pattern,
+                    track,
+                    before,
+                    after: length,
+                });
+                self.engine_send(EngineCommand::LoadEditor(Box::new(self.editor.clone())))?;
+            }
+            Action::Transform(section, transform) => {
+                let pattern = self.editor.current_pattern().id;
+                let track = self.editor.track_ids()[self.selected_track];
+                let section = section.unwrap_or(Section {
+                    start: 0,
+                    end: self.editor.num_lines(),
+                });
+                let before = self.editor.transform(pattern, track, section, transform)?;
+                self.history.push(Edit::Transform {
+                    pattern,
+                    track,
+                    section,
+                    transform,
+                    before: Box::new(before),
+                });
+                self.engine_send(EngineCommand::LoadEditor(Box::new(self.editor.clone())))?;
+            }
+            Action::Euclid(euclid) => {
+                let pattern = self.editor.current_pattern().id;
+                let track = self.editor.track_ids()[self.selected_track];
+                // The step under the cursor is the hit, or a plain note when it has none
+                let mut step = self
+                    .editor
+                    .step(self.selected_track, self.editor.cursor.line);
+                if matches!(step.pitch, None | Some(NOTE_OFF) | Some(NOTE_CUT)) {
+                    step = Step {
+                        pitch: Some(ROOT_PITCH),
+                        ..step
+                    };
+                }
+                let before = self.editor.fill_euclid(pattern, track, &euclid, step)?;
+                self.history.push(Edit::Euclid {
+                    pattern,
+                    track,
+                    euclid,
+                    step,
+                    before: Box::new(before),
+                });
+                self.engine_send(EngineCommand::LoadEditor(Box::new(self.editor.clone())))?;
+            }
+            Action::Resize(num_lines, policy) => {
+                let pattern = self.editor.current_pattern().id;
+                let before = self.editor.resize(pattern, num_lines, policy)?;
+                self.history.push(Edit::Resize {
+                    pattern,
+                    num_lines,
+                    policy,
+                    before: Box::new(before),
+                });
+                self.engine_send(EngineCommand::LoadEditor(Box::new(self.editor.clone())))?;
+            }
+            Action::Undo => {
+                if let Some(edit) = self.history.undo() {
+                    self.apply(&edit, true)?;
+                }
+            }
+            Action::Redo => {
+                if let Some(edit) = self.history.redo() {
+                    self.apply(&edit, false)?;
+                }
+            }
+            Action::SaveProject(path) => {
+                let path = path
+                    .map(|path| self.paths.project(&path))
+                    .or_else(|| self.project_path.clone())
+                    .ok_or_else(|| anyhow!("no project path given"))?;
+                self.project().save(&path)?;
+                self.history.note(format!("save {}", path));
+                self.project_path = Some(path);
+            }
+            Action::LoadProject(path) => {
+                let path = self.paths.project(&path);
+                let project = Project::load(&path)?;
+                self.load_project(project)?;
+                self.history.note(format!("open {}", path));
+                self.project_path = Some(path);
+            }
+            Action::DetectHits(path, threshold, sound) => {
+                let clip = Sampler::load_sound(&path)?;
+                let hits = drums::detect_hits(&clip, threshold);
+                let bpm = self.engine_params.get(EngineParam::Bpm);
+                let lines_per_beat = self.engine_params.get(EngineParam::LinesPerBeat);
+                let frames_per_line =
+                    (clip.sample_rate() as f64 * 60.) / (lines_per_beat * bpm) as f64;
+                let steps =
+                    drums::hit_steps(&hits, frames_per_line, self.editor.num_lines(), sound);
+
+                let pattern = self.editor.current_pattern().id;
+                let track = self.editor.track_ids()[self.selected_track];
+                for (line, after) in steps {
+                    let before = self.editor.step(self.selected_track, line);
+                    self.set_step(pattern, track, line, after)?;
+                    self.history.push(Edit::SetStep {
+                        pattern,
+                        track,
+                        line,
+                        before,
+                        after,
+                    });
+                }
+            }
+            Action::SetTrim(i, trim) => {
+                if self.instruments[i].is_none() {
+                    return Err(anyhow!("no instrument on track {}", i));
+                }
+                self.engine_params.mixer.channels[i].set_trim(trim);
+                self.history.note(format!("trim {} {}", i, trim));
+            }
+            Action::SetGain(i, gain) => {
+                self.engine_params.mixer.channels[i].set_gain(gain);
+                self.history.note(format!("gain {} {}", i, gain));
+            }
+            Action::SetPan(i, pan) => {
+                self.engine_params.mixer.channels[i].set_pan(pan);
+                self.history.note(format!("pan {} {}", i, pan));
+            }
+            Action::ToggleMute(i) => {
+                let channel = &self.engine_params.mixer.channels[i];
+                channel.toggle_mute();
+                if let Some(performance) = &mut self.performance {
+                    performance.record_mute(i, channel.mute.load(Ordering::Relaxed));
+                }
+                self.history.note(format!("mute {}", i));
+            }
+            Action::SetSource(i, source) => {
+                self.engine_params.mixer.channels[i].set_source(source);
+                self.history.note(format!("source {} {}", i, source.name()));
+            }
+            Action::ToggleDcBlock => {
+                self.engine_params.mixer.toggle_dc_block();
+                self.history.note("dc block");
+            }
+            Action::ToggleClip => {
+                self.engine_params.mixer.toggle_clip();
+                self.history.note("soft clip");
+            }
+            Action::ToggleDim => self.engine_params.monitor.toggle_dim(),
+            Action::ToggleMono => self.engine_params.monitor.toggle_mono(),
+            Action::ToggleReference => {
+                if self.reference.is_none() {
+                    return Err(anyhow!("no reference track loaded"));
+                }
+                self.engine_params.monitor.toggle_reference();
+            }
+            Action::LoadReference(path) => {
+                let reference = match &path {
+                    Some(path) => {
+                        // Resident, so playing it never waits for the disk.
+                        let sound = Sampler::load_sound_with(path, MemoryPolicy::Resident)?;
+                        Some(Box::new(Reference::new(Arc::new(sound))))
+                    }
+                    None => None,
+                };
+                if reference.is_none() {
+                    self.engine_params
+                        .monitor
+                        .reference
+                        .store(false, Ordering::Relaxed);
+                }
+                self.engine_send(EngineCommand::SetReference(reference))?;
+                self.reference = path;
+            }
+            Action::ToggleSolo(i) => {
+                self.engine_params.mixer.channels[i].toggle_solo();
+                self.history.note(format!("solo {}", i));
+            }
+            Action::SetSend(i, bus, level, pre_fader) => {
+                let send = &self.engine_params.mixer.channels[i].sends[bus];
+                send.set_level(level);
+                send.pre_fader.store(pre_fader, Ordering::Relaxed);
+                let mode = if pre_fader { "pre" } else { "post" };
+                self.history
+                    .note(format!("send {} {} {} {}", i, bus_name(bus), level, mode));
+            }
+            Action::AddEffect(i, kind, options) => {
+                if self.effects[i].len() == MAX_EFFECTS {
+                    return Err(anyhow!("no room for more effects"));
+                }
+                let effect = self.effect_registry.create(&kind, &options)?;
+                let bypass = Arc::new(AtomicBool::new(false));
+                let index = self.effects[i].len();
+                self.history.note(format!("fx add {} {}", i, kind));
+                self.effects[i].push(EffectSettings {
+                    kind,
+                    options,
+                    params: effect.params(),
+                    bypass: Arc::clone(&bypass),
+                });
+                self.engine_send(EngineCommand::InsertEffect(i, index, effect, bypass))?;
+            }
+            Action::RemoveEffect(i, index) => {
+                if index >= self.effects[i].len() {
+                    return Err(anyhow!("no effect {}", index));
+                }
+                self.effects[i].remove(index);
+                self.history.note(format!("fx rm {} {}", i, index));
+                self.engine_send(EngineCommand::RemoveEffect(i, index))?;
+            }
+            Action::MoveEffect(i, from, to) => {
+                let len = self.effects[i].len();
+                if from >= len || to >= len {
+                    return Err(anyhow!("no effect {}", usize::max(from, to)));
+                }
+                let effect = self.effects[i].remove(from);
+                self.effects[i].insert(to, effect);
+                self.history.note(format!("fx mv {} {} {}", i, from, to));
+                self.engine_send(EngineCommand::MoveEffect(i, from, to))?;
+            }
+            Action::ToggleBypass(i, index) => {
+                let effect = self.effects[i]
+                    .get(index)
+                    .ok_or_else(|| anyhow!("no effect {}", index))?;
+                effect.bypass.fetch_xor(true, Ordering::Relaxed);
+                self.history.note(format!("fx bypass {} {}", i, index));
+            }
+            Action::SetEffectParam(i, index, name, value) => {
+                let effect = self.effects[i]
+                    .get_mut(index)
+                    .ok_or_else(|| anyhow!("no effect {}", index))?;
+                let kind = &effect.kind;
+                let (_, param) = effect
+                    .params
+                    .iter_mut()
+                    .find(|(n, _)| n.eq_ignore_ascii_case(&name))
+                    .ok_or_else(|| anyhow!("{} has no param {}", kind, name))?;
+                param.set(value)?;
+                self.history
+                    .note(format!("fx set {} {} {} {}", i, index, name, value));
+            }
+            Action::ExportLog(path) => self.history.export_log(&path)?,
+            Action::Pretouch => {
+                // Runs in the background, reading a large sample library can take a while.
+                std::thread::spawn(|| {
+                    realtime::demote();
+                    mmap::pretouch_all()
+                });
+            }
+            Action::Capture(Some(dir)) => {
+                if self.capture.is_some() {
+                    return Err(anyhow!("already capturing"));
+                }
+                let slots: Vec<usize> = (0..self.instruments.len())
+                    .filter(|i| self.instruments[*i].is_some())
+                    .collect();
+                let (capture, writer) = Capture::new(&dir, &slots)?;
+                self.engine_send(EngineCommand::StartCapture(Box::new(capture)))?;
+                self.capture = Some(writer);
+            }
+            Action::Capture(None) => {
+                if let Some(writer) = self.capture.take() {
+                    self.engine_send(EngineCommand::StopCapture)?;
+                    writer.finish()?;
+                }
+            }
+            Action::ExportMidi(path) => {
+                let bpm = self.engine_params.get(EngineParam::Bpm);
+                let lines_per_beat = self.engine_params.get(EngineParam::LinesPerBeat);
+                midi::export(&self.editor, bpm, lines_per_beat, &path)?;
+            }
         }
         Ok(())
     }
 
+    pub fn project(&self) -> Project {
+        let instruments = self
+            .instruments
+            .iter()
+            .zip(&self.engine_params.mixer.channels)
+            .map(|(settings, channel)| {
+                settings.as_ref().map(|settings| InstrumentConfig {
+                    id: settings.id,
+                    kind: settings.kind.clone(),
+                    options: settings.options.clone(),
+                    params: param_values(&settings.params),
+                    modulation: modulation_config(settings),
+                    trim: channel.trim.load(Ordering::Relaxed),
+                })
+            })
+            .collect();
+        let mixer = self
+            .engine_params
+            .mixer
+            .channels
+            .iter()
+            .zip(&self.effects)
+            .map(|(channel, effects)| ChannelConfig {
+                gain: channel.gain.load(Ordering::Relaxed),
+                pan: channel.pan.load(Ordering::Relaxed),
+                mute: channel.mute.load(Ordering::Relaxed),
+                solo: channel.solo.load(Ordering::Relaxed),
+                effects: effects
+                    .iter()
+                    .map(|effect| EffectConfig {
+                        kind: effect.kind.clone(),
+                        options: effect.options.clone(),
+                        params: param_values(&effect.params),
+                        bypass: effect.bypass.load(Ordering::Relaxed),
+                    })
+                    .collect(),
+                sends: channel
+                    .sends
+                    .iter()
+                    .map(|send| SendConfig {
+                        level: send.level.load(Ordering::Relaxed),
+                        pre_fader: send.pre_fader.load(Ordering::Relaxed),
+                    })
+                    .collect(),
+                source: channel.source(),
+            })
+            .collect();
+        Project {
+            bpm: self.engine_params.get(EngineParam::Bpm),
+            lines_per_beat: self.engine_params.get(EngineParam::LinesPerBeat),
+            octave: self.engine_params.get(EngineParam::Octave),
+            key: self.editor.harmony.key,
+            scale: self.editor.harmony.scale,
+            voicings: self.editor.harmony.voicings.clone(),
+            instruments,
+            mixer,
+            dc_block: self.engine_params.mixer.dc_block.load(Ordering::Relaxed),
+            clip: self.engine_params.mixer.clip.load(Ordering::Relaxed),
+            track_ids: self.editor.track_ids().to_vec(),
+            patterns: self.editor.patterns().to_vec(),
+            current_pattern: self.editor.edit_index(),
+        }
+    }
+
+    pub fn load_project(&mut self, project: Project) -> Result<()> {
+        self.engine_params.set(EngineParam::Bpm, project.bpm);
+        self.engine_params
+            .set(EngineParam::LinesPerBeat, project.lines_per_beat);
+        self.engine_params.set(EngineParam::Octave, project.octave);
+
+        for i in 0..MAX_INSTRUMENTS {
+            match project.instruments.get(i) {
+                Some(Some(config)) => {
+                    let action =
+                        Action::CreateInstrument(i, config.kind.clone(), config.options.clone());
+                    self.take(action)?;
+                    if let Some(settings) = &mut self.instruments[i] {
+                        settings.id = config.id;
+                        self.instrument_ids.observe(config.id.0);
+                        set_param_values(&mut settings.params, &config.params)?;
+                        set_modulation(settings, &config.modulation)?;
+                    }
+                    self.engine_params.mixer.channels[i].set_trim(config.trim);
+                }
+                _ => {
+                    if self.instruments[i].is_some() {
+                        self.take(Action::RemoveInstrument(i))?;
+                    }
+                }
+            }
+        }
+
+        for i in 0..self.engine_params.mixer.channels.len() {
+            let config = project.mixer.get(i).cloned().unwrap_or_default();
+            let channel = &self.engine_params.mixer.channels[i];
+            channel.set_gain(config.gain);
+            channel.set_pan(config.pan);
+            channel.mute.store(config.mute, Ordering::Relaxed);
+            channel.solo.store(config.solo, Ordering::Relaxed);
+            channel.set_source(config.source);
+            for (bus, send) in channel.sends.iter().enumerate() {
+                match config.sends.get(bus) {
+                    Some(config) => {
+                        send.set_level(config.level);
+                        send.pre_fader.store(config.pre_fader, Ordering::Relaxed);
+                    }
+                    None => {
+                        send.set_level(MIN_GAIN);
+                        send.pre_fader.store(false, Ordering::Relaxed);
+                    }
+                }
+            }
+
+            while !self.effects[i].is_empty() {
+                self.take(Action::RemoveEffect(i, self.effects[i].len() - 1))?;
+            }
+            for effect in config.effects {
+                self.take(Action::AddEffect(i, effect.kind, effect.options))?;
+                if let Some(settings) = self.effects[i].last_mut() {
+                    set_param_values(&mut settings.params, &effect.params)?;
+                    settings.bypass.store(effect.bypass, Ordering::Relaxed);
+                }
+            }
+        }
+
+        let mixer = &self.engine_params.mixer;
+        mixer.dc_block.store(project.dc_block, Ordering::Relaxed);
+        mixer.clip.store(project.clip, Ordering::Relaxed);
+
+        self.editor
+            .load_patterns(project.patterns, project.track_ids, project.current_pattern);
+        self.editor.harmony = Harmony {
+            key: project.key,
+            scale: project.scale,
+            voicings: project.voicings,
+        };
+        self.selected_track = 0;
+        self.history.clear();
+        self.engine_send(EngineCommand::LoadEditor(Box::new(self.editor.clone())))
+    }
+
+    fn consolidate_duplicates(&mut self) -> Result<()> {
+        let duplicates = self.library.duplicates()?;
+        let cwd = std::env::current_dir()?.canonicalize()?;
+        for i in 0..MAX_INSTRUMENTS {
+            let path = match self.instruments[i]
+                .as_ref()
+                .and_then(|s| s.options.get("path").ok())
+            {
+                Some(path) => Utf8PathBuf::from(path),
+                None => continue,
+            };
+            // Missing or unreadable files are left for the instrument to complain about.
+            let hash = match self.library.hash(&path) {
+                Ok(hash) => hash,
+                Err(_) => continue,
+            };
+            let canonical = match duplicates.iter().find(|d| d.hash == hash) {
+                Some(duplicates) => &duplicates.sounds[0],
+                None => continue,
+            };
+            if path.canonicalize()? == *canonical {
+                continue;
+            }
+            let canonical = canonical.strip_prefix(&cwd).unwrap_or(canonical);
+            let action = Action::SetInstrumentOption(i, "path".into(), canonical.to_string());
+            self.take(action)?;
+        }
+        Ok(())
+    }
+
+    /// Runs an edit on the step under the cursor and records it in the history.
+    fn edit_step<F: FnOnce(&mut Editor)>(&mut self, edit: F) {
+        let track = self.editor.selected_track();
+        let line = self.editor.cursor.line;
+        let before = self.editor.step(track, line);
+        edit(&mut self.editor);
+        let after = self.editor.step(track, line);
+        if before != after {
+            self.history.push(Edit::SetStep {
+                pattern: self.editor.current_pattern().id,
+                track: self.editor.track_ids()[track],
+                line,
+                before,
+                after,
+            });
+        }
+    }
+
+    /// Sends the step under the cursor to the engine, after it was edited.
+    fn send_cursor_step(&mut self) -> Result<()> {
+        let track = self.editor.selected_track();
+        let line = self.editor.cursor.line;
+        self.engine_send(EngineCommand::SetStep(
+            self.editor.current_pattern().id,
+            self.editor.track_ids()[track],
+            line,
+            self.editor.step(track, line),
+        ))
+    }
+
+    /// Finds a param of the instrument in a slot by name, whatever its case.
+    fn param_key(&self, instrument: usize, name: &str) -> Result<ParamKey> {
+        let settings = self
+            .instruments
+            .get(instrument)
+            .and_then(Option::as_ref)
+            .ok_or_else(|| anyhow!("no instrument {}", instrument))?;
+        settings
+            .params
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(name, _)| ParamKey {
+                instrument: settings.id,
+                name: name.clone(),
+            })
+            .ok_or_else(|| anyhow!("{} has no param {}", settings.kind, name))
+    }
+
+    /// The param listed at `index` by the instrument in a slot, as the ui shows them.
+    fn nth_param_key(&self, instrument: usize, index: usize) -> Option<ParamKey> {
+        let settings = self.instruments.get(instrument)?.as_ref()?;
+        let (name, _) = settings.params.get(index)?;
+        Some(ParamKey {
+            instrument: settings.id,
+            name: name.clone(),
+        })
+    }
+
+    fn param_mut(&mut self, key: &ParamKey) -> Option<&mut Param> {
+        self.instruments
+            .iter_mut()
+            .flatten()
+            .filter(|settings| settings.id == key.instrument)
+            .flat_map(|settings| settings.params.iter_mut())
+            .find(|(name, _)| *name == key.name)
+            .map(|(_, param)| param)
+    }
+
+    /// Describes a param, with the default its instrument type gives it rather than its value.
+    pub fn describe_param(&self, key: &ParamKey) -> Option<ParamDescriptor> {
+        let settings = self
+            .instruments
+            .iter()
+            .flatten()
+            .find(|settings| settings.id == key.instrument)?;
+        let (name, param) = settings.params.iter().find(|(name, _)| *name == key.name)?;
+        let mut descriptor = param.describe(name);
+        if let Some(default) = self
+            .registry
+            .get(&settings.kind)
+            .into_iter()
+            .flat_map(|factory| factory.params())
+            .find(|descriptor| descriptor.name == key.name)
+            .map(|descriptor| descriptor.default)
+        {
+            descriptor.default = default;
+        }
+        Some(descriptor)
+    }
+
+    /// Changes a param of an instrument and records it in the history.
+    fn edit_param<F: FnOnce(&mut Param)>(&mut self, key: &ParamKey, edit: F) {
+        if let Some(param) = self.param_mut(key) {
+            let before = param.val.load(Ordering::Relaxed);
+            edit(param);
+            let after = param.val.load(Ordering::Relaxed);
+            if before != after {
+                self.history.push(Edit::SetParam {
+                    key: key.clone(),
+                    before,
+                    after,
+                });
+            }
+        }
+    }
+
+    /// Applies an edit, or reverts it when undoing.
+    fn apply(&mut self, edit: &Edit, undo: bool) -> Result<()> {
+        match edit {
+            Edit::SetStep {
+                pattern,
+                track,
+                line,
+                before,
+                after,
+            } => {
+                let step = if undo { *before } else { *after };
+                self.set_step(*pattern, *track, *line, step)?;
+            }
+            Edit::MoveTrack { from, to } => {
+                let (from, to) = if undo { (*to, *from) } else { (*from, *to) };
+                self.editor.move_track(from, to);
+                self.selected_track = self.editor.selected_track();
+                self.engine_send(EngineCommand::LoadEditor(Box::new(self.editor.clone())))?;
+            }
+            Edit::SetParam { key, before, after } => {
+                let value = if undo { *before } else { *after };
+                if let Some(param) = self.param_mut(key) {
+                    param.val.store(value, Ordering::Relaxed);
+                }
+            }
+            Edit::Rearrange {
+                pattern,
+                op,
+                before,
+            } => {
+                if undo {
+                    self.editor.restore_pattern(before.as_ref().clone());
+                } else {
+                    self.editor.rearrange(*pattern, *op)?;
+                }
+                self.engine_send(EngineCommand::LoadEditor(Box::new(self.editor.clone())))?;
+            }
+            Edit::Resize {
+                pattern,
+                num_lines,
+                policy,
+                before,
+            } => {
+                if undo {
+                    self.editor.restore_pattern(before.as_ref().clone());
+                } else {
+                    self.editor.resize(*pattern, *num_lines, *policy)?;
+                }
+                self.engine_send(EngineCommand::LoadEditor(Box::new(self.editor.clone())))?;
+            }
+            Edit::TrackLength {
+                pattern,
+                track,
+                before,
+                after,
+            } => {
+                let length = if undo { *before } else { *after };
+                self.editor.set_track_length(*pattern, *track, length)?;
+                self.engine_send(EngineCommand::LoadEditor(Box::new(self.editor.clone())))?;
+            }
+            Edit::Transform {
+                pattern,
+                track,
+                section,
+                transform,
+                before,
+            } => {
+                if undo {
+                    self.editor.restore_pattern(before.as_ref().clone());
+                } else {
+                    self.editor
+                        .transform(*pattern, *track, *section, *transform)?;
+                }
+                self.engine_send(EngineCommand::LoadEditor(Box::new(self.editor.clone())))?;
+            }
+            Edit::Record { before, after, .. } => {
+                let pattern = if undo { before } else { after };
+                self.editor.restore_pattern(pattern.as_ref().clone());
+                self.engine_send(EngineCommand::LoadEditor(Box::new(self.editor.clone())))?;
+            }
+            Edit::Euclid {
+                pattern,
+                track,
+                euclid,
+                step,
+                before,
+            } => {
+                if undo {
+                    self.editor.restore_pattern(before.as_ref().clone());
+                } else {
+                    self.editor.fill_euclid(*pattern, *track, euclid, *step)?;
+                }
+                self.engine_send(EngineCommand::LoadEditor(Box::new(self.editor.clone())))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn set_step(
+        &mut self,
+        pattern: PatternId,
+        track: TrackId,
+        line: usize,
+        step: Step,
+    ) -> Result<()> {
+        if self.editor.set_step(pattern, track, line, step) {
+            self.engine_send(EngineCommand::SetStep(pattern, track, line, step))?;
+        }
+        Ok(())
+    }
+
+    /// Creates a mixer with copies of all effects, with the current param values. The channel
+    /// settings are shared with the live mixer.
+    fn offline_mixer(&self) -> Result<Mixer> {
+        let mut mixer = Mixer::new(self.engine_params.mixer.clone());
+        for (i, chain) in self.effects.iter().enumerate() {
+            for (index, settings) in chain.iter().enumerate() {
+                let effect = self
+                    .effect_registry
+                    .create(&settings.kind, &settings.options)?;
+                for ((_, param), (_, copy)) in settings.params.iter().zip(effect.params()) {
+                    copy.val
+                        .store(param.val.load(Ordering::Relaxed), Ordering::Relaxed);
+                }
+                let bypass = settings.bypass.load(Ordering::Relaxed);
+                // Chains are as long as the engine's, there's room for every effect.
+                let _ = mixer.insert_effect(i, index, effect, Arc::new(AtomicBool::new(bypass)));
+            }
+        }
+        Ok(mixer)
+    }
+
+    /// Loads a loop stretched to the song tempo, and shifted to the song key when both keys
+    /// are known. Tempo and key are taken from the file name, see `LoopInfo`.
+    fn stretched_sound(&self, path: &Utf8PathBuf) -> Result<Sound> {
+        let sound = Sampler::load_sound_with(path, MemoryPolicy::Resident)?;
+        let info = match LoopInfo::from_path(path, sound.num_frames(), sound.sample_rate()) {
+            Some(info) => info,
+            None => return Ok(sound),
+        };
+        let bpm = self.engine_params.get(EngineParam::Bpm) as f64;
+        let semitones = match (info.key, self.editor.harmony.key) {
+            (Some(from), Some(to)) => from.semitones_to(to),
+            _ => 0,
+        };
+        let frames: Vec<(f32, f32)> = sound.frames().collect();
+        let frames = stretch::stretch_and_shift(&frames, info.bpm / bpm, semitones);
+        Ok(Sound::from_frames(frames, sound.sample_rate()))
+    }
+
+    /// The samplers of the instrument slots as pads of a drum kit, on consecutive keys.
+    fn kit_pads(&self) -> Result<Vec<Pad>> {
+        let mut pads = Vec::new();
+        for (i, settings) in self.instruments.iter().enumerate() {
+            let settings = match settings {
+                Some(settings) if settings.kind == "sampler" => settings,
+                _ => continue,
+            };
+            let path = Utf8PathBuf::from(settings.options.get("path")?);
+            let value = |name: &str| {
+                settings
+                    .params
+                    .iter()
+                    .find(|(n, _)| n == name)
+                    .map_or(0.0, |(_, param)| param.val.load(Ordering::Relaxed))
+            };
+            pads.push(Pad {
+                name: path.file_stem().unwrap_or("pad").to_string(),
+                key: kit::FIRST_KEY + i as u8,
+                sound: Sampler::load_sound(&path)?,
+                volume: value("Amp"),
+                // Drum lanes play the pads at the default root, not at their own.
+                tune: value("Tune") + ROOT_PITCH as f32 - value("Root"),
+                delay: value("Delay"),
+                attack: value("Attack"),
+                hold: value("Hold"),
+                decay: value("Decay"),
+                sustain: value("Sustain"),
+                release: value("Release"),
+            });
+        }
+        Ok(pads)
+    }
+
+    /// Creates a copy of every instrument with the current param values, skipping the ones
+    /// which don't render audio.
+    fn offline_instruments(&self) -> Result<Vec<Option<Box<dyn Instrument>>>> {
+        let mut instruments = Vec::with_capacity(self.instruments.len());
+        for settings in &self.instruments {
+            let settings = match settings {
+                Some(settings) => settings,
+                None => {
+                    instruments.push(None);
+                    continue;
+                }
+            };
+            match self.registry.get(&settings.kind) {
+                Some(factory) if factory.renders_audio() => {
+                    let instrument = self.registry.create(&settings.kind, &settings.options)?;
+                    let modulation = settings.modulation.clone();
+                    let instrument: Box<dyn Instrument> =
+                        Box::new(Modulated::new(instrument, modulation));
+                    for ((_, param), (_, copy)) in settings.params.iter().zip(instrument.params()) {
+                        copy.val
+                            .store(param.val.load(Ordering::Relaxed), Ordering::Relaxed);
+                    }
+                    instruments.push(Some(instrument));
+                }
+                _ => instruments.push(None),
+            }
+        }
+        Ok(instruments)
+    }
+
+    /// Sends notes of the computer keyboard to the selected instrument, and records them when
+    /// a take is going.
+    fn play_live(&mut self, notes: Vec<LiveNote>) -> Result<()> {
+        let instrument = self.selected_track;
+        if !notes.is_empty() {
+            // The take must know about the line playing before the notes are timed
+            self.run_commands();
+        }
+        for note in notes {
+            if let Some(take) = &mut self.live_take {
+                match note {
+                    LiveNote::On { pitch, .. } => take.note_on(pitch, Instant::now()),
+                    LiveNote::Off { pitch, .. } => take.note_off(pitch, Instant::now()),
+                }
+            }
+            let command = match note {
+                LiveNote::On {
+                    slot,
+                    pitch,
+                    velocity,
+                } => EngineCommand::NoteOn(instrument, harmony::live_column(slot), pitch, velocity),
+                LiveNote::Off { slot, .. } => {
+                    EngineCommand::NoteOff(instrument, harmony::live_column(slot))
+                }
+            };
+            self.engine_send(command)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the take along with the line playing, or starts one when recording is armed.
+    fn follow_take(&mut self, tick: usize) {
+        if let Some(take) = &mut self.live_take {
+            take.set_tick(tick, Instant::now());
+            return;
+        }
+        if let Some(policy) = self.record_arm {
+            let sample_rate = self.engine_params.sample_rate.load(Ordering::Relaxed) as f64;
+            let bpm = self.engine_params.get(EngineParam::Bpm) as f64;
+            let lines_per_beat = self.engine_params.get(EngineParam::LinesPerBeat) as f64;
+            let frames_per_line = (sample_rate * 60.0 / (lines_per_beat * bpm)).round();
+            self.live_take = Some(LiveTake::start(
+                &self.editor,
+                self.selected_track,
+                self.current_line,
+                tick,
+                frames_per_line,
+                sample_rate,
+                policy,
+            ));
+        }
+    }
+
+    /// Writes the notes of the take into its track, as one edit.
+    fn finish_take(&mut self) -> Result<()> {
+        let take = match self.live_take.take() {
+            Some(take) if !take.is_empty() => take,
+            _ => return Ok(()),
+        };
+        let pattern = self.editor.current_pattern().id;
+        let track = self.editor.track_ids()[take.track()];
+        let before = self.editor.current_pattern().clone();
+        take.finish(&mut self.editor, self.quantize);
+        self.history.push(Edit::Record {
+            pattern,
+            track,
+            before: Box::new(before),
+            after: Box::new(self.editor.current_pattern().clone()),
+        });
+        self.engine_send(EngineCommand::LoadEditor(Box::new(self.editor.clone())))
+    }
+
     fn engine_send(&mut self, cmd: EngineCommand) -> Result<()> {
         if self.prod.push(cmd).is_err() {
             Err(anyhow!("unable to send message to engine"))
@@ -194,29 +1789,269 @@ impl App {
     }
 }
 
+/// First free `<stem>-<n>.wav` in `dir`.
+fn free_path(dir: &Utf8Path, stem: &str) -> Result<Utf8PathBuf> {
+    fs::create_dir_all(dir)?;
+    (1..)
+        .map(|i| dir.join(format!("{}-{:03}.wav", stem, i)))
+        .find(|path| !path.exists())
+        .ok_or_else(|| anyhow!("no free file name for {}", stem))
+}
+
+/// Writes a sound as a 32-bit float WAV file.
+fn write_sound(path: &Utf8Path, sound: &Sound) -> Result<()> {
+    let spec = WavSpec {
+        channels: 2,
+        sample_rate: sound.sample_rate(),
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    let frames: Vec<(f32, f32)> = sound.frames().collect();
+    bounce::write_wav(path, spec, &frames)
+}
+
+fn param_values(params: &[(String, Param)]) -> Vec<(String, f32)> {
+    params
+        .iter()
+        .map(|(name, param)| (name.clone(), param.val.load(Ordering::Relaxed)))
+        .collect()
+}
+
+/// LFO settings and routes of an instrument, with the params routed to by name.
+fn modulation_config(settings: &InstrumentSettings) -> ModulationConfig {
+    let modulation = &settings.modulation;
+    ModulationConfig {
+        lfos: modulation
+            .lfos
+            .iter()
+            .map(|lfo| (lfo.shape(), lfo.rate()))
+            .collect(),
+        routes: modulation
+            .routes()
+            .iter()
+            .filter_map(|route| {
+                let (name, _) = settings.params.get(route.target)?;
+                Some((route.lfo, name.clone(), route.depth))
+            })
+            .collect(),
+        per_sample: modulation.per_sample.load(Ordering::Relaxed),
+    }
+}
+
+/// Restores saved modulation, routes to unknown params are ignored.
+fn set_modulation(settings: &InstrumentSettings, config: &ModulationConfig) -> Result<()> {
+    let modulation = &settings.modulation;
+    modulation.clear();
+    for (lfo, (shape, rate)) in modulation.lfos.iter().zip(&config.lfos) {
+        lfo.set(*shape, *rate);
+    }
+    for (lfo, name, depth) in &config.routes {
+        if let Some(target) = settings.params.iter().position(|(n, _)| n == name) {
+            let (lfo, depth) = (*lfo, *depth);
+            modulation.set_route(Route { lfo, target, depth })?;
+        }
+    }
+    modulation
+        .per_sample
+        .store(config.per_sample, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Restores saved param values by name, values for unknown params are ignored.
+fn set_param_values(params: &mut [(String, Param)], values: &[(String, f32)]) -> Result<()> {
+    for (name, value) in values {
+        if let Some((_, param)) = params.iter_mut().find(|(n, _)| n == name) {
+            param.set(*value)?;
+        }
+    }
+    Ok(())
+}
+
 pub enum AppCommand {
     SetCurrentTick(usize),
+    /// Something the engine let go of, dropped by the app.
+    Dispose(Garbage),
 }
 
 pub enum Action {
     Exit,
     LoadSound(usize, Utf8PathBuf),
     PreviewSound(Utf8PathBuf),
+    /// Sets the key of the song, for stretched previews and for the scale.
+    SetKey(Option<Key>),
+    /// Sets the scale of the song key, `None` for the plain major or minor of the key.
+    SetScale(Option<Scale>),
+    /// Defines a voicing, as semitones above the note of the step.
+    SetVoicing(usize, Vec<i8>),
+    /// Sets the chord of the step under the cursor.
+    SetChord(Option<Chord>),
+    /// Moves the step under the cursor off the grid, by ticks of `TICKS_PER_LINE` per line.
+    SetOffset(i8),
+    ToggleScaleSnap,
+    ToggleStretchPreview,
+    ToggleDrumLanes,
+    /// Starts recording mutes while the song plays, or stops and adds the take as a new
+    /// pattern.
+    TogglePerformance,
+    /// Arms recording of the notes played live into the selected track while the song plays,
+    /// or disarms it and writes the take. The policy says what happens to notes held when the
+    /// pattern loops.
+    RecordLive(Option<LoopPolicy>),
+    /// Sets how far recorded notes are moved onto the lines, from 0 to 1.
+    SetQuantize(f32),
+    /// Sets the lines the cursor moves down after a step is entered, at most `MAX_EDIT_STEP`.
+    SetEditStep(usize),
+    /// Starts listening for OSC messages on a port, or stops.
+    Osc(Option<u16>),
+    /// Runs the commands of a script, see `script`. It stops at the first command failing.
+    RunScript(Utf8PathBuf),
+    /// Runs a command whenever the transport does something.
+    AddHook(Hook, String),
+    /// Removes the commands of a hook.
+    ClearHook(Hook),
+    /// Moves the cursor to a track.
+    SelectTrack(usize),
+    /// Lists the instrument types in the history, or the params of one of them.
+    ListInstruments(Option<String>),
+    /// Starts a note received over OSC on the instrument of a track, or ends it when the
+    /// velocity is 0.
+    RemoteNote(usize, u8, u8),
+    /// Sets a param of an instrument by name, within its range.
+    SetParam(usize, String, f32),
+    /// Sets a param of an instrument by name from a fraction of its range.
+    SetParamNormalized(usize, String, f32),
+    /// Writes the range, unit and default of a param of an instrument to the history.
+    DescribeParam(usize, String),
+    SelectPattern(usize),
+    /// Starts tuning a hardware input, or stops the tuner.
+    SetTuner(Option<usize>),
+    /// Finds the pitch of a sound.
+    TuneSound(Utf8PathBuf),
+    /// Starts recording an input into a new sound for the selected slot, during a section of
+    /// the pattern or until stopped. Stopping loads the sound into the slot.
+    RecordSample(Option<(usize, Option<Section>)>),
+    /// Replaces the sound of the selected sampler with an edited copy.
+    EditSound(SoundEdit),
+    /// Edits the regions of the selected sampler, trimming the ones an edited region overlaps.
+    EditRegions(RegionEdit),
+    /// Adds or removes a hit of a pad on the line under the cursor, `None` being the sound of
+    /// the track itself.
+    ToggleHit(Option<u8>),
+    /// Adds tags to a sound in the library.
+    Tag(Utf8PathBuf, Vec<String>),
+    Untag(Utf8PathBuf, Vec<String>),
+    /// Rates a sound in the library from 0 to `MAX_RATING` stars.
+    Rate(Utf8PathBuf, u8),
+    SetLabel(Utf8PathBuf, Option<Label>),
+    /// Lists the sounds of the library matching a query in the browser.
+    FindSounds(Query),
+    /// Lists the sounds of the library which have the same audio in the browser, grouped.
+    FindDuplicates,
+    /// Points the instruments using a sound with duplicates to its canonical copy.
+    ConsolidateDuplicates,
     InsertNote(u8),
+    /// Writes a step ending the note playing on the track, after its release.
+    InsertNoteOff,
+    /// Writes a step silencing the note playing on the track at once.
+    InsertNoteCut,
     InsertNumber(i32),
     DeleteNote,
+    /// Moves the cursor down by the edit step, as after entering a step.
+    Advance,
     ChangeValue(i32),
+    /// Pauses when playing, plays otherwise.
     TogglePlay,
+    Play,
+    /// Stops playing where it is.
+    Pause,
+    /// Stops playing and goes back to the start of the song.
+    Stop,
+    /// Moves playback to a position, playing or not.
+    Seek(BarsBeats),
+    /// Loops over bars while playing, counted from 0 and up to the end bar, or stops looping.
+    /// The region is kept in lines, changing the lines per beat doesn't move it.
+    SetLoop(Option<Range<u64>>),
+    /// Plays the note of a key on the selected instrument, held until the key stops repeating.
+    /// Upper case keys play accented.
+    PlayKey(char),
+    /// Ends every note played from the computer keyboard.
+    ReleaseKeys,
     IncrParam(usize),
     DecrParam(usize),
     UpdateEngineParam(EngineParam, String),
     MoveCursor(Move),
+    ExportMidi(Utf8PathBuf),
+    /// Adds an effect to the end of a channel's chain.
+    AddEffect(usize, String, Options),
+    RemoveEffect(usize, usize),
+    MoveEffect(usize, usize, usize),
+    ToggleBypass(usize, usize),
+    SetEffectParam(usize, usize, String, f32),
+    /// Writes the session log to a JSON file.
+    ExportLog(Utf8PathBuf),
+    /// Loads all memory-mapped samples into memory, e.g. before a performance.
+    Pretouch,
+    /// Sets the output trim of an instrument in dB.
+    SetTrim(usize, f32),
+    SetGain(usize, f32),
+    /// Sets the send of a channel to an aux bus: channel, bus, level in dB and whether it is
+    /// pre-fader.
+    SetSend(usize, usize, f32, bool),
+    SetPan(usize, f32),
+    ToggleMute(usize),
+    ToggleSolo(usize),
+    /// Selects where the signal of an instrument channel comes from.
+    SetSource(usize, Source),
+    ToggleDcBlock,
+    ToggleClip,
+    ToggleDim,
+    ToggleMono,
+    /// Switches between the mix and the reference track.
+    ToggleReference,
+    /// Loads a track to compare the mix against, or unloads it.
+    LoadReference(Option<Utf8PathBuf>),
+    /// Starts recording every instrument to its own file in a directory, or stops recording.
+    Capture(Option<Utf8PathBuf>),
+    /// Writes the hits found in a clip into the selected track: path, threshold in dB and the
+    /// instrument to trigger.
+    DetectHits(Utf8PathBuf, f32, u8),
+    Bounce(Utf8PathBuf, BounceSettings),
+    /// Writes the samplers to a directory as a drum kit for other samplers.
+    ExportKit(Utf8PathBuf, KitFormat),
+    MoveTrack(usize),
+    /// Rearranges a section of the current pattern.
+    Rearrange(SectionOp),
+    /// Changes the length of the current pattern.
+    Resize(usize, LengthPolicy),
+    /// Gives the selected track its own length, or makes it follow the pattern again.
+    SetTrackLength(Option<usize>),
+    /// Transforms a section of the selected track, or the whole track.
+    Transform(Option<Section>, Transform),
+    /// Fills the selected track with a Euclidean rhythm of the step under the cursor.
+    Euclid(Euclid),
+    Undo,
+    Redo,
+    SaveProject(Option<Utf8PathBuf>),
+    LoadProject(Utf8PathBuf),
+    CreateInstrument(usize, String, Options),
+    /// Recreates an instrument with one of its options changed, keeping its param values.
+    SetInstrumentOption(usize, String, String),
+    /// Sets the shape and rate of an instrument's LFO.
+    SetLfo(usize, usize, Shape, Rate),
+    /// Routes an LFO of an instrument to one of its params by name, with a depth from -1 to 1
+    /// of the param's range, or removes the route.
+    SetRoute(usize, usize, String, Option<f32>),
+    /// Updates modulated params every sample instead of every control block.
+    SetModulationRate(usize, bool),
+    RemoveInstrument(usize),
 }
 
 pub struct FileBrowser {
-    entries: Vec<DirEntry>,
+    entries: Vec<Utf8PathBuf>,
     dir: Utf8PathBuf,
     short_dir: Utf8PathBuf,
+    /// Title of the search results listed instead of the directory.
+    search: Option<String>,
 }
 
 impl FileBrowser {
@@ -225,12 +2060,17 @@ impl FileBrowser {
             entries: Vec::new(),
             dir: Utf8PathBuf::new(),
             short_dir: Utf8PathBuf::new(),
+            search: None,
         };
         fb.move_to(path)?;
         Ok(fb)
     }
 
+    /// Moves to the parent directory, or back to the directory when showing search results.
     pub fn move_up(&mut self) -> Result<()> {
+        if self.search.is_some() {
+            return self.move_to(self.dir.clone());
+        }
         if let Some(parent) = self.dir.clone().parent() {
             self.move_to(parent)?;
         }
@@ -239,14 +2079,16 @@ impl FileBrowser {
 
     pub fn move_to<P: AsRef<Utf8Path>>(&mut self, path: P) -> Result<()> {
         self.entries.clear();
-        for entry in fs::read_dir(path.as_ref())? {
-            let entry = entry?;
-            if entry.path().is_dir() || entry.path().extension().map_or(false, |ext| ext == "wav") {
-                self.entries.push(entry);
-            }
-        }
+        self.search = None;
         self.dir = Utf8PathBuf::from_path_buf(path.as_ref().canonicalize()?)
             .map_err(|path| anyhow!("invalid path {}", path.display()))?;
+        for entry in fs::read_dir(&self.dir)? {
+            if let Ok(path) = Utf8PathBuf::from_path_buf(entry?.path()) {
+                if path.is_dir() || sampler::is_sound(&path) {
+                    self.entries.push(path);
+                }
+            }
+        }
 
         self.short_dir.clear();
         let parts: Vec<_> = self.dir.components().collect();
@@ -259,27 +2101,38 @@ impl FileBrowser {
         } else {
             self.short_dir = self.dir.clone();
         }
-        self.entries.sort_by_key(|e| e.path());
+        self.entries.sort();
         Ok(())
     }
 
+    /// Lists sounds from anywhere in the library under a title, until moving to a directory.
+    pub fn show_results(&mut self, title: String, sounds: Vec<Utf8PathBuf>) {
+        self.search = Some(title);
+        self.entries = sounds;
+    }
+
     pub fn num_entries(&self) -> usize {
         self.entries.len()
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = String> + '_ {
-        self.entries
-            .iter()
-            .map(|entry| entry.file_name().to_string_lossy().to_string())
+    /// Names and paths of the entries.
+    pub fn iter(&self) -> impl Iterator<Item = (String, &Utf8Path)> + '_ {
+        self.entries.iter().map(|path| {
+            (
+                path.file_name().unwrap_or_default().to_string(),
+                path.as_path(),
+            )
+        })
     }
 
     pub fn get(&self, i: usize) -> Option<Utf8PathBuf> {
-        self.entries
-            .get(i)
-            .and_then(|entry| Utf8PathBuf::from_path_buf(entry.path()).ok())
+        self.entries.get(i).cloned()
     }
 
     pub fn current_dir(&self) -> String {
-        self.short_dir.to_string()
+        match &self.search {
+            Some(title) => title.clone(),
+            None => self.short_dir.to_string(),
+        }
     }
 }
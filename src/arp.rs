@@ -0,0 +1,256 @@
+//! An arpeggiator between the notes played and an instrument: while a chord is held, it plays
+//! the notes of the chord one after the other at a rate synced to the song tempo. It follows
+//! every note the instrument gets, so chords held by several tracks of the pattern are
+//! arpeggiated as well as notes played live.
+
+use crate::engine::{Device, EngineConfig};
+use crate::instrument::{Instrument, Options, Quality};
+use crate::param::Param;
+use anyhow::{anyhow, Result};
+use atomic_float::AtomicF32;
+use std::sync::{atomic::Ordering, Arc};
+
+/// Column the arpeggiated notes are played on.
+const COLUMN: usize = 0;
+/// Most notes held at once, so holding notes doesn't allocate.
+const MAX_HELD: usize = 64;
+const MAX_OCTAVES: usize = 4;
+
+/// Order the notes of the chord are played in.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Pattern {
+    Up,
+    Down,
+    Random,
+    /// In the order the notes were played.
+    Played,
+}
+
+impl Pattern {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "up" => Ok(Pattern::Up),
+            "down" => Ok(Pattern::Down),
+            "random" => Ok(Pattern::Random),
+            "played" => Ok(Pattern::Played),
+            _ => Err(anyhow!(
+                "unknown arpeggio pattern {}, expected up, down, random or played",
+                name
+            )),
+        }
+    }
+}
+
+/// Wraps `instrument` in an arpeggiator when its options have an `arp` pattern.
+pub fn wrap(instrument: Box<dyn Instrument>, options: &Options) -> Result<Box<dyn Instrument>> {
+    match options.get("arp") {
+        Ok(pattern) => Ok(Box::new(Arpeggiator::new(
+            instrument,
+            Pattern::parse(pattern)?,
+        ))),
+        Err(_) => Ok(instrument),
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct HeldNote {
+    column: usize,
+    pitch: u8,
+    velocity: u8,
+}
+
+pub struct Arpeggiator {
+    instrument: Box<dyn Instrument>,
+    pattern: Pattern,
+    /// Notes per beat.
+    rate: Arc<AtomicF32>,
+    /// Octaves the chord is played over, going up from where it's held.
+    octaves: Arc<AtomicF32>,
+    /// Length of the notes, from a short blip to the full step at 1.
+    gate: Arc<AtomicF32>,
+    /// The held notes, one per column, in the order they were played.
+    held: Vec<HeldNote>,
+    /// The notes of the current step pattern, rebuilt at every step.
+    sequence: Vec<(u8, u8)>,
+    /// Number of the next step since the chord was first held.
+    step: usize,
+    /// Samples until the next step, fractional so steps don't drift from the tempo.
+    until_step: f64,
+    /// Samples until the note playing ends.
+    until_release: Option<f64>,
+    random: u32,
+    bpm: f32,
+    sample_rate: f32,
+}
+
+impl Arpeggiator {
+    pub fn new(instrument: Box<dyn Instrument>, pattern: Pattern) -> Self {
+        Self {
+            instrument,
+            pattern,
+            rate: Arc::new(AtomicF32::new(4.0)),
+            octaves: Arc::new(AtomicF32::new(1.0)),
+            gate: Arc::new(AtomicF32::new(0.5)),
+            held: Vec::with_capacity(MAX_HELD),
+            sequence: Vec::with_capacity(MAX_HELD * MAX_OCTAVES),
+            step: 0,
+            until_step: 0.0,
+            until_release: None,
+            random: 0x9e37_79b9,
+            bpm: 120.0,
+            sample_rate: EngineConfig::default().sample_rate as f32,
+        }
+    }
+
+    fn step_length(&self) -> f64 {
+        let rate = self.rate.load(Ordering::Relaxed).max(1.0);
+        self.sample_rate as f64 * 60.0 / (self.bpm as f64 * rate as f64)
+    }
+
+    /// Plays the note of the next step, ending the previous one.
+    fn next_step(&mut self) {
+        if self.until_release.take().is_some() {
+            self.instrument.note_off(COLUMN);
+        }
+        let octaves = (self.octaves.load(Ordering::Relaxed).round() as usize).clamp(1, MAX_OCTAVES);
+        let mut notes: [(u8, u8); MAX_HELD] = [(0, 0); MAX_HELD];
+        let notes = &mut notes[..self.held.len()];
+        for (note, held) in notes.iter_mut().zip(&self.held) {
+            *note = (held.pitch, held.velocity);
+        }
+        if self.pattern != Pattern::Played {
+            notes.sort_unstable_by_key(|(pitch, _)| *pitch);
+        }
+        self.sequence.clear();
+        for octave in 0..octaves {
+            for (pitch, velocity) in notes.iter() {
+                let pitch = *pitch as usize + 12 * octave;
+                if pitch < 128 {
+                    self.sequence.push((pitch as u8, *velocity));
+                }
+            }
+        }
+        if self.pattern == Pattern::Down {
+            self.sequence.reverse();
+        }
+        if self.sequence.is_empty() {
+            return;
+        }
+        let index = match self.pattern {
+            Pattern::Random => {
+                // xorshift
+                self.random ^= self.random << 13;
+                self.random ^= self.random >> 17;
+                self.random ^= self.random << 5;
+                self.random as usize % self.sequence.len()
+            }
+            _ => self.step % self.sequence.len(),
+        };
+        let (pitch, velocity) = self.sequence[index];
+        self.instrument.note_on(COLUMN, pitch, velocity);
+        let length = self.step_length();
+        let gate = self.gate.load(Ordering::Relaxed).clamp(0.05, 1.0) as f64;
+        self.until_release = Some(length * gate);
+        self.until_step = length;
+        self.step += 1;
+    }
+}
+
+impl Device for Arpeggiator {
+    /// Renders up to every step and note end, so they land on the sample.
+    fn render(&mut self, buffer: &mut [(f32, f32)]) {
+        let mut start = 0;
+        while start < buffer.len() {
+            if !self.held.is_empty() && self.until_step <= 0.0 {
+                self.next_step();
+            }
+            if self.until_release.is_some_and(|until| until <= 0.0) {
+                self.until_release = None;
+                self.instrument.note_off(COLUMN);
+            }
+            let mut until = buffer.len() - start;
+            if !self.held.is_empty() {
+                until = usize::min(until, self.until_step.ceil().max(1.0) as usize);
+            }
+            if let Some(release) = self.until_release {
+                until = usize::min(until, release.ceil().max(1.0) as usize);
+            }
+            self.instrument.render(&mut buffer[start..start + until]);
+            self.until_step -= until as f64;
+            if let Some(release) = &mut self.until_release {
+                *release -= until as f64;
+            }
+            start += until;
+        }
+    }
+}
+
+impl Instrument for Arpeggiator {
+    /// Adds a note to the chord, the first note of a chord starts the arpeggio at once.
+    fn note_on(&mut self, column: usize, pitch: u8, velocity: u8) {
+        self.held.retain(|note| note.column != column);
+        if self.held.is_empty() {
+            self.step = 0;
+            self.until_step = 0.0;
+        }
+        if self.held.len() < MAX_HELD {
+            self.held.push(HeldNote {
+                column,
+                pitch,
+                velocity,
+            });
+        }
+    }
+
+    fn note_off(&mut self, column: usize) {
+        self.held.retain(|note| note.column != column);
+        if self.held.is_empty() && self.until_release.take().is_some() {
+            self.instrument.note_off(COLUMN);
+        }
+    }
+
+    fn prepare(&mut self, config: &EngineConfig) {
+        self.sample_rate = config.sample_rate as f32;
+        self.instrument.prepare(config);
+    }
+
+    fn stop(&mut self) {
+        self.instrument.stop();
+    }
+
+    fn reset(&mut self) {
+        self.held.clear();
+        self.until_release = None;
+        self.instrument.reset();
+    }
+
+    fn set_quality(&mut self, quality: Quality) {
+        self.instrument.set_quality(quality);
+    }
+
+    fn set_tempo(&mut self, bpm: f32) {
+        self.bpm = bpm;
+        self.instrument.set_tempo(bpm);
+    }
+
+    fn active_voices(&self) -> usize {
+        self.instrument.active_voices()
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        let mut params = self.instrument.params();
+        params.push((
+            String::from("ArpRate"),
+            Param::new(1.0, Arc::clone(&self.rate), 8.0, 1.0),
+        ));
+        params.push((
+            String::from("ArpOctaves"),
+            Param::new(1.0, Arc::clone(&self.octaves), MAX_OCTAVES as f32, 1.0),
+        ));
+        params.push((
+            String::from("ArpGate"),
+            Param::new(0.05, Arc::clone(&self.gate), 1.0, 0.05),
+        ));
+        params
+    }
+}
@@ -0,0 +1,410 @@
+pub mod jack;
+pub(crate) mod realtime;
+
+use crate::drift;
+use crate::drift::DriftCorrector;
+use crate::engine::{Engine, EngineConfig};
+use crate::MAX_FRAMES_PER_BUFFER;
+use anyhow::{anyhow, Result};
+use portaudio::stream_callback_flags as paflags_callback;
+use portaudio::stream_flags as paflags;
+use portaudio::{InputStreamCallbackArgs, OutputStreamCallbackArgs, PortAudio};
+use realtime::Promotion;
+use ringbuf::{Consumer, Producer, RingBuffer};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Time without a callback after which the output device is considered gone.
+const STALL_TIME: Duration = Duration::from_secs(1);
+/// Time between two attempts to reconnect a lost device.
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    pub name: String,
+    /// The driver API the device is accessed through, e.g. ALSA, JACK or CoreAudio.
+    pub host: String,
+    pub channels: usize,
+    pub default_sample_rate: f64,
+    pub is_default: bool,
+}
+
+/// Changes of the output device, reported to the app by `AudioBackend::poll`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceEvent {
+    /// The device stopped, e.g. it was unplugged or the sound server restarted. The engine
+    /// keeps its state and is paused until a device is back.
+    Lost(String),
+    /// Rendering resumed, on the lost device or on the default one when it's still missing.
+    Reconnected(String),
+    /// The audio thread couldn't get a realtime priority, with the reason. It still runs, but
+    /// is more likely to underrun under load.
+    NoRealtime(String),
+}
+
+/// An audio output which drives the engine from its callback.
+pub trait AudioBackend {
+    fn name(&self) -> &'static str;
+
+    /// Lists the available output devices.
+    fn devices(&self) -> Result<Vec<DeviceInfo>>;
+
+    /// Starts rendering the engine. The device is selected by (part of) its name, the default
+    /// output device is used when no name is given. The engine config is taken as a request,
+    /// the engine is reconfigured with the settings the device actually runs at.
+    fn start(&mut self, engine: Engine, device: Option<&str>) -> Result<()>;
+
+    fn stop(&mut self) -> Result<()>;
+
+    /// Called regularly by the app. Notices when the device is lost, then tries to reconnect
+    /// it and keeps the paused engine up to date with the app in the meantime.
+    fn poll(&mut self) -> Option<DeviceEvent> {
+        None
+    }
+
+    /// Records the first hardware input from a device other than the output device, selected
+    /// by (part of) its name. Takes effect on the next `start`.
+    fn set_input(&mut self, _device: &str) -> Result<()> {
+        Err(anyhow!("{} can't record from another device", self.name()))
+    }
+}
+
+type AudioStream = portaudio::Stream<portaudio::NonBlocking, portaudio::Output<f32>>;
+type InputStream = portaudio::Stream<portaudio::NonBlocking, portaudio::Input<f32>>;
+
+/// Plays through an output device and can record the first hardware input from a separate
+/// input device, whose clock drift is corrected for. Other inputs stay silent. When the output
+/// device is lost, the stream is closed and reopened once the device is back, or on the default
+/// device.
+pub struct PortAudioBackend {
+    /// Initialized again when reconnecting, PortAudio only looks for devices on startup.
+    pa: Option<PortAudio>,
+    stream: Option<AudioStream>,
+    input: Option<String>,
+    input_stream: Option<InputStream>,
+    /// Output device requested by name.
+    device: Option<String>,
+    /// Holds the engine while no stream renders it.
+    engine: Option<Engine>,
+    /// Where the stream rendering the engine hands it back.
+    returned: Option<Consumer<Engine>>,
+    /// Name of the device in use, or of the lost one.
+    device_name: String,
+    /// Number of callbacks so far, and when it last changed.
+    callbacks: Arc<AtomicUsize>,
+    last_callback: (usize, Instant),
+    promotion: Promotion,
+    /// Time of the last attempt to reconnect, while the device is lost.
+    lost: Option<Instant>,
+}
+
+/// The engine as owned by a stream callback. It goes back to the backend when the stream is
+/// dropped, so it can move to another stream with its state intact. It's handed back through
+/// a queue rather than a lock the callback could contend for.
+struct Lease {
+    engine: Option<Engine>,
+    home: Producer<Engine>,
+}
+
+impl Deref for Lease {
+    type Target = Engine;
+
+    fn deref(&self) -> &Engine {
+        self.engine.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for Lease {
+    fn deref_mut(&mut self) -> &mut Engine {
+        self.engine.as_mut().unwrap()
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if let Some(engine) = self.engine.take() {
+            // The queue has room for the one engine.
+            let _ = self.home.push(engine);
+        }
+    }
+}
+
+impl PortAudioBackend {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            pa: Some(PortAudio::new()?),
+            stream: None,
+            input: None,
+            input_stream: None,
+            device: None,
+            engine: None,
+            returned: None,
+            device_name: String::new(),
+            callbacks: Arc::new(AtomicUsize::new(0)),
+            last_callback: (0, Instant::now()),
+            promotion: Promotion::new(),
+            lost: None,
+        })
+    }
+
+    /// Takes back the engine from the last stream, once it was dropped.
+    fn reclaim(&mut self) {
+        if let Some(engine) = self.returned.as_mut().and_then(Consumer::pop) {
+            self.engine = Some(engine);
+            self.returned = None;
+        }
+    }
+
+    fn pa(&self) -> Result<&PortAudio> {
+        self.pa
+            .as_ref()
+            .ok_or_else(|| anyhow!("PortAudio isn't initialized"))
+    }
+
+    fn find_input_device(&self, name: &str) -> Result<portaudio::DeviceIndex> {
+        for device in self.pa()?.devices()? {
+            let (index, info) = device?;
+            if info.max_input_channels >= 1 && info.name.contains(name) {
+                return Ok(index);
+            }
+        }
+        Err(anyhow!("no input device matching {}", name))
+    }
+
+    /// Starts recording from the input device into a FIFO, and returns the output side of it.
+    fn start_input(&mut self, name: &str, config: EngineConfig) -> Result<DriftCorrector> {
+        let device = self.find_input_device(name)?;
+        let info = self.pa()?.device_info(device)?;
+        let channels = i32::min(info.max_input_channels, 2);
+        let latency = info.default_low_input_latency;
+        let params = portaudio::StreamParameters::<f32>::new(device, channels, true, latency);
+        let mut sample_rate = config.sample_rate;
+        if self
+            .pa()?
+            .is_input_format_supported(params, sample_rate)
+            .is_err()
+        {
+            sample_rate = info.default_sample_rate;
+        }
+
+        // Enough buffering for a few periods of either device.
+        let fifo = 4 * config.buffer_size as usize;
+        let (mut producer, corrector) = drift::channel(fifo, sample_rate, config.sample_rate);
+        let settings = portaudio::InputStreamSettings::new(params, sample_rate, config.buffer_size);
+        let callback = move |InputStreamCallbackArgs { buffer, .. }| {
+            for frame in buffer.chunks(channels as usize) {
+                let right = frame.get(1).unwrap_or(&frame[0]);
+                // Dropped when the output has stopped reading, the corrector catches up.
+                let _ = producer.push((frame[0], *right));
+            }
+            portaudio::Continue
+        };
+        let mut stream = self.pa()?.open_non_blocking_stream(settings, callback)?;
+        stream.start()?;
+        self.input_stream = Some(stream);
+        Ok(corrector)
+    }
+
+    fn find_device(&self, name: &str) -> Result<portaudio::DeviceIndex> {
+        for device in self.pa()?.devices()? {
+            let (index, info) = device?;
+            if info.max_output_channels >= 2 && info.name.contains(name) {
+                return Ok(index);
+            }
+        }
+        Err(anyhow!("no output device matching {}", name))
+    }
+
+    /// Starts rendering the engine held by the backend on the requested device. When
+    /// reconnecting, `fallback` allows the default device instead of a missing one, and
+    /// playing without the input device.
+    fn open(&mut self, fallback: bool) -> Result<()> {
+        let device = match self.device.clone() {
+            Some(name) => match self.find_device(&name) {
+                Ok(device) => device,
+                Err(_) if fallback => self.pa()?.default_output_device()?,
+                Err(err) => return Err(err),
+            },
+            None => self.pa()?.default_output_device()?,
+        };
+        let info = self.pa()?.device_info(device)?;
+        let name = info.name.to_string();
+        let latency = info.default_low_output_latency;
+        let default_sample_rate = info.default_sample_rate;
+        let params = portaudio::StreamParameters::<f32>::new(device, 2, true, latency);
+
+        self.reclaim();
+        let engine = self.engine.take();
+        let (home, returned) = RingBuffer::new(1).split();
+        let mut engine = Lease {
+            engine: Some(engine.ok_or_else(|| anyhow!("no engine to render"))?),
+            home,
+        };
+        self.returned = Some(returned);
+        let mut config = engine.config();
+        if self
+            .pa()?
+            .is_output_format_supported(params, config.sample_rate)
+            .is_err()
+        {
+            config.sample_rate = default_sample_rate;
+        }
+        engine.set_config(config);
+        let mut input = match self.input.clone() {
+            Some(name) => match self.start_input(&name, config) {
+                Ok(input) => Some(input),
+                Err(_) if fallback => None,
+                Err(err) => return Err(err),
+            },
+            None => None,
+        };
+
+        let mut settings =
+            portaudio::OutputStreamSettings::new(params, config.sample_rate, config.buffer_size);
+        settings.flags = paflags::CLIP_OFF;
+
+        self.callbacks.store(0, Ordering::Relaxed);
+        let callbacks = Arc::clone(&self.callbacks);
+        self.promotion.reset();
+        let promotion = self.promotion.clone();
+        // The host may ask for a different number of frames than requested, so render in
+        // chunks of at most MAX_FRAMES_PER_BUFFER.
+        let mut buf = vec![(0., 0.); MAX_FRAMES_PER_BUFFER];
+        let telemetry = engine.params().telemetry.clone();
+        let callback = move |OutputStreamCallbackArgs {
+                                 buffer,
+                                 frames,
+                                 flags,
+                                 ..
+                             }| {
+            callbacks.fetch_add(1, Ordering::Relaxed);
+            promotion.promote_once();
+            if flags.contains(paflags_callback::OUTPUT_UNDERFLOW) {
+                telemetry.count_xrun();
+            }
+            let mut offset = 0;
+            while offset < frames {
+                let len = usize::min(frames - offset, buf.len());
+                if let Some(input) = &mut input {
+                    input.read(&mut engine.input_mut(0)[..len]);
+                }
+                engine.render(&mut buf[..len]);
+
+                let mut i = offset * 2;
+                for frame in &mut buf[..len] {
+                    buffer[i] = frame.0;
+                    buffer[i + 1] = frame.1;
+                    i += 2;
+                    *frame = (0.0, 0.0);
+                }
+                offset += len;
+            }
+
+            portaudio::Continue
+        };
+
+        let mut stream = self.pa()?.open_non_blocking_stream(settings, callback)?;
+        stream.start()?;
+        self.stream = Some(stream);
+        self.device_name = name;
+        self.last_callback = (0, Instant::now());
+        Ok(())
+    }
+}
+
+impl AudioBackend for PortAudioBackend {
+    fn name(&self) -> &'static str {
+        "portaudio"
+    }
+
+    fn devices(&self) -> Result<Vec<DeviceInfo>> {
+        let pa = self.pa()?;
+        let default = pa.default_output_device().ok();
+        let mut devices = Vec::new();
+        for device in pa.devices()? {
+            let (index, info) = device?;
+            if info.max_output_channels < 1 {
+                continue;
+            }
+            let host = pa
+                .host_api_info(info.host_api)
+                .map_or("unknown", |host| host.name);
+            devices.push(DeviceInfo {
+                name: info.name.to_string(),
+                host: host.to_string(),
+                channels: info.max_output_channels as usize,
+                default_sample_rate: info.default_sample_rate,
+                is_default: Some(index) == default,
+            });
+        }
+        Ok(devices)
+    }
+
+    fn start(&mut self, engine: Engine, device: Option<&str>) -> Result<()> {
+        self.stop()?;
+        self.device = device.map(String::from);
+        self.engine = Some(engine);
+        self.returned = None;
+        self.lost = None;
+        self.open(false)
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        if let Some(mut stream) = self.stream.take() {
+            stream.stop()?;
+            stream.close()?;
+        }
+        if let Some(mut stream) = self.input_stream.take() {
+            stream.stop()?;
+            stream.close()?;
+        }
+        Ok(())
+    }
+
+    fn set_input(&mut self, device: &str) -> Result<()> {
+        self.input = Some(device.to_string());
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Option<DeviceEvent> {
+        if let Some(reason) = self.promotion.take_failure() {
+            return Some(DeviceEvent::NoRealtime(reason));
+        }
+        match self.lost {
+            None => {
+                let stream = self.stream.as_ref()?;
+                let count = self.callbacks.load(Ordering::Relaxed);
+                if count != self.last_callback.0 {
+                    self.last_callback = (count, Instant::now());
+                    return None;
+                }
+                let active = stream.is_active().unwrap_or(false);
+                if active && self.last_callback.1.elapsed() < STALL_TIME {
+                    return None;
+                }
+                // Dropping the stream hands the engine back, errors are expected from a
+                // device which is gone.
+                let _ = self.stop();
+                self.lost = Some(Instant::now());
+                Some(DeviceEvent::Lost(self.device_name.clone()))
+            }
+            Some(attempt) => {
+                self.reclaim();
+                if let Some(engine) = &mut self.engine {
+                    engine.run_commands();
+                }
+                if attempt.elapsed() < RETRY_INTERVAL {
+                    return None;
+                }
+                self.lost = Some(Instant::now());
+                self.pa = None;
+                self.pa = PortAudio::new().ok();
+                self.open(true).ok()?;
+                self.lost = None;
+                Some(DeviceEvent::Reconnected(self.device_name.clone()))
+            }
+        }
+    }
+}
@@ -0,0 +1,547 @@
+//! JACK output, loaded at runtime so the JACK libraries are only needed when this backend is
+//! used.
+
+use super::{AudioBackend, DeviceEvent, DeviceInfo, RETRY_INTERVAL};
+use crate::engine::{Engine, EngineConfig, EngineParam, EngineParams};
+use crate::mixer::NUM_INPUTS;
+use crate::MAX_FRAMES_PER_BUFFER;
+use anyhow::{anyhow, Result};
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_ulong, c_void};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+const CLIENT_NAME: &str = "ruis";
+const AUDIO_TYPE: &str = "32 bit float mono audio";
+
+const PORT_IS_INPUT: c_ulong = 0x1;
+const PORT_IS_OUTPUT: c_ulong = 0x2;
+const PORT_IS_PHYSICAL: c_ulong = 0x4;
+const NO_START_SERVER: c_int = 0x01;
+
+const TRANSPORT_STOPPED: c_int = 0;
+const TRANSPORT_STARTING: c_int = 3;
+const POSITION_BBT: c_int = 0x10;
+
+const BEATS_PER_BAR: f64 = 4.0;
+const TICKS_PER_BEAT: f64 = 1920.0;
+
+type Client = *mut c_void;
+type Port = *mut c_void;
+type ProcessCallback = unsafe extern "C" fn(u32, *mut c_void) -> c_int;
+type TimebaseCallback = unsafe extern "C" fn(c_int, u32, *mut Position, c_int, *mut c_void);
+type ShutdownCallback = unsafe extern "C" fn(*mut c_void);
+type XrunCallback = unsafe extern "C" fn(*mut c_void) -> c_int;
+
+/// `jack_position_t`, which is a packed struct in the JACK headers.
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+struct Position {
+    unique_1: u64,
+    usecs: u64,
+    frame_rate: u32,
+    frame: u32,
+    valid: c_int,
+    bar: i32,
+    beat: i32,
+    tick: i32,
+    bar_start_tick: f64,
+    beats_per_bar: f32,
+    beat_type: f32,
+    ticks_per_beat: f64,
+    beats_per_minute: f64,
+    bbt_offset: u32,
+    audio_frames_per_video_frame: f32,
+    video_offset: u32,
+    padding: [i32; 7],
+    unique_2: u64,
+}
+
+/// The functions used from libjack, resolved with `dlsym`.
+#[derive(Copy, Clone)]
+struct Api {
+    client_open: unsafe extern "C" fn(*const c_char, c_int, *mut c_int, ...) -> Client,
+    client_close: unsafe extern "C" fn(Client) -> c_int,
+    get_sample_rate: unsafe extern "C" fn(Client) -> u32,
+    get_buffer_size: unsafe extern "C" fn(Client) -> u32,
+    port_register:
+        unsafe extern "C" fn(Client, *const c_char, *const c_char, c_ulong, c_ulong) -> Port,
+    port_name: unsafe extern "C" fn(Port) -> *const c_char,
+    port_get_buffer: unsafe extern "C" fn(Port, u32) -> *mut c_void,
+    get_ports:
+        unsafe extern "C" fn(Client, *const c_char, *const c_char, c_ulong) -> *mut *const c_char,
+    connect: unsafe extern "C" fn(Client, *const c_char, *const c_char) -> c_int,
+    free: unsafe extern "C" fn(*mut c_void),
+    set_process_callback: unsafe extern "C" fn(Client, ProcessCallback, *mut c_void) -> c_int,
+    set_timebase_callback:
+        unsafe extern "C" fn(Client, c_int, TimebaseCallback, *mut c_void) -> c_int,
+    release_timebase: unsafe extern "C" fn(Client) -> c_int,
+    is_realtime: unsafe extern "C" fn(Client) -> c_int,
+    on_shutdown: unsafe extern "C" fn(Client, ShutdownCallback, *mut c_void),
+    set_xrun_callback: unsafe extern "C" fn(Client, XrunCallback, *mut c_void) -> c_int,
+    activate: unsafe extern "C" fn(Client) -> c_int,
+    deactivate: unsafe extern "C" fn(Client) -> c_int,
+    transport_query: unsafe extern "C" fn(Client, *mut Position) -> c_int,
+    transport_start: unsafe extern "C" fn(Client),
+    transport_stop: unsafe extern "C" fn(Client),
+    transport_locate: unsafe extern "C" fn(Client, u32) -> c_int,
+}
+
+impl Api {
+    fn load() -> Result<Self> {
+        let lib = ["libjack.so.0", "libjack.so", "libjack.0.dylib"]
+            .iter()
+            .map(|name| CString::new(*name).unwrap())
+            .map(|name| unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW) })
+            .find(|lib| !lib.is_null())
+            .ok_or_else(|| anyhow!("unable to load the JACK library"))?;
+
+        Ok(Self {
+            client_open: unsafe { symbol(lib, "jack_client_open")? },
+            client_close: unsafe { symbol(lib, "jack_client_close")? },
+            get_sample_rate: unsafe { symbol(lib, "jack_get_sample_rate")? },
+            get_buffer_size: unsafe { symbol(lib, "jack_get_buffer_size")? },
+            port_register: unsafe { symbol(lib, "jack_port_register")? },
+            port_name: unsafe { symbol(lib, "jack_port_name")? },
+            port_get_buffer: unsafe { symbol(lib, "jack_port_get_buffer")? },
+            get_ports: unsafe { symbol(lib, "jack_get_ports")? },
+            connect: unsafe { symbol(lib, "jack_connect")? },
+            free: unsafe { symbol(lib, "jack_free")? },
+            set_process_callback: unsafe { symbol(lib, "jack_set_process_callback")? },
+            set_timebase_callback: unsafe { symbol(lib, "jack_set_timebase_callback")? },
+            release_timebase: unsafe { symbol(lib, "jack_release_timebase")? },
+            is_realtime: unsafe { symbol(lib, "jack_is_realtime")? },
+            on_shutdown: unsafe { symbol(lib, "jack_on_shutdown")? },
+            set_xrun_callback: unsafe { symbol(lib, "jack_set_xrun_callback")? },
+            activate: unsafe { symbol(lib, "jack_activate")? },
+            deactivate: unsafe { symbol(lib, "jack_deactivate")? },
+            transport_query: unsafe { symbol(lib, "jack_transport_query")? },
+            transport_start: unsafe { symbol(lib, "jack_transport_start")? },
+            transport_stop: unsafe { symbol(lib, "jack_transport_stop")? },
+            transport_locate: unsafe { symbol(lib, "jack_transport_locate")? },
+        })
+    }
+
+    fn open(&self) -> Result<Client> {
+        let name = CString::new(CLIENT_NAME).unwrap();
+        let mut status = 0;
+        let client = unsafe { (self.client_open)(name.as_ptr(), NO_START_SERVER, &mut status) };
+        if client.is_null() {
+            return Err(anyhow!(
+                "unable to connect to the JACK server ({:#x})",
+                status
+            ));
+        }
+        Ok(client)
+    }
+
+    /// Returns the names of the ports matching `pattern` and `flags`.
+    fn ports(&self, client: Client, pattern: &str, flags: c_ulong) -> Vec<String> {
+        let pattern = CString::new(pattern).unwrap();
+        let audio = CString::new(AUDIO_TYPE).unwrap();
+        let list = unsafe { (self.get_ports)(client, pattern.as_ptr(), audio.as_ptr(), flags) };
+        let mut ports = Vec::new();
+        if list.is_null() {
+            return ports;
+        }
+        unsafe {
+            let mut i = 0;
+            while !(*list.add(i)).is_null() {
+                ports.push(CStr::from_ptr(*list.add(i)).to_string_lossy().into_owned());
+                i += 1;
+            }
+            (self.free)(list as *mut c_void);
+        }
+        ports
+    }
+}
+
+/// Looks up a function in a library opened with `dlopen`. `T` must be a function pointer type.
+unsafe fn symbol<T: Copy>(lib: *mut c_void, name: &str) -> Result<T> {
+    let c_name = CString::new(name).unwrap();
+    let sym = libc::dlsym(lib, c_name.as_ptr());
+    if sym.is_null() {
+        return Err(anyhow!("missing JACK function {}", name));
+    }
+    Ok(std::mem::transmute_copy(&sym))
+}
+
+/// State shared with the JACK callbacks.
+struct Process {
+    api: Api,
+    client: Client,
+    ports: [Port; 2],
+    /// Left and right port of every hardware input.
+    inputs: Vec<(Port, Port)>,
+    engine: Engine,
+    params: EngineParams,
+    buf: Vec<(f32, f32)>,
+    is_master: bool,
+    was_playing: bool,
+    was_rolling: bool,
+    /// Transport frame expected at the next cycle, used to detect relocations.
+    next_frame: Option<u32>,
+    /// Set when the server goes away, e.g. when it's restarted.
+    shut_down: AtomicBool,
+}
+
+impl Process {
+    fn process(&mut self, frames: u32) {
+        let mut pos: Position = unsafe { std::mem::zeroed() };
+        let state = unsafe { (self.api.transport_query)(self.client, &mut pos) };
+        self.sync_transport(state, &pos, frames);
+
+        let left = unsafe { (self.api.port_get_buffer)(self.ports[0], frames) } as *mut f32;
+        let right = unsafe { (self.api.port_get_buffer)(self.ports[1], frames) } as *mut f32;
+        let left = unsafe { std::slice::from_raw_parts_mut(left, frames as usize) };
+        let right = unsafe { std::slice::from_raw_parts_mut(right, frames as usize) };
+
+        let nframes = frames;
+        let frames = frames as usize;
+        let mut offset = 0;
+        while offset < frames {
+            let len = usize::min(frames - offset, self.buf.len());
+            for (i, ports) in self.inputs.iter().enumerate() {
+                let (left, right) = unsafe {
+                    let left = (self.api.port_get_buffer)(ports.0, nframes) as *const f32;
+                    let right = (self.api.port_get_buffer)(ports.1, nframes) as *const f32;
+                    (
+                        std::slice::from_raw_parts(left.add(offset), len),
+                        std::slice::from_raw_parts(right.add(offset), len),
+                    )
+                };
+                let input = &mut self.engine.input_mut(i)[..len];
+                for (frame, (left, right)) in input.iter_mut().zip(left.iter().zip(right)) {
+                    *frame = (*left, *right);
+                }
+            }
+            self.engine.render(&mut self.buf[..len]);
+            for (i, frame) in self.buf[..len].iter_mut().enumerate() {
+                left[offset + i] = frame.0;
+                right[offset + i] = frame.1;
+                *frame = (0.0, 0.0);
+            }
+            offset += len;
+        }
+    }
+
+    /// Follows the JACK transport, and drives it when playback is toggled or moved from the
+    /// app. Seeks relocate the JACK transport, the engine follows once it reports the new frame.
+    fn sync_transport(&mut self, state: c_int, pos: &Position, frames: u32) {
+        if let Some(line) = self.params.transport.take_seek() {
+            let frame = u32::try_from(self.engine.line_frame(line)).unwrap_or(u32::MAX);
+            unsafe { (self.api.transport_locate)(self.client, frame) };
+        }
+        let is_playing = self.params.transport.is_playing();
+        let rolling = state != TRANSPORT_STOPPED && state != TRANSPORT_STARTING;
+
+        if is_playing != self.was_playing {
+            if is_playing {
+                unsafe { (self.api.transport_start)(self.client) };
+            } else {
+                unsafe { (self.api.transport_stop)(self.client) };
+            }
+        } else if rolling != self.was_rolling {
+            if rolling {
+                self.params.transport.play();
+            } else {
+                self.params.transport.pause();
+            }
+        }
+        self.was_rolling = rolling;
+        self.was_playing = self.params.transport.is_playing();
+
+        let frame = pos.frame;
+        if self.next_frame != Some(frame) {
+            self.engine.locate(frame as u64);
+        }
+        self.next_frame = Some(if rolling { frame + frames } else { frame });
+
+        let valid = pos.valid;
+        let bpm = pos.beats_per_minute;
+        if !self.is_master && valid & POSITION_BBT != 0 && bpm > 0.0 {
+            self.params.set(EngineParam::Bpm, bpm.round() as u16);
+        }
+    }
+
+    /// Fills in bars and beats when we are the timebase master.
+    fn timebase(&self, pos: &mut Position) {
+        let bpm = self.params.get(EngineParam::Bpm) as f64;
+        let beats = pos.frame as f64 * bpm / (60.0 * self.engine.config().sample_rate);
+        let bar = (beats / BEATS_PER_BAR).floor();
+        let beat = (beats - bar * BEATS_PER_BAR).floor();
+
+        pos.valid = POSITION_BBT;
+        pos.bar = bar as i32 + 1;
+        pos.beat = beat as i32 + 1;
+        pos.tick = (beats.fract() * TICKS_PER_BEAT) as i32;
+        pos.bar_start_tick = bar * BEATS_PER_BAR * TICKS_PER_BEAT;
+        pos.beats_per_bar = BEATS_PER_BAR as f32;
+        pos.beat_type = 4.0;
+        pos.ticks_per_beat = TICKS_PER_BEAT;
+        pos.beats_per_minute = bpm;
+    }
+}
+
+unsafe extern "C" fn process_callback(frames: u32, arg: *mut c_void) -> c_int {
+    let process = &mut *(arg as *mut Process);
+    process.process(frames);
+    0
+}
+
+unsafe extern "C" fn timebase_callback(
+    _state: c_int,
+    _frames: u32,
+    pos: *mut Position,
+    _new_pos: c_int,
+    arg: *mut c_void,
+) {
+    let process = &*(arg as *const Process);
+    process.timebase(&mut *pos);
+}
+
+unsafe extern "C" fn shutdown_callback(arg: *mut c_void) {
+    let process = &*(arg as *const Process);
+    process.shut_down.store(true, Ordering::Release);
+}
+
+unsafe extern "C" fn xrun_callback(arg: *mut c_void) -> c_int {
+    let process = &*(arg as *const Process);
+    process.params.telemetry.count_xrun();
+    0
+}
+
+/// Registers a stereo pair of output ports, a pair of input ports per hardware input, and
+/// follows the JACK transport. Tempo is published
+/// through the timebase API, unless another client already is the timebase master in which
+/// case its tempo is used. When the server shuts down, the client is opened again once it's
+/// back.
+#[derive(Default)]
+pub struct JackBackend {
+    process: Option<Box<Process>>,
+    /// Holds the engine while no client renders it.
+    engine: Option<Engine>,
+    /// Client the outputs are connected to, requested by name.
+    device: Option<String>,
+    /// Time of the last attempt to reconnect, while the server is gone.
+    lost: Option<Instant>,
+    /// Set when the server doesn't run its clients with a realtime priority.
+    not_realtime: bool,
+}
+
+impl JackBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn device_name(&self) -> String {
+        self.device.clone().unwrap_or_else(|| String::from("JACK"))
+    }
+
+    /// Opens a client rendering the engine held by the backend. When reconnecting,
+    /// `fallback` allows the physical outputs instead of a missing client.
+    fn open(&mut self, fallback: bool) -> Result<()> {
+        let api = Api::load()?;
+        let client = api.open()?;
+        // The JACK server decides the rate and period size for all of its clients.
+        let engine = match self.engine.as_mut() {
+            Some(engine) => engine,
+            None => {
+                unsafe { (api.client_close)(client) };
+                return Err(anyhow!("no engine to render"));
+            }
+        };
+        engine.set_config(EngineConfig {
+            sample_rate: unsafe { (api.get_sample_rate)(client) } as f64,
+            buffer_size: unsafe { (api.get_buffer_size)(client) },
+            ..engine.config()
+        });
+
+        let register = |name: &str, flags| {
+            let name = CString::new(name).unwrap();
+            let audio = CString::new(AUDIO_TYPE).unwrap();
+            let port =
+                unsafe { (api.port_register)(client, name.as_ptr(), audio.as_ptr(), flags, 0) };
+            if port.is_null() {
+                unsafe { (api.client_close)(client) };
+                return Err(anyhow!("unable to register JACK port"));
+            }
+            Ok(port)
+        };
+        let ports = [
+            register("out_left", PORT_IS_OUTPUT)?,
+            register("out_right", PORT_IS_OUTPUT)?,
+        ];
+        let mut inputs = Vec::with_capacity(NUM_INPUTS);
+        for i in 1..=NUM_INPUTS {
+            inputs.push((
+                register(&format!("in_{}_left", i), PORT_IS_INPUT)?,
+                register(&format!("in_{}_right", i), PORT_IS_INPUT)?,
+            ));
+        }
+
+        let capture: Vec<Port> = inputs
+            .iter()
+            .flat_map(|(left, right)| [*left, *right])
+            .collect();
+
+        let params = engine.params().clone();
+        let mut process = Box::new(Process {
+            api,
+            client,
+            ports,
+            inputs,
+            engine: self.engine.take().unwrap(),
+            params,
+            buf: vec![(0., 0.); MAX_FRAMES_PER_BUFFER],
+            is_master: false,
+            was_playing: false,
+            was_rolling: false,
+            next_frame: None,
+            shut_down: AtomicBool::new(false),
+        });
+        let arg = process.as_mut() as *mut Process as *mut c_void;
+        let api = process.api;
+        unsafe {
+            // Conditional, so an existing timebase master keeps control of the tempo.
+            process.is_master = (api.set_timebase_callback)(client, 1, timebase_callback, arg) == 0;
+            (api.on_shutdown)(client, shutdown_callback, arg);
+            (api.set_xrun_callback)(client, xrun_callback, arg);
+            if (api.set_process_callback)(client, process_callback, arg) != 0
+                || (api.activate)(client) != 0
+            {
+                self.process = Some(process);
+                self.stop()?;
+                return Err(anyhow!("unable to activate JACK client"));
+            }
+        }
+
+        // JACK runs the process callback on its own thread, realtime unless started without.
+        self.not_realtime = unsafe { (api.is_realtime)(client) } == 0;
+
+        let physical = api.ports(client, "", PORT_IS_INPUT | PORT_IS_PHYSICAL);
+        let destinations = match &self.device {
+            Some(name) => {
+                let pattern = format!("^{}:", name);
+                match api.ports(client, &pattern, PORT_IS_INPUT) {
+                    ports if !ports.is_empty() => ports,
+                    _ if fallback => physical,
+                    _ => {
+                        self.process = Some(process);
+                        self.stop()?;
+                        return Err(anyhow!("no JACK client matching {}", pattern));
+                    }
+                }
+            }
+            None => physical,
+        };
+        for (port, destination) in ports.iter().zip(destinations) {
+            let destination = CString::new(destination).unwrap();
+            unsafe {
+                (api.connect)(client, (api.port_name)(*port), destination.as_ptr());
+            }
+        }
+        // Inputs are fed by the system's capture ports, in order.
+        let sources = api.ports(client, "", PORT_IS_OUTPUT | PORT_IS_PHYSICAL);
+        for (port, source) in capture.iter().zip(sources) {
+            let source = CString::new(source).unwrap();
+            unsafe {
+                (api.connect)(client, source.as_ptr(), (api.port_name)(*port));
+            }
+        }
+
+        self.process = Some(process);
+        Ok(())
+    }
+}
+
+impl AudioBackend for JackBackend {
+    fn name(&self) -> &'static str {
+        "jack"
+    }
+
+    fn devices(&self) -> Result<Vec<DeviceInfo>> {
+        let api = Api::load()?;
+        let client = api.open()?;
+        let sample_rate = unsafe { (api.get_sample_rate)(client) };
+        let ports = api.ports(client, "", PORT_IS_INPUT);
+        let physical = api.ports(client, "", PORT_IS_INPUT | PORT_IS_PHYSICAL);
+        unsafe { (api.client_close)(client) };
+
+        // Every client with input ports is a possible destination.
+        let mut devices: Vec<DeviceInfo> = Vec::new();
+        for port in ports {
+            let name = port.split(':').next().unwrap_or(&port).to_string();
+            match devices.iter_mut().find(|d| d.name == name) {
+                Some(device) => device.channels += 1,
+                None => devices.push(DeviceInfo {
+                    is_default: physical.contains(&port),
+                    name,
+                    host: String::from("JACK"),
+                    channels: 1,
+                    default_sample_rate: sample_rate as f64,
+                }),
+            }
+        }
+        Ok(devices)
+    }
+
+    fn start(&mut self, engine: Engine, device: Option<&str>) -> Result<()> {
+        self.stop()?;
+        self.device = device.map(String::from);
+        self.engine = Some(engine);
+        self.lost = None;
+        self.open(false)
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        if let Some(process) = self.process.take() {
+            let process = *process;
+            let api = process.api;
+            unsafe {
+                // Only closing is left to do once the server is gone.
+                if !process.shut_down.load(Ordering::Acquire) {
+                    if process.is_master {
+                        (api.release_timebase)(process.client);
+                    }
+                    (api.deactivate)(process.client);
+                }
+                (api.client_close)(process.client);
+            }
+            self.engine = Some(process.engine);
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Option<DeviceEvent> {
+        if std::mem::take(&mut self.not_realtime) {
+            return Some(DeviceEvent::NoRealtime(String::from(
+                "the JACK server runs without realtime scheduling",
+            )));
+        }
+        match self.lost {
+            None => {
+                let process = self.process.as_ref()?;
+                if !process.shut_down.load(Ordering::Acquire) {
+                    return None;
+                }
+                let _ = self.stop();
+                self.lost = Some(Instant::now());
+                Some(DeviceEvent::Lost(self.device_name()))
+            }
+            Some(attempt) => {
+                if let Some(engine) = &mut self.engine {
+                    engine.run_commands();
+                }
+                if attempt.elapsed() < RETRY_INTERVAL {
+                    return None;
+                }
+                self.lost = Some(Instant::now());
+                self.open(true).ok()?;
+                self.lost = None;
+                Some(DeviceEvent::Reconnected(self.device_name()))
+            }
+        }
+    }
+}
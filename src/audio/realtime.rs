@@ -0,0 +1,194 @@
+//! Realtime scheduling of the audio thread, for hosts which don't already run their callbacks
+//! with a realtime priority. Without it, the callback competes with everything else running
+//! and buffers underrun under load.
+
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
+
+/// Below the priority of the JACK and PipeWire threads, which feed the device.
+#[cfg(all(unix, not(target_os = "macos")))]
+const PRIORITY: i32 = 70;
+/// Niceness of the background threads, which must not take time from the UI.
+#[cfg(target_os = "linux")]
+const BACKGROUND_NICENESS: i32 = 10;
+
+const PENDING: i32 = -1;
+const DONE: i32 = 0;
+const REPORTED: i32 = -2;
+
+/// Asks for the calling thread to be scheduled as realtime. Returns the OS error code when
+/// it's refused, usually for lack of the permission.
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn promote() -> Result<(), i32> {
+    unsafe {
+        let thread = libc::pthread_self();
+        let mut policy = 0;
+        let mut param: libc::sched_param = std::mem::zeroed();
+        // Some hosts, e.g. ALSA, may already have promoted it.
+        if libc::pthread_getschedparam(thread, &mut policy, &mut param) == 0
+            && (policy == libc::SCHED_FIFO || policy == libc::SCHED_RR)
+        {
+            return Ok(());
+        }
+        param.sched_priority = PRIORITY;
+        match libc::pthread_setschedparam(thread, libc::SCHED_FIFO, &param) {
+            0 => Ok(()),
+            err => Err(err),
+        }
+    }
+}
+
+/// CoreAudio already runs its IO threads with a time constraint policy.
+#[cfg(target_os = "macos")]
+pub fn promote() -> Result<(), i32> {
+    Ok(())
+}
+
+#[cfg(windows)]
+#[link(name = "avrt")]
+extern "system" {
+    fn AvSetMmThreadCharacteristicsW(task: *const u16, index: *mut u32) -> *mut std::ffi::c_void;
+}
+
+#[cfg(windows)]
+#[link(name = "kernel32")]
+extern "system" {
+    fn GetLastError() -> u32;
+}
+
+/// Registers the calling thread with MMCSS as a pro audio task.
+#[cfg(windows)]
+pub fn promote() -> Result<(), i32> {
+    let task: Vec<u16> = "Pro Audio\0".encode_utf16().collect();
+    let mut index = 0;
+    let handle = unsafe { AvSetMmThreadCharacteristicsW(task.as_ptr(), &mut index) };
+    if handle.is_null() {
+        Err(unsafe { GetLastError() } as i32)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn promote() -> Result<(), i32> {
+    Ok(())
+}
+
+/// Lowers the priority of the calling thread, for background work such as loading pages of
+/// samples. Best effort, failures are ignored.
+pub fn demote() {
+    // Linux schedules threads on their own, elsewhere this would affect the whole process.
+    #[cfg(target_os = "linux")]
+    unsafe {
+        libc::setpriority(libc::PRIO_PROCESS, 0, BACKGROUND_NICENESS);
+    }
+}
+
+/// Outcome of the promotion of an audio thread, written by its first callback and read by the
+/// backend.
+#[derive(Clone)]
+pub struct Promotion(Arc<AtomicI32>);
+
+impl Promotion {
+    pub fn new() -> Self {
+        Self(Arc::new(AtomicI32::new(PENDING)))
+    }
+
+    /// To be called again for a new audio thread.
+    pub fn reset(&self) {
+        self.0.store(PENDING, Ordering::Release);
+    }
+
+    /// Promotes the calling thread, the first time only.
+    pub fn promote_once(&self) {
+        if self.0.load(Ordering::Acquire) == PENDING {
+            let code = match promote() {
+                Ok(()) => DONE,
+                Err(code) => code,
+            };
+            self.0.store(code, Ordering::Release);
+        }
+    }
+
+    /// Returns why the promotion failed, once.
+    pub fn take_failure(&self) -> Option<String> {
+        match self.0.load(Ordering::Acquire) {
+            PENDING | DONE | REPORTED => None,
+            code => {
+                self.0.store(REPORTED, Ordering::Release);
+                Some(std::io::Error::from_raw_os_error(code).to_string())
+            }
+        }
+    }
+}
+
+/// Marks the calling thread as rendering until the guard is dropped. With the `alloc-check`
+/// feature, allocating or freeing memory meanwhile aborts the program, otherwise it does
+/// nothing.
+pub fn rendering() -> Rendering {
+    #[cfg(feature = "alloc-check")]
+    alloc_check::set_rendering(true);
+    Rendering(())
+}
+
+pub struct Rendering(());
+
+impl Drop for Rendering {
+    fn drop(&mut self) {
+        #[cfg(feature = "alloc-check")]
+        alloc_check::set_rendering(false);
+    }
+}
+
+/// The system allocator, checking that the audio thread doesn't use it while it renders.
+#[cfg(feature = "alloc-check")]
+mod alloc_check {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::backtrace::Backtrace;
+    use std::cell::Cell;
+
+    thread_local! {
+        static RENDERING: Cell<bool> = const { Cell::new(false) };
+    }
+
+    struct CheckedAlloc;
+
+    #[global_allocator]
+    static ALLOCATOR: CheckedAlloc = CheckedAlloc;
+
+    pub fn set_rendering(rendering: bool) {
+        RENDERING.with(|r| r.set(rendering));
+    }
+
+    fn check(what: &str) {
+        // The thread local is gone while the thread exits, nothing renders then.
+        if RENDERING.try_with(|r| r.replace(false)).unwrap_or(false) {
+            // An allocator mustn't unwind, so this aborts instead of panicking. The flag is
+            // cleared, printing the report may allocate.
+            eprintln!("{} while rendering\n{}", what, Backtrace::force_capture());
+            std::process::abort();
+        }
+    }
+
+    unsafe impl GlobalAlloc for CheckedAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            check("allocation");
+            System.alloc(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            check("allocation");
+            System.alloc_zeroed(layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            check("reallocation");
+            System.realloc(ptr, layout, new_size)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            check("deallocation");
+            System.dealloc(ptr, layout)
+        }
+    }
+}
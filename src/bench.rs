@@ -0,0 +1,145 @@
+//! Measures how fast the audio path renders, to check optimizations: `ruis bench`. Everything
+//! renders offline, no audio device is opened.
+
+use crate::app::AppCommand;
+use crate::engine::{Device, Engine, EngineCommand, EngineConfig, EngineParams, MAX_INSTRUMENTS};
+use crate::env::Envelope;
+use crate::filter::FilterMode;
+use crate::instrument::Instrument;
+use crate::sampler::{Sampler, Sound, ROOT_PITCH};
+use anyhow::Result;
+use ringbuf::RingBuffer;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Length of audio rendered by each benchmark.
+const SECONDS: f64 = 20.0;
+const BUFFER_SIZE: usize = 256;
+/// Render threads of the engine, at most.
+const MAX_THREADS: usize = 3;
+
+pub fn run() -> Result<()> {
+    let config = EngineConfig::default();
+    let num_frames = (SECONDS * config.sample_rate) as usize;
+    println!("rendering {}s at {} Hz", SECONDS, config.sample_rate);
+
+    let sound = Arc::new(noise(2 * num_frames, config.sample_rate as u32));
+    let plain = Sampler::with_sound(Arc::clone(&sound));
+    report("sampler", sampler_time(plain, &config, num_frames));
+    let filtered = Sampler::with_sound(Arc::clone(&sound)).with_filter(FilterMode::LowPass);
+    report(
+        "filtered sampler",
+        sampler_time(filtered, &config, num_frames),
+    );
+
+    report("envelope", envelope_time(&config, num_frames, false));
+    report("envelope, blocks", envelope_time(&config, num_frames, true));
+
+    report("engine", engine_time(&config, num_frames, &sound, 0));
+    let threads = thread::available_parallelism().map_or(1, |n| n.get()) - 1;
+    let threads = usize::min(threads, MAX_THREADS);
+    if threads > 0 {
+        let name = format!("engine, {} threads", threads);
+        report(&name, engine_time(&config, num_frames, &sound, threads));
+    }
+    Ok(())
+}
+
+/// Plays a note on every voice of `sampler`.
+fn sampler_time(mut sampler: Sampler, config: &EngineConfig, num_frames: usize) -> Duration {
+    sampler.prepare(config);
+    for voice in 0..sampler.num_voices() {
+        sampler.note_on(voice, ROOT_PITCH + voice as u8, 100);
+    }
+    let mut buffer = vec![(0.0, 0.0); BUFFER_SIZE];
+    time(num_frames, |len| sampler.render(&mut buffer[..len]))
+}
+
+/// Plays a note on every voice of a sampler on every track, with `threads` to help render
+/// them.
+fn engine_time(
+    config: &EngineConfig,
+    num_frames: usize,
+    sound: &Arc<Sound>,
+    threads: usize,
+) -> Duration {
+    let config = EngineConfig { threads, ..*config };
+    let (mut engine_send, engine_rcv) = RingBuffer::<EngineCommand>::new(MAX_INSTRUMENTS).split();
+    let (app_send, _app_rcv) = RingBuffer::<AppCommand>::new(16).split();
+    let mut engine = Engine::new(config, EngineParams::default(), engine_rcv, app_send);
+    for i in 0..MAX_INSTRUMENTS {
+        let mut sampler = Sampler::with_sound(Arc::clone(sound));
+        sampler.prepare(&config);
+        for voice in 0..sampler.num_voices() {
+            sampler.note_on(voice, ROOT_PITCH + voice as u8, 100);
+        }
+        let instrument: Box<dyn Instrument> = Box::new(sampler);
+        let _ = engine_send.push(EngineCommand::SetInstrument(i, Some(instrument)));
+    }
+    let mut buffer = vec![(0.0, 0.0); BUFFER_SIZE];
+    time(num_frames, |len| {
+        engine.render(&mut buffer[..len]);
+        buffer[..len]
+            .iter_mut()
+            .for_each(|frame| *frame = (0.0, 0.0));
+    })
+}
+
+/// Runs an envelope through notes a second long, reading its values one at a time or a
+/// buffer at once.
+fn envelope_time(config: &EngineConfig, num_frames: usize, per_block: bool) -> Duration {
+    let sample_rate = config.sample_rate as f32;
+    let mut env = Envelope::new(sample_rate);
+    env.set_adsr(0.2, 0.3, 0.5, 0.3);
+    let mut buffer = vec![0.0; BUFFER_SIZE];
+    let mut rendered = 0;
+    time(num_frames, |len| {
+        let second = rendered % config.sample_rate as usize;
+        if second < len {
+            env.start_attack_from(env.level());
+        } else if (second..second + len).contains(&(config.sample_rate as usize / 2)) {
+            env.start_release();
+        }
+        if per_block {
+            env.fill(&mut buffer[..len]);
+        } else {
+            buffer[..len].iter_mut().for_each(|v| *v = env.value());
+        }
+        rendered += len;
+    })
+}
+
+/// Calls `render` with the length of every buffer until `num_frames` are rendered.
+fn time<F: FnMut(usize)>(num_frames: usize, mut render: F) -> Duration {
+    let start = Instant::now();
+    let mut rendered = 0;
+    while rendered < num_frames {
+        let len = usize::min(BUFFER_SIZE, num_frames - rendered);
+        render(len);
+        rendered += len;
+    }
+    start.elapsed()
+}
+
+/// Prints the render time and the share of a core it takes to play in realtime.
+fn report(name: &str, elapsed: Duration) {
+    let load = elapsed.as_secs_f64() / SECONDS;
+    println!(
+        "{:<24}{:>10.1?}{:>9.3}% of a core",
+        name,
+        elapsed,
+        load * 100.0
+    );
+}
+
+/// White noise, so the renders can't be optimized away.
+fn noise(num_frames: usize, sample_rate: u32) -> Sound {
+    let mut state: u32 = 1;
+    let mut next = move || {
+        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (state >> 8) as f32 / (1 << 23) as f32 - 1.0
+    };
+    let frames = (0..num_frames).map(|_| (next(), next())).collect();
+    Sound::from_frames(frames, sample_rate)
+}
@@ -0,0 +1,204 @@
+use crate::app::AppCommand;
+use crate::engine::{
+    Engine, EngineCommand, EngineConfig, EngineParam, EngineParams, MAX_INSTRUMENTS,
+};
+use crate::instrument::{Instrument, Quality};
+use crate::mixer::{bus_name, return_channel, Mixer, Source, NUM_BUSES};
+use crate::pattern::Editor;
+use anyhow::{anyhow, Result};
+use camino::Utf8Path;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use ringbuf::RingBuffer;
+
+const BLOCK_SIZE: usize = 1024;
+const BEATS_PER_BAR: usize = 4;
+
+pub struct BounceSettings {
+    /// Number of bars to render, defaults to the length of the current pattern.
+    pub bars: Option<usize>,
+    pub bit_depth: u16,
+    pub sample_rate: u32,
+    /// Re-clock the song to a different tempo, e.g. to prepare it for a DJ set.
+    pub bpm: Option<u16>,
+    /// Also write every instrument to its own file next to the mix.
+    pub stems: bool,
+}
+
+impl Default for BounceSettings {
+    fn default() -> Self {
+        Self {
+            bars: None,
+            bit_depth: 24,
+            sample_rate: EngineConfig::default().sample_rate as u32,
+            bpm: None,
+            stems: false,
+        }
+    }
+}
+
+/// Renders the current pattern as fast as possible and writes the stereo mix to a WAV file.
+/// When rendering stems, every instrument is written to `<name>-<index>.wav` and every aux bus
+/// in use to `<name>-return-<bus>.wav` as well. Stems are taken after the mixer channel
+/// strips and before the master channel.
+pub fn bounce(
+    editor: &Editor,
+    instruments: Vec<Option<Box<dyn Instrument>>>,
+    mut mixer: Mixer,
+    bpm: u16,
+    lines_per_beat: u16,
+    settings: &BounceSettings,
+    path: &Utf8Path,
+) -> Result<()> {
+    let sample_format = match settings.bit_depth {
+        16 | 24 => SampleFormat::Int,
+        32 => SampleFormat::Float,
+        bits => return Err(anyhow!("unsupported bit depth {}", bits)),
+    };
+    if settings.sample_rate == 0 {
+        return Err(anyhow!("invalid sample rate"));
+    }
+    let bpm = settings.bpm.unwrap_or(bpm);
+    if bpm == 0 {
+        return Err(anyhow!("invalid tempo"));
+    }
+    let spec = WavSpec {
+        channels: 2,
+        sample_rate: settings.sample_rate,
+        bits_per_sample: settings.bit_depth,
+        sample_format,
+    };
+
+    let (mut engine_send, engine_rcv) = RingBuffer::<EngineCommand>::new(MAX_INSTRUMENTS).split();
+    let (app_send, mut app_rcv) = RingBuffer::<AppCommand>::new(16).split();
+
+    let mut params = EngineParams::default();
+    params.set(EngineParam::Bpm, bpm);
+    params.set(EngineParam::LinesPerBeat, lines_per_beat);
+    params.transport.play();
+
+    // Render at the output rate directly instead of resampling afterwards.
+    let config = EngineConfig {
+        sample_rate: settings.sample_rate as f64,
+        buffer_size: BLOCK_SIZE as u32,
+        ..EngineConfig::default()
+    };
+    let mut engine = Engine::new(config, params, engine_rcv, app_send);
+    mixer.prepare(&config);
+    // Renders start from silence, whatever the mixer and instruments went through before.
+    mixer.reset();
+    mixer.set_tempo(bpm as f32);
+    engine.load_editor(editor.clone());
+    // Hardware inputs are silent offline, but channels fed by a bus are rendered.
+    let loaded: Vec<usize> = (0..instruments.len())
+        .filter(|i| match mixer.params().channels[*i].source() {
+            Source::Instrument => instruments[*i].is_some(),
+            Source::Input(_) => false,
+            Source::Bus(_) => true,
+        })
+        .collect();
+    let returns: Vec<usize> = (0..NUM_BUSES)
+        .filter(|bus| mixer.params().bus_in_use(*bus))
+        .collect();
+    for (i, mut instrument) in instruments.into_iter().enumerate() {
+        if let Some(instrument) = &mut instrument {
+            instrument.set_quality(Quality::Offline);
+            instrument.reset();
+        }
+        if instrument.is_some()
+            && engine_send
+                .push(EngineCommand::SetInstrument(i, instrument))
+                .is_err()
+        {
+            return Err(anyhow!("unable to load instrument {} for bounce", i));
+        }
+    }
+
+    let lines_per_bar = BEATS_PER_BAR * lines_per_beat as usize;
+    let bars = settings
+        .bars
+        .unwrap_or_else(|| editor.num_lines().div_ceil(lines_per_bar));
+    let samples_per_line = (config.sample_rate * 60.) / (lines_per_beat * bpm) as f64;
+    let num_frames = bars * lines_per_bar * samples_per_line.round() as usize;
+
+    let num_channels = return_channel(NUM_BUSES);
+    let mut stems: Vec<Vec<(f32, f32)>> = (0..num_channels).map(|_| Vec::new()).collect();
+    for &i in &loaded {
+        stems[i].reserve(num_frames);
+    }
+    for &bus in &returns {
+        stems[return_channel(bus)].reserve(num_frames);
+    }
+    let mut bufs = vec![vec![(0., 0.); BLOCK_SIZE]; num_channels];
+    let mut rendered = 0;
+    while rendered < num_frames {
+        let len = usize::min(BLOCK_SIZE, num_frames - rendered);
+        engine.render_stems(&mut bufs, len);
+        mixer.begin();
+        for i in mixer.order().iter().copied() {
+            if loaded.contains(&i) {
+                if let Source::Bus(_) = mixer.params().channels[i].source() {
+                    mixer.read_source(i, 0, &mut bufs[i][..len]);
+                }
+                mixer.process(i, 0, &mut bufs[i][..len]);
+            }
+        }
+        for &bus in &returns {
+            mixer.render_return(bus, &mut bufs[return_channel(bus)][..len]);
+        }
+        let channels = loaded.iter().copied();
+        for i in channels.chain(returns.iter().map(|bus| return_channel(*bus))) {
+            stems[i].extend_from_slice(&bufs[i][..len]);
+            for frame in &mut bufs[i][..len] {
+                *frame = (0.0, 0.0);
+            }
+        }
+        rendered += len;
+        while app_rcv.pop().is_some() {}
+    }
+
+    let mut mix = vec![(0., 0.); num_frames];
+    for stem in &stems {
+        for (out, frame) in mix.iter_mut().zip(stem) {
+            out.0 += frame.0;
+            out.1 += frame.1;
+        }
+    }
+    for block in mix.chunks_mut(BLOCK_SIZE) {
+        mixer.process_master(block);
+    }
+    write_wav(path, spec, &mix)?;
+
+    if settings.stems {
+        let stem_name = path.file_stem().unwrap_or("bounce");
+        for i in loaded {
+            let name = format!("{}-{:02}.wav", stem_name, i);
+            write_wav(&path.with_file_name(name), spec, &stems[i])?;
+        }
+        for bus in returns {
+            let name = format!("{}-return-{}.wav", stem_name, bus_name(bus));
+            write_wav(
+                &path.with_file_name(name),
+                spec,
+                &stems[return_channel(bus)],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+pub fn write_wav(path: &Utf8Path, spec: WavSpec, output: &[(f32, f32)]) -> Result<()> {
+    let mut writer = WavWriter::create(path, spec)?;
+    let scale = ((1i64 << (spec.bits_per_sample - 1)) - 1) as f32;
+    for (left, right) in output {
+        for sample in [left, right].iter() {
+            match spec.sample_format {
+                SampleFormat::Float => writer.write_sample(**sample)?,
+                SampleFormat::Int => {
+                    writer.write_sample((sample.clamp(-1.0, 1.0) * scale).round() as i32)?
+                }
+            }
+        }
+    }
+    writer.finalize()?;
+    Ok(())
+}
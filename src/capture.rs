@@ -0,0 +1,180 @@
+use crate::MAX_FRAMES_PER_BUFFER;
+use anyhow::{anyhow, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use ringbuf::{Consumer, Producer, RingBuffer};
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Frames buffered per channel, about six seconds at 44.1kHz. When the disk can't keep up
+/// for longer than that, frames are dropped.
+const BUFFER_FRAMES: usize = 1 << 18;
+const WRITE_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Engine side of a multitrack capture: every captured instrument is rendered into its own
+/// buffer and handed to the writer thread.
+pub struct Capture {
+    channels: Vec<(usize, Producer<(f32, f32)>)>,
+    scratch: Vec<Vec<(f32, f32)>>,
+    sample_rate: Arc<AtomicU32>,
+    dropped: Arc<AtomicUsize>,
+    finished: Arc<AtomicBool>,
+}
+
+impl Capture {
+    /// Prepares capturing the given instrument slots to `<dir>/capture-<index>.wav`. Nothing
+    /// is written until the capture is handed to the engine.
+    pub fn new(dir: &Utf8Path, slots: &[usize]) -> Result<(Self, CaptureWriter)> {
+        if slots.is_empty() {
+            return Err(anyhow!("nothing to capture"));
+        }
+        fs::create_dir_all(dir)?;
+
+        let mut channels = Vec::with_capacity(slots.len());
+        let mut outputs = Vec::with_capacity(slots.len());
+        for &slot in slots {
+            let (prod, cons) = RingBuffer::new(BUFFER_FRAMES).split();
+            channels.push((slot, prod));
+            outputs.push((dir.join(format!("capture-{:02}.wav", slot)), cons));
+        }
+        let max_slot = slots.iter().copied().max().unwrap_or(0);
+
+        let capture = Self {
+            channels,
+            scratch: vec![vec![(0., 0.); MAX_FRAMES_PER_BUFFER]; max_slot + 1],
+            sample_rate: Arc::new(AtomicU32::new(0)),
+            dropped: Arc::new(AtomicUsize::new(0)),
+            finished: Arc::new(AtomicBool::new(false)),
+        };
+        let sample_rate = Arc::clone(&capture.sample_rate);
+        let finished = Arc::clone(&capture.finished);
+        let writer = CaptureWriter {
+            dropped: Arc::clone(&capture.dropped),
+            thread: thread::spawn(move || write(outputs, sample_rate, finished)),
+        };
+        Ok((capture, writer))
+    }
+
+    /// Called by the engine when the capture starts.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate
+            .store(sample_rate as u32, Ordering::Release);
+    }
+
+    /// Buffer to render an instrument into, if it is being captured.
+    pub fn buffer(&mut self, index: usize) -> Option<&mut [(f32, f32)]> {
+        if self.channels.iter().any(|(slot, _)| *slot == index) {
+            self.scratch.get_mut(index).map(|buf| buf.as_mut_slice())
+        } else {
+            None
+        }
+    }
+
+    /// Hands the first `num_frames` of every buffer to the writer and clears them.
+    pub fn push(&mut self, num_frames: usize) {
+        for (slot, prod) in &mut self.channels {
+            let buf = &mut self.scratch[*slot][..num_frames];
+            let pushed = prod.push_slice(buf);
+            if pushed < num_frames {
+                self.dropped
+                    .fetch_add(num_frames - pushed, Ordering::Relaxed);
+            }
+            for frame in buf {
+                *frame = (0.0, 0.0);
+            }
+        }
+    }
+}
+
+impl Capture {
+    /// Lets the writer finish, nothing is recorded afterwards. Dropping the capture does too.
+    pub fn close(&self) {
+        self.finished.store(true, Ordering::Release);
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// App side of a capture, owns the thread writing the files.
+pub struct CaptureWriter {
+    dropped: Arc<AtomicUsize>,
+    thread: JoinHandle<Result<()>>,
+}
+
+impl CaptureWriter {
+    /// Number of frames lost because the disk couldn't keep up.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the remaining audio to be written. The engine must have dropped the capture.
+    pub fn finish(self) -> Result<()> {
+        self.thread
+            .join()
+            .map_err(|_| anyhow!("capture writer panicked"))?
+    }
+}
+
+fn write(
+    outputs: Vec<(Utf8PathBuf, Consumer<(f32, f32)>)>,
+    sample_rate: Arc<AtomicU32>,
+    finished: Arc<AtomicBool>,
+) -> Result<()> {
+    // The sample rate is only known once the engine has picked up the capture.
+    let sample_rate = loop {
+        match sample_rate.load(Ordering::Acquire) {
+            0 if finished.load(Ordering::Acquire) => return Ok(()),
+            0 => thread::sleep(WRITE_INTERVAL),
+            rate => break rate,
+        }
+    };
+    let spec = WavSpec {
+        channels: 2,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+
+    let mut writers = Vec::with_capacity(outputs.len());
+    for (path, cons) in outputs {
+        writers.push((WavWriter::create(path, spec)?, cons));
+    }
+
+    let mut buf = vec![(0., 0.); MAX_FRAMES_PER_BUFFER];
+    loop {
+        // Check before draining, the engine doesn't push anything after setting the flag.
+        let done = finished.load(Ordering::Acquire);
+        let mut written = 0;
+        for (writer, cons) in &mut writers {
+            loop {
+                let len = cons.pop_slice(&mut buf);
+                if len == 0 {
+                    break;
+                }
+                for (left, right) in &buf[..len] {
+                    writer.write_sample(*left)?;
+                    writer.write_sample(*right)?;
+                }
+                written += len;
+            }
+        }
+        if done {
+            break;
+        }
+        if written == 0 {
+            thread::sleep(WRITE_INTERVAL);
+        }
+    }
+
+    for (writer, _) in writers {
+        writer.finalize()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,546 @@
+//! CLAP plugins, as instruments on tracks and as effects on mixer channels:
+//! `:inst clap path=<file.clap> [id=<plugin id>]` and `:fx add clap path=<file.clap>`. The
+//! first plugin of the file is used unless an id is given. Plugins are loaded at runtime, so
+//! nothing of CLAP is needed to build.
+//!
+//! The params of a plugin show up as regular params, changes are sent to the plugin with the
+//! next buffer. Notes keep the column they're played on as their note id, so the same pitch
+//! played by two tracks can be told apart. Plugins are activated when they're created, at the
+//! sample rate the engine runs at, and go silent if the rate changes since reactivating them
+//! isn't allowed from the audio thread. The latency an effect reports once it's activated is
+//! shown on its mixer channel. The host doesn't offer any extension to plugins.
+//!
+//! ruis itself is also built as a CLAP plugin, see `plugin`.
+
+mod abi;
+mod plugin;
+
+use self::abi::{
+    cstr, AudioBuffer, Descriptor, Entry, EventHeader, Host, InputEvents, NoteEvent, OutputEvents,
+    ParamInfo, ParamValueEvent, Plugin, PluginFactory, PluginLatency, PluginParams, Process,
+    CLAP_VERSION, EVENT_NOTE_OFF, EVENT_NOTE_ON, EVENT_PARAM_VALUE, EXT_LATENCY, EXT_PARAMS,
+    PARAM_IS_HIDDEN, PARAM_IS_READONLY, PARAM_IS_STEPPED, PLUGIN_FACTORY_ID, PROCESS_ERROR,
+};
+use crate::effect::{Effect, EffectFactory};
+use crate::engine::{Device, EngineConfig};
+use crate::instrument::{Instrument, InstrumentFactory, Options};
+use crate::param::Param;
+use crate::MAX_FRAMES_PER_BUFFER;
+use anyhow::{anyhow, Result};
+use atomic_float::AtomicF32;
+use camino::Utf8Path;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// Most notes queued for a plugin between two buffers, more are dropped.
+const MAX_NOTE_EVENTS: usize = 256;
+/// Steps a param without steps of its own is changed by in the params pane.
+const PARAM_STEPS: f32 = 100.0;
+
+unsafe extern "C" fn host_get_extension(_host: *const Host, _id: *const c_char) -> *const c_void {
+    ptr::null()
+}
+
+unsafe extern "C" fn host_request(_host: *const Host) {}
+
+unsafe extern "C" fn events_size(list: *const InputEvents) -> u32 {
+    let events = &*((*list).ctx as *const Vec<*const EventHeader>);
+    events.len() as u32
+}
+
+unsafe extern "C" fn events_get(list: *const InputEvents, index: u32) -> *const EventHeader {
+    let events = &*((*list).ctx as *const Vec<*const EventHeader>);
+    events.get(index as usize).copied().unwrap_or(ptr::null())
+}
+
+/// Events sent by the plugin, e.g. param changes from its own interface, aren't used.
+unsafe extern "C" fn events_try_push(
+    _list: *const OutputEvents,
+    _event: *const EventHeader,
+) -> bool {
+    true
+}
+
+/// A plugin library, open as long as an instance of one of its plugins is.
+struct Library {
+    handle: *mut c_void,
+    entry: *const Entry,
+}
+
+// The entry point of a plugin library can be called from any thread.
+unsafe impl Send for Library {}
+unsafe impl Sync for Library {}
+
+impl Library {
+    fn open(path: &Utf8Path) -> Result<Arc<Self>> {
+        let c_path = CString::new(path.as_str())?;
+        let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            return Err(anyhow!("unable to load CLAP plugin {}", path));
+        }
+        let entry = unsafe { libc::dlsym(handle, cstr(b"clap_entry\0")) } as *const Entry;
+        if entry.is_null() {
+            unsafe { libc::dlclose(handle) };
+            return Err(anyhow!("{} is not a CLAP plugin", path));
+        }
+        let version = unsafe { (*entry).clap_version };
+        if version.major < 1 || !unsafe { ((*entry).init)(c_path.as_ptr()) } {
+            unsafe { libc::dlclose(handle) };
+            return Err(anyhow!("unable to initialize CLAP plugin {}", path));
+        }
+        Ok(Arc::new(Self { handle, entry }))
+    }
+
+    fn factory(&self) -> Result<*const PluginFactory> {
+        let factory = unsafe { ((*self.entry).get_factory)(cstr(PLUGIN_FACTORY_ID)) };
+        match factory.is_null() {
+            true => Err(anyhow!("CLAP plugin has no plugin factory")),
+            false => Ok(factory as *const PluginFactory),
+        }
+    }
+}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        unsafe {
+            ((*self.entry).deinit)();
+            libc::dlclose(self.handle);
+        }
+    }
+}
+
+/// A param of a plugin, set by the app and sent to the plugin when it changes.
+struct HostedParam {
+    id: u32,
+    cookie: *mut c_void,
+    name: String,
+    min: f32,
+    max: f32,
+    step: f32,
+    value: Arc<AtomicF32>,
+    /// Value the plugin has.
+    sent: f32,
+}
+
+/// An activated plugin, shared by instruments and effects.
+struct Instance {
+    plugin: *const Plugin,
+    /// Read by the plugin for as long as it lives.
+    _host: Box<Host>,
+    params: Vec<HostedParam>,
+    sample_rate: f64,
+    processing: bool,
+    /// Set when the plugin fails to process, it's silent from then on.
+    failed: bool,
+    /// Frames the plugin delays its output by, as it reported once activated.
+    latency: usize,
+    steady_time: i64,
+    notes: Vec<NoteEvent>,
+    param_events: Vec<ParamValueEvent>,
+    /// Events of the next buffer in order, pointing into `notes` and `param_events`.
+    events: Vec<*const EventHeader>,
+    inputs: [Vec<f32>; 2],
+    outputs: [Vec<f32>; 2],
+    _library: Arc<Library>,
+}
+
+// The plugin is created and destroyed on the app thread and processes on the audio thread,
+// which CLAP allows. Nothing else touches it.
+unsafe impl Send for Instance {}
+
+impl Instance {
+    fn new(options: &Options, sample_rate: f64) -> Result<Self> {
+        let path = Utf8Path::new(options.get("path")?);
+        let library = Library::open(path)?;
+        let factory = library.factory()?;
+        let count = unsafe { ((*factory).get_plugin_count)(factory) };
+        let descriptors: Vec<&Descriptor> = (0..count)
+            .filter_map(|i| unsafe { ((*factory).get_plugin_descriptor)(factory, i).as_ref() })
+            .collect();
+        let id = |desc: &Descriptor| unsafe { CStr::from_ptr(desc.id) }.to_string_lossy();
+        let descriptor = match options.get("id") {
+            Ok(wanted) => descriptors.iter().find(|desc| id(desc) == wanted),
+            Err(_) => descriptors.first(),
+        };
+        let descriptor = descriptor.ok_or_else(|| {
+            let ids: Vec<_> = descriptors.iter().map(|desc| id(desc)).collect();
+            anyhow!("no such plugin in {}, it has {}", path, ids.join(", "))
+        })?;
+
+        let host = Box::new(Host {
+            clap_version: CLAP_VERSION,
+            host_data: ptr::null_mut(),
+            name: cstr(b"ruis\0"),
+            vendor: cstr(b"\0"),
+            url: cstr(b"\0"),
+            version: cstr(b"0.1.0\0"),
+            get_extension: host_get_extension,
+            request_restart: host_request,
+            request_process: host_request,
+            request_callback: host_request,
+        });
+        let plugin = unsafe { ((*factory).create_plugin)(factory, &*host, descriptor.id) };
+        if plugin.is_null() {
+            return Err(anyhow!("unable to create plugin {}", id(descriptor)));
+        }
+        unsafe {
+            if !((*plugin).init)(plugin) {
+                ((*plugin).destroy)(plugin);
+                return Err(anyhow!("unable to initialize plugin {}", id(descriptor)));
+            }
+            if !((*plugin).activate)(plugin, sample_rate, 1, MAX_FRAMES_PER_BUFFER as u32) {
+                ((*plugin).destroy)(plugin);
+                return Err(anyhow!("unable to activate plugin {}", id(descriptor)));
+            }
+        }
+        let params = unsafe { hosted_params(plugin) };
+        let latency = unsafe { latency(plugin) };
+        Ok(Self {
+            plugin,
+            _host: host,
+            sample_rate,
+            processing: false,
+            failed: false,
+            latency,
+            steady_time: 0,
+            notes: Vec::with_capacity(MAX_NOTE_EVENTS),
+            param_events: Vec::with_capacity(params.len()),
+            events: Vec::with_capacity(MAX_NOTE_EVENTS + params.len()),
+            params,
+            inputs: [
+                vec![0.0; MAX_FRAMES_PER_BUFFER],
+                vec![0.0; MAX_FRAMES_PER_BUFFER],
+            ],
+            outputs: [
+                vec![0.0; MAX_FRAMES_PER_BUFFER],
+                vec![0.0; MAX_FRAMES_PER_BUFFER],
+            ],
+            _library: library,
+        })
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        self.params
+            .iter()
+            .map(|hosted| {
+                let value = Arc::clone(&hosted.value);
+                let param = Param::new(hosted.min, value, hosted.max, hosted.step);
+                (hosted.name.clone(), param)
+            })
+            .collect()
+    }
+
+    fn queue_note(&mut self, kind: u16, column: usize, pitch: u8, velocity: u8) {
+        if self.notes.len() == MAX_NOTE_EVENTS {
+            return;
+        }
+        self.notes.push(NoteEvent {
+            header: EventHeader::new::<NoteEvent>(kind),
+            note_id: column as i32,
+            port_index: 0,
+            channel: 0,
+            key: pitch as i16,
+            velocity: velocity as f64 / 127.0,
+        });
+    }
+
+    fn prepare(&mut self, config: &EngineConfig) {
+        if config.sample_rate != self.sample_rate {
+            self.failed = true;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.notes.clear();
+        if self.processing {
+            unsafe { ((*self.plugin).reset)(self.plugin) };
+        }
+    }
+
+    /// Runs the plugin over `len` frames, at most `MAX_FRAMES_PER_BUFFER`, with `input` or
+    /// silence going in. Returns whether `outputs` has its output.
+    fn process(&mut self, input: Option<&[(f32, f32)]>, len: usize) -> bool {
+        if self.failed {
+            self.notes.clear();
+            return false;
+        }
+        if !self.processing {
+            self.processing = unsafe { ((*self.plugin).start_processing)(self.plugin) };
+            if !self.processing {
+                self.failed = true;
+                return false;
+            }
+        }
+
+        // Param changes come first, the engine runs them before the notes of a buffer
+        for param in &mut self.params {
+            let value = param.value.load(Ordering::Relaxed);
+            if value != param.sent {
+                param.sent = value;
+                self.param_events.push(ParamValueEvent {
+                    header: EventHeader::new::<ParamValueEvent>(EVENT_PARAM_VALUE),
+                    param_id: param.id,
+                    cookie: param.cookie,
+                    note_id: -1,
+                    port_index: -1,
+                    channel: -1,
+                    key: -1,
+                    value: value as f64,
+                });
+            }
+        }
+        let params = self.param_events.iter().map(|e| &e.header as *const _);
+        let notes = self.notes.iter().map(|e| &e.header as *const _);
+        self.events.extend(params.chain(notes));
+
+        let [left, right] = &mut self.inputs;
+        match input {
+            Some(input) => {
+                for ((l, r), frame) in left.iter_mut().zip(right.iter_mut()).zip(input) {
+                    *l = frame.0;
+                    *r = frame.1;
+                }
+            }
+            None => {
+                left[..len].fill(0.0);
+                right[..len].fill(0.0);
+            }
+        }
+        let mut input_channels = [left.as_mut_ptr(), right.as_mut_ptr()];
+        let [left, right] = &mut self.outputs;
+        left[..len].fill(0.0);
+        right[..len].fill(0.0);
+        let mut output_channels = [left.as_mut_ptr(), right.as_mut_ptr()];
+        let inputs = AudioBuffer {
+            data32: input_channels.as_mut_ptr(),
+            data64: ptr::null_mut(),
+            channel_count: 2,
+            latency: 0,
+            constant_mask: 0,
+        };
+        let mut outputs = AudioBuffer {
+            data32: output_channels.as_mut_ptr(),
+            data64: ptr::null_mut(),
+            channel_count: 2,
+            latency: 0,
+            constant_mask: 0,
+        };
+        let in_events = InputEvents {
+            ctx: &self.events as *const Vec<*const EventHeader> as *mut c_void,
+            size: events_size,
+            get: events_get,
+        };
+        let out_events = OutputEvents {
+            ctx: ptr::null_mut(),
+            try_push: events_try_push,
+        };
+        let process = Process {
+            steady_time: self.steady_time,
+            frames_count: len as u32,
+            transport: ptr::null(),
+            audio_inputs: &inputs,
+            audio_outputs: &mut outputs,
+            // Instruments have no audio input
+            audio_inputs_count: input.is_some() as u32,
+            audio_outputs_count: 1,
+            in_events: &in_events,
+            out_events: &out_events,
+        };
+        let status = unsafe { ((*self.plugin).process)(self.plugin, &process) };
+        self.events.clear();
+        self.notes.clear();
+        self.param_events.clear();
+        self.steady_time += len as i64;
+        status != PROCESS_ERROR
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        unsafe {
+            if self.processing {
+                ((*self.plugin).stop_processing)(self.plugin);
+            }
+            ((*self.plugin).deactivate)(self.plugin);
+            ((*self.plugin).destroy)(self.plugin);
+        }
+    }
+}
+
+/// The latency of an active plugin, none when it doesn't report any.
+unsafe fn latency(plugin: *const Plugin) -> usize {
+    let ext = ((*plugin).get_extension)(plugin, cstr(EXT_LATENCY)) as *const PluginLatency;
+    match ext.as_ref() {
+        Some(ext) => (ext.get)(plugin) as usize,
+        None => 0,
+    }
+}
+
+/// Reads the params of a plugin, leaving out the ones the user can't change.
+unsafe fn hosted_params(plugin: *const Plugin) -> Vec<HostedParam> {
+    let ext = ((*plugin).get_extension)(plugin, cstr(EXT_PARAMS)) as *const PluginParams;
+    let ext = match ext.as_ref() {
+        Some(ext) => ext,
+        None => return Vec::new(),
+    };
+    let mut params = Vec::new();
+    for i in 0..(ext.count)(plugin) {
+        let mut info: ParamInfo = std::mem::zeroed();
+        if !(ext.get_info)(plugin, i, &mut info)
+            || info.flags & (PARAM_IS_HIDDEN | PARAM_IS_READONLY) != 0
+        {
+            continue;
+        }
+        let mut value = info.default_value;
+        (ext.get_value)(plugin, info.id, &mut value);
+        let (min, max) = (info.min_value as f32, info.max_value as f32);
+        let step = match info.flags & PARAM_IS_STEPPED {
+            0 => (max - min) / PARAM_STEPS,
+            _ => 1.0,
+        };
+        let name = CStr::from_ptr(info.name.as_ptr())
+            .to_string_lossy()
+            .into_owned();
+        params.push(HostedParam {
+            id: info.id,
+            cookie: info.cookie,
+            name,
+            min,
+            max,
+            step,
+            value: Arc::new(AtomicF32::new(value as f32)),
+            sent: value as f32,
+        });
+    }
+    params
+}
+
+pub struct ClapInstrument {
+    instance: Instance,
+    /// Column and pitch of the notes playing, to end them by column.
+    held: Vec<(usize, u8)>,
+}
+
+impl Device for ClapInstrument {
+    fn render(&mut self, buffer: &mut [(f32, f32)]) {
+        for block in buffer.chunks_mut(MAX_FRAMES_PER_BUFFER) {
+            if !self.instance.process(None, block.len()) {
+                continue;
+            }
+            let [left, right] = &self.instance.outputs;
+            for ((out, l), r) in block.iter_mut().zip(left).zip(right) {
+                out.0 += l;
+                out.1 += r;
+            }
+        }
+    }
+}
+
+impl Instrument for ClapInstrument {
+    fn note_on(&mut self, column: usize, pitch: u8, velocity: u8) {
+        self.note_off(column);
+        if self.held.len() < MAX_NOTE_EVENTS {
+            self.held.push((column, pitch));
+        }
+        self.instance
+            .queue_note(EVENT_NOTE_ON, column, pitch, velocity);
+    }
+
+    fn note_off(&mut self, column: usize) {
+        if let Some(index) = self.held.iter().position(|(c, _)| *c == column) {
+            let (_, pitch) = self.held.swap_remove(index);
+            self.instance.queue_note(EVENT_NOTE_OFF, column, pitch, 0);
+        }
+    }
+
+    fn prepare(&mut self, config: &EngineConfig) {
+        self.instance.prepare(config);
+    }
+
+    fn stop(&mut self) {
+        while let Some((column, _)) = self.held.last().copied() {
+            self.note_off(column);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.held.clear();
+        self.instance.reset();
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        self.instance.params()
+    }
+}
+
+pub struct ClapEffect {
+    instance: Instance,
+}
+
+impl Effect for ClapEffect {
+    fn process(&mut self, buffer: &mut [(f32, f32)]) {
+        for block in buffer.chunks_mut(MAX_FRAMES_PER_BUFFER) {
+            if !self.instance.process(Some(block), block.len()) {
+                continue;
+            }
+            let [left, right] = &self.instance.outputs;
+            for ((out, l), r) in block.iter_mut().zip(left).zip(right) {
+                *out = (*l, *r);
+            }
+        }
+    }
+
+    fn prepare(&mut self, config: &EngineConfig) {
+        self.instance.prepare(config);
+    }
+
+    fn reset(&mut self) {
+        self.instance.reset();
+    }
+
+    fn latency(&self) -> usize {
+        self.instance.latency
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        self.instance.params()
+    }
+}
+
+pub struct ClapFactory {
+    pub sample_rate: Arc<AtomicU32>,
+}
+
+impl InstrumentFactory for ClapFactory {
+    fn name(&self) -> &'static str {
+        "clap"
+    }
+
+    fn create(&self, options: &Options) -> Result<Box<dyn Instrument>> {
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed) as f64;
+        Ok(Box::new(ClapInstrument {
+            instance: Instance::new(options, sample_rate)?,
+            held: Vec::with_capacity(MAX_NOTE_EVENTS),
+        }))
+    }
+}
+
+pub struct ClapEffectFactory {
+    pub sample_rate: Arc<AtomicU32>,
+}
+
+impl EffectFactory for ClapEffectFactory {
+    fn name(&self) -> &'static str {
+        "clap"
+    }
+
+    fn create(&self, options: &Options) -> Result<Box<dyn Effect>> {
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed) as f64;
+        Ok(Box::new(ClapEffect {
+            instance: Instance::new(options, sample_rate)?,
+        }))
+    }
+}
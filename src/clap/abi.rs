@@ -0,0 +1,299 @@
+//! The parts of the CLAP ABI used to host plugins and to be one, written out from the CLAP
+//! headers since nothing of CLAP is needed to build.
+
+use std::os::raw::{c_char, c_void};
+
+pub const CLAP_VERSION: Version = Version {
+    major: 1,
+    minor: 2,
+    revision: 2,
+};
+pub const PLUGIN_FACTORY_ID: &[u8] = b"clap.plugin-factory\0";
+pub const EXT_PARAMS: &[u8] = b"clap.params\0";
+pub const EXT_AUDIO_PORTS: &[u8] = b"clap.audio-ports\0";
+pub const EXT_NOTE_PORTS: &[u8] = b"clap.note-ports\0";
+pub const EXT_STATE: &[u8] = b"clap.state\0";
+pub const EXT_LATENCY: &[u8] = b"clap.latency\0";
+pub const INVALID_ID: u32 = u32::MAX;
+
+pub const CORE_EVENT_SPACE: u16 = 0;
+pub const EVENT_NOTE_ON: u16 = 0;
+pub const EVENT_NOTE_OFF: u16 = 1;
+pub const EVENT_NOTE_CHOKE: u16 = 2;
+pub const EVENT_PARAM_VALUE: u16 = 5;
+pub const EVENT_MIDI: u16 = 10;
+pub const PROCESS_ERROR: i32 = 0;
+pub const PROCESS_CONTINUE: i32 = 1;
+
+pub const TRANSPORT_HAS_TEMPO: u32 = 1 << 0;
+pub const TRANSPORT_HAS_BEATS_TIMELINE: u32 = 1 << 1;
+pub const TRANSPORT_IS_PLAYING: u32 = 1 << 4;
+/// Positions in beats are fixed point, with this many steps per beat.
+pub const BEATTIME_FACTOR: i64 = 1 << 31;
+
+pub const AUDIO_PORT_IS_MAIN: u32 = 1 << 0;
+pub const PORT_STEREO: &[u8] = b"stereo\0";
+pub const NOTE_DIALECT_CLAP: u32 = 1 << 0;
+pub const NOTE_DIALECT_MIDI: u32 = 1 << 1;
+
+pub const NAME_SIZE: usize = 256;
+pub const PATH_SIZE: usize = 1024;
+pub const PARAM_IS_STEPPED: u32 = 1 << 0;
+pub const PARAM_IS_HIDDEN: u32 = 1 << 2;
+pub const PARAM_IS_READONLY: u32 = 1 << 3;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub revision: u32,
+}
+
+/// `clap_plugin_entry`, exported by every plugin library as `clap_entry`.
+#[repr(C)]
+pub struct Entry {
+    pub clap_version: Version,
+    pub init: unsafe extern "C" fn(*const c_char) -> bool,
+    pub deinit: unsafe extern "C" fn(),
+    pub get_factory: unsafe extern "C" fn(*const c_char) -> *const c_void,
+}
+
+#[repr(C)]
+pub struct PluginFactory {
+    pub get_plugin_count: unsafe extern "C" fn(*const PluginFactory) -> u32,
+    pub get_plugin_descriptor: unsafe extern "C" fn(*const PluginFactory, u32) -> *const Descriptor,
+    pub create_plugin:
+        unsafe extern "C" fn(*const PluginFactory, *const Host, *const c_char) -> *const Plugin,
+}
+
+#[repr(C)]
+pub struct Descriptor {
+    pub clap_version: Version,
+    pub id: *const c_char,
+    pub name: *const c_char,
+    pub vendor: *const c_char,
+    pub url: *const c_char,
+    pub manual_url: *const c_char,
+    pub support_url: *const c_char,
+    pub version: *const c_char,
+    pub description: *const c_char,
+    pub features: *const *const c_char,
+}
+
+#[repr(C)]
+pub struct Host {
+    pub clap_version: Version,
+    pub host_data: *mut c_void,
+    pub name: *const c_char,
+    pub vendor: *const c_char,
+    pub url: *const c_char,
+    pub version: *const c_char,
+    pub get_extension: unsafe extern "C" fn(*const Host, *const c_char) -> *const c_void,
+    pub request_restart: unsafe extern "C" fn(*const Host),
+    pub request_process: unsafe extern "C" fn(*const Host),
+    pub request_callback: unsafe extern "C" fn(*const Host),
+}
+
+#[repr(C)]
+pub struct Plugin {
+    pub desc: *const Descriptor,
+    pub plugin_data: *mut c_void,
+    pub init: unsafe extern "C" fn(*const Plugin) -> bool,
+    pub destroy: unsafe extern "C" fn(*const Plugin),
+    pub activate: unsafe extern "C" fn(*const Plugin, f64, u32, u32) -> bool,
+    pub deactivate: unsafe extern "C" fn(*const Plugin),
+    pub start_processing: unsafe extern "C" fn(*const Plugin) -> bool,
+    pub stop_processing: unsafe extern "C" fn(*const Plugin),
+    pub reset: unsafe extern "C" fn(*const Plugin),
+    pub process: unsafe extern "C" fn(*const Plugin, *const Process) -> i32,
+    pub get_extension: unsafe extern "C" fn(*const Plugin, *const c_char) -> *const c_void,
+    pub on_main_thread: unsafe extern "C" fn(*const Plugin),
+}
+
+#[repr(C)]
+pub struct Process {
+    pub steady_time: i64,
+    pub frames_count: u32,
+    pub transport: *const TransportEvent,
+    pub audio_inputs: *const AudioBuffer,
+    pub audio_outputs: *mut AudioBuffer,
+    pub audio_inputs_count: u32,
+    pub audio_outputs_count: u32,
+    pub in_events: *const InputEvents,
+    pub out_events: *const OutputEvents,
+}
+
+#[repr(C)]
+pub struct AudioBuffer {
+    pub data32: *mut *mut f32,
+    pub data64: *mut *mut f64,
+    pub channel_count: u32,
+    pub latency: u32,
+    pub constant_mask: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct EventHeader {
+    pub size: u32,
+    pub time: u32,
+    pub space_id: u16,
+    pub kind: u16,
+    pub flags: u32,
+}
+
+impl EventHeader {
+    pub fn new<T>(kind: u16) -> Self {
+        Self {
+            size: std::mem::size_of::<T>() as u32,
+            time: 0,
+            space_id: CORE_EVENT_SPACE,
+            kind,
+            flags: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct NoteEvent {
+    pub header: EventHeader,
+    pub note_id: i32,
+    pub port_index: i16,
+    pub channel: i16,
+    pub key: i16,
+    pub velocity: f64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct ParamValueEvent {
+    pub header: EventHeader,
+    pub param_id: u32,
+    pub cookie: *mut c_void,
+    pub note_id: i32,
+    pub port_index: i16,
+    pub channel: i16,
+    pub key: i16,
+    pub value: f64,
+}
+
+#[repr(C)]
+pub struct InputEvents {
+    pub ctx: *mut c_void,
+    pub size: unsafe extern "C" fn(*const InputEvents) -> u32,
+    pub get: unsafe extern "C" fn(*const InputEvents, u32) -> *const EventHeader,
+}
+
+#[repr(C)]
+pub struct OutputEvents {
+    pub ctx: *mut c_void,
+    pub try_push: unsafe extern "C" fn(*const OutputEvents, *const EventHeader) -> bool,
+}
+
+#[repr(C)]
+pub struct PluginParams {
+    pub count: unsafe extern "C" fn(*const Plugin) -> u32,
+    pub get_info: unsafe extern "C" fn(*const Plugin, u32, *mut ParamInfo) -> bool,
+    pub get_value: unsafe extern "C" fn(*const Plugin, u32, *mut f64) -> bool,
+    pub value_to_text: unsafe extern "C" fn(*const Plugin, u32, f64, *mut c_char, u32) -> bool,
+    pub text_to_value: unsafe extern "C" fn(*const Plugin, u32, *const c_char, *mut f64) -> bool,
+    pub flush: unsafe extern "C" fn(*const Plugin, *const InputEvents, *const OutputEvents),
+}
+
+#[repr(C)]
+pub struct ParamInfo {
+    pub id: u32,
+    pub flags: u32,
+    pub cookie: *mut c_void,
+    pub name: [c_char; NAME_SIZE],
+    pub module: [c_char; PATH_SIZE],
+    pub min_value: f64,
+    pub max_value: f64,
+    pub default_value: f64,
+}
+
+#[repr(C)]
+pub struct MidiEvent {
+    pub header: EventHeader,
+    pub port_index: u16,
+    pub data: [u8; 3],
+}
+
+/// `clap_event_transport`, passed to every `process` call by hosts with a transport.
+#[repr(C)]
+pub struct TransportEvent {
+    pub header: EventHeader,
+    pub flags: u32,
+    pub song_pos_beats: i64,
+    pub song_pos_seconds: i64,
+    pub tempo: f64,
+    pub tempo_inc: f64,
+    pub loop_start_beats: i64,
+    pub loop_end_beats: i64,
+    pub loop_start_seconds: i64,
+    pub loop_end_seconds: i64,
+    pub bar_start: i64,
+    pub bar_number: i32,
+    pub tsig_num: u16,
+    pub tsig_denom: u16,
+}
+
+#[repr(C)]
+pub struct AudioPortInfo {
+    pub id: u32,
+    pub name: [c_char; NAME_SIZE],
+    pub flags: u32,
+    pub channel_count: u32,
+    pub port_type: *const c_char,
+    pub in_place_pair: u32,
+}
+
+#[repr(C)]
+pub struct PluginAudioPorts {
+    pub count: unsafe extern "C" fn(*const Plugin, bool) -> u32,
+    pub get: unsafe extern "C" fn(*const Plugin, u32, bool, *mut AudioPortInfo) -> bool,
+}
+
+#[repr(C)]
+pub struct NotePortInfo {
+    pub id: u32,
+    pub supported_dialects: u32,
+    pub preferred_dialect: u32,
+    pub name: [c_char; NAME_SIZE],
+}
+
+#[repr(C)]
+pub struct PluginNotePorts {
+    pub count: unsafe extern "C" fn(*const Plugin, bool) -> u32,
+    pub get: unsafe extern "C" fn(*const Plugin, u32, bool, *mut NotePortInfo) -> bool,
+}
+
+#[repr(C)]
+pub struct PluginLatency {
+    pub get: unsafe extern "C" fn(*const Plugin) -> u32,
+}
+
+#[repr(C)]
+pub struct InputStream {
+    pub ctx: *mut c_void,
+    pub read: unsafe extern "C" fn(*const InputStream, *mut c_void, u64) -> i64,
+}
+
+#[repr(C)]
+pub struct OutputStream {
+    pub ctx: *mut c_void,
+    pub write: unsafe extern "C" fn(*const OutputStream, *const c_void, u64) -> i64,
+}
+
+#[repr(C)]
+pub struct PluginState {
+    pub save: unsafe extern "C" fn(*const Plugin, *const OutputStream) -> bool,
+    pub load: unsafe extern "C" fn(*const Plugin, *const InputStream) -> bool,
+}
+
+/// A null terminated byte string as a C string.
+pub const fn cstr(s: &'static [u8]) -> *const c_char {
+    s.as_ptr() as *const c_char
+}
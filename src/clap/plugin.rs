@@ -0,0 +1,565 @@
+//! ruis as a CLAP plugin, to play its songs inside a DAW. The library built along with the app
+//! is the plugin: copy `libruis.so` (`libruis.dylib` on macOS, `ruis.dll` on Windows) into a
+//! CLAP folder as `ruis.clap`.
+//!
+//! The plugin has no interface of its own. It starts with the project `RUIS_PROJECT` points
+//! to, if it's set, and from then on the whole project is saved with the session of the host.
+//! Playback follows the transport of the host: it plays and stops with it, at its tempo, and
+//! jumps along when the host moves to another position. Notes from the host play the
+//! instrument of the track their MIDI channel is numbered after, the first channel playing the
+//! first track, on the columns of notes received over OSC.
+
+use super::abi::{
+    cstr, AudioPortInfo, Descriptor, Entry, EventHeader, Host, InputStream, MidiEvent, NoteEvent,
+    NotePortInfo, OutputStream, Plugin, PluginAudioPorts, PluginFactory, PluginNotePorts,
+    PluginState, Process, TransportEvent, AUDIO_PORT_IS_MAIN, BEATTIME_FACTOR, CLAP_VERSION,
+    CORE_EVENT_SPACE, EVENT_MIDI, EVENT_NOTE_CHOKE, EVENT_NOTE_OFF, EVENT_NOTE_ON, EXT_AUDIO_PORTS,
+    EXT_NOTE_PORTS, EXT_STATE, INVALID_ID, NAME_SIZE, NOTE_DIALECT_CLAP, NOTE_DIALECT_MIDI,
+    PLUGIN_FACTORY_ID, PORT_STEREO, PROCESS_CONTINUE, PROCESS_ERROR, TRANSPORT_HAS_BEATS_TIMELINE,
+    TRANSPORT_HAS_TEMPO, TRANSPORT_IS_PLAYING,
+};
+use crate::app::{App, AppCommand};
+use crate::engine::{Engine, EngineCommand, EngineConfig, EngineParam, EngineParams};
+use crate::harmony::{self, MAX_REMOTE_NOTES};
+use crate::paths::{Layout, Paths};
+use crate::project::Project;
+use crate::MAX_FRAMES_PER_BUFFER;
+use anyhow::Result;
+use camino::Utf8Path;
+use ringbuf::RingBuffer;
+use std::convert::TryFrom;
+use std::env;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+const PLUGIN_ID: &[u8] = b"ruis\0";
+/// Commands the app can queue before the engine runs them. The host may load a project before
+/// it starts processing, so there's room for a whole project.
+const ENGINE_COMMANDS: usize = 1024;
+/// Room for the garbage of a burst of commands on top of the position updates.
+const APP_COMMANDS: usize = 256;
+/// Bytes read at once when the host hands the project back.
+const READ_SIZE: usize = 4096;
+// The editor uses 0 based octaves, so C-4 (48) is middle C which is 60 in MIDI.
+const PITCH_OFFSET: u8 = 12;
+
+/// Statics holding pointers to other statics, which are never written to.
+struct Shared<T>(T);
+
+unsafe impl<T> Sync for Shared<T> {}
+
+static FEATURES: Shared<[*const c_char; 4]> = Shared([
+    cstr(b"instrument\0"),
+    cstr(b"synthesizer\0"),
+    cstr(b"stereo\0"),
+    ptr::null(),
+]);
+
+static DESCRIPTOR: Shared<Descriptor> = Shared(Descriptor {
+    clap_version: CLAP_VERSION,
+    id: cstr(PLUGIN_ID),
+    name: cstr(b"ruis\0"),
+    vendor: cstr(b"ruis\0"),
+    url: cstr(b"\0"),
+    manual_url: cstr(b"\0"),
+    support_url: cstr(b"\0"),
+    version: cstr(b"0.1.0\0"),
+    description: cstr(b"Tracker with samplers and synths\0"),
+    features: &FEATURES.0 as *const [*const c_char; 4] as *const *const c_char,
+});
+
+static FACTORY: PluginFactory = PluginFactory {
+    get_plugin_count,
+    get_plugin_descriptor,
+    create_plugin,
+};
+
+static AUDIO_PORTS: PluginAudioPorts = PluginAudioPorts {
+    count: audio_ports_count,
+    get: audio_ports_get,
+};
+
+static NOTE_PORTS: PluginNotePorts = PluginNotePorts {
+    count: note_ports_count,
+    get: note_ports_get,
+};
+
+static STATE: PluginState = PluginState {
+    save: state_save,
+    load: state_load,
+};
+
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static clap_entry: Entry = Entry {
+    clap_version: CLAP_VERSION,
+    init: entry_init,
+    deinit: entry_deinit,
+    get_factory,
+};
+
+/// The plugin handed to the host, `plugin_data` pointing back to it. The host calls it from
+/// its main thread and from its audio thread, each half has its own lock which the other
+/// thread never takes while processing.
+struct Ruis {
+    clap: Plugin,
+    host: *const Host,
+    /// The app without its interface, on the main thread. Set once the plugin is initialized.
+    app: Mutex<Option<App>>,
+    audio: Mutex<Option<Audio>>,
+}
+
+impl Ruis {
+    fn init(&self) -> Result<()> {
+        let (engine_send, engine_recv) = RingBuffer::<EngineCommand>::new(ENGINE_COMMANDS).split();
+        let (app_send, app_recv) = RingBuffer::<AppCommand>::new(APP_COMMANDS).split();
+
+        let params = EngineParams::default();
+        let config = EngineConfig::default();
+        let engine = Engine::new(config, params.clone(), engine_recv, app_send);
+        let paths = Paths::new(Layout::Platform)?;
+        paths.create()?;
+        let mut app = App::new(params.clone(), app_recv, engine_send, paths)?;
+        if let Ok(path) = env::var("RUIS_PROJECT") {
+            app.load_project(Project::load(Utf8Path::new(&path))?)?;
+        }
+        *lock(&self.app) = Some(app);
+        *lock(&self.audio) = Some(Audio::new(engine, params));
+        Ok(())
+    }
+
+    fn save(&self) -> Option<Result<String>> {
+        lock(&self.app).as_ref().map(|app| app.project().to_text())
+    }
+
+    fn load(&self, text: &str) -> Result<()> {
+        if let Some(app) = lock(&self.app).as_mut() {
+            app.load_project(Project::parse(text)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// The engine and the notes the host plays, on the audio thread.
+struct Audio {
+    engine: Engine,
+    params: EngineParams,
+    buf: Vec<(f32, f32)>,
+    /// Track and MIDI key of the notes the host plays, by the slot their column is for.
+    held: [Option<(usize, u8)>; MAX_REMOTE_NOTES],
+    /// Where the host transport should be at the next buffer, in frames, unless it jumps.
+    next_frame: Option<u64>,
+}
+
+impl Audio {
+    fn new(engine: Engine, params: EngineParams) -> Self {
+        Self {
+            engine,
+            params,
+            buf: vec![(0.0, 0.0); MAX_FRAMES_PER_BUFFER],
+            held: [None; MAX_REMOTE_NOTES],
+            next_frame: None,
+        }
+    }
+
+    fn activate(&mut self, sample_rate: f64, max_frames: u32) {
+        self.engine.set_config(EngineConfig {
+            sample_rate,
+            buffer_size: max_frames,
+            ..self.engine.config()
+        });
+        self.next_frame = None;
+    }
+
+    fn reset(&mut self) {
+        self.engine.reset();
+        self.held = [None; MAX_REMOTE_NOTES];
+        self.next_frame = None;
+    }
+
+    /// Renders a buffer of the host, starting the notes of its events on the frame they're
+    /// timed at.
+    unsafe fn process(&mut self, process: &Process) -> bool {
+        let frames = process.frames_count as usize;
+        if let Some(transport) = process.transport.as_ref() {
+            self.follow(transport, frames as u64);
+        }
+        let (left, right) = match process.audio_outputs.as_ref() {
+            Some(out) if process.audio_outputs_count > 0 && out.channel_count == 2 => {
+                let channels = out.data32;
+                (*channels, *channels.add(1))
+            }
+            _ => return false,
+        };
+        let events = &*process.in_events;
+        let num_events = (events.size)(events);
+        let event_at = |i| match i < num_events {
+            true => (events.get)(events, i).as_ref(),
+            false => None,
+        };
+
+        let mut next_event = 0;
+        let mut offset = 0;
+        while offset < frames {
+            while let Some(event) = event_at(next_event).filter(|e| e.time as usize <= offset) {
+                self.event(event);
+                next_event += 1;
+            }
+            let until =
+                event_at(next_event).map_or(frames, |e| usize::min(e.time as usize, frames));
+            let len = usize::min(until - offset, MAX_FRAMES_PER_BUFFER);
+            self.engine.render(&mut self.buf[..len]);
+            for (i, frame) in self.buf[..len].iter_mut().enumerate() {
+                *left.add(offset + i) = frame.0;
+                *right.add(offset + i) = frame.1;
+                *frame = (0.0, 0.0);
+            }
+            offset += len;
+        }
+        // Events timed past the end of the buffer
+        for i in next_event..num_events {
+            if let Some(event) = event_at(i) {
+                self.event(event);
+            }
+        }
+        true
+    }
+
+    /// Follows the transport of the host. The position is only used to notice the host
+    /// jumping, the engine keeps time itself in between.
+    fn follow(&mut self, transport: &TransportEvent, frames: u64) {
+        if transport.flags & TRANSPORT_HAS_TEMPO != 0 && transport.tempo > 0.0 {
+            let bpm = transport.tempo.round() as u16;
+            self.params.set(EngineParam::Bpm, bpm);
+        }
+        let playing = transport.flags & TRANSPORT_IS_PLAYING != 0;
+        if playing {
+            self.params.transport.play();
+        } else {
+            self.params.transport.pause();
+        }
+
+        if transport.flags & TRANSPORT_HAS_BEATS_TIMELINE == 0 {
+            return;
+        }
+        // In the tempo of the host, the tempo of the engine is rounded
+        let bpm = match transport.flags & TRANSPORT_HAS_TEMPO {
+            0 => self.params.get(EngineParam::Bpm) as f64,
+            _ => transport.tempo,
+        };
+        let beats = transport.song_pos_beats.max(0) as f64 / BEATTIME_FACTOR as f64;
+        let seconds = beats * 60.0 / f64::max(bpm, 1.0);
+        let frame = (seconds * self.engine.config().sample_rate).round() as u64;
+        // Converting to frames rounds, so the position can be a frame off either way
+        if self.next_frame.is_none_or(|next| next.abs_diff(frame) > 1) {
+            self.engine.locate(frame);
+        }
+        self.next_frame = Some(if playing { frame + frames } else { frame });
+    }
+
+    unsafe fn event(&mut self, header: &EventHeader) {
+        if header.space_id != CORE_EVENT_SPACE {
+            return;
+        }
+        match header.kind {
+            EVENT_NOTE_ON => {
+                let note = &*(header as *const EventHeader as *const NoteEvent);
+                if let (Ok(track), Ok(key)) =
+                    (usize::try_from(note.channel), u8::try_from(note.key))
+                {
+                    let velocity = (note.velocity * 127.0).round().clamp(1.0, 127.0) as u8;
+                    self.note_on(track, key, velocity);
+                }
+            }
+            EVENT_NOTE_OFF | EVENT_NOTE_CHOKE => {
+                // A channel or key of -1 stands for all of them
+                let note = &*(header as *const EventHeader as *const NoteEvent);
+                let track = usize::try_from(note.channel).ok();
+                let key = u8::try_from(note.key).ok();
+                self.note_off(track, key);
+            }
+            EVENT_MIDI => {
+                let midi = &*(header as *const EventHeader as *const MidiEvent);
+                let [status, key, velocity] = midi.data;
+                let track = (status & 0x0f) as usize;
+                match status & 0xf0 {
+                    0x90 if velocity > 0 => self.note_on(track, key, velocity),
+                    0x80 | 0x90 => self.note_off(Some(track), Some(key)),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Starts a note on the instrument of a track, dropped when every slot is taken.
+    fn note_on(&mut self, track: usize, key: u8, velocity: u8) {
+        self.note_off(Some(track), Some(key));
+        if let Some(slot) = self.held.iter().position(Option::is_none) {
+            self.held[slot] = Some((track, key));
+            let pitch = key.saturating_sub(PITCH_OFFSET);
+            let column = harmony::remote_column(slot);
+            self.engine.note_on(track, column, pitch, velocity);
+        }
+    }
+
+    /// Ends the notes on a track with a key, every track or key when it's not given.
+    fn note_off(&mut self, track: Option<usize>, key: Option<u8>) {
+        for slot in 0..MAX_REMOTE_NOTES {
+            if let Some((t, k)) = self.held[slot] {
+                if track.is_none_or(|track| track == t) && key.is_none_or(|key| key == k) {
+                    self.held[slot] = None;
+                    self.engine.note_off(t, harmony::remote_column(slot));
+                }
+            }
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+unsafe fn ruis<'a>(plugin: *const Plugin) -> &'a Ruis {
+    &*((*plugin).plugin_data as *const Ruis)
+}
+
+/// Copies `name` into a fixed size C string, cutting it short if it doesn't fit.
+fn write_name(dest: &mut [c_char; NAME_SIZE], name: &str) {
+    let len = usize::min(name.len(), NAME_SIZE - 1);
+    for (d, s) in dest.iter_mut().zip(&name.as_bytes()[..len]) {
+        *d = *s as c_char;
+    }
+    dest[len] = 0;
+}
+
+unsafe extern "C" fn entry_init(_path: *const c_char) -> bool {
+    true
+}
+
+unsafe extern "C" fn entry_deinit() {}
+
+unsafe extern "C" fn get_factory(id: *const c_char) -> *const c_void {
+    match CStr::from_ptr(id).to_bytes_with_nul() == PLUGIN_FACTORY_ID {
+        true => &FACTORY as *const PluginFactory as *const c_void,
+        false => ptr::null(),
+    }
+}
+
+unsafe extern "C" fn get_plugin_count(_factory: *const PluginFactory) -> u32 {
+    1
+}
+
+unsafe extern "C" fn get_plugin_descriptor(
+    _factory: *const PluginFactory,
+    index: u32,
+) -> *const Descriptor {
+    match index {
+        0 => &DESCRIPTOR.0,
+        _ => ptr::null(),
+    }
+}
+
+unsafe extern "C" fn create_plugin(
+    _factory: *const PluginFactory,
+    host: *const Host,
+    id: *const c_char,
+) -> *const Plugin {
+    if CStr::from_ptr(id).to_bytes_with_nul() != PLUGIN_ID {
+        return ptr::null();
+    }
+    let ruis = Box::into_raw(Box::new(Ruis {
+        clap: Plugin {
+            desc: &DESCRIPTOR.0,
+            plugin_data: ptr::null_mut(),
+            init,
+            destroy,
+            activate,
+            deactivate,
+            start_processing,
+            stop_processing,
+            reset,
+            process,
+            get_extension,
+            on_main_thread,
+        },
+        host,
+        app: Mutex::new(None),
+        audio: Mutex::new(None),
+    }));
+    (*ruis).clap.plugin_data = ruis as *mut c_void;
+    &(*ruis).clap
+}
+
+unsafe extern "C" fn init(plugin: *const Plugin) -> bool {
+    match ruis(plugin).init() {
+        Ok(()) => true,
+        Err(err) => {
+            eprintln!("ruis: {:?}", err);
+            false
+        }
+    }
+}
+
+unsafe extern "C" fn destroy(plugin: *const Plugin) {
+    drop(Box::from_raw((*plugin).plugin_data as *mut Ruis));
+}
+
+unsafe extern "C" fn activate(
+    plugin: *const Plugin,
+    sample_rate: f64,
+    _min_frames: u32,
+    max_frames: u32,
+) -> bool {
+    match lock(&ruis(plugin).audio).as_mut() {
+        Some(audio) => {
+            audio.activate(sample_rate, max_frames);
+            true
+        }
+        None => false,
+    }
+}
+
+unsafe extern "C" fn deactivate(_plugin: *const Plugin) {}
+
+unsafe extern "C" fn start_processing(_plugin: *const Plugin) -> bool {
+    true
+}
+
+unsafe extern "C" fn stop_processing(_plugin: *const Plugin) {}
+
+unsafe extern "C" fn reset(plugin: *const Plugin) {
+    if let Some(audio) = lock(&ruis(plugin).audio).as_mut() {
+        audio.reset();
+    }
+}
+
+unsafe extern "C" fn process(plugin: *const Plugin, process: *const Process) -> i32 {
+    let ruis = ruis(plugin);
+    // Only the audio thread takes the lock while the plugin is processing
+    let processed = match ruis.audio.try_lock() {
+        Ok(mut audio) => match audio.as_mut() {
+            Some(audio) => audio.process(&*process),
+            None => false,
+        },
+        Err(_) => false,
+    };
+    // The app picks up what the engine sent back on the main thread
+    ((*ruis.host).request_callback)(ruis.host);
+    match processed {
+        true => PROCESS_CONTINUE,
+        false => PROCESS_ERROR,
+    }
+}
+
+unsafe extern "C" fn get_extension(_plugin: *const Plugin, id: *const c_char) -> *const c_void {
+    match CStr::from_ptr(id).to_bytes_with_nul() {
+        id if id == EXT_AUDIO_PORTS => &AUDIO_PORTS as *const PluginAudioPorts as *const c_void,
+        id if id == EXT_NOTE_PORTS => &NOTE_PORTS as *const PluginNotePorts as *const c_void,
+        id if id == EXT_STATE => &STATE as *const PluginState as *const c_void,
+        _ => ptr::null(),
+    }
+}
+
+unsafe extern "C" fn on_main_thread(plugin: *const Plugin) {
+    if let Some(app) = lock(&ruis(plugin).app).as_mut() {
+        app.run_commands();
+    }
+}
+
+/// A single stereo output, and no input.
+unsafe extern "C" fn audio_ports_count(_plugin: *const Plugin, is_input: bool) -> u32 {
+    match is_input {
+        true => 0,
+        false => 1,
+    }
+}
+
+unsafe extern "C" fn audio_ports_get(
+    _plugin: *const Plugin,
+    index: u32,
+    is_input: bool,
+    info: *mut AudioPortInfo,
+) -> bool {
+    if is_input || index != 0 {
+        return false;
+    }
+    let info = &mut *info;
+    info.id = 0;
+    write_name(&mut info.name, "main");
+    info.flags = AUDIO_PORT_IS_MAIN;
+    info.channel_count = 2;
+    info.port_type = cstr(PORT_STEREO);
+    info.in_place_pair = INVALID_ID;
+    true
+}
+
+/// A single note input, taking CLAP notes or MIDI.
+unsafe extern "C" fn note_ports_count(_plugin: *const Plugin, is_input: bool) -> u32 {
+    match is_input {
+        true => 1,
+        false => 0,
+    }
+}
+
+unsafe extern "C" fn note_ports_get(
+    _plugin: *const Plugin,
+    index: u32,
+    is_input: bool,
+    info: *mut NotePortInfo,
+) -> bool {
+    if !is_input || index != 0 {
+        return false;
+    }
+    let info = &mut *info;
+    info.id = 0;
+    info.supported_dialects = NOTE_DIALECT_CLAP | NOTE_DIALECT_MIDI;
+    info.preferred_dialect = NOTE_DIALECT_CLAP;
+    write_name(&mut info.name, "notes");
+    true
+}
+
+unsafe extern "C" fn state_save(plugin: *const Plugin, stream: *const OutputStream) -> bool {
+    let text = match ruis(plugin).save() {
+        Some(Ok(text)) => text,
+        Some(Err(err)) => {
+            eprintln!("ruis: {:?}", err);
+            return false;
+        }
+        None => return false,
+    };
+    let mut data = text.as_bytes();
+    while !data.is_empty() {
+        let written = ((*stream).write)(stream, data.as_ptr() as *const c_void, data.len() as u64);
+        if written <= 0 {
+            return false;
+        }
+        data = &data[written as usize..];
+    }
+    true
+}
+
+unsafe extern "C" fn state_load(plugin: *const Plugin, stream: *const InputStream) -> bool {
+    let mut data = Vec::new();
+    let mut buf = [0u8; READ_SIZE];
+    loop {
+        let read = ((*stream).read)(stream, buf.as_mut_ptr() as *mut c_void, READ_SIZE as u64);
+        match read {
+            0 => break,
+            read if read < 0 => return false,
+            read => data.extend_from_slice(&buf[..read as usize]),
+        }
+    }
+    let result = String::from_utf8(data)
+        .map_err(anyhow::Error::from)
+        .and_then(|text| ruis(plugin).load(&text));
+    match result {
+        Ok(()) => true,
+        Err(err) => {
+            eprintln!("ruis: {:?}", err);
+            false
+        }
+    }
+}
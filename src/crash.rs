@@ -0,0 +1,100 @@
+//! Crash reports, written when the program panics so a bug report can say what was going on.
+//! The app keeps a snapshot of the song and of the recent log up to date, the panic hook adds
+//! the version and a backtrace and writes it all to a file.
+
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+use std::backtrace::Backtrace;
+use std::cell::Cell;
+use std::fmt::Write;
+use std::fs;
+use std::panic::{self, PanicHookInfo};
+use std::sync::{Mutex, TryLockError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Entries of the session log kept in a report.
+pub const LOG_ENTRIES: usize = 50;
+
+/// What the app was doing, as of its last update.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    /// The song without its steps or sounds, see `Project::summary`.
+    pub project: String,
+    /// The last entries of the session log, oldest first.
+    pub log: Vec<String>,
+}
+
+lazy_static! {
+    static ref SNAPSHOT: Mutex<Snapshot> = Mutex::new(Snapshot::default());
+}
+
+thread_local! {
+    static AUDIO_THREAD: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Writes a report into `dir` whenever a thread panics, except the audio thread which mustn't
+/// block on the file system. The previous hook still runs afterwards.
+pub fn install(dir: Utf8PathBuf) {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if !AUDIO_THREAD.with(Cell::get) {
+            match write_report(&dir, info) {
+                Ok(path) => eprintln!("crash report written to {}", path),
+                Err(err) => eprintln!("couldn't write a crash report: {:?}", err),
+            }
+        }
+        previous(info);
+    }));
+}
+
+/// Marks the calling thread as the audio thread, which gets no report.
+pub fn mark_audio_thread() {
+    AUDIO_THREAD.with(|audio| audio.set(true));
+}
+
+pub fn update(snapshot: Snapshot) {
+    // A panic while the lock is held leaves the last complete snapshot, which is still useful.
+    let mut current = match SNAPSHOT.lock() {
+        Ok(current) => current,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    *current = snapshot;
+}
+
+fn write_report(dir: &Utf8Path, info: &PanicHookInfo) -> Result<Utf8PathBuf> {
+    let mut report = String::new();
+    writeln!(report, "ruis {}", env!("CARGO_PKG_VERSION"))?;
+    let thread = std::thread::current();
+    writeln!(
+        report,
+        "panic on thread {}: {}",
+        thread.name().unwrap_or("unnamed"),
+        info
+    )?;
+    writeln!(report, "\nbacktrace:\n{}", Backtrace::force_capture())?;
+    // The panic may have happened while updating the snapshot, don't wait for it.
+    let snapshot = match SNAPSHOT.try_lock() {
+        Ok(snapshot) => Some(snapshot),
+        Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+        Err(TryLockError::WouldBlock) => None,
+    };
+    match snapshot {
+        Some(snapshot) => {
+            writeln!(report, "project:\n{}", snapshot.project)?;
+            writeln!(report, "\nlast {} log entries:", snapshot.log.len())?;
+            for entry in &snapshot.log {
+                writeln!(report, "{}", entry)?;
+            }
+        }
+        None => writeln!(report, "no snapshot, it was being updated")?,
+    }
+
+    fs::create_dir_all(dir)?;
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let path = dir.join(format!("crash-{}.txt", time));
+    fs::write(&path, report)?;
+    Ok(path)
+}
@@ -0,0 +1,207 @@
+//! A short song built in code for `ruis demo`: a synth bassline over sampled drums with a send
+//! reverb. It gives new users something to hear and poke at, and exercises the synth, the
+//! sampler, the sends and the effects together.
+
+use crate::bounce;
+use crate::id::InstrumentId;
+use crate::instrument::Options;
+use crate::mixer::return_channel;
+use crate::pattern::{Pattern, Step, NOTE_OFF};
+use crate::project::{EffectConfig, InstrumentConfig, ModulationConfig, Project, SendConfig};
+use crate::sampler::ROOT_PITCH;
+use crate::synth::SynthParam;
+use anyhow::Result;
+use camino::Utf8Path;
+use hound::{SampleFormat, WavSpec};
+use std::f32::consts::PI;
+use std::fs;
+
+const BPM: u16 = 112;
+/// Two bars of sixteenth notes.
+const NUM_LINES: usize = 32;
+const SAMPLE_RATE: u32 = 44_100;
+
+const KICK: usize = 0;
+const SNARE: usize = 1;
+const HAT: usize = 2;
+const BASS: usize = 3;
+
+/// Notes of the bassline by line, `NOTE_OFF` cutting the previous one short.
+const BASSLINE: [(usize, u8); 16] = [
+    (0, 33),
+    (2, NOTE_OFF),
+    (3, 33),
+    (6, 45),
+    (7, NOTE_OFF),
+    (8, 36),
+    (11, 38),
+    (14, 40),
+    (16, 33),
+    (18, NOTE_OFF),
+    (19, 33),
+    (22, 45),
+    (23, NOTE_OFF),
+    (24, 43),
+    (27, 40),
+    (30, 38),
+];
+
+/// Replaces the song of `project` with the demo. The drum hits are synthesized and written to
+/// `dir`, so the demo ships no audio files.
+pub fn project(mut project: Project, dir: &Utf8Path) -> Result<Project> {
+    fs::create_dir_all(dir)?;
+    let hits = [
+        (KICK, "kick", kick()),
+        (SNARE, "snare", snare()),
+        (HAT, "hat", hat()),
+    ];
+    project.instruments.iter_mut().for_each(|i| *i = None);
+    for (track, name, frames) in hits.iter() {
+        let path = dir.join(format!("{}.wav", name));
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        bounce::write_wav(&path, spec, frames)?;
+        let mut options = Options::default();
+        options.set("path", path.as_str());
+        project.instruments[*track] = Some(instrument(*track, "sampler", options, vec![]));
+    }
+    let bass = vec![
+        (SynthParam::Osc2Wave.name(), 1.0),
+        (SynthParam::Detune.name(), -12.0),
+        (SynthParam::Mix.name(), 0.3),
+        (SynthParam::Cutoff.name(), 400.0),
+        (SynthParam::Resonance.name(), 0.6),
+        (SynthParam::FilterAmount.name(), 0.6),
+        ("Sustain", 0.6),
+        ("Release", 0.08),
+        ("FilterDecay", 0.25),
+    ];
+    project.instruments[BASS] = Some(instrument(BASS, "synth", Options::default(), bass));
+
+    // The reverb sits on bus A, fully wet, the snare and the bass are sent to it.
+    for channel in &mut project.mixer {
+        channel.effects.clear();
+        channel.sends.clear();
+    }
+    let mut reverb = Options::default();
+    reverb.set("size", "0.7");
+    reverb.set("mix", "1");
+    project.mixer[return_channel(0)].effects.push(EffectConfig {
+        kind: "reverb".into(),
+        options: reverb,
+        params: vec![],
+        bypass: false,
+    });
+    for (track, level) in [(SNARE, -10.0), (BASS, -18.0)].iter() {
+        project.mixer[*track].sends = vec![SendConfig {
+            level: *level,
+            pre_fader: false,
+        }];
+    }
+
+    let id = project.patterns[project.current_pattern].id;
+    let mut pattern = Pattern::new(id, &project.track_ids, NUM_LINES);
+    let mut hit = |track: usize, line: usize, pitch: u8| {
+        let step = Step {
+            pitch: Some(pitch),
+            sound: None,
+            chord: None,
+            offset: 0,
+        };
+        pattern.set_step(track, line, step);
+    };
+    for line in (0..NUM_LINES).step_by(2) {
+        hit(HAT, line, ROOT_PITCH);
+    }
+    for line in [0, 6, 10, 16, 22].iter() {
+        hit(KICK, *line, ROOT_PITCH);
+    }
+    for line in [4, 12, 20, 28].iter() {
+        hit(SNARE, *line, ROOT_PITCH);
+    }
+    for (line, pitch) in BASSLINE.iter() {
+        hit(BASS, *line, *pitch);
+    }
+    project.patterns = vec![pattern];
+    project.current_pattern = 0;
+    project.bpm = BPM;
+    project.lines_per_beat = 4;
+    Ok(project)
+}
+
+fn instrument(
+    track: usize,
+    kind: &str,
+    options: Options,
+    params: Vec<(&str, f32)>,
+) -> InstrumentConfig {
+    InstrumentConfig {
+        id: InstrumentId(track as u32),
+        kind: kind.into(),
+        options,
+        params: params
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect(),
+        modulation: ModulationConfig::default(),
+        trim: 0.0,
+    }
+}
+
+/// A sine falling from 150 to 45 Hz.
+fn kick() -> Vec<(f32, f32)> {
+    let mut phase = 0.0;
+    render(0.5, |t| {
+        let frequency = 45.0 + 105.0 * f32::exp(-t * 25.0);
+        phase += frequency / SAMPLE_RATE as f32;
+        f32::sin(2.0 * PI * phase) * f32::exp(-t * 7.0)
+    })
+}
+
+/// Noise over a short 185 Hz body.
+fn snare() -> Vec<(f32, f32)> {
+    let mut noise = Noise(0x2545_f491);
+    render(0.3, |t| {
+        let body = f32::sin(2.0 * PI * 185.0 * t) * f32::exp(-t * 30.0);
+        0.6 * body + 0.5 * noise.next() * f32::exp(-t * 18.0)
+    })
+}
+
+/// Noise with its lows taken out, very short.
+fn hat() -> Vec<(f32, f32)> {
+    let mut noise = Noise(0x9e37_79b9);
+    let mut previous = 0.0;
+    render(0.08, |t| {
+        let sample = noise.next();
+        let high = sample - previous;
+        previous = sample;
+        0.3 * high * f32::exp(-t * 60.0)
+    })
+}
+
+/// `length` seconds of `f`, which gets the time of each sample.
+fn render(length: f32, mut f: impl FnMut(f32) -> f32) -> Vec<(f32, f32)> {
+    let num_frames = (length * SAMPLE_RATE as f32) as usize;
+    (0..num_frames)
+        .map(|i| {
+            let sample = f(i as f32 / SAMPLE_RATE as f32);
+            (sample, sample)
+        })
+        .collect()
+}
+
+/// White noise from a xorshift generator, the same every run.
+struct Noise(u32);
+
+impl Noise {
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
@@ -0,0 +1,102 @@
+//! Keeps an input recorded on one device in sync with the output of another. The two devices
+//! run on their own clocks, so even at the same nominal sample rate one of them slowly gets
+//! ahead of the other. The input is passed through a FIFO and resampled at a ratio which keeps
+//! the FIFO at its target fill level, which follows the actual ratio of the two clocks.
+
+use ringbuf::{Consumer, Producer, RingBuffer};
+
+/// How much the resampling ratio may be corrected, 1000ppm is far beyond real clock drift.
+const MAX_CORRECTION: f64 = 0.001;
+/// How fast the fill level estimate follows the FIFO, per output buffer.
+const FILL_SMOOTHING: f64 = 0.01;
+/// Gains of the controller turning the fill level error into a ratio correction, relative to
+/// the target fill level.
+const PROPORTIONAL_GAIN: f64 = 0.0005;
+const INTEGRAL_GAIN: f64 = 0.000_002;
+
+/// Creates the FIFO between the input and the output callback. `latency` is the number of
+/// input frames kept buffered, it has to cover the buffer sizes of both devices.
+pub fn channel(
+    latency: usize,
+    input_rate: f64,
+    output_rate: f64,
+) -> (Producer<(f32, f32)>, DriftCorrector) {
+    let (producer, consumer) = RingBuffer::new(latency * 4).split();
+    let corrector = DriftCorrector {
+        consumer,
+        nominal: input_rate / output_rate,
+        correction: 0.0,
+        integral: 0.0,
+        target: latency as f64,
+        fill: None,
+        position: 0.0,
+        frames: ((0.0, 0.0), (0.0, 0.0)),
+    };
+    (producer, corrector)
+}
+
+/// The output side of the FIFO, which reads the input resampled to the output clock.
+pub struct DriftCorrector {
+    consumer: Consumer<(f32, f32)>,
+    /// Ratio of the nominal input and output sample rates.
+    nominal: f64,
+    /// Measured deviation from the nominal ratio.
+    correction: f64,
+    integral: f64,
+    target: f64,
+    /// Smoothed fill level of the FIFO, unknown until the input has filled it up.
+    fill: Option<f64>,
+    /// Position between the two frames being interpolated.
+    position: f64,
+    frames: ((f32, f32), (f32, f32)),
+}
+
+impl DriftCorrector {
+    /// Fills `output` with the input resampled to the output clock. Outputs silence until the
+    /// FIFO reaches its target fill level, and again after an underrun.
+    pub fn read(&mut self, output: &mut [(f32, f32)]) {
+        let len = self.consumer.len() as f64;
+        let fill = match self.fill {
+            Some(fill) => fill + (len - fill) * FILL_SMOOTHING,
+            None if len >= self.target => len,
+            None => {
+                silence(output);
+                return;
+            }
+        };
+        self.fill = Some(fill);
+
+        let error = (fill - self.target) / self.target;
+        self.integral =
+            (self.integral + error * INTEGRAL_GAIN).clamp(-MAX_CORRECTION, MAX_CORRECTION);
+        self.correction =
+            (error * PROPORTIONAL_GAIN + self.integral).clamp(-MAX_CORRECTION, MAX_CORRECTION);
+        let step = self.nominal * (1.0 + self.correction);
+
+        for (i, frame) in output.iter_mut().enumerate() {
+            while self.position >= 1.0 {
+                match self.consumer.pop() {
+                    Some(next) => self.frames = (self.frames.1, next),
+                    None => {
+                        // Underrun: wait for the FIFO to fill up again.
+                        self.fill = None;
+                        self.position = 0.0;
+                        silence(&mut output[i..]);
+                        return;
+                    }
+                }
+                self.position -= 1.0;
+            }
+            let (a, b) = self.frames;
+            let t = self.position as f32;
+            *frame = (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t);
+            self.position += step;
+        }
+    }
+}
+
+fn silence(output: &mut [(f32, f32)]) {
+    for frame in output {
+        *frame = (0.0, 0.0);
+    }
+}
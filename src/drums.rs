@@ -0,0 +1,300 @@
+use crate::engine::{Device, EngineConfig, CONTROL_BLOCK_SIZE};
+use crate::instrument::{Instrument, Quality};
+use crate::param::{Param, Unit};
+use crate::pattern::Step;
+use crate::sampler::{Sampler, Sound, ROOT_PITCH};
+use atomic_float::AtomicF32;
+use std::sync::{atomic::Ordering, Arc};
+
+/// Time after an onset during which the peak level is measured to derive the velocity.
+const MEASURE_TIME: f64 = 0.01;
+/// Minimum time between two hits.
+const HOLD_TIME: f64 = 0.05;
+const ENVELOPE_RELEASE_TIME: f64 = 0.02;
+pub const DEFAULT_THRESHOLD: f32 = -24.0;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Hit {
+    /// Frame of the onset.
+    pub frame: usize,
+    pub velocity: u8,
+}
+
+enum DetectorState {
+    Idle,
+    Measuring { remaining: usize, peak: f32 },
+    Hold { remaining: usize },
+}
+
+/// Finds the transients in an audio signal, one frame at a time so it can run on live input
+/// as well as on a clip.
+pub struct HitDetector {
+    threshold: f32,
+    release: f32,
+    measure_frames: usize,
+    hold_frames: usize,
+    env: f32,
+    state: DetectorState,
+}
+
+impl HitDetector {
+    pub fn new(sample_rate: f64, threshold: f32) -> Self {
+        let mut detector = Self {
+            threshold: 0.0,
+            release: (-1.0 / (ENVELOPE_RELEASE_TIME * sample_rate)).exp() as f32,
+            measure_frames: (MEASURE_TIME * sample_rate) as usize,
+            hold_frames: (HOLD_TIME * sample_rate) as usize,
+            env: 0.0,
+            state: DetectorState::Idle,
+        };
+        detector.set_threshold(threshold);
+        detector
+    }
+
+    /// Sets the onset threshold in dB.
+    pub fn set_threshold(&mut self, threshold: f32) {
+        self.threshold = gain_factor(threshold);
+    }
+
+    pub fn reset(&mut self) {
+        self.env = 0.0;
+        self.state = DetectorState::Idle;
+    }
+
+    /// Feeds one frame to the detector. A hit is reported once its peak level is known, which
+    /// is `delay()` frames after its onset. Returns the velocity of the hit.
+    pub fn process(&mut self, frame: (f32, f32)) -> Option<u8> {
+        let level = f32::max(frame.0.abs(), frame.1.abs());
+        self.env = f32::max(level, self.env * self.release);
+
+        match &mut self.state {
+            DetectorState::Idle => {
+                if self.env >= self.threshold {
+                    self.state = DetectorState::Measuring {
+                        remaining: self.measure_frames,
+                        peak: self.env,
+                    };
+                }
+                None
+            }
+            DetectorState::Measuring { remaining, peak } => {
+                *peak = f32::max(*peak, self.env);
+                if *remaining > 0 {
+                    *remaining -= 1;
+                    return None;
+                }
+                let peak = *peak;
+                self.state = DetectorState::Hold {
+                    remaining: self.hold_frames.saturating_sub(self.measure_frames),
+                };
+                Some(self.velocity(peak))
+            }
+            DetectorState::Hold { remaining } if *remaining > 0 => {
+                *remaining -= 1;
+                None
+            }
+            DetectorState::Hold { .. } => {
+                // Wait for the signal to drop 6dB below the threshold before looking for the
+                // next hit so a noisy decay isn't detected as a roll.
+                if self.env < self.threshold * 0.5 {
+                    self.state = DetectorState::Idle;
+                }
+                None
+            }
+        }
+    }
+
+    /// Number of frames between the onset of a hit and the moment it is reported.
+    pub fn delay(&self) -> usize {
+        self.measure_frames + 1
+    }
+
+    /// Maps the peak level between the threshold and 0dB onto the MIDI velocity range.
+    fn velocity(&self, peak: f32) -> u8 {
+        let db = 20.0 * peak.log10();
+        let floor = 20.0 * self.threshold.log10();
+        if floor >= 0.0 {
+            return 127;
+        }
+        let v = 1.0 + (db - floor) / -floor * 126.0;
+        v.round().clamp(1.0, 127.0) as u8
+    }
+}
+
+/// Detects all hits in a clip.
+pub fn detect_hits(sound: &Sound, threshold: f32) -> Vec<Hit> {
+    let mut detector = HitDetector::new(sound.sample_rate() as f64, threshold);
+    let delay = detector.delay();
+    let silence = std::iter::repeat_n((0.0, 0.0), delay);
+    sound
+        .frames()
+        .chain(silence)
+        .enumerate()
+        .filter_map(|(i, frame)| {
+            detector.process(frame).map(|velocity| Hit {
+                frame: i - delay,
+                velocity,
+            })
+        })
+        .collect()
+}
+
+/// Quantizes hits to lines and returns the steps which trigger `sound` on each of them. Only
+/// the first hit is kept when several fall on the same line, hits past the end of the pattern
+/// are dropped.
+pub fn hit_steps(
+    hits: &[Hit],
+    frames_per_line: f64,
+    num_lines: usize,
+    sound: u8,
+) -> Vec<(usize, Step)> {
+    let mut steps: Vec<(usize, Step)> = Vec::with_capacity(hits.len());
+    for hit in hits {
+        let line = (hit.frame as f64 / frames_per_line).round() as usize;
+        if line >= num_lines {
+            break;
+        }
+        if steps.last().map(|(l, _)| *l) == Some(line) {
+            continue;
+        }
+        let step = Step {
+            pitch: Some(ROOT_PITCH),
+            sound: Some(sound),
+            chord: None,
+            offset: 0,
+        };
+        steps.push((line, step));
+    }
+    steps
+}
+
+/// Plays a clip silently and replaces every hit found in it with a sampler pad, e.g. to
+/// replace a recorded kick drum. A note starts the clip from the beginning.
+pub struct DrumReplacer {
+    clip: Vec<(f32, f32)>,
+    clip_rate: f64,
+    ratio: f64,
+    position: f64,
+    read: usize,
+    column: Option<usize>,
+    detector: HitDetector,
+    threshold: Arc<AtomicF32>,
+    pad: Sampler,
+}
+
+impl DrumReplacer {
+    pub fn new(clip: &Sound, pad: Arc<Sound>) -> Self {
+        let sample_rate = clip.sample_rate() as f64;
+        Self {
+            clip: clip.frames().collect(),
+            clip_rate: sample_rate,
+            ratio: sample_rate / EngineConfig::default().sample_rate,
+            position: 0.0,
+            read: 0,
+            column: None,
+            detector: HitDetector::new(sample_rate, DEFAULT_THRESHOLD),
+            threshold: Arc::new(AtomicF32::new(DEFAULT_THRESHOLD)),
+            pad: Sampler::with_sound(pad),
+        }
+    }
+
+    pub fn with_threshold(self, threshold: f32) -> Self {
+        self.threshold.store(threshold, Ordering::Relaxed);
+        self
+    }
+
+    fn render_block(&mut self, buffer: &mut [(f32, f32)]) {
+        let column = match self.column {
+            Some(column) => column,
+            None => return self.pad.render(buffer),
+        };
+        self.detector
+            .set_threshold(self.threshold.load(Ordering::Relaxed));
+
+        let mut rendered = 0;
+        for i in 0..buffer.len() {
+            self.position += self.ratio;
+            let end = usize::min(self.position as usize, self.clip.len());
+            while self.read < end {
+                if let Some(velocity) = self.detector.process(self.clip[self.read]) {
+                    self.pad.render(&mut buffer[rendered..i]);
+                    rendered = i;
+                    self.pad.note_on(column, ROOT_PITCH, velocity);
+                }
+                self.read += 1;
+            }
+            if self.read == self.clip.len() {
+                self.column = None;
+                break;
+            }
+        }
+        self.pad.render(&mut buffer[rendered..]);
+    }
+}
+
+impl Instrument for DrumReplacer {
+    fn note_on(&mut self, column: usize, _pitch: u8, _velocity: u8) {
+        self.position = 0.0;
+        self.read = 0;
+        self.column = Some(column);
+        self.detector.reset();
+    }
+
+    fn note_off(&mut self, column: usize) {
+        if self.column == Some(column) {
+            self.column = None;
+        }
+        self.pad.note_off(column);
+    }
+
+    fn note_cut(&mut self, column: usize) {
+        if self.column == Some(column) {
+            self.column = None;
+        }
+        self.pad.note_cut(column);
+    }
+
+    fn stop(&mut self) {
+        self.column = None;
+        self.pad.stop();
+    }
+
+    fn reset(&mut self) {
+        self.column = None;
+        self.detector.reset();
+        self.pad.reset();
+    }
+
+    fn prepare(&mut self, config: &EngineConfig) {
+        self.ratio = self.clip_rate / config.sample_rate;
+        self.pad.prepare(config);
+    }
+
+    fn set_quality(&mut self, quality: Quality) {
+        self.pad.set_quality(quality);
+    }
+
+    fn active_voices(&self) -> usize {
+        self.pad.active_voices()
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        let threshold =
+            Param::new(-60.0, Arc::clone(&self.threshold), 0.0, 1.0).with_unit(Unit::Decibel);
+        let mut params = vec![(String::from("Threshold"), threshold)];
+        params.extend(self.pad.params());
+        params
+    }
+}
+
+impl Device for DrumReplacer {
+    fn render(&mut self, buffer: &mut [(f32, f32)]) {
+        for block in buffer.chunks_mut(CONTROL_BLOCK_SIZE) {
+            self.render_block(block);
+        }
+    }
+}
+
+fn gain_factor(db: f32) -> f32 {
+    f32::powf(10.0, db / 20.0)
+}
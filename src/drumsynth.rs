@@ -0,0 +1,385 @@
+//! Drums synthesized from scratch, for rhythm tracks without any sample: a kick, a snare and
+//! closed and open hats. Notes pick the drum by their name in the octave, following the
+//! General MIDI drum map, so every octave plays the same kit.
+
+use crate::engine::{Device, EngineConfig, CONTROL_BLOCK_SIZE};
+use crate::filter::{Coefficients, FilterMode, Svf};
+use crate::instrument::Instrument;
+use crate::param::{Param, Unit};
+use atomic_float::AtomicF32;
+use std::f32::consts::PI;
+use std::sync::{atomic::Ordering, Arc};
+
+/// Level below which a drum has finished sounding.
+const SILENCE: f32 = 1e-4;
+/// Length of the click of the kick, in seconds.
+const CLICK_TIME: f32 = 0.003;
+/// Frequencies of the square waves mixed into the hats, from the TR-808.
+const HAT_FREQUENCIES: [f32; 6] = [205.3, 304.4, 369.6, 522.7, 540.0, 800.0];
+/// Cutoff of the highpass over the hats, at the default tone.
+const HAT_CUTOFF: f32 = 7_000.0;
+const HAT_RESONANCE: f32 = 0.2;
+/// Resonance of the band of noise of the snare.
+const SNARE_RESONANCE: f32 = 0.3;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Drum {
+    Kick,
+    Snare,
+    ClosedHat,
+    OpenHat,
+}
+
+impl Drum {
+    /// C is the kick, D to F the snare, F# to A the closed hat and A# and B the open hat.
+    fn from_pitch(pitch: u8) -> Self {
+        match pitch % 12 {
+            0 | 1 => Drum::Kick,
+            2..=5 => Drum::Snare,
+            10 | 11 => Drum::OpenHat,
+            _ => Drum::ClosedHat,
+        }
+    }
+}
+
+/// A decay reaching silence, -60 dB, after a given time.
+#[derive(Copy, Clone, Debug, Default)]
+struct Decay {
+    level: f32,
+    coefficient: f32,
+}
+
+impl Decay {
+    fn trigger(&mut self, level: f32, time: f32, sample_rate: f32) {
+        self.level = level;
+        self.coefficient = f32::powf(0.001, 1.0 / (time.max(0.001) * sample_rate));
+    }
+
+    fn next(&mut self) -> f32 {
+        let level = self.level;
+        self.level *= self.coefficient;
+        if self.level < SILENCE {
+            self.level = 0.0;
+        }
+        level
+    }
+
+    fn is_silent(&self) -> bool {
+        self.level == 0.0
+    }
+}
+
+/// Settings of the kick.
+struct KickParams {
+    /// Frequency the pitch sweep ends at.
+    pitch: Arc<AtomicF32>,
+    /// How far above `pitch` the sweep starts, in semitones.
+    bend: Arc<AtomicF32>,
+    /// Time the sweep takes to get most of the way down.
+    sweep: Arc<AtomicF32>,
+    decay: Arc<AtomicF32>,
+    /// Level of the click at the start, from 0 to 1.
+    click: Arc<AtomicF32>,
+    level: Arc<AtomicF32>,
+}
+
+#[derive(Default)]
+struct Kick {
+    phase: f32,
+    time: f32,
+    amp: Decay,
+    velocity: f32,
+}
+
+/// Settings of the snare.
+struct SnareParams {
+    /// Frequency of the body.
+    tone: Arc<AtomicF32>,
+    /// Balance of the noise against the body, from 0 to 1.
+    noise: Arc<AtomicF32>,
+    /// Center of the band of noise.
+    cutoff: Arc<AtomicF32>,
+    decay: Arc<AtomicF32>,
+    level: Arc<AtomicF32>,
+}
+
+#[derive(Default)]
+struct Snare {
+    phase: f32,
+    body: Decay,
+    rattle: Decay,
+    filter: Svf,
+    velocity: f32,
+}
+
+/// Settings of the hats, shared by the closed and the open one.
+struct HatParams {
+    /// Scales the frequencies of the metallic noise, 1 being the TR-808.
+    tone: Arc<AtomicF32>,
+    decay: Arc<AtomicF32>,
+    open_decay: Arc<AtomicF32>,
+    level: Arc<AtomicF32>,
+}
+
+/// The closed and the open hat are one voice, so one chokes the other like on a real hi-hat.
+#[derive(Default)]
+struct Hat {
+    phases: [f32; 6],
+    amp: Decay,
+    filter: Svf,
+    velocity: f32,
+}
+
+pub struct DrumSynth {
+    kick: Kick,
+    snare: Snare,
+    hat: Hat,
+    kick_params: KickParams,
+    snare_params: SnareParams,
+    hat_params: HatParams,
+    noise: u32,
+    sample_rate: f32,
+}
+
+impl DrumSynth {
+    pub fn new() -> Self {
+        let value = |value: f32| Arc::new(AtomicF32::new(value));
+        Self {
+            kick: Kick::default(),
+            snare: Snare::default(),
+            hat: Hat::default(),
+            kick_params: KickParams {
+                pitch: value(50.0),
+                bend: value(24.0),
+                sweep: value(0.04),
+                decay: value(0.6),
+                click: value(0.3),
+                level: value(0.0),
+            },
+            snare_params: SnareParams {
+                tone: value(180.0),
+                noise: value(0.6),
+                cutoff: value(4_000.0),
+                decay: value(0.25),
+                level: value(-3.0),
+            },
+            hat_params: HatParams {
+                tone: value(1.0),
+                decay: value(0.08),
+                open_decay: value(0.5),
+                level: value(-6.0),
+            },
+            noise: 0x9e37_79b9,
+            sample_rate: EngineConfig::default().sample_rate as f32,
+        }
+    }
+
+    /// White noise from a xorshift generator.
+    fn noise(&mut self) -> f32 {
+        self.noise ^= self.noise << 13;
+        self.noise ^= self.noise >> 17;
+        self.noise ^= self.noise << 5;
+        self.noise as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+
+    fn render_block(&mut self, buffer: &mut [(f32, f32)]) {
+        let load = |value: &AtomicF32| value.load(Ordering::Relaxed);
+        let sample_rate = self.sample_rate;
+        let kick_gain = db_to_gain(load(&self.kick_params.level));
+        let kick_pitch = load(&self.kick_params.pitch);
+        let kick_bend = f32::powf(2.0, load(&self.kick_params.bend) / 12.0) - 1.0;
+        let kick_sweep = load(&self.kick_params.sweep).max(0.001);
+        let click = load(&self.kick_params.click);
+        let snare_gain = db_to_gain(load(&self.snare_params.level));
+        let snare_tone = load(&self.snare_params.tone);
+        let snare_noise = load(&self.snare_params.noise);
+        let snare_filter = Coefficients::new(
+            load(&self.snare_params.cutoff),
+            SNARE_RESONANCE,
+            sample_rate,
+        );
+        let hat_gain = db_to_gain(load(&self.hat_params.level));
+        let hat_tone = load(&self.hat_params.tone);
+        let hat_filter = Coefficients::new(HAT_CUTOFF * hat_tone, HAT_RESONANCE, sample_rate);
+
+        for out in buffer.iter_mut() {
+            let mut sample = 0.0;
+
+            if !self.kick.amp.is_silent() {
+                let kick = &mut self.kick;
+                let frequency = kick_pitch * (1.0 + kick_bend * f32::exp(-kick.time / kick_sweep));
+                let mut kick_sample = f32::sin(2.0 * PI * kick.phase);
+                kick.phase = (kick.phase + frequency / sample_rate).fract();
+                kick_sample *= kick.amp.next();
+                let clicking = kick.time < CLICK_TIME;
+                kick.time += 1.0 / sample_rate;
+                let velocity = kick.velocity;
+                if clicking {
+                    kick_sample += click * self.noise();
+                }
+                sample += kick_sample * velocity * kick_gain;
+            }
+
+            if !self.snare.body.is_silent() || !self.snare.rattle.is_silent() {
+                let noise = self.noise();
+                let snare = &mut self.snare;
+                let body = f32::sin(2.0 * PI * snare.phase) * snare.body.next();
+                snare.phase = (snare.phase + snare_tone / sample_rate).fract();
+                let rattle = snare
+                    .filter
+                    .process(noise, FilterMode::BandPass, snare_filter)
+                    * snare.rattle.next();
+                let snare_sample = body * (1.0 - snare_noise) + rattle * snare_noise * 2.0;
+                sample += snare_sample * snare.velocity * snare_gain;
+            }
+
+            if !self.hat.amp.is_silent() {
+                let hat = &mut self.hat;
+                let mut metal = 0.0;
+                for (phase, frequency) in hat.phases.iter_mut().zip(HAT_FREQUENCIES) {
+                    metal += if *phase < 0.5 { 1.0 } else { -1.0 };
+                    *phase = (*phase + frequency * hat_tone / sample_rate).fract();
+                }
+                // Little of the squares is left above the highpass, so they're summed at full
+                // level
+                let hat_sample = hat.filter.process(metal, FilterMode::HighPass, hat_filter);
+                sample += hat_sample * hat.amp.next() * hat.velocity * hat_gain;
+            }
+
+            out.0 += sample;
+            out.1 += sample;
+        }
+    }
+}
+
+impl Device for DrumSynth {
+    fn render(&mut self, buffer: &mut [(f32, f32)]) {
+        for block in buffer.chunks_mut(CONTROL_BLOCK_SIZE) {
+            self.render_block(block);
+        }
+    }
+}
+
+impl Instrument for DrumSynth {
+    fn note_on(&mut self, _column: usize, pitch: u8, velocity: u8) {
+        let load = |value: &AtomicF32| value.load(Ordering::Relaxed);
+        let velocity = velocity as f32 / 127.0;
+        let sample_rate = self.sample_rate;
+        match Drum::from_pitch(pitch) {
+            Drum::Kick => {
+                let kick = &mut self.kick;
+                kick.phase = 0.0;
+                kick.time = 0.0;
+                kick.velocity = velocity;
+                kick.amp
+                    .trigger(1.0, load(&self.kick_params.decay), sample_rate);
+            }
+            Drum::Snare => {
+                let snare = &mut self.snare;
+                let decay = load(&self.snare_params.decay);
+                if snare.rattle.is_silent() {
+                    let cutoff = load(&self.snare_params.cutoff);
+                    snare
+                        .filter
+                        .reset(Coefficients::new(cutoff, SNARE_RESONANCE, sample_rate));
+                }
+                snare.phase = 0.0;
+                snare.velocity = velocity;
+                // The body dies out well before the rattle of the wires
+                snare.body.trigger(1.0, decay / 2.0, sample_rate);
+                snare.rattle.trigger(1.0, decay, sample_rate);
+            }
+            drum => {
+                let decay = match drum {
+                    Drum::OpenHat => &self.hat_params.open_decay,
+                    _ => &self.hat_params.decay,
+                };
+                if self.hat.amp.is_silent() {
+                    let cutoff = HAT_CUTOFF * load(&self.hat_params.tone);
+                    self.hat
+                        .filter
+                        .reset(Coefficients::new(cutoff, HAT_RESONANCE, sample_rate));
+                }
+                self.hat.velocity = velocity;
+                self.hat.amp.trigger(1.0, load(decay), sample_rate);
+            }
+        }
+    }
+
+    /// Drums are one-shots, they ring out whatever the length of the note.
+    fn note_off(&mut self, _column: usize) {}
+
+    fn prepare(&mut self, config: &EngineConfig) {
+        self.sample_rate = config.sample_rate as f32;
+    }
+
+    fn reset(&mut self) {
+        self.kick = Kick::default();
+        self.snare = Snare::default();
+        self.hat = Hat::default();
+    }
+
+    /// The drums still ringing.
+    fn active_voices(&self) -> usize {
+        let amps = [&self.kick.amp, &self.snare.rattle, &self.hat.amp];
+        amps.iter().filter(|amp| !amp.is_silent()).count()
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        let kick = &self.kick_params;
+        let snare = &self.snare_params;
+        let hat = &self.hat_params;
+        let seconds = |value: &Arc<AtomicF32>| {
+            Param::new(0.01, Arc::clone(value), 4.0, 0.01).with_unit(Unit::Seconds)
+        };
+        let level = |value: &Arc<AtomicF32>| {
+            Param::new(-60.0, Arc::clone(value), 6.0, 1.0).with_unit(Unit::Decibel)
+        };
+        vec![
+            (
+                "KickPitch",
+                Param::new(20.0, Arc::clone(&kick.pitch), 200.0, 1.0).with_unit(Unit::Hertz),
+            ),
+            (
+                "KickBend",
+                Param::new(0.0, Arc::clone(&kick.bend), 48.0, 1.0),
+            ),
+            (
+                "KickSweep",
+                Param::new(0.001, Arc::clone(&kick.sweep), 0.5, 0.005).with_unit(Unit::Seconds),
+            ),
+            ("KickDecay", seconds(&kick.decay)),
+            (
+                "KickClick",
+                Param::new(0.0, Arc::clone(&kick.click), 1.0, 0.05),
+            ),
+            ("KickLevel", level(&kick.level)),
+            (
+                "SnareTone",
+                Param::new(80.0, Arc::clone(&snare.tone), 400.0, 5.0).with_unit(Unit::Hertz),
+            ),
+            (
+                "SnareNoise",
+                Param::new(0.0, Arc::clone(&snare.noise), 1.0, 0.05),
+            ),
+            (
+                "SnareCutoff",
+                Param::new(500.0, Arc::clone(&snare.cutoff), 12_000.0, 100.0)
+                    .with_unit(Unit::Hertz),
+            ),
+            ("SnareDecay", seconds(&snare.decay)),
+            ("SnareLevel", level(&snare.level)),
+            ("HatTone", Param::new(0.5, Arc::clone(&hat.tone), 2.0, 0.05)),
+            ("HatDecay", seconds(&hat.decay)),
+            ("HatOpenDecay", seconds(&hat.open_decay)),
+            ("HatLevel", level(&hat.level)),
+        ]
+        .into_iter()
+        .map(|(name, param)| (String::from(name), param))
+        .collect()
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    f32::powf(10.0, db / 20.0)
+}
@@ -0,0 +1,1351 @@
+use crate::clap::ClapEffectFactory;
+use crate::engine::{EngineConfig, CONTROL_BLOCK_SIZE, MAX_INSTRUMENTS};
+use crate::filter::{saturate, Biquad, Coefficients, Curve, FilterMode, Oversampler, Svf};
+use crate::instrument::Options;
+use crate::param::{Param, Unit};
+use crate::vst2::Vst2EffectFactory;
+use anyhow::{anyhow, Result};
+use atomic_float::AtomicF32;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// Maximum number of effects in a mixer channel.
+pub const MAX_EFFECTS: usize = 8;
+
+/// Processes the audio of a mixer channel in place.
+pub trait Effect: Send {
+    fn process(&mut self, buffer: &mut [(f32, f32)]);
+
+    /// Called before the effect is first used and whenever the audio settings change.
+    fn prepare(&mut self, _config: &EngineConfig) {}
+
+    /// Called before every buffer with the song tempo, for tempo synced effects.
+    fn set_tempo(&mut self, _bpm: f32) {}
+
+    /// Clears what the effect still holds of the signal, e.g. delay lines, reverb tails and
+    /// filter states, so it starts over in silence. Called from the audio thread.
+    fn reset(&mut self) {}
+
+    /// Instrument channel whose input drives the effect instead of the channel's own signal.
+    fn sidechain(&self) -> Option<usize> {
+        None
+    }
+
+    /// Processes `buffer` with the sidechain signal, `key` has the same length.
+    fn process_keyed(&mut self, buffer: &mut [(f32, f32)], _key: &[(f32, f32)]) {
+        self.process(buffer);
+    }
+
+    /// Frames the effect delays its output by, e.g. to look ahead, at the sample rate it was
+    /// last prepared for.
+    fn latency(&self) -> usize {
+        0
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        Vec::new()
+    }
+}
+
+/// Creates effects of a single type, see `InstrumentFactory`.
+pub trait EffectFactory {
+    fn name(&self) -> &'static str;
+    fn create(&self, options: &Options) -> Result<Box<dyn Effect>>;
+}
+
+pub struct EffectRegistry {
+    factories: Vec<Box<dyn EffectFactory>>,
+}
+
+impl EffectRegistry {
+    /// Registers the built-in effects, `sample_rate` is the rate the engine runs at.
+    pub fn new(sample_rate: Arc<AtomicU32>) -> Self {
+        let mut registry = Self {
+            factories: Vec::new(),
+        };
+        registry.register(Box::new(FilterFactory));
+        registry.register(Box::new(DelayFactory));
+        registry.register(Box::new(ReverbFactory));
+        registry.register(Box::new(CompressorFactory));
+        registry.register(Box::new(LimiterFactory));
+        registry.register(Box::new(ModulationFactory(Modulation::Chorus)));
+        registry.register(Box::new(ModulationFactory(Modulation::Flanger)));
+        registry.register(Box::new(ModulationFactory(Modulation::Phaser)));
+        registry.register(Box::new(DistortionFactory));
+        registry.register(Box::new(EqFactory));
+        registry.register(Box::new(ClapEffectFactory {
+            sample_rate: Arc::clone(&sample_rate),
+        }));
+        registry.register(Box::new(Vst2EffectFactory { sample_rate }));
+        registry
+    }
+
+    /// Adds a factory, replacing any factory registered under the same name.
+    pub fn register(&mut self, factory: Box<dyn EffectFactory>) {
+        self.factories.retain(|f| f.name() != factory.name());
+        self.factories.push(factory);
+    }
+
+    pub fn create(&self, name: &str, options: &Options) -> Result<Box<dyn Effect>> {
+        match self.factories.iter().find(|f| f.name() == name) {
+            Some(factory) => factory.create(options),
+            None => Err(anyhow!("unknown effect type {}", name)),
+        }
+    }
+}
+
+/// Parses an optional numeric option, falling back to `default`.
+fn option_f32(options: &Options, key: &str, default: f32) -> Result<f32> {
+    match options.get(key) {
+        Ok(value) => Ok(value.parse()?),
+        Err(_) => Ok(default),
+    }
+}
+
+/// A multimode state variable filter with drive in front of it.
+pub struct Filter {
+    mode: FilterMode,
+    cutoff: Arc<AtomicF32>,
+    resonance: Arc<AtomicF32>,
+    drive: Arc<AtomicF32>,
+    sample_rate: f32,
+    svf: (Svf, Svf),
+}
+
+impl Filter {
+    fn new(mode: FilterMode, cutoff: f32, resonance: f32, drive: f32) -> Self {
+        let mut filter = Self {
+            mode,
+            cutoff: Arc::new(AtomicF32::new(cutoff)),
+            resonance: Arc::new(AtomicF32::new(resonance)),
+            drive: Arc::new(AtomicF32::new(drive)),
+            sample_rate: EngineConfig::default().sample_rate as f32,
+            svf: (Svf::default(), Svf::default()),
+        };
+        filter.reset();
+        filter
+    }
+
+    fn coefficients(&self) -> Coefficients {
+        Coefficients::new(
+            self.cutoff.load(Ordering::Relaxed),
+            self.resonance.load(Ordering::Relaxed),
+            self.sample_rate,
+        )
+    }
+}
+
+impl Effect for Filter {
+    fn process(&mut self, buffer: &mut [(f32, f32)]) {
+        for block in buffer.chunks_mut(CONTROL_BLOCK_SIZE) {
+            let coefficients = self.coefficients();
+            let drive = self.drive.load(Ordering::Relaxed);
+            for frame in block {
+                let left = saturate(frame.0, drive);
+                let right = saturate(frame.1, drive);
+                frame.0 = self.svf.0.process(left, self.mode, coefficients);
+                frame.1 = self.svf.1.process(right, self.mode, coefficients);
+            }
+        }
+    }
+
+    fn prepare(&mut self, config: &EngineConfig) {
+        self.sample_rate = config.sample_rate as f32;
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        let coefficients = self.coefficients();
+        self.svf.0.reset(coefficients);
+        self.svf.1.reset(coefficients);
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        vec![
+            (
+                String::from("Cutoff"),
+                Param::new(20.0, Arc::clone(&self.cutoff), 20_000.0, 50.0).with_unit(Unit::Hertz),
+            ),
+            (
+                String::from("Resonance"),
+                Param::new(0.0, Arc::clone(&self.resonance), 1.0, 0.05),
+            ),
+            (
+                String::from("Drive"),
+                Param::new(0.0, Arc::clone(&self.drive), 1.0, 0.05),
+            ),
+        ]
+    }
+}
+
+pub struct FilterFactory;
+
+impl EffectFactory for FilterFactory {
+    fn name(&self) -> &'static str {
+        "filter"
+    }
+
+    fn create(&self, options: &Options) -> Result<Box<dyn Effect>> {
+        let mode = FilterMode::parse(options.get_or("mode", "lowpass"))?;
+        let cutoff = option_f32(options, "cutoff", 1000.0)?;
+        let resonance = option_f32(options, "resonance", 0.0)?;
+        let drive = option_f32(options, "drive", 0.0)?;
+        Ok(Box::new(Filter::new(mode, cutoff, resonance, drive)))
+    }
+}
+
+/// Longest delay time in seconds.
+const MAX_DELAY_TIME: f32 = 2.0;
+/// The delay line is allocated up front for this rate, so it never allocates while playing.
+const MAX_DELAY_SAMPLE_RATE: f32 = 96_000.0;
+
+/// Note values a delay can be synced to, with their length in beats. The `Note` param of the
+/// delay is an index into this table plus one, 0 means free time.
+pub const NOTE_VALUES: [(&str, f32); 14] = [
+    ("1/1", 4.0),
+    ("1/2", 2.0),
+    ("1/2d", 3.0),
+    ("1/2t", 4.0 / 3.0),
+    ("1/4", 1.0),
+    ("1/4d", 1.5),
+    ("1/4t", 2.0 / 3.0),
+    ("1/8", 0.5),
+    ("1/8d", 0.75),
+    ("1/8t", 1.0 / 3.0),
+    ("1/16", 0.25),
+    ("1/16d", 0.375),
+    ("1/16t", 1.0 / 6.0),
+    ("1/32", 0.125),
+];
+
+/// A stereo feedback delay. The delay time is either free or synced to a note value at the
+/// song tempo. The feedback path is band limited by a low cut and a high cut filter, so
+/// repeats get darker and thinner. In ping-pong mode the input is summed to mono and the
+/// repeats alternate between the left and the right channel.
+pub struct Delay {
+    time: Arc<AtomicF32>,
+    note: Arc<AtomicF32>,
+    feedback: Arc<AtomicF32>,
+    low_cut: Arc<AtomicF32>,
+    high_cut: Arc<AtomicF32>,
+    mix: Arc<AtomicF32>,
+    ping_pong: bool,
+    bpm: f32,
+    sample_rate: f32,
+    line: Vec<(f32, f32)>,
+    position: usize,
+    // one pole filter states of the feedback path per channel
+    low: (f32, f32),
+    high: (f32, f32),
+}
+
+impl Delay {
+    fn new(time: f32, note: usize, feedback: f32, mix: f32, ping_pong: bool) -> Self {
+        Self {
+            time: Arc::new(AtomicF32::new(time)),
+            note: Arc::new(AtomicF32::new(note as f32)),
+            feedback: Arc::new(AtomicF32::new(feedback)),
+            low_cut: Arc::new(AtomicF32::new(20.0)),
+            high_cut: Arc::new(AtomicF32::new(20_000.0)),
+            mix: Arc::new(AtomicF32::new(mix)),
+            ping_pong,
+            bpm: 120.0,
+            sample_rate: EngineConfig::default().sample_rate as f32,
+            line: vec![(0.0, 0.0); (MAX_DELAY_TIME * MAX_DELAY_SAMPLE_RATE) as usize],
+            position: 0,
+            low: (0.0, 0.0),
+            high: (0.0, 0.0),
+        }
+    }
+
+    /// Delay time in seconds, either the free time or the synced note value.
+    fn seconds(&self) -> f32 {
+        let note = self.note.load(Ordering::Relaxed).round() as usize;
+        match NOTE_VALUES.get(note.wrapping_sub(1)) {
+            Some((_, beats)) => f32::min(beats * 60.0 / self.bpm, MAX_DELAY_TIME),
+            None => self.time.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Coefficient of a one pole lowpass filter.
+    fn coefficient(&self, cutoff: f32) -> f32 {
+        let cutoff = cutoff.clamp(1.0, self.sample_rate * 0.45);
+        1.0 - f32::exp(-2.0 * PI * cutoff / self.sample_rate)
+    }
+}
+
+impl Effect for Delay {
+    fn process(&mut self, buffer: &mut [(f32, f32)]) {
+        let len = self.line.len();
+        for block in buffer.chunks_mut(CONTROL_BLOCK_SIZE) {
+            let time = self.seconds() * self.sample_rate;
+            let delay = (time as usize).clamp(1, len - 1);
+            let feedback = self.feedback.load(Ordering::Relaxed);
+            let mix = self.mix.load(Ordering::Relaxed);
+            let high_cut = self.coefficient(self.high_cut.load(Ordering::Relaxed));
+            let low_cut = self.coefficient(self.low_cut.load(Ordering::Relaxed));
+            for frame in block {
+                let delayed = self.line[(self.position + len - delay) % len];
+                let repeat = (
+                    one_pole(
+                        delayed.0,
+                        high_cut,
+                        low_cut,
+                        &mut self.high.0,
+                        &mut self.low.0,
+                    ),
+                    one_pole(
+                        delayed.1,
+                        high_cut,
+                        low_cut,
+                        &mut self.high.1,
+                        &mut self.low.1,
+                    ),
+                );
+                self.line[self.position] = match self.ping_pong {
+                    true => (
+                        (frame.0 + frame.1) * 0.5 + repeat.1 * feedback,
+                        repeat.0 * feedback,
+                    ),
+                    false => (frame.0 + repeat.0 * feedback, frame.1 + repeat.1 * feedback),
+                };
+                self.position = (self.position + 1) % len;
+                frame.0 = frame.0 * (1.0 - mix) + delayed.0 * mix;
+                frame.1 = frame.1 * (1.0 - mix) + delayed.1 * mix;
+            }
+        }
+    }
+
+    fn prepare(&mut self, config: &EngineConfig) {
+        self.sample_rate = config.sample_rate as f32;
+    }
+
+    fn set_tempo(&mut self, bpm: f32) {
+        self.bpm = bpm.max(1.0);
+    }
+
+    fn reset(&mut self) {
+        for frame in &mut self.line {
+            *frame = (0.0, 0.0);
+        }
+        self.low = (0.0, 0.0);
+        self.high = (0.0, 0.0);
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        vec![
+            (
+                String::from("Time"),
+                Param::new(0.001, Arc::clone(&self.time), MAX_DELAY_TIME, 0.01)
+                    .with_unit(Unit::Seconds),
+            ),
+            (
+                String::from("Note"),
+                Param::new(0.0, Arc::clone(&self.note), NOTE_VALUES.len() as f32, 1.0),
+            ),
+            (
+                String::from("Feedback"),
+                Param::new(0.0, Arc::clone(&self.feedback), 0.95, 0.05),
+            ),
+            (
+                String::from("LowCut"),
+                Param::new(20.0, Arc::clone(&self.low_cut), 2_000.0, 10.0).with_unit(Unit::Hertz),
+            ),
+            (
+                String::from("HighCut"),
+                Param::new(500.0, Arc::clone(&self.high_cut), 20_000.0, 250.0)
+                    .with_unit(Unit::Hertz),
+            ),
+            (
+                String::from("Mix"),
+                Param::new(0.0, Arc::clone(&self.mix), 1.0, 0.05),
+            ),
+        ]
+    }
+}
+
+/// Band limits a sample with a one pole lowpass at the high cut, followed by a one pole
+/// highpass at the low cut.
+fn one_pole(input: f32, high_cut: f32, low_cut: f32, high: &mut f32, low: &mut f32) -> f32 {
+    *high += high_cut * (input - *high);
+    *low += low_cut * (*high - *low);
+    *high - *low
+}
+
+pub struct DelayFactory;
+
+impl EffectFactory for DelayFactory {
+    fn name(&self) -> &'static str {
+        "delay"
+    }
+
+    /// Takes `time` in seconds or `sync` as a note value such as 1/8d, and `feedback`, `mix`
+    /// and `mode=stereo|pingpong`.
+    fn create(&self, options: &Options) -> Result<Box<dyn Effect>> {
+        let time = option_f32(options, "time", 0.25)?;
+        let feedback = option_f32(options, "feedback", 0.4)?;
+        let mix = option_f32(options, "mix", 0.3)?;
+        if !(0.0..=MAX_DELAY_TIME).contains(&time) {
+            return Err(anyhow!("delay time must be at most {}s", MAX_DELAY_TIME));
+        }
+        let note = match options.get("sync") {
+            Ok(sync) => match NOTE_VALUES.iter().position(|(name, _)| *name == sync) {
+                Some(index) => index + 1,
+                None => return Err(anyhow!("unknown note value {}", sync)),
+            },
+            Err(_) => 0,
+        };
+        let ping_pong = match options.get_or("mode", "stereo") {
+            "stereo" => false,
+            "pingpong" => true,
+            mode => return Err(anyhow!("unknown delay mode {}", mode)),
+        };
+        Ok(Box::new(Delay::new(time, note, feedback, mix, ping_pong)))
+    }
+}
+
+/// Longest pre-delay of the reverb in seconds.
+const MAX_PRE_DELAY: f32 = 0.5;
+/// Delay lengths of the Freeverb combs and allpasses in samples at 44.1kHz, the right channel
+/// uses slightly longer ones to decorrelate it from the left.
+const COMB_TUNING: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNING: [usize; 4] = [556, 441, 341, 225];
+const STEREO_SPREAD: usize = 23;
+const TUNING_RATE: f32 = 44_100.0;
+/// Scales the input down so the eight parallel combs don't clip.
+const REVERB_INPUT_GAIN: f32 = 0.015;
+const REVERB_WET_GAIN: f32 = 3.0;
+
+/// A delay line whose buffer is allocated for the highest supported sample rate, so its length
+/// can change without allocating.
+struct Line {
+    buf: Vec<f32>,
+    len: usize,
+    position: usize,
+}
+
+impl Line {
+    fn new(tuning: usize) -> Self {
+        let capacity = (tuning as f32 * MAX_DELAY_SAMPLE_RATE / TUNING_RATE).ceil() as usize;
+        Self {
+            buf: vec![0.0; capacity.max(1)],
+            len: tuning,
+            position: 0,
+        }
+    }
+
+    fn prepare(&mut self, tuning: usize, sample_rate: f32) {
+        let len = (tuning as f32 * sample_rate / TUNING_RATE).round() as usize;
+        self.len = len.clamp(1, self.buf.len());
+        self.clear();
+    }
+
+    fn clear(&mut self) {
+        self.position = 0;
+        for sample in &mut self.buf {
+            *sample = 0.0;
+        }
+    }
+
+    /// Returns the oldest sample and replaces it with `input`.
+    fn next(&mut self, input: impl FnOnce(f32) -> f32) -> f32 {
+        let output = self.buf[self.position];
+        self.buf[self.position] = input(output);
+        self.position = (self.position + 1) % self.len;
+        output
+    }
+}
+
+/// A lowpass feedback comb filter.
+struct Comb {
+    line: Line,
+    tuning: usize,
+    store: f32,
+}
+
+impl Comb {
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let store = &mut self.store;
+        self.line.next(|output| {
+            *store = output * (1.0 - damping) + *store * damping;
+            input + *store * feedback
+        })
+    }
+}
+
+struct AllPass {
+    line: Line,
+    tuning: usize,
+}
+
+impl AllPass {
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.line.next(|output| input + output * 0.5);
+        delayed - input
+    }
+}
+
+/// An algorithmic reverb after Freeverb: eight parallel lowpass combs followed by four
+/// allpasses per channel, with a pre-delay in front. Works as an insert, or on a return bus
+/// with Mix at 1.
+pub struct Reverb {
+    size: Arc<AtomicF32>,
+    damping: Arc<AtomicF32>,
+    pre_delay: Arc<AtomicF32>,
+    mix: Arc<AtomicF32>,
+    sample_rate: f32,
+    pre_delay_line: Vec<(f32, f32)>,
+    position: usize,
+    combs: Vec<(Comb, Comb)>,
+    allpasses: Vec<(AllPass, AllPass)>,
+}
+
+impl Reverb {
+    fn new(size: f32, damping: f32, pre_delay: f32, mix: f32) -> Self {
+        let comb = |tuning| Comb {
+            line: Line::new(tuning),
+            tuning,
+            store: 0.0,
+        };
+        let allpass = |tuning| AllPass {
+            line: Line::new(tuning),
+            tuning,
+        };
+        let mut reverb = Self {
+            size: Arc::new(AtomicF32::new(size)),
+            damping: Arc::new(AtomicF32::new(damping)),
+            pre_delay: Arc::new(AtomicF32::new(pre_delay)),
+            mix: Arc::new(AtomicF32::new(mix)),
+            sample_rate: EngineConfig::default().sample_rate as f32,
+            pre_delay_line: vec![(0.0, 0.0); (MAX_PRE_DELAY * MAX_DELAY_SAMPLE_RATE) as usize + 1],
+            position: 0,
+            combs: COMB_TUNING
+                .iter()
+                .map(|&t| (comb(t), comb(t + STEREO_SPREAD)))
+                .collect(),
+            allpasses: ALLPASS_TUNING
+                .iter()
+                .map(|&t| (allpass(t), allpass(t + STEREO_SPREAD)))
+                .collect(),
+        };
+        reverb.prepare(&EngineConfig::default());
+        reverb
+    }
+}
+
+impl Effect for Reverb {
+    fn process(&mut self, buffer: &mut [(f32, f32)]) {
+        let len = self.pre_delay_line.len();
+        for block in buffer.chunks_mut(CONTROL_BLOCK_SIZE) {
+            let feedback = 0.7 + self.size.load(Ordering::Relaxed) * 0.28;
+            let damping = self.damping.load(Ordering::Relaxed) * 0.4;
+            let pre_delay = self.pre_delay.load(Ordering::Relaxed) * self.sample_rate;
+            let pre_delay = (pre_delay as usize).min(len - 1);
+            let mix = self.mix.load(Ordering::Relaxed);
+            for frame in block {
+                self.pre_delay_line[self.position] = *frame;
+                let delayed = self.pre_delay_line[(self.position + len - pre_delay) % len];
+                self.position = (self.position + 1) % len;
+
+                let input = (delayed.0 + delayed.1) * REVERB_INPUT_GAIN;
+                let mut wet = (0.0, 0.0);
+                for (left, right) in &mut self.combs {
+                    wet.0 += left.process(input, feedback, damping);
+                    wet.1 += right.process(input, feedback, damping);
+                }
+                for (left, right) in &mut self.allpasses {
+                    wet.0 = left.process(wet.0);
+                    wet.1 = right.process(wet.1);
+                }
+                frame.0 = frame.0 * (1.0 - mix) + wet.0 * mix * REVERB_WET_GAIN;
+                frame.1 = frame.1 * (1.0 - mix) + wet.1 * mix * REVERB_WET_GAIN;
+            }
+        }
+    }
+
+    fn prepare(&mut self, config: &EngineConfig) {
+        self.sample_rate = config.sample_rate as f32;
+        for (left, right) in &mut self.combs {
+            left.line.prepare(left.tuning, self.sample_rate);
+            right.line.prepare(right.tuning, self.sample_rate);
+        }
+        for (left, right) in &mut self.allpasses {
+            left.line.prepare(left.tuning, self.sample_rate);
+            right.line.prepare(right.tuning, self.sample_rate);
+        }
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        for frame in &mut self.pre_delay_line {
+            *frame = (0.0, 0.0);
+        }
+        for (left, right) in &mut self.combs {
+            for comb in [left, right] {
+                comb.line.clear();
+                comb.store = 0.0;
+            }
+        }
+        for (left, right) in &mut self.allpasses {
+            left.line.clear();
+            right.line.clear();
+        }
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        vec![
+            (
+                String::from("Size"),
+                Param::new(0.0, Arc::clone(&self.size), 1.0, 0.05),
+            ),
+            (
+                String::from("Damping"),
+                Param::new(0.0, Arc::clone(&self.damping), 1.0, 0.05),
+            ),
+            (
+                String::from("PreDelay"),
+                Param::new(0.0, Arc::clone(&self.pre_delay), MAX_PRE_DELAY, 0.005)
+                    .with_unit(Unit::Seconds),
+            ),
+            (
+                String::from("Mix"),
+                Param::new(0.0, Arc::clone(&self.mix), 1.0, 0.05),
+            ),
+        ]
+    }
+}
+
+pub struct ReverbFactory;
+
+impl EffectFactory for ReverbFactory {
+    fn name(&self) -> &'static str {
+        "reverb"
+    }
+
+    fn create(&self, options: &Options) -> Result<Box<dyn Effect>> {
+        let size = option_f32(options, "size", 0.5)?;
+        let damping = option_f32(options, "damping", 0.5)?;
+        let pre_delay = option_f32(options, "predelay", 0.0)?;
+        let mix = option_f32(options, "mix", 0.3)?;
+        if !(0.0..=MAX_PRE_DELAY).contains(&pre_delay) {
+            return Err(anyhow!("pre-delay must be at most {}s", MAX_PRE_DELAY));
+        }
+        Ok(Box::new(Reverb::new(size, damping, pre_delay, mix)))
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    f32::powf(10.0, db / 20.0)
+}
+
+fn gain_to_db(gain: f32) -> f32 {
+    20.0 * gain.max(1e-6).log10()
+}
+
+/// Coefficient of a one pole smoother reaching about 63% of a step after `time` seconds.
+fn smoothing(time: f32, sample_rate: f32) -> f32 {
+    f32::exp(-1.0 / (time.max(1e-5) * sample_rate))
+}
+
+/// A feed-forward compressor with a stereo linked peak detector. The detector listens to the
+/// channel itself, or to another instrument channel when keyed.
+pub struct Compressor {
+    threshold: Arc<AtomicF32>,
+    ratio: Arc<AtomicF32>,
+    attack: Arc<AtomicF32>,
+    release: Arc<AtomicF32>,
+    makeup: Arc<AtomicF32>,
+    key: Option<usize>,
+    sample_rate: f32,
+    /// Current gain reduction in dB.
+    reduction: f32,
+}
+
+impl Compressor {
+    fn process_with<F: Fn(usize) -> (f32, f32)>(&mut self, buffer: &mut [(f32, f32)], detector: F) {
+        let mut start = 0;
+        for block in buffer.chunks_mut(CONTROL_BLOCK_SIZE) {
+            let threshold = self.threshold.load(Ordering::Relaxed);
+            let slope = 1.0 - 1.0 / self.ratio.load(Ordering::Relaxed).max(1.0);
+            let attack = smoothing(self.attack.load(Ordering::Relaxed), self.sample_rate);
+            let release = smoothing(self.release.load(Ordering::Relaxed), self.sample_rate);
+            let makeup = self.makeup.load(Ordering::Relaxed);
+            for (i, frame) in block.iter_mut().enumerate() {
+                let input = detector(start + i);
+                let level = gain_to_db(f32::max(input.0.abs(), input.1.abs()));
+                let target = f32::max(level - threshold, 0.0) * slope;
+                let coefficient = if target > self.reduction {
+                    attack
+                } else {
+                    release
+                };
+                self.reduction = target + (self.reduction - target) * coefficient;
+                let gain = db_to_gain(makeup - self.reduction);
+                frame.0 *= gain;
+                frame.1 *= gain;
+            }
+            start += block.len();
+        }
+    }
+}
+
+impl Effect for Compressor {
+    fn process(&mut self, buffer: &mut [(f32, f32)]) {
+        let input = buffer.to_owned();
+        self.process_with(buffer, |i| input[i]);
+    }
+
+    fn process_keyed(&mut self, buffer: &mut [(f32, f32)], key: &[(f32, f32)]) {
+        self.process_with(buffer, |i| key[i]);
+    }
+
+    fn sidechain(&self) -> Option<usize> {
+        self.key
+    }
+
+    fn prepare(&mut self, config: &EngineConfig) {
+        self.sample_rate = config.sample_rate as f32;
+    }
+
+    fn reset(&mut self) {
+        self.reduction = 0.0;
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        vec![
+            (
+                String::from("Threshold"),
+                Param::new(-60.0, Arc::clone(&self.threshold), 0.0, 1.0).with_unit(Unit::Decibel),
+            ),
+            (
+                String::from("Ratio"),
+                Param::new(1.0, Arc::clone(&self.ratio), 20.0, 0.5),
+            ),
+            (
+                String::from("Attack"),
+                Param::new(0.0001, Arc::clone(&self.attack), 0.5, 0.001).with_unit(Unit::Seconds),
+            ),
+            (
+                String::from("Release"),
+                Param::new(0.01, Arc::clone(&self.release), 2.0, 0.01).with_unit(Unit::Seconds),
+            ),
+            (
+                String::from("Makeup"),
+                Param::new(0.0, Arc::clone(&self.makeup), 24.0, 0.5).with_unit(Unit::Decibel),
+            ),
+        ]
+    }
+}
+
+pub struct CompressorFactory;
+
+impl EffectFactory for CompressorFactory {
+    fn name(&self) -> &'static str {
+        "compressor"
+    }
+
+    fn create(&self, options: &Options) -> Result<Box<dyn Effect>> {
+        let key = match options.get("key") {
+            Ok(key) => Some(key.parse()?),
+            Err(_) => None,
+        };
+        if key.is_some_and(|key| key >= MAX_INSTRUMENTS) {
+            return Err(anyhow!(
+                "sidechain key must be a track below {}",
+                MAX_INSTRUMENTS
+            ));
+        }
+        Ok(Box::new(Compressor {
+            threshold: Arc::new(AtomicF32::new(option_f32(options, "threshold", -18.0)?)),
+            ratio: Arc::new(AtomicF32::new(option_f32(options, "ratio", 4.0)?)),
+            attack: Arc::new(AtomicF32::new(option_f32(options, "attack", 0.01)?)),
+            release: Arc::new(AtomicF32::new(option_f32(options, "release", 0.1)?)),
+            makeup: Arc::new(AtomicF32::new(option_f32(options, "makeup", 0.0)?)),
+            key,
+            sample_rate: EngineConfig::default().sample_rate as f32,
+            reduction: 0.0,
+        }))
+    }
+}
+
+/// How far the limiter looks ahead, in seconds. Its output is delayed by as much.
+const LIMITER_LOOKAHEAD: f32 = 0.005;
+
+/// A brickwall limiter for the master channel. Peaks are detected ahead of time so the gain
+/// can be ramped down before they come out, anything still above the ceiling is clipped.
+pub struct Limiter {
+    ceiling: Arc<AtomicF32>,
+    release: Arc<AtomicF32>,
+    sample_rate: f32,
+    line: Vec<(f32, f32)>,
+    lookahead: usize,
+    position: usize,
+    gain: f32,
+    target: f32,
+    step: f32,
+    hold: usize,
+}
+
+impl Effect for Limiter {
+    fn process(&mut self, buffer: &mut [(f32, f32)]) {
+        for block in buffer.chunks_mut(CONTROL_BLOCK_SIZE) {
+            let ceiling = db_to_gain(self.ceiling.load(Ordering::Relaxed));
+            let release = 1.0 - smoothing(self.release.load(Ordering::Relaxed), self.sample_rate);
+            for frame in block {
+                let peak = f32::max(frame.0.abs(), frame.1.abs());
+                let needed = if peak > ceiling { ceiling / peak } else { 1.0 };
+                if needed < f32::min(self.gain, self.target) {
+                    // Reach the new gain just as the peak comes out of the delay line.
+                    self.target = needed;
+                    self.step = (self.gain - needed) / self.lookahead as f32;
+                    self.hold = self.lookahead;
+                }
+                if self.hold > 0 {
+                    self.gain = f32::max(self.gain - self.step, self.target);
+                    self.hold -= 1;
+                } else {
+                    self.target = 1.0;
+                    self.gain += (1.0 - self.gain) * release;
+                }
+
+                let len = self.lookahead + 1;
+                self.line[self.position] = *frame;
+                self.position = (self.position + 1) % len;
+                let delayed = self.line[self.position];
+                frame.0 = (delayed.0 * self.gain).clamp(-ceiling, ceiling);
+                frame.1 = (delayed.1 * self.gain).clamp(-ceiling, ceiling);
+            }
+        }
+    }
+
+    fn prepare(&mut self, config: &EngineConfig) {
+        self.sample_rate = config.sample_rate as f32;
+        let lookahead = (LIMITER_LOOKAHEAD * self.sample_rate) as usize;
+        self.lookahead = lookahead.clamp(1, self.line.len() - 1);
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        self.position = 0;
+        for frame in &mut self.line {
+            *frame = (0.0, 0.0);
+        }
+        self.gain = 1.0;
+        self.target = 1.0;
+        self.hold = 0;
+    }
+
+    fn latency(&self) -> usize {
+        self.lookahead
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        vec![
+            (
+                String::from("Ceiling"),
+                Param::new(-12.0, Arc::clone(&self.ceiling), 0.0, 0.1).with_unit(Unit::Decibel),
+            ),
+            (
+                String::from("Release"),
+                Param::new(0.01, Arc::clone(&self.release), 1.0, 0.01).with_unit(Unit::Seconds),
+            ),
+        ]
+    }
+}
+
+pub struct LimiterFactory;
+
+impl EffectFactory for LimiterFactory {
+    fn name(&self) -> &'static str {
+        "limiter"
+    }
+
+    fn create(&self, options: &Options) -> Result<Box<dyn Effect>> {
+        let mut limiter = Limiter {
+            ceiling: Arc::new(AtomicF32::new(option_f32(options, "ceiling", -0.3)?)),
+            release: Arc::new(AtomicF32::new(option_f32(options, "release", 0.1)?)),
+            sample_rate: EngineConfig::default().sample_rate as f32,
+            line: vec![(0.0, 0.0); (LIMITER_LOOKAHEAD * MAX_DELAY_SAMPLE_RATE) as usize + 2],
+            lookahead: 1,
+            position: 0,
+            gain: 1.0,
+            target: 1.0,
+            step: 0.0,
+            hold: 0,
+        };
+        limiter.prepare(&EngineConfig::default());
+        Ok(Box::new(limiter))
+    }
+}
+
+/// Longest delay of the modulation effects in seconds.
+const MAX_MODULATION_DELAY: f32 = 0.05;
+const PHASER_STAGES: usize = 6;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Modulation {
+    Chorus,
+    Flanger,
+    Phaser,
+}
+
+impl Modulation {
+    /// Delay swept by the LFO in seconds, as its shortest time and the range covered at full
+    /// depth. The phaser sweeps the frequency of its allpass stages in Hz instead.
+    fn sweep(self) -> (f32, f32) {
+        match self {
+            Modulation::Chorus => (0.01, 0.02),
+            Modulation::Flanger => (0.0005, 0.005),
+            Modulation::Phaser => (200.0, 4_000.0),
+        }
+    }
+}
+
+/// A delay line read at a fractional position, which can move from one sample to the next.
+struct ModulatedLine {
+    buf: Vec<(f32, f32)>,
+    position: usize,
+}
+
+impl ModulatedLine {
+    fn new() -> Self {
+        let len = (MAX_MODULATION_DELAY * MAX_DELAY_SAMPLE_RATE) as usize + 2;
+        Self {
+            buf: vec![(0.0, 0.0); len],
+            position: 0,
+        }
+    }
+
+    fn push(&mut self, frame: (f32, f32)) {
+        self.position = (self.position + 1) % self.buf.len();
+        self.buf[self.position] = frame;
+    }
+
+    /// Reads `delay` samples back on each channel, interpolating linearly.
+    fn read(&self, delay: (f32, f32)) -> (f32, f32) {
+        let len = self.buf.len();
+        let tap = |delay: f32| {
+            let delay = delay.clamp(1.0, (len - 2) as f32);
+            let whole = delay as usize;
+            let fraction = delay - whole as f32;
+            let a = (self.position + len - whole) % len;
+            let b = (a + len - 1) % len;
+            (a, b, fraction)
+        };
+        let (a, b, fraction) = tap(delay.0);
+        let left = self.buf[a].0 + (self.buf[b].0 - self.buf[a].0) * fraction;
+        let (a, b, fraction) = tap(delay.1);
+        let right = self.buf[a].1 + (self.buf[b].1 - self.buf[a].1) * fraction;
+        (left, right)
+    }
+
+    fn clear(&mut self) {
+        for frame in &mut self.buf {
+            *frame = (0.0, 0.0);
+        }
+    }
+}
+
+/// Chorus, flanger and phaser, which all move a short delay with an LFO and mix the result
+/// with the dry signal. The chorus and flanger read a modulated delay line, the phaser sweeps
+/// a chain of allpass filters, which delay every frequency by a different amount. Spread
+/// moves the LFO of the right channel up to half a cycle away from the left one.
+pub struct Modulator {
+    kind: Modulation,
+    rate: Arc<AtomicF32>,
+    depth: Arc<AtomicF32>,
+    feedback: Arc<AtomicF32>,
+    spread: Arc<AtomicF32>,
+    mix: Arc<AtomicF32>,
+    sample_rate: f32,
+    /// LFO phase from 0 to 1.
+    phase: f32,
+    line: ModulatedLine,
+    /// States of the phaser stages per channel.
+    stages: [[f32; PHASER_STAGES]; 2],
+    /// Previous wet output, fed back into the input.
+    last: (f32, f32),
+}
+
+impl Modulator {
+    /// Runs a sample through the phaser's allpass chain, `coefficient` sets where it delays the
+    /// phase by 90 degrees per stage.
+    fn phase_shift(stages: &mut [f32; PHASER_STAGES], input: f32, coefficient: f32) -> f32 {
+        let mut signal = input;
+        for state in stages.iter_mut() {
+            let output = coefficient * signal + *state;
+            *state = signal - coefficient * output;
+            signal = output;
+        }
+        signal
+    }
+}
+
+impl Effect for Modulator {
+    fn process(&mut self, buffer: &mut [(f32, f32)]) {
+        let (start, range) = self.kind.sweep();
+        for block in buffer.chunks_mut(CONTROL_BLOCK_SIZE) {
+            let increment = self.rate.load(Ordering::Relaxed) / self.sample_rate;
+            let depth = self.depth.load(Ordering::Relaxed);
+            let feedback = self.feedback.load(Ordering::Relaxed);
+            let spread = self.spread.load(Ordering::Relaxed) * 0.5;
+            let mix = self.mix.load(Ordering::Relaxed);
+            for frame in block {
+                let lfo = |phase: f32| 0.5 - 0.5 * f32::cos(2.0 * PI * phase);
+                let sweep = (
+                    start + range * depth * lfo(self.phase),
+                    start + range * depth * lfo(self.phase + spread),
+                );
+                self.phase = (self.phase + increment).fract();
+
+                let input = (
+                    frame.0 + self.last.0 * feedback,
+                    frame.1 + self.last.1 * feedback,
+                );
+                let wet = match self.kind {
+                    Modulation::Chorus | Modulation::Flanger => {
+                        self.line.push(input);
+                        self.line
+                            .read((sweep.0 * self.sample_rate, sweep.1 * self.sample_rate))
+                    }
+                    Modulation::Phaser => {
+                        let coefficient = |frequency: f32| {
+                            let t = f32::tan(
+                                PI * frequency.min(self.sample_rate * 0.45) / self.sample_rate,
+                            );
+                            (t - 1.0) / (t + 1.0)
+                        };
+                        let (left, right) = (coefficient(sweep.0), coefficient(sweep.1));
+                        (
+                            Self::phase_shift(&mut self.stages[0], input.0, left),
+                            Self::phase_shift(&mut self.stages[1], input.1, right),
+                        )
+                    }
+                };
+                self.last = wet;
+                frame.0 = frame.0 * (1.0 - mix) + wet.0 * mix;
+                frame.1 = frame.1 * (1.0 - mix) + wet.1 * mix;
+            }
+        }
+    }
+
+    fn prepare(&mut self, config: &EngineConfig) {
+        self.sample_rate = config.sample_rate as f32;
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        self.phase = 0.0;
+        self.line.clear();
+        self.stages = [[0.0; PHASER_STAGES]; 2];
+        self.last = (0.0, 0.0);
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        vec![
+            (
+                String::from("Rate"),
+                Param::new(0.01, Arc::clone(&self.rate), 10.0, 0.05).with_unit(Unit::Hertz),
+            ),
+            (
+                String::from("Depth"),
+                Param::new(0.0, Arc::clone(&self.depth), 1.0, 0.05),
+            ),
+            (
+                String::from("Feedback"),
+                Param::new(-0.95, Arc::clone(&self.feedback), 0.95, 0.05),
+            ),
+            (
+                String::from("Spread"),
+                Param::new(0.0, Arc::clone(&self.spread), 1.0, 0.05),
+            ),
+            (
+                String::from("Mix"),
+                Param::new(0.0, Arc::clone(&self.mix), 1.0, 0.05),
+            ),
+        ]
+    }
+}
+
+pub struct ModulationFactory(pub Modulation);
+
+impl EffectFactory for ModulationFactory {
+    fn name(&self) -> &'static str {
+        match self.0 {
+            Modulation::Chorus => "chorus",
+            Modulation::Flanger => "flanger",
+            Modulation::Phaser => "phaser",
+        }
+    }
+
+    /// Takes `rate` in Hz, `depth`, `feedback`, `spread` and `mix`.
+    fn create(&self, options: &Options) -> Result<Box<dyn Effect>> {
+        let (rate, feedback) = match self.0 {
+            Modulation::Chorus => (0.8, 0.0),
+            Modulation::Flanger => (0.2, 0.6),
+            Modulation::Phaser => (0.4, 0.4),
+        };
+        let feedback = option_f32(options, "feedback", feedback)?;
+        if !(-0.95..=0.95).contains(&feedback) {
+            return Err(anyhow!("feedback must be between -0.95 and 0.95"));
+        }
+        let mut modulator = Modulator {
+            kind: self.0,
+            rate: Arc::new(AtomicF32::new(option_f32(options, "rate", rate)?)),
+            depth: Arc::new(AtomicF32::new(option_f32(options, "depth", 0.5)?)),
+            feedback: Arc::new(AtomicF32::new(feedback)),
+            spread: Arc::new(AtomicF32::new(option_f32(options, "spread", 0.5)?)),
+            mix: Arc::new(AtomicF32::new(option_f32(options, "mix", 0.5)?)),
+            sample_rate: EngineConfig::default().sample_rate as f32,
+            phase: 0.0,
+            line: ModulatedLine::new(),
+            stages: [[0.0; PHASER_STAGES]; 2],
+            last: (0.0, 0.0),
+        };
+        modulator.prepare(&EngineConfig::default());
+        Ok(Box::new(modulator))
+    }
+}
+
+/// A waveshaper, run at a multiple of the sample rate to keep aliasing down. Drive is the
+/// gain into the curve, tone a lowpass after it.
+pub struct Distortion {
+    curve: Curve,
+    factor: usize,
+    drive: Arc<AtomicF32>,
+    tone: Arc<AtomicF32>,
+    mix: Arc<AtomicF32>,
+    sample_rate: f32,
+    oversamplers: (Oversampler, Oversampler),
+    /// One pole tone filter state per channel.
+    tone_state: (f32, f32),
+}
+
+impl Effect for Distortion {
+    fn process(&mut self, buffer: &mut [(f32, f32)]) {
+        let curve = self.curve;
+        for block in buffer.chunks_mut(CONTROL_BLOCK_SIZE) {
+            let drive = db_to_gain(self.drive.load(Ordering::Relaxed));
+            let tone = self
+                .tone
+                .load(Ordering::Relaxed)
+                .clamp(1.0, self.sample_rate * 0.45);
+            let tone = 1.0 - f32::exp(-2.0 * PI * tone / self.sample_rate);
+            let mix = self.mix.load(Ordering::Relaxed);
+            for frame in block {
+                let shape = |sample: f32| curve.shape(sample * drive);
+                let left = self.oversamplers.0.process(frame.0, shape);
+                let right = self.oversamplers.1.process(frame.1, shape);
+                self.tone_state.0 += tone * (left - self.tone_state.0);
+                self.tone_state.1 += tone * (right - self.tone_state.1);
+                frame.0 = frame.0 * (1.0 - mix) + self.tone_state.0 * mix;
+                frame.1 = frame.1 * (1.0 - mix) + self.tone_state.1 * mix;
+            }
+        }
+    }
+
+    fn prepare(&mut self, config: &EngineConfig) {
+        self.sample_rate = config.sample_rate as f32;
+        let oversampler = Oversampler::new(self.factor, self.sample_rate);
+        self.oversamplers = (oversampler.clone(), oversampler);
+        self.tone_state = (0.0, 0.0);
+    }
+
+    fn reset(&mut self) {
+        self.oversamplers.0.reset();
+        self.oversamplers.1.reset();
+        self.tone_state = (0.0, 0.0);
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        vec![
+            (
+                String::from("Drive"),
+                Param::new(0.0, Arc::clone(&self.drive), 48.0, 1.0).with_unit(Unit::Decibel),
+            ),
+            (
+                String::from("Tone"),
+                Param::new(200.0, Arc::clone(&self.tone), 20_000.0, 250.0).with_unit(Unit::Hertz),
+            ),
+            (
+                String::from("Mix"),
+                Param::new(0.0, Arc::clone(&self.mix), 1.0, 0.05),
+            ),
+        ]
+    }
+}
+
+pub struct DistortionFactory;
+
+impl EffectFactory for DistortionFactory {
+    fn name(&self) -> &'static str {
+        "distortion"
+    }
+
+    /// Takes `curve=tanh|hard|fold`, `oversample=1|2|4`, `drive` in dB, `tone` in Hz and
+    /// `mix`.
+    fn create(&self, options: &Options) -> Result<Box<dyn Effect>> {
+        let curve = Curve::parse(options.get_or("curve", "tanh"))?;
+        let factor = match options.get_or("oversample", "4") {
+            "1" => 1,
+            "2" => 2,
+            "4" => 4,
+            factor => return Err(anyhow!("oversampling must be 1, 2 or 4, not {}", factor)),
+        };
+        let config = EngineConfig::default();
+        let mut distortion = Distortion {
+            curve,
+            factor,
+            drive: Arc::new(AtomicF32::new(option_f32(options, "drive", 12.0)?)),
+            tone: Arc::new(AtomicF32::new(option_f32(options, "tone", 8_000.0)?)),
+            mix: Arc::new(AtomicF32::new(option_f32(options, "mix", 1.0)?)),
+            sample_rate: config.sample_rate as f32,
+            oversamplers: (
+                Oversampler::new(factor, config.sample_rate as f32),
+                Oversampler::new(factor, config.sample_rate as f32),
+            ),
+            tone_state: (0.0, 0.0),
+        };
+        distortion.prepare(&config);
+        Ok(Box::new(distortion))
+    }
+}
+
+/// A three band EQ: a low shelf, a parametric mid and a high shelf.
+pub struct Eq {
+    low_frequency: Arc<AtomicF32>,
+    low_gain: Arc<AtomicF32>,
+    mid_frequency: Arc<AtomicF32>,
+    mid_gain: Arc<AtomicF32>,
+    mid_q: Arc<AtomicF32>,
+    high_frequency: Arc<AtomicF32>,
+    high_gain: Arc<AtomicF32>,
+    sample_rate: f32,
+    /// Param values the filters were designed for, they're only redesigned when these change.
+    designed: Option<[f32; 7]>,
+    /// Low, mid and high band per channel.
+    bands: [[Biquad; 3]; 2],
+}
+
+impl Eq {
+    fn values(&self) -> [f32; 7] {
+        [
+            &self.low_frequency,
+            &self.low_gain,
+            &self.mid_frequency,
+            &self.mid_gain,
+            &self.mid_q,
+            &self.high_frequency,
+            &self.high_gain,
+        ]
+        .map(|param| param.load(Ordering::Relaxed))
+    }
+
+    fn update(&mut self) {
+        let values = self.values();
+        if self.designed == Some(values) {
+            return;
+        }
+        let [low_frequency, low_gain, mid_frequency, mid_gain, mid_q, high_frequency, high_gain] =
+            values;
+        let sample_rate = self.sample_rate;
+        let designs = [
+            Biquad::low_shelf(low_frequency, low_gain, sample_rate),
+            Biquad::peaking(mid_frequency, mid_gain, mid_q, sample_rate),
+            Biquad::high_shelf(high_frequency, high_gain, sample_rate),
+        ];
+        for channel in &mut self.bands {
+            for (band, design) in channel.iter_mut().zip(designs.iter()) {
+                band.retune(design);
+            }
+        }
+        self.designed = Some(values);
+    }
+}
+
+impl Effect for Eq {
+    fn process(&mut self, buffer: &mut [(f32, f32)]) {
+        for block in buffer.chunks_mut(CONTROL_BLOCK_SIZE) {
+            self.update();
+            let [left, right] = &mut self.bands;
+            for frame in block {
+                frame.0 = left.iter_mut().fold(frame.0, |x, band| band.process(x));
+                frame.1 = right.iter_mut().fold(frame.1, |x, band| band.process(x));
+            }
+        }
+    }
+
+    fn prepare(&mut self, config: &EngineConfig) {
+        self.sample_rate = config.sample_rate as f32;
+        self.bands = [[Biquad::default(); 3]; 2];
+        self.designed = None;
+        self.update();
+    }
+
+    fn reset(&mut self) {
+        for band in self.bands.iter_mut().flatten() {
+            band.reset();
+        }
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        let gain = |name: &str, val: &Arc<AtomicF32>| {
+            (
+                String::from(name),
+                Param::new(-18.0, Arc::clone(val), 18.0, 0.5).with_unit(Unit::Decibel),
+            )
+        };
+        vec![
+            (
+                String::from("LowFreq"),
+                Param::new(20.0, Arc::clone(&self.low_frequency), 1_000.0, 10.0)
+                    .with_unit(Unit::Hertz),
+            ),
+            gain("LowGain", &self.low_gain),
+            (
+                String::from("MidFreq"),
+                Param::new(100.0, Arc::clone(&self.mid_frequency), 10_000.0, 50.0)
+                    .with_unit(Unit::Hertz),
+            ),
+            gain("MidGain", &self.mid_gain),
+            (
+                String::from("MidQ"),
+                Param::new(0.1, Arc::clone(&self.mid_q), 10.0, 0.1),
+            ),
+            (
+                String::from("HighFreq"),
+                Param::new(1_000.0, Arc::clone(&self.high_frequency), 20_000.0, 250.0)
+                    .with_unit(Unit::Hertz),
+            ),
+            gain("HighGain", &self.high_gain),
+        ]
+    }
+}
+
+pub struct EqFactory;
+
+impl EffectFactory for EqFactory {
+    fn name(&self) -> &'static str {
+        "eq"
+    }
+
+    /// Takes `low`, `mid` and `high` gains in dB.
+    fn create(&self, options: &Options) -> Result<Box<dyn Effect>> {
+        let param = |value: f32| Arc::new(AtomicF32::new(value));
+        let mut eq = Eq {
+            low_frequency: param(120.0),
+            low_gain: param(option_f32(options, "low", 0.0)?),
+            mid_frequency: param(1_000.0),
+            mid_gain: param(option_f32(options, "mid", 0.0)?),
+            mid_q: param(0.7),
+            high_frequency: param(8_000.0),
+            high_gain: param(option_f32(options, "high", 0.0)?),
+            sample_rate: EngineConfig::default().sample_rate as f32,
+            designed: None,
+            bands: [[Biquad::default(); 3]; 2],
+        };
+        eq.prepare(&EngineConfig::default());
+        Ok(Box::new(eq))
+    }
+}
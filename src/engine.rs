@@ -1,14 +1,32 @@
-use crate::pattern::{Editor, Position, MAX_TRACKS};
-use crate::SAMPLE_RATE;
+use crate::audio::realtime;
+use crate::capture::Capture;
+use crate::crash;
+use crate::effect::Effect;
+use crate::harmony;
+use crate::id::{PatternId, TrackId};
+use crate::instrument::Instrument;
+use crate::mixer::{Mixer, MixerParams, Source, NUM_BUSES};
+use crate::monitor::{Monitor, MonitorParams, Reference};
+use crate::pattern::{
+    self, Editor, Position, Step, MAX_OFFSET, MAX_TRACKS, NOTE_CUT, TICKS_PER_LINE,
+};
+use crate::rtlog::{self, Message};
+use crate::sampling::SamplingTap;
+use crate::telemetry::Telemetry;
+use crate::transport::Transport;
+use crate::tuner::TunerTap;
+use crate::workers::Workers;
+use crate::MAX_FRAMES_PER_BUFFER;
 use crate::{
     app::AppCommand,
     sampler::{Sampler, Sound, ROOT_PITCH},
 };
 use ringbuf::{Consumer, Producer};
 use std::sync::{
-    atomic::{AtomicBool, AtomicU16, Ordering},
+    atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering},
     Arc,
 };
+use std::time::{Duration, Instant};
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub enum EngineParam {
@@ -22,29 +40,115 @@ pub enum EngineCommand {
     InputNumber(Position, i32),
     ChangeValue(Position, i32),
     DeleteValue(Position),
-    LoadSound(usize, Arc<Sound>),
+    SetInstrument(usize, Option<Box<dyn Instrument>>),
     PreviewSound(Arc<Sound>),
+    /// Starts a note played live on an instrument: instrument, column, pitch and velocity.
+    NoteOn(usize, usize, u8, u8),
+    /// Ends the live note on a column of an instrument.
+    NoteOff(usize, usize),
+    LoadEditor(Box<Editor>),
+    SetStep(PatternId, TrackId, usize, Step),
+    /// Inserts an effect into a mixer channel: channel, position, effect and its bypass switch.
+    InsertEffect(usize, usize, Box<dyn Effect>, Arc<AtomicBool>),
+    RemoveEffect(usize, usize),
+    MoveEffect(usize, usize, usize),
+    StartCapture(Box<Capture>),
+    StopCapture,
+    SetReference(Option<Box<Reference>>),
+    SetTuner(Option<Box<TunerTap>>),
+    /// Starts recording a hardware input into a new sound, or stops.
+    SetSampling(Option<Box<SamplingTap>>),
+}
+
+/// Something the engine let go of, sent back for the app to drop: freeing memory on the audio
+/// thread isn't realtime safe.
+pub enum Garbage {
+    Instrument(Box<dyn Instrument>),
+    Editor(Box<Editor>),
+    Effect(Box<dyn Effect>, Arc<AtomicBool>),
+    Capture(Box<Capture>),
+    Reference(Box<Reference>),
+    Tuner(Box<TunerTap>),
+    Sampling(Box<SamplingTap>),
+    Sound(Arc<Sound>),
+}
+
+/// Audio settings, the audio backend replaces these with whatever the device negotiated.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct EngineConfig {
+    pub sample_rate: f64,
+    /// Preferred number of frames per callback. Devices may still ask for other sizes.
+    pub buffer_size: u32,
+    /// Threads rendering instruments along with the audio thread, none to render everything
+    /// on it. Fixed once the engine is created.
+    pub threads: usize,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: 44_100.0,
+            buffer_size: 256,
+            threads: 0,
+        }
+    }
 }
 
+/// Number of frames between control rate updates such as reading parameter values.
+pub const CONTROL_BLOCK_SIZE: usize = 64;
+
+/// Blocks shorter than this render on the audio thread alone, handing them out would take
+/// longer than rendering them.
+const MIN_PARALLEL_FRAMES: usize = 32;
+
+/// Things let go of while the app's queue is full that the engine keeps until there's room.
+const MAX_PENDING_GARBAGE: usize = 16;
+
+pub const MAX_INSTRUMENTS: usize = MAX_TRACKS;
+
 pub trait Device {
+    /// Adds the output of the device to `buffer`. The buffer can have any length, devices are
+    /// expected to split it up internally at `CONTROL_BLOCK_SIZE` boundaries.
     fn render(&mut self, buffer: &mut [(f32, f32)]);
 }
 
+/// Plays a recorded signal, for channels fed by an input or a bus.
+struct Signal<'a>(&'a [(f32, f32)]);
+
+impl Device for Signal<'_> {
+    fn render(&mut self, buffer: &mut [(f32, f32)]) {
+        for (out, frame) in buffer.iter_mut().zip(self.0) {
+            out.0 += frame.0;
+            out.1 += frame.1;
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct EngineParams {
     pub bpm: Arc<AtomicU16>,
     pub lines_per_beat: Arc<AtomicU16>,
     pub octave: Arc<AtomicU16>,
-    pub is_playing: Arc<AtomicBool>,
+    pub transport: Transport,
+    /// Rate the engine runs at, set by the engine once the device is open.
+    pub sample_rate: Arc<AtomicU32>,
+    pub mixer: MixerParams,
+    pub monitor: MonitorParams,
+    pub telemetry: Telemetry,
 }
 
 impl Default for EngineParams {
     fn default() -> Self {
+        let lines_per_beat = Arc::new(AtomicU16::new(4));
         Self {
             bpm: Arc::new(AtomicU16::new(120)),
             octave: Arc::new(AtomicU16::new(4)),
-            lines_per_beat: Arc::new(AtomicU16::new(4)),
-            is_playing: Arc::new(AtomicBool::new(false)),
+            transport: Transport::new(Arc::clone(&lines_per_beat)),
+            lines_per_beat,
+            sample_rate: Arc::new(AtomicU32::new(EngineConfig::default().sample_rate as u32)),
+            mixer: MixerParams::default(),
+            monitor: MonitorParams::default(),
+            telemetry: Telemetry::default(),
         }
     }
 }
@@ -78,58 +182,336 @@ pub struct Engine {
     prod: Producer<AppCommand>,
 
     editor: Editor,
-    channels: Vec<Sampler>,
-    sounds: Vec<Option<Arc<Sound>>>,
+    instruments: Vec<Option<Box<dyn Instrument>>>,
+    // instrument currently playing on each track
+    active: Vec<Option<usize>>,
 
     preview: Sampler,
+    /// Every sound previewed, until no voice plays it any more. The engine keeps a reference
+    /// so a voice never drops the last one, every voice holds at most two sounds.
+    previews: Vec<Arc<Sound>>,
+    capture: Option<Box<Capture>>,
+    tuner: Option<Box<TunerTap>>,
+    sampling: Option<Box<SamplingTap>>,
+    mixer: Mixer,
+    monitor: Monitor,
+    /// Signal of the channel being rendered when it isn't fed by its instrument.
+    source: Vec<(f32, f32)>,
+    workers: Option<Workers>,
+    /// Output of every instrument of the block, when the workers rendered them.
+    rendered: Vec<Vec<(f32, f32)>>,
 
+    config: EngineConfig,
     params: EngineParams,
 
-    samples_to_tick: usize,
+    /// Samples until `position`.
+    samples_to_event: usize,
+    /// Where the next line starts or the next note off the grid plays, in `TICKS_PER_LINE` per
+    /// line since the start of the song.
+    position: u64,
+    /// The line after the one played last.
     current_tick: u64,
+    /// Whether the notes timed before the next line are played with it, as playback starts
+    /// there and they've been missed.
+    catch_up: bool,
+    was_playing: bool,
+    /// What the app had no room in its queue for, sent again before the next commands run.
+    garbage: Vec<Garbage>,
 }
 
 impl Engine {
     pub fn new(
+        config: EngineConfig,
         params: EngineParams,
         cons: Consumer<EngineCommand>,
         prod: Producer<AppCommand>,
     ) -> Engine {
-        let mut channels = Vec::with_capacity(MAX_TRACKS);
-        for _ in 0..MAX_TRACKS {
-            let sampler = Sampler::new();
-            channels.push(sampler);
-        }
+        let mut preview = Sampler::new();
+        preview.prepare(&config);
+        params
+            .sample_rate
+            .store(config.sample_rate as u32, Ordering::Relaxed);
+        let mut monitor = Monitor::new(params.monitor.clone());
+        monitor.prepare(&config);
+        let max_previews = 2 * preview.num_voices() + 1;
+        let (workers, rendered) = match config.threads {
+            0 => (None, Vec::new()),
+            threads => (
+                Some(Workers::new(threads)),
+                vec![vec![(0., 0.); MAX_FRAMES_PER_BUFFER]; MAX_INSTRUMENTS],
+            ),
+        };
         Self {
             cons,
             prod,
             editor: Editor::new(),
-            channels,
-            sounds: vec![None; MAX_TRACKS],
-            preview: Sampler::new(),
+            instruments: (0..MAX_INSTRUMENTS).map(|_| None).collect(),
+            active: vec![None; MAX_TRACKS],
+            preview,
+            previews: Vec::with_capacity(max_previews),
+            capture: None,
+            tuner: None,
+            sampling: None,
+            mixer: Mixer::new(params.mixer.clone()),
+            monitor,
+            source: vec![(0., 0.); MAX_FRAMES_PER_BUFFER],
+            workers,
+            rendered,
+            config,
             params,
-            samples_to_tick: 0,
+            samples_to_event: 0,
+            position: 0,
             current_tick: 0,
+            catch_up: true,
+            was_playing: false,
+            garbage: Vec::with_capacity(MAX_PENDING_GARBAGE),
+        }
+    }
+
+    pub fn load_editor(&mut self, editor: Editor) {
+        self.editor = editor;
+    }
+
+    pub fn config(&self) -> EngineConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: EngineConfig) {
+        self.config = config;
+        self.params
+            .sample_rate
+            .store(config.sample_rate as u32, Ordering::Relaxed);
+        for instrument in self.instruments.iter_mut().flatten() {
+            instrument.prepare(&config);
+        }
+        self.preview.prepare(&config);
+        self.mixer.prepare(&config);
+        self.monitor.prepare(&config);
+    }
+
+    pub fn params(&self) -> &EngineParams {
+        &self.params
+    }
+
+    /// Buffer for hardware input `input`, to fill in with as many frames as the next call to
+    /// `render` renders. Inputs which aren't filled in stay silent.
+    pub fn input_mut(&mut self, input: usize) -> &mut [(f32, f32)] {
+        self.mixer.input_mut(input)
+    }
+
+    /// Moves playback to a position given in frames from the start of the song, e.g. when an
+    /// external transport relocates. Playback continues at the next line, without anything
+    /// still sounding from before.
+    pub fn locate(&mut self, frame: u64) {
+        let samples_per_line = self.samples_per_line() as u64;
+        let line = frame.div_ceil(samples_per_line);
+        self.locate_line(line, (line * samples_per_line - frame) as usize);
+    }
+
+    /// Frame of the song a line starts at, at the current tempo.
+    pub fn line_frame(&self, line: u64) -> u64 {
+        line * self.samples_per_line() as u64
+    }
+
+    /// Moves playback to start `line` in `samples_to_line` samples.
+    fn locate_line(&mut self, line: u64, samples_to_line: usize) {
+        self.reset();
+        self.current_tick = line;
+        self.position = line * TICKS_PER_LINE as u64;
+        self.catch_up = true;
+        self.samples_to_event = samples_to_line;
+        self.params.transport.set_tick(line);
+    }
+
+    /// Silences every instrument and clears the effects, e.g. before an offline render.
+    pub fn reset(&mut self) {
+        for instrument in self.instruments.iter_mut().flatten() {
+            instrument.reset();
         }
+        self.active.iter_mut().for_each(|active| *active = None);
+        self.mixer.reset();
     }
 
+    fn samples_per_line(&self) -> usize {
+        let bpm = self.params.get(EngineParam::Bpm);
+        let lines_per_beat = self.params.get(EngineParam::LinesPerBeat);
+        let num_samples = (self.config.sample_rate * 60.) / (lines_per_beat * bpm) as f64;
+        num_samples.round() as usize
+    }
+
+    /// Renders the mix into `buffer`, which can't be longer than `MAX_FRAMES_PER_BUFFER` while
+    /// capturing or sending to an aux bus. Doesn't allocate, lock or touch files, whatever was
+    /// replaced is sent back to the app to drop.
     pub fn render(&mut self, buffer: &mut [(f32, f32)]) {
+        crash::mark_audio_thread();
+        let _rendering = realtime::rendering();
+        let start = Instant::now();
+        self.run_commands();
+        if let Some(tuner) = &mut self.tuner {
+            let input = self.mixer.input(tuner.input);
+            tuner.push(&input[..usize::min(buffer.len(), input.len())]);
+        }
+        let mut capture = self.capture.take();
+        self.render_with(buffer.len(), |index, device, block, mixer| {
+            let offset = block.start;
+            let output = &mut buffer[block.start..block.end];
+            let index = match index {
+                Some(index) => index,
+                None => return device.render(output),
+            };
+            // Captured channels are rendered into the capture buffer and recorded post-fader.
+            match capture.as_mut().and_then(|capture| capture.buffer(index)) {
+                Some(channel) => {
+                    let channel = &mut channel[block.start..block.end];
+                    device.render(channel);
+                    mixer.mix(index, offset, channel, output);
+                }
+                None => mixer.render(index, offset, device, output),
+            }
+        });
+        for bus in 0..NUM_BUSES {
+            self.mixer.render_return(bus, buffer);
+        }
+        self.mixer.process_master(buffer);
+        let is_playing = self.params.transport.is_playing();
+        self.monitor.process(buffer, is_playing);
+        if let Some(capture) = &mut capture {
+            capture.push(buffer.len());
+        }
+        self.capture = capture;
+        self.update_telemetry(start.elapsed(), buffer.len());
+    }
+
+    /// Publishes the load of the buffer just rendered and the voices left playing.
+    fn update_telemetry(&self, elapsed: Duration, num_frames: usize) {
+        let telemetry = &self.params.telemetry;
+        if num_frames > 0 {
+            let duration = num_frames as f64 / self.config.sample_rate;
+            telemetry.record_load((elapsed.as_secs_f64() / duration) as f32);
+        }
+        for (i, instrument) in self.instruments.iter().enumerate() {
+            telemetry.set_voices(i, instrument.as_ref().map_or(0, |i| i.active_voices()));
+        }
+    }
+
+    /// Renders every instrument into its own buffer instead of summing them. The mixer is
+    /// bypassed and the preview is not rendered.
+    pub fn render_stems(&mut self, stems: &mut [Vec<(f32, f32)>], num_frames: usize) {
         self.run_commands();
+        self.render_with(num_frames, |index, device, block, _| {
+            if let Some(stem) = index.and_then(|i| stems.get_mut(i)) {
+                device.render(&mut stem[block.start..block.end]);
+            }
+        });
+    }
+
+    fn render_with<F>(&mut self, num_frames: usize, mut output: F)
+    where
+        F: FnMut(Option<usize>, &mut dyn Device, &Block, &mut Mixer),
+    {
+        if let Some(line) = self.params.transport.take_seek() {
+            self.locate_line(line, 0);
+        }
+        let is_playing = self.params.transport.is_playing();
+        if self.was_playing && !is_playing {
+            for instrument in self.instruments.iter_mut().flatten() {
+                instrument.reset();
+            }
+        }
+        self.was_playing = is_playing;
+        let bpm = self.params.get(EngineParam::Bpm) as f32;
+        for instrument in self.instruments.iter_mut().flatten() {
+            instrument.set_tempo(bpm);
+        }
+        self.mixer.set_tempo(bpm);
+        self.mixer.begin();
+
         let mut block = Block { start: 0, end: 0 };
-        while self.next_block(&mut block, buffer.len()) {
-            for chan in &mut self.channels {
-                chan.render(&mut buffer[block.start..block.end]);
+        while self.next_block(&mut block, num_frames) {
+            if let Some(sampling) = &mut self.sampling {
+                // The tick was already moved on to the next line.
+                let num_lines = self.editor.num_lines();
+                let line = if is_playing {
+                    self.current_tick
+                        .checked_sub(1)
+                        .map(|tick| tick as usize % num_lines)
+                } else {
+                    None
+                };
+                let input = self.mixer.input(sampling.input);
+                let end = usize::min(block.end, input.len());
+                sampling.record(line, &input[block.start..end]);
+            }
+            let rendered = self.render_parallel(&block);
+            for i in self.mixer.order().iter().copied() {
+                if self.mixer.params().channels[i].source() != Source::Instrument {
+                    let len = usize::min(block.end - block.start, self.source.len());
+                    let signal = &mut self.source[..len];
+                    self.mixer.read_source(i, block.start, signal);
+                    output(Some(i), &mut Signal(signal), &block, &mut self.mixer);
+                } else if rendered[i] {
+                    let signal = &self.rendered[i][..block.end - block.start];
+                    output(Some(i), &mut Signal(signal), &block, &mut self.mixer);
+                } else if let Some(instrument) = &mut self.instruments[i] {
+                    output(Some(i), instrument.as_mut(), &block, &mut self.mixer);
+                }
             }
-            self.preview.render(&mut buffer[block.start..block.end]);
+            output(None, &mut self.preview, &block, &mut self.mixer);
         }
     }
 
+    /// Renders the instruments of the block on the workers, if there are any and enough to
+    /// share. Returns which instruments were rendered, into `rendered`.
+    fn render_parallel(&mut self, block: &Block) -> [bool; MAX_INSTRUMENTS] {
+        let mut rendered = [false; MAX_INSTRUMENTS];
+        let workers = match &mut self.workers {
+            Some(workers) => workers,
+            None => return rendered,
+        };
+        let len = block.end - block.start;
+        if !(MIN_PARALLEL_FRAMES..=MAX_FRAMES_PER_BUFFER).contains(&len) {
+            return rendered;
+        }
+        let channels = &self.mixer.params().channels;
+        for (i, instrument) in self.instruments.iter().enumerate() {
+            rendered[i] = instrument.is_some() && channels[i].source() == Source::Instrument;
+        }
+        if rendered.iter().filter(|r| **r).count() < 2 {
+            return [false; MAX_INSTRUMENTS];
+        }
+        let jobs = self
+            .instruments
+            .iter_mut()
+            .zip(&mut self.rendered)
+            .zip(&rendered)
+            .filter(|(_, rendered)| **rendered)
+            .filter_map(|((instrument, buffer), _)| {
+                let buffer = &mut buffer[..len];
+                buffer.iter_mut().for_each(|frame| *frame = (0.0, 0.0));
+                instrument.as_mut().map(|instrument| (instrument, buffer))
+            });
+        workers.render(jobs);
+        rendered
+    }
+
     pub fn run_commands(&mut self) {
-        while let Some(update) = self.cons.pop() {
+        self.send_garbage();
+        while let Some(update) = self.next_command() {
             match update {
-                EngineCommand::LoadSound(index, sound) => {
-                    self.sounds[index] = Some(sound);
+                EngineCommand::SetInstrument(index, mut instrument) => {
+                    if let Some(instrument) = &mut instrument {
+                        instrument.prepare(&self.config);
+                    }
+                    let prev = std::mem::replace(&mut self.instruments[index], instrument);
+                    if let Some(mut prev) = prev {
+                        prev.stop();
+                        self.dispose(Garbage::Instrument(prev));
+                    }
+                }
+                EngineCommand::NoteOn(index, column, pitch, velocity) => {
+                    self.note_on(index, column, pitch, velocity);
                 }
+                EngineCommand::NoteOff(index, column) => self.note_off(index, column),
                 EngineCommand::InputNote(pos, pitch) => {
                     self.editor.set_cursor(pos);
                     self.editor.set_pitch(pitch);
@@ -146,15 +528,127 @@ impl Engine {
                     self.editor.set_cursor(pos);
                     self.editor.delete_value();
                 }
+                EngineCommand::LoadEditor(mut editor) => {
+                    std::mem::swap(&mut self.editor, &mut editor);
+                    self.dispose(Garbage::Editor(editor));
+                }
+                EngineCommand::SetStep(pattern, track, line, step) => {
+                    self.editor.set_step(pattern, track, line, step);
+                }
+                EngineCommand::InsertEffect(channel, index, mut effect, bypass) => {
+                    effect.prepare(&self.config);
+                    if let Err((effect, bypass)) =
+                        self.mixer.insert_effect(channel, index, effect, bypass)
+                    {
+                        self.dispose(Garbage::Effect(effect, bypass));
+                    }
+                }
+                EngineCommand::RemoveEffect(channel, index) => {
+                    if let Some((effect, bypass)) = self.mixer.remove_effect(channel, index) {
+                        self.dispose(Garbage::Effect(effect, bypass));
+                    }
+                }
+                EngineCommand::MoveEffect(channel, from, to) => {
+                    self.mixer.move_effect(channel, from, to);
+                }
+                EngineCommand::StartCapture(mut capture) => {
+                    capture.set_sample_rate(self.config.sample_rate);
+                    if let Some(prev) = self.capture.replace(capture) {
+                        self.stop_capture(prev);
+                    }
+                }
+                EngineCommand::StopCapture => {
+                    if let Some(capture) = self.capture.take() {
+                        self.stop_capture(capture);
+                    }
+                }
+                EngineCommand::SetReference(reference) => {
+                    if let Some(prev) = self.monitor.set_reference(reference) {
+                        self.dispose(Garbage::Reference(prev));
+                    }
+                }
+                EngineCommand::SetTuner(mut tuner) => {
+                    if let Some(tuner) = &mut tuner {
+                        tuner.set_sample_rate(self.config.sample_rate);
+                    }
+                    if let Some(prev) = std::mem::replace(&mut self.tuner, tuner) {
+                        self.dispose(Garbage::Tuner(prev));
+                    }
+                }
+                EngineCommand::SetSampling(mut sampling) => {
+                    if let Some(sampling) = &mut sampling {
+                        sampling.set_sample_rate(self.config.sample_rate);
+                    }
+                    if let Some(prev) = std::mem::replace(&mut self.sampling, sampling) {
+                        self.dispose(Garbage::Sampling(prev));
+                    }
+                }
                 EngineCommand::PreviewSound(snd) => {
-                    self.preview.note_on(snd, 0, ROOT_PITCH, 80);
+                    if self.previews.len() == self.previews.capacity() {
+                        let preview = &self.preview;
+                        if let Some(i) = self.previews.iter().position(|s| !preview.holds(s)) {
+                            let sound = self.previews.remove(i);
+                            self.dispose(Garbage::Sound(sound));
+                        }
+                    }
+                    if self.previews.len() < self.previews.capacity() {
+                        self.previews.push(Arc::clone(&snd));
+                    }
+                    self.preview.trigger(snd, 0, ROOT_PITCH, 80);
                 }
             }
         }
     }
 
+    /// Starts a note on an instrument right away, e.g. for notes the audio thread receives
+    /// itself rather than through the app.
+    pub fn note_on(&mut self, index: usize, column: usize, pitch: u8, velocity: u8) {
+        if let Some(Some(instrument)) = self.instruments.get_mut(index) {
+            instrument.note_on(column, pitch, velocity);
+        }
+    }
+
+    pub fn note_off(&mut self, index: usize, column: usize) {
+        if let Some(Some(instrument)) = self.instruments.get_mut(index) {
+            instrument.note_off(column);
+        }
+    }
+
+    /// The writer of the capture finishes as soon as the capture stops, rather than once the
+    /// app got around to dropping it.
+    fn stop_capture(&mut self, capture: Box<Capture>) {
+        capture.close();
+        self.dispose(Garbage::Capture(capture));
+    }
+
+    /// The next command from the app, unless there's no room left to keep what it could let go
+    /// of: every command lets go of one thing at most, the rest wait in the queue.
+    fn next_command(&mut self) -> Option<EngineCommand> {
+        if self.garbage.len() >= MAX_PENDING_GARBAGE {
+            return None;
+        }
+        self.cons.pop()
+    }
+
+    /// Sends `garbage` back to the app, or keeps it until there's room in the app's queue.
+    fn dispose(&mut self, garbage: Garbage) {
+        if let Err(AppCommand::Dispose(garbage)) = self.prod.push(AppCommand::Dispose(garbage)) {
+            self.garbage.push(garbage);
+        }
+    }
+
+    fn send_garbage(&mut self) {
+        while let Some(garbage) = self.garbage.pop() {
+            if let Err(AppCommand::Dispose(garbage)) = self.prod.push(AppCommand::Dispose(garbage))
+            {
+                self.garbage.push(garbage);
+                return;
+            }
+        }
+    }
+
     pub fn next_block(&mut self, block: &mut Block, num_frames: usize) -> bool {
-        if !self.params.is_playing.load(Ordering::Relaxed) {
+        if !self.params.transport.is_playing() {
             if block.end == num_frames {
                 return false;
             }
@@ -162,7 +656,7 @@ impl Engine {
             return true;
         }
 
-        self.samples_to_tick -= block.end - block.start;
+        self.samples_to_event -= block.end - block.start;
         if block.end == num_frames {
             return false;
         }
@@ -170,31 +664,258 @@ impl Engine {
             block.start = block.end;
         }
 
-        if self.samples_to_tick == 0 {
-            for note in self.editor.iter_notes(self.current_tick) {
-                if let Some(Some(snd)) = &self.sounds.get(note.sound as usize) {
-                    let sampler = &mut self.channels[note.track as usize];
-                    sampler.note_on(snd.clone(), note.track as usize, note.pitch, 80);
-                }
-            }
-            let bpm = self.params.get(EngineParam::Bpm);
-            let lines_per_beat = self.params.get(EngineParam::LinesPerBeat);
-            let num_samples = (SAMPLE_RATE * 60.) / (lines_per_beat * bpm) as f64;
-            self.samples_to_tick = num_samples.round() as usize;
-            self.app_send(AppCommand::SetCurrentTick(self.current_tick as usize));
-            self.current_tick += 1;
+        if self.samples_to_event == 0 {
+            self.play_position();
         }
 
-        block.end = block.start + self.samples_to_tick;
+        block.end = block.start + self.samples_to_event;
         if block.end > num_frames {
             block.end = num_frames;
         }
         true
     }
 
+    /// Starts the line at `position` if there's one and plays the notes timed there, then
+    /// moves on to the next position where something happens.
+    fn play_position(&mut self) {
+        let ticks = TICKS_PER_LINE as u64;
+        // Back to the start of the loop once its last line ended
+        let on_line = self.position.is_multiple_of(ticks);
+        if let Some(start) = self.loop_start(self.position / ticks).filter(|_| on_line) {
+            self.release_all();
+            self.position = start * ticks;
+            self.catch_up = true;
+        }
+        let position = self.position;
+        let line = position / ticks;
+        let offset = (position % ticks) as i8;
+        if offset == 0 {
+            self.app_send(AppCommand::SetCurrentTick(line as usize));
+            self.params.transport.set_tick(line);
+            self.current_tick = line + 1;
+        }
+        // Late notes of the line, then early notes of the next one
+        if offset == 0 && self.catch_up {
+            self.play_notes(line, |at| at <= 0);
+        } else {
+            self.play_notes(line, |at| at == offset);
+        }
+        self.catch_up = false;
+        let early = offset - TICKS_PER_LINE as i8;
+        if early >= -MAX_OFFSET && self.loop_start(line + 1).is_none() {
+            self.play_notes(line + 1, |at| at == early);
+        }
+
+        let next = self.next_position(position);
+        let samples_per_line = self.samples_per_line() as u64;
+        let samples = |position: u64| position * samples_per_line / ticks;
+        self.samples_to_event = (samples(next) - samples(position)) as usize;
+        self.position = next;
+    }
+
+    /// Where playback goes back to when `line` is the end of the loop region, if there's one.
+    /// Early notes of the first line of the loop are played with it rather than before the end
+    /// of the loop, so the last line plays as written.
+    fn loop_start(&self, line: u64) -> Option<u64> {
+        let region = self.params.transport.loop_region()?;
+        Some(region.start).filter(|_| line == region.end)
+    }
+
+    /// Releases the notes playing on every track, so nothing hangs when playback jumps. Unlike
+    /// `reset`, the instruments play their release.
+    fn release_all(&mut self) {
+        let instruments = &mut self.instruments;
+        for (track, active) in self.active.iter_mut().enumerate() {
+            let instrument = active.take().and_then(|i| instruments.get_mut(i));
+            if let Some(Some(instrument)) = instrument {
+                for column in harmony::columns(track) {
+                    instrument.note_off(column);
+                }
+            }
+        }
+    }
+
+    /// The first position after `position` where a line starts or a note off the grid plays.
+    fn next_position(&self, position: u64) -> u64 {
+        let ticks = TICKS_PER_LINE as u64;
+        let line = position / ticks;
+        let timed = |line: u64| {
+            self.editor
+                .iter_notes(line)
+                .filter(|note| note.chord_note == 0)
+                .map(move |note| (line * ticks) as i64 + note.offset as i64)
+        };
+        let next = Some(line + 1).filter(|next| self.loop_start(*next).is_none());
+        timed(line)
+            .chain(next.into_iter().flat_map(timed))
+            .filter(|at| *at > position as i64)
+            .fold((line + 1) * ticks, |next, at| u64::min(next, at as u64))
+    }
+
+    /// Plays the notes of `line` whose offset matches. Whatever the order of the tracks in the
+    /// pattern, every note ends before any note starts, so a note starting doesn't steal the
+    /// voice of one about to be released, and within each pass the tracks go in order.
+    /// Commands from the app, such as param changes, were run at the start of the buffer,
+    /// before any note.
+    fn play_notes<F: Fn(i8) -> bool>(&mut self, line: u64, at: F) {
+        for note in self
+            .editor
+            .iter_notes(line)
+            .filter(|note| note.chord_note == 0 && at(note.offset))
+        {
+            let track = note.track as usize;
+            // A new note or a note off ends the note on the track, even when it was played by
+            // another instrument. The root of a new note on the same instrument takes over the
+            // column of the previous one, the other notes of a chord are ended.
+            if let Some(prev) = self.active[track] {
+                let ends = pattern::ends_note(note.pitch) || prev != note.sound as usize;
+                if let Some(Some(instrument)) = self.instruments.get_mut(prev) {
+                    let skip = if ends { 0 } else { 1 };
+                    for column in harmony::columns(track).skip(skip) {
+                        match note.pitch {
+                            NOTE_CUT => instrument.note_cut(column),
+                            _ => instrument.note_off(column),
+                        }
+                    }
+                }
+                if ends {
+                    self.active[track] = None;
+                }
+            }
+        }
+        for note in self.editor.iter_notes(line).filter(|note| at(note.offset)) {
+            let track = note.track as usize;
+            let index = note.sound as usize;
+            if pattern::ends_note(note.pitch) {
+                continue;
+            }
+            if let Some(Some(instrument)) = self.instruments.get_mut(index) {
+                let column = harmony::column(track, note.chord_note as usize);
+                instrument.note_on(column, note.pitch, 80);
+                self.active[track] = Some(index);
+            }
+        }
+    }
+
     fn app_send(&mut self, cmd: AppCommand) {
-        if let Err(_) = self.prod.push(cmd) {
-            eprintln!("unable to update client state");
+        if self.prod.push(cmd).is_err() {
+            rtlog::log(Message::AppQueueFull);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pattern::NOTE_OFF;
+    use ringbuf::RingBuffer;
+    use std::sync::Mutex;
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        On(usize, u8),
+        Off(usize),
+    }
+
+    /// Writes down the notes it's asked to play, in order.
+    struct Recorder(Arc<Mutex<Vec<Event>>>);
+
+    impl Device for Recorder {
+        fn render(&mut self, _buffer: &mut [(f32, f32)]) {}
+    }
+
+    impl Instrument for Recorder {
+        fn note_on(&mut self, column: usize, pitch: u8, _velocity: u8) {
+            self.0.lock().unwrap().push(Event::On(column, pitch));
         }
+
+        fn note_off(&mut self, column: usize) {
+            self.0.lock().unwrap().push(Event::Off(column));
+        }
+    }
+
+    fn note(pitch: u8) -> Step {
+        Step {
+            pitch: Some(pitch),
+            sound: Some(0),
+            ..Step::default()
+        }
+    }
+
+    /// Plays the first `num_lines` lines of a pattern on a recorder, with the steps given by
+    /// track and line, and returns what it was asked to play.
+    fn play(steps: &[(usize, usize, u8)], num_lines: usize) -> Vec<Event> {
+        let (mut engine_send, engine_recv) = RingBuffer::<EngineCommand>::new(8).split();
+        let (app_send, _app_recv) = RingBuffer::<AppCommand>::new(1024).split();
+        let config = EngineConfig::default();
+        let params = EngineParams::default();
+        params.transport.play();
+        let mut engine = Engine::new(config, params, engine_recv, app_send);
+
+        let mut editor = Editor::new();
+        let pattern = editor.current_pattern().id;
+        for &(track, line, pitch) in steps {
+            let track = editor.track_ids()[track];
+            editor.set_step(pattern, track, line, note(pitch));
+        }
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Recorder(Arc::clone(&events));
+        let _ = engine_send.push(EngineCommand::SetInstrument(0, Some(Box::new(recorder))));
+        let _ = engine_send.push(EngineCommand::LoadEditor(Box::new(editor)));
+
+        // Up to the middle of the last line
+        let samples_per_line = engine.samples_per_line();
+        let num_frames = samples_per_line * num_lines - samples_per_line / 2;
+        let mut buffer = vec![(0.0, 0.0); 64];
+        for _ in 0..num_frames / buffer.len() {
+            engine.render(&mut buffer);
+        }
+        let mut events = events.lock().unwrap();
+        std::mem::take(&mut *events)
+    }
+
+    #[test]
+    fn note_offs_come_before_note_ons() {
+        // Track 1 ends its note on the line track 0 starts one, on the same instrument
+        let events = play(&[(1, 0, 60), (0, 1, 62), (1, 1, NOTE_OFF)], 2);
+        let mut expected = vec![Event::On(harmony::column(1, 0), 60)];
+        expected.extend(harmony::columns(1).map(Event::Off));
+        expected.push(Event::On(harmony::column(0, 0), 62));
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn garbage_waits_for_room_in_the_app_queue() {
+        let num_commands = MAX_PENDING_GARBAGE + 2;
+        let (mut engine_send, engine_recv) = RingBuffer::<EngineCommand>::new(num_commands).split();
+        let (app_send, mut app_recv) = RingBuffer::<AppCommand>::new(1).split();
+        let config = EngineConfig::default();
+        let mut engine = Engine::new(config, EngineParams::default(), engine_recv, app_send);
+        for _ in 0..num_commands {
+            let _ = engine_send.push(EngineCommand::LoadEditor(Box::new(Editor::new())));
+        }
+
+        // One editor fits in the app queue, the others are kept or left in the engine queue
+        engine.run_commands();
+        assert_eq!(engine.garbage.len(), MAX_PENDING_GARBAGE);
+        assert_eq!(engine_send.len(), 1);
+        let mut disposed = 0;
+        while let Some(command) = app_recv.pop() {
+            assert!(matches!(command, AppCommand::Dispose(Garbage::Editor(_))));
+            disposed += 1;
+            engine.run_commands();
+        }
+        assert_eq!(disposed, num_commands);
+        assert!(engine.garbage.is_empty());
+        assert!(engine_send.is_empty());
+    }
+
+    #[test]
+    fn tracks_play_in_order() {
+        let events = play(&[(2, 0, 62), (0, 0, 60), (1, 0, 61)], 1);
+        let expected: Vec<_> = (0..3)
+            .map(|track| Event::On(harmony::column(track, 0), 60 + track as u8))
+            .collect();
+        assert_eq!(events, expected);
     }
 }
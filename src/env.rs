@@ -1,44 +1,114 @@
+use crate::param::{Param, Unit};
+use atomic_float::AtomicF32;
+use std::sync::{atomic::Ordering, Arc};
+
+/// Number of stages an envelope can have without allocating.
+const MAX_STAGES: usize = 8;
+
+/// How sharply curved stages bend, higher is sharper.
+const CURVATURE: f32 = 5.0;
+
 #[derive(Debug, PartialEq)]
 pub enum State {
     Init,
-    Attack,
-    Decay,
+    /// Running the stage at this index.
+    Stage(usize),
     Sustain,
     Release,
 }
 
+/// How the level moves from one breakpoint to the next.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Curve {
+    #[default]
+    Linear,
+    /// Moves fast at first and slows down towards the target, like an analog envelope.
+    Exponential,
+    /// Moves slowly at first and speeds up towards the target.
+    Logarithmic,
+}
+
+impl Curve {
+    /// Curve of a param value: -1 is logarithmic, 0 linear and 1 exponential.
+    fn from_value(value: f32) -> Self {
+        match value.round() as i32 {
+            v if v < 0 => Curve::Logarithmic,
+            0 => Curve::Linear,
+            _ => Curve::Exponential,
+        }
+    }
+
+    /// Maps the progress through a stage, from 0 to 1, to the progress of the level.
+    fn shape(self, x: f32) -> f32 {
+        match self {
+            Curve::Linear => x,
+            Curve::Exponential => (1.0 - f32::exp(-CURVATURE * x)) / (1.0 - f32::exp(-CURVATURE)),
+            Curve::Logarithmic => (f32::exp(CURVATURE * x) - 1.0) / (f32::exp(CURVATURE) - 1.0),
+        }
+    }
+}
+
+/// A segment of an envelope, which moves the level to `target` in `time` seconds.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Stage {
+    pub target: f32,
+    pub time: f32,
+    pub curve: Curve,
+}
+
 #[derive(Debug)]
 pub struct Envelope {
-    pub attack: f32,
-    pub decay: f32,
-    pub sustain: f32,
+    /// Run in order when the note starts, the level then holds at the target of the last one
+    /// until the note is released. The envelope ends there when that target is silence.
+    pub stages: Vec<Stage>,
     pub release: f32,
+    pub release_curve: Curve,
+    pub sample_rate: f32,
 
-    attack_rate: f32,
-    pub decay_rate: f32,
-    release_rate: f32,
-
-    samples_after_release: i32,
+    /// Level at the start of the current stage.
+    from: f32,
+    /// Progress through the current stage, from 0 to 1.
+    progress: f32,
+    /// Progress made every sample.
+    rate: f32,
 
     val: f32,
     pub state: State,
 }
 
 impl Envelope {
-    pub fn new() -> Envelope {
-        Envelope {
-            attack: 0.01,
-            decay: 0.1,
-            sustain: 0.8,
-            release: 0.01,
-
-            attack_rate: 0.,
-            decay_rate: 0.,
-            release_rate: 0.,
+    pub fn new(sample_rate: f32) -> Envelope {
+        let mut env = Envelope {
+            stages: Vec::with_capacity(MAX_STAGES),
+            release: 0.0,
+            release_curve: Curve::Linear,
+            sample_rate,
+
+            from: 0.,
+            progress: 0.,
+            rate: 0.,
             val: 0.,
             state: State::Init,
-            samples_after_release: 0,
-        }
+        };
+        env.set_adsr(0.01, 0.1, 0.8, 0.01);
+        env
+    }
+
+    /// The classic linear attack, decay, sustain and release.
+    pub fn set_adsr(&mut self, attack: f32, decay: f32, sustain: f32, release: f32) {
+        self.stages.clear();
+        self.stages.push(Stage {
+            target: 1.0,
+            time: attack,
+            curve: Curve::Linear,
+        });
+        self.stages.push(Stage {
+            target: sustain,
+            time: decay,
+            curve: Curve::Linear,
+        });
+        self.release = release;
+        self.release_curve = Curve::Linear;
     }
 
     pub fn value(&mut self) -> f32 {
@@ -46,62 +116,259 @@ impl Envelope {
             State::Init => {
                 return 0.0;
             }
-            State::Attack => {
-                self.val += self.attack_rate;
-                if self.val >= 1.0 {
-                    self.val = 1.0;
-                    self.state = if self.decay_rate > 0.0 {
-                        State::Decay
-                    } else {
-                        State::Sustain
-                    }
-                }
-            }
-            State::Decay => {
-                self.val -= self.decay_rate;
-                if self.val <= self.sustain {
-                    self.val = self.sustain;
-                    self.state = State::Sustain
+            State::Stage(i) => {
+                let stage = self.stages[i];
+                self.progress += self.rate;
+                if self.progress >= 1.0 {
+                    self.val = stage.target;
+                    self.enter(i + 1);
+                } else {
+                    self.val =
+                        self.from + (stage.target - self.from) * stage.curve.shape(self.progress);
                 }
             }
             State::Sustain => {
-                if self.sustain == 0.0 {
+                if self.val <= 0.0 {
                     self.state = State::Init;
-                } else {
-                    self.val = self.sustain;
                 }
             }
             State::Release => {
-                self.samples_after_release -= 1;
-                if self.samples_after_release <= 0 {
-                    self.val = 0.0;
-                } else {
-                    self.val -= self.release_rate;
-                }
-                if self.val <= 0.0 {
+                self.progress += self.rate;
+                if self.progress >= 1.0 {
                     self.val = 0.0;
                     self.state = State::Init;
+                } else {
+                    self.val = self.from * (1.0 - self.release_curve.shape(self.progress));
                 }
             }
         }
         return self.val;
     }
 
-    pub fn start_attack(&mut self) {
-        let sample_rate = super::SAMPLE_RATE as f32;
-        self.val = 0.0;
-        self.state = State::Attack;
-        self.attack_rate = 1.0 / (self.attack * sample_rate);
-        self.decay_rate = if self.sustain > 0.0 {
-            1.0 - self.sustain / (self.decay * sample_rate)
-        } else {
-            1.0 / (self.decay * sample_rate)
+    /// Fills `out` with the next values, as `value` would return them one by one up to
+    /// rounding. Within a stage every value is computed from its position in the block rather
+    /// than from the one before, so the loop vectorizes.
+    pub fn fill(&mut self, out: &mut [f32]) {
+        let mut i = 0;
+        while i < out.len() {
+            let (scale, curve) = match self.state {
+                State::Init => {
+                    out[i..].iter_mut().for_each(|v| *v = 0.0);
+                    return;
+                }
+                State::Sustain if self.val > 0.0 => {
+                    let val = self.val;
+                    out[i..].iter_mut().for_each(|v| *v = val);
+                    return;
+                }
+                State::Stage(index) => (
+                    self.stages[index].target - self.from,
+                    self.stages[index].curve,
+                ),
+                State::Release => (-self.from, self.release_curve),
+                State::Sustain => {
+                    out[i] = self.value();
+                    i += 1;
+                    continue;
+                }
+            };
+            // The sample which ends the stage goes through `value`, so do the one before it
+            // in case the progress rounds differently.
+            let left = ((1.0 - self.progress) / self.rate) as usize;
+            let run = usize::min(left.saturating_sub(2), out.len() - i);
+            if run == 0 {
+                out[i] = self.value();
+                i += 1;
+                continue;
+            }
+            let (from, progress, rate) = (self.from, self.progress, self.rate);
+            for (k, v) in out[i..i + run].iter_mut().enumerate() {
+                *v = from + scale * curve.shape(progress + rate * (k + 1) as f32);
+            }
+            self.progress = progress + rate * run as f32;
+            self.val = out[i + run - 1];
+            i += run;
         }
     }
 
+    pub fn level(&self) -> f32 {
+        self.val
+    }
+
+    /// Starts the attack from `level` instead of silence, e.g. to retrigger a sounding note
+    /// without a jump in level.
+    pub fn start_attack_from(&mut self, level: f32) {
+        self.val = level;
+        self.enter(0);
+    }
+
+    /// Silences the envelope at once.
+    pub fn reset(&mut self) {
+        self.state = State::Init;
+        self.val = 0.0;
+        self.from = 0.0;
+        self.progress = 0.0;
+    }
+
     pub fn start_release(&mut self) {
         self.state = State::Release;
-        self.samples_after_release = (self.release * super::SAMPLE_RATE as f32) as i32;
-        self.release_rate = self.val / self.samples_after_release as f32;
+        self.from = self.val;
+        self.progress = 0.0;
+        self.rate = self.rate(self.release);
+    }
+
+    /// Starts the stage at `index`, stages which take no time are skipped.
+    fn enter(&mut self, mut index: usize) {
+        while let Some(stage) = self.stages.get(index) {
+            if stage.time > 0.0 {
+                break;
+            }
+            self.val = stage.target;
+            index += 1;
+        }
+        self.from = self.val;
+        self.progress = 0.0;
+        match self.stages.get(index) {
+            Some(stage) => {
+                self.state = State::Stage(index);
+                self.rate = self.rate(stage.time);
+            }
+            None => self.state = State::Sustain,
+        }
+    }
+
+    fn rate(&self, time: f32) -> f32 {
+        if time > 0.0 {
+            1.0 / (time * self.sample_rate)
+        } else {
+            1.0
+        }
+    }
+}
+
+/// Envelope settings of an instrument, shared by all its voices and adjustable while playing.
+/// With no delay or hold and linear curves, this is a plain ADSR.
+pub struct EnvelopeParams {
+    delay: Arc<AtomicF32>,
+    attack: Arc<AtomicF32>,
+    hold: Arc<AtomicF32>,
+    decay: Arc<AtomicF32>,
+    sustain: Arc<AtomicF32>,
+    release: Arc<AtomicF32>,
+    /// How much velocity shortens the attack, from 0 (not at all) to 1.
+    velocity_attack: Arc<AtomicF32>,
+    /// Same as `velocity_attack` for the decay.
+    velocity_decay: Arc<AtomicF32>,
+    /// Curve of each stage, see `Curve::from_value`.
+    attack_curve: Arc<AtomicF32>,
+    decay_curve: Arc<AtomicF32>,
+    release_curve: Arc<AtomicF32>,
+}
+
+impl EnvelopeParams {
+    pub fn new(attack: f32, decay: f32, sustain: f32, release: f32) -> Self {
+        Self {
+            delay: Arc::new(AtomicF32::new(0.0)),
+            attack: Arc::new(AtomicF32::new(attack)),
+            hold: Arc::new(AtomicF32::new(0.0)),
+            decay: Arc::new(AtomicF32::new(decay)),
+            sustain: Arc::new(AtomicF32::new(sustain)),
+            release: Arc::new(AtomicF32::new(release)),
+            velocity_attack: Arc::new(AtomicF32::new(0.0)),
+            velocity_decay: Arc::new(AtomicF32::new(0.0)),
+            attack_curve: Arc::new(AtomicF32::new(0.0)),
+            decay_curve: Arc::new(AtomicF32::new(0.0)),
+            release_curve: Arc::new(AtomicF32::new(0.0)),
+        }
+    }
+
+    /// Sets up the envelope of a voice for a new note and starts its attack from `level`.
+    /// Hard hits get shorter attack and decay times, soft hits longer ones, up to four times
+    /// either way at full modulation.
+    pub fn trigger(&self, env: &mut Envelope, velocity: u8, level: f32) {
+        let velocity = velocity as f32 / 127.0;
+        let scale = |amount: &AtomicF32| {
+            f32::powf(4.0, amount.load(Ordering::Relaxed) * (1.0 - 2.0 * velocity))
+        };
+        let curve = |curve: &AtomicF32| Curve::from_value(curve.load(Ordering::Relaxed));
+        let stages = [
+            Stage {
+                target: level,
+                time: self.delay.load(Ordering::Relaxed),
+                curve: Curve::Linear,
+            },
+            Stage {
+                target: 1.0,
+                time: self.attack.load(Ordering::Relaxed) * scale(&self.velocity_attack),
+                curve: curve(&self.attack_curve),
+            },
+            Stage {
+                target: 1.0,
+                time: self.hold.load(Ordering::Relaxed),
+                curve: Curve::Linear,
+            },
+            Stage {
+                target: self.sustain.load(Ordering::Relaxed),
+                time: self.decay.load(Ordering::Relaxed) * scale(&self.velocity_decay),
+                curve: curve(&self.decay_curve),
+            },
+        ];
+        env.stages.clear();
+        env.stages.extend_from_slice(&stages);
+        env.release = self.release.load(Ordering::Relaxed);
+        env.release_curve = curve(&self.release_curve);
+        env.start_attack_from(level);
+    }
+
+    pub fn params(&self) -> Vec<(String, Param)> {
+        vec![
+            (
+                "Attack",
+                Param::new(0.0, Arc::clone(&self.attack), 15.0, 0.01).with_unit(Unit::Seconds),
+            ),
+            (
+                "Decay",
+                Param::new(0.0, Arc::clone(&self.decay), 15.0, 0.01).with_unit(Unit::Seconds),
+            ),
+            (
+                "Sustain",
+                Param::new(0.0, Arc::clone(&self.sustain), 15.0, 0.01),
+            ),
+            (
+                "Release",
+                Param::new(0.0, Arc::clone(&self.release), 15.0, 0.01).with_unit(Unit::Seconds),
+            ),
+            (
+                "VelAttack",
+                Param::new(0.0, Arc::clone(&self.velocity_attack), 1.0, 0.05),
+            ),
+            (
+                "VelDecay",
+                Param::new(0.0, Arc::clone(&self.velocity_decay), 1.0, 0.05),
+            ),
+            (
+                "Delay",
+                Param::new(0.0, Arc::clone(&self.delay), 15.0, 0.01).with_unit(Unit::Seconds),
+            ),
+            (
+                "Hold",
+                Param::new(0.0, Arc::clone(&self.hold), 15.0, 0.01).with_unit(Unit::Seconds),
+            ),
+            (
+                "AttackCurve",
+                Param::new(-1.0, Arc::clone(&self.attack_curve), 1.0, 1.0),
+            ),
+            (
+                "DecayCurve",
+                Param::new(-1.0, Arc::clone(&self.decay_curve), 1.0, 1.0),
+            ),
+            (
+                "ReleaseCurve",
+                Param::new(-1.0, Arc::clone(&self.release_curve), 1.0, 1.0),
+            ),
+        ]
+        .into_iter()
+        .map(|(k, v)| (String::from(k), v))
+        .collect()
     }
 }
@@ -0,0 +1,63 @@
+//! Euclidean rhythms: a number of hits spread as evenly as possible over a number of steps,
+//! e.g. 3 hits over 8 steps make the tresillo `x..x..x.`. They're a quick way to program
+//! percussion, a few tracks of them with different lengths make rhythms which don't repeat
+//! for a while.
+
+use crate::pattern::MAX_PATTERN_LENGTH;
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Euclid {
+    /// Number of hits.
+    pub pulses: usize,
+    /// Length of the rhythm in lines, it's repeated over the whole pattern.
+    pub steps: usize,
+    /// Steps the hits are moved later by.
+    pub rotation: usize,
+    /// Lines the rhythm rotates further at every repeat of the track, 0 to play it the same
+    /// every time.
+    pub shift: usize,
+}
+
+impl Euclid {
+    pub fn new(pulses: usize, steps: usize, rotation: usize, shift: usize) -> Result<Self> {
+        if steps == 0 || steps > MAX_PATTERN_LENGTH {
+            return Err(anyhow!(
+                "rhythm length must be between 1 and {}",
+                MAX_PATTERN_LENGTH
+            ));
+        }
+        if pulses > steps {
+            return Err(anyhow!("can't fit {} hits in {} steps", pulses, steps));
+        }
+        Ok(Self {
+            pulses,
+            steps,
+            rotation,
+            shift,
+        })
+    }
+
+    /// Whether each step of the rhythm is a hit. Without rotation the first step is a hit, the
+    /// rhythms are the ones of the Bjorklund algorithm, up to a rotation.
+    pub fn hits(&self) -> Vec<bool> {
+        let rotation = self.rotation % self.steps;
+        (0..self.steps)
+            .map(|step| (step + self.steps - rotation) * self.pulses % self.steps < self.pulses)
+            .collect()
+    }
+}
+
+impl fmt::Display for Euclid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} over {}", self.pulses, self.steps)?;
+        if self.rotation > 0 {
+            write!(f, " rotated by {}", self.rotation)?;
+        }
+        if self.shift > 0 {
+            write!(f, " shifting by {}", self.shift)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+use anyhow::{anyhow, Result};
+use std::f32::consts::PI;
+
+/// How far the filter coefficients move towards their target every sample, so parameter
+/// changes don't produce zipper noise.
+const SMOOTHING: f32 = 0.005;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FilterMode {
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+}
+
+impl FilterMode {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "lowpass" | "lp" => Ok(FilterMode::LowPass),
+            "highpass" | "hp" => Ok(FilterMode::HighPass),
+            "bandpass" | "bp" => Ok(FilterMode::BandPass),
+            "notch" => Ok(FilterMode::Notch),
+            _ => Err(anyhow!("unknown filter mode {}", name)),
+        }
+    }
+}
+
+/// Frequency and damping coefficients of a state variable filter.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Coefficients {
+    f: f32,
+    q: f32,
+}
+
+impl Coefficients {
+    /// `resonance` goes from 0 to 1, just below self-oscillation.
+    pub fn new(cutoff: f32, resonance: f32, sample_rate: f32) -> Self {
+        // Stay well below Nyquist, where this filter becomes unstable.
+        let cutoff = cutoff.clamp(1.0, sample_rate / 6.0);
+        Self {
+            f: 2.0 * f32::sin(PI * cutoff / sample_rate),
+            q: 1.0 - resonance.clamp(0.0, 1.0) * 0.95,
+        }
+    }
+}
+
+/// A 12dB/octave Chamberlin state variable filter for a single channel, with smoothed
+/// coefficients.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Svf {
+    low: f32,
+    band: f32,
+    current: Coefficients,
+}
+
+impl Svf {
+    /// Clears the filter state and jumps straight to `coefficients`, e.g. for a new note.
+    pub fn reset(&mut self, coefficients: Coefficients) {
+        self.low = 0.0;
+        self.band = 0.0;
+        self.current = coefficients;
+    }
+
+    pub fn process(&mut self, input: f32, mode: FilterMode, target: Coefficients) -> f32 {
+        self.current.f += (target.f - self.current.f) * SMOOTHING;
+        self.current.q += (target.q - self.current.q) * SMOOTHING;
+        let Coefficients { f, q } = self.current;
+        self.low += f * self.band;
+        let high = input - self.low - q * self.band;
+        self.band += f * high;
+        match mode {
+            FilterMode::LowPass => self.low,
+            FilterMode::HighPass => high,
+            FilterMode::BandPass => self.band,
+            FilterMode::Notch => self.low + high,
+        }
+    }
+}
+
+/// Soft clips a sample, `drive` goes from 0 (clean) to 1. Full scale input stays at full
+/// scale.
+pub fn saturate(input: f32, drive: f32) -> f32 {
+    if drive <= 0.0 {
+        return input;
+    }
+    let gain = 1.0 + drive * 9.0;
+    f32::tanh(input * gain) / f32::tanh(gain)
+}
+
+/// Waveshaping curves, for full scale output at and beyond full scale input.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Curve {
+    Tanh,
+    HardClip,
+    /// Reflects the signal back from full scale, which adds strong high harmonics.
+    Foldback,
+}
+
+impl Curve {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "tanh" => Ok(Curve::Tanh),
+            "hard" | "clip" => Ok(Curve::HardClip),
+            "fold" | "foldback" => Ok(Curve::Foldback),
+            _ => Err(anyhow!("unknown curve {}", name)),
+        }
+    }
+
+    pub fn shape(self, input: f32) -> f32 {
+        match self {
+            Curve::Tanh => input.tanh(),
+            Curve::HardClip => input.clamp(-1.0, 1.0),
+            Curve::Foldback => ((input - 1.0).rem_euclid(4.0) - 2.0).abs() - 1.0,
+        }
+    }
+}
+
+/// Q of the sections of an 8th order Butterworth lowpass.
+const BUTTERWORTH_Q: [f32; 4] = [0.5098, 0.6013, 0.9000, 2.5629];
+
+/// A second order filter section in transposed direct form II, with the RBJ cookbook
+/// designs.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    fn new(b: [f32; 3], a: [f32; 3]) -> Self {
+        Self {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    pub fn low_shelf(frequency: f32, gain_db: f32, sample_rate: f32) -> Self {
+        let (a, cos, alpha) = shelf_terms(frequency, gain_db, sample_rate);
+        let root = 2.0 * a.sqrt() * alpha;
+        Self::new(
+            [
+                a * ((a + 1.0) - (a - 1.0) * cos + root),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - root),
+            ],
+            [
+                (a + 1.0) + (a - 1.0) * cos + root,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - root,
+            ],
+        )
+    }
+
+    pub fn high_shelf(frequency: f32, gain_db: f32, sample_rate: f32) -> Self {
+        let (a, cos, alpha) = shelf_terms(frequency, gain_db, sample_rate);
+        let root = 2.0 * a.sqrt() * alpha;
+        Self::new(
+            [
+                a * ((a + 1.0) + (a - 1.0) * cos + root),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - root),
+            ],
+            [
+                (a + 1.0) - (a - 1.0) * cos + root,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - root,
+            ],
+        )
+    }
+
+    pub fn peaking(frequency: f32, gain_db: f32, q: f32, sample_rate: f32) -> Self {
+        let a = f32::powf(10.0, gain_db / 40.0);
+        let w = 2.0 * PI * frequency.clamp(10.0, sample_rate * 0.45) / sample_rate;
+        let alpha = f32::sin(w) / (2.0 * q.max(0.1));
+        let cos = f32::cos(w);
+        Self::new(
+            [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+            [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+        )
+    }
+
+    /// Switches to the response of `other`, keeping the filter state so there's no click.
+    pub fn retune(&mut self, other: &Biquad) {
+        self.b0 = other.b0;
+        self.b1 = other.b1;
+        self.b2 = other.b2;
+        self.a1 = other.a1;
+        self.a2 = other.a2;
+    }
+
+    /// Clears the filter state, keeping its response.
+    pub fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
+    }
+
+    fn lowpass(cutoff: f32, q: f32, sample_rate: f32) -> Self {
+        let w = 2.0 * PI * cutoff / sample_rate;
+        let alpha = f32::sin(w) / (2.0 * q);
+        let cos = f32::cos(w);
+        Self::new(
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        let output = self.b0 * input + self.z1;
+        self.z1 = self.b1 * input - self.a1 * output + self.z2;
+        self.z2 = self.b2 * input - self.a2 * output;
+        output
+    }
+}
+
+/// Gain, cosine and alpha of a shelf with a slope of 1.
+fn shelf_terms(frequency: f32, gain_db: f32, sample_rate: f32) -> (f32, f32, f32) {
+    let a = f32::powf(10.0, gain_db / 40.0);
+    let w = 2.0 * PI * frequency.clamp(10.0, sample_rate * 0.45) / sample_rate;
+    let alpha = f32::sin(w) / 2.0 * f32::sqrt(2.0);
+    (a, f32::cos(w), alpha)
+}
+
+/// Runs a nonlinear function at a multiple of the sample rate for a single channel, so the
+/// harmonics it adds above Nyquist are filtered out instead of aliasing back down.
+#[derive(Clone, Debug)]
+pub struct Oversampler {
+    factor: usize,
+    up: [Biquad; 4],
+    down: [Biquad; 4],
+}
+
+impl Oversampler {
+    pub fn new(factor: usize, sample_rate: f32) -> Self {
+        let factor = factor.max(1);
+        let rate = sample_rate * factor as f32;
+        // Just below the original Nyquist frequency.
+        let cutoff = sample_rate * 0.45;
+        let mut sections = [Biquad::default(); 4];
+        for (section, q) in sections.iter_mut().zip(BUTTERWORTH_Q.iter()) {
+            *section = Biquad::lowpass(cutoff, *q, rate);
+        }
+        Self {
+            factor,
+            up: sections,
+            down: sections,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for section in self.up.iter_mut().chain(&mut self.down) {
+            section.reset();
+        }
+    }
+
+    pub fn process(&mut self, input: f32, mut shape: impl FnMut(f32) -> f32) -> f32 {
+        if self.factor == 1 {
+            return shape(input);
+        }
+        let mut output = 0.0;
+        for i in 0..self.factor {
+            // Zero stuffing, the gain makes up for the inserted zeros.
+            let mut sample = match i {
+                0 => input * self.factor as f32,
+                _ => 0.0,
+            };
+            for section in &mut self.up {
+                sample = section.process(sample);
+            }
+            sample = shape(sample);
+            for section in &mut self.down {
+                sample = section.process(sample);
+            }
+            output = sample;
+        }
+        output
+    }
+}
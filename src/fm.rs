@@ -0,0 +1,340 @@
+//! A four operator FM synth in the style of the DX and TX81Z: every operator is a sine with its
+//! own envelope, and the algorithm decides which operators modulate the phase of which, and
+//! which ones are heard.
+
+use crate::engine::{Device, EngineConfig, CONTROL_BLOCK_SIZE};
+use crate::env::{Envelope, EnvelopeParams, State as EnvelopeState};
+use crate::instrument::Instrument;
+use crate::param::{Param, Smoothed, Unit};
+use atomic_float::AtomicF32;
+use std::f32::consts::PI;
+use std::sync::{atomic::Ordering, Arc};
+
+const NUM_VOICES: usize = 8;
+const NUM_OPERATORS: usize = 4;
+/// Phase shift in cycles of an operator modulated by another at full level.
+const MODULATION_DEPTH: f32 = 1.0;
+/// Phase shift in cycles of an operator fed back into itself at full feedback.
+const FEEDBACK_DEPTH: f32 = 0.25;
+
+/// The algorithms of the TX81Z, operators are numbered from 0 and only ever modulated by
+/// operators with a higher number.
+struct Algorithm {
+    /// The operators modulating each operator, as bits.
+    modulators: [u8; NUM_OPERATORS],
+    /// The operators which are heard, as bits.
+    carriers: u8,
+}
+
+const ALGORITHMS: [Algorithm; 8] = [
+    // 4 > 3 > 2 > 1
+    Algorithm {
+        modulators: [0b0010, 0b0100, 0b1000, 0],
+        carriers: 0b0001,
+    },
+    // 3 + 4 > 2 > 1
+    Algorithm {
+        modulators: [0b0010, 0b1100, 0, 0],
+        carriers: 0b0001,
+    },
+    // 3 > 2 > 1 and 4 > 1
+    Algorithm {
+        modulators: [0b1010, 0b0100, 0, 0],
+        carriers: 0b0001,
+    },
+    // 4 > 3 > 1 and 2 > 1
+    Algorithm {
+        modulators: [0b0110, 0, 0b1000, 0],
+        carriers: 0b0001,
+    },
+    // 2 > 1 and 4 > 3
+    Algorithm {
+        modulators: [0b0010, 0, 0b1000, 0],
+        carriers: 0b0101,
+    },
+    // 4 > 1, 2 and 3
+    Algorithm {
+        modulators: [0b1000, 0b1000, 0b1000, 0],
+        carriers: 0b0111,
+    },
+    // 4 > 3, 1 and 2 alone
+    Algorithm {
+        modulators: [0, 0, 0b1000, 0],
+        carriers: 0b0111,
+    },
+    // Four sines
+    Algorithm {
+        modulators: [0; NUM_OPERATORS],
+        carriers: 0b1111,
+    },
+];
+
+/// Settings of an operator, shared by all voices.
+struct Operator {
+    /// Frequency relative to the note.
+    ratio: Arc<AtomicF32>,
+    /// Output level, which sets the depth of modulation when modulating.
+    level: Arc<AtomicF32>,
+    /// How much the operator modulates itself, from 0 to 1.
+    feedback: Arc<AtomicF32>,
+    envelope: EnvelopeParams,
+}
+
+impl Operator {
+    fn new(ratio: f32, level: f32, envelope: EnvelopeParams) -> Self {
+        Self {
+            ratio: Arc::new(AtomicF32::new(ratio)),
+            level: Arc::new(AtomicF32::new(level)),
+            feedback: Arc::new(AtomicF32::new(0.0)),
+            envelope,
+        }
+    }
+}
+
+struct Voice {
+    column: usize,
+    pitch: u8,
+    volume: f32,
+    /// Position of each operator in its cycle, from 0 to 1.
+    phases: [f32; NUM_OPERATORS],
+    /// Last two outputs of each operator, averaged for the feedback so it doesn't turn into
+    /// noise.
+    outputs: [[f32; 2]; NUM_OPERATORS],
+    envs: Vec<Envelope>,
+}
+
+impl Voice {
+    fn new(sample_rate: f32) -> Self {
+        Self {
+            column: 0,
+            pitch: 0,
+            volume: 0.0,
+            phases: [0.0; NUM_OPERATORS],
+            outputs: [[0.0; 2]; NUM_OPERATORS],
+            envs: (0..NUM_OPERATORS)
+                .map(|_| Envelope::new(sample_rate))
+                .collect(),
+        }
+    }
+
+    /// Sounding until the envelopes of all operators end.
+    fn is_busy(&self) -> bool {
+        self.envs.iter().any(|env| env.state != EnvelopeState::Init)
+    }
+
+    fn is_held(&self) -> bool {
+        self.envs
+            .iter()
+            .any(|env| env.state != EnvelopeState::Init && env.state != EnvelopeState::Release)
+    }
+
+    fn level(&self) -> f32 {
+        self.envs.iter().map(Envelope::level).fold(0.0, f32::max)
+    }
+
+    fn reset(&mut self) {
+        for env in &mut self.envs {
+            env.reset();
+        }
+        self.outputs = [[0.0; 2]; NUM_OPERATORS];
+    }
+}
+
+pub struct Fm {
+    voices: Vec<Voice>,
+    operators: Vec<Operator>,
+    /// Number of the algorithm, from 1.
+    algorithm: Arc<AtomicF32>,
+    amp: Smoothed,
+    sample_rate: f32,
+}
+
+impl Fm {
+    /// A plain electric piano like tone: the second operator modulates the first at twice the
+    /// frequency, the others are silent.
+    pub fn new() -> Self {
+        let sample_rate = EngineConfig::default().sample_rate as f32;
+        let operators = vec![
+            Operator::new(1.0, 1.0, EnvelopeParams::new(0.002, 1.5, 0.5, 0.4)),
+            Operator::new(2.0, 0.4, EnvelopeParams::new(0.002, 0.6, 0.1, 0.4)),
+            Operator::new(1.0, 0.0, EnvelopeParams::new(0.002, 0.6, 0.5, 0.4)),
+            Operator::new(1.0, 0.0, EnvelopeParams::new(0.002, 0.6, 0.5, 0.4)),
+        ];
+        Self {
+            voices: (0..NUM_VOICES).map(|_| Voice::new(sample_rate)).collect(),
+            operators,
+            algorithm: Arc::new(AtomicF32::new(1.0)),
+            amp: Smoothed::new(-6.0, sample_rate),
+            sample_rate,
+        }
+    }
+
+    fn render_block(&mut self, buffer: &mut [(f32, f32)]) {
+        let mut amp = [0.0; CONTROL_BLOCK_SIZE];
+        let amp = &mut amp[..buffer.len()];
+        self.amp.fill(amp);
+        amp.iter_mut().for_each(|amp| *amp = db_to_gain(*amp));
+
+        let index = self.algorithm.load(Ordering::Relaxed).round() as usize;
+        let algorithm = &ALGORITHMS[index.clamp(1, ALGORITHMS.len()) - 1];
+        let carrier_gain = 1.0 / algorithm.carriers.count_ones() as f32;
+        let load = |value: &AtomicF32| value.load(Ordering::Relaxed);
+        let mut ratios = [0.0; NUM_OPERATORS];
+        let mut levels = [0.0; NUM_OPERATORS];
+        let mut feedbacks = [0.0; NUM_OPERATORS];
+        for (i, operator) in self.operators.iter().enumerate() {
+            ratios[i] = load(&operator.ratio);
+            levels[i] = load(&operator.level);
+            feedbacks[i] = load(&operator.feedback) * FEEDBACK_DEPTH;
+        }
+        let sample_rate = self.sample_rate;
+
+        for voice in self.voices.iter_mut().filter(|v| v.is_busy()) {
+            let frequency = 440.0 * f32::powf(2.0, (voice.pitch as f32 - 69.0) / 12.0);
+            for (i, out) in buffer.iter_mut().enumerate() {
+                // Modulators have higher numbers, so they're done first
+                let mut outputs = [0.0; NUM_OPERATORS];
+                for op in (0..NUM_OPERATORS).rev() {
+                    let modulation: f32 = (0..NUM_OPERATORS)
+                        .filter(|m| algorithm.modulators[op] & (1 << m) != 0)
+                        .map(|m| outputs[m] * MODULATION_DEPTH)
+                        .sum();
+                    let [previous, last] = voice.outputs[op];
+                    let feedback = feedbacks[op] * (previous + last) / 2.0;
+                    let phase = voice.phases[op] + modulation + feedback;
+                    let output = f32::sin(2.0 * PI * phase) * levels[op] * voice.envs[op].value();
+                    voice.outputs[op] = [last, output];
+                    outputs[op] = output;
+                    let increment = frequency * ratios[op] / sample_rate;
+                    voice.phases[op] = (voice.phases[op] + increment).fract();
+                }
+                let carriers: f32 = (0..NUM_OPERATORS)
+                    .filter(|op| algorithm.carriers & (1 << op) != 0)
+                    .map(|op| outputs[op])
+                    .sum();
+                let sample = carriers * carrier_gain * voice.volume * amp[i];
+                out.0 += sample;
+                out.1 += sample;
+                if !voice.is_busy() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl Device for Fm {
+    fn render(&mut self, buffer: &mut [(f32, f32)]) {
+        for block in buffer.chunks_mut(CONTROL_BLOCK_SIZE) {
+            self.render_block(block);
+        }
+    }
+}
+
+impl Instrument for Fm {
+    fn note_on(&mut self, column: usize, pitch: u8, velocity: u8) {
+        // Tracks play one note at a time, the previous one fades out with its release
+        self.note_off(column);
+        // A free voice, or else the quietest one, which restarts from its level so it doesn't
+        // click.
+        let index = match self.voices.iter().position(|v| !v.is_busy()) {
+            Some(index) => index,
+            None => match self
+                .voices
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.level().total_cmp(&b.level()))
+            {
+                Some((index, _)) => index,
+                None => return,
+            },
+        };
+        let voice = &mut self.voices[index];
+        if !voice.is_busy() {
+            voice.phases = [0.0; NUM_OPERATORS];
+            voice.outputs = [[0.0; 2]; NUM_OPERATORS];
+        }
+        voice.column = column;
+        voice.pitch = pitch;
+        voice.volume = db_to_gain(-60.0 * (1.0 - velocity as f32 / 127.0));
+        for (operator, env) in self.operators.iter().zip(&mut voice.envs) {
+            let level = env.level();
+            operator.envelope.trigger(env, velocity, level);
+        }
+    }
+
+    fn note_off(&mut self, column: usize) {
+        for voice in &mut self.voices {
+            if voice.column == column && voice.is_held() {
+                for env in &mut voice.envs {
+                    env.start_release();
+                }
+            }
+        }
+    }
+
+    fn prepare(&mut self, config: &EngineConfig) {
+        self.sample_rate = config.sample_rate as f32;
+        self.amp.prepare(self.sample_rate);
+        for voice in &mut self.voices {
+            for env in &mut voice.envs {
+                env.sample_rate = self.sample_rate;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        for voice in &mut self.voices {
+            voice.reset();
+        }
+    }
+
+    fn active_voices(&self) -> usize {
+        self.voices.iter().filter(|v| v.is_busy()).count()
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        let mut params = vec![
+            (
+                String::from("Amp"),
+                Param::new(-60.0, Arc::clone(&self.amp.val), 6.0, 1.0).with_unit(Unit::Decibel),
+            ),
+            (
+                String::from("Algorithm"),
+                Param::new(
+                    1.0,
+                    Arc::clone(&self.algorithm),
+                    ALGORITHMS.len() as f32,
+                    1.0,
+                ),
+            ),
+        ];
+        for (i, operator) in self.operators.iter().enumerate() {
+            let prefix = format!("Op{}", i + 1);
+            params.push((
+                format!("{}Ratio", prefix),
+                Param::new(0.5, Arc::clone(&operator.ratio), 16.0, 0.5),
+            ));
+            params.push((
+                format!("{}Level", prefix),
+                Param::new(0.0, Arc::clone(&operator.level), 1.0, 0.01),
+            ));
+            params.push((
+                format!("{}Feedback", prefix),
+                Param::new(0.0, Arc::clone(&operator.feedback), 1.0, 0.05),
+            ));
+            params.extend(
+                operator
+                    .envelope
+                    .params()
+                    .into_iter()
+                    .map(|(name, param)| (format!("{}{}", prefix, name), param)),
+            );
+        }
+        params
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    f32::powf(10.0, db / 20.0)
+}
@@ -0,0 +1,135 @@
+//! A frame of audio, one sample per channel. The channels are a plain array so a slice of
+//! frames is one contiguous run of samples, which the compiler can vectorize, and the
+//! arithmetic unrolls to the same code as hand-written stereo.
+
+use std::ops::{Add, AddAssign, Index, IndexMut, Mul, MulAssign, Sub};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(transparent)]
+pub struct Frame<const N: usize>(pub [f32; N]);
+
+pub type Mono = Frame<1>;
+pub type Stereo = Frame<2>;
+
+impl<const N: usize> Frame<N> {
+    pub const SILENCE: Self = Self([0.0; N]);
+
+    /// The same sample on every channel.
+    pub fn splat(sample: f32) -> Self {
+        Self([sample; N])
+    }
+
+    pub fn map(self, mut f: impl FnMut(f32) -> f32) -> Self {
+        let mut out = self;
+        for sample in &mut out.0 {
+            *sample = f(*sample);
+        }
+        out
+    }
+
+    /// Combines the samples of two frames channel by channel.
+    pub fn zip_map(self, other: Self, mut f: impl FnMut(f32, f32) -> f32) -> Self {
+        let mut out = self;
+        for (sample, other) in out.0.iter_mut().zip(other.0) {
+            *sample = f(*sample, other);
+        }
+        out
+    }
+
+    /// Highest absolute sample of all channels.
+    pub fn peak(&self) -> f32 {
+        self.0
+            .iter()
+            .fold(0.0, |peak, sample| f32::max(peak, sample.abs()))
+    }
+}
+
+impl Stereo {
+    pub fn new(left: f32, right: f32) -> Self {
+        Self([left, right])
+    }
+
+    pub fn left(&self) -> f32 {
+        self.0[0]
+    }
+
+    pub fn right(&self) -> f32 {
+        self.0[1]
+    }
+}
+
+impl<const N: usize> Default for Frame<N> {
+    fn default() -> Self {
+        Self::SILENCE
+    }
+}
+
+impl<const N: usize> Index<usize> for Frame<N> {
+    type Output = f32;
+
+    fn index(&self, channel: usize) -> &f32 {
+        &self.0[channel]
+    }
+}
+
+impl<const N: usize> IndexMut<usize> for Frame<N> {
+    fn index_mut(&mut self, channel: usize) -> &mut f32 {
+        &mut self.0[channel]
+    }
+}
+
+impl<const N: usize> Add for Frame<N> {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        self.zip_map(other, |a, b| a + b)
+    }
+}
+
+impl<const N: usize> AddAssign for Frame<N> {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other;
+    }
+}
+
+impl<const N: usize> Sub for Frame<N> {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        self.zip_map(other, |a, b| a - b)
+    }
+}
+
+impl<const N: usize> Mul<f32> for Frame<N> {
+    type Output = Self;
+
+    fn mul(self, gain: f32) -> Self {
+        self.map(|sample| sample * gain)
+    }
+}
+
+impl<const N: usize> MulAssign<f32> for Frame<N> {
+    fn mul_assign(&mut self, gain: f32) {
+        *self = *self * gain;
+    }
+}
+
+/// Buffers between the engine, the mixer and the effects are still slices of tuples, these
+/// convert at their boundary.
+impl From<(f32, f32)> for Stereo {
+    fn from((left, right): (f32, f32)) -> Self {
+        Self([left, right])
+    }
+}
+
+impl From<Stereo> for (f32, f32) {
+    fn from(frame: Stereo) -> Self {
+        (frame.0[0], frame.0[1])
+    }
+}
+
+impl From<Mono> for Stereo {
+    fn from(frame: Mono) -> Self {
+        Self::splat(frame.0[0])
+    }
+}
@@ -0,0 +1,272 @@
+//! The key and scale of the song, for entering notes in the scale and for chords: a step can
+//! hold a chord, which is expanded into its notes when the step is played.
+
+use crate::pattern::MAX_TRACKS;
+use crate::stretch::Key;
+use anyhow::{anyhow, Result};
+
+/// Most notes of a chord.
+pub const MAX_CHORD_NOTES: usize = 6;
+/// Number of user defined voicings.
+pub const NUM_VOICINGS: usize = 8;
+
+/// A seven note scale, from the root of the key.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Scale {
+    Major,
+    Minor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+    HarmonicMinor,
+    MelodicMinor,
+}
+
+impl Scale {
+    const ALL: [Scale; 9] = [
+        Scale::Major,
+        Scale::Minor,
+        Scale::Dorian,
+        Scale::Phrygian,
+        Scale::Lydian,
+        Scale::Mixolydian,
+        Scale::Locrian,
+        Scale::HarmonicMinor,
+        Scale::MelodicMinor,
+    ];
+
+    pub fn parse(name: &str) -> Result<Self> {
+        Self::ALL
+            .iter()
+            .find(|scale| scale.name() == name)
+            .copied()
+            .ok_or_else(|| {
+                anyhow!(
+                    "unknown scale {}, expected major|minor|dorian|phrygian|lydian|mixolydian|locrian|harmonic|melodic",
+                    name
+                )
+            })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Scale::Major => "major",
+            Scale::Minor => "minor",
+            Scale::Dorian => "dorian",
+            Scale::Phrygian => "phrygian",
+            Scale::Lydian => "lydian",
+            Scale::Mixolydian => "mixolydian",
+            Scale::Locrian => "locrian",
+            Scale::HarmonicMinor => "harmonic",
+            Scale::MelodicMinor => "melodic",
+        }
+    }
+
+    /// Semitones of each degree above the root.
+    fn intervals(self) -> [u8; 7] {
+        match self {
+            Scale::Major => [0, 2, 4, 5, 7, 9, 11],
+            Scale::Minor => [0, 2, 3, 5, 7, 8, 10],
+            Scale::Dorian => [0, 2, 3, 5, 7, 9, 10],
+            Scale::Phrygian => [0, 1, 3, 5, 7, 8, 10],
+            Scale::Lydian => [0, 2, 4, 6, 7, 9, 11],
+            Scale::Mixolydian => [0, 2, 4, 5, 7, 9, 10],
+            Scale::Locrian => [0, 1, 3, 5, 6, 8, 10],
+            Scale::HarmonicMinor => [0, 2, 3, 5, 7, 8, 11],
+            Scale::MelodicMinor => [0, 2, 3, 5, 7, 9, 11],
+        }
+    }
+}
+
+/// A chord played from the note of a step.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Chord {
+    Triad,
+    Seventh,
+    Sus2,
+    Sus4,
+    Add9,
+    /// One of the user defined voicings, from 0.
+    Voicing(u8),
+}
+
+impl Chord {
+    const BUILT_IN: [Chord; 5] = [
+        Chord::Triad,
+        Chord::Seventh,
+        Chord::Sus2,
+        Chord::Sus4,
+        Chord::Add9,
+    ];
+
+    /// Parses a built-in chord, or a voicing as `v1` to `v8`.
+    pub fn parse(name: &str) -> Result<Self> {
+        if let Some(chord) = Self::BUILT_IN.iter().find(|chord| chord.name() == name) {
+            return Ok(*chord);
+        }
+        match name.strip_prefix('v').map(str::parse::<usize>) {
+            Some(Ok(n @ 1..=NUM_VOICINGS)) => Ok(Chord::Voicing(n as u8 - 1)),
+            _ => Err(anyhow!(
+                "unknown chord {}, expected triad|7th|sus2|sus4|add9|v1-v{}",
+                name,
+                NUM_VOICINGS
+            )),
+        }
+    }
+
+    pub fn name(self) -> String {
+        match self {
+            Chord::Triad => String::from("triad"),
+            Chord::Seventh => String::from("7th"),
+            Chord::Sus2 => String::from("sus2"),
+            Chord::Sus4 => String::from("sus4"),
+            Chord::Add9 => String::from("add9"),
+            Chord::Voicing(n) => format!("v{}", n + 1),
+        }
+    }
+
+    /// A single character for the pattern editor.
+    pub fn symbol(self) -> char {
+        match self {
+            Chord::Triad => '3',
+            Chord::Seventh => '7',
+            Chord::Sus2 => '2',
+            Chord::Sus4 => '4',
+            Chord::Add9 => '9',
+            Chord::Voicing(n) => (b'a' + n) as char,
+        }
+    }
+
+    /// Degrees of the scale above the root, for the built-in chords.
+    fn degrees(self) -> &'static [usize] {
+        match self {
+            Chord::Triad => &[0, 2, 4],
+            Chord::Seventh => &[0, 2, 4, 6],
+            Chord::Sus2 => &[0, 1, 4],
+            Chord::Sus4 => &[0, 3, 4],
+            Chord::Add9 => &[0, 2, 4, 8],
+            Chord::Voicing(_) => &[0],
+        }
+    }
+}
+
+/// Column an instrument plays a note of a chord on, the root keeps the column of the track.
+pub fn column(track: usize, note: usize) -> usize {
+    track + note * MAX_TRACKS
+}
+
+/// Number of columns the tracks play notes on.
+pub const NUM_COLUMNS: usize = MAX_TRACKS * MAX_CHORD_NOTES;
+
+/// Most notes played live at once, e.g. from the computer keyboard.
+pub const MAX_LIVE_NOTES: usize = 10;
+
+/// Column an instrument plays a live note on, after the columns of the tracks so playing along
+/// doesn't end the notes of the song.
+pub fn live_column(slot: usize) -> usize {
+    NUM_COLUMNS + slot
+}
+
+/// Most notes played over OSC at once.
+pub const MAX_REMOTE_NOTES: usize = 16;
+
+/// Column an instrument plays a note received over OSC on, after the live columns.
+pub fn remote_column(slot: usize) -> usize {
+    NUM_COLUMNS + MAX_LIVE_NOTES + slot
+}
+
+/// Every column the chords of a track can play on.
+pub fn columns(track: usize) -> impl Iterator<Item = usize> {
+    (0..MAX_CHORD_NOTES).map(move |note| column(track, note))
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Harmony {
+    pub key: Option<Key>,
+    /// Scale of the key, when it's neither its plain major nor minor.
+    pub scale: Option<Scale>,
+    /// Semitones of each note above the note of the step, empty when not defined.
+    pub voicings: [Vec<i8>; NUM_VOICINGS],
+}
+
+impl Harmony {
+    /// Root and scale of the song, when it has a key.
+    fn root_and_scale(&self) -> Option<(u8, Scale)> {
+        let key = self.key?;
+        let scale = match self.scale {
+            Some(scale) => scale,
+            None if key.minor => Scale::Minor,
+            None => Scale::Major,
+        };
+        Some((key.root, scale))
+    }
+
+    /// The note of the scale nearest to `pitch`, the lower one when two are as near. Without a
+    /// key every note is in the scale.
+    pub fn snap(&self, pitch: u8) -> u8 {
+        let (root, scale) = match self.root_and_scale() {
+            Some(scale) => scale,
+            None => return pitch,
+        };
+        let intervals = scale.intervals();
+        let in_scale = |pitch: i32| {
+            let offset = (pitch - root as i32).rem_euclid(12) as u8;
+            (0..=127).contains(&pitch) && intervals.contains(&offset)
+        };
+        let pitch = pitch as i32;
+        (0..12)
+            .flat_map(|distance| [pitch - distance, pitch + distance])
+            .find(|pitch| in_scale(*pitch))
+            .unwrap_or(pitch) as u8
+    }
+
+    /// The notes of `chord` played from `pitch`, in the order they're given columns. Built-in
+    /// chords stack the notes of the scale above the root, or of the major scale of the root
+    /// when the song has no key. Notes out of range are left out.
+    pub fn chord_notes(&self, pitch: u8, chord: Chord) -> [Option<u8>; MAX_CHORD_NOTES] {
+        let mut offsets = [None; MAX_CHORD_NOTES];
+        match chord {
+            Chord::Voicing(n) => match self.voicings.get(n as usize) {
+                Some(voicing) if !voicing.is_empty() => {
+                    for (offset, semitones) in offsets.iter_mut().zip(voicing) {
+                        *offset = Some(*semitones as i32);
+                    }
+                }
+                _ => offsets[0] = Some(0),
+            },
+            chord => {
+                let (root, scale) = self.root_and_scale().unwrap_or((pitch % 12, Scale::Major));
+                let intervals = scale.intervals();
+                // Degree of the scale the chord is built on, counted from the root of the key
+                let base = self.snap(pitch) as i32 - root as i32;
+                let octave = base.div_euclid(12);
+                let degree = intervals
+                    .iter()
+                    .position(|interval| *interval as i32 == base.rem_euclid(12))
+                    .unwrap_or(0);
+                for (offset, step) in offsets.iter_mut().zip(chord.degrees()) {
+                    let degree = degree + step;
+                    let note = (octave + (degree / 7) as i32) * 12
+                        + intervals[degree % 7] as i32
+                        + root as i32;
+                    // The root stays the note of the step, even out of the scale
+                    *offset = Some(match step {
+                        0 => 0,
+                        _ => note - pitch as i32,
+                    });
+                }
+            }
+        }
+        let mut notes = [None; MAX_CHORD_NOTES];
+        for (note, offset) in notes.iter_mut().zip(offsets) {
+            if let Some(pitch) = offset.map(|offset| pitch as i32 + offset) {
+                if (0..=127).contains(&pitch) {
+                    *note = Some(pitch as u8);
+                }
+            }
+        }
+        notes
+    }
+}
@@ -0,0 +1,48 @@
+use std::fmt;
+
+macro_rules! define_id {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub struct $name(pub u32);
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}", self.0)
+            }
+        }
+    };
+}
+
+define_id!(
+    /// Identifies a pattern, independent of its position in the song.
+    PatternId
+);
+define_id!(
+    /// Identifies a track column, shared by all patterns.
+    TrackId
+);
+define_id!(
+    /// Identifies a loaded instrument, independent of the slot it lives in.
+    InstrumentId
+);
+
+/// Hands out unique ids. Ids are never reused, so references to removed items can't
+/// accidentally point to new ones.
+#[derive(Clone, Default)]
+pub struct IdGen {
+    next: u32,
+}
+
+impl IdGen {
+    pub fn next(&mut self) -> u32 {
+        let id = self.next;
+        self.next += 1;
+        id
+    }
+
+    /// Makes sure an id loaded from elsewhere is never handed out again.
+    pub fn observe(&mut self, id: u32) {
+        self.next = u32::max(self.next, id + 1);
+    }
+}
@@ -1,23 +1,51 @@
-use crate::pattern::NUM_TRACK_LANES;
+use crate::bounce::BounceSettings;
+use crate::drums::DEFAULT_THRESHOLD;
+use crate::euclid::Euclid;
+use crate::harmony::{Chord, Scale, NUM_VOICINGS};
+use crate::instrument::Options;
+use crate::keyboard;
+use crate::keymap::{Region, RegionEdit};
+use crate::kit::KitFormat;
+use crate::lfo::{self, Rate, Shape};
+use crate::library::{self, Label, Query};
+use crate::mixer::{bus_name, return_channel, Source, MASTER_CHANNEL, MIN_GAIN, NUM_BUSES};
+use crate::osc;
+use crate::pattern::{
+    LengthPolicy, Section, SectionOp, Transform, MAX_EDIT_STEP, MAX_OFFSET, MAX_TRACKS,
+    NUM_TRACK_LANES,
+};
+use crate::record::LoopPolicy;
+use crate::sampler::{MemoryPolicy, ModDestination, RateConversion, Retrigger, SoundEdit};
+use crate::script::Hook;
+use crate::stretch;
+use crate::transport::BarsBeats;
 use crate::{
     app::{Action, App},
     engine::EngineParam,
 };
 use crate::{pattern::Move, ui::ListCursorExt};
 use anyhow::{anyhow, Result};
+use camino::Utf8PathBuf;
 use std::{
     io,
-    sync::mpsc::{self, Receiver},
+    sync::mpsc::{self, Receiver, Sender},
     thread,
     time::Duration,
 };
 use termion::{event::Key, input::TermRead};
 
+/// Highest octave notes are entered in, the octave above it is past the last MIDI note.
+const MAX_OCTAVE: i32 = 9;
+
 #[derive(PartialEq)]
 pub enum Focus {
     Editor,
     CommandLine,
     FileBrowser,
+    /// The params of the selected instrument.
+    Params,
+    /// The computer keyboard plays the selected instrument.
+    Keyboard,
 }
 
 pub struct CommandState {
@@ -26,18 +54,30 @@ pub struct CommandState {
 
 pub enum Input {
     Key(Key),
+    /// A message received by the OSC server, or why a packet couldn't be read.
+    Osc(Result<osc::Message>),
     Tick,
 }
 
 pub struct InputQueue {
     events: Receiver<Input>,
+    sender: Sender<Input>,
 }
 
 impl InputQueue {
     pub fn new() -> Self {
         let (sender, receiver) = mpsc::channel();
+        Self {
+            events: receiver,
+            sender,
+        }
+    }
+
+    /// Starts reading key presses from the terminal, along with a tick to redraw at. Not done
+    /// on creation, the app has no terminal when it runs as a plugin.
+    pub fn listen(&self) {
         {
-            let sender = sender.clone();
+            let sender = self.sender.clone();
             thread::spawn(move || {
                 let stdin = io::stdin();
                 for evt in stdin.keys() {
@@ -47,13 +87,20 @@ impl InputQueue {
                 }
             })
         };
-        thread::spawn(move || loop {
-            if sender.send(Input::Tick).is_err() {
-                return;
-            }
-            thread::sleep(Duration::from_millis(33));
-        });
-        Self { events: receiver }
+        {
+            let sender = self.sender.clone();
+            thread::spawn(move || loop {
+                if sender.send(Input::Tick).is_err() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(33));
+            });
+        }
+    }
+
+    /// Lets another thread add inputs to the queue.
+    pub fn sender(&self) -> Sender<Input> {
+        self.sender.clone()
     }
 
     pub fn next(&mut self) -> Result<Input> {
@@ -67,12 +114,15 @@ pub fn handle(key: Key, app: &mut App) -> Result<()> {
     match key {
         Key::Ctrl('w') => match app.focus {
             Focus::FileBrowser => {
+                app.focus = Focus::Params;
+            }
+            Focus::Params => {
                 app.focus = Focus::Editor;
             }
             Focus::Editor => {
                 app.focus = Focus::FileBrowser;
             }
-            Focus::CommandLine => {}
+            Focus::CommandLine | Focus::Keyboard => {}
         },
         Key::Char(':') => {
             app.focus = Focus::CommandLine;
@@ -88,6 +138,8 @@ pub fn handle(key: Key, app: &mut App) -> Result<()> {
     match app.focus {
         Focus::Editor => handle_editor_input(key, app)?,
         Focus::CommandLine => handle_command_input(key, app)?,
+        Focus::Params => handle_params_input(key, app)?,
+        Focus::Keyboard => handle_keyboard_input(key, app)?,
         Focus::FileBrowser => {
             let num_files = app.file_browser.num_entries();
             match key {
@@ -137,20 +189,489 @@ fn handle_command_input(key: Key, app: &mut App) -> Result<()> {
 }
 
 fn exec_command(app: &mut App) -> Result<()> {
-    let parts: Vec<&str> = app.command.buffer.split(" ").collect();
+    let action = parse_command(app, &app.command.buffer)?;
+    app.command.buffer.clear();
+    app.take(action)
+}
+
+/// Word `n` of a command, an error when the command is too short for it.
+fn arg<'a>(parts: &[&'a str], n: usize) -> Result<&'a str> {
+    parts
+        .get(n)
+        .copied()
+        .ok_or_else(|| anyhow!("{} expects more arguments", parts.first().unwrap_or(&"")))
+}
+
+/// Reads a command typed on the command line or run from a script.
+pub fn parse_command(app: &App, line: &str) -> Result<Action> {
+    let mut parts: Vec<&str> = line.split(" ").collect();
     if parts.len() == 0 {
         return Err(anyhow!("invalid command"));
     }
 
+    // Mixer commands apply to the selected track, or to a return channel when prefixed with
+    // `ret <bus>`, or to the master channel when prefixed with `master`.
+    let mut channel = app.selected_track;
+    if parts[0] == "ret" {
+        channel = return_channel(parse_bus(arg(&parts, 1)?)?);
+        parts.drain(..2);
+        if !matches!(
+            parts.first(),
+            Some(&"gain" | &"pan" | &"mute" | &"solo" | &"fx")
+        ) {
+            return Err(anyhow!("expected ret <bus> gain|pan|mute|solo|fx"));
+        }
+    } else if parts[0] == "master" {
+        channel = MASTER_CHANNEL;
+        parts.drain(..1);
+        if !matches!(
+            parts.first(),
+            Some(&"gain" | &"pan" | &"mute" | &"fx" | &"dc" | &"clip" | &"dim" | &"mono" | &"ref")
+        ) {
+            return Err(anyhow!(
+                "expected master gain|pan|mute|fx|dc|clip|dim|mono|ref"
+            ));
+        }
+    }
+
     let action = match parts[0] {
         "quit" | "exit" => Action::Exit,
-        "bpm" => Action::UpdateEngineParam(EngineParam::Bpm, parts[1].to_string()),
-        "oct" | "octave" => Action::UpdateEngineParam(EngineParam::Octave, parts[1].to_string()),
+        "bpm" => Action::UpdateEngineParam(EngineParam::Bpm, arg(&parts, 1)?.to_string()),
+        "oct" | "octave" => {
+            Action::UpdateEngineParam(EngineParam::Octave, arg(&parts, 1)?.to_string())
+        }
+        "undo" => Action::Undo,
+        "redo" => Action::Redo,
+        "mvtrack" => Action::MoveTrack(arg(&parts, 1)?.parse()?),
+        // Doubling and halving spread the steps over the new length
+        "len" | "length" if arg(&parts, 1)? == "double" => {
+            Action::Resize(app.editor.num_lines() * 2, LengthPolicy::Stretch)
+        }
+        "len" | "length" if arg(&parts, 1)? == "halve" => Action::Resize(
+            usize::max(1, app.editor.num_lines() / 2),
+            LengthPolicy::Stretch,
+        ),
+        "len" | "length" => {
+            let policy = match parts.get(2) {
+                Some(policy) => LengthPolicy::parse(policy)?,
+                None => LengthPolicy::default(),
+            };
+            Action::Resize(arg(&parts, 1)?.parse()?, policy)
+        }
+        "tracklen" => match arg(&parts, 1)? {
+            "off" => Action::SetTrackLength(None),
+            length => Action::SetTrackLength(Some(length.parse()?)),
+        },
+        "tr" | "transform" => {
+            let (transform, rest) = match arg(&parts, 1)? {
+                "transpose" => (
+                    Transform::Transpose(arg(&parts, 2)?.parse()?),
+                    &parts.get(3..).unwrap_or_default(),
+                ),
+                "rotate" => (
+                    Transform::Rotate(arg(&parts, 2)?.parse()?),
+                    &parts.get(3..).unwrap_or_default(),
+                ),
+                "reverse" => (Transform::Reverse, &parts.get(2..).unwrap_or_default()),
+                "insert" => (Transform::InsertLine, &parts.get(2..).unwrap_or_default()),
+                "delete" => (Transform::DeleteLine, &parts.get(2..).unwrap_or_default()),
+                "timing" => (
+                    Transform::RandomizeTiming(arg(&parts, 2)?.parse()?),
+                    &parts.get(3..).unwrap_or_default(),
+                ),
+                _ => {
+                    return Err(anyhow!(
+                        "expected transform transpose|rotate|reverse|insert|delete|timing"
+                    ))
+                }
+            };
+            let section = match rest {
+                [] => None,
+                [start, end] => Some(Section::parse(start, end)?),
+                _ => return Err(anyhow!("expected a start and an end line")),
+            };
+            Action::Transform(section, transform)
+        }
+        "euclid" => {
+            let number = |i: usize| parts.get(i).map_or(Ok(0), |n| n.parse());
+            Action::Euclid(Euclid::new(
+                arg(&parts, 1)?.parse()?,
+                arg(&parts, 2)?.parse()?,
+                number(3)?,
+                number(4)?,
+            )?)
+        }
+        "section" => {
+            let section = Section::parse(arg(&parts, 2)?, arg(&parts, 3)?)?;
+            match arg(&parts, 1)? {
+                "dup" => Action::Rearrange(SectionOp::Duplicate(section)),
+                "mv" => Action::Rearrange(SectionOp::Move(section, arg(&parts, 4)?.parse()?)),
+                "rm" => Action::Rearrange(SectionOp::Delete(section)),
+                _ => return Err(anyhow!("expected section dup|mv|rm")),
+            }
+        }
+        "w" | "save" => Action::SaveProject(parts.get(1).map(|p| Utf8PathBuf::from(*p))),
+        "e" | "load" => Action::LoadProject(Utf8PathBuf::from(arg(&parts, 1)?)),
+        "midi" => Action::ExportMidi(Utf8PathBuf::from(arg(&parts, 1)?)),
+        "fx" => {
+            let i = channel;
+            match arg(&parts, 1)? {
+                "add" => {
+                    let options =
+                        Options::parse(parts.get(3..).unwrap_or_default().iter().copied())?;
+                    Action::AddEffect(i, arg(&parts, 2)?.to_string(), options)
+                }
+                "rm" => Action::RemoveEffect(i, arg(&parts, 2)?.parse()?),
+                "mv" => Action::MoveEffect(i, arg(&parts, 2)?.parse()?, arg(&parts, 3)?.parse()?),
+                "bypass" => Action::ToggleBypass(i, arg(&parts, 2)?.parse()?),
+                "set" => Action::SetEffectParam(
+                    i,
+                    arg(&parts, 2)?.parse()?,
+                    arg(&parts, 3)?.to_string(),
+                    arg(&parts, 4)?.parse()?,
+                ),
+                cmd => return Err(anyhow!("invalid fx command {}", cmd)),
+            }
+        }
+        "log" => Action::ExportLog(Utf8PathBuf::from(arg(&parts, 1)?)),
+        "pretouch" => Action::Pretouch,
+        "key" => match arg(&parts, 1)? {
+            "none" => Action::SetKey(None),
+            key => Action::SetKey(Some(stretch::Key::parse(key)?)),
+        },
+        "scale" => match arg(&parts, 1)? {
+            "key" => Action::SetScale(None),
+            scale => Action::SetScale(Some(Scale::parse(scale)?)),
+        },
+        "snap" => Action::ToggleScaleSnap,
+        "chord" => match arg(&parts, 1)? {
+            "none" => Action::SetChord(None),
+            chord => Action::SetChord(Some(Chord::parse(chord)?)),
+        },
+        "nudge" => match arg(&parts, 1)?.parse::<i8>() {
+            Ok(offset) if offset.abs() <= MAX_OFFSET => Action::SetOffset(offset),
+            _ => {
+                return Err(anyhow!(
+                    "expected an offset between -{} and {} ticks",
+                    MAX_OFFSET,
+                    MAX_OFFSET
+                ))
+            }
+        },
+        "voicing" => {
+            let index = match arg(&parts, 1)?.parse::<usize>() {
+                Ok(n @ 1..=NUM_VOICINGS) => n - 1,
+                _ => {
+                    return Err(anyhow!(
+                        "expected voicing 1-{} <semitones>...",
+                        NUM_VOICINGS
+                    ))
+                }
+            };
+            let semitones = parts
+                .get(2..)
+                .unwrap_or_default()
+                .iter()
+                .map(|p| p.parse::<i8>())
+                .collect::<Result<Vec<_>, _>>()?;
+            Action::SetVoicing(index, semitones)
+        }
+        "stretch" => Action::ToggleStretchPreview,
+        "lanes" => Action::ToggleDrumLanes,
+        "perform" => Action::TogglePerformance,
+        "rec" => match (parts.get(1), app.record_arm) {
+            (Some(&"off"), _) | (None, Some(_)) => Action::RecordLive(None),
+            (Some(policy), _) => Action::RecordLive(Some(LoopPolicy::parse(policy)?)),
+            (None, None) => Action::RecordLive(Some(LoopPolicy::Wrap)),
+        },
+        "step" => match parts.get(1).map(|p| p.parse::<usize>()) {
+            Some(Ok(lines)) if lines <= MAX_EDIT_STEP => Action::SetEditStep(lines),
+            _ => return Err(anyhow!("expected step <0-{}>", MAX_EDIT_STEP)),
+        },
+        "osc" => match parts.get(1) {
+            Some(&"off") => Action::Osc(None),
+            Some(port) => Action::Osc(Some(port.parse()?)),
+            None => return Err(anyhow!("expected osc <port>|off")),
+        },
+        "quantize" => match parts.get(1).map(|p| p.parse::<f32>()) {
+            Some(Ok(percent)) if (0.0..=100.0).contains(&percent) => {
+                Action::SetQuantize(percent / 100.0)
+            }
+            _ => return Err(anyhow!("expected quantize <0-100>")),
+        },
+        "tuner" => match parts.get(1) {
+            Some(&"off") => Action::SetTuner(None),
+            Some(input) => match Source::parse(input)? {
+                Source::Input(input) => Action::SetTuner(Some(input)),
+                _ => return Err(anyhow!("expected tuner in<n>|off")),
+            },
+            None => Action::TuneSound(browser_selection(app)?),
+        },
+        "sample" => match parts.get(1) {
+            Some(&"stop") => Action::RecordSample(None),
+            Some(input) => match Source::parse(input)? {
+                Source::Input(input) => {
+                    let punch = match (parts.get(2), parts.get(3)) {
+                        (Some(start), Some(end)) => Some(Section::parse(start, end)?),
+                        _ => None,
+                    };
+                    Action::RecordSample(Some((input, punch)))
+                }
+                _ => return Err(anyhow!("expected sample in<n> [start end]|stop")),
+            },
+            None => return Err(anyhow!("expected sample in<n> [start end]|stop")),
+        },
+        "edit" => {
+            let edit = match arg(&parts, 1)? {
+                "trim" => SoundEdit::Trim(arg(&parts, 2)?.parse()?, arg(&parts, 3)?.parse()?),
+                "normalize" => SoundEdit::Normalize(parts.get(2).map_or(Ok(0.0), |p| p.parse())?),
+                "fade" => SoundEdit::Fade(arg(&parts, 2)?.parse()?, arg(&parts, 3)?.parse()?),
+                "dc" => SoundEdit::RemoveDc,
+                "reverse" => SoundEdit::Reverse,
+                _ => {
+                    return Err(anyhow!(
+                        "expected edit trim <start> <end>|normalize [db]|fade <in> <out>|dc|reverse"
+                    ))
+                }
+            };
+            Action::EditSound(edit)
+        }
+        "region" => {
+            // Regions are numbered from 1 like in the regions list
+            let index = |part: &str| -> Result<usize> {
+                match part.parse::<usize>()? {
+                    0 => Err(anyhow!("regions are numbered from 1")),
+                    n => Ok(n - 1),
+                }
+            };
+            let edit = match arg(&parts, 1)? {
+                "add" => {
+                    let keys: (u8, u8) = (arg(&parts, 3)?.parse()?, arg(&parts, 4)?.parse()?);
+                    let root = parts.get(5).map_or(Ok(keys.0), |p| p.parse())?;
+                    let mut region = Region::new(Utf8PathBuf::from(arg(&parts, 2)?), keys, root);
+                    if let (Some(low), Some(high)) = (parts.get(6), parts.get(7)) {
+                        region.velocities = (low.parse()?, high.parse()?);
+                    }
+                    RegionEdit::Add(region)
+                }
+                "move" => RegionEdit::Move(index(arg(&parts, 2)?)?, arg(&parts, 3)?.parse()?),
+                "resize" => RegionEdit::Resize(index(arg(&parts, 2)?)?, arg(&parts, 3)?.parse()?, arg(&parts, 4)?.parse()?),
+                "split" => RegionEdit::Split(index(arg(&parts, 2)?)?, arg(&parts, 3)?.parse()?),
+                "rm" => RegionEdit::Remove(index(arg(&parts, 2)?)?),
+                _ => {
+                    return Err(anyhow!(
+                        "expected region add <path> <low> <high> [root] [vlow vhigh]|move <n> <semitones>|resize <n> <low> <high>|split <n> <velocity>|rm <n>"
+                    ))
+                }
+            };
+            Action::EditRegions(edit)
+        }
+        "pat" | "pattern" => Action::SelectPattern(arg(&parts, 1)?.parse()?),
+        "hit" => match arg(&parts, 1)? {
+            "-" => Action::ToggleHit(None),
+            pad => Action::ToggleHit(Some(pad.parse()?)),
+        },
+        "find" => Action::FindSounds(Query::parse(&parts.get(1..).unwrap_or_default().join(" "))?),
+        "dupes" => Action::FindDuplicates,
+        "dedupe" => Action::ConsolidateDuplicates,
+        "tag" | "untag" => {
+            let path = browser_selection(app)?;
+            let tags = parts
+                .get(1..)
+                .unwrap_or_default()
+                .iter()
+                .map(|tag| library::normalize_tag(tag))
+                .collect::<Result<Vec<_>>>()?;
+            match parts[0] {
+                "tag" => Action::Tag(path, tags),
+                _ => Action::Untag(path, tags),
+            }
+        }
+        "rate" => {
+            let rating = arg(&parts, 1)?.parse()?;
+            if rating > library::MAX_RATING {
+                return Err(anyhow!(
+                    "expected a rating from 0 to {}",
+                    library::MAX_RATING
+                ));
+            }
+            Action::Rate(browser_selection(app)?, rating)
+        }
+        "label" => {
+            let label = match arg(&parts, 1)? {
+                "none" => None,
+                label => Some(Label::parse(label)?),
+            };
+            Action::SetLabel(browser_selection(app)?, label)
+        }
+        "lfo" => {
+            let lfo = parse_lfo(arg(&parts, 1)?)?;
+            let shape = Shape::parse(arg(&parts, 2)?)?;
+            let rate = Rate::parse(arg(&parts, 3)?)?;
+            Action::SetLfo(app.selected_track, lfo, shape, rate)
+        }
+        "mod" => match arg(&parts, 1)? {
+            "block" => Action::SetModulationRate(app.selected_track, false),
+            "sample" => Action::SetModulationRate(app.selected_track, true),
+            lfo => {
+                let depth = match arg(&parts, 3)? {
+                    "off" => None,
+                    depth => Some(depth.parse()?),
+                };
+                Action::SetRoute(
+                    app.selected_track,
+                    parse_lfo(lfo)?,
+                    arg(&parts, 2)?.to_string(),
+                    depth,
+                )
+            }
+        },
+        "trim" => Action::SetTrim(app.selected_track, arg(&parts, 1)?.parse()?),
+        "gain" => Action::SetGain(channel, arg(&parts, 1)?.parse()?),
+        "pan" => Action::SetPan(channel, arg(&parts, 1)?.parse()?),
+        "mute" => Action::ToggleMute(channel),
+        "source" => Action::SetSource(app.selected_track, Source::parse(arg(&parts, 1)?)?),
+        "dc" if channel == MASTER_CHANNEL => Action::ToggleDcBlock,
+        "clip" if channel == MASTER_CHANNEL => Action::ToggleClip,
+        "dim" if channel == MASTER_CHANNEL => Action::ToggleDim,
+        "mono" if channel == MASTER_CHANNEL => Action::ToggleMono,
+        "ref" if channel == MASTER_CHANNEL => match parts.get(1) {
+            None => Action::ToggleReference,
+            Some(&"none") => Action::LoadReference(None),
+            Some(path) => Action::LoadReference(Some(Utf8PathBuf::from(*path))),
+        },
+        "solo" => Action::ToggleSolo(channel),
+        "send" => {
+            let level = match arg(&parts, 2)? {
+                "off" => MIN_GAIN,
+                level => level.parse()?,
+            };
+            let pre_fader = match parts.get(3) {
+                Some(&"pre") => true,
+                Some(&"post") | None => false,
+                Some(mode) => return Err(anyhow!("invalid send mode {}, expected pre|post", mode)),
+            };
+            Action::SetSend(channel, parse_bus(arg(&parts, 1)?)?, level, pre_fader)
+        }
+        "capture" => Action::Capture(parts.get(1).map(|p| Utf8PathBuf::from(*p))),
+        "hits" => {
+            let threshold = match parts.get(2) {
+                Some(threshold) => threshold.parse()?,
+                None => DEFAULT_THRESHOLD,
+            };
+            let sound = match parts.get(3) {
+                Some(sound) => sound.parse()?,
+                None => app.selected_track as u8,
+            };
+            Action::DetectHits(Utf8PathBuf::from(arg(&parts, 1)?), threshold, sound)
+        }
+        "bounce" | "stems" => {
+            let mut settings = BounceSettings {
+                stems: parts[0] == "stems",
+                ..BounceSettings::default()
+            };
+            if let Some(bars) = parts.get(2) {
+                settings.bars = Some(bars.parse()?);
+            }
+            if let Some(bits) = parts.get(3) {
+                settings.bit_depth = bits.parse()?;
+            }
+            if let Some(rate) = parts.get(4) {
+                settings.sample_rate = rate.parse()?;
+            }
+            if let Some(bpm) = parts.get(5) {
+                settings.bpm = Some(bpm.parse()?);
+            }
+            Action::Bounce(Utf8PathBuf::from(arg(&parts, 1)?), settings)
+        }
+        "kit" => {
+            let format = KitFormat::parse(parts.get(2).unwrap_or(&"sfz"))?;
+            Action::ExportKit(Utf8PathBuf::from(arg(&parts, 1)?), format)
+        }
+        "memory" | "retrigger" | "modenv" | "root" | "resample" => {
+            let is_sampler = app.instruments[app.selected_track]
+                .as_ref()
+                .is_some_and(|settings| settings.kind == "sampler");
+            if !is_sampler {
+                return Err(anyhow!("{} only applies to samplers", parts[0]));
+            }
+            match parts[0] {
+                "memory" => MemoryPolicy::parse(arg(&parts, 1)?).map(|_| ())?,
+                "modenv" => ModDestination::parse_list(arg(&parts, 1)?).map(|_| ())?,
+                "root"
+                    if arg(&parts, 1)? != "auto"
+                        && !matches!(arg(&parts, 1)?.parse(), Ok(0..=127u8)) =>
+                {
+                    return Err(anyhow!(
+                        "invalid root {}, expected auto or 0-127",
+                        arg(&parts, 1)?
+                    ));
+                }
+                "retrigger" => Retrigger::parse(arg(&parts, 1)?).map(|_| ())?,
+                "resample" => RateConversion::parse(arg(&parts, 1)?).map(|_| ())?,
+                _ => (),
+            }
+            Action::SetInstrumentOption(
+                app.selected_track,
+                parts[0].to_string(),
+                arg(&parts, 1)?.to_string(),
+            )
+        }
+        "inst" | "instrument" => match arg(&parts, 1)? {
+            "none" => Action::RemoveInstrument(app.selected_track),
+            "types" => Action::ListInstruments(parts.get(2).map(|kind| kind.to_string())),
+            kind => {
+                let options = Options::parse(parts.get(2..).unwrap_or_default().iter().copied())?;
+                Action::CreateInstrument(app.selected_track, kind.to_string(), options)
+            }
+        },
+        "play" => Action::Play,
+        "pause" => Action::Pause,
+        "stop" => Action::Stop,
+        "seek" => match parts.get(1) {
+            Some(position) => Action::Seek(BarsBeats::parse(position)?),
+            None => return Err(anyhow!("expected seek <bar>[:<beat>[.<line>]]")),
+        },
+        "loop" => match parts.get(1..) {
+            Some(["off"]) => Action::SetLoop(None),
+            Some([start, end]) => {
+                let bar = |bar: &str| match bar.parse::<u64>() {
+                    Ok(bar) if bar > 0 => Ok(bar),
+                    _ => Err(anyhow!("invalid bar {}, bars count from 1", bar)),
+                };
+                let (start, end) = (bar(start)?, bar(end)?);
+                if end < start {
+                    return Err(anyhow!("the loop ends before bar {}", start));
+                }
+                Action::SetLoop(Some(start - 1..end))
+            }
+            _ => return Err(anyhow!("expected loop <start bar> <end bar>|off")),
+        },
+        "run" => match parts.get(1) {
+            Some(path) => Action::RunScript(Utf8PathBuf::from(path)),
+            None => return Err(anyhow!("expected run <path>")),
+        },
+        "on" => match parts.get(1..) {
+            Some([hook, "off"]) => Action::ClearHook(Hook::parse(hook)?),
+            Some([hook, command @ ..]) if !command.is_empty() => {
+                Action::AddHook(Hook::parse(hook)?, command.join(" "))
+            }
+            _ => return Err(anyhow!("expected on play|stop|loop <command>|off")),
+        },
+        "param" => match parts.get(1..) {
+            Some([name]) => Action::DescribeParam(app.selected_track, name.to_string()),
+            Some([name, value]) => {
+                Action::SetParam(app.selected_track, name.to_string(), value.parse()?)
+            }
+            _ => return Err(anyhow!("expected param <name> [value]")),
+        },
+        "track" => match parts.get(1).map(|track| track.parse()) {
+            Some(Ok(track)) if track < MAX_TRACKS => Action::SelectTrack(track),
+            _ => return Err(anyhow!("expected track <0-{}>", MAX_TRACKS - 1)),
+        },
         _ => return Err(anyhow!("invalid command {}", parts[0])),
     };
-
-    app.command.buffer.clear();
-    app.take(action)
+    Ok(action)
 }
 
 fn handle_editor_input(key: Key, app: &mut App) -> Result<()> {
@@ -162,12 +683,19 @@ fn handle_editor_input(key: Key, app: &mut App) -> Result<()> {
         Key::Ctrl('b') | Key::Left => app.take(Action::MoveCursor(Move::Left))?,
         Key::Ctrl('a') => app.take(Action::MoveCursor(Move::Start))?,
         Key::Ctrl('e') => app.take(Action::MoveCursor(Move::End))?,
+        Key::Ctrl('z') => app.take(Action::Undo)?,
+        Key::Ctrl('y') => app.take(Action::Redo)?,
         Key::Backspace => delete_note(app)?,
+        Key::Insert => shift_lines(app, Transform::InsertLine)?,
+        Key::Delete => shift_lines(app, Transform::DeleteLine)?,
         Key::Char('\n') => app.take(Action::MoveCursor(Move::Down))?,
         Key::Char(']') => app.take(Action::ChangeValue(-1))?,
         Key::Char('[') => app.take(Action::ChangeValue(1))?,
         Key::Char('}') => app.take(Action::ChangeValue(-12))?,
         Key::Char('{') => app.take(Action::ChangeValue(12))?,
+        Key::Char('<') => shift_octave(app, -1)?,
+        Key::Char('>') => shift_octave(app, 1)?,
+        Key::Ctrl('k') => app.focus = Focus::Keyboard,
         Key::Char(key) => match app.editor.cursor.column % NUM_TRACK_LANES {
             0 => insert_note(app, key)?,
             1 => insert_number(app, key)?,
@@ -178,26 +706,114 @@ fn handle_editor_input(key: Key, app: &mut App) -> Result<()> {
     Ok(())
 }
 
+/// Moves through the params of the selected instrument and changes them a step at a time.
+fn handle_params_input(key: Key, app: &mut App) -> Result<()> {
+    let num_params = app.instruments[app.selected_track]
+        .as_ref()
+        .map_or(0, |settings| settings.params.len());
+    if num_params == 0 {
+        return Ok(());
+    }
+    match key {
+        Key::Down | Key::Ctrl('n') => app.params.next(num_params),
+        Key::Up | Key::Ctrl('p') => app.params.prev(num_params),
+        Key::Right | Key::Ctrl('f') | Key::Char('[') => {
+            if let Some(index) = app.params.selected() {
+                app.take(Action::IncrParam(index))?;
+            }
+        }
+        Key::Left | Key::Ctrl('b') | Key::Char(']') => {
+            if let Some(index) = app.params.selected() {
+                app.take(Action::DecrParam(index))?;
+            }
+        }
+        Key::Char(' ') => app.take(Action::TogglePlay)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Plays the selected instrument, see `Keyboard`. Shift accents a note.
+fn handle_keyboard_input(key: Key, app: &mut App) -> Result<()> {
+    match key {
+        Key::Esc | Key::Ctrl('k') => {
+            app.take(Action::ReleaseKeys)?;
+            app.focus = Focus::Editor;
+        }
+        Key::Char(' ') => app.take(Action::TogglePlay)?,
+        Key::Char('<') => shift_octave(app, -1)?,
+        Key::Char('>') => shift_octave(app, 1)?,
+        Key::Char('[') => app.keyboard.raise_velocity(),
+        Key::Char(']') => app.keyboard.lower_velocity(),
+        Key::Char(key) => app.take(Action::PlayKey(key))?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Changes the octave of the notes entered, within the range of MIDI notes.
+fn shift_octave(app: &mut App, delta: i32) -> Result<()> {
+    let octave = app.engine_params.get(EngineParam::Octave) as i32 + delta;
+    if !(0..=MAX_OCTAVE).contains(&octave) {
+        return Ok(());
+    }
+    app.take(Action::UpdateEngineParam(
+        EngineParam::Octave,
+        octave.to_string(),
+    ))
+}
+
+/// Enters the note of a key, `1` for a note off and `!` for a note cut.
 fn insert_note(app: &mut App, key: char) -> Result<()> {
-    let pitch = match key {
-        'z' => 0,
-        's' => 1,
-        'x' => 2,
-        'd' => 3,
-        'c' => 4,
-        'v' => 5,
-        'g' => 6,
-        'b' => 7,
-        'h' => 8,
-        'n' => 9,
-        'j' => 10,
-        'm' => 11,
-        _ => return Ok(()),
-    };
-    app.take(Action::InsertNote(pitch as u8))?;
+    match key {
+        '1' => app.take(Action::InsertNoteOff)?,
+        '!' => app.take(Action::InsertNoteCut)?,
+        key => {
+            if let Some(semitone) = keyboard::semitone(key) {
+                app.take(Action::InsertNote(semitone))?;
+            }
+        }
+    }
     Ok(())
 }
 
+/// Inserts or deletes a line of the selected track at the cursor, moving the lines below it.
+fn shift_lines(app: &mut App, transform: Transform) -> Result<()> {
+    let section = Section {
+        start: app.editor.cursor.line,
+        end: app.editor.num_lines(),
+    };
+    app.take(Action::Transform(Some(section), transform))
+}
+
+/// Parses an LFO counting from 1.
+fn parse_lfo(name: &str) -> Result<usize> {
+    match name.parse() {
+        Ok(lfo @ 1..=lfo::NUM_LFOS) => Ok(lfo - 1),
+        _ => Err(anyhow!(
+            "invalid LFO {}, expected 1-{}",
+            name,
+            lfo::NUM_LFOS
+        )),
+    }
+}
+
+/// Parses a bus by its letter.
+fn parse_bus(name: &str) -> Result<usize> {
+    (0..NUM_BUSES)
+        .find(|bus| name.eq_ignore_ascii_case(&bus_name(*bus).to_string()))
+        .ok_or_else(|| anyhow!("unknown bus {}", name))
+}
+
+/// The sound under the cursor of the file browser.
+fn browser_selection(app: &App) -> Result<Utf8PathBuf> {
+    app.files
+        .selected()
+        .and_then(|index| app.file_browser.get(index))
+        .filter(|path| !path.is_dir())
+        .ok_or_else(|| anyhow!("no sound selected in the browser"))
+}
+
 fn insert_number(app: &mut App, key: char) -> Result<()> {
     if let Some(num) = key.to_digit(10) {
         app.take(Action::InsertNumber(num as i32))?;
@@ -207,6 +823,167 @@ fn insert_number(app: &mut App, key: char) -> Result<()> {
 
 fn delete_note(app: &mut App) -> Result<()> {
     let result = app.take(Action::DeleteNote);
-    app.take(Action::MoveCursor(Move::Down))?;
+    app.take(Action::Advance)?;
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::AppCommand;
+    use crate::engine::{EngineCommand, EngineParams};
+    use crate::paths::Paths;
+    use crate::script;
+    use ringbuf::RingBuffer;
+    use std::fs;
+
+    /// Commands cut short, each missing an argument it needs.
+    const TRUNCATED: &[&str] = &[
+        "ret",
+        "bpm",
+        "oct",
+        "mvtrack",
+        "len",
+        "tracklen",
+        "tr",
+        "tr transpose",
+        "tr rotate",
+        "tr timing",
+        "euclid",
+        "euclid 3",
+        "section",
+        "section dup",
+        "section dup 1",
+        "section mv 0 3",
+        "load",
+        "midi",
+        "fx",
+        "fx add",
+        "fx rm",
+        "fx mv 1",
+        "fx bypass",
+        "fx set 0 cutoff",
+        "log",
+        "key",
+        "scale",
+        "chord",
+        "nudge",
+        "voicing",
+        "edit",
+        "edit trim 1",
+        "edit fade 1",
+        "region",
+        "region add kick.wav 36",
+        "region move 1",
+        "region resize 1 36",
+        "region split",
+        "region rm",
+        "pat",
+        "hit",
+        "rate",
+        "label",
+        "lfo",
+        "lfo 1",
+        "lfo 1 sine",
+        "mod",
+        "mod 1",
+        "mod 1 cutoff",
+        "trim",
+        "gain",
+        "pan",
+        "source",
+        "send",
+        "send a",
+        "hits",
+        "bounce",
+        "stems",
+        "kit",
+        "memory",
+        "root",
+        "inst",
+        "seek",
+        "loop 1",
+        "run",
+        "on play",
+        "param",
+        "track",
+    ];
+
+    fn app(dir: &Utf8PathBuf) -> App {
+        let (_, app_recv) = RingBuffer::<AppCommand>::new(16).split();
+        let (engine_send, _) = RingBuffer::<EngineCommand>::new(16).split();
+        let paths = Paths {
+            projects: dir.join("projects"),
+            sounds: dir.join("sounds"),
+        };
+        paths.create().unwrap();
+        App::new(EngineParams::default(), app_recv, engine_send, paths).unwrap()
+    }
+
+    #[test]
+    fn truncated_commands_are_errors() {
+        let dir = Utf8PathBuf::from_path_buf(std::env::temp_dir())
+            .unwrap()
+            .join(format!("ruis-input-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut app = app(&dir);
+        let path = dir.join("truncated.txt");
+        fs::write(&path, TRUNCATED.join("\n")).unwrap();
+
+        let commands = script::load(&path).unwrap();
+        assert_eq!(commands.len(), TRUNCATED.len());
+        for (line, command) in &commands {
+            let result = parse_command(&app, command);
+            assert!(result.is_err(), "line {}: {} parsed", line, command);
+        }
+        assert!(app.take(Action::RunScript(path)).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn halving_keeps_the_last_line() {
+        let dir = Utf8PathBuf::from_path_buf(std::env::temp_dir())
+            .unwrap()
+            .join(format!("ruis-input-{}-halve", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut app = app(&dir);
+        let num_lines = app.editor.num_lines();
+        app.editor.write_note(0, num_lines - 1, 50, 0);
+
+        let action = parse_command(&app, "len halve").unwrap();
+        app.take(action).unwrap();
+        let pattern = app.editor.current_pattern();
+        assert_eq!(pattern.num_lines, num_lines / 2);
+        assert_eq!(pattern.step(0, pattern.num_lines - 1).pitch, Some(50));
+        app.take(Action::Undo).unwrap();
+        let pattern = app.editor.current_pattern();
+        assert_eq!(pattern.step(0, num_lines - 1).pitch, Some(50));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn randomized_timing_is_undone_and_redone() {
+        let dir = Utf8PathBuf::from_path_buf(std::env::temp_dir())
+            .unwrap()
+            .join(format!("ruis-input-{}-timing", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut app = app(&dir);
+        for line in 0..4 {
+            app.editor.write_note(0, line, 48, 0);
+        }
+        let offsets = |app: &App| -> Vec<i8> {
+            let pattern = app.editor.current_pattern();
+            (0..4).map(|line| pattern.step(0, line).offset).collect()
+        };
+
+        let action = parse_command(&app, "tr timing 6 0 4").unwrap();
+        app.take(action).unwrap();
+        let randomized = offsets(&app);
+        assert_ne!(randomized, [0; 4]);
+        app.take(Action::Undo).unwrap();
+        assert_eq!(offsets(&app), [0; 4]);
+        app.take(Action::Redo).unwrap();
+        assert_eq!(offsets(&app), randomized);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
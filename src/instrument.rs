@@ -0,0 +1,337 @@
+use crate::arp;
+use crate::clap::ClapFactory;
+use crate::drums::{DrumReplacer, DEFAULT_THRESHOLD};
+use crate::drumsynth::DrumSynth;
+use crate::engine::{Device, EngineConfig};
+use crate::filter::FilterMode;
+use crate::fm::Fm;
+use crate::keymap::Keymap;
+use crate::midi::MidiOut;
+use crate::mono::Priority;
+use crate::param::{Param, ParamDescriptor};
+use crate::sampler::{
+    MemoryPolicy, ModDestination, RateConversion, Retrigger, Sampler, Sound, ROOT_PITCH,
+};
+use crate::synth::{Oscillators, Synth};
+use crate::tuner;
+use crate::wavetable::Wavetable;
+use anyhow::{anyhow, Result};
+use camino::Utf8PathBuf;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// A sound source which can be played from the pattern. The column is the track which
+/// triggered the note, so an instrument shared by several tracks can tell them apart.
+pub trait Instrument: Device + Send {
+    fn note_on(&mut self, column: usize, pitch: u8, velocity: u8);
+    fn note_off(&mut self, column: usize);
+
+    /// Silences the note of a column at once rather than releasing it. Instruments which
+    /// can't release it any faster than `note_off` don't need to implement it.
+    fn note_cut(&mut self, column: usize) {
+        self.note_off(column);
+    }
+
+    /// Called before the instrument is first rendered and whenever the audio settings change.
+    fn prepare(&mut self, _config: &EngineConfig) {}
+
+    /// Called when playback stops.
+    fn stop(&mut self) {}
+
+    /// Silences the instrument at once and clears its state, e.g. voices, envelopes, filters
+    /// and delay lines, so nothing played before is heard afterwards. Called when playback
+    /// stops or moves and before offline renders.
+    fn reset(&mut self) {
+        self.stop();
+    }
+
+    /// Selects between the low latency live path and a more expensive one for offline renders.
+    fn set_quality(&mut self, _quality: Quality) {}
+
+    /// Called before every buffer with the song tempo, for tempo synced modulation.
+    fn set_tempo(&mut self, _bpm: f32) {}
+
+    /// Number of voices sounding, for the status bar. Instruments without voices count the
+    /// notes they play.
+    fn active_voices(&self) -> usize {
+        0
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        Vec::new()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Quality {
+    Realtime,
+    /// Used when bouncing, where rendering doesn't have to keep up with the audio device.
+    Offline,
+}
+
+/// Creates instruments of a single type. Implement this to add new instrument types to the
+/// `Registry`.
+pub trait InstrumentFactory {
+    fn name(&self) -> &'static str;
+    fn create(&self, options: &Options) -> Result<Box<dyn Instrument>>;
+
+    /// Instruments which only talk to the outside world are skipped when bouncing.
+    fn renders_audio(&self) -> bool {
+        true
+    }
+
+    /// The params of a new instrument of this type, for listing them before there's one. By
+    /// default they're taken from an instrument created without options, types which can't
+    /// be created that way override this or have none listed.
+    fn params(&self) -> Vec<ParamDescriptor> {
+        match self.create(&Options::default()) {
+            Ok(instrument) => describe(&instrument.params()),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+fn describe(params: &[(String, Param)]) -> Vec<ParamDescriptor> {
+    params
+        .iter()
+        .map(|(name, param)| param.describe(name))
+        .collect()
+}
+
+/// Options used to create an instrument, e.g. the path of the sample to load.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Options(BTreeMap<String, String>);
+
+impl Options {
+    /// Parses `key=value` pairs.
+    pub fn parse<'a, I: IntoIterator<Item = &'a str>>(parts: I) -> Result<Self> {
+        let mut options = Self::default();
+        for part in parts {
+            let mut kv = part.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) => options.set(key, value),
+                _ => return Err(anyhow!("invalid option {}, expected key=value", part)),
+            }
+        }
+        Ok(options)
+    }
+
+    pub fn set<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        self.0.insert(key.into(), value.into());
+    }
+
+    pub fn get(&self, key: &str) -> Result<&str> {
+        self.0
+            .get(key)
+            .map(|v| v.as_str())
+            .ok_or_else(|| anyhow!("missing option {}", key))
+    }
+
+    pub fn get_or<'a>(&'a self, key: &str, default: &'a str) -> &'a str {
+        self.0.get(key).map_or(default, |v| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.0.iter()
+    }
+}
+
+pub struct Registry {
+    factories: Vec<Box<dyn InstrumentFactory>>,
+}
+
+impl Registry {
+    /// Registers the built-in instruments, `sample_rate` is the rate the engine runs at.
+    pub fn new(sample_rate: Arc<AtomicU32>) -> Self {
+        let mut registry = Self {
+            factories: Vec::new(),
+        };
+        registry.register(Box::new(SamplerFactory {
+            sample_rate: Arc::clone(&sample_rate),
+        }));
+        registry.register(Box::new(SynthFactory));
+        registry.register(Box::new(FmFactory));
+        registry.register(Box::new(DrumSynthFactory));
+        registry.register(Box::new(MidiOutFactory));
+        registry.register(Box::new(DrumReplacerFactory));
+        registry.register(Box::new(ClapFactory { sample_rate }));
+        registry
+    }
+
+    /// Adds a factory, replacing any factory registered under the same name.
+    pub fn register(&mut self, factory: Box<dyn InstrumentFactory>) {
+        self.factories.retain(|f| f.name() != factory.name());
+        self.factories.push(factory);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn InstrumentFactory> {
+        self.factories
+            .iter()
+            .find(|f| f.name() == name)
+            .map(|f| f.as_ref())
+    }
+
+    pub fn create(&self, name: &str, options: &Options) -> Result<Box<dyn Instrument>> {
+        match self.get(name) {
+            Some(factory) => arp::wrap(factory.create(options)?, options),
+            None => Err(anyhow!("unknown instrument type {}", name)),
+        }
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.factories.iter().map(|f| f.name())
+    }
+}
+
+pub struct SamplerFactory {
+    sample_rate: Arc<AtomicU32>,
+}
+
+impl InstrumentFactory for SamplerFactory {
+    fn name(&self) -> &'static str {
+        "sampler"
+    }
+
+    fn create(&self, options: &Options) -> Result<Box<dyn Instrument>> {
+        let path = Utf8PathBuf::from(options.get("path")?);
+        let policy = match options.get("memory") {
+            Ok(policy) => MemoryPolicy::parse(policy)?,
+            Err(_) => MemoryPolicy::default(),
+        };
+        let retrigger = Retrigger::parse(options.get_or("retrigger", "reset"))?;
+        let conversion = RateConversion::parse(options.get_or("resample", "voice"))?;
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed);
+        let load = |path: &Utf8PathBuf| -> Result<Sound> {
+            let sound = Sampler::load_sound_with(path, policy)?;
+            if conversion == RateConversion::Load && sound.sample_rate() != sample_rate {
+                return Ok(sound.resample(sample_rate));
+            }
+            Ok(sound)
+        };
+        let sound = load(&path)?;
+        // The root is either detected, given as a note number, or the default one, which
+        // suits drums and other sounds without a clear pitch.
+        let root = match options.get("root") {
+            Ok("auto") => tuner::detect_sound(&sound).map_or(ROOT_PITCH as f32, |reading| {
+                reading.note as f32 + reading.cents / 100.0
+            }),
+            Ok(note) => note
+                .parse::<u8>()
+                .ok()
+                .filter(|note| *note < 128)
+                .ok_or_else(|| anyhow!("invalid root {}, expected auto or 0-127", note))?
+                as f32,
+            Err(_) => ROOT_PITCH as f32,
+        };
+        let mut sampler = Sampler::with_sound(Arc::new(sound))
+            .with_retrigger(retrigger)
+            .with_root(root);
+        for region in Keymap::parse(options.get_or("regions", ""))?.regions() {
+            let sound = load(&region.path)?;
+            sampler = sampler.with_region(region.clone(), Arc::new(sound));
+        }
+        if let Ok(mode) = options.get("filter") {
+            sampler = sampler.with_filter(FilterMode::parse(mode)?);
+        }
+        for destination in ModDestination::parse_list(options.get_or("modenv", ""))? {
+            sampler = sampler.with_mod_envelope(destination)?;
+        }
+        if let Ok(priority) = options.get("mono") {
+            sampler = sampler.with_mono(Priority::parse(priority)?);
+        }
+        Ok(Box::new(sampler))
+    }
+
+    /// The params of a sampler without a sound, options such as `filter` and `modenv` add
+    /// more.
+    fn params(&self) -> Vec<ParamDescriptor> {
+        describe(&Sampler::new().params())
+    }
+}
+
+pub struct SynthFactory;
+
+impl InstrumentFactory for SynthFactory {
+    fn name(&self) -> &'static str {
+        "synth"
+    }
+
+    fn create(&self, options: &Options) -> Result<Box<dyn Instrument>> {
+        let mode = FilterMode::parse(options.get_or("filter", "lowpass"))?;
+        let oscillators = Oscillators::parse(options.get_or("oscillators", "polyblep"))?;
+        let mut synth = Synth::new(mode, oscillators);
+        if let Ok(path) = options.get("wavetable") {
+            let wavetable = Wavetable::load(&Utf8PathBuf::from(path))?;
+            synth = synth.with_wavetable(Arc::new(wavetable));
+        }
+        if let Ok(priority) = options.get("mono") {
+            synth = synth.with_mono(Priority::parse(priority)?);
+        }
+        Ok(Box::new(synth))
+    }
+}
+
+pub struct FmFactory;
+
+impl InstrumentFactory for FmFactory {
+    fn name(&self) -> &'static str {
+        "fm"
+    }
+
+    fn create(&self, _options: &Options) -> Result<Box<dyn Instrument>> {
+        Ok(Box::new(Fm::new()))
+    }
+}
+
+pub struct DrumSynthFactory;
+
+impl InstrumentFactory for DrumSynthFactory {
+    fn name(&self) -> &'static str {
+        "drumsynth"
+    }
+
+    fn create(&self, _options: &Options) -> Result<Box<dyn Instrument>> {
+        Ok(Box::new(DrumSynth::new()))
+    }
+}
+
+pub struct MidiOutFactory;
+
+impl InstrumentFactory for MidiOutFactory {
+    fn name(&self) -> &'static str {
+        "midi-out"
+    }
+
+    fn create(&self, options: &Options) -> Result<Box<dyn Instrument>> {
+        let port = Utf8PathBuf::from(options.get("port")?);
+        let channel: u8 = options.get_or("channel", "1").parse()?;
+        if channel == 0 {
+            return Err(anyhow!("MIDI channel must be between 1 and 16"));
+        }
+        Ok(Box::new(MidiOut::open(&port, channel - 1)?))
+    }
+
+    fn renders_audio(&self) -> bool {
+        false
+    }
+}
+
+pub struct DrumReplacerFactory;
+
+impl InstrumentFactory for DrumReplacerFactory {
+    fn name(&self) -> &'static str {
+        "drums"
+    }
+
+    fn create(&self, options: &Options) -> Result<Box<dyn Instrument>> {
+        let clip = Sampler::load_sound(&Utf8PathBuf::from(options.get("clip")?))?;
+        let pad = Sampler::load_sound(&Utf8PathBuf::from(options.get("pad")?))?;
+        let threshold = match options.get("threshold") {
+            Ok(threshold) => threshold.parse()?,
+            Err(_) => DEFAULT_THRESHOLD,
+        };
+        let replacer = DrumReplacer::new(&clip, Arc::new(pad)).with_threshold(threshold);
+        Ok(Box::new(replacer))
+    }
+}
@@ -0,0 +1,427 @@
+use anyhow::{anyhow, Result};
+use std::fmt::Write;
+
+/// A minimal JSON document model, just enough for project files.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// Like `get` but fails when the key is missing.
+    pub fn field(&self, key: &str) -> Result<&Value> {
+        self.get(key)
+            .ok_or_else(|| anyhow!("missing field {}", key))
+    }
+
+    pub fn as_f64(&self) -> Result<f64> {
+        match self {
+            Value::Number(n) => Ok(*n),
+            _ => Err(anyhow!("expected a number")),
+        }
+    }
+
+    pub fn as_usize(&self) -> Result<usize> {
+        let n = self.as_f64()?;
+        if n < 0.0 || n.fract() != 0.0 {
+            return Err(anyhow!("expected a positive integer, got {}", n));
+        }
+        Ok(n as usize)
+    }
+
+    pub fn as_bool(&self) -> Result<bool> {
+        match self {
+            Value::Bool(b) => Ok(*b),
+            _ => Err(anyhow!("expected a boolean")),
+        }
+    }
+
+    pub fn as_str(&self) -> Result<&str> {
+        match self {
+            Value::String(s) => Ok(s),
+            _ => Err(anyhow!("expected a string")),
+        }
+    }
+
+    pub fn as_array(&self) -> Result<&[Value]> {
+        match self {
+            Value::Array(items) => Ok(items),
+            _ => Err(anyhow!("expected an array")),
+        }
+    }
+
+    pub fn as_object(&self) -> Result<&[(String, Value)]> {
+        match self {
+            Value::Object(fields) => Ok(fields),
+            _ => Err(anyhow!("expected an object")),
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == Value::Null
+    }
+
+    /// Fails on NaN and infinities, which JSON has no way to write, naming the field they're in.
+    pub fn to_pretty_string(&self) -> Result<String> {
+        let mut out = String::new();
+        self.write(&mut out, 0)?;
+        out.push('\n');
+        Ok(out)
+    }
+
+    fn write(&self, out: &mut String, indent: usize) -> Result<()> {
+        match self {
+            Value::Null => out.push_str("null"),
+            Value::Bool(b) => write!(out, "{}", b)?,
+            Value::Number(n) if !n.is_finite() => return Err(anyhow!("{} can't be saved", n)),
+            Value::Number(n) => write!(out, "{}", n)?,
+            Value::String(s) => write_string(out, s),
+            Value::Array(items) if items.is_empty() => out.push_str("[]"),
+            Value::Array(items) => {
+                // Arrays of plain values are kept on one line to keep pattern data compact.
+                let flat = items
+                    .iter()
+                    .all(|v| !matches!(v, Value::Array(_) | Value::Object(_)));
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    if flat {
+                        if i > 0 {
+                            out.push(' ');
+                        }
+                    } else {
+                        newline(out, indent + 1);
+                    }
+                    item.write(out, indent + 1)
+                        .map_err(|err| anyhow!("{}: {}", i, err))?;
+                }
+                if !flat {
+                    newline(out, indent);
+                }
+                out.push(']');
+            }
+            Value::Object(fields) if fields.is_empty() => out.push_str("{}"),
+            Value::Object(fields) => {
+                out.push('{');
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    newline(out, indent + 1);
+                    write_string(out, key);
+                    out.push_str(": ");
+                    value
+                        .write(out, indent + 1)
+                        .map_err(|err| anyhow!("{}: {}", key, err))?;
+                }
+                newline(out, indent);
+                out.push('}');
+            }
+        }
+        Ok(())
+    }
+
+    pub fn parse(input: &str) -> Result<Value> {
+        let mut parser = Parser {
+            chars: input.chars().collect(),
+            pos: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos < parser.chars.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Number(n)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(n: usize) -> Self {
+        Value::Number(n as f64)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+fn newline(out: &mut String, indent: usize) {
+    out.push('\n');
+    for _ in 0..indent {
+        out.push_str("  ");
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error(&self, msg: &str) -> anyhow::Error {
+        anyhow!("invalid JSON at offset {}: {}", self.pos, msg)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", c)))
+        }
+    }
+
+    fn keyword(&mut self, word: &str, value: Value) -> Result<Value> {
+        for c in word.chars() {
+            if self.chars.get(self.pos) != Some(&c) {
+                return Err(self.error("unexpected character"));
+            }
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        match self.peek() {
+            Some('n') => self.keyword("null", Value::Null),
+            Some('t') => self.keyword("true", Value::Bool(true)),
+            Some('f') => self.keyword("false", Value::Bool(false)),
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some('[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if self.peek() == Some(']') {
+                    self.pos += 1;
+                    return Ok(Value::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    match self.peek() {
+                        Some(',') => self.pos += 1,
+                        Some(']') => {
+                            self.pos += 1;
+                            return Ok(Value::Array(items));
+                        }
+                        _ => return Err(self.error("expected ',' or ']'")),
+                    }
+                }
+            }
+            Some('{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                if self.peek() == Some('}') {
+                    self.pos += 1;
+                    return Ok(Value::Object(fields));
+                }
+                loop {
+                    if self.peek() != Some('"') {
+                        return Err(self.error("expected a key"));
+                    }
+                    let key = self.string()?;
+                    self.expect(':')?;
+                    fields.push((key, self.value()?));
+                    match self.peek() {
+                        Some(',') => self.pos += 1,
+                        Some('}') => {
+                            self.pos += 1;
+                            return Ok(Value::Object(fields));
+                        }
+                        _ => return Err(self.error("expected ',' or '}'")),
+                    }
+                }
+            }
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Value> {
+        let start = self.pos;
+        while let Some(c) = self.chars.get(self.pos) {
+            if c.is_ascii_digit() || "+-.eE".contains(*c) {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        let s: String = self.chars[start..self.pos].iter().collect();
+        s.parse()
+            .map(Value::Number)
+            .map_err(|_| self.error("invalid number"))
+    }
+
+    /// The 4 hex digits of a `\u` escape.
+    fn hex4(&mut self) -> Result<u32> {
+        let hex: String = self
+            .chars
+            .get(self.pos..self.pos + 4)
+            .ok_or_else(|| self.error("invalid unicode escape"))?
+            .iter()
+            .collect();
+        self.pos += 4;
+        u32::from_str_radix(&hex, 16).map_err(|_| self.error("invalid unicode escape"))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            let c = match self.chars.get(self.pos) {
+                Some(c) => *c,
+                None => return Err(self.error("unterminated string")),
+            };
+            self.pos += 1;
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escaped = self.chars.get(self.pos).copied();
+                    self.pos += 1;
+                    match escaped {
+                        Some('"') => s.push('"'),
+                        Some('\\') => s.push('\\'),
+                        Some('/') => s.push('/'),
+                        Some('b') => s.push('\u{8}'),
+                        Some('f') => s.push('\u{c}'),
+                        Some('n') => s.push('\n'),
+                        Some('r') => s.push('\r'),
+                        Some('t') => s.push('\t'),
+                        Some('u') => {
+                            let mut code = self.hex4()?;
+                            // Characters past the BMP are escaped as a surrogate pair
+                            let low = self.chars.get(self.pos..self.pos + 2) == Some(&['\\', 'u']);
+                            if (0xd800..0xdc00).contains(&code) && low {
+                                let start = self.pos;
+                                self.pos += 2;
+                                match self.hex4()? {
+                                    low @ 0xdc00..=0xdfff => {
+                                        code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                                    }
+                                    _ => self.pos = start,
+                                }
+                            }
+                            s.push(std::char::from_u32(code).unwrap_or('\u{fffd}'));
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    }
+                }
+                c => s.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(value: &Value) -> Value {
+        Value::parse(&value.to_pretty_string().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn documents_round_trip() {
+        let value = Value::Object(vec![
+            ("name".to_string(), "Bass \"303\"\\\n\t\u{1}é🎹".into()),
+            ("volume".to_string(), 0.25.into()),
+            ("tiny".to_string(), 1e-9.into()),
+            ("lines".to_string(), 64usize.into()),
+            ("muted".to_string(), false.into()),
+            ("sound".to_string(), Value::Null),
+            ("empty".to_string(), Value::Object(Vec::new())),
+            (
+                "steps".to_string(),
+                Value::Array(vec![
+                    Value::Array(vec![48usize.into(), Value::Null, (-3.5).into()]),
+                    Value::Array(Vec::new()),
+                ]),
+            ),
+        ]);
+        assert_eq!(round_trip(&value), value);
+    }
+
+    #[test]
+    fn non_finite_numbers_are_errors() {
+        for n in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let value = Value::Object(vec![(
+                "params".to_string(),
+                Value::Array(vec![0.5.into(), n.into()]),
+            )]);
+            let err = value.to_pretty_string().unwrap_err();
+            assert_eq!(err.to_string(), format!("params: 1: {} can't be saved", n));
+        }
+    }
+
+    #[test]
+    fn surrogate_pairs_are_decoded() {
+        let value = Value::parse(r#""\ud83c\udfb9 \u00e9""#).unwrap();
+        assert_eq!(value, Value::from("🎹 é"));
+    }
+
+    #[test]
+    fn lone_surrogates_are_replaced() {
+        let value = Value::parse(r#""\ud83c \udfb9 \ud83cA""#).unwrap();
+        assert_eq!(value, Value::from("\u{fffd} \u{fffd} \u{fffd}A"));
+    }
+
+    #[test]
+    fn truncated_escapes_are_errors() {
+        assert!(Value::parse(r#""\ud83c\u"#).is_err());
+        assert!(Value::parse(r#""\u12"#).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use crate::harmony::MAX_LIVE_NOTES;
+use std::time::{Duration, Instant};
+
+/// Time a key counts as held after it's pressed. The terminal only reports presses, a held key
+/// repeats them once the keyboard's repeat delay has passed, so this has to outlast it.
+const FIRST_REPEAT: Duration = Duration::from_millis(700);
+/// Time a key counts as held after a repeat, repeats follow each other much faster.
+const REPEAT: Duration = Duration::from_millis(120);
+
+pub const DEFAULT_VELOCITY: u8 = 96;
+/// Velocity of the notes played with shift held.
+pub const ACCENT_VELOCITY: u8 = 127;
+const VELOCITY_STEP: u8 = 16;
+
+/// Semitone above the octave a key enters or plays, laid out like a piano over two rows of the
+/// keyboard: `zsxdcvgbhnjm` for the first octave and `q2w3er5t6y7ui` for the one above.
+pub fn semitone(key: char) -> Option<u8> {
+    let semitone = match key {
+        'z' => 0,
+        's' => 1,
+        'x' => 2,
+        'd' => 3,
+        'c' => 4,
+        'v' => 5,
+        'g' => 6,
+        'b' => 7,
+        'h' => 8,
+        'n' => 9,
+        'j' => 10,
+        'm' => 11,
+        'q' => 12,
+        '2' => 13,
+        'w' => 14,
+        '3' => 15,
+        'e' => 16,
+        'r' => 17,
+        '5' => 18,
+        't' => 19,
+        '6' => 20,
+        'y' => 21,
+        '7' => 22,
+        'u' => 23,
+        'i' => 24,
+        _ => return None,
+    };
+    Some(semitone)
+}
+
+/// A note started or ended by the computer keyboard. `slot` tells apart the notes held at
+/// once, from 0 to `MAX_LIVE_NOTES`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LiveNote {
+    On {
+        slot: usize,
+        pitch: u8,
+        velocity: u8,
+    },
+    Off {
+        slot: usize,
+        pitch: u8,
+    },
+}
+
+#[derive(Clone, Debug)]
+struct Held {
+    key: char,
+    slot: usize,
+    pitch: u8,
+    /// When the key counts as released unless it repeats before.
+    until: Instant,
+}
+
+impl Held {
+    fn off(&self) -> LiveNote {
+        LiveNote::Off {
+            slot: self.slot,
+            pitch: self.pitch,
+        }
+    }
+}
+
+/// Plays the computer keyboard like a MIDI keyboard. Repeats of a held key keep its note
+/// sustained instead of starting it again, and the note ends once the repeats stop, shortly
+/// after the key is released.
+pub struct Keyboard {
+    pub velocity: u8,
+    held: Vec<Held>,
+}
+
+impl Keyboard {
+    pub fn new() -> Self {
+        Self {
+            velocity: DEFAULT_VELOCITY,
+            held: Vec::with_capacity(MAX_LIVE_NOTES),
+        }
+    }
+
+    /// Handles a key press, `pitch` being the note the key plays at the current octave.
+    /// Returns the notes to end and to start, nothing when the press is a repeat.
+    pub fn press(&mut self, key: char, pitch: u8, now: Instant) -> Vec<LiveNote> {
+        if let Some(held) = self.held.iter_mut().find(|held| held.key == key) {
+            held.until = now + REPEAT;
+            return Vec::new();
+        }
+        let velocity = match key.is_ascii_uppercase() {
+            true => ACCENT_VELOCITY,
+            false => self.velocity,
+        };
+        let mut notes = Vec::new();
+        // The oldest note makes room when every slot is taken
+        if self.held.len() == MAX_LIVE_NOTES {
+            let oldest = self.held.remove(0);
+            notes.push(oldest.off());
+        }
+        let slot = (0..MAX_LIVE_NOTES)
+            .find(|slot| self.held.iter().all(|held| held.slot != *slot))
+            .unwrap_or(0);
+        self.held.push(Held {
+            key,
+            slot,
+            pitch,
+            until: now + FIRST_REPEAT,
+        });
+        notes.push(LiveNote::On {
+            slot,
+            pitch,
+            velocity,
+        });
+        notes
+    }
+
+    /// Ends the notes of the keys which stopped repeating.
+    pub fn release_expired(&mut self, now: Instant) -> Vec<LiveNote> {
+        let mut notes = Vec::new();
+        self.held.retain(|held| {
+            let expired = held.until <= now;
+            if expired {
+                notes.push(held.off());
+            }
+            !expired
+        });
+        notes
+    }
+
+    /// Ends every note, e.g. when leaving the keyboard.
+    pub fn release_all(&mut self) -> Vec<LiveNote> {
+        self.held.drain(..).map(|held| held.off()).collect()
+    }
+
+    pub fn raise_velocity(&mut self) {
+        self.velocity = u8::min(self.velocity.saturating_add(VELOCITY_STEP), 127);
+    }
+
+    pub fn lower_velocity(&mut self) {
+        self.velocity = u8::max(self.velocity.saturating_sub(VELOCITY_STEP), 1);
+    }
+}
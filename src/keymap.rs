@@ -0,0 +1,305 @@
+//! Regions of a multi-sampled instrument: which sound plays for which keys and velocities.
+//! Edits keep the regions from overlapping, a region placed over others trims them, and report
+//! what they changed so the UI can show it.
+
+use anyhow::{anyhow, Result};
+use camino::Utf8PathBuf;
+use std::fmt;
+
+const MAX_KEY: u8 = 127;
+const MAX_VELOCITY: u8 = 127;
+/// Separates regions in the `regions` option of a sampler.
+const SEPARATOR: char = ';';
+
+/// A sound played over a range of keys and velocities, both inclusive.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Region {
+    pub keys: (u8, u8),
+    pub velocities: (u8, u8),
+    /// Key at which the sound plays at its original speed.
+    pub root: u8,
+    pub path: Utf8PathBuf,
+}
+
+impl Region {
+    /// A region over all velocities.
+    pub fn new(path: Utf8PathBuf, keys: (u8, u8), root: u8) -> Self {
+        Self {
+            keys,
+            velocities: (1, MAX_VELOCITY),
+            root,
+            path,
+        }
+    }
+
+    pub fn contains(&self, key: u8, velocity: u8) -> bool {
+        (self.keys.0..=self.keys.1).contains(&key)
+            && (self.velocities.0..=self.velocities.1).contains(&velocity)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let (low, high) = self.keys;
+        if low > high || high > MAX_KEY {
+            return Err(anyhow!("invalid key range {}-{}", low, high));
+        }
+        let (low, high) = self.velocities;
+        if low == 0 || low > high || high > MAX_VELOCITY {
+            return Err(anyhow!("invalid velocity range {}-{}", low, high));
+        }
+        if self.root > MAX_KEY {
+            return Err(anyhow!("invalid root {}", self.root));
+        }
+        if self.path.as_str().contains(SEPARATOR) {
+            return Err(anyhow!("{} can't be used in a region", self.path));
+        }
+        Ok(())
+    }
+
+    /// What's left of the region outside of `other`, as up to four regions: the keys below
+    /// and above it, then the velocities below and above it on the keys they share.
+    fn minus(&self, other: &Region) -> Vec<Region> {
+        let overlap = |a: (u8, u8), b: (u8, u8)| a.0 <= b.1 && b.0 <= a.1;
+        if !overlap(self.keys, other.keys) || !overlap(self.velocities, other.velocities) {
+            return vec![self.clone()];
+        }
+        let piece = |keys: (u8, u8), velocities: (u8, u8)| Region {
+            keys,
+            velocities,
+            ..self.clone()
+        };
+        let mut pieces = Vec::new();
+        if self.keys.0 < other.keys.0 {
+            pieces.push(piece((self.keys.0, other.keys.0 - 1), self.velocities));
+        }
+        if self.keys.1 > other.keys.1 {
+            pieces.push(piece((other.keys.1 + 1, self.keys.1), self.velocities));
+        }
+        let shared = (
+            u8::max(self.keys.0, other.keys.0),
+            u8::min(self.keys.1, other.keys.1),
+        );
+        if self.velocities.0 < other.velocities.0 {
+            pieces.push(piece(shared, (self.velocities.0, other.velocities.0 - 1)));
+        }
+        if self.velocities.1 > other.velocities.1 {
+            pieces.push(piece(shared, (other.velocities.1 + 1, self.velocities.1)));
+        }
+        pieces
+    }
+
+    /// Parses `<low>-<high>:<low velocity>-<high velocity>:<root>:<path>`.
+    pub fn parse(text: &str) -> Result<Self> {
+        let invalid = || anyhow!("invalid region {}", text);
+        let range = |range: &str| -> Result<(u8, u8)> {
+            let (low, high) = range.split_once('-').ok_or_else(invalid)?;
+            Ok((low.parse()?, high.parse()?))
+        };
+        let mut parts = text.splitn(4, ':');
+        let keys = range(parts.next().ok_or_else(invalid)?)?;
+        let velocities = range(parts.next().ok_or_else(invalid)?)?;
+        let root = parts.next().ok_or_else(invalid)?.parse()?;
+        let path = Utf8PathBuf::from(parts.next().ok_or_else(invalid)?);
+        let region = Self {
+            keys,
+            velocities,
+            root,
+            path,
+        };
+        region.validate()?;
+        Ok(region)
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}:{}-{}:{}:{}",
+            self.keys.0, self.keys.1, self.velocities.0, self.velocities.1, self.root, self.path
+        )
+    }
+}
+
+/// Changes made to a keymap, in order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeymapEvent {
+    Added(Region),
+    Removed(Region),
+    /// A region before and after the change.
+    Changed(Region, Region),
+}
+
+impl fmt::Display for KeymapEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeymapEvent::Added(region) => write!(f, "+{}", region),
+            KeymapEvent::Removed(region) => write!(f, "-{}", region),
+            KeymapEvent::Changed(before, after) => write!(f, "{} > {}", before, after),
+        }
+    }
+}
+
+/// An edit of a keymap, regions are given by their index in `Keymap::regions`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RegionEdit {
+    Add(Region),
+    /// Moves the keys and root by a number of semitones.
+    Move(usize, i8),
+    /// Sets the key range.
+    Resize(usize, u8, u8),
+    /// Splits the velocities in two, the upper part starting at the given velocity.
+    Split(usize, u8),
+    Remove(usize),
+}
+
+/// Regions which never overlap, ordered by key then velocity.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Keymap {
+    regions: Vec<Region>,
+    events: Vec<KeymapEvent>,
+}
+
+impl Keymap {
+    /// Parses the `regions` option of a sampler, regions separated by `;`.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut keymap = Self::default();
+        for region in text.split(SEPARATOR).filter(|r| !r.is_empty()) {
+            keymap.add(Region::parse(region)?)?;
+        }
+        keymap.events.clear();
+        Ok(keymap)
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    pub fn apply(&mut self, edit: RegionEdit) -> Result<()> {
+        match edit {
+            RegionEdit::Add(region) => self.add(region),
+            RegionEdit::Move(index, semitones) => {
+                let mut region = self.get(index)?.clone();
+                let shift = |key: u8| {
+                    let key = key as i16 + semitones as i16;
+                    match key {
+                        0..=127 => Ok(key as u8),
+                        _ => Err(anyhow!("region {} can't move that far", index + 1)),
+                    }
+                };
+                region.keys = (shift(region.keys.0)?, shift(region.keys.1)?);
+                region.root = shift(region.root)?;
+                self.replace(index, region)
+            }
+            RegionEdit::Resize(index, low, high) => {
+                let mut region = self.get(index)?.clone();
+                region.keys = (low, high);
+                self.replace(index, region)
+            }
+            RegionEdit::Split(index, velocity) => {
+                let region = self.get(index)?.clone();
+                if velocity <= region.velocities.0 || velocity > region.velocities.1 {
+                    return Err(anyhow!(
+                        "velocity {} isn't inside region {}",
+                        velocity,
+                        index + 1
+                    ));
+                }
+                let lower = Region {
+                    velocities: (region.velocities.0, velocity - 1),
+                    ..region.clone()
+                };
+                let upper = Region {
+                    velocities: (velocity, region.velocities.1),
+                    ..region.clone()
+                };
+                self.regions[index] = lower.clone();
+                self.events.push(KeymapEvent::Changed(region, lower));
+                self.events.push(KeymapEvent::Added(upper.clone()));
+                self.insert(upper);
+                Ok(())
+            }
+            RegionEdit::Remove(index) => {
+                self.get(index)?;
+                let region = self.regions.remove(index);
+                self.events.push(KeymapEvent::Removed(region));
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the changes made since the last call.
+    pub fn take_events(&mut self) -> Vec<KeymapEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn get(&self, index: usize) -> Result<&Region> {
+        self.regions
+            .get(index)
+            .ok_or_else(|| anyhow!("no region {}", index + 1))
+    }
+
+    /// Adds a region, trimming the regions it overlaps.
+    fn add(&mut self, region: Region) -> Result<()> {
+        region.validate()?;
+        self.make_room(&region);
+        self.events.push(KeymapEvent::Added(region.clone()));
+        self.insert(region);
+        Ok(())
+    }
+
+    /// Puts a changed region back, trimming the regions it now overlaps. Nothing changes when
+    /// the new region is invalid.
+    fn replace(&mut self, index: usize, region: Region) -> Result<()> {
+        region.validate()?;
+        let before = self.regions.remove(index);
+        self.make_room(&region);
+        self.events
+            .push(KeymapEvent::Changed(before, region.clone()));
+        self.insert(region);
+        Ok(())
+    }
+
+    fn make_room(&mut self, region: &Region) {
+        let mut kept = Vec::with_capacity(self.regions.len());
+        for other in self.regions.drain(..) {
+            let mut pieces = other.minus(region).into_iter();
+            match pieces.next() {
+                Some(first) if first == other => kept.push(first),
+                Some(first) => {
+                    self.events
+                        .push(KeymapEvent::Changed(other.clone(), first.clone()));
+                    kept.push(first);
+                    for piece in pieces {
+                        self.events.push(KeymapEvent::Added(piece.clone()));
+                        kept.push(piece);
+                    }
+                }
+                None => self.events.push(KeymapEvent::Removed(other)),
+            }
+        }
+        self.regions = kept;
+        self.sort();
+    }
+
+    fn insert(&mut self, region: Region) {
+        self.regions.push(region);
+        self.sort();
+    }
+
+    fn sort(&mut self) {
+        self.regions
+            .sort_by_key(|r| (r.keys.0, r.velocities.0, r.keys.1, r.velocities.1));
+    }
+}
+
+impl fmt::Display for Keymap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, region) in self.regions.iter().enumerate() {
+            if i > 0 {
+                write!(f, "{}", SEPARATOR)?;
+            }
+            write!(f, "{}", region)?;
+        }
+        Ok(())
+    }
+}
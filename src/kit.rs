@@ -0,0 +1,144 @@
+use crate::bounce::write_wav;
+use crate::sampler::Sound;
+use anyhow::{anyhow, Result};
+use camino::Utf8Path;
+use hound::{SampleFormat, WavSpec};
+use std::fmt::Write;
+use std::fs;
+
+/// Key of the first pad, the kick of the General MIDI drum map.
+pub const FIRST_KEY: u8 = 36;
+const BIT_DEPTH: u16 = 24;
+
+/// Mapping files understood by hardware and software samplers.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum KitFormat {
+    Sfz,
+    /// The XML presets of Decent Sampler.
+    DecentSampler,
+}
+
+impl KitFormat {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "sfz" => Ok(Self::Sfz),
+            "ds" | "dspreset" => Ok(Self::DecentSampler),
+            _ => Err(anyhow!("unknown kit format {}, expected sfz|ds", name)),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Sfz => "sfz",
+            Self::DecentSampler => "dspreset",
+        }
+    }
+}
+
+/// A sampler of the kit with its current settings.
+pub struct Pad {
+    pub name: String,
+    pub key: u8,
+    pub sound: Sound,
+    /// In dB.
+    pub volume: f32,
+    /// In semitones.
+    pub tune: f32,
+    /// Times in seconds, sustain from 0 to 1.
+    pub delay: f32,
+    pub attack: f32,
+    pub hold: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+}
+
+impl Pad {
+    fn file_name(&self) -> String {
+        let name: String = self
+            .name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        format!("{:03}-{}.wav", self.key, name)
+    }
+}
+
+/// Writes every pad to its own WAV file in `dir`, along with a mapping file named after the
+/// directory which plays each of them on its key.
+pub fn export(pads: &[Pad], dir: &Utf8Path, format: KitFormat) -> Result<()> {
+    if pads.is_empty() {
+        return Err(anyhow!("no sampler to export"));
+    }
+    let name = dir
+        .file_name()
+        .ok_or_else(|| anyhow!("invalid kit directory {}", dir))?;
+    fs::create_dir_all(dir)?;
+    for pad in pads {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: pad.sound.sample_rate(),
+            bits_per_sample: BIT_DEPTH,
+            sample_format: SampleFormat::Int,
+        };
+        let frames: Vec<(f32, f32)> = pad.sound.frames().collect();
+        write_wav(&dir.join(pad.file_name()), spec, &frames)?;
+    }
+    let mapping = match format {
+        KitFormat::Sfz => sfz(pads),
+        KitFormat::DecentSampler => decent_sampler(pads),
+    };
+    fs::write(dir.join(name).with_extension(format.extension()), mapping)?;
+    Ok(())
+}
+
+fn sfz(pads: &[Pad]) -> String {
+    let mut sfz = String::from("<control>\ndefault_path=./\n\n<group>\n");
+    for pad in pads {
+        let transpose = pad.tune.trunc();
+        let _ = write!(
+            sfz,
+            "\n<region>\nsample={}\nkey={}\npitch_keycenter={}\nvolume={:.1}\ntranspose={}\ntune={:.0}\n\
+             ampeg_delay={:.3}\nampeg_attack={:.3}\nampeg_hold={:.3}\nampeg_decay={:.3}\n\
+             ampeg_sustain={:.1}\nampeg_release={:.3}\n",
+            pad.file_name(),
+            pad.key,
+            pad.key,
+            pad.volume,
+            transpose,
+            (pad.tune - transpose) * 100.0,
+            pad.delay,
+            pad.attack,
+            pad.hold,
+            pad.decay,
+            pad.sustain * 100.0,
+            pad.release,
+        );
+    }
+    sfz
+}
+
+fn decent_sampler(pads: &[Pad]) -> String {
+    let mut preset =
+        String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<DecentSampler>\n  <groups>\n");
+    for pad in pads {
+        let _ = writeln!(
+            preset,
+            "    <group>\n      <sample path=\"{}\" rootNote=\"{}\" loNote=\"{}\" hiNote=\"{}\" \
+             volume=\"{:.1}dB\" tuning=\"{:.2}\" attack=\"{:.3}\" decay=\"{:.3}\" \
+             sustain=\"{:.3}\" release=\"{:.3}\" trigger=\"attack\"/>\n    </group>",
+            pad.file_name(),
+            pad.key,
+            pad.key,
+            pad.key,
+            pad.volume,
+            pad.tune,
+            pad.delay + pad.attack,
+            pad.hold + pad.decay,
+            pad.sustain,
+            pad.release,
+        );
+    }
+    preset.push_str("  </groups>\n</DecentSampler>\n");
+    preset
+}
@@ -0,0 +1,461 @@
+//! LFOs which modulate the params of an instrument, for vibrato, tremolo, filter wobble and
+//! the like.
+//!
+//! Every instrument is wrapped in a `Modulated`, which hands out its own copies of the
+//! instrument's params. The app edits those, and before every block the wrapper writes them
+//! with the LFOs applied to the params the instrument actually reads, so the values the user
+//! set are what gets saved and displayed.
+
+use crate::engine::{Device, EngineConfig, CONTROL_BLOCK_SIZE};
+use crate::instrument::{Instrument, Quality};
+use crate::param::Param;
+use anyhow::{anyhow, Result};
+use atomic_float::AtomicF32;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+pub const NUM_LFOS: usize = 2;
+/// Number of LFO to param routes per instrument.
+pub const MAX_ROUTES: usize = 8;
+
+const MIN_RATE: f32 = 0.01;
+const MAX_RATE: f32 = 50.0;
+
+/// Lengths a tempo synced LFO cycle can take, in beats.
+const DIVISIONS: [(&str, f32); 8] = [
+    ("1/32", 0.125),
+    ("1/16", 0.25),
+    ("1/8", 0.5),
+    ("1/4", 1.0),
+    ("1/2", 2.0),
+    ("1bar", 4.0),
+    ("2bar", 8.0),
+    ("4bar", 16.0),
+];
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Shape {
+    #[default]
+    Sine,
+    Triangle,
+    /// Rising ramp.
+    Saw,
+    Square,
+    /// A new random value every cycle.
+    SampleAndHold,
+}
+
+impl Shape {
+    const ALL: [Shape; 5] = [
+        Shape::Sine,
+        Shape::Triangle,
+        Shape::Saw,
+        Shape::Square,
+        Shape::SampleAndHold,
+    ];
+
+    pub fn parse(name: &str) -> Result<Self> {
+        Self::ALL
+            .iter()
+            .find(|shape| shape.name() == name)
+            .copied()
+            .ok_or_else(|| {
+                anyhow!(
+                    "invalid LFO shape {}, expected sine|tri|saw|square|sh",
+                    name
+                )
+            })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Shape::Sine => "sine",
+            Shape::Triangle => "tri",
+            Shape::Saw => "saw",
+            Shape::Square => "square",
+            Shape::SampleAndHold => "sh",
+        }
+    }
+
+    /// Value at `phase`, between -1 and 1. `held` is the current sample and hold value.
+    fn value(&self, phase: f32, held: f32) -> f32 {
+        match self {
+            Shape::Sine => f32::sin(2.0 * PI * phase),
+            Shape::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Shape::Saw => 2.0 * phase - 1.0,
+            Shape::Square if phase < 0.5 => 1.0,
+            Shape::Square => -1.0,
+            Shape::SampleAndHold => held,
+        }
+    }
+}
+
+/// Speed of an LFO, either free running or a note length at the song tempo.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Rate {
+    Hz(f32),
+    /// Index into the synced divisions.
+    Sync(usize),
+}
+
+impl Default for Rate {
+    fn default() -> Self {
+        Rate::Hz(1.0)
+    }
+}
+
+impl Rate {
+    /// Parses a frequency in Hz, or a note length from `1/32` to `1/2`, or `1bar` to `4bar`.
+    pub fn parse(name: &str) -> Result<Self> {
+        if let Some(i) = DIVISIONS.iter().position(|(division, _)| *division == name) {
+            return Ok(Rate::Sync(i));
+        }
+        match name.parse::<f32>() {
+            Ok(hz) if (MIN_RATE..=MAX_RATE).contains(&hz) => Ok(Rate::Hz(hz)),
+            _ => Err(anyhow!(
+                "invalid LFO rate {}, expected {} to {} Hz or 1/32|1/16|1/8|1/4|1/2|1bar|2bar|4bar",
+                name,
+                MIN_RATE,
+                MAX_RATE
+            )),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Rate::Hz(hz) => hz.to_string(),
+            Rate::Sync(i) => DIVISIONS[*i].0.to_string(),
+        }
+    }
+
+    fn hz(&self, bpm: f32) -> f32 {
+        match self {
+            Rate::Hz(hz) => *hz,
+            Rate::Sync(i) => bpm / 60.0 / DIVISIONS[*i].1,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LfoParams {
+    shape: Arc<AtomicUsize>,
+    /// Free running rate in Hz, used when not synced.
+    rate: Arc<AtomicF32>,
+    /// Synced division plus one, zero when free running.
+    sync: Arc<AtomicUsize>,
+}
+
+impl Default for LfoParams {
+    fn default() -> Self {
+        Self {
+            shape: Arc::new(AtomicUsize::new(0)),
+            rate: Arc::new(AtomicF32::new(1.0)),
+            sync: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl LfoParams {
+    pub fn shape(&self) -> Shape {
+        Shape::ALL[self.shape.load(Ordering::Relaxed) % Shape::ALL.len()]
+    }
+
+    pub fn rate(&self) -> Rate {
+        match self.sync.load(Ordering::Relaxed) {
+            0 => Rate::Hz(self.rate.load(Ordering::Relaxed)),
+            i => Rate::Sync(i - 1),
+        }
+    }
+
+    pub fn set(&self, shape: Shape, rate: Rate) {
+        let shape = Shape::ALL.iter().position(|s| *s == shape).unwrap_or(0);
+        self.shape.store(shape, Ordering::Relaxed);
+        match rate {
+            Rate::Hz(hz) => {
+                self.rate.store(hz, Ordering::Relaxed);
+                self.sync.store(0, Ordering::Relaxed);
+            }
+            Rate::Sync(i) => self.sync.store(i + 1, Ordering::Relaxed),
+        }
+    }
+}
+
+/// Modulates param `target`, an index into the instrument's params, by an LFO. `depth` is a
+/// fraction of the param's range, negative depths invert the LFO.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Route {
+    pub lfo: usize,
+    pub target: usize,
+    pub depth: f32,
+}
+
+#[derive(Clone)]
+struct RouteParams {
+    /// LFO plus one, zero when the route is unused.
+    lfo: Arc<AtomicUsize>,
+    target: Arc<AtomicUsize>,
+    depth: Arc<AtomicF32>,
+}
+
+impl Default for RouteParams {
+    fn default() -> Self {
+        Self {
+            lfo: Arc::new(AtomicUsize::new(0)),
+            target: Arc::new(AtomicUsize::new(0)),
+            depth: Arc::new(AtomicF32::new(0.0)),
+        }
+    }
+}
+
+impl RouteParams {
+    fn get(&self) -> Option<Route> {
+        match self.lfo.load(Ordering::Relaxed) {
+            0 => None,
+            lfo => Some(Route {
+                lfo: lfo - 1,
+                target: self.target.load(Ordering::Relaxed),
+                depth: self.depth.load(Ordering::Relaxed),
+            }),
+        }
+    }
+}
+
+/// LFO settings and the modulation matrix of an instrument, shared between the app and the
+/// engine.
+#[derive(Clone)]
+pub struct ModulationParams {
+    pub lfos: Vec<LfoParams>,
+    routes: Vec<RouteParams>,
+    /// Updates the params every sample instead of every control block, for instruments which
+    /// read them that often.
+    pub per_sample: Arc<AtomicBool>,
+}
+
+impl Default for ModulationParams {
+    fn default() -> Self {
+        Self {
+            lfos: (0..NUM_LFOS).map(|_| LfoParams::default()).collect(),
+            routes: (0..MAX_ROUTES).map(|_| RouteParams::default()).collect(),
+            per_sample: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl ModulationParams {
+    pub fn routes(&self) -> Vec<Route> {
+        self.routes.iter().filter_map(|route| route.get()).collect()
+    }
+
+    /// Adds a route, or changes the depth of the route from the same LFO to the same param.
+    pub fn set_route(&self, route: Route) -> Result<()> {
+        if route.lfo >= NUM_LFOS {
+            return Err(anyhow!("invalid LFO {}", route.lfo + 1));
+        }
+        let same = |r: &Route| r.lfo == route.lfo && r.target == route.target;
+        let slot = self
+            .routes
+            .iter()
+            .find(|slot| slot.get().is_some_and(|r| same(&r)))
+            .or_else(|| self.routes.iter().find(|slot| slot.get().is_none()))
+            .ok_or_else(|| anyhow!("at most {} modulation routes", MAX_ROUTES))?;
+        // Enabled last, so the engine never sees a half written route.
+        slot.depth
+            .store(route.depth.clamp(-1.0, 1.0), Ordering::Relaxed);
+        slot.target.store(route.target, Ordering::Relaxed);
+        slot.lfo.store(route.lfo + 1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn remove_route(&self, lfo: usize, target: usize) {
+        for slot in &self.routes {
+            if slot
+                .get()
+                .is_some_and(|r| r.lfo == lfo && r.target == target)
+            {
+                slot.lfo.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Removes every route and sets the LFOs back to their defaults.
+    pub fn clear(&self) {
+        for slot in &self.routes {
+            slot.lfo.store(0, Ordering::Relaxed);
+        }
+        for lfo in &self.lfos {
+            lfo.set(Shape::default(), Rate::default());
+        }
+        self.per_sample.store(false, Ordering::Relaxed);
+    }
+}
+
+struct Lfo {
+    /// Position in the cycle, from 0 to 1.
+    phase: f32,
+    held: f32,
+}
+
+/// A param of the wrapped instrument, with the value set by the user.
+struct Target {
+    base: Arc<AtomicF32>,
+    value: Arc<AtomicF32>,
+    min: f32,
+    max: f32,
+}
+
+/// Applies the LFOs of an instrument to its params.
+pub struct Modulated {
+    instrument: Box<dyn Instrument>,
+    params: ModulationParams,
+    targets: Vec<Target>,
+    /// Modulation of every target for the current block, in fractions of its range.
+    offsets: Vec<f32>,
+    lfos: Vec<Lfo>,
+    /// State of the random generator of the sample and hold LFOs.
+    random: u32,
+    sample_rate: f32,
+    bpm: f32,
+}
+
+impl Modulated {
+    pub fn new(instrument: Box<dyn Instrument>, params: ModulationParams) -> Self {
+        let targets: Vec<Target> = instrument
+            .params()
+            .into_iter()
+            .map(|(_, param)| {
+                let (min, max) = param.range();
+                let value = param.val;
+                Target {
+                    base: Arc::new(AtomicF32::new(value.load(Ordering::Relaxed))),
+                    value,
+                    min,
+                    max,
+                }
+            })
+            .collect();
+        Self {
+            instrument,
+            params,
+            offsets: vec![0.0; targets.len()],
+            targets,
+            lfos: (0..NUM_LFOS)
+                .map(|_| Lfo {
+                    phase: 0.0,
+                    held: 0.0,
+                })
+                .collect(),
+            random: 0x9e37_79b9,
+            sample_rate: EngineConfig::default().sample_rate as f32,
+            bpm: 120.0,
+        }
+    }
+
+    /// Writes the modulated params and advances the LFOs by `len` frames.
+    fn update(&mut self, len: usize) {
+        for offset in &mut self.offsets {
+            *offset = 0.0;
+        }
+        for route in &self.params.routes {
+            let route = match route.get() {
+                Some(route) => route,
+                None => continue,
+            };
+            if let (Some(lfo), Some(offset)) =
+                (self.lfos.get(route.lfo), self.offsets.get_mut(route.target))
+            {
+                let shape = self.params.lfos[route.lfo].shape();
+                *offset += shape.value(lfo.phase, lfo.held) * route.depth;
+            }
+        }
+        for (target, offset) in self.targets.iter().zip(&self.offsets) {
+            let base = target.base.load(Ordering::Relaxed);
+            let value = base + offset * (target.max - target.min);
+            target
+                .value
+                .store(value.clamp(target.min, target.max), Ordering::Relaxed);
+        }
+
+        for (lfo, params) in self.lfos.iter_mut().zip(&self.params.lfos) {
+            lfo.phase += params.rate().hz(self.bpm) * len as f32 / self.sample_rate;
+            if lfo.phase >= 1.0 {
+                lfo.phase = lfo.phase.fract();
+                // xorshift
+                self.random ^= self.random << 13;
+                self.random ^= self.random >> 17;
+                self.random ^= self.random << 5;
+                lfo.held = self.random as f32 / u32::MAX as f32 * 2.0 - 1.0;
+            }
+        }
+    }
+}
+
+impl Device for Modulated {
+    fn render(&mut self, buffer: &mut [(f32, f32)]) {
+        let block_size = match self.params.per_sample.load(Ordering::Relaxed) {
+            true => 1,
+            false => CONTROL_BLOCK_SIZE,
+        };
+        for block in buffer.chunks_mut(block_size) {
+            self.update(block.len());
+            self.instrument.render(block);
+        }
+    }
+}
+
+impl Instrument for Modulated {
+    fn note_on(&mut self, column: usize, pitch: u8, velocity: u8) {
+        self.instrument.note_on(column, pitch, velocity);
+    }
+
+    fn note_off(&mut self, column: usize) {
+        self.instrument.note_off(column);
+    }
+
+    fn note_cut(&mut self, column: usize) {
+        self.instrument.note_cut(column);
+    }
+
+    fn prepare(&mut self, config: &EngineConfig) {
+        self.sample_rate = config.sample_rate as f32;
+        self.instrument.prepare(config);
+    }
+
+    /// Synced LFOs start over with playback, so they line up with the song.
+    fn stop(&mut self) {
+        for lfo in &mut self.lfos {
+            lfo.phase = 0.0;
+        }
+        self.instrument.stop();
+    }
+
+    fn reset(&mut self) {
+        for lfo in &mut self.lfos {
+            lfo.phase = 0.0;
+        }
+        self.instrument.reset();
+    }
+
+    fn set_quality(&mut self, quality: Quality) {
+        self.instrument.set_quality(quality);
+    }
+
+    fn set_tempo(&mut self, bpm: f32) {
+        self.bpm = bpm;
+        self.instrument.set_tempo(bpm);
+    }
+
+    fn active_voices(&self) -> usize {
+        self.instrument.active_voices()
+    }
+
+    /// The params of the instrument, without modulation.
+    fn params(&self) -> Vec<(String, Param)> {
+        self.instrument
+            .params()
+            .into_iter()
+            .zip(&self.targets)
+            .map(|((name, param), target)| (name, param.share(Arc::clone(&target.base))))
+            .collect()
+    }
+}
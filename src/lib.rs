@@ -0,0 +1,69 @@
+//! The sequencer, its instruments and the engine playing them. Built as a library for the app
+//! in `main.rs` and as a CLAP plugin, see `clap::plugin`.
+
+extern crate anyhow;
+extern crate atomic_float;
+extern crate portaudio;
+
+#[macro_use]
+extern crate lazy_static;
+
+mod aiff;
+pub mod app;
+mod arp;
+pub mod audio;
+pub mod bench;
+mod bounce;
+mod capture;
+mod clap;
+pub mod crash;
+pub mod demo;
+mod drift;
+mod drums;
+mod drumsynth;
+mod effect;
+pub mod engine;
+mod env;
+mod euclid;
+mod filter;
+mod fm;
+mod frame;
+mod harmony;
+mod id;
+mod input;
+mod instrument;
+mod json;
+mod keyboard;
+mod keymap;
+mod kit;
+mod lfo;
+mod library;
+mod midi;
+mod mixer;
+mod mmap;
+mod monitor;
+mod mono;
+mod osc;
+mod param;
+pub mod paths;
+mod pattern;
+mod perform;
+mod project;
+mod record;
+mod resample;
+mod rtlog;
+mod sampler;
+mod sampling;
+mod script;
+mod stretch;
+mod synth;
+mod telemetry;
+mod transport;
+mod tuner;
+mod ui;
+mod undo;
+mod vst2;
+mod wavetable;
+mod workers;
+
+pub const MAX_FRAMES_PER_BUFFER: usize = 4096;
@@ -0,0 +1,676 @@
+//! Tags, ratings and color labels on the sounds of the library, and hashes of their audio to
+//! find duplicates, kept in a sidecar file at the root of the library so they move along with
+//! the sounds.
+
+use crate::aiff;
+use crate::json::Value;
+use crate::sampler;
+use anyhow::{anyhow, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use hound::WavReader;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::time::UNIX_EPOCH;
+
+pub const MAX_RATING: u8 = 5;
+
+const FILE_NAME: &str = ".library.json";
+const VERSION: usize = 1;
+
+/// Color label of a sound, to group sounds at a glance in the browser.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Label {
+    Red,
+    Orange,
+    Yellow,
+    Green,
+    Blue,
+    Purple,
+}
+
+impl Label {
+    const ALL: [Label; 6] = [
+        Label::Red,
+        Label::Orange,
+        Label::Yellow,
+        Label::Green,
+        Label::Blue,
+        Label::Purple,
+    ];
+
+    pub fn parse(name: &str) -> Result<Self> {
+        Self::ALL
+            .iter()
+            .find(|label| label.name().eq_ignore_ascii_case(name))
+            .copied()
+            .ok_or_else(|| {
+                anyhow!(
+                    "invalid label {}, expected red|orange|yellow|green|blue|purple",
+                    name
+                )
+            })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Label::Red => "red",
+            Label::Orange => "orange",
+            Label::Yellow => "yellow",
+            Label::Green => "green",
+            Label::Blue => "blue",
+            Label::Purple => "purple",
+        }
+    }
+}
+
+/// What the user knows about a sound. Sounds without an entry have the default one.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Entry {
+    /// Lowercase and sorted.
+    pub tags: Vec<String>,
+    /// From 0 (unrated) to `MAX_RATING` stars.
+    pub rating: u8,
+    pub label: Option<Label>,
+}
+
+impl Entry {
+    fn is_empty(&self) -> bool {
+        *self == Entry::default()
+    }
+}
+
+/// Checks a tag and makes it lowercase. Tags are single words so queries can tell them from
+/// operators.
+pub fn normalize_tag(tag: &str) -> Result<String> {
+    let valid = |c: char| c.is_alphanumeric() || c == '-' || c == '_' || c == '#';
+    if tag.is_empty() || !tag.chars().all(valid) || Keyword::parse(tag).is_some() {
+        return Err(anyhow!("invalid tag {}", tag));
+    }
+    Ok(tag.to_lowercase())
+}
+
+/// Hash of the audio of a file, with what is needed to tell whether the file has changed
+/// since.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Fingerprint {
+    size: u64,
+    /// Modification time in seconds since the epoch.
+    modified: f64,
+    hash: u64,
+}
+
+/// Sounds with the same audio. The first one is the canonical copy: the highest rated one, or
+/// the first by path among equals.
+pub struct Duplicates {
+    pub hash: u64,
+    pub sounds: Vec<Utf8PathBuf>,
+}
+
+/// The sounds below a directory, with their entries.
+pub struct Library {
+    root: Utf8PathBuf,
+    /// Entries by path relative to the root, with `/` separators.
+    entries: BTreeMap<String, Entry>,
+    /// Audio hashes by path relative to the root, filled in when looking for duplicates.
+    fingerprints: BTreeMap<String, Fingerprint>,
+}
+
+impl Library {
+    /// Opens the library at `root`, which is empty until something is tagged, rated or labeled.
+    pub fn open<P: AsRef<Utf8Path>>(root: P) -> Result<Self> {
+        let root = canonicalize(root.as_ref())?;
+        let mut library = Self {
+            root,
+            entries: BTreeMap::new(),
+            fingerprints: BTreeMap::new(),
+        };
+        let path = library.root.join(FILE_NAME);
+        if path.exists() {
+            let data = fs::read_to_string(&path)?;
+            library
+                .load(&Value::parse(&data)?)
+                .map_err(|err| anyhow!("{}: {}", path, err))?;
+        }
+        Ok(library)
+    }
+
+    /// The entry of a sound, which is the default one when it isn't in the library.
+    pub fn entry(&self, path: &Utf8Path) -> Entry {
+        self.key(path)
+            .and_then(|key| self.entries.get(&key).cloned())
+            .unwrap_or_default()
+    }
+
+    /// Changes the entry of a sound in the library and saves the library.
+    pub fn update<F: FnOnce(&mut Entry)>(&mut self, path: &Utf8Path, edit: F) -> Result<()> {
+        let key = self
+            .key(path)
+            .ok_or_else(|| anyhow!("{} is not in the library at {}", path, self.root))?;
+        let mut entry = self.entries.remove(&key).unwrap_or_default();
+        edit(&mut entry);
+        entry.rating = entry.rating.min(MAX_RATING);
+        entry.tags.sort();
+        entry.tags.dedup();
+        if !entry.is_empty() {
+            self.entries.insert(key, entry);
+        }
+        self.save()
+    }
+
+    pub fn matches(&self, path: &Utf8Path, query: &Query) -> bool {
+        query.matches(&self.entry(path))
+    }
+
+    /// Every sound below the root which matches `query`, sorted by path.
+    pub fn search(&self, query: &Query) -> Result<Vec<Utf8PathBuf>> {
+        let mut sounds = Vec::new();
+        find_sounds(&self.root, &mut sounds)?;
+        sounds.retain(|path| self.matches(path, query));
+        sounds.sort();
+        Ok(sounds)
+    }
+
+    /// Hashes the audio of a sound, ignoring the other chunks of the file so copies with
+    /// different metadata still match. Hashes of sounds in the library are cached until the
+    /// file changes.
+    pub fn hash(&mut self, path: &Utf8Path) -> Result<u64> {
+        let metadata = fs::metadata(path)?;
+        let size = metadata.len();
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)?
+            .as_secs_f64();
+        let key = self.key(path);
+        if let Some(fingerprint) = key.as_ref().and_then(|key| self.fingerprints.get(key)) {
+            if fingerprint.size == size && fingerprint.modified == modified {
+                return Ok(fingerprint.hash);
+            }
+        }
+        let hash = hash_audio(path)?;
+        if let Some(key) = key {
+            let fingerprint = Fingerprint {
+                size,
+                modified,
+                hash,
+            };
+            self.fingerprints.insert(key, fingerprint);
+        }
+        Ok(hash)
+    }
+
+    /// Finds the sounds of the library which have the same audio, sorted by their canonical
+    /// copy. Hashing a large library takes a while the first time, the hashes are saved in the
+    /// library.
+    pub fn duplicates(&mut self) -> Result<Vec<Duplicates>> {
+        let mut sounds = Vec::new();
+        find_sounds(&self.root, &mut sounds)?;
+        let mut groups: BTreeMap<u64, Vec<Utf8PathBuf>> = BTreeMap::new();
+        let mut keys = HashSet::new();
+        for path in sounds {
+            keys.extend(self.key(&path));
+            // Files hound can't read can't be compared, and can't be loaded either.
+            if let Ok(hash) = self.hash(&path) {
+                groups.entry(hash).or_default().push(path);
+            }
+        }
+        self.fingerprints.retain(|key, _| keys.contains(key));
+        self.save()?;
+
+        let mut duplicates: Vec<Duplicates> = groups
+            .into_iter()
+            .filter(|(_, sounds)| sounds.len() > 1)
+            .map(|(hash, mut sounds)| {
+                sounds.sort_by_cached_key(|path| {
+                    (MAX_RATING - self.entry(path).rating, path.clone())
+                });
+                Duplicates { hash, sounds }
+            })
+            .collect();
+        duplicates.sort_by(|a, b| a.sounds[0].cmp(&b.sounds[0]));
+        Ok(duplicates)
+    }
+
+    /// Paths which are already below the root aren't canonicalized, the browser looks up
+    /// every file it shows on every frame.
+    fn key(&self, path: &Utf8Path) -> Option<String> {
+        let canonical;
+        let relative = match path.strip_prefix(&self.root) {
+            Ok(relative) => relative,
+            Err(_) => {
+                canonical = canonicalize(path).ok()?;
+                canonical.strip_prefix(&self.root).ok()?
+            }
+        };
+        let parts: Vec<&str> = relative.components().map(|c| c.as_str()).collect();
+        Some(parts.join("/"))
+    }
+
+    fn save(&self) -> Result<()> {
+        let sounds = self
+            .entries
+            .iter()
+            .map(|(key, entry)| {
+                let tags = entry.tags.iter().map(|tag| tag.as_str().into()).collect();
+                let label = match entry.label {
+                    Some(label) => label.name().into(),
+                    None => Value::Null,
+                };
+                let fields = vec![
+                    ("tags".into(), Value::Array(tags)),
+                    ("rating".into(), (entry.rating as usize).into()),
+                    ("label".into(), label),
+                ];
+                (key.clone(), Value::Object(fields))
+            })
+            .collect();
+        let hashes = self
+            .fingerprints
+            .iter()
+            .map(|(key, fingerprint)| {
+                let fields = vec![
+                    ("size".into(), (fingerprint.size as usize).into()),
+                    ("modified".into(), fingerprint.modified.into()),
+                    (
+                        "hash".into(),
+                        format!("{:016x}", fingerprint.hash).as_str().into(),
+                    ),
+                ];
+                (key.clone(), Value::Object(fields))
+            })
+            .collect();
+        let json = Value::Object(vec![
+            ("version".into(), VERSION.into()),
+            ("sounds".into(), Value::Object(sounds)),
+            ("hashes".into(), Value::Object(hashes)),
+        ]);
+        fs::write(self.root.join(FILE_NAME), json.to_pretty_string()?)?;
+        Ok(())
+    }
+
+    fn load(&mut self, json: &Value) -> Result<()> {
+        let version = json.field("version")?.as_usize()?;
+        if version > VERSION {
+            return Err(anyhow!("unsupported library version {}", version));
+        }
+        for (key, sound) in json.field("sounds")?.as_object()? {
+            let mut tags = Vec::new();
+            for tag in sound.field("tags")?.as_array()? {
+                tags.push(normalize_tag(tag.as_str()?)?);
+            }
+            let entry = Entry {
+                tags,
+                rating: sound.field("rating")?.as_usize()?.min(MAX_RATING as usize) as u8,
+                label: match sound.get("label") {
+                    Some(Value::Null) | None => None,
+                    Some(label) => Some(Label::parse(label.as_str()?)?),
+                },
+            };
+            self.entries.insert(key.clone(), entry);
+        }
+        if let Some(hashes) = json.get("hashes") {
+            for (key, fingerprint) in hashes.as_object()? {
+                let hash = fingerprint.field("hash")?.as_str()?;
+                let fingerprint = Fingerprint {
+                    size: fingerprint.field("size")?.as_usize()? as u64,
+                    modified: fingerprint.field("modified")?.as_f64()?,
+                    hash: u64::from_str_radix(hash, 16)
+                        .map_err(|_| anyhow!("invalid hash {}", hash))?,
+                };
+                self.fingerprints.insert(key.clone(), fingerprint);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// FNV-1a over the format and the sample data of a WAV file, or over the decoded frames of
+/// other files.
+fn hash_audio(path: &Utf8Path) -> Result<u64> {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    let mut hash = OFFSET;
+    let mut write = |bytes: &[u8]| {
+        for byte in bytes {
+            hash = (hash ^ *byte as u64).wrapping_mul(PRIME);
+        }
+    };
+    if !path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("wav"))
+    {
+        let (frames, sample_rate) = aiff::read(path)?;
+        write(&sample_rate.to_le_bytes());
+        for (left, right) in frames {
+            write(&left.to_le_bytes());
+            write(&right.to_le_bytes());
+        }
+        return Ok(hash);
+    }
+    let wav = WavReader::open(path)?;
+    let spec = wav.spec();
+    let len = wav.len() as u64 * (spec.bits_per_sample as u64).div_ceil(8);
+    write(&spec.channels.to_le_bytes());
+    write(&spec.sample_rate.to_le_bytes());
+    write(&spec.bits_per_sample.to_le_bytes());
+    write(&[spec.sample_format as u8]);
+    // The reader is left at the start of the sample data
+    let mut reader = wav.into_inner().take(len);
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        write(&buffer[..read]);
+    }
+    Ok(hash)
+}
+
+fn canonicalize(path: &Utf8Path) -> Result<Utf8PathBuf> {
+    Utf8PathBuf::from_path_buf(path.canonicalize()?)
+        .map_err(|path| anyhow!("invalid path {}", path.display()))
+}
+
+/// Adds the sound files below `dir` to `sounds`, skipping hidden files and directories.
+fn find_sounds(dir: &Utf8Path, sounds: &mut Vec<Utf8PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = match Utf8PathBuf::from_path_buf(entry?.path()) {
+            Ok(path) => path,
+            Err(_) => continue,
+        };
+        if path.file_name().is_none_or(|name| name.starts_with('.')) {
+            continue;
+        }
+        if path.is_dir() {
+            find_sounds(&path, sounds)?;
+        } else if sampler::is_sound(&path) {
+            sounds.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Equal,
+    GreaterOrEqual,
+    Greater,
+}
+
+impl Comparison {
+    fn symbol(&self) -> &'static str {
+        match self {
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Equal => "=",
+            Comparison::GreaterOrEqual => ">=",
+            Comparison::Greater => ">",
+        }
+    }
+
+    fn compare(&self, a: u8, b: u8) -> bool {
+        match self {
+            Comparison::Less => a < b,
+            Comparison::LessOrEqual => a <= b,
+            Comparison::Equal => a == b,
+            Comparison::GreaterOrEqual => a >= b,
+            Comparison::Greater => a > b,
+        }
+    }
+}
+
+/// A filter on library entries, e.g. `kick AND analog, rating >= 4`.
+///
+/// Words are tags, `rating <op> <stars>` compares the rating and `label <color>` matches a
+/// label. Terms combine with `NOT`, `AND` and `OR`, in order of precedence, and parentheses.
+/// Terms next to each other and commas are shorthands for `AND`, commas binding loosest.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Query {
+    Tag(String),
+    Rating(Comparison, u8),
+    Label(Label),
+    Not(Box<Query>),
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+}
+
+impl Query {
+    pub fn parse(input: &str) -> Result<Self> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            position: 0,
+        };
+        let query = parser.list()?;
+        match parser.peek() {
+            None => Ok(query),
+            Some(token) => Err(anyhow!("unexpected {} in query", token)),
+        }
+    }
+
+    pub fn matches(&self, entry: &Entry) -> bool {
+        match self {
+            Query::Tag(tag) => entry.tags.iter().any(|t| t == tag),
+            Query::Rating(comparison, stars) => comparison.compare(entry.rating, *stars),
+            Query::Label(label) => entry.label == Some(*label),
+            Query::Not(query) => !query.matches(entry),
+            Query::And(a, b) => a.matches(entry) && b.matches(entry),
+            Query::Or(a, b) => a.matches(entry) || b.matches(entry),
+        }
+    }
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Query::Tag(tag) => write!(f, "{}", tag),
+            Query::Rating(comparison, stars) => {
+                write!(f, "rating {} {}", comparison.symbol(), stars)
+            }
+            Query::Label(label) => write!(f, "label {}", label.name()),
+            Query::Not(query) => write!(f, "NOT {}", query),
+            Query::And(a, b) => write!(f, "({} AND {})", a, b),
+            Query::Or(a, b) => write!(f, "({} OR {})", a, b),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Keyword {
+    And,
+    Or,
+    Not,
+    Rating,
+    Label,
+}
+
+impl Keyword {
+    fn parse(word: &str) -> Option<Self> {
+        match word.to_ascii_lowercase().as_str() {
+            "and" => Some(Keyword::And),
+            "or" => Some(Keyword::Or),
+            "not" => Some(Keyword::Not),
+            "rating" => Some(Keyword::Rating),
+            "label" => Some(Keyword::Label),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Keyword::And => "AND",
+            Keyword::Or => "OR",
+            Keyword::Not => "NOT",
+            Keyword::Rating => "rating",
+            Keyword::Label => "label",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Keyword(Keyword),
+    Comparison(Comparison),
+    Comma,
+    Open,
+    Close,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{}", word),
+            Token::Keyword(keyword) => write!(f, "{}", keyword.name()),
+            Token::Comparison(comparison) => write!(f, "{}", comparison.symbol()),
+            Token::Comma => write!(f, ","),
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            ',' => Token::Comma,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '=' => Token::Comparison(Comparison::Equal),
+            '≤' => Token::Comparison(Comparison::LessOrEqual),
+            '≥' => Token::Comparison(Comparison::GreaterOrEqual),
+            '<' | '>' => {
+                let or_equal = chars.next_if_eq(&'=').is_some();
+                Token::Comparison(match (c, or_equal) {
+                    ('<', false) => Comparison::Less,
+                    ('<', true) => Comparison::LessOrEqual,
+                    (_, false) => Comparison::Greater,
+                    (_, true) => Comparison::GreaterOrEqual,
+                })
+            }
+            c if c.is_alphanumeric() || c == '-' || c == '_' || c == '#' => {
+                let mut word = c.to_string();
+                while let Some(c) =
+                    chars.next_if(|c| c.is_alphanumeric() || *c == '-' || *c == '_' || *c == '#')
+                {
+                    word.push(c);
+                }
+                match Keyword::parse(&word) {
+                    Some(keyword) => Token::Keyword(keyword),
+                    None => Token::Word(word.to_lowercase()),
+                }
+            }
+            c => return Err(anyhow!("unexpected {} in query", c)),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent over the tokens, one method per level of precedence.
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<&'a Token> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .ok_or_else(|| anyhow!("unexpected end of query"))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn list(&mut self) -> Result<Query> {
+        let mut query = self.or()?;
+        while self.peek() == Some(&Token::Comma) {
+            self.position += 1;
+            query = Query::And(Box::new(query), Box::new(self.or()?));
+        }
+        Ok(query)
+    }
+
+    fn or(&mut self) -> Result<Query> {
+        let mut query = self.and()?;
+        while self.peek() == Some(&Token::Keyword(Keyword::Or)) {
+            self.position += 1;
+            query = Query::Or(Box::new(query), Box::new(self.and()?));
+        }
+        Ok(query)
+    }
+
+    fn and(&mut self) -> Result<Query> {
+        let mut query = self.not()?;
+        loop {
+            match self.peek() {
+                Some(Token::Keyword(Keyword::And)) => self.position += 1,
+                Some(Token::Comma | Token::Close | Token::Keyword(Keyword::Or)) | None => {
+                    return Ok(query)
+                }
+                // Juxtaposed terms
+                Some(_) => {}
+            }
+            query = Query::And(Box::new(query), Box::new(self.not()?));
+        }
+    }
+
+    fn not(&mut self) -> Result<Query> {
+        if self.peek() == Some(&Token::Keyword(Keyword::Not)) {
+            self.position += 1;
+            return Ok(Query::Not(Box::new(self.not()?)));
+        }
+        self.term()
+    }
+
+    fn term(&mut self) -> Result<Query> {
+        match self.next()? {
+            Token::Word(tag) => Ok(Query::Tag(tag.clone())),
+            Token::Open => {
+                let query = self.list()?;
+                match self.next()? {
+                    Token::Close => Ok(query),
+                    token => Err(anyhow!("expected ) in query, got {}", token)),
+                }
+            }
+            Token::Keyword(Keyword::Rating) => {
+                let comparison = match self.next()? {
+                    Token::Comparison(comparison) => *comparison,
+                    token => {
+                        return Err(anyhow!("expected a comparison after rating, got {}", token))
+                    }
+                };
+                let stars = match self.next()? {
+                    Token::Word(word) => word.parse::<u8>().ok(),
+                    _ => None,
+                };
+                match stars {
+                    Some(stars) if stars <= MAX_RATING => Ok(Query::Rating(comparison, stars)),
+                    _ => Err(anyhow!("expected a rating from 0 to {}", MAX_RATING)),
+                }
+            }
+            Token::Keyword(Keyword::Label) => {
+                if self.peek() == Some(&Token::Comparison(Comparison::Equal)) {
+                    self.position += 1;
+                }
+                match self.next()? {
+                    Token::Word(label) => Ok(Query::Label(Label::parse(label)?)),
+                    token => Err(anyhow!("expected a label color, got {}", token)),
+                }
+            }
+            token => Err(anyhow!("unexpected {} in query", token)),
+        }
+    }
+}
@@ -1,30 +1,11 @@
-extern crate anyhow;
-extern crate atomic_float;
-extern crate portaudio;
-
-#[macro_use]
-extern crate lazy_static;
-
-mod app;
-mod engine;
-mod env;
-mod input;
-mod param;
-mod pattern;
-mod sampler;
-mod ui;
-
-use anyhow::Result;
-use app::{Action, App, AppCommand};
-use camino::Utf8PathBuf;
-use engine::{Engine, EngineCommand, EngineParams};
-use portaudio::stream_flags as paflags;
-use portaudio::OutputStreamCallbackArgs;
-use portaudio::PortAudio;
+use anyhow::{anyhow, Result};
 use ringbuf::RingBuffer;
-
-const SAMPLE_RATE: f64 = 44_100.0;
-const FRAMES_PER_BUFFER: u32 = 256;
+use ruis::app::{Action, App, AppCommand};
+use ruis::audio::jack::JackBackend;
+use ruis::audio::{AudioBackend, PortAudioBackend};
+use ruis::engine::{Engine, EngineCommand, EngineConfig, EngineParams};
+use ruis::paths::{Layout, Paths};
+use ruis::{bench, crash, demo};
 
 fn main() {
     match run() {
@@ -36,61 +17,114 @@ fn main() {
 }
 
 fn run() -> Result<()> {
+    // Benchmarks render offline, without opening the audio backend.
+    if std::env::args().nth(1).as_deref() == Some("bench") {
+        return bench::run();
+    }
+    let mut backend: Box<dyn AudioBackend> = Box::new(PortAudioBackend::new()?);
+    let mut device = None;
+    let mut config = EngineConfig::default();
+    let mut layout = Layout::Local;
+    let mut demo = false;
+    let mut osc_port = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--backend" => {
+                backend = match args.next().as_deref() {
+                    Some("portaudio") => Box::new(PortAudioBackend::new()?),
+                    Some("jack") => Box::new(JackBackend::new()),
+                    _ => return Err(anyhow!("expected --backend portaudio|jack")),
+                }
+            }
+            "--list-devices" => {
+                println!("{} output devices:", backend.name());
+                for info in backend.devices()? {
+                    println!(
+                        "{}{} ({}, {} channels, {} Hz)",
+                        if info.is_default { "* " } else { "  " },
+                        info.name,
+                        info.host,
+                        info.channels,
+                        info.default_sample_rate
+                    );
+                }
+                return Ok(());
+            }
+            "--device" => device = args.next(),
+            "--portable" => layout = Layout::Portable,
+            "--dirs" => match args.next() {
+                Some(name) => layout = Layout::parse(&name)?,
+                None => return Err(anyhow!("expected --dirs local|platform|portable")),
+            },
+            "--input-device" => match args.next() {
+                Some(name) => backend.set_input(&name)?,
+                None => return Err(anyhow!("expected --input-device <name>")),
+            },
+            "--sample-rate" => match args.next().map(|rate| rate.parse()) {
+                Some(Ok(rate)) => config.sample_rate = rate,
+                _ => return Err(anyhow!("expected --sample-rate <rate>")),
+            },
+            "--buffer-size" => match args.next().map(|size| size.parse()) {
+                Some(Ok(size)) => config.buffer_size = size,
+                _ => return Err(anyhow!("expected --buffer-size <frames>")),
+            },
+            "--threads" => match args.next().map(|threads| threads.parse()) {
+                Some(Ok(threads)) => config.threads = threads,
+                _ => return Err(anyhow!("expected --threads <count>")),
+            },
+            "--osc" => match args.next().map(|port| port.parse()) {
+                Some(Ok(port)) => osc_port = Some(port),
+                _ => return Err(anyhow!("expected --osc <port>")),
+            },
+            "demo" => demo = true,
+            _ => return Err(anyhow!("unknown argument {}", arg)),
+        }
+    }
+
     let (engine_send, engine_rcv) = RingBuffer::<EngineCommand>::new(16).split();
-    let (app_send, app_recv) = RingBuffer::<AppCommand>::new(16).split();
+    // Room for the garbage of a burst of commands on top of the position updates.
+    let (app_send, app_recv) = RingBuffer::<AppCommand>::new(256).split();
 
     let params = EngineParams::default();
-    let engine = Engine::new(params.clone(), engine_rcv, app_send);
-    let mut app = App::new(params, app_recv, engine_send)?;
-    let mut stream = run_audio(engine)?;
+    let engine = Engine::new(config, params.clone(), engine_rcv, app_send);
+    let paths = Paths::new(layout)?;
+    paths.create()?;
+    crash::install(paths.crashes());
+    let mut app = App::new(params, app_recv, engine_send, paths.clone())?;
+    backend.start(engine, device.as_deref())?;
+    if osc_port.is_some() {
+        app.take(Action::Osc(osc_port))?;
+    }
+
+    if demo {
+        let project = demo::project(app.project(), &paths.sounds.join("demo"))?;
+        app.load_project(project)?;
+        app.take(Action::TogglePlay)?;
+        let result = app.run(backend.as_mut());
+        backend.stop()?;
+        return result;
+    }
 
-    // Load some default sounds for easier testing
-    for (i, path) in vec![
-        "sounds/kick.wav",
-        "sounds/snare.wav",
-        "sounds/hihat-open.wav",
-        "sounds/hihat-closed.wav",
-        "sounds/chord.wav",
-        "sounds/bass.wav",
+    // Load some default sounds for easier testing, when the library has them
+    for (i, name) in vec![
+        "kick.wav",
+        "snare.wav",
+        "hihat-open.wav",
+        "hihat-closed.wav",
+        "chord.wav",
+        "bass.wav",
     ]
     .iter()
     .enumerate()
     {
-        app.take(Action::LoadSound(i, Utf8PathBuf::from(path)))?;
+        let path = paths.sounds.join(name);
+        if path.exists() {
+            app.take(Action::LoadSound(i, path))?;
+        }
     }
 
-    let result = app.run();
-
-    stream.stop()?;
-    stream.close()?;
-
+    let result = app.run(backend.as_mut());
+    backend.stop()?;
     result
 }
-
-type AudioStream = portaudio::Stream<portaudio::NonBlocking, portaudio::Output<f32>>;
-
-fn run_audio(mut engine: Engine) -> Result<AudioStream> {
-    let pa = PortAudio::new()?;
-    let mut settings =
-        pa.default_output_stream_settings::<f32>(2, SAMPLE_RATE, FRAMES_PER_BUFFER)?;
-    settings.flags = paflags::CLIP_OFF;
-
-    let mut buf = [(0., 0.); FRAMES_PER_BUFFER as usize];
-    let callback = move |OutputStreamCallbackArgs { buffer, .. }| {
-        engine.render(&mut buf);
-
-        let mut i = 0;
-        for j in 0..buf.len() {
-            buffer[i] = buf[j].0;
-            buffer[i + 1] = buf[j].1;
-            i += 2;
-            buf[j] = (0.0, 0.0);
-        }
-
-        portaudio::Continue
-    };
-
-    let mut stream = pa.open_non_blocking_stream(settings, callback)?;
-    stream.start()?;
-    Ok(stream)
-}
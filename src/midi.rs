@@ -0,0 +1,435 @@
+use crate::engine::{Device, EngineConfig};
+use crate::harmony::{MAX_LIVE_NOTES, MAX_REMOTE_NOTES, NUM_COLUMNS};
+use crate::instrument::Instrument;
+use crate::pattern::{self, Editor, TICKS_PER_LINE};
+use crate::rtlog::{self, Message};
+use anyhow::{anyhow, Result};
+use camino::Utf8Path;
+use ringbuf::{Producer, RingBuffer};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const VELOCITY: u8 = 80;
+// The editor uses 0 based octaves, so C-4 (48) is middle C which is 60 in MIDI.
+const PITCH_OFFSET: u8 = 12;
+
+/// Writes every pattern, one after the other, as a type 1 Standard MIDI File. The first track
+/// holds the tempo map, every tracker track with notes gets its own MIDI track.
+pub fn export(editor: &Editor, bpm: u16, lines_per_beat: u16, path: &Utf8Path) -> Result<()> {
+    // Ticks per beat, the top bit of the field is for SMPTE timing
+    let division = lines_per_beat as u32 * TICKS_PER_LINE;
+    if division == 0 || division > 0x7fff {
+        return Err(anyhow!(
+            "can't export {} lines per beat, expected 1 to {}",
+            lines_per_beat,
+            0x7fff / TICKS_PER_LINE
+        ));
+    }
+    let mut tracks = vec![tempo_track(bpm)];
+    let end = song_end(editor);
+    for i in 0..editor.current_pattern().num_tracks() {
+        let events = track_events(editor, i);
+        if !events.is_empty() {
+            tracks.push(note_track(i, &events, end));
+        }
+    }
+
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(b"MThd")?;
+    out.write_all(&6u32.to_be_bytes())?;
+    out.write_all(&1u16.to_be_bytes())?;
+    out.write_all(&(tracks.len() as u16).to_be_bytes())?;
+    out.write_all(&(division as u16).to_be_bytes())?;
+    for track in tracks {
+        out.write_all(b"MTrk")?;
+        out.write_all(&(track.len() as u32).to_be_bytes())?;
+        out.write_all(&track)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Note ons and offs of a track over every pattern, in MIDI ticks. The notes are the ones
+/// playback plays, see `Editor::iter_notes`: tracks with their own length start over until the
+/// end of the pattern, shifting tracks rotate and chords are expanded into their notes. A note
+/// still playing at the end of a pattern lasts until the next one ends it.
+fn track_events(editor: &Editor, i: usize) -> Vec<(u32, [u8; 3])> {
+    let mut events = Vec::new();
+    let mut playing: Vec<u8> = Vec::new();
+    let lines = editor
+        .patterns()
+        .iter()
+        .enumerate()
+        .flat_map(|(index, pattern)| (0..pattern.num_lines).map(move |line| (index, line)));
+    for (song_line, (index, line)) in lines.enumerate() {
+        let mut notes = editor
+            .pattern_notes(index, line as u64)
+            .filter(|note| note.track as usize == i);
+        // The root comes first, the rest of a chord shares its offset
+        let root = match notes.next() {
+            Some(root) => root,
+            None => continue,
+        };
+        // Early steps on the first line can't start before the song
+        let tick = (song_line as i64 * TICKS_PER_LINE as i64 + root.offset as i64).max(0) as u32;
+        for prev in playing.drain(..) {
+            events.push((tick, [0x80 | channel(i), prev, 0]));
+        }
+        if pattern::ends_note(root.pitch) {
+            continue;
+        }
+        for note in std::iter::once(root).chain(notes) {
+            let pitch = u8::min(note.pitch.saturating_add(PITCH_OFFSET), 127);
+            events.push((tick, [0x90 | channel(i), pitch, VELOCITY]));
+            playing.push(pitch);
+        }
+    }
+    for prev in playing {
+        events.push((song_end(editor), [0x80 | channel(i), prev, 0]));
+    }
+    events
+}
+
+/// The end of the last pattern, in MIDI ticks.
+fn song_end(editor: &Editor) -> u32 {
+    let num_lines: usize = editor.patterns().iter().map(|p| p.num_lines).sum();
+    num_lines as u32 * TICKS_PER_LINE
+}
+
+fn channel(track: usize) -> u8 {
+    (track % 16) as u8
+}
+
+fn tempo_track(bpm: u16) -> Vec<u8> {
+    let mut data = Vec::new();
+    let micros_per_beat = 60_000_000 / u32::max(bpm as u32, 1);
+    write_var_len(&mut data, 0);
+    data.extend_from_slice(&[0xff, 0x51, 0x03]);
+    data.extend_from_slice(&micros_per_beat.to_be_bytes()[1..]);
+    // 4/4, 24 clocks per metronome click, 8 32nd notes per quarter
+    write_var_len(&mut data, 0);
+    data.extend_from_slice(&[0xff, 0x58, 0x04, 4, 2, 24, 8]);
+    write_var_len(&mut data, 0);
+    data.extend_from_slice(&[0xff, 0x2f, 0x00]);
+    data
+}
+
+fn note_track(index: usize, events: &[(u32, [u8; 3])], end: u32) -> Vec<u8> {
+    let mut data = Vec::new();
+    let name = format!("Track {}", index);
+    write_var_len(&mut data, 0);
+    data.extend_from_slice(&[0xff, 0x03]);
+    write_var_len(&mut data, name.len() as u32);
+    data.extend_from_slice(name.as_bytes());
+
+    let mut last = 0;
+    for (tick, msg) in events {
+        write_var_len(&mut data, tick - last);
+        data.extend_from_slice(msg);
+        last = *tick;
+    }
+    write_var_len(&mut data, end - last);
+    data.extend_from_slice(&[0xff, 0x2f, 0x00]);
+    data
+}
+
+fn write_var_len(data: &mut Vec<u8>, mut value: u32) {
+    let mut bytes = [0u8; 4];
+    let mut i = bytes.len() - 1;
+    bytes[i] = (value & 0x7f) as u8;
+    value >>= 7;
+    while value > 0 {
+        i -= 1;
+        bytes[i] = (value & 0x7f) as u8 | 0x80;
+        value >>= 7;
+    }
+    data.extend_from_slice(&bytes[i..]);
+}
+
+struct MidiMessage {
+    deadline: Instant,
+    data: [u8; 3],
+}
+
+/// A pseudo instrument which forwards notes to an external MIDI port instead of rendering
+/// audio. Messages are timestamped against the frames rendered so far and written to the port
+/// by a separate thread, so notes triggered in the middle of a buffer keep their timing.
+pub struct MidiOut {
+    prod: Producer<MidiMessage>,
+    /// Set when the instrument is dropped, the writer thread ends once it sent the rest.
+    closed: Arc<AtomicBool>,
+    channel: u8,
+    active: Vec<(usize, u8)>,
+    clock: u64,
+    epoch: Option<Instant>,
+    sample_rate: f64,
+}
+
+impl MidiOut {
+    pub fn open(port: &Utf8Path, channel: u8) -> Result<Self> {
+        if channel > 15 {
+            return Err(anyhow!("MIDI channel must be between 1 and 16"));
+        }
+        let mut file = OpenOptions::new().write(true).open(port)?;
+        let (prod, mut cons) = RingBuffer::<MidiMessage>::new(256).split();
+        let closed = Arc::new(AtomicBool::new(false));
+        let writer_closed = Arc::clone(&closed);
+        thread::spawn(move || loop {
+            match cons.pop() {
+                Some(msg) => {
+                    let now = Instant::now();
+                    if msg.deadline > now {
+                        thread::sleep(msg.deadline - now);
+                    }
+                    if file.write_all(&msg.data).is_err() {
+                        return;
+                    }
+                }
+                // Messages sent before the instrument was dropped, such as the note offs of
+                // `stop`, are still written.
+                None if writer_closed.load(Ordering::Acquire) && cons.is_empty() => return,
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+        });
+        Ok(Self {
+            prod,
+            closed,
+            channel,
+            active: Vec::with_capacity(NUM_COLUMNS + MAX_LIVE_NOTES + MAX_REMOTE_NOTES),
+            clock: 0,
+            epoch: None,
+            sample_rate: EngineConfig::default().sample_rate,
+        })
+    }
+
+    fn release(&mut self, index: usize) {
+        let (_, pitch) = self.active.swap_remove(index);
+        self.send([0x80 | self.channel, pitch, 0]);
+    }
+
+    fn clock_time(&self) -> Duration {
+        Duration::from_secs_f64(self.clock as f64 / self.sample_rate)
+    }
+
+    fn send(&mut self, data: [u8; 3]) {
+        let epoch = *self.epoch.get_or_insert_with(Instant::now);
+        let deadline = epoch + self.clock_time();
+        if self.prod.push(MidiMessage { deadline, data }).is_err() {
+            rtlog::log(Message::MidiEventDropped);
+        }
+    }
+}
+
+impl Device for MidiOut {
+    fn render(&mut self, buffer: &mut [(f32, f32)]) {
+        // Keep the frame clock in line with the wall clock, resync after underruns or when the
+        // audio device drifts by more than a buffer.
+        let now = Instant::now();
+        let elapsed = self.clock_time();
+        let in_sync = match self.epoch {
+            Some(epoch) => {
+                let expected = epoch + elapsed;
+                now < expected + MAX_DRIFT && expected < now + MAX_DRIFT
+            }
+            None => false,
+        };
+        if !in_sync {
+            self.epoch = Some(now.checked_sub(elapsed).unwrap_or(now));
+        }
+        self.clock += buffer.len() as u64;
+    }
+}
+
+impl Instrument for MidiOut {
+    fn note_on(&mut self, column: usize, pitch: u8, velocity: u8) {
+        self.note_off(column);
+        let pitch = u8::min(pitch.saturating_add(PITCH_OFFSET), 127);
+        self.send([0x90 | self.channel, pitch, velocity]);
+        self.active.push((column, pitch));
+    }
+
+    fn note_off(&mut self, column: usize) {
+        if let Some(index) = self.active.iter().position(|(c, _)| *c == column) {
+            self.release(index);
+        }
+    }
+
+    fn prepare(&mut self, config: &EngineConfig) {
+        // The clock is counted in frames, so start a new epoch at the new rate.
+        self.sample_rate = config.sample_rate;
+        self.clock = 0;
+        self.epoch = None;
+    }
+
+    fn stop(&mut self) {
+        while !self.active.is_empty() {
+            self.release(self.active.len() - 1);
+        }
+    }
+
+    fn active_voices(&self) -> usize {
+        self.active.len()
+    }
+}
+
+impl Drop for MidiOut {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Release);
+    }
+}
+
+const MAX_DRIFT: Duration = Duration::from_millis(50);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::euclid::Euclid;
+    use crate::harmony::Chord;
+    use crate::pattern::Step;
+    use camino::Utf8PathBuf;
+    use std::fs;
+
+    fn temp_path(name: &str) -> Utf8PathBuf {
+        let dir = Utf8PathBuf::from_path_buf(std::env::temp_dir()).unwrap();
+        dir.join(format!("ruis-midi-{}-{}", std::process::id(), name))
+    }
+
+    const LINE: u32 = TICKS_PER_LINE;
+
+    fn note_on(pitch: u8) -> [u8; 3] {
+        [0x90, pitch + PITCH_OFFSET, VELOCITY]
+    }
+
+    fn note_off(pitch: u8) -> [u8; 3] {
+        [0x80, pitch + PITCH_OFFSET, 0]
+    }
+
+    fn events(editor: &Editor) -> Vec<(u32, [u8; 3])> {
+        track_events(editor, 0)
+    }
+
+    #[test]
+    fn tracks_loop_over_their_own_length() {
+        let mut editor = Editor::new();
+        editor.set_num_lines(5);
+        let (pattern, track) = (editor.current_pattern().id, editor.track_ids()[0]);
+        editor.set_track_length(pattern, track, Some(2)).unwrap();
+        editor.write_note(0, 0, 48, 0);
+        assert_eq!(
+            events(&editor),
+            [
+                (0, note_on(48)),
+                (2 * LINE, note_off(48)),
+                (2 * LINE, note_on(48)),
+                (4 * LINE, note_off(48)),
+                (4 * LINE, note_on(48)),
+                (5 * LINE, note_off(48)),
+            ]
+        );
+    }
+
+    #[test]
+    fn euclid_tracks_rotate() {
+        let mut editor = Editor::new();
+        editor.set_num_lines(6);
+        let (pattern, track) = (editor.current_pattern().id, editor.track_ids()[0]);
+        editor.set_track_length(pattern, track, Some(3)).unwrap();
+        let step = Step {
+            pitch: Some(48),
+            ..Step::default()
+        };
+        let euclid = Euclid::new(1, 3, 0, 1).unwrap();
+        editor.fill_euclid(pattern, track, &euclid, step).unwrap();
+        // The second time around the step plays a line later
+        assert_eq!(
+            events(&editor),
+            [
+                (0, note_on(48)),
+                (4 * LINE, note_off(48)),
+                (4 * LINE, note_on(48)),
+                (6 * LINE, note_off(48)),
+            ]
+        );
+    }
+
+    #[test]
+    fn patterns_play_one_after_the_other() {
+        let mut editor = Editor::new();
+        editor.set_num_lines(4);
+        editor.write_note(0, 0, 48, 0);
+        editor.add_pattern(vec![vec![Step::default(); 2]]).unwrap();
+        editor.write_note(0, 1, 50, 0);
+        // The note of the first pattern lasts until the second one ends it
+        assert_eq!(
+            events(&editor),
+            [
+                (0, note_on(48)),
+                (5 * LINE, note_off(48)),
+                (5 * LINE, note_on(50)),
+                (6 * LINE, note_off(50)),
+            ]
+        );
+    }
+
+    #[test]
+    fn division_fits_the_header() {
+        let path = temp_path("division.mid");
+        let editor = Editor::new();
+        export(&editor, 120, 4, &path).unwrap();
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data[12..14], (4 * TICKS_PER_LINE as u16).to_be_bytes());
+        fs::remove_file(&path).unwrap();
+        assert!(export(&editor, 120, 0, &path).is_err());
+        let too_many = (0x7fff / TICKS_PER_LINE + 1) as u16;
+        assert!(export(&editor, 120, too_many, &path).is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn chords_play_and_end_every_note() {
+        let mut editor = Editor::new();
+        editor.set_num_lines(4);
+        editor.write_note(0, 0, 48, 0);
+        editor.set_chord(Some(Chord::Triad));
+        editor.write_note(0, 2, 50, 0);
+        assert_eq!(
+            events(&editor),
+            [
+                (0, note_on(48)),
+                (0, note_on(52)),
+                (0, note_on(55)),
+                (2 * LINE, note_off(48)),
+                (2 * LINE, note_off(52)),
+                (2 * LINE, note_off(55)),
+                (2 * LINE, note_on(50)),
+                (4 * LINE, note_off(50)),
+            ]
+        );
+    }
+
+    #[test]
+    fn writer_ends_after_sending_the_rest() {
+        let path = temp_path("port");
+        File::create(&path).unwrap();
+        let mut midi = MidiOut::open(&path, 0).unwrap();
+        let closed = Arc::clone(&midi.closed);
+        midi.note_on(0, 48, 100);
+        midi.stop();
+        drop(midi);
+
+        let start = Instant::now();
+        while Arc::strong_count(&closed) > 1 {
+            assert!(
+                start.elapsed() < Duration::from_secs(1),
+                "writer still running"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(fs::read(&path).unwrap(), [0x90, 60, 100, 0x80, 60, 0]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,788 @@
+use crate::effect::{Effect, MAX_EFFECTS};
+use crate::engine::{Device, EngineConfig, MAX_INSTRUMENTS};
+use crate::MAX_FRAMES_PER_BUFFER;
+use anyhow::{anyhow, Result};
+use atomic_float::AtomicF32;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+pub const MIN_GAIN: f32 = -60.0;
+pub const MAX_GAIN: f32 = 6.0;
+
+/// Number of aux buses. Every bus has a return channel after the instrument channels.
+pub const NUM_BUSES: usize = 2;
+
+/// Buses are named by letter, starting at A.
+pub fn bus_name(bus: usize) -> char {
+    (b'A' + bus as u8) as char
+}
+
+/// Index of the mixer channel which returns `bus` to the mix.
+pub fn return_channel(bus: usize) -> usize {
+    MAX_INSTRUMENTS + bus
+}
+
+/// Index of the master channel, which processes the whole mix. It comes after the returns.
+pub const MASTER_CHANNEL: usize = MAX_INSTRUMENTS + NUM_BUSES;
+
+/// Number of stereo hardware inputs channels can take their signal from.
+pub const NUM_INPUTS: usize = 2;
+
+/// Where the signal of an instrument channel comes from.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Source {
+    /// The instrument in the channel's slot.
+    #[default]
+    Instrument,
+    /// A stereo hardware input.
+    Input(usize),
+    /// The sum of an aux bus, e.g. to process it differently from its return. A channel fed by
+    /// a bus doesn't send to the buses itself.
+    Bus(usize),
+}
+
+impl Source {
+    /// Parses `inst`, `in<input>` counting from 1, or `bus<bus>`.
+    pub fn parse(name: &str) -> Result<Self> {
+        let invalid = || {
+            anyhow!(
+                "invalid source {}, expected inst|in<1-{}>|bus<bus>",
+                name,
+                NUM_INPUTS
+            )
+        };
+        if name == "inst" {
+            return Ok(Source::Instrument);
+        }
+        if let Some(input) = name.strip_prefix("in") {
+            let input: usize = input.parse().map_err(|_| invalid())?;
+            return match input {
+                1..=NUM_INPUTS => Ok(Source::Input(input - 1)),
+                _ => Err(invalid()),
+            };
+        }
+        if let Some(bus) = name.strip_prefix("bus") {
+            let mut chars = bus.chars();
+            if let (Some(c), None) = (chars.next(), chars.next()) {
+                let bus = (c.to_ascii_uppercase() as usize).wrapping_sub('A' as usize);
+                if bus < NUM_BUSES {
+                    return Ok(Source::Bus(bus));
+                }
+            }
+        }
+        Err(invalid())
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Source::Instrument => String::from("inst"),
+            Source::Input(input) => format!("in{}", input + 1),
+            Source::Bus(bus) => format!("bus{}", bus_name(*bus)),
+        }
+    }
+
+    fn encode(self) -> usize {
+        match self {
+            Source::Instrument => 0,
+            Source::Input(input) => 1 + input,
+            Source::Bus(bus) => 1 + NUM_INPUTS + bus,
+        }
+    }
+
+    fn decode(value: usize) -> Self {
+        match value {
+            0 => Source::Instrument,
+            value if value <= NUM_INPUTS => Source::Input(value - 1),
+            value => Source::Bus(value - 1 - NUM_INPUTS),
+        }
+    }
+}
+
+/// Level of a channel's send to an aux bus.
+#[derive(Clone)]
+pub struct SendParams {
+    /// Level in dB, the send is off at `MIN_GAIN`.
+    pub level: Arc<AtomicF32>,
+    /// Sends the signal before the channel's gain, pan and mute instead of after them.
+    pub pre_fader: Arc<AtomicBool>,
+}
+
+impl Default for SendParams {
+    fn default() -> Self {
+        Self {
+            level: Arc::new(AtomicF32::new(MIN_GAIN)),
+            pre_fader: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl SendParams {
+    pub fn set_level(&self, level: f32) {
+        self.level
+            .store(level.clamp(MIN_GAIN, MAX_GAIN), Ordering::Relaxed);
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.level.load(Ordering::Relaxed) > MIN_GAIN
+    }
+
+    fn gain(&self) -> f32 {
+        match self.is_on() {
+            true => db_to_gain(self.level.load(Ordering::Relaxed)),
+            false => 0.0,
+        }
+    }
+}
+
+/// Settings of a channel strip, shared between the app and the engine.
+#[derive(Clone)]
+pub struct ChannelParams {
+    /// Output trim of the instrument on the channel in dB, applied to the instrument's output
+    /// before the inserts. It isn't an instrument param, so it can't be automated.
+    pub trim: Arc<AtomicF32>,
+    /// Gain in dB.
+    pub gain: Arc<AtomicF32>,
+    /// Balance from -1 (left) to 1 (right).
+    pub pan: Arc<AtomicF32>,
+    pub mute: Arc<AtomicBool>,
+    pub solo: Arc<AtomicBool>,
+    /// Sends to every aux bus, unused on return channels.
+    pub sends: Vec<SendParams>,
+    /// Encoded `Source`, only used on instrument channels.
+    source: Arc<AtomicUsize>,
+    peak: Arc<AtomicF32>,
+    /// Frames the inserts which aren't bypassed delay the channel by, as of the last buffer.
+    latency: Arc<AtomicUsize>,
+}
+
+impl Default for ChannelParams {
+    fn default() -> Self {
+        Self {
+            trim: Arc::new(AtomicF32::new(0.0)),
+            gain: Arc::new(AtomicF32::new(0.0)),
+            pan: Arc::new(AtomicF32::new(0.0)),
+            mute: Arc::new(AtomicBool::new(false)),
+            solo: Arc::new(AtomicBool::new(false)),
+            sends: (0..NUM_BUSES).map(|_| SendParams::default()).collect(),
+            source: Arc::new(AtomicUsize::new(0)),
+            peak: Arc::new(AtomicF32::new(0.0)),
+            latency: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl ChannelParams {
+    /// Returns the highest output level since the last call, for meters.
+    pub fn take_peak(&self) -> f32 {
+        self.peak.swap(0.0, Ordering::Relaxed)
+    }
+
+    pub fn latency(&self) -> usize {
+        self.latency.load(Ordering::Relaxed)
+    }
+
+    pub fn source(&self) -> Source {
+        Source::decode(self.source.load(Ordering::Relaxed))
+    }
+
+    pub fn set_source(&self, source: Source) {
+        self.source.store(source.encode(), Ordering::Relaxed);
+    }
+
+    pub fn set_trim(&self, trim: f32) {
+        self.trim
+            .store(trim.clamp(MIN_GAIN, MAX_GAIN), Ordering::Relaxed);
+    }
+
+    pub fn set_gain(&self, gain: f32) {
+        self.gain
+            .store(gain.clamp(MIN_GAIN, MAX_GAIN), Ordering::Relaxed);
+    }
+
+    pub fn set_pan(&self, pan: f32) {
+        self.pan.store(pan.clamp(-1.0, 1.0), Ordering::Relaxed);
+    }
+
+    pub fn toggle_mute(&self) {
+        self.mute.fetch_xor(true, Ordering::Relaxed);
+    }
+
+    pub fn toggle_solo(&self) {
+        self.solo.fetch_xor(true, Ordering::Relaxed);
+    }
+}
+
+/// A channel strip for every instrument slot, followed by the return channels of the aux
+/// buses and the master channel.
+#[derive(Clone)]
+pub struct MixerParams {
+    pub channels: Vec<ChannelParams>,
+    /// Set when the master output had an inter-sample peak above 0 dBTP, until cleared.
+    pub over: Arc<AtomicBool>,
+    /// Removes DC offset from the master output, after its gain.
+    pub dc_block: Arc<AtomicBool>,
+    /// Soft clips the master output after the DC blocker, so it stays below 0 dBFS.
+    pub clip: Arc<AtomicBool>,
+}
+
+impl Default for MixerParams {
+    fn default() -> Self {
+        Self {
+            channels: (0..=MASTER_CHANNEL)
+                .map(|_| ChannelParams::default())
+                .collect(),
+            over: Arc::new(AtomicBool::new(false)),
+            dc_block: Arc::new(AtomicBool::new(true)),
+            clip: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl MixerParams {
+    pub fn is_over(&self) -> bool {
+        self.over.load(Ordering::Relaxed)
+    }
+
+    pub fn clear_over(&self) {
+        self.over.store(false, Ordering::Relaxed);
+    }
+
+    pub fn toggle_dc_block(&self) {
+        self.dc_block.fetch_xor(true, Ordering::Relaxed);
+    }
+
+    pub fn toggle_clip(&self) {
+        self.clip.fetch_xor(true, Ordering::Relaxed);
+    }
+
+    /// Whether any instrument channel sends to `bus`.
+    pub fn bus_in_use(&self, bus: usize) -> bool {
+        self.channels[..MAX_INSTRUMENTS]
+            .iter()
+            .any(|channel| channel.sends[bus].is_on())
+    }
+}
+
+struct Insert {
+    effect: Box<dyn Effect>,
+    bypass: Arc<AtomicBool>,
+}
+
+/// Applies the channel strips and sums the instruments into the output. Channels can also
+/// feed the aux buses, which are summed into the output through their return channels with
+/// `render_return` once all channels have been mixed.
+pub struct Mixer {
+    params: MixerParams,
+    /// Effects per channel, processed in order before the gain and pan.
+    chains: Vec<Vec<Insert>>,
+    /// Gain applied at the end of the previous block per channel, to ramp towards changes.
+    gains: Vec<(f32, f32)>,
+    /// Same as `gains`, for the trim.
+    trims: Vec<f32>,
+    /// Same as `gains`, for the send of every channel to every bus.
+    send_gains: Vec<[f32; NUM_BUSES]>,
+    buses: Vec<Vec<(f32, f32)>>,
+    /// Hardware inputs for the current buffer, filled in by the audio backend.
+    inputs: Vec<Vec<(f32, f32)>>,
+    /// Order to process the instrument channels in. Channels with a sidechain input come after
+    /// the others so their key channels have been rendered first, and channels fed by a bus
+    /// come last so everything has been sent to the bus.
+    order: [usize; MAX_INSTRUMENTS],
+    /// Input of every channel used as a sidechain key, for the current buffer.
+    keys: Vec<Vec<(f32, f32)>>,
+    is_key: [bool; MAX_INSTRUMENTS],
+    any_solo: bool,
+    scratch: Vec<(f32, f32)>,
+    dc_blocker: DcBlocker,
+    true_peak: TruePeak,
+}
+
+impl Mixer {
+    pub fn new(params: MixerParams) -> Self {
+        let gains = params
+            .channels
+            .iter()
+            .map(|channel| channel_gain(channel, false))
+            .collect();
+        let chains = params
+            .channels
+            .iter()
+            .map(|_| Vec::with_capacity(MAX_EFFECTS))
+            .collect();
+        let trims = params
+            .channels
+            .iter()
+            .map(|channel| db_to_gain(channel.trim.load(Ordering::Relaxed)))
+            .collect();
+        let mut mixer = Self {
+            trims,
+            send_gains: vec![[0.0; NUM_BUSES]; params.channels.len()],
+            buses: vec![vec![(0., 0.); MAX_FRAMES_PER_BUFFER]; NUM_BUSES],
+            inputs: vec![vec![(0., 0.); MAX_FRAMES_PER_BUFFER]; NUM_INPUTS],
+            order: [0; MAX_INSTRUMENTS],
+            keys: vec![vec![(0., 0.); MAX_FRAMES_PER_BUFFER]; MAX_INSTRUMENTS],
+            is_key: [false; MAX_INSTRUMENTS],
+            params,
+            chains,
+            gains,
+            any_solo: false,
+            scratch: vec![(0., 0.); MAX_FRAMES_PER_BUFFER],
+            dc_blocker: DcBlocker::new(EngineConfig::default().sample_rate as f32),
+            true_peak: TruePeak::new(),
+        };
+        mixer.update_order();
+        mixer
+    }
+
+    /// The order in which instrument channels have to be rendered, so sidechain keys are
+    /// available when they're needed. A key channel which has a sidechain input itself is
+    /// only rendered with the other keyed channels, the ones before it hear a silent key.
+    pub fn order(&self) -> [usize; MAX_INSTRUMENTS] {
+        self.order
+    }
+
+    fn update_order(&mut self) {
+        let channels = &self.params.channels;
+        let rank = |i: usize, chain: &Vec<Insert>| match channels[i].source() {
+            Source::Bus(_) => 2,
+            _ if chain.iter().any(|i| i.effect.sidechain().is_some()) => 1,
+            _ => 0,
+        };
+        let mut len = 0;
+        for pass in 0..3 {
+            for (i, chain) in self.chains[..MAX_INSTRUMENTS].iter().enumerate() {
+                if rank(i, chain) == pass {
+                    self.order[len] = i;
+                    len += 1;
+                }
+            }
+        }
+        self.is_key = [false; MAX_INSTRUMENTS];
+        for insert in self.chains.iter().flatten() {
+            if let Some(key) = insert.effect.sidechain() {
+                if key < MAX_INSTRUMENTS {
+                    self.is_key[key] = true;
+                }
+            }
+        }
+    }
+
+    pub fn params(&self) -> &MixerParams {
+        &self.params
+    }
+
+    pub fn prepare(&mut self, config: &EngineConfig) {
+        for insert in self.chains.iter_mut().flatten() {
+            insert.effect.prepare(config);
+        }
+        self.dc_blocker = DcBlocker::new(config.sample_rate as f32);
+    }
+
+    /// Clears the state of every effect and of the DC blocker, so no tail of what was mixed
+    /// before comes out.
+    pub fn reset(&mut self) {
+        for insert in self.chains.iter_mut().flatten() {
+            insert.effect.reset();
+        }
+        self.dc_blocker.input = (0.0, 0.0);
+        self.dc_blocker.output = (0.0, 0.0);
+    }
+
+    /// Inserts an effect in a channel at `index`, or at the end when `index` is past the end.
+    /// The effect must already be prepared. Chains are limited to `MAX_EFFECTS`, so they don't
+    /// allocate while playing.
+    pub fn insert_effect(
+        &mut self,
+        channel: usize,
+        index: usize,
+        effect: Box<dyn Effect>,
+        bypass: Arc<AtomicBool>,
+    ) -> Result<(), (Box<dyn Effect>, Arc<AtomicBool>)> {
+        match self.chains.get_mut(channel) {
+            Some(chain) if chain.len() < MAX_EFFECTS => {
+                let index = usize::min(index, chain.len());
+                chain.insert(index, Insert { effect, bypass });
+                Ok(())
+            }
+            _ => Err((effect, bypass)),
+        }
+    }
+
+    /// Takes an effect out of its chain and returns it, for the caller to drop.
+    pub fn remove_effect(
+        &mut self,
+        channel: usize,
+        index: usize,
+    ) -> Option<(Box<dyn Effect>, Arc<AtomicBool>)> {
+        let chain = self.chains.get_mut(channel)?;
+        if index < chain.len() {
+            let insert = chain.remove(index);
+            Some((insert.effect, insert.bypass))
+        } else {
+            None
+        }
+    }
+
+    pub fn move_effect(&mut self, channel: usize, from: usize, to: usize) {
+        if let Some(chain) = self.chains.get_mut(channel) {
+            if from < chain.len() && to < chain.len() {
+                let insert = chain.remove(from);
+                chain.insert(to, insert);
+            }
+        }
+    }
+
+    /// Passes the song tempo on to the effects, call before mixing a buffer.
+    pub fn set_tempo(&mut self, bpm: f32) {
+        for insert in self.chains.iter_mut().flatten() {
+            insert.effect.set_tempo(bpm);
+        }
+    }
+
+    /// Reads the solo state and clears the sidechain keys, call once before mixing a buffer.
+    pub fn begin(&mut self) {
+        self.update_order();
+        self.any_solo = self
+            .params
+            .channels
+            .iter()
+            .any(|channel| channel.solo.load(Ordering::Relaxed));
+        for (key, is_key) in self.keys.iter_mut().zip(self.is_key.iter()) {
+            if *is_key {
+                for frame in key.iter_mut() {
+                    *frame = (0.0, 0.0);
+                }
+            }
+        }
+    }
+
+    /// Runs the finished mix through the master channel: its inserts, gain and pan, then the
+    /// DC blocker and the soft clipper when they're on.
+    pub fn process_master(&mut self, buffer: &mut [(f32, f32)]) {
+        self.process(MASTER_CHANNEL, 0, buffer);
+    }
+
+    /// Renders a device through its channel strip and adds the result to `output`. `offset`
+    /// is the position of `output` in the buffer being mixed, for the sends.
+    pub fn render<D: Device + ?Sized>(
+        &mut self,
+        index: usize,
+        offset: usize,
+        device: &mut D,
+        output: &mut [(f32, f32)],
+    ) {
+        let mut scratch = std::mem::take(&mut self.scratch);
+        let chunk_size = scratch.len();
+        for (i, output) in output.chunks_mut(chunk_size).enumerate() {
+            let input = &mut scratch[..output.len()];
+            device.render(input);
+            self.mix(index, offset + i * chunk_size, input, output);
+            for frame in input {
+                *frame = (0.0, 0.0);
+            }
+        }
+        self.scratch = scratch;
+    }
+
+    /// Runs `input` through a channel strip in place and adds it to `output`.
+    pub fn mix(
+        &mut self,
+        index: usize,
+        offset: usize,
+        input: &mut [(f32, f32)],
+        output: &mut [(f32, f32)],
+    ) {
+        self.process(index, offset, input);
+        for (out, frame) in output.iter_mut().zip(input.iter()) {
+            out.0 += frame.0;
+            out.1 += frame.1;
+        }
+    }
+
+    /// Buffer the audio backend copies hardware input `input` to before rendering, the
+    /// channels fed by it read it at the same offsets as their output.
+    pub fn input_mut(&mut self, input: usize) -> &mut [(f32, f32)] {
+        &mut self.inputs[input]
+    }
+
+    pub fn input(&self, input: usize) -> &[(f32, f32)] {
+        &self.inputs[input]
+    }
+
+    /// Copies the signal of a channel which isn't fed by its instrument to `buffer`.
+    pub fn read_source(&self, index: usize, offset: usize, buffer: &mut [(f32, f32)]) {
+        let source = match self.params.channels[index].source() {
+            Source::Instrument => None,
+            Source::Input(input) => self.inputs.get(input),
+            Source::Bus(bus) => self.buses.get(bus),
+        };
+        match source.and_then(|source| source.get(offset..offset + buffer.len())) {
+            Some(source) => buffer.copy_from_slice(source),
+            None => {
+                for frame in buffer.iter_mut() {
+                    *frame = (0.0, 0.0);
+                }
+            }
+        }
+    }
+
+    /// Runs an aux bus through its return channel, adds it to `output` and clears the bus.
+    /// Call once per bus after all channels of the buffer have been mixed.
+    pub fn render_return(&mut self, bus: usize, output: &mut [(f32, f32)]) {
+        let mut buffer = std::mem::take(&mut self.buses[bus]);
+        let len = usize::min(output.len(), buffer.len());
+        let input = &mut buffer[..len];
+        self.mix(return_channel(bus), 0, input, &mut output[..len]);
+        for frame in input {
+            *frame = (0.0, 0.0);
+        }
+        self.buses[bus] = buffer;
+    }
+
+    /// Runs the effects, sends, gain, pan, mute and solo of a channel in place and updates its
+    /// meter. Buses only hold `MAX_FRAMES_PER_BUFFER` frames, anything sent past that is
+    /// dropped.
+    pub fn process(&mut self, index: usize, offset: usize, buffer: &mut [(f32, f32)]) {
+        if index >= self.params.channels.len() {
+            return;
+        }
+        let start = self.trims[index];
+        let end = db_to_gain(self.params.channels[index].trim.load(Ordering::Relaxed));
+        self.trims[index] = end;
+        if start != 1.0 || end != 1.0 {
+            let step = 1.0 / buffer.len() as f32;
+            for (i, frame) in buffer.iter_mut().enumerate() {
+                let gain = start + (end - start) * (i + 1) as f32 * step;
+                frame.0 *= gain;
+                frame.1 *= gain;
+            }
+        }
+        if index < MAX_INSTRUMENTS && self.is_key[index] {
+            if let Some(key) = self.keys[index].get_mut(offset..offset + buffer.len()) {
+                key.copy_from_slice(buffer);
+            }
+        }
+        let keys = &self.keys;
+        let mut latency = 0;
+        for insert in &mut self.chains[index] {
+            if insert.bypass.load(Ordering::Relaxed) {
+                continue;
+            }
+            latency += insert.effect.latency();
+            let key = insert
+                .effect
+                .sidechain()
+                .and_then(|key| keys.get(key))
+                .and_then(|key| key.get(offset..offset + buffer.len()));
+            match key {
+                Some(key) => insert.effect.process_keyed(buffer, key),
+                None => insert.effect.process(buffer),
+            }
+        }
+        self.params.channels[index]
+            .latency
+            .store(latency, Ordering::Relaxed);
+        self.send(index, offset, buffer, true);
+
+        // Returns are solo safe, so soloed channels keep their reverb.
+        let channel = &self.params.channels[index];
+        let start = self.gains[index];
+        let end = channel_gain(channel, self.any_solo && index < MAX_INSTRUMENTS);
+        self.gains[index] = end;
+
+        // Ramp linearly over the buffer so gain changes don't click.
+        let step = 1.0 / buffer.len() as f32;
+        for (i, frame) in buffer.iter_mut().enumerate() {
+            let t = (i + 1) as f32 * step;
+            frame.0 *= start.0 + (end.0 - start.0) * t;
+            frame.1 *= start.1 + (end.1 - start.1) * t;
+        }
+        if index == MASTER_CHANNEL {
+            // The filter keeps running while it's off, so turning it on doesn't thump.
+            let dc_block = self.params.dc_block.load(Ordering::Relaxed);
+            for frame in buffer.iter_mut() {
+                let filtered = self.dc_blocker.process(*frame);
+                if dc_block {
+                    *frame = filtered;
+                }
+            }
+            if self.params.clip.load(Ordering::Relaxed) {
+                for frame in buffer.iter_mut() {
+                    *frame = (soft_clip(frame.0), soft_clip(frame.1));
+                }
+            }
+        }
+        let mut peak = buffer.iter().fold(0.0f32, |peak, frame| {
+            peak.max(frame.0.abs()).max(frame.1.abs())
+        });
+        // The master meter shows true peaks, which lossy encoders and DACs can reach between
+        // samples.
+        if index == MASTER_CHANNEL {
+            peak = peak.max(self.true_peak.process(buffer));
+            if peak > 1.0 {
+                self.params.over.store(true, Ordering::Relaxed);
+            }
+        }
+        channel.peak.fetch_max(peak, Ordering::Relaxed);
+        self.send(index, offset, buffer, false);
+    }
+
+    /// Adds the channel to the buses it sends to, either before or after the fader.
+    fn send(&mut self, index: usize, offset: usize, buffer: &[(f32, f32)], pre_fader: bool) {
+        if index >= MAX_INSTRUMENTS {
+            return;
+        }
+        let channel = &self.params.channels[index];
+        if let Source::Bus(_) = channel.source() {
+            return;
+        }
+        for (bus, send) in channel.sends.iter().enumerate() {
+            if send.pre_fader.load(Ordering::Relaxed) != pre_fader {
+                continue;
+            }
+            let start = self.send_gains[index][bus];
+            let end = send.gain();
+            self.send_gains[index][bus] = end;
+            if start == 0.0 && end == 0.0 {
+                continue;
+            }
+            let output = match self.buses[bus].get_mut(offset..offset + buffer.len()) {
+                Some(output) => output,
+                None => continue,
+            };
+            let step = 1.0 / buffer.len() as f32;
+            for (i, (out, frame)) in output.iter_mut().zip(buffer).enumerate() {
+                let gain = start + (end - start) * (i + 1) as f32 * step;
+                out.0 += frame.0 * gain;
+                out.1 += frame.1 * gain;
+            }
+        }
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    f32::powf(10.0, db / 20.0)
+}
+
+/// Left and right gain factors of a channel.
+fn channel_gain(channel: &ChannelParams, any_solo: bool) -> (f32, f32) {
+    let silent =
+        channel.mute.load(Ordering::Relaxed) || (any_solo && !channel.solo.load(Ordering::Relaxed));
+    if silent {
+        return (0.0, 0.0);
+    }
+    let gain = db_to_gain(channel.gain.load(Ordering::Relaxed));
+    // Balance rather than a pan law, so a centered stereo source keeps its level.
+    let pan = channel.pan.load(Ordering::Relaxed);
+    (
+        gain * f32::min(1.0, 1.0 - pan),
+        gain * f32::min(1.0, 1.0 + pan),
+    )
+}
+
+/// Cutoff of the DC blocker in Hz, well below anything audible.
+const DC_CUTOFF: f32 = 5.0;
+/// Level in dBFS above which the soft clipper starts to bend the signal.
+const CLIP_THRESHOLD: f32 = -3.0;
+
+/// A one pole, one zero highpass which removes DC offset from a stereo signal.
+struct DcBlocker {
+    pole: f32,
+    input: (f32, f32),
+    output: (f32, f32),
+}
+
+impl DcBlocker {
+    fn new(sample_rate: f32) -> Self {
+        Self {
+            pole: 1.0 - 2.0 * PI * DC_CUTOFF / sample_rate,
+            input: (0.0, 0.0),
+            output: (0.0, 0.0),
+        }
+    }
+
+    fn process(&mut self, frame: (f32, f32)) -> (f32, f32) {
+        self.output = (
+            frame.0 - self.input.0 + self.pole * self.output.0,
+            frame.1 - self.input.1 + self.pole * self.output.1,
+        );
+        self.input = frame;
+        self.output
+    }
+}
+
+/// Leaves samples below the threshold untouched and bends the ones above it smoothly towards
+/// full scale, which they never reach.
+fn soft_clip(sample: f32) -> f32 {
+    let threshold = db_to_gain(CLIP_THRESHOLD);
+    let level = sample.abs();
+    if level <= threshold {
+        return sample;
+    }
+    let headroom = 1.0 - threshold;
+    let clipped = threshold + headroom * f32::tanh((level - threshold) / headroom);
+    clipped.copysign(sample)
+}
+
+/// Taps of every phase of the oversampling filter.
+const TRUE_PEAK_TAPS: usize = 12;
+const TRUE_PEAK_OVERSAMPLING: usize = 4;
+
+/// Measures true peaks by interpolating a stereo signal at 4x its sample rate with a windowed
+/// sinc filter, as described in ITU-R BS.1770.
+struct TruePeak {
+    /// Filter coefficients per phase, for the most recent sample first.
+    phases: [[f32; TRUE_PEAK_TAPS]; TRUE_PEAK_OVERSAMPLING],
+    history: [(f32, f32); TRUE_PEAK_TAPS],
+    position: usize,
+}
+
+impl TruePeak {
+    fn new() -> Self {
+        let mut phases = [[0.0; TRUE_PEAK_TAPS]; TRUE_PEAK_OVERSAMPLING];
+        let center = (TRUE_PEAK_TAPS / 2) as f32;
+        for (k, phase) in phases.iter_mut().enumerate() {
+            let fraction = k as f32 / TRUE_PEAK_OVERSAMPLING as f32;
+            for (j, coefficient) in phase.iter_mut().enumerate() {
+                let x = center - j as f32 - fraction;
+                let sinc = match x == 0.0 {
+                    true => 1.0,
+                    false => f32::sin(PI * x) / (PI * x),
+                };
+                let window = 0.5 + 0.5 * f32::cos(PI * x / (center + 1.0));
+                *coefficient = sinc * window;
+            }
+            // Unity gain at DC for every phase.
+            let sum: f32 = phase.iter().sum();
+            for coefficient in phase.iter_mut() {
+                *coefficient /= sum;
+            }
+        }
+        Self {
+            phases,
+            history: [(0.0, 0.0); TRUE_PEAK_TAPS],
+            position: 0,
+        }
+    }
+
+    /// Returns the highest interpolated level in `buffer`.
+    fn process(&mut self, buffer: &[(f32, f32)]) -> f32 {
+        let mut peak: f32 = 0.0;
+        for frame in buffer {
+            self.position = (self.position + 1) % TRUE_PEAK_TAPS;
+            self.history[self.position] = *frame;
+            for phase in &self.phases {
+                let mut sum = (0.0, 0.0);
+                for (j, coefficient) in phase.iter().enumerate() {
+                    let index = (self.position + TRUE_PEAK_TAPS - j) % TRUE_PEAK_TAPS;
+                    let sample = self.history[index];
+                    sum.0 += sample.0 * coefficient;
+                    sum.1 += sample.1 * coefficient;
+                }
+                peak = peak.max(sum.0.abs()).max(sum.1.abs());
+            }
+        }
+        peak
+    }
+}
@@ -0,0 +1,126 @@
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+lazy_static! {
+    /// Every live mapping, so they can all be paged in before a performance.
+    static ref MAPPINGS: Mutex<Vec<Weak<Mapping>>> = Mutex::new(Vec::new());
+}
+
+/// A read-only memory-mapped file. Pages are loaded lazily by the OS when they are first read
+/// and can be dropped again under memory pressure.
+///
+/// The file must not be truncated while it is mapped, reading past its new end kills the
+/// process with SIGBUS.
+pub struct Mapping {
+    ptr: *const u8,
+    len: usize,
+    purged: AtomicBool,
+}
+
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    pub fn open(file: &File) -> Result<Arc<Mapping>> {
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Err(anyhow!("unable to map an empty file"));
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let mapping = Arc::new(Mapping {
+            ptr: ptr as *const u8,
+            len,
+            purged: AtomicBool::new(false),
+        });
+        let mut mappings = MAPPINGS.lock().unwrap();
+        mappings.retain(|m| m.strong_count() > 0);
+        mappings.push(Arc::downgrade(&mapping));
+        Ok(mapping)
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    /// Reads one byte of every page so playback doesn't stall on page faults later.
+    pub fn pretouch(&self) {
+        self.pretouch_range(0, self.len);
+    }
+
+    /// Like `pretouch` but only for the pages overlapping `offset..offset + len`.
+    pub fn pretouch_range(&self, offset: usize, len: usize) {
+        let end = usize::min(offset + len, self.len);
+        let start = offset - offset % page_size();
+        if start >= end {
+            return;
+        }
+        unsafe {
+            libc::madvise(
+                self.ptr.add(start) as *mut libc::c_void,
+                end - start,
+                libc::MADV_WILLNEED,
+            );
+        }
+        let bytes = self.bytes();
+        for offset in (start..end).step_by(page_size()) {
+            unsafe { std::ptr::read_volatile(&bytes[offset]) };
+        }
+    }
+
+    /// Releases all loaded pages, they are read from disk again when they are next used.
+    /// Purged mappings are skipped by `pretouch_all`.
+    pub fn purge(&self) {
+        self.purged.store(true, Ordering::Relaxed);
+        unsafe {
+            libc::madvise(self.ptr as *mut libc::c_void, self.len, libc::MADV_DONTNEED);
+        }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+fn page_size() -> usize {
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if size > 0 {
+        size as usize
+    } else {
+        4096
+    }
+}
+
+/// Pages in every mapped file which hasn't been purged and returns the number of bytes
+/// touched.
+pub fn pretouch_all() -> usize {
+    let mappings: Vec<Arc<Mapping>> = MAPPINGS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|m| m.upgrade())
+        .filter(|m| !m.purged.load(Ordering::Relaxed))
+        .collect();
+    for mapping in &mappings {
+        mapping.pretouch();
+    }
+    mappings.iter().map(|m| m.len).sum()
+}
@@ -0,0 +1,178 @@
+use crate::engine::EngineConfig;
+use crate::sampler::Sound;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Attenuation of the dim switch, in dB.
+const DIM: f32 = -20.0;
+/// Time over which the loudness of the mix is averaged to match the reference, in seconds.
+const LEVEL_TIME: f32 = 3.0;
+/// Most the reference is turned up or down to match the mix, in dB.
+const MAX_MATCH: f32 = 24.0;
+
+/// Monitoring switches on the output. They only affect what is heard, not bounces or
+/// captures.
+#[derive(Clone)]
+pub struct MonitorParams {
+    pub dim: Arc<AtomicBool>,
+    /// Sums the output to mono, to check for phase issues.
+    pub mono: Arc<AtomicBool>,
+    /// Plays the reference track instead of the mix.
+    pub reference: Arc<AtomicBool>,
+}
+
+impl Default for MonitorParams {
+    fn default() -> Self {
+        Self {
+            dim: Arc::new(AtomicBool::new(false)),
+            mono: Arc::new(AtomicBool::new(false)),
+            reference: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl MonitorParams {
+    pub fn toggle_dim(&self) {
+        self.dim.fetch_xor(true, Ordering::Relaxed);
+    }
+
+    pub fn toggle_mono(&self) {
+        self.mono.fetch_xor(true, Ordering::Relaxed);
+    }
+
+    pub fn toggle_reference(&self) {
+        self.reference.fetch_xor(true, Ordering::Relaxed);
+    }
+}
+
+/// An external track to compare the mix against.
+pub struct Reference {
+    sound: Arc<Sound>,
+    /// Mean square of the whole track, for level matching.
+    level: f32,
+}
+
+impl Reference {
+    /// Measures the level of `sound`, which reads the whole file so it should be resident.
+    pub fn new(sound: Arc<Sound>) -> Self {
+        let sum: f64 = sound
+            .frames()
+            .map(|(left, right)| (left * left + right * right) as f64 / 2.0)
+            .sum();
+        let level = (sum / sound.num_frames().max(1) as f64) as f32;
+        Self { sound, level }
+    }
+}
+
+/// Applies the monitoring switches to the master output. The reference track plays along
+/// while the song is playing, starting over with it, and is turned up or down to the average
+/// level of the mix over the last few seconds.
+pub struct Monitor {
+    params: MonitorParams,
+    reference: Option<Box<Reference>>,
+    /// Position in the reference, in frames of the reference.
+    position: f64,
+    /// Mean square of the mix, averaged over `LEVEL_TIME`.
+    mix_level: f32,
+    /// Gains at the end of the previous buffer, to ramp towards changes.
+    dim_gain: f32,
+    match_gain: f32,
+    sample_rate: f32,
+    was_playing: bool,
+}
+
+impl Monitor {
+    pub fn new(params: MonitorParams) -> Self {
+        Self {
+            params,
+            reference: None,
+            position: 0.0,
+            mix_level: 0.0,
+            dim_gain: 1.0,
+            match_gain: 1.0,
+            sample_rate: EngineConfig::default().sample_rate as f32,
+            was_playing: false,
+        }
+    }
+
+    pub fn prepare(&mut self, config: &EngineConfig) {
+        self.sample_rate = config.sample_rate as f32;
+    }
+
+    /// Returns the previous reference, for the caller to drop.
+    pub fn set_reference(&mut self, reference: Option<Box<Reference>>) -> Option<Box<Reference>> {
+        self.position = 0.0;
+        std::mem::replace(&mut self.reference, reference)
+    }
+
+    pub fn process(&mut self, buffer: &mut [(f32, f32)], is_playing: bool) {
+        if is_playing && !self.was_playing {
+            self.position = 0.0;
+        }
+        self.was_playing = is_playing;
+        if buffer.is_empty() {
+            return;
+        }
+        let step = 1.0 / buffer.len() as f32;
+
+        if is_playing {
+            let coefficient = f32::exp(-1.0 / (LEVEL_TIME * self.sample_rate));
+            for frame in buffer.iter() {
+                let square = (frame.0 * frame.0 + frame.1 * frame.1) / 2.0;
+                self.mix_level = square + (self.mix_level - square) * coefficient;
+            }
+        }
+
+        if let Some(reference) = &self.reference {
+            if self.params.reference.load(Ordering::Relaxed) {
+                let max = f32::powf(10.0, MAX_MATCH / 10.0);
+                let ratio = (self.mix_level / reference.level.max(1e-12)).clamp(1.0 / max, max);
+                let start = self.match_gain;
+                let end = ratio.sqrt();
+                self.match_gain = end;
+                let sound = &reference.sound;
+                let increment = sound.sample_rate() as f64 / self.sample_rate as f64;
+                for (i, frame) in buffer.iter_mut().enumerate() {
+                    if !is_playing || sound.num_frames() < 2 {
+                        *frame = (0.0, 0.0);
+                        continue;
+                    }
+                    let index = self.position as usize;
+                    let fraction = (self.position - index as f64) as f32;
+                    let a = sound.frame_at(index);
+                    let b = sound.frame_at((index + 1) % sound.num_frames());
+                    let gain = start + (end - start) * (i + 1) as f32 * step;
+                    frame.0 = (a.0 + (b.0 - a.0) * fraction) * gain;
+                    frame.1 = (a.1 + (b.1 - a.1) * fraction) * gain;
+                    self.position = (self.position + increment) % sound.num_frames() as f64;
+                }
+            } else if is_playing {
+                // Keep the reference in time with the song while listening to the mix.
+                let increment = reference.sound.sample_rate() as f64 / self.sample_rate as f64;
+                let len = reference.sound.num_frames().max(1) as f64;
+                self.position = (self.position + increment * buffer.len() as f64) % len;
+            }
+        }
+
+        if self.params.mono.load(Ordering::Relaxed) {
+            for frame in buffer.iter_mut() {
+                let mono = (frame.0 + frame.1) / 2.0;
+                *frame = (mono, mono);
+            }
+        }
+
+        let start = self.dim_gain;
+        let end = match self.params.dim.load(Ordering::Relaxed) {
+            true => f32::powf(10.0, DIM / 20.0),
+            false => 1.0,
+        };
+        self.dim_gain = end;
+        if start != 1.0 || end != 1.0 {
+            for (i, frame) in buffer.iter_mut().enumerate() {
+                let gain = start + (end - start) * (i + 1) as f32 * step;
+                frame.0 *= gain;
+                frame.1 *= gain;
+            }
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Monophonic playing: an instrument in mono mode sounds one note at a time, whatever the number
+//! of tracks playing it. The notes still held are remembered, so releasing the note which sounds
+//! goes back to the one it took over from.
+
+use anyhow::{anyhow, Result};
+
+/// Column all the notes of an instrument in mono mode are played on.
+pub const COLUMN: usize = 0;
+
+/// Which of the held notes sounds.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Priority {
+    /// The note played last.
+    Last,
+    /// The lowest note.
+    Low,
+    /// The highest note.
+    High,
+}
+
+impl Priority {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "last" => Ok(Priority::Last),
+            "low" => Ok(Priority::Low),
+            "high" => Ok(Priority::High),
+            _ => Err(anyhow!(
+                "unknown note priority {}, expected last, low or high",
+                name
+            )),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct HeldNote {
+    column: usize,
+    pitch: u8,
+    velocity: u8,
+}
+
+/// What the instrument has to do after a note is played or released.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Change {
+    /// The note which sounds stays the same.
+    None,
+    /// Moves to another note.
+    Play { pitch: u8, velocity: u8 },
+    /// Nothing is held anymore.
+    Release,
+}
+
+/// The notes held on each column, one per column, in the order they were played.
+pub struct HeldNotes {
+    priority: Priority,
+    notes: Vec<HeldNote>,
+}
+
+impl HeldNotes {
+    pub fn new(priority: Priority) -> Self {
+        Self {
+            priority,
+            // More than enough for every track, so playing doesn't allocate
+            notes: Vec::with_capacity(64),
+        }
+    }
+
+    /// The note which sounds. Between notes of the same pitch the last one played wins.
+    fn current(&self) -> Option<HeldNote> {
+        // `min_by_key` keeps the first of equal notes and `max_by_key` the last
+        let notes = self.notes.iter().copied();
+        match self.priority {
+            Priority::Last => self.notes.last().copied(),
+            Priority::Low => notes.rev().min_by_key(|note| note.pitch),
+            Priority::High => notes.max_by_key(|note| note.pitch),
+        }
+    }
+
+    /// Holds a note on `column`, in place of the note it held before.
+    pub fn press(&mut self, column: usize, pitch: u8, velocity: u8) -> Change {
+        let before = self.current();
+        self.notes.retain(|note| note.column != column);
+        let note = HeldNote {
+            column,
+            pitch,
+            velocity,
+        };
+        self.notes.push(note);
+        match self.current() {
+            // A new note which sounds is played even at the pitch of the previous one
+            Some(current) if current == note || Some(current) != before => Change::Play {
+                pitch: current.pitch,
+                velocity: current.velocity,
+            },
+            _ => Change::None,
+        }
+    }
+
+    /// Lets go of the note held on `column`.
+    pub fn release(&mut self, column: usize) -> Change {
+        let before = self.current();
+        self.notes.retain(|note| note.column != column);
+        match self.current() {
+            Some(current) if Some(current) != before => Change::Play {
+                pitch: current.pitch,
+                velocity: current.velocity,
+            },
+            Some(_) => Change::None,
+            None if before.is_some() => Change::Release,
+            None => Change::None,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.notes.clear();
+    }
+}
@@ -0,0 +1,394 @@
+//! Remote control over OSC, e.g. from TouchOSC, Max or SuperCollider: `:osc <port>`. Messages
+//! are received on a thread and handed to the app along with key presses, addresses map to
+//! actions:
+//!
+//! - `/transport/play`, `/transport/pause`, `/transport/stop`, `/transport/toggle`, stop going
+//!   back to the start of the song
+//! - `/transport/seek <bar>`, counting bars from 1
+//! - `/bpm <n>`, `/lpb <n>`, `/octave <n>`, `/pattern <n>`
+//! - `/track/<n>/note <pitch> [velocity]`, velocity 0 ending the note
+//! - `/track/<n>/<instrument>/<param> <value>`, e.g. `/track/2/sampler/attack 0.1`, in the
+//!   unit of the param, or from 0 to 1 with `/norm` appended for faders
+//!
+//! Tracks count from 0 like in the editor. Buttons sending 0 on release only act on press.
+//! Bundles are run as soon as they're received, their time tags are ignored.
+
+use crate::app::{Action, App};
+use crate::engine::{EngineCommand, EngineParam};
+use crate::harmony::{self, MAX_REMOTE_NOTES};
+use crate::input::Input;
+use crate::transport::BarsBeats;
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Largest packet received, the most a UDP datagram carries.
+const MAX_PACKET: usize = 65_536;
+/// Time the receiving thread waits for a packet before checking whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const DEFAULT_VELOCITY: u8 = 100;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Arg {
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    Str(String),
+    Blob(Vec<u8>),
+    Bool(bool),
+    Nil,
+}
+
+impl Arg {
+    /// The value of a number or a bool, as a float.
+    pub fn as_f32(&self) -> Option<f32> {
+        match self {
+            Arg::Int(v) => Some(*v as f32),
+            Arg::Long(v) => Some(*v as f32),
+            Arg::Float(v) => Some(*v),
+            Arg::Double(v) => Some(*v as f32),
+            Arg::Bool(v) => Some(*v as u8 as f32),
+            Arg::Str(_) | Arg::Blob(_) | Arg::Nil => None,
+        }
+    }
+}
+
+impl fmt::Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arg::Int(v) => write!(f, "{}", v),
+            Arg::Long(v) => write!(f, "{}", v),
+            Arg::Float(v) => write!(f, "{}", v),
+            Arg::Double(v) => write!(f, "{}", v),
+            Arg::Str(v) => write!(f, "{:?}", v),
+            Arg::Blob(v) => write!(f, "<{} bytes>", v.len()),
+            Arg::Bool(v) => write!(f, "{}", v),
+            Arg::Nil => write!(f, "nil"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub address: String,
+    pub args: Vec<Arg>,
+}
+
+impl Message {
+    /// The argument at `index` as a number.
+    fn number(&self, index: usize) -> Result<f32> {
+        self.args
+            .get(index)
+            .and_then(Arg::as_f32)
+            .ok_or_else(|| anyhow!("{} expects a number as argument {}", self.address, index))
+    }
+
+    /// Whether a button was pressed rather than released. Messages without arguments count as
+    /// a press.
+    fn pressed(&self) -> bool {
+        self.args
+            .first()
+            .and_then(Arg::as_f32)
+            .is_none_or(|v| v != 0.0)
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.address)?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the messages of a packet, a single message or a bundle of them.
+pub fn decode(packet: &[u8]) -> Result<Vec<Message>> {
+    let mut messages = Vec::new();
+    decode_into(packet, &mut messages)?;
+    Ok(messages)
+}
+
+fn decode_into(packet: &[u8], messages: &mut Vec<Message>) -> Result<()> {
+    let mut reader = Reader { data: packet };
+    if packet.starts_with(b"#bundle\0") {
+        reader.take(8)?;
+        // Time tag
+        reader.take(8)?;
+        while !reader.data.is_empty() {
+            let size = reader.i32()?;
+            if size < 0 {
+                return Err(anyhow!("invalid bundle element size {}", size));
+            }
+            decode_into(reader.take(size as usize)?, messages)?;
+        }
+        return Ok(());
+    }
+    let address = reader.string()?;
+    if !address.starts_with('/') {
+        return Err(anyhow!("invalid OSC address {}", address));
+    }
+    // Very old senders leave out the type tags, there's nothing to read then
+    let tags = match reader.data.is_empty() {
+        true => String::from(","),
+        false => reader.string()?,
+    };
+    let tags = tags
+        .strip_prefix(',')
+        .ok_or_else(|| anyhow!("invalid OSC type tags {}", tags))?;
+    let mut args = Vec::with_capacity(tags.len());
+    for tag in tags.chars() {
+        let arg = match tag {
+            'i' => Arg::Int(reader.i32()?),
+            'h' => Arg::Long(i64::from_be_bytes(reader.array()?)),
+            'f' => Arg::Float(f32::from_be_bytes(reader.array()?)),
+            'd' => Arg::Double(f64::from_be_bytes(reader.array()?)),
+            's' | 'S' => Arg::Str(reader.string()?),
+            'b' => {
+                let size = reader.i32()?;
+                if size < 0 {
+                    return Err(anyhow!("invalid OSC blob size {}", size));
+                }
+                let blob = reader.take(size as usize)?.to_vec();
+                reader.take(padding(size as usize))?;
+                Arg::Blob(blob)
+            }
+            'T' => Arg::Bool(true),
+            'F' => Arg::Bool(false),
+            'N' | 'I' => Arg::Nil,
+            _ => return Err(anyhow!("unsupported OSC type tag {}", tag)),
+        };
+        args.push(arg);
+    }
+    messages.push(Message { address, args });
+    Ok(())
+}
+
+/// Bytes after `len` bytes of data up to the next multiple of 4.
+fn padding(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.data.len() {
+            return Err(anyhow!("truncated OSC packet"));
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(self.take(N)?);
+        Ok(bytes)
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_be_bytes(self.array()?))
+    }
+
+    /// Reads a string ended by a null byte and padded to 4 bytes.
+    fn string(&mut self) -> Result<String> {
+        let len = self
+            .data
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or_else(|| anyhow!("unterminated OSC string"))?;
+        let s = std::str::from_utf8(self.take(len)?)?.to_string();
+        self.take(1 + padding(len + 1))?;
+        Ok(s)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct HeldNote {
+    track: usize,
+    pitch: u8,
+    slot: usize,
+}
+
+/// Receives OSC messages on a UDP port and keeps track of the notes they play.
+pub struct Server {
+    port: u16,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    held: Vec<HeldNote>,
+}
+
+impl Server {
+    /// Listens on `port` on every interface, sending what it receives to `inputs`.
+    pub fn start(port: u16, inputs: Sender<Input>) -> Result<Self> {
+        let socket = UdpSocket::bind(("0.0.0.0", port))
+            .map_err(|err| anyhow!("unable to listen for OSC on port {}: {}", port, err))?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = Arc::clone(&stop);
+            thread::Builder::new()
+                .name(String::from("osc"))
+                .spawn(move || receive(socket, inputs, &stop))?
+        };
+        Ok(Self {
+            port,
+            stop,
+            thread: Some(thread),
+            held: Vec::with_capacity(MAX_REMOTE_NOTES),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Starts or ends a note on the instrument of a track, a velocity of 0 ending it. Returns
+    /// the commands playing it.
+    pub fn note(&mut self, track: usize, pitch: u8, velocity: u8) -> Vec<EngineCommand> {
+        let mut commands = Vec::new();
+        if let Some(index) = self
+            .held
+            .iter()
+            .position(|held| held.track == track && held.pitch == pitch)
+        {
+            let held = self.held.remove(index);
+            commands.push(off(&held));
+        }
+        if velocity == 0 {
+            return commands;
+        }
+        // The oldest note makes room when every slot is taken
+        if self.held.len() == MAX_REMOTE_NOTES {
+            let oldest = self.held.remove(0);
+            commands.push(off(&oldest));
+        }
+        let slot = (0..MAX_REMOTE_NOTES)
+            .find(|slot| self.held.iter().all(|held| held.slot != *slot))
+            .unwrap_or(0);
+        self.held.push(HeldNote { track, pitch, slot });
+        commands.push(EngineCommand::NoteOn(
+            track,
+            harmony::remote_column(slot),
+            pitch,
+            velocity,
+        ));
+        commands
+    }
+
+    /// Ends every note, e.g. when the server stops.
+    pub fn release_all(&mut self) -> Vec<EngineCommand> {
+        self.held.drain(..).map(|held| off(&held)).collect()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn off(held: &HeldNote) -> EngineCommand {
+    EngineCommand::NoteOff(held.track, harmony::remote_column(held.slot))
+}
+
+fn receive(socket: UdpSocket, inputs: Sender<Input>, stop: &AtomicBool) {
+    let mut buf = vec![0; MAX_PACKET];
+    while !stop.load(Ordering::Relaxed) {
+        let len = match socket.recv_from(&mut buf) {
+            Ok((len, _)) => len,
+            // Timed out, or a packet too large for the buffer
+            Err(_) => continue,
+        };
+        let messages = match decode(&buf[..len]) {
+            Ok(messages) => messages.into_iter().map(Ok).collect(),
+            Err(err) => vec![Err(err)],
+        };
+        for message in messages {
+            if inputs.send(Input::Osc(message)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// The action a message asks for, if any.
+pub fn action(message: &Message, app: &App) -> Result<Option<Action>> {
+    let parts: Vec<&str> = message.address[1..].split('/').collect();
+    let is_playing = app.engine_params.transport.is_playing();
+    let action = match parts.as_slice() {
+        ["transport", "play"] if message.pressed() && !is_playing => Action::Play,
+        ["transport", "pause"] if message.pressed() && is_playing => Action::Pause,
+        ["transport", "stop"] if message.pressed() => Action::Stop,
+        ["transport", "toggle"] if message.pressed() => Action::TogglePlay,
+        ["transport", "play" | "pause" | "stop" | "toggle"] => return Ok(None),
+        ["transport", "seek"] => Action::Seek(BarsBeats {
+            bar: (message.number(0)?.round() as u64).saturating_sub(1),
+            beat: 0,
+            line: 0,
+        }),
+        ["bpm"] => engine_param(EngineParam::Bpm, message)?,
+        ["lpb"] => engine_param(EngineParam::LinesPerBeat, message)?,
+        ["octave"] => engine_param(EngineParam::Octave, message)?,
+        ["pattern"] => Action::SelectPattern(message.number(0)? as usize),
+        ["track", track, "note"] => {
+            let track = parse_track(track, app)?;
+            let pitch = message.number(0)?.round().clamp(0.0, 127.0) as u8;
+            let velocity = match message.args.len() {
+                1 => DEFAULT_VELOCITY,
+                _ => message.number(1)?.round().clamp(0.0, 127.0) as u8,
+            };
+            Action::RemoteNote(track, pitch, velocity)
+        }
+        ["track", track, kind, param] => {
+            let track = instrument_track(track, kind, app)?;
+            Action::SetParam(track, param.to_string(), message.number(0)?)
+        }
+        ["track", track, kind, param, "norm"] => {
+            let track = instrument_track(track, kind, app)?;
+            Action::SetParamNormalized(track, param.to_string(), message.number(0)?)
+        }
+        _ => return Err(anyhow!("unknown OSC address {}", message.address)),
+    };
+    Ok(Some(action))
+}
+
+fn engine_param(param: EngineParam, message: &Message) -> Result<Action> {
+    let value = message.number(0)?.round() as i64;
+    Ok(Action::UpdateEngineParam(param, value.to_string()))
+}
+
+fn parse_track(track: &str, app: &App) -> Result<usize> {
+    match track.parse() {
+        Ok(track) if track < app.instruments.len() => Ok(track),
+        _ => Err(anyhow!("invalid track {}", track)),
+    }
+}
+
+/// Parses the track of an address naming its instrument, which must be the one loaded.
+fn instrument_track(track: &str, kind: &str, app: &App) -> Result<usize> {
+    let track = parse_track(track, app)?;
+    match &app.instruments[track] {
+        Some(settings) if settings.kind.eq_ignore_ascii_case(kind) => Ok(track),
+        Some(settings) => Err(anyhow!(
+            "track {} plays a {}, not a {}",
+            track,
+            settings.kind,
+            kind
+        )),
+        None => Err(anyhow!("track {} has no instrument", track)),
+    }
+}
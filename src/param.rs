@@ -1,13 +1,18 @@
 use std::sync::{atomic::Ordering, Arc};
 
+use crate::id::InstrumentId;
 use anyhow::{anyhow, Result};
 use atomic_float::AtomicF32;
 
+/// Time smoothed values take to follow about two thirds of a change, in seconds.
+const SMOOTHING_TIME: f32 = 0.005;
+
 #[derive(Copy, Clone)]
 pub enum Unit {
     Decibel,
     Seconds,
     Samples,
+    Hertz,
 }
 
 pub struct Param {
@@ -34,6 +39,32 @@ impl Param {
         self
     }
 
+    /// A param with the same range, step and unit, stored in `val`.
+    pub fn share(&self, val: Arc<AtomicF32>) -> Self {
+        Self {
+            min: self.min,
+            max: self.max,
+            val,
+            step: self.step,
+            unit: self.unit,
+        }
+    }
+
+    pub fn range(&self) -> (f32, f32) {
+        (self.min, self.max)
+    }
+
+    /// Describes the param, its current value taken as the default.
+    pub fn describe(&self, name: &str) -> ParamDescriptor {
+        ParamDescriptor {
+            name: name.to_string(),
+            min: self.min,
+            max: self.max,
+            unit: self.unit,
+            default: self.val.load(Ordering::Relaxed),
+        }
+    }
+
     pub fn incr(&mut self) {
         let mut val = self.val.load(Ordering::Relaxed);
         val = f32::min(val + self.step, self.max);
@@ -46,6 +77,36 @@ impl Param {
         self.val.store(val, Ordering::Relaxed);
     }
 
+    /// The value as a fraction of the range, following the curve of the unit so equal moves
+    /// sound like equal changes: frequencies are spread evenly over octaves and times give
+    /// more room to short ones. Decibels are already logarithmic and stay linear.
+    pub fn normalized(&self) -> f32 {
+        let val = self.val.load(Ordering::Relaxed).clamp(self.min, self.max);
+        if self.max <= self.min {
+            return 0.0;
+        }
+        let linear = (val - self.min) / (self.max - self.min);
+        match self.unit {
+            Some(Unit::Hertz) if self.min > 0.0 => {
+                (val / self.min).ln() / (self.max / self.min).ln()
+            }
+            Some(Unit::Seconds) => linear.cbrt(),
+            _ => linear,
+        }
+    }
+
+    /// Sets the value from a fraction of the range, see `normalized`.
+    pub fn set_normalized(&mut self, normalized: f32) {
+        let x = normalized.clamp(0.0, 1.0);
+        let val = match self.unit {
+            Some(Unit::Hertz) if self.min > 0.0 => self.min * (self.max / self.min).powf(x),
+            Some(Unit::Seconds) => self.min + (self.max - self.min) * x * x * x,
+            _ => self.min + (self.max - self.min) * x,
+        };
+        self.val
+            .store(val.clamp(self.min, self.max), Ordering::Relaxed);
+    }
+
     pub fn set(&mut self, value: f32) -> Result<()> {
         if value > self.max || value < self.min {
             return Err(anyhow!(
@@ -61,21 +122,111 @@ impl Param {
 
 impl std::fmt::Display for Param {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let val = self.val.load(Ordering::Relaxed);
-        if let Some(unit) = self.unit {
-            match unit {
-                Unit::Decibel => write!(f, "{:.2} dB", val),
-                Unit::Seconds => {
-                    if val < 1.0 {
-                        write!(f, "{:.0} ms", val * 1000.0)
-                    } else {
-                        write!(f, "{:.2} s", val)
-                    }
+        write_value(f, self.val.load(Ordering::Relaxed), self.unit)
+    }
+}
+
+/// Names a param of any instrument: the instrument it belongs to and the name it has there.
+/// Instruments list their own params, so a new type of instrument needs nothing more here for
+/// its params to be set, undone or described.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParamKey {
+    pub instrument: InstrumentId,
+    pub name: String,
+}
+
+impl std::fmt::Display for ParamKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} of instrument {}", self.name, self.instrument.0)
+    }
+}
+
+/// What a param is apart from its value, to list the params of an instrument type without
+/// creating an instrument.
+#[derive(Clone)]
+pub struct ParamDescriptor {
+    pub name: String,
+    pub min: f32,
+    pub max: f32,
+    pub unit: Option<Unit>,
+    /// Value of the param on a new instrument.
+    pub default: f32,
+}
+
+impl std::fmt::Display for ParamDescriptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} ", self.name)?;
+        write_value(f, self.min, self.unit)?;
+        write!(f, " to ")?;
+        write_value(f, self.max, self.unit)?;
+        write!(f, ", default ")?;
+        write_value(f, self.default, self.unit)
+    }
+}
+
+fn write_value(f: &mut std::fmt::Formatter, val: f32, unit: Option<Unit>) -> std::fmt::Result {
+    if let Some(unit) = unit {
+        match unit {
+            Unit::Decibel => write!(f, "{:.1} dB", val),
+            Unit::Seconds => {
+                if val < 1.0 {
+                    write!(f, "{:.0} ms", val * 1000.0)
+                } else {
+                    write!(f, "{:.2} s", val)
                 }
-                Unit::Samples => write!(f, "{:.0}", val),
             }
-        } else {
-            write!(f, "{:.2}", val)
+            Unit::Samples => write!(f, "{:.0}", val),
+            Unit::Hertz => {
+                if val < 10.0 {
+                    write!(f, "{:.2} Hz", val)
+                } else if val < 1000.0 {
+                    write!(f, "{:.0} Hz", val)
+                } else {
+                    write!(f, "{:.1} kHz", val / 1000.0)
+                }
+            }
+        }
+    } else {
+        write!(f, "{:.2}", val)
+    }
+}
+
+/// Reads a param value on the audio thread and follows its changes with a one pole filter, so
+/// they don't step and click.
+pub struct Smoothed {
+    pub val: Arc<AtomicF32>,
+    current: f32,
+    coefficient: f32,
+}
+
+impl Smoothed {
+    pub fn new(value: f32, sample_rate: f32) -> Self {
+        let mut smoothed = Self {
+            val: Arc::new(AtomicF32::new(value)),
+            current: value,
+            coefficient: 0.0,
+        };
+        smoothed.prepare(sample_rate);
+        smoothed
+    }
+
+    pub fn prepare(&mut self, sample_rate: f32) {
+        self.coefficient = f32::exp(-1.0 / (SMOOTHING_TIME * sample_rate));
+    }
+
+    /// Writes the smoothed value of every sample of a block into `out`.
+    pub fn fill(&mut self, out: &mut [f32]) {
+        let target = self.val.load(Ordering::Relaxed);
+        if self.current == target {
+            out.fill(target);
+            return;
+        }
+        for value in out.iter_mut() {
+            self.current = target + (self.current - target) * self.coefficient;
+            *value = self.current;
+        }
+        if (self.current - target).abs() < 1e-6 {
+            self.current = target;
         }
     }
 }
@@ -0,0 +1,126 @@
+use anyhow::{anyhow, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+const APP_NAME: &str = "ruis";
+
+/// Where the directories of the program are.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Layout {
+    /// In the working directory, e.g. when running from a checkout.
+    Local,
+    /// In the data directory of the platform: `$XDG_DATA_HOME` on Linux, Application Support
+    /// on macOS and AppData on Windows.
+    Platform,
+    /// Next to the executable, e.g. to carry a whole rig on a USB stick.
+    Portable,
+}
+
+impl Layout {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "local" => Ok(Self::Local),
+            "platform" => Ok(Self::Platform),
+            "portable" => Ok(Self::Portable),
+            _ => Err(anyhow!(
+                "unknown layout {}, expected local|platform|portable",
+                name
+            )),
+        }
+    }
+}
+
+/// Directories of the projects and of the sound library.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Paths {
+    pub projects: Utf8PathBuf,
+    pub sounds: Utf8PathBuf,
+}
+
+impl Paths {
+    pub fn new(layout: Layout) -> Result<Self> {
+        let root = match layout {
+            Layout::Local => {
+                return Ok(Self {
+                    projects: Utf8PathBuf::from("."),
+                    sounds: Utf8PathBuf::from("./sounds"),
+                })
+            }
+            Layout::Platform => data_dir()?.join(APP_NAME),
+            Layout::Portable => {
+                let exe = env::current_exe()?.canonicalize()?;
+                let dir = exe
+                    .parent()
+                    .ok_or_else(|| anyhow!("no directory for {}", exe.display()))?;
+                utf8(dir.to_path_buf())?
+            }
+        };
+        Ok(Self {
+            projects: root.join("projects"),
+            sounds: root.join("sounds"),
+        })
+    }
+
+    /// Creates the directories which don't exist yet.
+    pub fn create(&self) -> Result<()> {
+        fs::create_dir_all(&self.projects)?;
+        fs::create_dir_all(&self.sounds)?;
+        Ok(())
+    }
+
+    /// Directory of the sounds recorded from the inputs, inside the library.
+    pub fn recordings(&self) -> Utf8PathBuf {
+        self.sounds.join("recordings")
+    }
+
+    /// Directory of the edited copies of sounds, inside the library.
+    pub fn edits(&self) -> Utf8PathBuf {
+        self.sounds.join("edits")
+    }
+
+    /// Directory of the crash reports, next to the projects.
+    pub fn crashes(&self) -> Utf8PathBuf {
+        self.projects.join("crashes")
+    }
+
+    /// Resolves the path of a project, relative paths being in the projects directory.
+    pub fn project(&self, path: &Utf8Path) -> Utf8PathBuf {
+        self.projects.join(path)
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn data_dir() -> Result<Utf8PathBuf> {
+    Ok(home()?.join("Library").join("Application Support"))
+}
+
+#[cfg(windows)]
+fn data_dir() -> Result<Utf8PathBuf> {
+    match env::var_os("APPDATA") {
+        Some(dir) => utf8(PathBuf::from(dir)),
+        None => Err(anyhow!("APPDATA isn't set")),
+    }
+}
+
+/// `$XDG_DATA_HOME`, which must be absolute, or its default.
+#[cfg(not(any(target_os = "macos", windows)))]
+fn data_dir() -> Result<Utf8PathBuf> {
+    match env::var_os("XDG_DATA_HOME").map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => utf8(dir),
+        _ => Ok(home()?.join(".local").join("share")),
+    }
+}
+
+#[cfg(not(windows))]
+fn home() -> Result<Utf8PathBuf> {
+    match env::var_os("HOME") {
+        Some(dir) => utf8(PathBuf::from(dir)),
+        None => Err(anyhow!("HOME isn't set")),
+    }
+}
+
+fn utf8(path: PathBuf) -> Result<Utf8PathBuf> {
+    Utf8PathBuf::from_path_buf(path).map_err(|path| anyhow!("invalid path {}", path.display()))
+}
@@ -1,11 +1,33 @@
+use crate::euclid::Euclid;
+use crate::harmony::{Chord, Harmony, MAX_CHORD_NOTES};
+use crate::id::{IdGen, PatternId, TrackId};
 use crate::sampler::ROOT_PITCH;
+use anyhow::{anyhow, Result};
+use std::fmt;
 
 pub const NUM_TRACK_LANES: usize = 2;
 pub const MAX_TRACKS: usize = 8;
 pub const MAX_COLS: usize = MAX_TRACKS * NUM_TRACK_LANES;
+/// Pitch value of a step that releases the note playing on its track.
+pub const NOTE_OFF: u8 = 0xff;
+/// Pitch value of a step that silences the note playing on its track at once, skipping its
+/// release.
+pub const NOTE_CUT: u8 = 0xfe;
 
 const MAX_PATTERNS: usize = 32;
-const MAX_PATTERN_LENGTH: usize = 512;
+const DEFAULT_PATTERN_LENGTH: usize = 32;
+pub const MAX_PATTERN_LENGTH: usize = 512;
+/// Resolution of the timing of steps within a line.
+pub const TICKS_PER_LINE: u32 = 24;
+/// Most ticks a step can be played before or after its line, half a line.
+pub const MAX_OFFSET: i8 = (TICKS_PER_LINE / 2) as i8;
+/// Most lines the cursor moves down after a step is entered.
+pub const MAX_EDIT_STEP: usize = 16;
+
+/// Whether the pitch of a step ends the note playing on its track rather than starting one.
+pub fn ends_note(pitch: u8) -> bool {
+    pitch == NOTE_OFF || pitch == NOTE_CUT
+}
 
 #[derive(Clone, Copy, Debug)]
 pub struct Position {
@@ -13,6 +35,126 @@ pub struct Position {
     pub column: usize,
 }
 
+/// Lines `start..end` of a pattern, across all tracks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Section {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Section {
+    /// Parses `start end` line numbers, the end being exclusive.
+    pub fn parse(start: &str, end: &str) -> Result<Self> {
+        let section = Self {
+            start: start.parse()?,
+            end: end.parse()?,
+        };
+        if section.start >= section.end {
+            return Err(anyhow!("empty section {}", section));
+        }
+        Ok(section)
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+/// Rearranges a whole section of a pattern, following material is shifted to make room or to
+/// close the gap.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SectionOp {
+    /// Inserts a copy of the section right after it.
+    Duplicate(Section),
+    /// Moves the section before a line, given as a line number before the move.
+    Move(Section, usize),
+    Delete(Section),
+}
+
+impl fmt::Display for SectionOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SectionOp::Duplicate(section) => write!(f, "dup {}", section),
+            SectionOp::Move(section, to) => write!(f, "move {} {}", section, to),
+            SectionOp::Delete(section) => write!(f, "delete {}", section),
+        }
+    }
+}
+
+/// Changes the steps of a section of a track.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transform {
+    /// Moves notes by semitones, within the range of pitches.
+    Transpose(i32),
+    /// Moves steps later by lines, or earlier when negative. Steps moved out of the section
+    /// come back at its other end.
+    Rotate(i32),
+    /// Plays the steps backwards.
+    Reverse,
+    /// Moves the steps down a line, leaving the first line empty. The last step is dropped.
+    InsertLine,
+    /// Removes the first step and moves the others up a line, leaving the last line empty.
+    DeleteLine,
+    /// Plays steps up to a number of ticks early or late at random, within `MAX_OFFSET`. 0 puts
+    /// them back on the grid. The offsets follow from the steps, so redoing gives the same ones
+    /// and randomizing again new ones.
+    RandomizeTiming(i8),
+}
+
+impl fmt::Display for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Transform::Transpose(semitones) => write!(f, "transpose {}", semitones),
+            Transform::Rotate(lines) => write!(f, "rotate {}", lines),
+            Transform::Reverse => write!(f, "reverse"),
+            Transform::InsertLine => write!(f, "insert line"),
+            Transform::DeleteLine => write!(f, "delete line"),
+            Transform::RandomizeTiming(ticks) => write!(f, "randomize timing {}", ticks),
+        }
+    }
+}
+
+/// What happens to the steps of a pattern when its length changes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LengthPolicy {
+    /// Steps past the new end are cleared, new lines are empty.
+    #[default]
+    Truncate,
+    /// New lines repeat the pattern from the start, shrinking truncates.
+    Loop,
+    /// Steps keep their relative position, e.g. doubling the length doubles the space between
+    /// notes. Steps which land on the same line are merged, the earliest wins.
+    Stretch,
+}
+
+impl LengthPolicy {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "truncate" => Ok(LengthPolicy::Truncate),
+            "loop" => Ok(LengthPolicy::Loop),
+            "stretch" => Ok(LengthPolicy::Stretch),
+            _ => Err(anyhow!(
+                "unknown length policy {}, expected truncate, loop or stretch",
+                name
+            )),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LengthPolicy::Truncate => "truncate",
+            LengthPolicy::Loop => "loop",
+            LengthPolicy::Stretch => "stretch",
+        }
+    }
+}
+
 pub enum Move {
     Left,
     Right,
@@ -24,25 +166,248 @@ pub enum Move {
     Bottom,
 }
 
+#[derive(Clone)]
 pub struct Editor {
     patterns: Vec<Pattern>,
+    track_ids: Vec<TrackId>,
+    ids: IdGen,
     edit_index: usize,
     pub cursor: Position,
+    /// Key, scale and voicings the chords of the steps are built from.
+    pub harmony: Harmony,
 }
 
 impl Editor {
     pub fn new() -> Self {
+        let mut ids = IdGen::default();
+        let track_ids: Vec<_> = (0..MAX_TRACKS).map(|_| TrackId(ids.next())).collect();
         let mut patterns = Vec::with_capacity(MAX_PATTERNS);
-        let pattern = Pattern::default();
+        let pattern = Pattern::new(PatternId(ids.next()), &track_ids, DEFAULT_PATTERN_LENGTH);
         patterns.push(pattern);
 
         Self {
             edit_index: 0,
             patterns,
+            track_ids,
+            ids,
             cursor: Position { line: 0, column: 0 },
+            harmony: Harmony::default(),
         }
     }
 
+    pub fn patterns(&self) -> &[Pattern] {
+        &self.patterns
+    }
+
+    pub fn edit_index(&self) -> usize {
+        self.edit_index
+    }
+
+    /// Track ids in display order.
+    pub fn track_ids(&self) -> &[TrackId] {
+        &self.track_ids
+    }
+
+    pub fn track_index(&self, id: TrackId) -> Option<usize> {
+        self.track_ids.iter().position(|t| *t == id)
+    }
+
+    pub fn pattern_index(&self, id: PatternId) -> Option<usize> {
+        self.patterns.iter().position(|p| p.id == id)
+    }
+
+    /// Replaces all patterns. Every pattern must contain the tracks in `track_ids`, in order.
+    pub fn load_patterns(
+        &mut self,
+        patterns: Vec<Pattern>,
+        track_ids: Vec<TrackId>,
+        edit_index: usize,
+    ) {
+        self.ids = IdGen::default();
+        for id in &track_ids {
+            self.ids.observe(id.0);
+        }
+        for pattern in &patterns {
+            self.ids.observe(pattern.id.0);
+        }
+        self.track_ids = track_ids;
+        self.patterns = patterns;
+        if self.patterns.is_empty() {
+            let id = PatternId(self.ids.next());
+            let pattern = Pattern::new(id, &self.track_ids, DEFAULT_PATTERN_LENGTH);
+            self.patterns.push(pattern);
+        }
+        self.edit_index = usize::min(edit_index, self.patterns.len() - 1);
+        self.cursor = Position { line: 0, column: 0 };
+    }
+
+    /// Sets a step in any pattern, returns false if the pattern or track doesn't exist.
+    pub fn set_step(
+        &mut self,
+        pattern: PatternId,
+        track: TrackId,
+        line: usize,
+        step: Step,
+    ) -> bool {
+        let track = match self.track_index(track) {
+            Some(track) => track,
+            None => return false,
+        };
+        match self.patterns.iter_mut().find(|p| p.id == pattern) {
+            Some(pattern) if line < MAX_PATTERN_LENGTH => {
+                pattern.set_step(track, line, step);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Rearranges a section of a pattern and returns the pattern as it was before.
+    pub fn rearrange(&mut self, pattern: PatternId, op: SectionOp) -> Result<Pattern> {
+        let index = self
+            .pattern_index(pattern)
+            .ok_or_else(|| anyhow!("unknown pattern {}", pattern.0))?;
+        let pattern = &mut self.patterns[index];
+        let before = pattern.clone();
+        pattern.rearrange(op)?;
+        self.clamp_cursor();
+        Ok(before)
+    }
+
+    /// Adds a pattern with the given steps for every track and makes it the current one.
+    pub fn add_pattern(&mut self, tracks: Vec<Vec<Step>>) -> Result<PatternId> {
+        if self.patterns.len() == MAX_PATTERNS {
+            return Err(anyhow!(
+                "there can't be more than {} patterns",
+                MAX_PATTERNS
+            ));
+        }
+        let num_lines = tracks.first().map_or(0, Vec::len);
+        let id = PatternId(self.ids.next());
+        let mut pattern = Pattern::new(id, &self.track_ids, num_lines);
+        for (track, steps) in tracks.into_iter().enumerate().take(pattern.num_tracks()) {
+            for (line, step) in steps.into_iter().enumerate().take(MAX_PATTERN_LENGTH) {
+                pattern.set_step(track, line, step);
+            }
+        }
+        self.patterns.push(pattern);
+        self.select_pattern(self.patterns.len() - 1)?;
+        Ok(id)
+    }
+
+    pub fn select_pattern(&mut self, index: usize) -> Result<()> {
+        if index >= self.patterns.len() {
+            return Err(anyhow!("there are only {} patterns", self.patterns.len()));
+        }
+        self.edit_index = index;
+        self.clamp_cursor();
+        Ok(())
+    }
+
+    /// Changes the length of a pattern and returns the pattern as it was before.
+    pub fn resize(
+        &mut self,
+        pattern: PatternId,
+        num_lines: usize,
+        policy: LengthPolicy,
+    ) -> Result<Pattern> {
+        let index = self
+            .pattern_index(pattern)
+            .ok_or_else(|| anyhow!("unknown pattern {}", pattern.0))?;
+        let pattern = &mut self.patterns[index];
+        let before = pattern.clone();
+        pattern.resize(num_lines, policy)?;
+        self.clamp_cursor();
+        Ok(before)
+    }
+
+    /// Gives a track of a pattern its own length, or makes it follow the pattern again.
+    /// Returns the length the track had before.
+    pub fn set_track_length(
+        &mut self,
+        pattern: PatternId,
+        track: TrackId,
+        length: Option<usize>,
+    ) -> Result<Option<usize>> {
+        let index = self
+            .pattern_index(pattern)
+            .ok_or_else(|| anyhow!("unknown pattern {}", pattern.0))?;
+        let track = self
+            .track_index(track)
+            .ok_or_else(|| anyhow!("unknown track {}", track.0))?;
+        let pattern = &mut self.patterns[index];
+        let before = pattern.own_length(track);
+        pattern.set_track_length(track, length)?;
+        Ok(before)
+    }
+
+    /// Transforms a section of a track and returns the pattern as it was before.
+    pub fn transform(
+        &mut self,
+        pattern: PatternId,
+        track: TrackId,
+        section: Section,
+        transform: Transform,
+    ) -> Result<Pattern> {
+        let index = self
+            .pattern_index(pattern)
+            .ok_or_else(|| anyhow!("unknown pattern {}", pattern.0))?;
+        let track = self
+            .track_index(track)
+            .ok_or_else(|| anyhow!("unknown track {}", track.0))?;
+        let pattern = &mut self.patterns[index];
+        let before = pattern.clone();
+        pattern.transform(track, section, transform)?;
+        Ok(before)
+    }
+
+    /// Fills a track of a pattern with a Euclidean rhythm of `step` and returns the pattern as
+    /// it was before.
+    pub fn fill_euclid(
+        &mut self,
+        pattern: PatternId,
+        track: TrackId,
+        euclid: &Euclid,
+        step: Step,
+    ) -> Result<Pattern> {
+        let index = self
+            .pattern_index(pattern)
+            .ok_or_else(|| anyhow!("unknown pattern {}", pattern.0))?;
+        let track = self
+            .track_index(track)
+            .ok_or_else(|| anyhow!("unknown track {}", track.0))?;
+        let pattern = &mut self.patterns[index];
+        let before = pattern.clone();
+        pattern.fill_euclid(track, euclid, step);
+        Ok(before)
+    }
+
+    /// Replaces the pattern with the same id, e.g. to revert `rearrange` or `resize`.
+    pub fn restore_pattern(&mut self, pattern: Pattern) {
+        if let Some(index) = self.pattern_index(pattern.id) {
+            self.patterns[index] = pattern;
+            self.clamp_cursor();
+        }
+    }
+
+    fn clamp_cursor(&mut self) {
+        self.cursor.line = usize::min(self.cursor.line, self.num_lines() - 1);
+    }
+
+    /// Moves a track column to another position in every pattern.
+    pub fn move_track(&mut self, from: usize, to: usize) {
+        if from >= self.track_ids.len() || to >= self.track_ids.len() {
+            return;
+        }
+        let id = self.track_ids.remove(from);
+        self.track_ids.insert(to, id);
+        for pattern in &mut self.patterns {
+            let track = pattern.tracks.remove(from);
+            pattern.tracks.insert(to, track);
+        }
+        self.cursor.column = to * NUM_TRACK_LANES + self.cursor.column % NUM_TRACK_LANES;
+    }
+
     pub fn current_pattern(&self) -> &Pattern {
         &self.patterns[self.edit_index]
     }
@@ -55,6 +420,31 @@ impl Editor {
         self.cursor.column / NUM_TRACK_LANES
     }
 
+    /// Moves the cursor to a track, on the same lane.
+    pub fn select_track(&mut self, track: usize) {
+        let lane = self.cursor.column % NUM_TRACK_LANES;
+        self.cursor.column = usize::min(track, MAX_TRACKS - 1) * NUM_TRACK_LANES + lane;
+    }
+
+    pub fn set_num_lines(&mut self, num_lines: usize) {
+        let pattern = &mut self.patterns[self.edit_index];
+        pattern.num_lines = usize::max(1, usize::min(num_lines, MAX_PATTERN_LENGTH));
+        self.cursor.line = usize::min(self.cursor.line, pattern.num_lines - 1);
+    }
+
+    /// Writes a note into the current pattern, `offset` ticks off its line.
+    pub fn write_note(&mut self, track: usize, line: usize, pitch: u8, offset: i8) {
+        let pattern = &mut self.patterns[self.edit_index];
+        if let Some(step) = pattern.tracks[track].steps.get_mut(line) {
+            step.pitch = Some(pitch);
+            step.offset = offset.clamp(-MAX_OFFSET, MAX_OFFSET);
+        }
+    }
+
+    pub fn step(&self, track: usize, line: usize) -> Step {
+        self.current_pattern().tracks[track].steps[line]
+    }
+
     pub fn set_cursor(&mut self, pos: Position) {
         self.cursor = pos;
     }
@@ -77,11 +467,28 @@ impl Editor {
         }
     }
 
+    /// Moves the cursor down by `lines` after a step is entered, stopping at the last line.
+    pub fn advance(&mut self, lines: usize) {
+        let height = self.current_pattern().num_lines;
+        self.cursor.line = usize::min(height - 1, self.cursor.line + lines);
+    }
+
     pub fn set_pitch(&mut self, pitch: u8) {
         let step = self.get_step();
         step.pitch = Some(pitch);
     }
 
+    pub fn set_chord(&mut self, chord: Option<Chord>) {
+        let step = self.get_step();
+        step.chord = chord;
+    }
+
+    /// Moves the step under the cursor off the grid, by ticks within `MAX_OFFSET`.
+    pub fn set_offset(&mut self, offset: i8) {
+        let step = self.get_step();
+        step.offset = offset.clamp(-MAX_OFFSET, MAX_OFFSET);
+    }
+
     pub fn set_number(&mut self, num: i32) {
         match self.cursor.column % NUM_TRACK_LANES {
             1 => {
@@ -106,6 +513,9 @@ impl Editor {
     pub fn change_value(&mut self, delta: i32) {
         let field = self.cursor.column % NUM_TRACK_LANES;
         let step = self.get_step();
+        if step.pitch.is_some_and(ends_note) {
+            step.pitch = None;
+        }
         let p = match field {
             0 => step.pitch.get_or_insert(ROOT_PITCH),
             1 => step.sound.get_or_insert(0),
@@ -124,28 +534,51 @@ impl Editor {
 
     pub fn iter_tracks(&self) -> impl Iterator<Item = TrackView> {
         let pattern = &self.patterns[self.edit_index];
-        pattern.tracks.iter().map(move |track| TrackView {
-            steps: &track.steps[0..pattern.num_lines],
+        (0..pattern.num_tracks()).map(move |track| TrackView {
+            steps: &pattern.tracks[track].steps[0..pattern.num_lines],
+            length: pattern.track_length(track),
+            shift: pattern.shift(track),
         })
     }
 
+    /// Notes played at `tick`, ordered by track, see `Pattern::played_line`. Chords are
+    /// expanded into their notes, the root first.
     pub fn iter_notes(&self, tick: u64) -> impl Iterator<Item = NoteEvent> + '_ {
-        let pattern = &self.patterns[self.edit_index];
-        let line = (tick % pattern.num_lines as u64) as usize;
-        pattern
-            .tracks
-            .iter()
+        self.pattern_notes(self.edit_index, tick)
+    }
+
+    /// Like `iter_notes`, for any pattern by index.
+    pub fn pattern_notes(&self, index: usize, tick: u64) -> impl Iterator<Item = NoteEvent> + '_ {
+        let pattern = &self.patterns[index];
+        let harmony = &self.harmony;
+        (0..pattern.num_tracks())
+            .map(move |track| pattern.step(track, pattern.played_line(track, tick)))
             .enumerate()
-            .flat_map(move |(i, track)| {
-                track
-                    .steps
-                    .iter()
-                    .enumerate()
-                    .filter(move |(l, step)| *l == line && step.pitch.is_some())
-                    .map(move |(_, &step)| NoteEvent {
-                        pitch: step.pitch.unwrap(),
-                        track: i as u8,
-                        sound: step.sound.unwrap_or(i as u8),
+            .flat_map(move |(i, step)| {
+                Some(step)
+                    .filter(|step| step.pitch.is_some())
+                    .into_iter()
+                    .flat_map(move |step| {
+                        let pitch = step.pitch.unwrap();
+                        let notes = match step.chord {
+                            Some(chord) if !ends_note(pitch) => harmony.chord_notes(pitch, chord),
+                            _ => {
+                                let mut notes = [None; MAX_CHORD_NOTES];
+                                notes[0] = Some(pitch);
+                                notes
+                            }
+                        };
+                        IntoIterator::into_iter(notes).enumerate().filter_map(
+                            move |(chord_note, pitch)| {
+                                Some(NoteEvent {
+                                    pitch: pitch?,
+                                    track: i as u8,
+                                    sound: step.sound.unwrap_or(i as u8),
+                                    chord_note: chord_note as u8,
+                                    offset: step.offset,
+                                })
+                            },
+                        )
                     })
             })
     }
@@ -153,36 +586,312 @@ impl Editor {
 
 pub struct TrackView<'a> {
     pub steps: &'a [Step],
+    /// Lines played before the track starts over, the steps past it are silent.
+    pub length: usize,
+    pub shift: usize,
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub struct Pattern {
+    pub id: PatternId,
     pub num_lines: usize,
     tracks: Vec<Track>,
 }
 
-impl Default for Pattern {
-    fn default() -> Self {
-        let mut tracks = Vec::with_capacity(MAX_TRACKS);
-        for _ in 0..MAX_TRACKS {
-            tracks.push(Track {
+impl Pattern {
+    pub fn new(id: PatternId, track_ids: &[TrackId], num_lines: usize) -> Self {
+        let tracks = track_ids
+            .iter()
+            .map(|id| Track {
+                id: *id,
                 steps: vec![Step::default(); MAX_PATTERN_LENGTH],
+                length: None,
+                shift: 0,
             })
-        }
+            .collect();
         Self {
-            num_lines: 32,
+            id,
+            num_lines: usize::max(1, usize::min(num_lines, MAX_PATTERN_LENGTH)),
             tracks,
         }
     }
+
+    pub fn num_tracks(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn track_id(&self, track: usize) -> TrackId {
+        self.tracks[track].id
+    }
+
+    pub fn step(&self, track: usize, line: usize) -> Step {
+        self.tracks[track].steps[line]
+    }
+
+    pub fn set_step(&mut self, track: usize, line: usize, step: Step) {
+        self.tracks[track].steps[line] = step;
+    }
+
+    /// Transforms the steps of a section of a track. Fails without changing anything when the
+    /// section isn't within the pattern.
+    pub fn transform(
+        &mut self,
+        track: usize,
+        section: Section,
+        transform: Transform,
+    ) -> Result<()> {
+        if section.start >= section.end || section.end > self.num_lines {
+            return Err(anyhow!(
+                "section {} is outside of the pattern, which has {} lines",
+                section,
+                self.num_lines
+            ));
+        }
+        let steps = &mut self.tracks[track].steps[section.start..section.end];
+        match transform {
+            Transform::Transpose(semitones) => {
+                for pitch in steps.iter_mut().filter_map(|step| step.pitch.as_mut()) {
+                    if !ends_note(*pitch) {
+                        *pitch = (*pitch as i32 + semitones).clamp(0, 127) as u8;
+                    }
+                }
+            }
+            Transform::Rotate(lines) => {
+                let lines = lines.rem_euclid(steps.len() as i32) as usize;
+                steps.rotate_right(lines);
+            }
+            Transform::Reverse => {
+                // Played backwards, a late step is early
+                steps.reverse();
+                for step in steps.iter_mut() {
+                    step.offset = -step.offset;
+                }
+            }
+            Transform::InsertLine => {
+                steps.rotate_right(1);
+                steps[0] = Step::default();
+            }
+            Transform::DeleteLine => {
+                steps.rotate_left(1);
+                steps[steps.len() - 1] = Step::default();
+            }
+            Transform::RandomizeTiming(ticks) => {
+                let ticks = ticks.clamp(0, MAX_OFFSET) as u32;
+                // xorshift, seeded with a hash of the steps
+                let mut random = steps.iter().fold(0x811c_9dc5u32, |hash, step| {
+                    let hash = (hash ^ step.pitch.unwrap_or(0) as u32).wrapping_mul(0x0100_0193);
+                    (hash ^ step.offset as u8 as u32).wrapping_mul(0x0100_0193)
+                }) | 1;
+                for step in steps.iter_mut().filter(|step| step.pitch.is_some()) {
+                    random ^= random << 13;
+                    random ^= random >> 17;
+                    random ^= random << 5;
+                    step.offset = (random % (2 * ticks + 1)) as i8 - ticks as i8;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Lines a track plays before starting over. Tracks follow the length of the pattern
+    /// unless they have their own, which is cut to the pattern when it's shorter.
+    pub fn track_length(&self, track: usize) -> usize {
+        match self.tracks[track].length {
+            Some(length) => usize::min(length, self.num_lines),
+            None => self.num_lines,
+        }
+    }
+
+    /// The length of a track when it has its own.
+    pub fn own_length(&self, track: usize) -> Option<usize> {
+        self.tracks[track].length
+    }
+
+    /// Gives a track its own length, or makes it follow the pattern again.
+    pub fn set_track_length(&mut self, track: usize, length: Option<usize>) -> Result<()> {
+        if let Some(length) = length {
+            if length == 0 || length > MAX_PATTERN_LENGTH {
+                return Err(anyhow!(
+                    "track length must be between 1 and {}",
+                    MAX_PATTERN_LENGTH
+                ));
+            }
+        }
+        self.tracks[track].length = length;
+        Ok(())
+    }
+
+    /// Line of a track played at `tick`, counted in lines since the start of the song. Every
+    /// track loops over its own length, so tracks of different lengths drift apart and meet
+    /// again. Tracks which shift play the step as far back as they've shifted since the start.
+    pub fn played_line(&self, track: usize, tick: u64) -> usize {
+        let length = self.track_length(track) as u64;
+        let repeat = tick / length;
+        let shift = repeat % length * (self.tracks[track].shift as u64 % length) % length;
+        ((tick % length + length - shift) % length) as usize
+    }
+
+    /// Lines the steps of a track move later at every repeat of the track.
+    pub fn shift(&self, track: usize) -> usize {
+        self.tracks[track].shift
+    }
+
+    pub fn set_shift(&mut self, track: usize, shift: usize) {
+        self.tracks[track].shift = shift;
+    }
+
+    /// Replaces the steps of a track with `step` on the hits of a Euclidean rhythm, repeated
+    /// over the whole pattern. The track shifts as the rhythm does.
+    pub fn fill_euclid(&mut self, track: usize, euclid: &Euclid, step: Step) {
+        let hits = euclid.hits();
+        let track = &mut self.tracks[track];
+        for (line, s) in track.steps[..self.num_lines].iter_mut().enumerate() {
+            *s = match hits[line % hits.len()] {
+                true => step,
+                false => Step::default(),
+            };
+        }
+        track.shift = euclid.shift;
+    }
+
+    /// The steps of a track as one lane per pad.
+    pub fn drum_lanes(&self, track: usize) -> DrumLanes {
+        DrumLanes::from_steps(&self.tracks[track].steps[..self.num_lines])
+    }
+
+    /// Applies a section operation to every track. Fails without changing anything when the
+    /// section isn't within the pattern or the result wouldn't fit.
+    pub fn rearrange(&mut self, op: SectionOp) -> Result<()> {
+        let section = match op {
+            SectionOp::Duplicate(section) | SectionOp::Move(section, _) => section,
+            SectionOp::Delete(section) => section,
+        };
+        if section.start >= section.end || section.end > self.num_lines {
+            return Err(anyhow!(
+                "section {} is outside of the pattern, which has {} lines",
+                section,
+                self.num_lines
+            ));
+        }
+        match op {
+            SectionOp::Duplicate(section) => {
+                if self.num_lines + section.len() > MAX_PATTERN_LENGTH {
+                    return Err(anyhow!(
+                        "patterns can't be longer than {} lines",
+                        MAX_PATTERN_LENGTH
+                    ));
+                }
+                let lines: Vec<Vec<Step>> = self
+                    .tracks
+                    .iter()
+                    .map(|track| track.steps[section.start..section.end].to_vec())
+                    .collect();
+                self.insert_lines(section.end, &lines);
+            }
+            SectionOp::Move(section, to) => {
+                if to > self.num_lines || (to > section.start && to < section.end) {
+                    return Err(anyhow!("can't move section {} to line {}", section, to));
+                }
+                let lines = self.remove_lines(section);
+                let to = if to >= section.end {
+                    to - section.len()
+                } else {
+                    to
+                };
+                self.insert_lines(to, &lines);
+            }
+            SectionOp::Delete(section) => {
+                self.remove_lines(section);
+                self.num_lines = usize::max(1, self.num_lines);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn resize(&mut self, num_lines: usize, policy: LengthPolicy) -> Result<()> {
+        if num_lines == 0 || num_lines > MAX_PATTERN_LENGTH {
+            return Err(anyhow!(
+                "pattern length must be between 1 and {}",
+                MAX_PATTERN_LENGTH
+            ));
+        }
+        let old_lines = self.num_lines;
+        for track in &mut self.tracks {
+            match policy {
+                LengthPolicy::Truncate | LengthPolicy::Loop => {
+                    for line in usize::min(old_lines, num_lines)..MAX_PATTERN_LENGTH {
+                        track.steps[line] = Step::default();
+                    }
+                    if policy == LengthPolicy::Loop {
+                        for line in old_lines..num_lines {
+                            track.steps[line] = track.steps[line % old_lines];
+                        }
+                    }
+                }
+                LengthPolicy::Stretch => {
+                    let mut steps = vec![Step::default(); MAX_PATTERN_LENGTH];
+                    for (line, step) in track.steps[..old_lines].iter().enumerate() {
+                        // Rounded to the nearest line, the last steps of a pattern shrunk to
+                        // less than half round up past its end and merge into its last line
+                        let to = (line * num_lines * 2 + old_lines) / (old_lines * 2);
+                        let to = usize::min(to, num_lines - 1);
+                        if *step != Step::default() && steps[to] == Step::default() {
+                            steps[to] = *step;
+                        }
+                    }
+                    track.steps = steps;
+                }
+            }
+        }
+        self.num_lines = num_lines;
+        Ok(())
+    }
+
+    /// Removes the lines of a section from every track, returns them per track.
+    fn remove_lines(&mut self, section: Section) -> Vec<Vec<Step>> {
+        self.num_lines -= section.len();
+        self.tracks
+            .iter_mut()
+            .map(|track| {
+                let lines = track.steps.drain(section.start..section.end).collect();
+                track.steps.resize(MAX_PATTERN_LENGTH, Step::default());
+                lines
+            })
+            .collect()
+    }
+
+    /// Inserts lines before `line` in every track. Steps pushed past the maximum pattern
+    /// length are dropped.
+    fn insert_lines(&mut self, line: usize, lines: &[Vec<Step>]) {
+        for (track, lines) in self.tracks.iter_mut().zip(lines) {
+            track.steps.splice(line..line, lines.iter().copied());
+            track.steps.truncate(MAX_PATTERN_LENGTH);
+        }
+        let len = lines.first().map_or(0, Vec::len);
+        self.num_lines = usize::min(self.num_lines + len, MAX_PATTERN_LENGTH);
+    }
 }
 
+#[derive(Clone, Debug, PartialEq)]
 struct Track {
+    id: TrackId,
     steps: Vec<Step>,
+    /// Lines played before starting over, when the track doesn't follow the pattern.
+    length: Option<usize>,
+    /// Lines the steps move later at every repeat of the track, wrapping around, so a
+    /// generated rhythm is played rotated a bit more every time.
+    shift: usize,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Step {
     pub pitch: Option<u8>,
     pub sound: Option<u8>,
+    /// Chord played from the pitch, see `harmony`.
+    pub chord: Option<Chord>,
+    /// Ticks the step is played after its line, or before when negative, so it can be pushed
+    /// or laid back without leaving the grid.
+    pub offset: i8,
 }
 
 impl Default for Step {
@@ -190,12 +899,333 @@ impl Default for Step {
         Self {
             pitch: None,
             sound: None,
+            chord: None,
+            offset: 0,
+        }
+    }
+}
+
+/// A drum track seen as a step sequencer, with one lane of steps per pad. Converts to and from
+/// the track without losing anything, as long as no two lanes hit on the same line.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DrumLanes {
+    /// Sorted by pad.
+    pub lanes: Vec<DrumLane>,
+    /// Steps which don't hit a pad, e.g. note offs, chords and steps off the grid, by line.
+    rest: Vec<(usize, Step)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DrumLane {
+    /// Sound played by the pad, `None` for hits which play the track's own sound.
+    pub pad: Option<u8>,
+    /// Pitch of the hit on every line of the pattern, if there's one.
+    pub hits: Vec<Option<u8>>,
+}
+
+impl DrumLanes {
+    /// Splits the steps of a track into lanes.
+    pub fn from_steps(steps: &[Step]) -> Self {
+        let mut lanes = Self::default();
+        for (line, step) in steps.iter().enumerate() {
+            match step.pitch {
+                Some(pitch) if !ends_note(pitch) && step.chord.is_none() && step.offset == 0 => {
+                    lanes.lane_mut(step.sound, steps.len()).hits[line] = Some(pitch);
+                }
+                _ if *step != Step::default() => lanes.rest.push((line, *step)),
+                _ => {}
+            }
+        }
+        lanes
+    }
+
+    /// Merges the lanes back into the steps of a track.
+    pub fn to_steps(&self, num_lines: usize) -> Result<Vec<Step>> {
+        let mut steps = vec![Step::default(); num_lines];
+        for (line, step) in &self.rest {
+            if let Some(s) = steps.get_mut(*line) {
+                *s = *step;
+            }
+        }
+        for lane in &self.lanes {
+            for (line, pitch) in lane.hits.iter().enumerate().take(num_lines) {
+                if let Some(pitch) = pitch {
+                    if steps[line] != Step::default() {
+                        return Err(anyhow!("more than one step on line {}", line));
+                    }
+                    steps[line] = Step {
+                        pitch: Some(*pitch),
+                        sound: lane.pad,
+                        chord: None,
+                        offset: 0,
+                    };
+                }
+            }
+        }
+        Ok(steps)
+    }
+
+    /// Adds a hit on a line or removes it when there's one. Anything else on that line is
+    /// removed, a track only plays one step at a time.
+    pub fn toggle(&mut self, pad: Option<u8>, line: usize, pitch: u8, num_lines: usize) {
+        let hit = self
+            .lane_mut(pad, num_lines)
+            .hits
+            .get(line)
+            .copied()
+            .flatten();
+        for lane in &mut self.lanes {
+            if let Some(h) = lane.hits.get_mut(line) {
+                *h = None;
+            }
+        }
+        self.rest.retain(|(l, _)| *l != line);
+        if hit.is_none() {
+            if let Some(h) = self.lane_mut(pad, num_lines).hits.get_mut(line) {
+                *h = Some(pitch);
+            }
         }
     }
+
+    fn lane_mut(&mut self, pad: Option<u8>, num_lines: usize) -> &mut DrumLane {
+        let index = match self.lanes.binary_search_by_key(&pad, |lane| lane.pad) {
+            Ok(index) => index,
+            Err(index) => {
+                let hits = vec![None; num_lines];
+                self.lanes.insert(index, DrumLane { pad, hits });
+                index
+            }
+        };
+        &mut self.lanes[index]
+    }
 }
 
 pub struct NoteEvent {
     pub pitch: u8,
     pub sound: u8,
     pub track: u8,
+    /// Index of the note in the chord of its step, 0 for the root and for single notes.
+    pub chord_note: u8,
+    /// Ticks the note plays after its line, see `Step::offset`.
+    pub offset: i8,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A pattern of one track with notes by line.
+    fn pattern(num_lines: usize, notes: &[(usize, u8)]) -> Pattern {
+        let mut pattern = Pattern::new(PatternId(0), &[TrackId(0)], num_lines);
+        for &(line, pitch) in notes {
+            let step = Step {
+                pitch: Some(pitch),
+                ..Step::default()
+            };
+            pattern.set_step(0, line, step);
+        }
+        pattern
+    }
+
+    /// The notes of the track by line, including any past the end of the pattern.
+    fn notes(pattern: &Pattern) -> Vec<(usize, u8)> {
+        (0..MAX_PATTERN_LENGTH)
+            .filter_map(|line| Some((line, pattern.step(0, line).pitch?)))
+            .collect()
+    }
+
+    fn section(start: usize, end: usize) -> Section {
+        Section { start, end }
+    }
+
+    #[test]
+    fn duplicate_inserts_a_copy_after_the_section() {
+        let mut pattern = pattern(4, &[(0, 48), (1, 50), (3, 52)]);
+        pattern
+            .rearrange(SectionOp::Duplicate(section(0, 2)))
+            .unwrap();
+        assert_eq!(pattern.num_lines, 6);
+        assert_eq!(
+            notes(&pattern),
+            [(0, 48), (1, 50), (2, 48), (3, 50), (5, 52)]
+        );
+    }
+
+    #[test]
+    fn move_shifts_what_follows() {
+        let mut pattern = pattern(6, &[(0, 48), (1, 50), (4, 52)]);
+        // Line numbers are from before the move
+        pattern
+            .rearrange(SectionOp::Move(section(0, 2), 5))
+            .unwrap();
+        assert_eq!(pattern.num_lines, 6);
+        assert_eq!(notes(&pattern), [(2, 52), (3, 48), (4, 50)]);
+        pattern
+            .rearrange(SectionOp::Move(section(3, 5), 0))
+            .unwrap();
+        assert_eq!(notes(&pattern), [(0, 48), (1, 50), (4, 52)]);
+    }
+
+    #[test]
+    fn delete_closes_the_gap() {
+        let mut pattern = pattern(6, &[(0, 48), (2, 50), (5, 52)]);
+        pattern.rearrange(SectionOp::Delete(section(1, 3))).unwrap();
+        assert_eq!(pattern.num_lines, 4);
+        assert_eq!(notes(&pattern), [(0, 48), (3, 52)]);
+        // A pattern keeps a line
+        pattern.rearrange(SectionOp::Delete(section(0, 4))).unwrap();
+        assert_eq!(pattern.num_lines, 1);
+        assert_eq!(notes(&pattern), []);
+    }
+
+    #[test]
+    fn rearranging_outside_the_pattern_is_an_error() {
+        let mut pattern = pattern(4, &[(0, 48)]);
+        let before = pattern.clone();
+        assert!(pattern.rearrange(SectionOp::Delete(section(2, 5))).is_err());
+        assert!(pattern
+            .rearrange(SectionOp::Move(section(0, 2), 1))
+            .is_err());
+        let mut long = pattern.clone();
+        long.resize(MAX_PATTERN_LENGTH, LengthPolicy::Truncate)
+            .unwrap();
+        assert!(long.rearrange(SectionOp::Duplicate(section(0, 1))).is_err());
+        assert_eq!(pattern, before);
+    }
+
+    #[test]
+    fn transpose_keeps_pitches_in_range() {
+        let mut pattern = pattern(4, &[(0, 48), (1, 125), (2, NOTE_OFF)]);
+        pattern
+            .transform(0, section(0, 4), Transform::Transpose(5))
+            .unwrap();
+        assert_eq!(notes(&pattern), [(0, 53), (1, 127), (2, NOTE_OFF)]);
+    }
+
+    #[test]
+    fn rotate_wraps_within_the_section() {
+        let mut pattern = pattern(6, &[(0, 48), (1, 50), (2, 52), (5, 53)]);
+        pattern
+            .transform(0, section(0, 3), Transform::Rotate(-1))
+            .unwrap();
+        assert_eq!(notes(&pattern), [(0, 50), (1, 52), (2, 48), (5, 53)]);
+        pattern
+            .transform(0, section(0, 3), Transform::Rotate(4))
+            .unwrap();
+        assert_eq!(notes(&pattern), [(0, 48), (1, 50), (2, 52), (5, 53)]);
+    }
+
+    #[test]
+    fn reverse_mirrors_offsets() {
+        let mut pattern = pattern(4, &[(0, 48), (1, 50)]);
+        pattern.tracks[0].steps[1].offset = 3;
+        pattern
+            .transform(0, section(0, 4), Transform::Reverse)
+            .unwrap();
+        assert_eq!(notes(&pattern), [(2, 50), (3, 48)]);
+        assert_eq!(pattern.step(0, 2).offset, -3);
+    }
+
+    #[test]
+    fn insert_and_delete_shift_the_section() {
+        let mut pattern = pattern(4, &[(0, 48), (2, 50), (3, 52)]);
+        pattern
+            .transform(0, section(0, 3), Transform::InsertLine)
+            .unwrap();
+        // The step pushed out of the section is dropped
+        assert_eq!(notes(&pattern), [(1, 48), (3, 52)]);
+        pattern
+            .transform(0, section(0, 4), Transform::DeleteLine)
+            .unwrap();
+        assert_eq!(notes(&pattern), [(0, 48), (2, 52)]);
+    }
+
+    #[test]
+    fn randomize_timing_stays_within_bounds() {
+        let notes: Vec<_> = (0..16).map(|line| (line, 48)).collect();
+        let mut pattern = pattern(32, &notes);
+        let offsets = |pattern: &Pattern| -> Vec<i8> {
+            (0..32).map(|line| pattern.step(0, line).offset).collect()
+        };
+        let transform = Transform::RandomizeTiming(i8::MAX);
+        pattern.transform(0, section(0, 32), transform).unwrap();
+        let randomized = offsets(&pattern);
+        assert!(randomized.iter().all(|offset| offset.abs() <= MAX_OFFSET));
+        assert!(randomized[..16].iter().any(|offset| *offset != 0));
+        // Empty steps stay on the grid
+        assert!(randomized[16..].iter().all(|offset| *offset == 0));
+
+        let mut again = pattern.clone();
+        again.transform(0, section(0, 32), transform).unwrap();
+        assert_ne!(offsets(&again), randomized);
+        pattern.transform(0, section(0, 32), transform).unwrap();
+        assert_eq!(offsets(&pattern), offsets(&again));
+
+        let transform = Transform::RandomizeTiming(0);
+        pattern.transform(0, section(0, 32), transform).unwrap();
+        assert!(offsets(&pattern).iter().all(|offset| *offset == 0));
+    }
+
+    #[test]
+    fn transforming_outside_the_pattern_is_an_error() {
+        let mut pattern = pattern(4, &[(0, 48)]);
+        let before = pattern.clone();
+        let transform = Transform::Transpose(1);
+        assert!(pattern.transform(0, section(2, 5), transform).is_err());
+        assert_eq!(pattern, before);
+    }
+
+    #[test]
+    fn truncate_clears_steps_past_the_end() {
+        let mut pattern = pattern(8, &[(0, 48), (3, 50), (6, 52)]);
+        pattern.resize(4, LengthPolicy::Truncate).unwrap();
+        assert_eq!(notes(&pattern), [(0, 48), (3, 50)]);
+        pattern.resize(8, LengthPolicy::Truncate).unwrap();
+        assert_eq!(notes(&pattern), [(0, 48), (3, 50)]);
+    }
+
+    #[test]
+    fn loop_repeats_the_pattern() {
+        let mut pattern = pattern(3, &[(0, 48), (2, 50)]);
+        pattern.resize(7, LengthPolicy::Loop).unwrap();
+        assert_eq!(
+            notes(&pattern),
+            [(0, 48), (2, 50), (3, 48), (5, 50), (6, 48)]
+        );
+    }
+
+    #[test]
+    fn stretch_spreads_steps_when_growing() {
+        let mut pattern = pattern(4, &[(0, 48), (1, 50), (3, 52)]);
+        pattern.resize(8, LengthPolicy::Stretch).unwrap();
+        assert_eq!(notes(&pattern), [(0, 48), (2, 50), (6, 52)]);
+    }
+
+    #[test]
+    fn stretch_merges_steps_when_shrinking() {
+        let mut pattern = pattern(8, &[(0, 48), (5, 50), (6, 52), (7, 53)]);
+        pattern.resize(4, LengthPolicy::Stretch).unwrap();
+        // Lines 5 to 7 land on line 3, the earliest wins
+        assert_eq!(notes(&pattern), [(0, 48), (3, 50)]);
+    }
+
+    #[test]
+    fn stretch_keeps_the_last_line_when_halving() {
+        let mut halved = pattern(32, &[(0, 48), (31, 50)]);
+        halved.resize(16, LengthPolicy::Stretch).unwrap();
+        assert_eq!(notes(&halved), [(0, 48), (15, 50)]);
+        let mut single = pattern(64, &[(63, 50)]);
+        single.resize(1, LengthPolicy::Stretch).unwrap();
+        assert_eq!(notes(&single), [(0, 50)]);
+    }
+
+    #[test]
+    fn resizing_out_of_range_is_an_error() {
+        let mut pattern = pattern(4, &[(0, 48)]);
+        assert!(pattern.resize(0, LengthPolicy::Stretch).is_err());
+        assert!(pattern
+            .resize(MAX_PATTERN_LENGTH + 1, LengthPolicy::Loop)
+            .is_err());
+        assert_eq!(pattern.num_lines, 4);
+    }
 }
@@ -0,0 +1,74 @@
+use crate::pattern::{Pattern, Step, MAX_PATTERN_LENGTH, NOTE_OFF};
+
+/// Records the mutes toggled while the song plays and turns the take into a linear pattern,
+/// so a good live performance becomes an arrangement which can be edited.
+pub struct Performance {
+    /// Tick of every line played since the take started, in order. Usually consecutive, but
+    /// playback can be relocated.
+    ticks: Vec<usize>,
+    /// Whether each track was muted when the take started.
+    muted: Vec<bool>,
+    /// Line of the take, track and whether it's muted from then on.
+    changes: Vec<(usize, usize, bool)>,
+}
+
+impl Performance {
+    pub fn start(muted: Vec<bool>) -> Self {
+        Self {
+            ticks: Vec::with_capacity(MAX_PATTERN_LENGTH),
+            muted,
+            changes: Vec::new(),
+        }
+    }
+
+    /// Called for every line played, takes longer than a pattern can hold are cut.
+    pub fn set_tick(&mut self, tick: usize) {
+        if self.ticks.len() < MAX_PATTERN_LENGTH {
+            self.ticks.push(tick);
+        }
+    }
+
+    /// Records a mute or unmute. It's quantized to the next line, the current one has already
+    /// been played.
+    pub fn record_mute(&mut self, track: usize, muted: bool) {
+        self.changes.push((self.ticks.len(), track, muted));
+    }
+
+    /// Number of lines played since the take started.
+    pub fn num_lines(&self) -> usize {
+        self.ticks.len()
+    }
+
+    /// Plays the take back from `pattern`, which was looping while it was recorded. Returns
+    /// the steps of every track, leaving out the notes of muted tracks and releasing the note
+    /// playing when a track was muted.
+    pub fn arrange(&self, pattern: &Pattern) -> Vec<Vec<Step>> {
+        let mut muted = self.muted.clone();
+        muted.resize(pattern.num_tracks(), false);
+        let mut tracks = vec![Vec::with_capacity(self.num_lines()); pattern.num_tracks()];
+        let mut changes = self.changes.iter().peekable();
+        for (line, tick) in self.ticks.iter().enumerate() {
+            let mut muting = vec![false; muted.len()];
+            while let Some((_, track, mute)) = changes.next_if(|(l, _, _)| *l <= line) {
+                if let Some(m) = muted.get_mut(*track) {
+                    muting[*track] = *mute && !*m;
+                    *m = *mute;
+                }
+            }
+            for (track, steps) in tracks.iter_mut().enumerate() {
+                let step = match (muting[track], muted[track]) {
+                    (true, _) => Step {
+                        pitch: Some(NOTE_OFF),
+                        sound: None,
+                        chord: None,
+                        offset: 0,
+                    },
+                    (false, true) => Step::default(),
+                    (false, false) => pattern.step(track, pattern.played_line(track, *tick as u64)),
+                };
+                steps.push(step);
+            }
+        }
+        tracks
+    }
+}
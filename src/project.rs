@@ -0,0 +1,585 @@
+use crate::harmony::{Chord, Scale, NUM_VOICINGS};
+use crate::id::{InstrumentId, PatternId, TrackId};
+use crate::instrument::Options;
+use crate::json::Value;
+use crate::lfo::{Rate, Shape};
+use crate::mixer::Source;
+use crate::pattern::{Pattern, Step, MAX_OFFSET, MAX_PATTERN_LENGTH, MAX_TRACKS};
+use crate::stretch::Key;
+use anyhow::{anyhow, Result};
+use camino::Utf8Path;
+use std::fs;
+
+const VERSION: usize = 2;
+
+pub struct InstrumentConfig {
+    pub id: InstrumentId,
+    pub kind: String,
+    pub options: Options,
+    pub params: Vec<(String, f32)>,
+    pub modulation: ModulationConfig,
+    /// Output trim in dB.
+    pub trim: f32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ModulationConfig {
+    pub lfos: Vec<(Shape, Rate)>,
+    /// LFO, param name and depth of every route.
+    pub routes: Vec<(usize, String, f32)>,
+    pub per_sample: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EffectConfig {
+    pub kind: String,
+    pub options: Options,
+    pub params: Vec<(String, f32)>,
+    pub bypass: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SendConfig {
+    pub level: f32,
+    pub pre_fader: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChannelConfig {
+    pub gain: f32,
+    pub pan: f32,
+    pub mute: bool,
+    pub solo: bool,
+    pub effects: Vec<EffectConfig>,
+    /// Sends per aux bus, missing sends are off.
+    pub sends: Vec<SendConfig>,
+    pub source: Source,
+}
+
+/// Everything needed to restore a song, stored as JSON.
+pub struct Project {
+    pub bpm: u16,
+    pub lines_per_beat: u16,
+    pub octave: u16,
+    pub key: Option<Key>,
+    /// Scale of the key, when it's neither its plain major nor minor.
+    pub scale: Option<Scale>,
+    /// User defined chord voicings, as semitones above the note of the step.
+    pub voicings: [Vec<i8>; NUM_VOICINGS],
+    pub instruments: Vec<Option<InstrumentConfig>>,
+    pub mixer: Vec<ChannelConfig>,
+    /// Switches of the master output stage.
+    pub dc_block: bool,
+    pub clip: bool,
+    pub track_ids: Vec<TrackId>,
+    pub patterns: Vec<Pattern>,
+    pub current_pattern: usize,
+}
+
+impl Project {
+    pub fn save(&self, path: &Utf8Path) -> Result<()> {
+        fs::write(path, self.to_text()?)?;
+        Ok(())
+    }
+
+    /// The project as saved to a file.
+    pub fn to_text(&self) -> Result<String> {
+        self.to_json().to_pretty_string()
+    }
+
+    /// A description of the song for crash reports: its structure without steps or sounds.
+    pub fn summary(&self) -> String {
+        let lengths: Vec<String> = self
+            .patterns
+            .iter()
+            .map(|pattern| pattern.num_lines.to_string())
+            .collect();
+        let mut lines = vec![
+            format!(
+                "{} bpm, {} lines per beat, {} tracks",
+                self.bpm,
+                self.lines_per_beat,
+                self.track_ids.len()
+            ),
+            format!(
+                "patterns of {} lines, editing {}",
+                lengths.join(", "),
+                self.current_pattern
+            ),
+        ];
+        for (i, config) in self.instruments.iter().enumerate() {
+            if let Some(config) = config {
+                let options: Vec<String> = config
+                    .options
+                    .iter()
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect();
+                lines.push(format!(
+                    "instrument {}: {} {}",
+                    i,
+                    config.kind,
+                    options.join(" ")
+                ));
+            }
+        }
+        for (i, channel) in self.mixer.iter().enumerate() {
+            if !channel.effects.is_empty() {
+                let effects: Vec<&str> = channel.effects.iter().map(|e| e.kind.as_str()).collect();
+                lines.push(format!("channel {} effects: {}", i, effects.join(", ")));
+            }
+        }
+        lines.join("\n")
+    }
+
+    pub fn load(path: &Utf8Path) -> Result<Project> {
+        let data = fs::read_to_string(path)?;
+        Self::parse(&data).map_err(|err| anyhow!("{}: {}", path, err))
+    }
+
+    pub fn parse(data: &str) -> Result<Project> {
+        Self::from_json(&Value::parse(data)?)
+    }
+
+    fn to_json(&self) -> Value {
+        let instruments = self
+            .instruments
+            .iter()
+            .map(|instrument| match instrument {
+                Some(instrument) => Value::Object(vec![
+                    ("id".into(), (instrument.id.0 as usize).into()),
+                    ("kind".into(), instrument.kind.as_str().into()),
+                    ("options".into(), options_to_json(&instrument.options)),
+                    ("params".into(), params_to_json(&instrument.params)),
+                    (
+                        "modulation".into(),
+                        modulation_to_json(&instrument.modulation),
+                    ),
+                    ("trim".into(), (instrument.trim as f64).into()),
+                ]),
+                None => Value::Null,
+            })
+            .collect();
+
+        let mixer = self
+            .mixer
+            .iter()
+            .map(|channel| {
+                let effects = channel
+                    .effects
+                    .iter()
+                    .map(|effect| {
+                        Value::Object(vec![
+                            ("kind".into(), effect.kind.as_str().into()),
+                            ("options".into(), options_to_json(&effect.options)),
+                            ("params".into(), params_to_json(&effect.params)),
+                            ("bypass".into(), effect.bypass.into()),
+                        ])
+                    })
+                    .collect();
+                let sends = channel
+                    .sends
+                    .iter()
+                    .map(|send| {
+                        Value::Object(vec![
+                            ("level".into(), (send.level as f64).into()),
+                            ("pre_fader".into(), send.pre_fader.into()),
+                        ])
+                    })
+                    .collect();
+                Value::Object(vec![
+                    ("gain".into(), (channel.gain as f64).into()),
+                    ("pan".into(), (channel.pan as f64).into()),
+                    ("mute".into(), channel.mute.into()),
+                    ("solo".into(), channel.solo.into()),
+                    ("effects".into(), Value::Array(effects)),
+                    ("sends".into(), Value::Array(sends)),
+                    ("source".into(), channel.source.name().as_str().into()),
+                ])
+            })
+            .collect();
+
+        let patterns = self
+            .patterns
+            .iter()
+            .map(|pattern| {
+                let tracks = (0..pattern.num_tracks())
+                    .map(|track| {
+                        // Only steps with content are stored, as [line, pitch, sound] followed
+                        // by the chord when there's one, and by the offset when the step is
+                        // off the grid
+                        let steps = (0..pattern.num_lines)
+                            .filter_map(|line| {
+                                let step = pattern.step(track, line);
+                                if step == Step::default() {
+                                    return None;
+                                }
+                                let mut values =
+                                    vec![line.into(), optional(step.pitch), optional(step.sound)];
+                                let chord = step.chord.map(|chord| chord.name());
+                                if chord.is_some() || step.offset != 0 {
+                                    values.push(chord.as_deref().map_or(Value::Null, Value::from));
+                                }
+                                if step.offset != 0 {
+                                    values.push((step.offset as f64).into());
+                                }
+                                Some(Value::Array(values))
+                            })
+                            .collect();
+                        Value::Array(steps)
+                    })
+                    .collect();
+                let lengths = (0..pattern.num_tracks())
+                    .map(|track| pattern.own_length(track).map_or(Value::Null, Value::from))
+                    .collect();
+                let shifts = (0..pattern.num_tracks())
+                    .map(|track| pattern.shift(track).into())
+                    .collect();
+                Value::Object(vec![
+                    ("id".into(), (pattern.id.0 as usize).into()),
+                    ("lines".into(), pattern.num_lines.into()),
+                    ("tracks".into(), Value::Array(tracks)),
+                    ("lengths".into(), Value::Array(lengths)),
+                    ("shifts".into(), Value::Array(shifts)),
+                ])
+            })
+            .collect();
+
+        Value::Object(vec![
+            ("version".into(), VERSION.into()),
+            ("bpm".into(), (self.bpm as usize).into()),
+            (
+                "lines_per_beat".into(),
+                (self.lines_per_beat as usize).into(),
+            ),
+            ("octave".into(), (self.octave as usize).into()),
+            (
+                "key".into(),
+                match self.key {
+                    Some(key) => key.name().as_str().into(),
+                    None => Value::Null,
+                },
+            ),
+            (
+                "scale".into(),
+                match self.scale {
+                    Some(scale) => scale.name().into(),
+                    None => Value::Null,
+                },
+            ),
+            (
+                "voicings".into(),
+                Value::Array(
+                    self.voicings
+                        .iter()
+                        .map(|voicing| {
+                            let notes = voicing.iter().map(|n| (*n as f64).into()).collect();
+                            Value::Array(notes)
+                        })
+                        .collect(),
+                ),
+            ),
+            ("instruments".into(), Value::Array(instruments)),
+            ("mixer".into(), Value::Array(mixer)),
+            ("dc_block".into(), self.dc_block.into()),
+            ("clip".into(), self.clip.into()),
+            (
+                "tracks".into(),
+                Value::Array(
+                    self.track_ids
+                        .iter()
+                        .map(|id| (id.0 as usize).into())
+                        .collect(),
+                ),
+            ),
+            ("patterns".into(), Value::Array(patterns)),
+            ("current_pattern".into(), self.current_pattern.into()),
+        ])
+    }
+
+    fn from_json(json: &Value) -> Result<Project> {
+        let version = json.field("version")?.as_usize()?;
+        if version > VERSION {
+            return Err(anyhow!("unsupported project version {}", version));
+        }
+
+        // Version 1 projects have no ids, they are generated from the positions instead.
+        let id = |value: &Value, index: usize| -> Result<u32> {
+            match value.get("id") {
+                Some(id) => Ok(id.as_usize()? as u32),
+                None if version < 2 => Ok(index as u32),
+                None => Err(anyhow!("missing field id")),
+            }
+        };
+
+        let mut instruments = Vec::new();
+        for (i, instrument) in json.field("instruments")?.as_array()?.iter().enumerate() {
+            if instrument.is_null() {
+                instruments.push(None);
+                continue;
+            }
+            instruments.push(Some(InstrumentConfig {
+                id: InstrumentId(id(instrument, i)?),
+                kind: instrument.field("kind")?.as_str()?.to_string(),
+                options: options_from_json(instrument.field("options")?)?,
+                params: params_from_json(instrument.field("params")?)?,
+                modulation: match instrument.get("modulation") {
+                    Some(modulation) => modulation_from_json(modulation)?,
+                    None => ModulationConfig::default(),
+                },
+                trim: match instrument.get("trim") {
+                    Some(trim) => trim.as_f64()? as f32,
+                    None => 0.0,
+                },
+            }));
+        }
+
+        // Projects saved before the mixer existed use the default channel settings.
+        let mut mixer = Vec::new();
+        if let Some(channels) = json.get("mixer") {
+            for channel in channels.as_array()? {
+                let mut effects = Vec::new();
+                if let Some(chain) = channel.get("effects") {
+                    for effect in chain.as_array()? {
+                        effects.push(EffectConfig {
+                            kind: effect.field("kind")?.as_str()?.to_string(),
+                            options: options_from_json(effect.field("options")?)?,
+                            params: params_from_json(effect.field("params")?)?,
+                            bypass: effect.field("bypass")?.as_bool()?,
+                        });
+                    }
+                }
+                let mut sends = Vec::new();
+                if let Some(json) = channel.get("sends") {
+                    for send in json.as_array()? {
+                        sends.push(SendConfig {
+                            level: send.field("level")?.as_f64()? as f32,
+                            pre_fader: send.field("pre_fader")?.as_bool()?,
+                        });
+                    }
+                }
+                mixer.push(ChannelConfig {
+                    gain: channel.field("gain")?.as_f64()? as f32,
+                    pan: channel.field("pan")?.as_f64()? as f32,
+                    mute: channel.field("mute")?.as_bool()?,
+                    solo: channel.field("solo")?.as_bool()?,
+                    effects,
+                    sends,
+                    source: match channel.get("source") {
+                        Some(source) => Source::parse(source.as_str()?)?,
+                        None => Source::Instrument,
+                    },
+                });
+            }
+        }
+
+        let track_ids: Vec<TrackId> = match json.get("tracks") {
+            Some(ids) => ids
+                .as_array()?
+                .iter()
+                .map(|id| Ok(TrackId(id.as_usize()? as u32)))
+                .collect::<Result<_>>()?,
+            None if version < 2 => (0..MAX_TRACKS).map(|i| TrackId(i as u32)).collect(),
+            None => return Err(anyhow!("missing field tracks")),
+        };
+        if track_ids.len() != MAX_TRACKS {
+            return Err(anyhow!("expected {} tracks", MAX_TRACKS));
+        }
+
+        let mut voicings: [Vec<i8>; NUM_VOICINGS] = Default::default();
+        if let Some(json) = json.get("voicings") {
+            for (voicing, notes) in voicings.iter_mut().zip(json.as_array()?) {
+                for note in notes.as_array()? {
+                    voicing.push(note.as_f64()? as i8);
+                }
+            }
+        }
+
+        let mut patterns = Vec::new();
+        for (i, json) in json.field("patterns")?.as_array()?.iter().enumerate() {
+            // Keep generated pattern ids clear of the generated track ids
+            let pattern_id = PatternId(id(json, MAX_TRACKS + i)?);
+            let num_lines = json.field("lines")?.as_usize()?;
+            let mut pattern = Pattern::new(pattern_id, &track_ids, num_lines);
+            for (track, steps) in json.field("tracks")?.as_array()?.iter().enumerate() {
+                if track >= pattern.num_tracks() {
+                    return Err(anyhow!("too many tracks in pattern"));
+                }
+                for step in steps.as_array()? {
+                    let (line, pitch, sound, rest) = match step.as_array()? {
+                        [line, pitch, sound, rest @ ..] if rest.len() <= 2 => {
+                            (line.as_usize()?, pitch, sound, rest)
+                        }
+                        _ => return Err(anyhow!("invalid step")),
+                    };
+                    let chord = match rest.first() {
+                        Some(chord) if !chord.is_null() => Some(Chord::parse(chord.as_str()?)?),
+                        _ => None,
+                    };
+                    let offset = match rest.get(1) {
+                        Some(offset) => offset.as_f64()?,
+                        None => 0.0,
+                    };
+                    if offset.abs() > MAX_OFFSET as f64 {
+                        return Err(anyhow!("step offset {} out of range", offset));
+                    }
+                    if line >= MAX_PATTERN_LENGTH {
+                        return Err(anyhow!("step line {} out of range", line));
+                    }
+                    let step = Step {
+                        pitch: optional_u8(pitch)?,
+                        sound: optional_u8(sound)?,
+                        chord,
+                        offset: offset as i8,
+                    };
+                    pattern.set_step(track, line, step);
+                }
+            }
+            if let Some(lengths) = json.get("lengths") {
+                for (track, length) in lengths.as_array()?.iter().enumerate() {
+                    if track >= pattern.num_tracks() {
+                        return Err(anyhow!("too many tracks in pattern"));
+                    }
+                    let length = match length {
+                        Value::Null => None,
+                        length => Some(length.as_usize()?),
+                    };
+                    pattern.set_track_length(track, length)?;
+                }
+            }
+            if let Some(shifts) = json.get("shifts") {
+                for (track, shift) in shifts.as_array()?.iter().enumerate() {
+                    if track >= pattern.num_tracks() {
+                        return Err(anyhow!("too many tracks in pattern"));
+                    }
+                    pattern.set_shift(track, shift.as_usize()?);
+                }
+            }
+            patterns.push(pattern);
+        }
+
+        Ok(Project {
+            bpm: json.field("bpm")?.as_usize()? as u16,
+            lines_per_beat: json.field("lines_per_beat")?.as_usize()? as u16,
+            octave: json.field("octave")?.as_usize()? as u16,
+            key: match json.get("key") {
+                Some(Value::Null) | None => None,
+                Some(key) => Some(Key::parse(key.as_str()?)?),
+            },
+            scale: match json.get("scale") {
+                Some(Value::Null) | None => None,
+                Some(scale) => Some(Scale::parse(scale.as_str()?)?),
+            },
+            voicings,
+            instruments,
+            mixer,
+            dc_block: match json.get("dc_block") {
+                Some(dc_block) => dc_block.as_bool()?,
+                None => true,
+            },
+            clip: match json.get("clip") {
+                Some(clip) => clip.as_bool()?,
+                None => false,
+            },
+            track_ids,
+            patterns,
+            current_pattern: json.field("current_pattern")?.as_usize()?,
+        })
+    }
+}
+
+fn modulation_to_json(modulation: &ModulationConfig) -> Value {
+    let lfos = modulation
+        .lfos
+        .iter()
+        .map(|(shape, rate)| {
+            Value::Object(vec![
+                ("shape".into(), shape.name().into()),
+                ("rate".into(), rate.name().as_str().into()),
+            ])
+        })
+        .collect();
+    let routes = modulation
+        .routes
+        .iter()
+        .map(|(lfo, param, depth)| {
+            Value::Object(vec![
+                ("lfo".into(), (lfo + 1).into()),
+                ("param".into(), param.as_str().into()),
+                ("depth".into(), (*depth as f64).into()),
+            ])
+        })
+        .collect();
+    Value::Object(vec![
+        ("lfos".into(), Value::Array(lfos)),
+        ("routes".into(), Value::Array(routes)),
+        ("per_sample".into(), modulation.per_sample.into()),
+    ])
+}
+
+fn modulation_from_json(json: &Value) -> Result<ModulationConfig> {
+    let mut modulation = ModulationConfig::default();
+    for lfo in json.field("lfos")?.as_array()? {
+        let shape = Shape::parse(lfo.field("shape")?.as_str()?)?;
+        let rate = Rate::parse(lfo.field("rate")?.as_str()?)?;
+        modulation.lfos.push((shape, rate));
+    }
+    for route in json.field("routes")?.as_array()? {
+        let lfo = route.field("lfo")?.as_usize()?;
+        if lfo == 0 {
+            return Err(anyhow!("invalid LFO 0"));
+        }
+        let param = route.field("param")?.as_str()?.to_string();
+        let depth = route.field("depth")?.as_f64()? as f32;
+        modulation.routes.push((lfo - 1, param, depth));
+    }
+    modulation.per_sample = json.field("per_sample")?.as_bool()?;
+    Ok(modulation)
+}
+
+fn options_to_json(options: &Options) -> Value {
+    Value::Object(
+        options
+            .iter()
+            .map(|(k, v)| (k.clone(), v.as_str().into()))
+            .collect(),
+    )
+}
+
+fn options_from_json(json: &Value) -> Result<Options> {
+    let mut options = Options::default();
+    for (key, value) in json.as_object()? {
+        options.set(key.as_str(), value.as_str()?);
+    }
+    Ok(options)
+}
+
+fn params_to_json(params: &[(String, f32)]) -> Value {
+    Value::Object(
+        params
+            .iter()
+            .map(|(k, v)| (k.clone(), (*v as f64).into()))
+            .collect(),
+    )
+}
+
+fn params_from_json(json: &Value) -> Result<Vec<(String, f32)>> {
+    json.as_object()?
+        .iter()
+        .map(|(key, value)| Ok((key.clone(), value.as_f64()? as f32)))
+        .collect()
+}
+
+fn optional(value: Option<u8>) -> Value {
+    value.map_or(Value::Null, |v| (v as usize).into())
+}
+
+fn optional_u8(value: &Value) -> Result<Option<u8>> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    let v = value.as_usize()?;
+    if v > u8::MAX as usize {
+        return Err(anyhow!("value {} out of range", v));
+    }
+    Ok(Some(v as u8))
+}
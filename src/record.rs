@@ -0,0 +1,325 @@
+use crate::pattern::{Editor, NOTE_OFF, TICKS_PER_LINE};
+use anyhow::{anyhow, Result};
+use std::time::Instant;
+
+/// What happens to notes which are still held when loop recording wraps around.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LoopPolicy {
+    /// End the note at the loop boundary.
+    Truncate,
+    /// End the note at the loop boundary and continue it from the start of the loop.
+    Wrap,
+    /// Grow the take by another loop length until all notes are released.
+    Extend,
+}
+
+impl LoopPolicy {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "truncate" => Ok(LoopPolicy::Truncate),
+            "wrap" => Ok(LoopPolicy::Wrap),
+            "extend" => Ok(LoopPolicy::Extend),
+            _ => Err(anyhow!(
+                "unknown loop policy {}, expected truncate, wrap or extend",
+                name
+            )),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RecordedNote {
+    pub pitch: u8,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Captures notes played during loop recording. All positions are in frames relative to the
+/// start of the loop, the recorder clock is moved forward with `advance`.
+pub struct Recorder {
+    pub policy: LoopPolicy,
+    loop_len: u64,
+    take_len: u64,
+    position: u64,
+    held: Vec<(u8, u64)>,
+    notes: Vec<RecordedNote>,
+}
+
+impl Recorder {
+    pub fn new(loop_len: u64, policy: LoopPolicy) -> Self {
+        Self {
+            policy,
+            loop_len: u64::max(loop_len, 1),
+            take_len: u64::max(loop_len, 1),
+            position: 0,
+            held: Vec::with_capacity(16),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn notes(&self) -> &[RecordedNote] {
+        &self.notes
+    }
+
+    pub fn note_on(&mut self, pitch: u8) {
+        self.note_off(pitch);
+        self.held.push((pitch, self.position));
+    }
+
+    pub fn note_off(&mut self, pitch: u8) {
+        if let Some(index) = self.held.iter().position(|(p, _)| *p == pitch) {
+            let (pitch, start) = self.held.remove(index);
+            self.notes.push(RecordedNote {
+                pitch,
+                start,
+                end: self.position,
+            });
+        }
+    }
+
+    /// Moves the recorder clock forward, handling every loop boundary crossed on the way.
+    pub fn advance(&mut self, mut frames: u64) {
+        while self.position + frames >= self.take_len {
+            frames -= self.take_len - self.position;
+            self.position = self.take_len;
+            self.wrap();
+        }
+        self.position += frames;
+    }
+
+    fn wrap(&mut self) {
+        if self.held.is_empty() {
+            self.position = 0;
+            return;
+        }
+        match self.policy {
+            LoopPolicy::Truncate => {
+                for (pitch, start) in self.held.drain(..) {
+                    self.notes.push(RecordedNote {
+                        pitch,
+                        start,
+                        end: self.take_len,
+                    });
+                }
+                self.position = 0;
+            }
+            LoopPolicy::Wrap => {
+                for (pitch, start) in &mut self.held {
+                    self.notes.push(RecordedNote {
+                        pitch: *pitch,
+                        start: *start,
+                        end: self.take_len,
+                    });
+                    *start = 0;
+                }
+                self.position = 0;
+            }
+            LoopPolicy::Extend => {
+                self.take_len += self.loop_len;
+            }
+        }
+    }
+
+    /// Ends all held notes at the current position.
+    pub fn finish(&mut self) {
+        let end = self.position;
+        for (pitch, start) in self.held.drain(..) {
+            self.notes.push(RecordedNote { pitch, start, end });
+        }
+    }
+
+    /// Writes the recorded notes into a track of the current pattern. `strength` is how far
+    /// they're moved onto the lines, from 0 which keeps the timing as played off the grid to 1
+    /// which puts them on the nearest line. A note off ends every note, unless the next one
+    /// starts first. When the take was extended the pattern grows to fit.
+    pub fn write(&self, editor: &mut Editor, track: usize, frames_per_line: f64, strength: f32) {
+        let strength = strength.clamp(0.0, 1.0) as f64;
+        let to_line = |frame: u64| {
+            let position = frame as f64 / frames_per_line;
+            let line = position.round();
+            let ticks = (position - line) * (1.0 - strength) * TICKS_PER_LINE as f64;
+            (line as usize, ticks.round() as i8)
+        };
+        let num_lines = usize::max(editor.num_lines(), to_line(self.take_len).0);
+        editor.set_num_lines(num_lines);
+        let num_lines = editor.num_lines();
+
+        let mut notes = self.notes.clone();
+        notes.sort_by_key(|note| note.start);
+        for (i, note) in notes.iter().enumerate() {
+            let (start, offset) = to_line(note.start);
+            let (mut end, mut end_offset) = to_line(note.end);
+            if end <= start {
+                end = start + 1;
+                end_offset = 0;
+            }
+            editor.write_note(track, start % num_lines, note.pitch, offset);
+            let next = notes.get(i + 1).map(|next| to_line(next.start).0);
+            if end < num_lines && next.is_none_or(|next| next > end) {
+                editor.write_note(track, end, NOTE_OFF, end_offset);
+            }
+        }
+    }
+}
+
+/// A take recorded along the pattern as it plays, into a track of the current pattern. The
+/// recorder's clock follows the lines played and the time since the last one started, the
+/// pattern loops around as the take goes on.
+pub struct LiveTake {
+    recorder: Recorder,
+    track: usize,
+    frames_per_line: f64,
+    sample_rate: f64,
+    /// Line of the pattern the take started at, and the tick it was played at.
+    start: (usize, usize),
+    /// Tick of the line playing and when it started.
+    tick: (usize, Instant),
+    /// Frames the recorder was moved forward so far.
+    clock: u64,
+}
+
+impl LiveTake {
+    /// Starts a take at a line of the pattern, played at `tick`.
+    pub fn start(
+        editor: &Editor,
+        track: usize,
+        line: usize,
+        tick: usize,
+        frames_per_line: f64,
+        sample_rate: f64,
+        policy: LoopPolicy,
+    ) -> Self {
+        let loop_len = (editor.num_lines() as f64 * frames_per_line).round() as u64;
+        Self {
+            recorder: Recorder::new(loop_len, policy),
+            track,
+            frames_per_line,
+            sample_rate,
+            start: (line, tick),
+            tick: (tick, Instant::now()),
+            clock: 0,
+        }
+    }
+
+    pub fn track(&self) -> usize {
+        self.track
+    }
+
+    /// Called when a line starts playing.
+    pub fn set_tick(&mut self, tick: usize, now: Instant) {
+        self.tick = (tick, now);
+        self.sync(now);
+    }
+
+    pub fn note_on(&mut self, pitch: u8, now: Instant) {
+        self.sync(now);
+        self.recorder.note_on(pitch);
+    }
+
+    pub fn note_off(&mut self, pitch: u8, now: Instant) {
+        self.sync(now);
+        self.recorder.note_off(pitch);
+    }
+
+    /// Ends the notes still held and writes the take, see `Recorder::write`.
+    pub fn finish(mut self, editor: &mut Editor, strength: f32) {
+        self.sync(Instant::now());
+        self.recorder.finish();
+        let track = self.track;
+        self.recorder
+            .write(editor, track, self.frames_per_line, strength);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.recorder.notes().is_empty() && self.recorder.held.is_empty()
+    }
+
+    /// Moves the recorder clock forward to `now`, within the line playing.
+    fn sync(&mut self, now: Instant) {
+        let (line, tick) = self.start;
+        let lines = (line + self.tick.0.saturating_sub(tick)) as f64;
+        let elapsed = now.saturating_duration_since(self.tick.1).as_secs_f64();
+        let within = f64::min(elapsed * self.sample_rate, self.frames_per_line - 1.0);
+        let clock = (lines * self.frames_per_line + within).round() as u64;
+        if clock > self.clock {
+            self.recorder.advance(clock - self.clock);
+            self.clock = clock;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOOP_LEN: u64 = 100;
+
+    fn note(pitch: u8, start: u64, end: u64) -> RecordedNote {
+        RecordedNote { pitch, start, end }
+    }
+
+    /// A recorder which held a note from 20 frames before the end of the loop to 20 frames
+    /// after it.
+    fn across_boundary(policy: LoopPolicy) -> Recorder {
+        let mut recorder = Recorder::new(LOOP_LEN, policy);
+        recorder.advance(LOOP_LEN - 20);
+        recorder.note_on(60);
+        recorder.advance(40);
+        recorder.note_off(60);
+        recorder
+    }
+
+    #[test]
+    fn truncate_ends_notes_at_the_boundary() {
+        let recorder = across_boundary(LoopPolicy::Truncate);
+        assert_eq!(recorder.notes(), &[note(60, 80, LOOP_LEN)]);
+        assert_eq!(recorder.position, 20);
+        assert_eq!(recorder.take_len, LOOP_LEN);
+    }
+
+    #[test]
+    fn wrap_continues_notes_from_the_start() {
+        let recorder = across_boundary(LoopPolicy::Wrap);
+        assert_eq!(recorder.notes(), &[note(60, 80, LOOP_LEN), note(60, 0, 20)]);
+        assert_eq!(recorder.position, 20);
+        assert_eq!(recorder.take_len, LOOP_LEN);
+    }
+
+    #[test]
+    fn extend_grows_the_take() {
+        let mut recorder = across_boundary(LoopPolicy::Extend);
+        assert_eq!(recorder.notes(), &[note(60, 80, 120)]);
+        assert_eq!(recorder.position, 120);
+        assert_eq!(recorder.take_len, 2 * LOOP_LEN);
+
+        // Nothing held at the end of the longer take, it loops as it is
+        recorder.advance(100);
+        assert_eq!(recorder.position, 20);
+        assert_eq!(recorder.take_len, 2 * LOOP_LEN);
+    }
+
+    #[test]
+    fn notes_released_before_the_boundary_are_kept() {
+        for policy in [LoopPolicy::Truncate, LoopPolicy::Wrap, LoopPolicy::Extend] {
+            let mut recorder = Recorder::new(LOOP_LEN, policy);
+            recorder.advance(LOOP_LEN - 20);
+            recorder.note_on(60);
+            recorder.advance(19);
+            recorder.note_off(60);
+            recorder.advance(LOOP_LEN);
+            assert_eq!(recorder.notes(), &[note(60, 80, 99)]);
+            assert_eq!(recorder.take_len, LOOP_LEN);
+        }
+    }
+
+    #[test]
+    fn notes_held_to_the_boundary_wrap_on_it() {
+        let mut recorder = Recorder::new(LOOP_LEN, LoopPolicy::Wrap);
+        recorder.note_on(60);
+        recorder.advance(LOOP_LEN);
+        assert_eq!(recorder.position, 0);
+        recorder.advance(10);
+        recorder.note_off(60);
+        assert_eq!(recorder.notes(), &[note(60, 0, LOOP_LEN), note(60, 0, 10)]);
+    }
+}
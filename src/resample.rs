@@ -0,0 +1,55 @@
+//! Sample rate conversion of whole sounds with a windowed sinc filter, too slow for the audio
+//! thread but much cleaner than the interpolation of the voices.
+
+use std::f64::consts::PI;
+
+/// Zero crossings of the sinc on each side of a sample, more gives a steeper filter.
+const ZERO_CROSSINGS: f64 = 32.0;
+/// Cutoff of the filter relative to the lower of the two Nyquist frequencies, leaving room for
+/// its transition band so nothing folds back.
+const ROLLOFF: f64 = 0.95;
+
+/// Converts frames recorded at `from` Hz to `to` Hz.
+pub fn convert(input: &[(f32, f32)], from: u32, to: u32) -> Vec<(f32, f32)> {
+    if from == to || from == 0 || to == 0 || input.is_empty() {
+        return input.to_vec();
+    }
+    // Input frames per output frame
+    let step = from as f64 / to as f64;
+    // Lowers the cutoff when converting down, so the filter removes what the new rate can't
+    // hold.
+    let cutoff = ROLLOFF * f64::min(1.0, 1.0 / step);
+    let width = ZERO_CROSSINGS / cutoff;
+    let len = (input.len() as f64 / step).round() as usize;
+    let last = input.len() - 1;
+    (0..len)
+        .map(|i| {
+            let position = i as f64 * step;
+            let first = (position - width).ceil().max(0.0) as usize;
+            let end = usize::min((position + width).floor() as usize, last);
+            let mut sum = (0.0, 0.0);
+            for (k, frame) in input.iter().enumerate().take(end + 1).skip(first) {
+                let weight = kernel(k as f64 - position, cutoff, width);
+                sum.0 += frame.0 as f64 * weight;
+                sum.1 += frame.1 as f64 * weight;
+            }
+            (sum.0 as f32, sum.1 as f32)
+        })
+        .collect()
+}
+
+/// A lowpass sinc at `cutoff`, relative to the input Nyquist frequency, under a Blackman
+/// window `width` samples wide on each side.
+fn kernel(x: f64, cutoff: f64, width: f64) -> f64 {
+    if x.abs() >= width {
+        return 0.0;
+    }
+    let sinc = if x == 0.0 {
+        cutoff
+    } else {
+        f64::sin(PI * cutoff * x) / (PI * x)
+    };
+    let phase = PI * x / width;
+    let window = 0.42 + 0.5 * f64::cos(phase) + 0.08 * f64::cos(2.0 * phase);
+    sinc * window
+}
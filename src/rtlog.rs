@@ -0,0 +1,47 @@
+//! Diagnostics from the audio thread, which mustn't print: writing to stderr takes a lock and
+//! can block on the terminal. Messages are counted in atomics instead, the app reads the counts
+//! back and logs them. A message repeated on every callback shows up once with its count
+//! rather than filling a queue.
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Message {
+    /// The app didn't read the engine's commands in time.
+    AppQueueFull,
+    /// A MIDI output couldn't keep up with the notes played.
+    MidiEventDropped,
+}
+
+impl Message {
+    const ALL: [Message; 2] = [Message::AppQueueFull, Message::MidiEventDropped];
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Message::AppQueueFull => write!(f, "unable to update client state"),
+            Message::MidiEventDropped => write!(f, "dropped MIDI event"),
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+static COUNTS: [AtomicUsize; Message::ALL.len()] = [ZERO; Message::ALL.len()];
+
+/// Logs a message, safe to call from the audio thread.
+pub fn log(message: Message) {
+    COUNTS[message as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// The messages logged since the last call, with the number of times each was.
+pub fn drain() -> impl Iterator<Item = (Message, usize)> {
+    IntoIterator::into_iter(Message::ALL).filter_map(|message| {
+        match COUNTS[message as usize].swap(0, Ordering::Relaxed) {
+            0 => None,
+            count => Some((message, count)),
+        }
+    })
+}
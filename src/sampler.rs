@@ -1,19 +1,176 @@
-use crate::engine::Device;
-use crate::param::Param;
-use crate::SAMPLE_RATE;
+use crate::aiff;
+use crate::engine::{Device, EngineConfig, CONTROL_BLOCK_SIZE};
+use crate::filter::{Coefficients, FilterMode, Svf};
+use crate::frame::Stereo;
+use crate::instrument::{Instrument, Quality};
+use crate::keymap::Region;
+use crate::mmap::Mapping;
+use crate::mono::{self, Change, HeldNotes, Priority};
+use crate::param::{Param, Smoothed};
+use crate::resample;
 use crate::{
-    env::{Envelope, State as EnvelopeState},
+    env::{Envelope, EnvelopeParams, State as EnvelopeState},
     param::Unit,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use atomic_float::AtomicF32;
-use camino::Utf8PathBuf;
-use hound::WavReader;
-use std::ops::{Add, Mul};
+use camino::{Utf8Path, Utf8PathBuf};
+use hound::{SampleFormat, WavReader, WavSpec};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Seek};
 use std::sync::{atomic::Ordering, Arc};
 
+/// Note at which sounds play at their original speed, unless their root was detected or set.
 pub const ROOT_PITCH: u8 = 48;
 
+/// Fewest frames a sound needs to be played, voices read between a frame and the next.
+pub const MIN_FRAMES: usize = 2;
+
+/// Extensions of the files `Sampler::load_sound` can read.
+const EXTENSIONS: [&str; 4] = ["wav", "aif", "aiff", "aifc"];
+
+/// Sample data at least this large is memory-mapped instead of decoded up front.
+const MAP_THRESHOLD: usize = 1 << 20;
+
+/// Release of a note cut by a new note on its column, in seconds.
+const CUT_TIME: f32 = 0.005;
+
+/// Fade out of a voice stolen for a new note when all are busy, in seconds. The new note
+/// starts once it's silent.
+const STEAL_TIME: f32 = 0.002;
+
+/// Length of the start of a streamed sound which is loaded up front.
+const ATTACK_SECONDS: usize = 1;
+
+/// What happens when a note is played on a column which is still sounding.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Retrigger {
+    /// The previous note is cut and the new one starts its attack from silence.
+    #[default]
+    Reset,
+    /// The previous note is cut and the new one starts its attack from the level the previous
+    /// one was at, so fast repeated notes don't click.
+    FromCurrent,
+    /// A held note only changes pitch, the sound and the envelope carry on.
+    Legato,
+}
+
+impl Retrigger {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "reset" => Ok(Retrigger::Reset),
+            "current" => Ok(Retrigger::FromCurrent),
+            "legato" => Ok(Retrigger::Legato),
+            _ => Err(anyhow!(
+                "unknown retrigger mode {}, expected reset, current or legato",
+                name
+            )),
+        }
+    }
+}
+
+/// Range of a pitch modulation envelope at full amount, in semitones.
+const PITCH_MOD_RANGE: f32 = 24.0;
+
+/// Range of a cutoff modulation envelope at full amount, in octaves.
+const CUTOFF_MOD_RANGE: f32 = 6.0;
+
+/// What a modulation envelope is applied to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ModDestination {
+    /// Transposes the voice.
+    Pitch,
+    /// Moves the cutoff of the voice filter.
+    Cutoff,
+    /// Moves the position the sound is read from, up to its whole length either way.
+    Start,
+}
+
+impl ModDestination {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "pitch" => Ok(ModDestination::Pitch),
+            "cutoff" => Ok(ModDestination::Cutoff),
+            "start" => Ok(ModDestination::Start),
+            _ => Err(anyhow!(
+                "unknown modulation destination {}, expected pitch, cutoff or start",
+                name
+            )),
+        }
+    }
+
+    /// Parses a comma separated list of destinations, e.g. `pitch,cutoff`.
+    pub fn parse_list(names: &str) -> Result<Vec<Self>> {
+        names
+            .split(',')
+            .filter(|name| !name.is_empty())
+            .map(Self::parse)
+            .collect()
+    }
+
+    /// Prefix of the params of an envelope with this destination.
+    fn prefix(self) -> &'static str {
+        match self {
+            ModDestination::Pitch => "Pitch",
+            ModDestination::Cutoff => "Cutoff",
+            ModDestination::Start => "Start",
+        }
+    }
+}
+
+/// How the sample data of an instrument is kept in memory.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum MemoryPolicy {
+    /// Decoded into memory when loaded.
+    Resident,
+    /// Large sounds are memory-mapped, only their attack is loaded up front and the rest is
+    /// read from disk as it is played.
+    #[default]
+    Stream,
+    /// Large sounds are memory-mapped and nothing is loaded until the sound is first played,
+    /// not even by `:pretouch`. The first notes may drop out.
+    Purge,
+}
+
+impl MemoryPolicy {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "resident" => Ok(MemoryPolicy::Resident),
+            "stream" => Ok(MemoryPolicy::Stream),
+            "purge" => Ok(MemoryPolicy::Purge),
+            _ => Err(anyhow!(
+                "unknown memory policy {}, expected resident, stream or purge",
+                name
+            )),
+        }
+    }
+}
+
+/// How sounds recorded at another rate than the engine are played.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum RateConversion {
+    /// Each voice reads the sound faster or slower, with its own interpolation.
+    #[default]
+    Voice,
+    /// The sound is converted to the engine rate with a high quality filter when it's loaded.
+    /// It's then decoded into memory, whatever the memory policy.
+    Load,
+}
+
+impl RateConversion {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "voice" => Ok(RateConversion::Voice),
+            "load" => Ok(RateConversion::Load),
+            _ => Err(anyhow!(
+                "unknown rate conversion {}, expected voice or load",
+                name
+            )),
+        }
+    }
+}
+
 struct Voice {
     position: f32,
     state: VoiceState,
@@ -21,8 +178,25 @@ struct Voice {
     pitch: u8,
     volume: f32,
     env: Envelope,
+    /// One per modulation envelope of the sampler, in the same order.
+    mod_envs: Vec<Envelope>,
+    filter: (Svf, Svf),
     column: usize,
     sound: Option<Arc<Sound>>,
+    /// Note to play once the voice has faded out, when it was stolen.
+    pending: Option<Note>,
+}
+
+/// A note which is about to start on a voice.
+struct Note {
+    sound: Arc<Sound>,
+    /// Note of the sound at its original speed.
+    root: f32,
+    column: usize,
+    pitch: u8,
+    velocity: u8,
+    /// Envelope level the attack starts from.
+    level: f32,
 }
 
 #[derive(PartialEq, Debug)]
@@ -32,7 +206,7 @@ enum VoiceState {
 }
 
 impl<'a> Voice {
-    fn new() -> Self {
+    fn new(sample_rate: f32) -> Self {
         Self {
             position: 0.0,
             column: 0,
@@ -40,161 +214,808 @@ impl<'a> Voice {
             volume: 0.0,
             pitch_ratio: 0.,
             state: VoiceState::Free,
-            env: Envelope::new(),
+            env: Envelope::new(sample_rate),
+            mod_envs: Vec::new(),
+            filter: (Svf::default(), Svf::default()),
             sound: None,
+            pending: None,
+        }
+    }
+
+    /// Frees the voice at once, with its envelopes and filters back to silence.
+    fn reset(&mut self) {
+        self.state = VoiceState::Free;
+        self.env.reset();
+        for env in &mut self.mod_envs {
+            env.reset();
+        }
+        self.filter = (Svf::default(), Svf::default());
+        self.pending = None;
+    }
+
+    /// Fades the voice out over `time` seconds, from wherever its envelopes are.
+    fn cut(&mut self, time: f32) {
+        self.env.release = time;
+        self.env.start_release();
+        for env in &mut self.mod_envs {
+            env.release = time;
+            env.start_release();
         }
     }
 }
 
 pub struct Sound {
-    buf: Vec<Frame>,
+    samples: Samples,
+    len: usize,
     sample_rate: u32,
     offset: usize,
 }
 
+enum Samples {
+    Decoded(Vec<Stereo>),
+    /// Interleaved samples read straight from the WAV file. Pages are only loaded when they're
+    /// played for the first time, unless they've been touched beforehand.
+    Mapped {
+        mapping: Arc<Mapping>,
+        start: usize,
+        channels: usize,
+        encoding: Encoding,
+    },
+}
+
+impl Sound {
+    pub fn from_frames(frames: Vec<(f32, f32)>, sample_rate: u32) -> Self {
+        Self::from_stereo(frames.into_iter().map(Stereo::from).collect(), sample_rate)
+    }
+
+    fn from_stereo(frames: Vec<Stereo>, sample_rate: u32) -> Self {
+        let len = frames.len();
+        Self {
+            samples: Samples::Decoded(frames),
+            len,
+            sample_rate,
+            offset: 0,
+        }
+    }
+
+    /// Starts playback at the first sample which isn't silent.
+    fn skip_silence(mut self) -> Self {
+        const SILENCE: f32 = 0.01;
+        let offset = self
+            .frames()
+            .position(|(left, right)| left >= SILENCE || right >= SILENCE)
+            .unwrap_or(0);
+        self.offset = offset;
+        self
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn num_frames(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the stereo frame at `i`, which must be below `num_frames`.
+    pub fn frame_at(&self, i: usize) -> (f32, f32) {
+        self.frame(i).into()
+    }
+
+    pub fn frames(&self) -> impl Iterator<Item = (f32, f32)> + '_ {
+        self.stereo_frames().map(Stereo::into)
+    }
+
+    fn stereo_frames(&self) -> impl Iterator<Item = Stereo> + '_ {
+        (0..self.len).map(move |i| self.frame(i))
+    }
+
+    fn frame(&self, i: usize) -> Stereo {
+        match &self.samples {
+            Samples::Decoded(buf) => buf[i],
+            Samples::Mapped {
+                mapping,
+                start,
+                channels,
+                encoding,
+            } => {
+                let size = encoding.size();
+                let at = start + i * channels * size;
+                let bytes = &mapping.bytes()[at..at + channels * size];
+                let left = encoding.decode(&bytes[..size]);
+                let right = match channels {
+                    1 => left,
+                    _ => encoding.decode(&bytes[size..2 * size]),
+                };
+                Stereo::new(left, right)
+            }
+        }
+    }
+}
+
+/// Destructive edits of a sound, see `Sound::edit`. Times are in seconds.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SoundEdit {
+    /// Keeps the part between two times.
+    Trim(f32, f32),
+    /// Scales the sound so its peak reaches a level in dB.
+    Normalize(f32),
+    /// Fades in from the start and out to the end over the given times.
+    Fade(f32, f32),
+    RemoveDc,
+    Reverse,
+}
+
+impl fmt::Display for SoundEdit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SoundEdit::Trim(start, end) => write!(f, "trim {} {}", start, end),
+            SoundEdit::Normalize(peak) => write!(f, "normalize {}", peak),
+            SoundEdit::Fade(fade_in, fade_out) => write!(f, "fade {} {}", fade_in, fade_out),
+            SoundEdit::RemoveDc => write!(f, "dc"),
+            SoundEdit::Reverse => write!(f, "reverse"),
+        }
+    }
+}
+
+impl Sound {
+    /// Returns an edited copy, the sound itself stays as it is for the voices playing it.
+    pub fn edit(&self, edit: SoundEdit) -> Sound {
+        let to_frames = |seconds: f32| (seconds.max(0.0) * self.sample_rate as f32) as usize;
+        match edit {
+            SoundEdit::Trim(start, end) => self.trim(to_frames(start), to_frames(end)),
+            SoundEdit::Normalize(peak) => self.normalize(peak),
+            SoundEdit::Fade(fade_in, fade_out) => {
+                self.fade(to_frames(fade_in), to_frames(fade_out))
+            }
+            SoundEdit::RemoveDc => self.remove_dc(),
+            SoundEdit::Reverse => self.reverse(),
+        }
+    }
+
+    /// Keeps the frames from `start` to `end`, clamped to the sound.
+    pub fn trim(&self, start: usize, end: usize) -> Sound {
+        let end = usize::min(end, self.len);
+        let start = usize::min(start, end);
+        let frames = (start..end).map(|i| self.frame(i)).collect();
+        Sound::from_stereo(frames, self.sample_rate)
+    }
+
+    /// Scales the sound so its highest peak on either channel is at `peak` dB. Silence is left
+    /// as it is.
+    pub fn normalize(&self, peak: f32) -> Sound {
+        let max = self
+            .stereo_frames()
+            .fold(0.0f32, |max, frame| max.max(frame.peak()));
+        let gain = match max {
+            max if max > 0.0 => f32::powf(10.0, peak / 20.0) / max,
+            _ => 1.0,
+        };
+        self.map(|frame| frame * gain)
+    }
+
+    /// Linear fades over the first `fade_in` and the last `fade_out` frames.
+    pub fn fade(&self, fade_in: usize, fade_out: usize) -> Sound {
+        let len = self.len;
+        let frames = self
+            .stereo_frames()
+            .enumerate()
+            .map(|(i, frame)| {
+                let mut gain = 1.0;
+                if i < fade_in {
+                    gain *= i as f32 / fade_in as f32;
+                }
+                let remaining = len - 1 - i;
+                if remaining < fade_out {
+                    gain *= remaining as f32 / fade_out as f32;
+                }
+                frame * gain
+            })
+            .collect();
+        Sound::from_stereo(frames, self.sample_rate)
+    }
+
+    /// Removes the average of each channel.
+    pub fn remove_dc(&self) -> Sound {
+        let n = usize::max(self.len, 1) as f64;
+        let (left, right) = self.frames().fold((0.0, 0.0), |(l, r), (left, right)| {
+            (l + left as f64, r + right as f64)
+        });
+        let offset = Stereo::new((left / n) as f32, (right / n) as f32);
+        self.map(|frame| frame - offset)
+    }
+
+    /// Converts the sound to `sample_rate`, see `resample::convert`.
+    pub fn resample(&self, sample_rate: u32) -> Sound {
+        let frames: Vec<(f32, f32)> = self.frames().collect();
+        let frames = resample::convert(&frames, self.sample_rate, sample_rate);
+        Sound::from_frames(frames, sample_rate).skip_silence()
+    }
+
+    pub fn reverse(&self) -> Sound {
+        let frames = (0..self.len).rev().map(|i| self.frame(i)).collect();
+        Sound::from_stereo(frames, self.sample_rate)
+    }
+
+    fn map<F: Fn(Stereo) -> Stereo>(&self, f: F) -> Sound {
+        Sound::from_stereo(self.stereo_frames().map(f).collect(), self.sample_rate)
+    }
+}
+
+/// Sample formats which can be played from a mapped file.
+#[derive(Copy, Clone, Debug)]
+enum Encoding {
+    U8,
+    I16,
+    I24,
+    I32,
+    F32,
+}
+
+impl Encoding {
+    fn from_spec(spec: &WavSpec) -> Option<Self> {
+        match (spec.sample_format, spec.bits_per_sample) {
+            (SampleFormat::Int, 8) => Some(Encoding::U8),
+            (SampleFormat::Int, 16) => Some(Encoding::I16),
+            (SampleFormat::Int, 24) => Some(Encoding::I24),
+            (SampleFormat::Int, 32) => Some(Encoding::I32),
+            (SampleFormat::Float, 32) => Some(Encoding::F32),
+            _ => None,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Encoding::U8 => 1,
+            Encoding::I16 => 2,
+            Encoding::I24 => 3,
+            Encoding::I32 | Encoding::F32 => 4,
+        }
+    }
+
+    fn decode(self, b: &[u8]) -> f32 {
+        match self {
+            Encoding::U8 => (b[0] as f32 - 128.0) / 128.0,
+            Encoding::I16 => i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0,
+            Encoding::I24 => (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8388608.0,
+            Encoding::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2147483648.0,
+            Encoding::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+        }
+    }
+}
+
 pub struct Sampler {
     voices: Vec<Voice>,
-    amp: Arc<AtomicF32>,
-    attack: Arc<AtomicF32>,
-    decay: Arc<AtomicF32>,
-    sustain: Arc<AtomicF32>,
-    release: Arc<AtomicF32>,
+    sound: Option<Arc<Sound>>,
+    amp: Smoothed,
+    /// Transposition of every voice in semitones, on top of the note.
+    tune: Smoothed,
+    /// Note of the sound at its original speed, fractional when it's slightly off.
+    root: Arc<AtomicF32>,
+    /// Sounds played instead of `sound` over some keys and velocities, see `keymap`.
+    regions: Vec<(Region, Arc<Sound>)>,
+    envelope: EnvelopeParams,
+    quality: Quality,
+    retrigger: Retrigger,
+    /// Notes held in mono mode, which plays one note at a time.
+    mono: Option<HeldNotes>,
+    filter: Option<VoiceFilter>,
+    mod_envelopes: Vec<ModEnvelope>,
+    sample_rate: f32,
+}
+
+/// An envelope of its own, separate from the amp envelope, which modulates one destination.
+struct ModEnvelope {
+    destination: ModDestination,
+    envelope: EnvelopeParams,
+    /// From -1 to 1, negative amounts modulate downwards.
+    amount: Arc<AtomicF32>,
+}
+
+/// Settings of the per-voice filter, shared by all voices.
+struct VoiceFilter {
+    mode: FilterMode,
+    cutoff: Arc<AtomicF32>,
+    resonance: Arc<AtomicF32>,
 }
 
 impl Sampler {
     pub fn new() -> Self {
         let num_voices = 8;
+        let sample_rate = EngineConfig::default().sample_rate as f32;
         let mut voices = Vec::with_capacity(num_voices);
         for _ in 0..num_voices {
-            voices.push(Voice::new());
+            voices.push(Voice::new(sample_rate));
         }
         Self {
-            amp: Arc::new(AtomicF32::new(-6.0)),
-            attack: Arc::new(AtomicF32::new(0.005)),
-            decay: Arc::new(AtomicF32::new(0.25)),
-            sustain: Arc::new(AtomicF32::new(1.0)),
-            release: Arc::new(AtomicF32::new(0.3)),
+            amp: Smoothed::new(-6.0, sample_rate),
+            tune: Smoothed::new(0.0, sample_rate),
+            root: Arc::new(AtomicF32::new(ROOT_PITCH as f32)),
+            regions: Vec::new(),
+            envelope: EnvelopeParams::new(0.005, 0.25, 1.0, 0.3),
             voices,
+            sound: None,
+            quality: Quality::Realtime,
+            retrigger: Retrigger::default(),
+            mono: None,
+            filter: None,
+            mod_envelopes: Vec::new(),
+            sample_rate,
         }
     }
 
+    pub fn with_sound(sound: Arc<Sound>) -> Self {
+        let mut sampler = Self::new();
+        sampler.sound = Some(sound);
+        sampler
+    }
+
+    pub fn with_root(self, root: f32) -> Self {
+        self.root.store(root, Ordering::Relaxed);
+        self
+    }
+
+    /// Plays `sound` over the keys and velocities of `region`, which mustn't overlap the
+    /// previous ones.
+    pub fn with_region(mut self, region: Region, sound: Arc<Sound>) -> Self {
+        self.regions.push((region, sound));
+        self
+    }
+
+    /// Runs every voice through its own filter.
+    pub fn with_filter(mut self, mode: FilterMode) -> Self {
+        self.filter = Some(VoiceFilter {
+            mode,
+            cutoff: Arc::new(AtomicF32::new(2_000.0)),
+            resonance: Arc::new(AtomicF32::new(0.0)),
+        });
+        self
+    }
+
+    /// Adds a modulation envelope to every voice. Cutoff modulation needs a filter, see
+    /// `with_filter`.
+    pub fn with_mod_envelope(mut self, destination: ModDestination) -> Result<Self> {
+        if destination == ModDestination::Cutoff && self.filter.is_none() {
+            return Err(anyhow!("cutoff modulation needs a filter"));
+        }
+        if self
+            .mod_envelopes
+            .iter()
+            .any(|m| m.destination == destination)
+        {
+            return Ok(self);
+        }
+        self.mod_envelopes.push(ModEnvelope {
+            destination,
+            envelope: EnvelopeParams::new(0.001, 0.2, 0.0, 0.2),
+            amount: Arc::new(AtomicF32::new(0.0)),
+        });
+        for voice in &mut self.voices {
+            voice.mod_envs.push(Envelope::new(self.sample_rate));
+        }
+        Ok(self)
+    }
+
+    fn filter_coefficients(&self) -> Option<(FilterMode, Coefficients)> {
+        self.filter.as_ref().map(|filter| {
+            let coefficients = Coefficients::new(
+                filter.cutoff.load(Ordering::Relaxed),
+                filter.resonance.load(Ordering::Relaxed),
+                self.sample_rate,
+            );
+            (filter.mode, coefficients)
+        })
+    }
+
+    pub fn with_retrigger(mut self, retrigger: Retrigger) -> Self {
+        self.retrigger = retrigger;
+        self
+    }
+
+    /// Plays one note at a time, picked among the held notes by `priority`. With the legato
+    /// retrigger mode, moving between held notes only changes the pitch.
+    pub fn with_mono(mut self, priority: Priority) -> Self {
+        self.mono = Some(HeldNotes::new(priority));
+        self
+    }
+
+    /// Loads a WAV file with the default memory policy.
     pub fn load_sound(path: &Utf8PathBuf) -> Result<Sound> {
-        let mut wav = WavReader::open(path.clone())?;
-        let wav_spec = wav.spec();
-        let bit_depth = wav_spec.bits_per_sample as f32;
-        let samples: Vec<Frame> = wav
-            .samples::<i32>()
-            .map(|sample| sample.unwrap() as f32 / (f32::powf(2., bit_depth - 1.)))
-            .collect::<Vec<f32>>()
-            .chunks(wav_spec.channels as usize)
-            .map(|f| {
-                let left = *f.get(0).unwrap();
-                let right = *f.get(1).unwrap_or(&left);
-                Frame { left, right }
-            })
-            .collect();
+        Self::load_sound_with(path, MemoryPolicy::default())
+    }
 
-        const SILENCE: f32 = 0.01;
-        let mut offset = 0;
-        for (i, frame) in samples.iter().enumerate() {
-            if frame.left < SILENCE && frame.right < SILENCE {
-                continue;
-            } else {
-                offset = i;
-                break;
+    /// Loads a WAV or AIFF file. Unless the policy is `Resident`, large WAV files are
+    /// memory-mapped so they load instantly and their pages are read from disk on first use,
+    /// see `mmap::pretouch_all`.
+    pub fn load_sound_with(path: &Utf8PathBuf, policy: MemoryPolicy) -> Result<Sound> {
+        let extension = path.extension().map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("aif") | Some("aiff") | Some("aifc") => {
+                let (frames, sample_rate) = aiff::read(path)?;
+                return Ok(Sound::from_frames(frames, sample_rate).skip_silence());
             }
+            Some(format @ "flac") | Some(format @ "ogg") | Some(format @ "mp3") => {
+                return Err(anyhow!("can't load {}, {} isn't supported", path, format));
+            }
+            _ => {}
         }
-        Ok(Sound {
+        let wav = WavReader::open(path.clone())?;
+        let wav_spec = wav.spec();
+        let channels = wav_spec.channels as usize;
+        let len = wav.len() as usize / channels;
+        let samples = match Encoding::from_spec(&wav_spec) {
+            Some(encoding)
+                if policy != MemoryPolicy::Resident
+                    && wav.len() as usize * encoding.size() >= MAP_THRESHOLD =>
+            {
+                let mut reader = wav.into_inner();
+                // The reader is left at the start of the sample data
+                let start = reader.stream_position()? as usize;
+                let mapping = Mapping::open(reader.get_ref())?;
+                let frame_size = channels * encoding.size();
+                if start + len * frame_size > mapping.bytes().len() {
+                    return Err(anyhow!("{} is truncated", path));
+                }
+                match policy {
+                    MemoryPolicy::Purge => mapping.purge(),
+                    _ => {
+                        let attack = ATTACK_SECONDS * wav_spec.sample_rate as usize;
+                        mapping.pretouch_range(start, attack * frame_size);
+                    }
+                }
+                Samples::Mapped {
+                    mapping,
+                    start,
+                    channels,
+                    encoding,
+                }
+            }
+            _ => Samples::Decoded(decode(wav)?),
+        };
+        let sound = Sound {
+            samples,
+            len,
             sample_rate: wav_spec.sample_rate,
-            buf: samples,
-            offset,
-        })
+            offset: 0,
+        };
+        Ok(sound.skip_silence())
     }
 
-    pub fn params(&self) -> Vec<(String, Param)> {
-        vec![
-            (
-                "Amp",
-                Param::new(-60.0, Arc::clone(&self.amp), 6.0, 1.0).with_unit(Unit::Decibel),
-            ),
-            (
-                "Attack",
-                Param::new(0.0, Arc::clone(&self.attack), 15.0, 0.01).with_unit(Unit::Seconds),
-            ),
-            (
-                "Decay",
-                Param::new(0.0, Arc::clone(&self.decay), 15.0, 0.01).with_unit(Unit::Seconds),
-            ),
-            (
-                "Sustain",
-                Param::new(0.0, Arc::clone(&self.sustain), 15.0, 0.01),
-            ),
-            (
-                "Release",
-                Param::new(0.0, Arc::clone(&self.release), 15.0, 0.01).with_unit(Unit::Seconds),
-            ),
-        ]
-        .into_iter()
-        .map(|(k, v)| (String::from(k), v))
-        .collect()
-    }
-
-    pub fn note_on(&mut self, sound: Arc<Sound>, column: usize, pitch: u8, velocity: u8) {
+    pub fn trigger(&mut self, sound: Arc<Sound>, column: usize, pitch: u8, velocity: u8) {
+        let root = self.root.load(Ordering::Relaxed);
+        self.play(sound, root, column, pitch, velocity);
+    }
+
+    fn play(&mut self, sound: Arc<Sound>, root: f32, column: usize, pitch: u8, velocity: u8) {
+        if sound.len < MIN_FRAMES {
+            return;
+        }
+        let sample_rate = self.sample_rate;
+        let sounding = |v: &&mut Voice| v.state == VoiceState::Busy && v.column == column;
+        if self.retrigger == Retrigger::Legato {
+            let held = self.voices.iter_mut().filter(sounding).find(|v| {
+                v.env.state != EnvelopeState::Release
+                    && v.sound.as_ref().is_some_and(|s| Arc::ptr_eq(s, &sound))
+            });
+            if let Some(voice) = held {
+                voice.pitch = pitch;
+                voice.pitch_ratio = pitch_ratio(&sound, pitch, root, sample_rate);
+                return;
+            }
+        }
+        let level = match self.retrigger {
+            Retrigger::FromCurrent => self
+                .voices
+                .iter_mut()
+                .filter(sounding)
+                .map(|v| v.env.level())
+                .fold(0.0, f32::max),
+            _ => 0.0,
+        };
         self.stop_note(column);
 
-        let attack = self.attack.load(Ordering::Relaxed);
-        let decay = self.decay.load(Ordering::Relaxed);
-        let sustain = self.sustain.load(Ordering::Relaxed);
-        let release = self.release.load(Ordering::Relaxed);
-        if let Some(voice) = self.voices.iter_mut().find(|v| v.state == VoiceState::Free) {
-            voice.env.attack = attack;
-            voice.env.decay = decay;
-            voice.env.sustain = sustain;
-            voice.env.release = release;
-            voice.env.start_attack();
-            voice.state = VoiceState::Busy;
-            voice.pitch = pitch;
-            voice.volume = gain_factor(map(velocity as f32, (0.0, 127.0), (-60.0, 0.0)));
-            voice.column = column;
-            let pitch = pitch as i8 - ROOT_PITCH as i8;
-            voice.pitch_ratio = f32::powf(2., pitch as f32 / 12.0)
-                * (sound.sample_rate as f32 / SAMPLE_RATE as f32);
-            voice.position = sound.offset as f32;
-            voice.sound = Some(sound);
-        } else {
-            eprintln!("dropped event");
-        }
-    }
-
-    fn note_off(&mut self, column: usize, pitch: u8) {
-        if let Some(voice) = self
+        let note = Note {
+            sound,
+            root,
+            column,
+            pitch,
+            velocity,
+            level,
+        };
+        let free = |v: &Voice| v.state == VoiceState::Free && v.pending.is_none();
+        if let Some(index) = self.voices.iter().position(free) {
+            return self.start(index, note);
+        }
+        // Steal the quietest voice, it fades out before the new note starts so it doesn't
+        // click.
+        let quietest = self
             .voices
             .iter_mut()
-            .find(|v| v.state == VoiceState::Busy && v.column == column && v.pitch == pitch)
-        {
-            voice.env.start_release();
+            .min_by(|a, b| a.env.level().total_cmp(&b.env.level()));
+        if let Some(voice) = quietest {
+            if voice.pending.is_none() {
+                voice.cut(STEAL_TIME);
+            }
+            voice.pending = Some(note);
+        }
+    }
+
+    /// Starts a note on a voice.
+    fn start(&mut self, index: usize, note: Note) {
+        let Note {
+            sound,
+            root,
+            column,
+            pitch,
+            velocity,
+            level,
+        } = note;
+        let sample_rate = self.sample_rate;
+        let filter = self.filter_coefficients();
+        let voice = &mut self.voices[index];
+        self.envelope.trigger(&mut voice.env, velocity, level);
+        for (m, env) in self.mod_envelopes.iter().zip(&mut voice.mod_envs) {
+            m.envelope.trigger(env, velocity, 0.0);
+        }
+        if let Some((_, coefficients)) = filter {
+            voice.filter.0.reset(coefficients);
+            voice.filter.1.reset(coefficients);
         }
+        voice.state = VoiceState::Busy;
+        voice.pitch = pitch;
+        voice.volume = gain_factor(map(velocity as f32, (0.0, 127.0), (-60.0, 0.0)));
+        voice.column = column;
+        voice.pitch_ratio = pitch_ratio(&sound, pitch, root, sample_rate);
+        voice.position = sound.offset as f32;
+        voice.sound = Some(sound);
     }
 
+    pub fn num_voices(&self) -> usize {
+        self.voices.len()
+    }
+
+    /// Whether a voice plays `sound` or is about to.
+    pub fn holds(&self, sound: &Arc<Sound>) -> bool {
+        self.voices.iter().any(|voice| {
+            let pending = voice.pending.as_ref().map(|note| &note.sound);
+            voice
+                .sound
+                .iter()
+                .chain(pending)
+                .any(|s| Arc::ptr_eq(s, sound))
+        })
+    }
+
+    /// Quickly fades out every note still sounding on a column.
     fn stop_note(&mut self, column: usize) {
-        if let Some(voice) = self
-            .voices
-            .iter_mut()
-            .find(|v| v.state == VoiceState::Busy && v.column == column)
-        {
-            voice.env.release = 0.005; // set a short release (5ms)
+        for voice in &mut self.voices {
+            if voice.state == VoiceState::Busy && voice.column == column {
+                voice.cut(CUT_TIME);
+            }
+        }
+    }
+
+    /// Plays the region of the note, or else the main sound.
+    fn play_note(&mut self, column: usize, pitch: u8, velocity: u8) {
+        let region = self
+            .regions
+            .iter()
+            .find(|(region, _)| region.contains(pitch, velocity));
+        if let Some((region, sound)) = region {
+            let (sound, root) = (Arc::clone(sound), region.root as f32);
+            self.play(sound, root, column, pitch, velocity);
+        } else if let Some(sound) = self.sound.clone() {
+            self.trigger(sound, column, pitch, velocity);
+        }
+    }
+
+    fn release(&mut self, column: usize) {
+        for voice in &mut self.voices {
+            if voice
+                .pending
+                .as_ref()
+                .is_some_and(|note| note.column == column)
+            {
+                voice.pending = None;
+            }
+        }
+        if let Some(voice) = self.voices.iter_mut().find(|v| {
+            v.state == VoiceState::Busy
+                && v.column == column
+                && v.env.state != EnvelopeState::Release
+        }) {
             voice.env.start_release();
+            for env in &mut voice.mod_envs {
+                env.start_release();
+            }
+        }
+    }
+
+    /// Follows a change of the held notes in mono mode, the sounding note is played with the
+    /// retrigger mode of the sampler.
+    fn follow(&mut self, change: Change) {
+        match change {
+            Change::Play { pitch, velocity } => self.play_note(mono::COLUMN, pitch, velocity),
+            Change::Release => self.release(mono::COLUMN),
+            Change::None => {}
         }
     }
 }
 
+impl Instrument for Sampler {
+    fn note_on(&mut self, column: usize, pitch: u8, velocity: u8) {
+        match self.mono.as_mut() {
+            Some(held) => {
+                let change = held.press(column, pitch, velocity);
+                self.follow(change);
+            }
+            None => self.play_note(column, pitch, velocity),
+        }
+    }
+
+    fn note_off(&mut self, column: usize) {
+        match self.mono.as_mut() {
+            Some(held) => {
+                let change = held.release(column);
+                self.follow(change);
+            }
+            None => self.release(column),
+        }
+    }
+
+    fn note_cut(&mut self, column: usize) {
+        match self.mono {
+            Some(_) => self.note_off(column),
+            None => {
+                self.release(column);
+                self.stop_note(column);
+            }
+        }
+    }
+
+    fn prepare(&mut self, config: &EngineConfig) {
+        self.sample_rate = config.sample_rate as f32;
+        self.amp.prepare(self.sample_rate);
+        self.tune.prepare(self.sample_rate);
+        for voice in &mut self.voices {
+            voice.env.sample_rate = self.sample_rate;
+            for env in &mut voice.mod_envs {
+                env.sample_rate = self.sample_rate;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        for voice in &mut self.voices {
+            voice.reset();
+        }
+        if let Some(held) = &mut self.mono {
+            held.clear();
+        }
+    }
+
+    fn set_quality(&mut self, quality: Quality) {
+        self.quality = quality;
+    }
+
+    fn active_voices(&self) -> usize {
+        let busy = |voice: &&Voice| voice.state == VoiceState::Busy;
+        self.voices.iter().filter(busy).count()
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        let amp = Param::new(-60.0, Arc::clone(&self.amp.val), 6.0, 1.0).with_unit(Unit::Decibel);
+        let tune = Param::new(-12.0, Arc::clone(&self.tune.val), 12.0, 0.1);
+        let root = Param::new(0.0, Arc::clone(&self.root), 127.0, 1.0);
+        let mut params = vec![
+            (String::from("Amp"), amp),
+            (String::from("Tune"), tune),
+            (String::from("Root"), root),
+        ];
+        params.extend(self.envelope.params());
+        if let Some(filter) = &self.filter {
+            params.push((
+                String::from("Cutoff"),
+                Param::new(20.0, Arc::clone(&filter.cutoff), 20_000.0, 50.0).with_unit(Unit::Hertz),
+            ));
+            params.push((
+                String::from("Resonance"),
+                Param::new(0.0, Arc::clone(&filter.resonance), 1.0, 0.05),
+            ));
+        }
+        for m in &self.mod_envelopes {
+            let prefix = m.destination.prefix();
+            params.extend(
+                m.envelope
+                    .params()
+                    .into_iter()
+                    .map(|(name, param)| (format!("{}{}", prefix, name), param)),
+            );
+            params.push((
+                format!("{}Amount", prefix),
+                Param::new(-1.0, Arc::clone(&m.amount), 1.0, 0.05),
+            ));
+        }
+        params
+    }
+}
+
+/// Reads every sample of a WAV file, scaling integers of any size to -1..1.
+fn decode(mut wav: WavReader<BufReader<File>>) -> Result<Vec<Stereo>> {
+    let wav_spec = wav.spec();
+    let samples = match wav_spec.sample_format {
+        SampleFormat::Float => wav.samples::<f32>().collect::<Result<Vec<f32>, _>>()?,
+        SampleFormat::Int => {
+            // 24-bit samples come sign extended, 8-bit ones centered on zero
+            let scale = f32::powi(2.0, wav_spec.bits_per_sample as i32 - 1);
+            wav.samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f32 / scale))
+                .collect::<Result<Vec<f32>, _>>()?
+        }
+    };
+    let frames = samples
+        .chunks(wav_spec.channels as usize)
+        .map(|f| {
+            let left = f[0];
+            let right = *f.get(1).unwrap_or(&left);
+            Stereo::new(left, right)
+        })
+        .collect();
+    Ok(frames)
+}
+
+/// Whether `path` names a file a sampler can load, going by its extension.
+pub fn is_sound(path: &Utf8Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| EXTENSIONS.iter().any(|e| ext.eq_ignore_ascii_case(e)))
+}
+
+/// Playback speed of a sound to play it at `pitch`, when it sounds like `root`.
+fn pitch_ratio(sound: &Sound, pitch: u8, root: f32, sample_rate: f32) -> f32 {
+    let pitch = pitch as f32 - root;
+    f32::powf(2., pitch / 12.0) * (sound.sample_rate as f32 / sample_rate)
+}
+
 fn gain_factor(db: f32) -> f32 {
     f32::powf(10.0, db / 20.0)
 }
 
 impl Device for Sampler {
     fn render(&mut self, buffer: &mut [(f32, f32)]) {
-        let amp = gain_factor(self.amp.load(Ordering::Relaxed));
+        for block in buffer.chunks_mut(CONTROL_BLOCK_SIZE) {
+            self.render_block(block);
+        }
+    }
+}
+
+impl Sampler {
+    fn render_block(&mut self, buffer: &mut [(f32, f32)]) {
+        // Voices read and filter the sound one frame at a time, then apply their envelope and
+        // mix over the whole block in loops simple enough for the compiler to vectorize.
+        let mut frames = [Stereo::SILENCE; CONTROL_BLOCK_SIZE];
+        let mut gains = [0.0; CONTROL_BLOCK_SIZE];
+
+        // Smoothed per sample, as gain and ratio
+        let mut amp = [0.0; CONTROL_BLOCK_SIZE];
+        let mut tune = [0.0; CONTROL_BLOCK_SIZE];
+        let (amp, tune) = (&mut amp[..buffer.len()], &mut tune[..buffer.len()]);
+        self.amp.fill(amp);
+        self.tune.fill(tune);
+        amp.iter_mut().for_each(|amp| *amp = gain_factor(*amp));
+        tune.iter_mut()
+            .for_each(|tune| *tune = f32::powf(2.0, *tune / 12.0));
+        let filter = self.filter_coefficients();
+
+        // Stolen voices which have faded out start their next note
+        for index in 0..self.voices.len() {
+            let voice = &mut self.voices[index];
+            let silent = voice.env.state == EnvelopeState::Init || voice.state == VoiceState::Free;
+            if silent {
+                if let Some(note) = voice.pending.take() {
+                    self.start(index, note);
+                }
+            }
+        }
 
         for voice in &mut self.voices {
             if voice.env.state == EnvelopeState::Init {
@@ -205,56 +1026,258 @@ impl Device for Sampler {
                 continue;
             }
             let sound = &voice.sound.as_ref().unwrap();
+
+            // Cutoff modulation runs at control rate, the filter of each voice then gets its
+            // own coefficients.
+            let mut filter = filter;
+            if let (Some((mode, _)), Some(settings)) = (filter, &self.filter) {
+                let octaves: f32 = self
+                    .mod_envelopes
+                    .iter()
+                    .zip(&voice.mod_envs)
+                    .filter(|(m, _)| m.destination == ModDestination::Cutoff)
+                    .map(|(m, env)| m.amount.load(Ordering::Relaxed) * env.level())
+                    .sum();
+                if octaves != 0.0 {
+                    let cutoff = settings.cutoff.load(Ordering::Relaxed)
+                        * f32::powf(2.0, octaves * CUTOFF_MOD_RANGE);
+                    let coefficients = Coefficients::new(
+                        cutoff.clamp(20.0, 20_000.0),
+                        settings.resonance.load(Ordering::Relaxed),
+                        self.sample_rate,
+                    );
+                    filter = Some((mode, coefficients));
+                }
+            }
+
+            let mut len = buffer.len();
             for i in 0..buffer.len() {
-                let pos = voice.position as usize;
-                let weight = voice.position - pos as f32;
+                let mut semitones = 0.0;
+                let mut start = 0.0;
+                for (m, env) in self.mod_envelopes.iter().zip(&mut voice.mod_envs) {
+                    let value = m.amount.load(Ordering::Relaxed) * env.value();
+                    match m.destination {
+                        ModDestination::Pitch => semitones += value * PITCH_MOD_RANGE,
+                        ModDestination::Start => start += value * sound.len as f32,
+                        ModDestination::Cutoff => {}
+                    }
+                }
+
+                let position = if start != 0.0 {
+                    (voice.position + start).clamp(0.0, (sound.len - 2) as f32)
+                } else {
+                    voice.position
+                };
+                let pos = position as usize;
+                let weight = position - pos as f32;
+
                 let inverse_weight = 1.0 - weight;
 
-                let frame = &sound.buf[pos];
-                let next_frame = &sound.buf[pos + 1];
-                let new_frame = frame * inverse_weight + next_frame * weight;
+                let new_frame = match self.quality {
+                    Quality::Realtime => {
+                        let frame = sound.frame(pos);
+                        let next_frame = sound.frame(pos + 1);
+                        frame * inverse_weight + next_frame * weight
+                    }
+                    Quality::Offline => {
+                        let frame = |offset: isize| {
+                            let i = (pos as isize + offset).max(0) as usize;
+                            sound.frame(usize::min(i, sound.len - 1))
+                        };
+                        let (a, b, c, d) = (frame(-1), frame(0), frame(1), frame(2));
+                        Stereo::new(
+                            cubic(a.left(), b.left(), c.left(), d.left(), weight),
+                            cubic(a.right(), b.right(), c.right(), d.right(), weight),
+                        )
+                    }
+                };
 
-                let env = voice.env.value() as f32;
-                buffer[i].0 += voice.volume * amp * env * new_frame.left;
-                buffer[i].1 += voice.volume * amp * env * new_frame.right;
-                voice.position += voice.pitch_ratio;
-                if voice.position >= (sound.buf.len() - 1) as f32 {
+                // The filter depends on the sample before, it runs while the next frames are
+                // read.
+                frames[i] = match filter {
+                    Some((mode, coefficients)) => Stereo::new(
+                        voice.filter.0.process(new_frame.left(), mode, coefficients),
+                        voice
+                            .filter
+                            .1
+                            .process(new_frame.right(), mode, coefficients),
+                    ),
+                    None => new_frame,
+                };
+
+                voice.position += if semitones != 0.0 {
+                    voice.pitch_ratio * tune[i] * f32::powf(2.0, semitones / 12.0)
+                } else {
+                    voice.pitch_ratio * tune[i]
+                };
+                if voice.position >= (sound.len - 1) as f32 {
                     voice.state = VoiceState::Free;
                     voice.sound = None;
+                    len = i + 1;
                     break;
                 }
             }
+
+            let gains = &mut gains[..len];
+            voice.env.fill(gains);
+            for (gain, amp) in gains.iter_mut().zip(amp.iter()) {
+                *gain *= voice.volume * amp;
+            }
+            for ((out, frame), gain) in buffer.iter_mut().zip(&frames[..len]).zip(gains.iter()) {
+                let output = *frame * *gain;
+                out.0 += output.left();
+                out.1 += output.right();
+            }
         }
     }
 }
 
-struct Frame {
-    left: f32,
-    right: f32,
+fn map(v: f32, from: (f32, f32), to: (f32, f32)) -> f32 {
+    (v - from.0) * (to.1 - to.0) / (from.1 - from.0) + to.0
+}
+
+/// Catmull-Rom interpolation between `y1` and `y2`, `t` is the position between them.
+fn cubic(y0: f32, y1: f32, y2: f32, y3: f32, t: f32) -> f32 {
+    let a = -0.5 * y0 + 1.5 * y1 - 1.5 * y2 + 0.5 * y3;
+    let b = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+    let c = -0.5 * y0 + 0.5 * y2;
+    ((a * t + b) * t + c) * t + y1
 }
 
-impl Mul<f32> for &Frame {
-    type Output = Frame;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hound::WavWriter;
+    use std::fs;
+
+    /// Left and right of the frames written to every test file, exact in 8 bits.
+    const FRAMES: [(f32, f32); 3] = [(0.5, -0.25), (-0.5, 0.25), (0.75, -1.0)];
 
-    fn mul(self, f: f32) -> Frame {
-        Frame {
-            left: self.left * f,
-            right: self.right * f,
+    fn temp_path(name: &str) -> Utf8PathBuf {
+        let dir = Utf8PathBuf::from_path_buf(std::env::temp_dir()).unwrap();
+        dir.join(format!("ruis-sampler-{}-{}", std::process::id(), name))
+    }
+
+    /// Writes `FRAMES` in a stereo WAV file and loads it back, decoded up front.
+    fn round_trip(name: &str, sample_format: SampleFormat, bits_per_sample: u16) -> Sound {
+        let path = temp_path(name);
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample,
+            sample_format,
+        };
+        let mut writer = WavWriter::create(&path, spec).unwrap();
+        for sample in FRAMES.iter().flat_map(|(left, right)| [*left, *right]) {
+            match sample_format {
+                SampleFormat::Float => writer.write_sample(sample).unwrap(),
+                SampleFormat::Int => {
+                    let scale = f32::powi(2.0, bits_per_sample as i32 - 1);
+                    let max = scale - 1.0;
+                    writer
+                        .write_sample((sample * scale).clamp(-scale, max) as i32)
+                        .unwrap();
+                }
+            }
         }
+        writer.finalize().unwrap();
+        let sound = Sampler::load_sound_with(&path, MemoryPolicy::Resident).unwrap();
+        fs::remove_file(&path).unwrap();
+        sound
     }
-}
 
-impl Add for Frame {
-    type Output = Frame;
+    fn assert_frames(sound: &Sound, precision: f32) {
+        let frames: Vec<(f32, f32)> = sound.frames().collect();
+        assert_eq!(frames.len(), FRAMES.len());
+        for (frame, expected) in frames.iter().zip(&FRAMES) {
+            assert!((frame.0 - expected.0).abs() <= precision, "{:?}", frames);
+            assert!((frame.1 - expected.1).abs() <= precision, "{:?}", frames);
+        }
+    }
+
+    #[test]
+    fn decodes_float_wav() {
+        assert_frames(&round_trip("float.wav", SampleFormat::Float, 32), 0.0);
+    }
 
-    fn add(self, other: Frame) -> Frame {
-        Frame {
-            left: self.left + other.left,
-            right: self.right + other.right,
+    #[test]
+    fn decodes_24_bit_wav() {
+        assert_frames(
+            &round_trip("24.wav", SampleFormat::Int, 24),
+            1.0 / 8388608.0,
+        );
+    }
+
+    #[test]
+    fn decodes_8_bit_wav() {
+        assert_frames(&round_trip("8.wav", SampleFormat::Int, 8), 1.0 / 128.0);
+    }
+
+    #[test]
+    fn decodes_mapped_samples() {
+        let cases: [(Encoding, &[u8], f32); 5] = [
+            (Encoding::U8, &[192], 0.5),
+            (Encoding::I16, &(-16384i16).to_le_bytes(), -0.5),
+            (Encoding::I24, &[0x00, 0x00, 0xa0], -0.75),
+            (Encoding::I32, &(1i32 << 30).to_le_bytes(), 0.5),
+            (Encoding::F32, &0.25f32.to_le_bytes(), 0.25),
+        ];
+        for (encoding, bytes, expected) in cases.iter() {
+            assert_eq!(encoding.decode(bytes), *expected);
         }
     }
-}
 
-fn map(v: f32, from: (f32, f32), to: (f32, f32)) -> f32 {
-    (v - from.0) * (to.1 - to.0) / (from.1 - from.0) + to.0
+    #[test]
+    fn unsupported_formats_are_errors() {
+        // A WAV header for 4-bit ADPCM, which hound doesn't read
+        let path = temp_path("adpcm.wav");
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&38u32.to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        for field in [2u16, 1] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        bytes.extend_from_slice(&44100u32.to_le_bytes());
+        bytes.extend_from_slice(&22050u32.to_le_bytes());
+        for field in [1u16, 4] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(&[0x12, 0x34]);
+        fs::write(&path, bytes).unwrap();
+        let result = Sampler::load_sound_with(&path, MemoryPolicy::Resident);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+
+        assert!(Sampler::load_sound(&temp_path("sound.flac")).is_err());
+    }
+
+    fn render(sampler: &mut Sampler) -> f32 {
+        let mut buffer = [(0.0, 0.0); 256];
+        sampler.render(&mut buffer);
+        buffer.iter().map(|frame| frame.0.abs()).sum()
+    }
+
+    #[test]
+    fn sounds_too_short_are_not_played() {
+        let mut sampler = Sampler::new();
+        sampler.prepare(&EngineConfig::default());
+        let sound = Arc::new(Sound::from_frames(vec![(1.0, 1.0)], 44100));
+        sampler.trigger(sound, 0, ROOT_PITCH, 127);
+        assert_eq!(render(&mut sampler), 0.0);
+    }
+
+    #[test]
+    fn shortest_sounds_play() {
+        let mut sampler = Sampler::new();
+        sampler.prepare(&EngineConfig::default());
+        let frames = vec![(1.0, 1.0); MIN_FRAMES];
+        let sound = Arc::new(Sound::from_frames(frames, 44100));
+        sampler.trigger(sound, 0, ROOT_PITCH, 127);
+        render(&mut sampler);
+        assert_eq!(sampler.active_voices(), 0);
+    }
 }
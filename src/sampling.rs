@@ -0,0 +1,143 @@
+use crate::pattern::Section;
+use crate::sampler::Sound;
+use ringbuf::{Consumer, Producer, RingBuffer};
+use std::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Frames buffered between the engine and the app, about six seconds at 44.1kHz.
+const BUFFER_FRAMES: usize = 1 << 18;
+
+const ARMED: u8 = 0;
+const RECORDING: u8 = 1;
+const DONE: u8 = 2;
+
+/// Engine side of a recording from a hardware input into a new sound.
+pub struct SamplingTap {
+    pub input: usize,
+    /// Lines of the pattern to record, or everything from the start until it's stopped.
+    punch: Option<Section>,
+    previous_line: Option<usize>,
+    prod: Producer<(f32, f32)>,
+    sample_rate: Arc<AtomicU32>,
+    state: Arc<AtomicU8>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl SamplingTap {
+    /// Called by the engine when the recording starts.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate
+            .store(sample_rate as u32, Ordering::Release);
+    }
+
+    /// Called for every block rendered, with the line of the pattern playing if any. With a
+    /// punch, recording starts when playback enters its first line and stops when it leaves
+    /// the section.
+    pub fn record(&mut self, line: Option<usize>, frames: &[(f32, f32)]) {
+        let state = self.state.load(Ordering::Relaxed);
+        let recording = match (state, self.punch) {
+            (DONE, _) => false,
+            (_, None) => true,
+            (ARMED, Some(punch)) => line == Some(punch.start) && self.previous_line != line,
+            (_, Some(punch)) => line.is_some_and(|line| (punch.start..punch.end).contains(&line)),
+        };
+        self.previous_line = line;
+        if !recording {
+            if state == RECORDING {
+                self.state.store(DONE, Ordering::Release);
+            }
+            return;
+        }
+        if state == ARMED {
+            self.state.store(RECORDING, Ordering::Release);
+        }
+        let pushed = self.prod.push_slice(frames);
+        if pushed < frames.len() {
+            self.dropped
+                .fetch_add(frames.len() - pushed, Ordering::Relaxed);
+        }
+    }
+}
+
+/// App side of a recording, gathers the frames into the new sound.
+pub struct Sampling {
+    /// Instrument slot the sound goes to.
+    pub slot: usize,
+    pub input: usize,
+    pub punch: Option<Section>,
+    cons: Consumer<(f32, f32)>,
+    frames: Vec<(f32, f32)>,
+    sample_rate: Arc<AtomicU32>,
+    state: Arc<AtomicU8>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Sampling {
+    pub fn new(input: usize, slot: usize, punch: Option<Section>) -> (Self, SamplingTap) {
+        let (prod, cons) = RingBuffer::new(BUFFER_FRAMES).split();
+        let sample_rate = Arc::new(AtomicU32::new(0));
+        let state = Arc::new(AtomicU8::new(ARMED));
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let tap = SamplingTap {
+            input,
+            punch,
+            previous_line: None,
+            prod,
+            sample_rate: Arc::clone(&sample_rate),
+            state: Arc::clone(&state),
+            dropped: Arc::clone(&dropped),
+        };
+        let sampling = Self {
+            slot,
+            input,
+            punch,
+            cons,
+            frames: Vec::new(),
+            sample_rate,
+            state,
+            dropped,
+        };
+        (sampling, tap)
+    }
+
+    /// Reads the frames recorded since the last call.
+    pub fn update(&mut self) {
+        while let Some(frame) = self.cons.pop() {
+            self.frames.push(frame);
+        }
+    }
+
+    /// Whether input is being recorded, rather than waiting for the punch-in.
+    pub fn is_recording(&self) -> bool {
+        self.state.load(Ordering::Acquire) == RECORDING
+    }
+
+    /// Whether playback left the punch section.
+    pub fn is_done(&self) -> bool {
+        self.state.load(Ordering::Acquire) == DONE
+    }
+
+    /// Number of frames lost because the app didn't read them in time.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Length of the recording so far, in seconds.
+    pub fn seconds(&self) -> f64 {
+        match self.sample_rate.load(Ordering::Acquire) {
+            0 => 0.0,
+            rate => self.frames.len() as f64 / rate as f64,
+        }
+    }
+
+    /// The recorded sound, unless nothing was recorded. Called once the tap was taken away from
+    /// the engine, the frames it still records are lost.
+    pub fn finish(mut self) -> Option<Sound> {
+        self.update();
+        let sample_rate = self.sample_rate.load(Ordering::Acquire);
+        if self.frames.is_empty() || sample_rate == 0 {
+            return None;
+        }
+        Some(Sound::from_frames(self.frames, sample_rate))
+    }
+}
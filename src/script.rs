@@ -0,0 +1,103 @@
+//! Scripts are files of commands, run as if typed on the command line: `:run <path>`. One
+//! command per line, without the colon, blank lines and lines starting with `#` are skipped.
+//! Commands can also be hooked to the transport, `:on loop tr rotate 1` moves the notes of the
+//! track along every time the pattern comes around.
+
+use anyhow::{anyhow, Context, Result};
+use camino::Utf8Path;
+use std::fmt;
+use std::fs;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Hook {
+    /// The song starts playing.
+    Play,
+    /// The song stops.
+    Stop,
+    /// The pattern starts over while playing.
+    Loop,
+}
+
+impl Hook {
+    const ALL: [Hook; 3] = [Hook::Play, Hook::Stop, Hook::Loop];
+
+    pub fn parse(name: &str) -> Result<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|hook| hook.name() == name)
+            .ok_or_else(|| anyhow!("unknown hook {}, expected play, stop or loop", name))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::Play => "play",
+            Hook::Stop => "stop",
+            Hook::Loop => "loop",
+        }
+    }
+}
+
+impl fmt::Display for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Reads the commands of a script, with the line each is on.
+pub fn load(path: &Utf8Path) -> Result<Vec<(usize, String)>> {
+    let text = fs::read_to_string(path).with_context(|| format!("unable to read {}", path))?;
+    Ok(text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| (i, line.to_string()))
+        .collect())
+}
+
+/// Commands hooked to the transport, and what it did when last checked.
+#[derive(Default)]
+pub struct Hooks {
+    commands: Vec<(Hook, String)>,
+    playing: bool,
+    /// Times the pattern played through, as of the last check.
+    loops: usize,
+}
+
+impl Hooks {
+    pub fn add(&mut self, hook: Hook, command: String) {
+        self.commands.push((hook, command));
+    }
+
+    pub fn clear(&mut self, hook: Hook) {
+        self.commands.retain(|(h, _)| *h != hook);
+    }
+
+    /// Follows the transport, returning the commands of the hooks it set off since the last
+    /// call, in order.
+    pub fn update(&mut self, playing: bool, current_tick: usize, num_lines: usize) -> Vec<String> {
+        let mut fired = Vec::new();
+        let loops = current_tick / usize::max(num_lines, 1);
+        match (self.playing, playing) {
+            (false, true) => fired.push(Hook::Play),
+            (true, false) => fired.push(Hook::Stop),
+            (true, true) if loops != self.loops => fired.push(Hook::Loop),
+            _ => {}
+        }
+        self.playing = playing;
+        self.loops = loops;
+        fired
+            .into_iter()
+            .flat_map(|hook| self.commands_of(hook))
+            .collect()
+    }
+
+    fn commands_of(&self, hook: Hook) -> Vec<String> {
+        self.commands
+            .iter()
+            .filter(|(h, _)| *h == hook)
+            .map(|(_, command)| command.clone())
+            .collect()
+    }
+}
@@ -0,0 +1,193 @@
+//! Offline time stretching and pitch shifting, to audition loops at the tempo and in the key of
+//! the song.
+
+use anyhow::{anyhow, Result};
+use camino::Utf8Path;
+use std::f32::consts::PI;
+
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// Tempos a loop without one in its name is assumed to be in, from its length.
+const MIN_LOOP_BPM: f64 = 75.0;
+const MAX_LOOP_BPM: f64 = 150.0;
+const BEATS_PER_BAR: f64 = 4.0;
+
+/// Length of the grains the stretcher cuts the input into, in frames.
+const GRAIN: usize = 2048;
+/// How far a grain can be moved to line up with the previous one.
+const TOLERANCE: usize = GRAIN / 4;
+
+/// A musical key, e.g. `Am` or `F#`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Key {
+    /// Pitch class of the root, 0 is C.
+    pub root: u8,
+    pub minor: bool,
+}
+
+impl Key {
+    /// Parses a note name, optionally with a sharp or flat, followed by `m` or `min` for minor
+    /// keys.
+    pub fn parse(name: &str) -> Result<Self> {
+        let invalid = || anyhow!("invalid key {}", name);
+        let mut chars = name.chars();
+        let root = match chars.next() {
+            Some(c @ 'A'..='G') => NOTE_NAMES.iter().position(|n| n.starts_with(c)).unwrap(),
+            _ => return Err(invalid()),
+        };
+        let rest = chars.as_str();
+        let (root, rest) = match rest.chars().next() {
+            Some('#') => (root + 1, &rest[1..]),
+            Some('b') => (root + 11, &rest[1..]),
+            _ => (root, rest),
+        };
+        let minor = match rest {
+            "" | "maj" => false,
+            "m" | "min" => true,
+            _ => return Err(invalid()),
+        };
+        Ok(Self {
+            root: (root % 12) as u8,
+            minor,
+        })
+    }
+
+    pub fn name(&self) -> String {
+        let suffix = if self.minor { "m" } else { "" };
+        format!("{}{}", NOTE_NAMES[self.root as usize], suffix)
+    }
+
+    /// Smallest transposition from this key to `other`, between -6 and 5 semitones. Minor keys
+    /// are treated as their relative major, so Am goes to C without being transposed.
+    pub fn semitones_to(&self, other: Key) -> i32 {
+        let major = |key: &Key| key.root as i32 + if key.minor { 3 } else { 0 };
+        let distance = (major(&other) - major(self)).rem_euclid(12);
+        if distance > 5 {
+            distance - 12
+        } else {
+            distance
+        }
+    }
+}
+
+/// Tempo and key of a loop, from its file name where sample packs usually put them, e.g.
+/// `bass_Am_124bpm.wav`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LoopInfo {
+    pub bpm: f64,
+    pub key: Option<Key>,
+}
+
+impl LoopInfo {
+    /// Without a tempo in the name, the loop is assumed to be a power of two bars long.
+    pub fn from_path(path: &Utf8Path, num_frames: usize, sample_rate: u32) -> Option<Self> {
+        let name = path.file_stem()?;
+        let tokens = name.split(|c: char| !c.is_ascii_alphanumeric() && c != '#');
+        let mut bpm = None;
+        let mut key = None;
+        for token in tokens {
+            let lower = token.to_ascii_lowercase();
+            if let Some(tempo) = lower.strip_suffix("bpm") {
+                bpm = tempo.parse().ok().or(bpm);
+            } else if let Ok(k) = Key::parse(token) {
+                key = Some(k);
+            }
+        }
+        let bpm = match bpm {
+            Some(bpm) => bpm,
+            None => {
+                let seconds = num_frames as f64 / sample_rate as f64;
+                if seconds <= 0.0 {
+                    return None;
+                }
+                let mut bpm = BEATS_PER_BAR * 60.0 / seconds;
+                while bpm < MIN_LOOP_BPM {
+                    bpm *= 2.0;
+                }
+                while bpm >= MAX_LOOP_BPM {
+                    bpm /= 2.0;
+                }
+                bpm
+            }
+        };
+        Some(Self { bpm, key })
+    }
+}
+
+/// Changes the pitch by `semitones` and the length by `factor`, independently.
+pub fn stretch_and_shift(input: &[(f32, f32)], factor: f64, semitones: i32) -> Vec<(f32, f32)> {
+    let pitch = f64::powf(2.0, semitones as f64 / 12.0);
+    resample(&stretch(input, factor * pitch), pitch)
+}
+
+/// Time stretches with WSOLA: overlapping grains are taken from the input at the stretched
+/// rate, each moved a little so it lines up with the end of the previous one.
+pub fn stretch(input: &[(f32, f32)], factor: f64) -> Vec<(f32, f32)> {
+    let len = (input.len() as f64 * factor).round() as usize;
+    if input.len() < GRAIN || factor <= 0.0 {
+        return resample(input, 1.0 / factor.max(1e-3));
+    }
+    let hop = GRAIN / 2;
+    // A periodic Hann window, whose overlapping copies at half a grain sum to one.
+    let window: Vec<f32> = (0..GRAIN)
+        .map(|i| 0.5 - 0.5 * f32::cos(2.0 * PI * i as f32 / GRAIN as f32))
+        .collect();
+    let mono = |i: usize| input.get(i).map_or(0.0, |(l, r)| l + r);
+
+    let mut output = vec![(0.0, 0.0); len + GRAIN];
+    let mut previous = 0;
+    let mut grain = 0;
+    while grain * hop < len {
+        let nominal = (grain as f64 * hop as f64 / factor) as usize;
+        // Where the previous grain would have continued, which the new grain should resemble.
+        let continuation = previous + hop;
+        let start = if grain == 0 {
+            0
+        } else {
+            let low = nominal.saturating_sub(TOLERANCE);
+            let high = usize::min(nominal + TOLERANCE, input.len().saturating_sub(1));
+            let correlation = |start: usize| {
+                (0..hop)
+                    .step_by(4)
+                    .map(|i| mono(start + i) * mono(continuation + i))
+                    .sum::<f32>()
+            };
+            (low..=high.max(low))
+                .step_by(2)
+                .map(|start| (start, correlation(start)))
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map_or(nominal, |(start, _)| start)
+        };
+        for (i, w) in window.iter().enumerate() {
+            if let Some(frame) = input.get(start + i) {
+                let out = &mut output[grain * hop + i];
+                out.0 += frame.0 * w;
+                out.1 += frame.1 * w;
+            }
+        }
+        previous = start;
+        grain += 1;
+    }
+    output.truncate(len);
+    output
+}
+
+/// Reads the input `ratio` times faster, interpolating linearly.
+pub fn resample(input: &[(f32, f32)], ratio: f64) -> Vec<(f32, f32)> {
+    if input.is_empty() || ratio <= 0.0 {
+        return Vec::new();
+    }
+    let len = (input.len() as f64 / ratio) as usize;
+    (0..len)
+        .map(|i| {
+            let position = i as f64 * ratio;
+            let index = position as usize;
+            let t = (position - index as f64) as f32;
+            let a = input[index.min(input.len() - 1)];
+            let b = input[(index + 1).min(input.len() - 1)];
+            (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t)
+        })
+        .collect()
+}
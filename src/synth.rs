@@ -0,0 +1,619 @@
+//! A polyphonic subtractive synth: two oscillators are mixed into a resonant filter, which has
+//! an envelope of its own, and then shaped by the amp envelope. The first oscillator can play a
+//! wavetable instead, morphing between its frames. Both oscillators can be stacked in unison,
+//! detuned and spread across the stereo field.
+
+use crate::engine::{Device, EngineConfig, CONTROL_BLOCK_SIZE};
+use crate::env::{Envelope, EnvelopeParams, State as EnvelopeState};
+use crate::filter::{Coefficients, FilterMode, Svf};
+use crate::instrument::{Instrument, Quality};
+use crate::mono::{self, Change, HeldNotes, Priority};
+use crate::param::{Param, Smoothed, Unit};
+use crate::wavetable::Wavetable;
+use anyhow::{anyhow, Result};
+use atomic_float::AtomicF32;
+use std::f32::consts::{PI, SQRT_2};
+use std::sync::{atomic::Ordering, Arc};
+
+const NUM_VOICES: usize = 8;
+/// Octaves the filter envelope moves the cutoff at full amount.
+const CUTOFF_MOD_RANGE: f32 = 6.0;
+/// Most copies of each oscillator stacked in unison.
+const MAX_UNISON: usize = 8;
+/// Semitones the outermost unison copies are detuned by at full detune, on either side.
+const UNISON_DETUNE_RANGE: f32 = 1.0;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Waveform {
+    Saw,
+    Square,
+    Triangle,
+    Sine,
+}
+
+impl Waveform {
+    /// Waveform of a param value, in the order of the enum.
+    fn from_value(value: f32) -> Self {
+        match value.round() as i32 {
+            v if v <= 0 => Waveform::Saw,
+            1 => Waveform::Square,
+            2 => Waveform::Triangle,
+            _ => Waveform::Sine,
+        }
+    }
+
+    /// The sample at `phase`, from 0 to 1 over a cycle.
+    fn sample(self, phase: f32) -> f32 {
+        match self {
+            Waveform::Saw => 2.0 * phase - 1.0,
+            Waveform::Square if phase < 0.5 => 1.0,
+            Waveform::Square => -1.0,
+            Waveform::Triangle => 4.0 * (phase - 0.5).abs() - 1.0,
+            Waveform::Sine => f32::sin(2.0 * PI * phase),
+        }
+    }
+
+    /// Same as `sample` with the corners rounded off over one `increment` on each side, which
+    /// removes most of what would fold back above Nyquist. Jumps get a PolyBLEP, the kinks of
+    /// the triangle a PolyBLAMP.
+    fn sample_band_limited(self, phase: f32, increment: f32) -> f32 {
+        let half = (phase + 0.5).fract();
+        match self {
+            Waveform::Saw => self.sample(phase) - blep(phase, increment),
+            Waveform::Square => self.sample(phase) + blep(phase, increment) - blep(half, increment),
+            Waveform::Triangle => {
+                self.sample(phase)
+                    - 4.0 * increment * (blamp(phase, increment) - blamp(half, increment))
+            }
+            Waveform::Sine => self.sample(phase),
+        }
+    }
+}
+
+/// How the oscillators deal with aliasing.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Oscillators {
+    /// Band-limited with PolyBLEP, clean up to the top of the keyboard.
+    #[default]
+    PolyBlep,
+    /// The plain waveforms, which alias above a few hundred Hz but cost the least. Offline
+    /// renders are band-limited anyway.
+    Naive,
+}
+
+impl Oscillators {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "polyblep" => Ok(Oscillators::PolyBlep),
+            "naive" => Ok(Oscillators::Naive),
+            _ => Err(anyhow!(
+                "unknown oscillators {}, expected polyblep or naive",
+                name
+            )),
+        }
+    }
+}
+
+/// Correction of a jump of -2 at phase 0, spread over the samples on either side.
+fn blep(phase: f32, increment: f32) -> f32 {
+    if phase < increment {
+        let x = phase / increment;
+        2.0 * x - x * x - 1.0
+    } else if phase > 1.0 - increment {
+        let x = (phase - 1.0) / increment;
+        x * x + 2.0 * x + 1.0
+    } else {
+        0.0
+    }
+}
+
+/// Correction of a change of slope at phase 0, the integral of `blep`.
+fn blamp(phase: f32, increment: f32) -> f32 {
+    if phase < increment {
+        let x = phase / increment - 1.0;
+        -x * x * x / 3.0
+    } else if phase > 1.0 - increment {
+        let x = (phase - 1.0) / increment + 1.0;
+        x * x * x / 3.0
+    } else {
+        0.0
+    }
+}
+
+/// Settings of the synth besides its envelopes, shared by all voices.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SynthParam {
+    Amp,
+    /// Waveform of each oscillator, see `Waveform::from_value`.
+    Osc1Wave,
+    Osc2Wave,
+    /// Transposition of the second oscillator in semitones.
+    Detune,
+    /// Level of the second oscillator against the first, from 0 to 1.
+    Mix,
+    Cutoff,
+    Resonance,
+    /// How far the filter envelope moves the cutoff, from -1 to 1.
+    FilterAmount,
+    /// Frame of the wavetable, from the first at 0 to the last at 1.
+    Position,
+    /// How far the position envelope moves the position, from -1 to 1.
+    PositionAmount,
+    /// Copies of each oscillator, 1 being a single one.
+    Unison,
+    /// Spread in pitch of the unison copies, from 0 to 1.
+    UnisonDetune,
+    /// Response of `UnisonDetune`, from linear at 0 to the curve of a supersaw at 1.
+    UnisonCurve,
+    /// Spread of the unison copies across the stereo field, from 0 to 1.
+    UnisonSpread,
+}
+
+impl SynthParam {
+    pub const ALL: [SynthParam; 14] = [
+        SynthParam::Amp,
+        SynthParam::Osc1Wave,
+        SynthParam::Osc2Wave,
+        SynthParam::Detune,
+        SynthParam::Mix,
+        SynthParam::Cutoff,
+        SynthParam::Resonance,
+        SynthParam::FilterAmount,
+        SynthParam::Position,
+        SynthParam::PositionAmount,
+        SynthParam::Unison,
+        SynthParam::UnisonDetune,
+        SynthParam::UnisonCurve,
+        SynthParam::UnisonSpread,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SynthParam::Amp => "Amp",
+            SynthParam::Osc1Wave => "Osc1Wave",
+            SynthParam::Osc2Wave => "Osc2Wave",
+            SynthParam::Detune => "Detune",
+            SynthParam::Mix => "Mix",
+            SynthParam::Cutoff => "Cutoff",
+            SynthParam::Resonance => "Resonance",
+            SynthParam::FilterAmount => "FilterAmount",
+            SynthParam::Position => "Position",
+            SynthParam::PositionAmount => "PositionAmount",
+            SynthParam::Unison => "Unison",
+            SynthParam::UnisonDetune => "UnisonDetune",
+            SynthParam::UnisonCurve => "UnisonCurve",
+            SynthParam::UnisonSpread => "UnisonSpread",
+        }
+    }
+
+    fn default_value(self) -> f32 {
+        match self {
+            SynthParam::Amp => -6.0,
+            SynthParam::Osc1Wave | SynthParam::Osc2Wave => 0.0,
+            SynthParam::Detune => 0.1,
+            SynthParam::Mix => 0.5,
+            SynthParam::Cutoff => 2_000.0,
+            SynthParam::Resonance => 0.2,
+            SynthParam::FilterAmount => 0.5,
+            SynthParam::Position | SynthParam::PositionAmount => 0.0,
+            SynthParam::Unison => 1.0,
+            SynthParam::UnisonDetune => 0.3,
+            SynthParam::UnisonCurve => 1.0,
+            SynthParam::UnisonSpread => 0.5,
+        }
+    }
+
+    /// Settings which only apply with a wavetable.
+    fn is_wavetable(self) -> bool {
+        matches!(self, SynthParam::Position | SynthParam::PositionAmount)
+    }
+
+    /// The param editing `value`, with the range and step of this setting.
+    fn param(self, value: Arc<AtomicF32>) -> Param {
+        match self {
+            SynthParam::Amp => Param::new(-60.0, value, 6.0, 1.0).with_unit(Unit::Decibel),
+            SynthParam::Osc1Wave | SynthParam::Osc2Wave => Param::new(0.0, value, 3.0, 1.0),
+            SynthParam::Detune => Param::new(-24.0, value, 24.0, 0.05),
+            SynthParam::Mix => Param::new(0.0, value, 1.0, 0.05),
+            SynthParam::Cutoff => Param::new(20.0, value, 20_000.0, 50.0).with_unit(Unit::Hertz),
+            SynthParam::Resonance => Param::new(0.0, value, 1.0, 0.05),
+            SynthParam::FilterAmount | SynthParam::PositionAmount => {
+                Param::new(-1.0, value, 1.0, 0.05)
+            }
+            SynthParam::Position => Param::new(0.0, value, 1.0, 0.01),
+            SynthParam::Unison => Param::new(1.0, value, MAX_UNISON as f32, 1.0),
+            SynthParam::UnisonDetune | SynthParam::UnisonCurve | SynthParam::UnisonSpread => {
+                Param::new(0.0, value, 1.0, 0.05)
+            }
+        }
+    }
+}
+
+struct Voice {
+    column: usize,
+    pitch: u8,
+    volume: f32,
+    /// Position of each unison copy of each oscillator in its cycle, from 0 to 1.
+    phases: [[f32; MAX_UNISON]; 2],
+    env: Envelope,
+    filter_env: Envelope,
+    position_env: Envelope,
+    /// One filter per channel, the unison copies are panned apart.
+    filters: [Svf; 2],
+}
+
+impl Voice {
+    fn new(sample_rate: f32) -> Self {
+        Self {
+            column: 0,
+            pitch: 0,
+            volume: 0.0,
+            phases: [[0.0; MAX_UNISON]; 2],
+            env: Envelope::new(sample_rate),
+            filter_env: Envelope::new(sample_rate),
+            position_env: Envelope::new(sample_rate),
+            filters: [Svf::default(); 2],
+        }
+    }
+
+    /// Sounding until the amp envelope ends.
+    fn is_busy(&self) -> bool {
+        self.env.state != EnvelopeState::Init
+    }
+
+    fn is_held(&self) -> bool {
+        self.is_busy() && self.env.state != EnvelopeState::Release
+    }
+
+    fn reset(&mut self) {
+        self.env.reset();
+        self.filter_env.reset();
+        self.position_env.reset();
+        self.filters = [Svf::default(); 2];
+    }
+}
+
+pub struct Synth {
+    voices: Vec<Voice>,
+    /// One value per `SynthParam`, in the order of `SynthParam::ALL`.
+    values: Vec<Arc<AtomicF32>>,
+    amp: Smoothed,
+    envelope: EnvelopeParams,
+    filter_envelope: EnvelopeParams,
+    /// Played by the first oscillator instead of its waveform.
+    wavetable: Option<Arc<Wavetable>>,
+    position_envelope: EnvelopeParams,
+    filter_mode: FilterMode,
+    oscillators: Oscillators,
+    /// Notes held in mono mode, which plays one note at a time.
+    mono: Option<HeldNotes>,
+    quality: Quality,
+    /// State of the generator randomizing the phases of unison copies.
+    seed: u32,
+    sample_rate: f32,
+}
+
+impl Synth {
+    pub fn new(filter_mode: FilterMode, oscillators: Oscillators) -> Self {
+        let sample_rate = EngineConfig::default().sample_rate as f32;
+        let values: Vec<_> = SynthParam::ALL
+            .iter()
+            .map(|param| Arc::new(AtomicF32::new(param.default_value())))
+            .collect();
+        let mut amp = Smoothed::new(SynthParam::Amp.default_value(), sample_rate);
+        amp.val = Arc::clone(&values[SynthParam::Amp as usize]);
+        Self {
+            voices: (0..NUM_VOICES).map(|_| Voice::new(sample_rate)).collect(),
+            values,
+            amp,
+            envelope: EnvelopeParams::new(0.005, 0.3, 0.7, 0.3),
+            filter_envelope: EnvelopeParams::new(0.005, 0.4, 0.0, 0.3),
+            wavetable: None,
+            position_envelope: EnvelopeParams::new(0.005, 1.0, 0.0, 0.3),
+            filter_mode,
+            oscillators,
+            mono: None,
+            quality: Quality::Realtime,
+            seed: 0x2545_f491,
+            sample_rate,
+        }
+    }
+
+    /// Plays `wavetable` on the first oscillator, morphing with the `Position` param and the
+    /// position envelope.
+    pub fn with_wavetable(mut self, wavetable: Arc<Wavetable>) -> Self {
+        self.wavetable = Some(wavetable);
+        self
+    }
+
+    /// Plays one note at a time on a single voice, picked among the held notes by `priority`.
+    pub fn with_mono(mut self, priority: Priority) -> Self {
+        self.mono = Some(HeldNotes::new(priority));
+        self
+    }
+
+    fn get(&self, param: SynthParam) -> f32 {
+        self.values[param as usize].load(Ordering::Relaxed)
+    }
+
+    fn unison(&self) -> usize {
+        (self.get(SynthParam::Unison).round() as usize).clamp(1, MAX_UNISON)
+    }
+
+    /// A random phase from a xorshift generator.
+    fn random_phase(&mut self) -> f32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed as f32 / u32::MAX as f32
+    }
+
+    fn render_block(&mut self, buffer: &mut [(f32, f32)]) {
+        let mut amp = [0.0; CONTROL_BLOCK_SIZE];
+        let amp = &mut amp[..buffer.len()];
+        self.amp.fill(amp);
+        amp.iter_mut().for_each(|amp| *amp = db_to_gain(*amp));
+
+        let waveforms = [
+            Waveform::from_value(self.get(SynthParam::Osc1Wave)),
+            Waveform::from_value(self.get(SynthParam::Osc2Wave)),
+        ];
+        let detune = f32::powf(2.0, self.get(SynthParam::Detune) / 12.0);
+        let mix = self.get(SynthParam::Mix);
+        let cutoff = self.get(SynthParam::Cutoff);
+        let resonance = self.get(SynthParam::Resonance);
+        let amount = self.get(SynthParam::FilterAmount);
+        let position = self.get(SynthParam::Position);
+        let position_amount = self.get(SynthParam::PositionAmount);
+        let unison = self.unison();
+        let detune_amount = detune_curve(
+            self.get(SynthParam::UnisonDetune),
+            self.get(SynthParam::UnisonCurve),
+        );
+        let spread = self.get(SynthParam::UnisonSpread);
+        // Copies are spaced evenly from one side to the other, in pitch and in the stereo
+        // field, and each pair is panned with equal power so a centered copy stays at unity.
+        let mut ratios = [1.0; MAX_UNISON];
+        let mut pans = [(1.0, 1.0); MAX_UNISON];
+        for copy in 0..unison {
+            let offset = match unison {
+                1 => 0.0,
+                _ => 2.0 * copy as f32 / (unison - 1) as f32 - 1.0,
+            };
+            ratios[copy] = f32::powf(2.0, offset * detune_amount * UNISON_DETUNE_RANGE / 12.0);
+            let angle = (offset * spread + 1.0) * PI / 4.0;
+            pans[copy] = (angle.cos() * SQRT_2, angle.sin() * SQRT_2);
+        }
+        let unison_gain = 1.0 / (unison as f32).sqrt();
+        let ratios = &ratios[..unison];
+        let pans = &pans[..unison];
+        let wavetable = self.wavetable.as_deref();
+        let (mode, sample_rate) = (self.filter_mode, self.sample_rate);
+        let band_limited =
+            self.oscillators == Oscillators::PolyBlep || self.quality == Quality::Offline;
+        let oscillator = |waveform: Waveform, phase: f32, increment: f32| {
+            if band_limited {
+                waveform.sample_band_limited(phase, increment)
+            } else {
+                waveform.sample(phase)
+            }
+        };
+
+        for voice in self.voices.iter_mut().filter(|v| v.is_busy()) {
+            let frequency = 440.0 * f32::powf(2.0, (voice.pitch as f32 - 69.0) / 12.0);
+            let increments = [frequency / sample_rate, frequency * detune / sample_rate];
+            // The filter envelope moves the cutoff at control rate, the filter smooths the
+            // steps.
+            let octaves = amount * voice.filter_env.level() * CUTOFF_MOD_RANGE;
+            let coefficients = Coefficients::new(
+                (cutoff * f32::powf(2.0, octaves)).clamp(20.0, 20_000.0),
+                resonance,
+                sample_rate,
+            );
+            for (i, out) in buffer.iter_mut().enumerate() {
+                let position = position + position_amount * voice.position_env.value();
+                let (mut left, mut right) = (0.0, 0.0);
+                for (copy, (ratio, pan)) in ratios.iter().zip(pans).enumerate() {
+                    let increments = [increments[0] * ratio, increments[1] * ratio];
+                    let phases = [voice.phases[0][copy], voice.phases[1][copy]];
+                    let first = match wavetable {
+                        Some(table) => table.sample(phases[0], position, increments[0]),
+                        None => oscillator(waveforms[0], phases[0], increments[0]),
+                    };
+                    let sample = first * (1.0 - mix)
+                        + oscillator(waveforms[1], phases[1], increments[1]) * mix;
+                    left += sample * pan.0;
+                    right += sample * pan.1;
+                    for (phases, increment) in voice.phases.iter_mut().zip(increments) {
+                        phases[copy] = (phases[copy] + increment).fract();
+                    }
+                }
+                let left = voice.filters[0].process(left * unison_gain, mode, coefficients);
+                let right = voice.filters[1].process(right * unison_gain, mode, coefficients);
+                voice.filter_env.value();
+                let gain = voice.volume * amp[i] * voice.env.value();
+                out.0 += left * gain;
+                out.1 += right * gain;
+                if !voice.is_busy() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Plays a note on a free voice, or else on the quietest one.
+    fn play_note(&mut self, column: usize, pitch: u8, velocity: u8) {
+        // Tracks play one note at a time, the previous one fades out with its release
+        self.release(column);
+        let index = match self.voices.iter().position(|v| !v.is_busy()) {
+            Some(index) => index,
+            None => match self
+                .voices
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.env.level().total_cmp(&b.env.level()))
+            {
+                Some((index, _)) => index,
+                None => return,
+            },
+        };
+        self.start(index, column, pitch, velocity);
+    }
+
+    /// Starts a note on a voice. A voice still sounding restarts from its level so it doesn't
+    /// click.
+    fn start(&mut self, index: usize, column: usize, pitch: u8, velocity: u8) {
+        let coefficients = Coefficients::new(
+            self.get(SynthParam::Cutoff),
+            self.get(SynthParam::Resonance),
+            self.sample_rate,
+        );
+        // Copies start at random phases so a stack doesn't start as one loud cycle, a single
+        // oscillator starts at 0 for a consistent attack.
+        let unison = self.unison();
+        let mut phases = [[0.0; MAX_UNISON]; 2];
+        if unison > 1 {
+            for phase in phases.iter_mut().flat_map(|phases| &mut phases[..unison]) {
+                *phase = self.random_phase();
+            }
+        }
+        let voice = &mut self.voices[index];
+        let level = voice.env.level();
+        if !voice.is_busy() {
+            for filter in &mut voice.filters {
+                filter.reset(coefficients);
+            }
+            voice.phases = phases;
+        }
+        voice.column = column;
+        voice.pitch = pitch;
+        voice.volume = db_to_gain(-60.0 * (1.0 - velocity as f32 / 127.0));
+        self.envelope.trigger(&mut voice.env, velocity, level);
+        self.filter_envelope
+            .trigger(&mut voice.filter_env, velocity, 0.0);
+        self.position_envelope
+            .trigger(&mut voice.position_env, velocity, 0.0);
+    }
+
+    fn release(&mut self, column: usize) {
+        for voice in &mut self.voices {
+            if voice.column == column && voice.is_held() {
+                voice.env.start_release();
+                voice.filter_env.start_release();
+                voice.position_env.start_release();
+            }
+        }
+    }
+
+    /// Follows a change of the held notes in mono mode. Moving between held notes is legato,
+    /// the envelopes carry on and only the pitch changes.
+    fn follow(&mut self, change: Change) {
+        match change {
+            Change::Play { pitch, velocity } => {
+                let voice = &mut self.voices[0];
+                if voice.is_held() {
+                    voice.pitch = pitch;
+                } else {
+                    self.start(0, mono::COLUMN, pitch, velocity);
+                }
+            }
+            Change::Release => self.release(mono::COLUMN),
+            Change::None => {}
+        }
+    }
+}
+
+impl Device for Synth {
+    fn render(&mut self, buffer: &mut [(f32, f32)]) {
+        for block in buffer.chunks_mut(CONTROL_BLOCK_SIZE) {
+            self.render_block(block);
+        }
+    }
+}
+
+impl Instrument for Synth {
+    fn note_on(&mut self, column: usize, pitch: u8, velocity: u8) {
+        match self.mono.as_mut() {
+            Some(held) => {
+                let change = held.press(column, pitch, velocity);
+                self.follow(change);
+            }
+            None => self.play_note(column, pitch, velocity),
+        }
+    }
+
+    fn note_off(&mut self, column: usize) {
+        match self.mono.as_mut() {
+            Some(held) => {
+                let change = held.release(column);
+                self.follow(change);
+            }
+            None => self.release(column),
+        }
+    }
+
+    fn prepare(&mut self, config: &EngineConfig) {
+        self.sample_rate = config.sample_rate as f32;
+        self.amp.prepare(self.sample_rate);
+        for voice in &mut self.voices {
+            voice.env.sample_rate = self.sample_rate;
+            voice.filter_env.sample_rate = self.sample_rate;
+            voice.position_env.sample_rate = self.sample_rate;
+        }
+    }
+
+    fn reset(&mut self) {
+        for voice in &mut self.voices {
+            voice.reset();
+        }
+        if let Some(held) = &mut self.mono {
+            held.clear();
+        }
+    }
+
+    fn set_quality(&mut self, quality: Quality) {
+        self.quality = quality;
+    }
+
+    fn active_voices(&self) -> usize {
+        self.voices.iter().filter(|v| v.is_busy()).count()
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        let has_wavetable = self.wavetable.is_some();
+        let mut params: Vec<(String, Param)> = SynthParam::ALL
+            .iter()
+            .filter(|param| has_wavetable || !param.is_wavetable())
+            .map(|param| {
+                let value = Arc::clone(&self.values[*param as usize]);
+                (String::from(param.name()), param.param(value))
+            })
+            .collect();
+        params.extend(self.envelope.params());
+        params.extend(
+            self.filter_envelope
+                .params()
+                .into_iter()
+                .map(|(name, param)| (format!("Filter{}", name), param)),
+        );
+        if has_wavetable {
+            params.extend(
+                self.position_envelope
+                    .params()
+                    .into_iter()
+                    .map(|(name, param)| (format!("Position{}", name), param)),
+            );
+        }
+        params
+    }
+}
+
+/// Bends the unison detune knob by `curve`, towards the response of the supersaw of the JP-8000
+/// which stays subtle over most of its travel and spreads out fast at the top.
+fn detune_curve(amount: f32, curve: f32) -> f32 {
+    amount * (1.0 - curve) + amount.powi(3) * curve
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    f32::powf(10.0, db / 20.0)
+}
@@ -0,0 +1,74 @@
+//! Figures about the engine for the status bar: how much of its time budget rendering takes,
+//! how many voices play and how often the output glitched. The audio thread writes them into
+//! atomics, the app reads them back whenever it redraws.
+
+use crate::engine::MAX_INSTRUMENTS;
+use atomic_float::AtomicF32;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[derive(Clone)]
+pub struct Telemetry {
+    /// Highest render time of a buffer since the app last read it, as a fraction of the time
+    /// the buffer plays for.
+    load: Arc<AtomicF32>,
+    /// Voices sounding on every instrument, as of the last buffer.
+    voices: Arc<Vec<AtomicUsize>>,
+    /// Buffers the audio device reports it didn't get in time.
+    xruns: Arc<AtomicUsize>,
+    /// Buffers which took longer to render than they play for, whether or not the device
+    /// noticed.
+    overloads: Arc<AtomicUsize>,
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Self {
+            load: Arc::new(AtomicF32::new(0.0)),
+            voices: Arc::new((0..MAX_INSTRUMENTS).map(|_| AtomicUsize::new(0)).collect()),
+            xruns: Arc::new(AtomicUsize::new(0)),
+            overloads: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl Telemetry {
+    /// Returns the highest load since the last call, 1 being a buffer which took as long to
+    /// render as it plays for.
+    pub fn take_load(&self) -> f32 {
+        self.load.swap(0.0, Ordering::Relaxed)
+    }
+
+    pub fn voices(&self, instrument: usize) -> usize {
+        self.voices[instrument].load(Ordering::Relaxed)
+    }
+
+    pub fn total_voices(&self) -> usize {
+        (0..MAX_INSTRUMENTS).map(|i| self.voices(i)).sum()
+    }
+
+    pub fn xruns(&self) -> usize {
+        self.xruns.load(Ordering::Relaxed)
+    }
+
+    pub fn overloads(&self) -> usize {
+        self.overloads.load(Ordering::Relaxed)
+    }
+
+    /// Records the time a buffer took to render, as a fraction of the time it plays for.
+    pub fn record_load(&self, load: f32) {
+        self.load.fetch_max(load, Ordering::Relaxed);
+        if load > 1.0 {
+            self.overloads.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_voices(&self, instrument: usize, voices: usize) {
+        self.voices[instrument].store(voices, Ordering::Relaxed);
+    }
+
+    /// Called by audio backends when the device reports an underrun.
+    pub fn count_xrun(&self) {
+        self.xruns.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,168 @@
+//! The clock of the song. The app, the engine and whatever syncs to them (JACK, a plugin host,
+//! OSC controllers) all start, stop and move playback through a `Transport` and read where it is
+//! from there, without knowing how the engine steps through lines.
+
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::ops::Range;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering},
+    Arc,
+};
+
+pub const BEATS_PER_BAR: u64 = 4;
+
+/// No seek is waiting for the engine.
+const NO_SEEK: u64 = u64::MAX;
+
+/// A position in bars, beats and lines of the beat, counted from 0.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BarsBeats {
+    pub bar: u64,
+    pub beat: u64,
+    pub line: u64,
+}
+
+impl BarsBeats {
+    /// Parses a position as shown, from 1: `bar`, `bar:beat` or `bar:beat.line`.
+    pub fn parse(text: &str) -> Result<Self> {
+        let invalid = || anyhow!("invalid position {}, expected bar[:beat[.line]]", text);
+        let (bar, rest) = match text.split_once(':') {
+            Some((bar, rest)) => (bar, Some(rest)),
+            None => (text, None),
+        };
+        let (beat, line) = match rest.map(|rest| rest.split_once('.').unwrap_or((rest, "1"))) {
+            Some((beat, line)) => (beat, line),
+            None => ("1", "1"),
+        };
+        let number = |s: &str| match s.parse::<u64>() {
+            Ok(n) if n > 0 => Ok(n - 1),
+            _ => Err(invalid()),
+        };
+        Ok(Self {
+            bar: number(bar)?,
+            beat: number(beat)?,
+            line: number(line)?,
+        })
+    }
+}
+
+impl fmt::Display for BarsBeats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.bar + 1, self.beat + 1)?;
+        if self.line > 0 {
+            write!(f, ".{}", self.line + 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Shared between threads, every clone controls the same transport.
+#[derive(Clone)]
+pub struct Transport {
+    playing: Arc<AtomicBool>,
+    /// Line playing, in lines since the start of the song.
+    tick: Arc<AtomicU64>,
+    /// Line playback moves to, until the engine gets to it.
+    seek: Arc<AtomicU64>,
+    /// Start and end line of the loop, packed in one value so they change together.
+    loop_region: Arc<AtomicU64>,
+    lines_per_beat: Arc<AtomicU16>,
+}
+
+impl Transport {
+    /// A stopped transport at the start of the song, counting beats in `lines_per_beat`.
+    pub fn new(lines_per_beat: Arc<AtomicU16>) -> Self {
+        Self {
+            playing: Arc::new(AtomicBool::new(false)),
+            tick: Arc::new(AtomicU64::new(0)),
+            seek: Arc::new(AtomicU64::new(NO_SEEK)),
+            loop_region: Arc::new(AtomicU64::new(0)),
+            lines_per_beat,
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing.load(Ordering::Relaxed)
+    }
+
+    pub fn play(&self) {
+        self.playing.store(true, Ordering::Relaxed);
+    }
+
+    /// Stops playing where it is, playing again carries on from there.
+    pub fn pause(&self) {
+        self.playing.store(false, Ordering::Relaxed);
+    }
+
+    /// Stops playing and goes back to the start of the song.
+    pub fn stop(&self) {
+        self.pause();
+        self.seek(0);
+    }
+
+    /// Moves playback to a line, playing or not. Notes still sounding are released.
+    pub fn seek(&self, tick: u64) {
+        self.seek.store(tick, Ordering::Relaxed);
+    }
+
+    /// The line a seek asked for since the last call, for whoever keeps time to move to.
+    pub fn take_seek(&self) -> Option<u64> {
+        match self.seek.swap(NO_SEEK, Ordering::Relaxed) {
+            NO_SEEK => None,
+            tick => Some(tick),
+        }
+    }
+
+    /// Line playing, or where playback starts, in lines since the start of the song.
+    pub fn tick(&self) -> u64 {
+        self.tick.load(Ordering::Relaxed)
+    }
+
+    /// Follows the engine, which is the only one to call this.
+    pub fn set_tick(&self, tick: u64) {
+        self.tick.store(tick, Ordering::Relaxed);
+    }
+
+    pub fn bars_beats(&self) -> BarsBeats {
+        self.to_bars_beats(self.tick())
+    }
+
+    pub fn to_bars_beats(&self, tick: u64) -> BarsBeats {
+        let lines_per_beat = self.lines_per_beat();
+        let beats = tick / lines_per_beat;
+        BarsBeats {
+            bar: beats / BEATS_PER_BAR,
+            beat: beats % BEATS_PER_BAR,
+            line: tick % lines_per_beat,
+        }
+    }
+
+    pub fn to_tick(&self, position: BarsBeats) -> u64 {
+        let beats = position.bar * BEATS_PER_BAR + position.beat;
+        beats * self.lines_per_beat() + position.line
+    }
+
+    /// Lines the loop goes over, if there is one.
+    pub fn loop_region(&self) -> Option<Range<u64>> {
+        let packed = self.loop_region.load(Ordering::Relaxed);
+        let region = (packed >> 32)..(packed & u32::MAX as u64);
+        Some(region).filter(|region| !region.is_empty())
+    }
+
+    /// Sets the lines to loop over, or clears the loop. Lines past `u32::MAX` are out of reach.
+    pub fn set_loop_region(&self, region: Option<Range<u64>>) {
+        let packed = match region {
+            Some(region) if !region.is_empty() => {
+                let clamp = |tick: u64| u64::min(tick, u32::MAX as u64);
+                clamp(region.start) << 32 | clamp(region.end)
+            }
+            _ => 0,
+        };
+        self.loop_region.store(packed, Ordering::Relaxed);
+    }
+
+    fn lines_per_beat(&self) -> u64 {
+        u64::max(self.lines_per_beat.load(Ordering::Relaxed) as u64, 1)
+    }
+}
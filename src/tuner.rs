@@ -0,0 +1,191 @@
+use crate::sampler::Sound;
+use ringbuf::{Consumer, Producer, RingBuffer};
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// Lowest pitch detected, in Hz, a bit below the low E of a bass.
+const MIN_FREQUENCY: f32 = 35.0;
+/// Samples analyzed at once, long enough for two periods of `MIN_FREQUENCY` at 48kHz.
+const WINDOW: usize = 1 << 12;
+/// Samples buffered between the engine and the app.
+const BUFFER_SAMPLES: usize = 1 << 15;
+/// Dips of the difference function below this are taken as the period.
+const THRESHOLD: f32 = 0.15;
+/// Readings less confident than this are ignored.
+const MIN_CONFIDENCE: f32 = 0.5;
+/// Part of a sound analyzed to find its pitch, in seconds from its start.
+const SOUND_SECONDS: usize = 4;
+
+/// Pitch of a signal, as the nearest note and how far off it is.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Reading {
+    pub note: u8,
+    /// From -50 to 50.
+    pub cents: f32,
+    /// From 0 to 1, how periodic the signal is.
+    pub confidence: f32,
+}
+
+impl Reading {
+    fn from_frequency(frequency: f32, confidence: f32) -> Option<Self> {
+        let pitch = 69.0 + 12.0 * f32::log2(frequency / 440.0);
+        let note = pitch.round();
+        if !(0.0..128.0).contains(&note) {
+            return None;
+        }
+        Some(Self {
+            note: note as u8,
+            cents: (pitch - note) * 100.0,
+            confidence,
+        })
+    }
+}
+
+impl fmt::Display for Reading {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = [
+            "C-", "C#", "D-", "D#", "E-", "F-", "F#", "G-", "G#", "A-", "A#", "B-",
+        ];
+        // Same octave numbers as the editor
+        let note = self.note as usize;
+        write!(
+            f,
+            "{}{} {:+.0}c {:.0}%",
+            names[note % 12],
+            note / 12,
+            self.cents,
+            self.confidence * 100.0
+        )
+    }
+}
+
+/// Finds the pitch of a mono signal with the YIN algorithm. Returns nothing when the signal
+/// isn't periodic enough, e.g. noise or silence.
+pub fn detect(samples: &[f32], sample_rate: f32) -> Option<Reading> {
+    let max_lag = usize::min((sample_rate / MIN_FREQUENCY) as usize, samples.len() / 2);
+    if max_lag < 3 {
+        return None;
+    }
+    let len = samples.len() - max_lag;
+
+    // Cumulative mean normalized difference
+    let mut diff = vec![1.0; max_lag];
+    let mut sum = 0.0;
+    for lag in 1..max_lag {
+        let d: f32 = (0..len)
+            .map(|i| {
+                let delta = samples[i] - samples[i + lag];
+                delta * delta
+            })
+            .sum();
+        sum += d;
+        diff[lag] = if sum > 0.0 { d * lag as f32 / sum } else { 1.0 };
+    }
+
+    // First dip below the threshold, or the deepest one
+    let mut lag = (2..max_lag)
+        .find(|&lag| diff[lag] < THRESHOLD)
+        .or_else(|| (2..max_lag).min_by(|a, b| diff[*a].total_cmp(&diff[*b])))?;
+    while lag + 1 < max_lag && diff[lag + 1] < diff[lag] {
+        lag += 1;
+    }
+    let confidence = 1.0 - diff[lag].clamp(0.0, 1.0);
+    if confidence < MIN_CONFIDENCE {
+        return None;
+    }
+
+    // Parabolic interpolation between lags
+    let offset = match (diff.get(lag - 1), diff.get(lag + 1)) {
+        (Some(a), Some(c)) => {
+            let b = diff[lag];
+            let curve = a + c - 2.0 * b;
+            if curve > 0.0 {
+                (a - c) / (2.0 * curve)
+            } else {
+                0.0
+            }
+        }
+        _ => 0.0,
+    };
+    Reading::from_frequency(sample_rate / (lag as f32 + offset), confidence)
+}
+
+/// Finds the pitch of the start of a sound, from the most confident of its windows.
+pub fn detect_sound(sound: &Sound) -> Option<Reading> {
+    let sample_rate = sound.sample_rate() as f32;
+    let samples: Vec<f32> = sound
+        .frames()
+        .take(SOUND_SECONDS * sound.sample_rate() as usize)
+        .map(|(left, right)| (left + right) / 2.0)
+        .collect();
+    let window = usize::min(WINDOW, samples.len());
+    samples
+        .chunks(window.max(1))
+        .filter_map(|window| detect(window, sample_rate))
+        .max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+}
+
+/// Engine side of the tuner, hands the signal of a hardware input to the app.
+pub struct TunerTap {
+    pub input: usize,
+    prod: Producer<f32>,
+    sample_rate: Arc<AtomicU32>,
+}
+
+impl TunerTap {
+    /// Called by the engine when the tuner starts.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate
+            .store(sample_rate as u32, Ordering::Release);
+    }
+
+    /// Mixes the input down to mono and pushes it, dropping what doesn't fit.
+    pub fn push(&mut self, frames: &[(f32, f32)]) {
+        for frame in frames {
+            if self.prod.push((frame.0 + frame.1) / 2.0).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// App side of the tuner, analyzes the input as it comes in.
+pub struct Tuner {
+    cons: Consumer<f32>,
+    window: Vec<f32>,
+    sample_rate: Arc<AtomicU32>,
+}
+
+impl Tuner {
+    pub fn new(input: usize) -> (Self, TunerTap) {
+        let (prod, cons) = RingBuffer::new(BUFFER_SAMPLES).split();
+        let sample_rate = Arc::new(AtomicU32::new(0));
+        let tap = TunerTap {
+            input,
+            prod,
+            sample_rate: Arc::clone(&sample_rate),
+        };
+        let tuner = Self {
+            cons,
+            window: Vec::with_capacity(WINDOW),
+            sample_rate,
+        };
+        (tuner, tap)
+    }
+
+    /// Reads the input received since the last call. Returns the reading of the last window
+    /// filled, which is `Some(None)` when it had no clear pitch.
+    pub fn update(&mut self) -> Option<Option<Reading>> {
+        let sample_rate = self.sample_rate.load(Ordering::Acquire) as f32;
+        let mut reading = None;
+        while let Some(sample) = self.cons.pop() {
+            self.window.push(sample);
+            if self.window.len() == WINDOW {
+                reading = Some(detect(&self.window, sample_rate));
+                self.window.clear();
+            }
+        }
+        reading
+    }
+}
@@ -1,15 +1,24 @@
 pub mod editor;
 
+use crate::input::Focus;
 pub use crate::input::{CommandState, Input, InputQueue};
+use crate::library::Label;
+use crate::mixer::{bus_name, return_channel, Source, MASTER_CHANNEL, NUM_BUSES};
+use crate::transport::BarsBeats;
+use crate::tuner::Reading;
 pub use crate::ui::editor::{Editor, EditorState};
-use crate::{app::App, engine::EngineParam};
+use crate::{
+    app::App,
+    engine::{EngineParam, MAX_INSTRUMENTS},
+};
+use std::sync::atomic::{AtomicBool, Ordering};
 use tui::{
     backend::Backend,
     buffer::Buffer,
     layout::Rect,
     layout::{Constraint, Direction, Layout},
     style::{Color, Modifier, Style},
-    text::Span,
+    text::{Span, Spans},
     widgets::{Block, Borders, List, ListItem, ListState, Paragraph, StatefulWidget, Widget},
     Frame,
 };
@@ -60,23 +69,98 @@ pub fn draw<B: Backend>(f: &mut Frame<B>, app: &mut App) {
 fn render_sidebar<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
     let sections = Layout::default()
         .direction(Direction::Vertical)
-        .constraints([Constraint::Ratio(1, 3), Constraint::Ratio(2, 3)].as_ref())
+        .constraints(
+            [
+                Constraint::Ratio(1, 3),
+                Constraint::Ratio(1, 3),
+                Constraint::Ratio(1, 3),
+            ]
+            .as_ref(),
+        )
         .split(area);
 
-    // Instruments
-    let instruments: Vec<ListItem> = app
+    // Instruments, followed by the returns of the aux buses and the master
+    let channel_row = |i: usize, name: String, label: &str| {
+        let channel = &app.engine_params.mixer.channels[i];
+        let flag = |flag: &AtomicBool, c| if flag.load(Ordering::Relaxed) { c } else { '-' };
+        // Sends are shown by bus, in lowercase when pre-fader
+        let sends: String = match i < MAX_INSTRUMENTS {
+            true => channel
+                .sends
+                .iter()
+                .enumerate()
+                .map(|(bus, send)| match send.is_on() {
+                    true if send.pre_fader.load(Ordering::Relaxed) => {
+                        bus_name(bus).to_ascii_lowercase()
+                    }
+                    true => bus_name(bus),
+                    false => '-',
+                })
+                .collect(),
+            false => " ".repeat(NUM_BUSES),
+        };
+        // Bypassed effects are shown in parentheses
+        let effects: String = app.effects[i]
+            .iter()
+            .map(|effect| match effect.bypass.load(Ordering::Relaxed) {
+                true => format!(" > ({})", effect.kind),
+                false => format!(" > {}", effect.kind),
+            })
+            .collect();
+        // Latency of the inserts, when they delay the channel
+        let sample_rate = app.engine_params.sample_rate.load(Ordering::Relaxed) as f32;
+        let latency = match channel.latency() {
+            0 => String::new(),
+            frames => format!(" {:.1}ms", frames as f32 * 1000.0 / sample_rate),
+        };
+        ListItem::new(Span::raw(format!(
+            " {:>2} {}{} {} {} {}{}{}",
+            name,
+            flag(&channel.mute, 'M'),
+            flag(&channel.solo, 'S'),
+            sends,
+            meter(app.meters[i]),
+            label,
+            effects,
+            latency,
+        )))
+    };
+    let mut instruments: Vec<ListItem> = app
         .instruments
         .iter()
         .enumerate()
         .map(|(i, track)| {
-            ListItem::new(Span::raw(format!(
-                " {:0width$} {}",
-                i,
-                track.as_ref().map_or("", |v| v.sample_path.as_ref()),
-                width = 2
-            )))
+            // Channels which aren't fed by their instrument show their source instead
+            let source = app.engine_params.mixer.channels[i].source();
+            let label = match source {
+                Source::Instrument => track
+                    .as_ref()
+                    .map_or(String::new(), |v| v.label().to_string()),
+                source => source.name(),
+            };
+            channel_row(i, format!("{:02}", i), &label)
         })
         .collect();
+    for bus in 0..NUM_BUSES {
+        let name = bus_name(bus).to_string();
+        instruments.push(channel_row(return_channel(bus), name, "return"));
+    }
+    // Inter-sample overs are held until playback restarts
+    let monitor = &app.engine_params.monitor;
+    let mixer = &app.engine_params.mixer;
+    let mut master = String::from("master");
+    let flags = [
+        (mixer.is_over(), " OVER"),
+        (!mixer.dc_block.load(Ordering::Relaxed), " NODC"),
+        (mixer.clip.load(Ordering::Relaxed), " CLIP"),
+        (monitor.dim.load(Ordering::Relaxed), " DIM"),
+        (monitor.mono.load(Ordering::Relaxed), " MONO"),
+        (monitor.reference.load(Ordering::Relaxed), " REF"),
+    ];
+    for (_, flag) in flags.iter().filter(|(on, _)| *on) {
+        master.push_str(flag);
+    }
+    instruments.push(channel_row(MASTER_CHANNEL, "MS".into(), &master));
 
     let instruments = List::new(instruments)
         .block(Block::default())
@@ -84,18 +168,32 @@ fn render_sidebar<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
 
     f.render_stateful_widget(instruments, sections[0], &mut app.instrument_list);
 
+    render_params(f, app, sections[1]);
+
     // File Browser
     let file_sections = Layout::default()
         .direction(Direction::Vertical)
         .constraints(
             [
                 Constraint::Length(1),
-                Constraint::Length(sections[1].height - 1),
+                Constraint::Length(sections[2].height - 1),
             ]
             .as_ref(),
         )
-        .split(sections[1]);
-    let current_dir = format!(" {}", app.file_browser.current_dir());
+        .split(sections[2]);
+    let mut current_dir = format!(" {}", app.file_browser.current_dir());
+    if app.stretch_preview {
+        let key = app
+            .editor
+            .harmony
+            .key
+            .map_or(String::new(), |key| format!(" {}", key.name()));
+        current_dir.push_str(&format!(
+            " [stretch {}{}]",
+            app.engine_params.bpm.load(Ordering::Relaxed),
+            key
+        ));
+    }
     let header = Paragraph::new(current_dir).style(
         Style::default()
             .add_modifier(Modifier::REVERSED)
@@ -105,7 +203,22 @@ fn render_sidebar<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
     let files: Vec<ListItem> = app
         .file_browser
         .iter()
-        .map(|name| ListItem::new(Span::raw(format!(" {}", name))))
+        .map(|(name, path)| {
+            let entry = app.library.entry(path);
+            let style = match entry.label {
+                Some(label) => Style::default().fg(label_color(label)),
+                None => Style::default(),
+            };
+            let mut spans = vec![Span::styled(format!(" {}", name), style)];
+            if entry.rating > 0 {
+                spans.push(Span::raw(format!(" {}", "*".repeat(entry.rating as usize))));
+            }
+            if !entry.tags.is_empty() {
+                let tags = format!(" [{}]", entry.tags.join(" "));
+                spans.push(Span::styled(tags, Style::default().fg(Color::DarkGray)));
+            }
+            ListItem::new(Spans::from(spans))
+        })
         .collect();
     let files = List::new(files)
         .block(Block::default())
@@ -113,27 +226,216 @@ fn render_sidebar<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
     f.render_stateful_widget(files, file_sections[1], &mut app.files);
 }
 
+/// Params of the selected instrument with their current values, which follow modulation and
+/// automation as they play.
+fn render_params<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
+    let sections = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(1), Constraint::Length(area.height - 1)].as_ref())
+        .split(area);
+    let instrument = app.instruments[app.selected_track].as_ref();
+    let title = match instrument {
+        Some(settings) => format!(" {:02} {}", app.selected_track, settings.kind),
+        None => format!(" {:02} empty", app.selected_track),
+    };
+    let header = Paragraph::new(title).style(
+        Style::default()
+            .add_modifier(Modifier::REVERSED)
+            .add_modifier(Modifier::BOLD),
+    );
+    f.render_widget(header, sections[0]);
+
+    let params: Vec<ListItem> = instrument
+        .map_or(&[][..], |settings| &settings.params)
+        .iter()
+        .map(|(name, param)| ListItem::new(Span::raw(format!(" {:<14}{}", name, param))))
+        .collect();
+    // The selection outlives the instrument it was made on
+    match app.params.selected() {
+        Some(index) if index >= params.len() => app.params.select(params.len().checked_sub(1)),
+        _ => {}
+    }
+    let style = match app.focus {
+        Focus::Params => Style::default().fg(Color::White).bg(Color::Green),
+        _ => Style::default(),
+    };
+    let params = List::new(params)
+        .block(Block::default())
+        .highlight_style(style);
+    f.render_stateful_widget(params, sections[1], &mut app.params);
+}
+
+fn label_color(label: Label) -> Color {
+    match label {
+        Label::Red => Color::Red,
+        Label::Orange => Color::Indexed(208),
+        Label::Yellow => Color::Yellow,
+        Label::Green => Color::Green,
+        Label::Blue => Color::Blue,
+        Label::Purple => Color::Magenta,
+    }
+}
+
+/// Draws a level between -48dB and 0dB as a bar.
+fn meter(level: f32) -> String {
+    const WIDTH: usize = 8;
+    const FLOOR: f32 = -48.0;
+    let db = 20.0 * level.max(1e-6).log10();
+    let len = ((1.0 - db / FLOOR).clamp(0.0, 1.0) * WIDTH as f32).round() as usize;
+    format!("{:<width$}", "|".repeat(len), width = WIDTH)
+}
+
 struct StatusLine {
+    name: String,
+    is_playing: bool,
+    position: BarsBeats,
+    /// First and last bar of the loop.
+    loop_bars: Option<(u64, u64)>,
     bpm: u16,
     lines_per_beat: u16,
     octave: u16,
+    edit_step: usize,
+    /// Frames dropped so far when capturing.
+    capture: Option<usize>,
+    /// Current pattern and number of patterns.
+    pattern: (usize, usize),
+    performing: bool,
+    /// Port the OSC server listens on.
+    osc: Option<u16>,
+    /// Whether notes played live are recorded, and whether a take is going.
+    recording: Option<bool>,
+    /// Velocity of the computer keyboard, while it plays the selected instrument.
+    keyboard: Option<u8>,
+    /// Whether the tuner is showing, and what it found.
+    tuning: Option<Option<Reading>>,
+    lost_device: Option<String>,
+    /// Render load of the engine, voices playing, underruns reported by the device and
+    /// buffers rendered too slowly.
+    load: f32,
+    voices: usize,
+    xruns: usize,
+    overloads: usize,
+    /// Input being recorded into a sound, seconds recorded and frames dropped, or nothing yet
+    /// while waiting for the punch-in.
+    sampling: Option<(usize, Option<(f64, usize)>)>,
 }
 
 impl StatusLine {
     fn new(app: &App) -> Self {
         Self {
+            name: app
+                .project_path
+                .as_ref()
+                .and_then(|path| path.file_name())
+                .unwrap_or("*Untitled*")
+                .to_string(),
+            is_playing: app.engine_params.transport.is_playing(),
+            position: app.engine_params.transport.bars_beats(),
+            loop_bars: app.engine_params.transport.loop_region().map(|region| {
+                let transport = &app.engine_params.transport;
+                let bar = |tick| transport.to_bars_beats(tick).bar + 1;
+                (bar(region.start), bar(region.end - 1))
+            }),
             bpm: app.engine_params.get(EngineParam::Bpm),
             lines_per_beat: app.engine_params.get(EngineParam::LinesPerBeat),
             octave: app.engine_params.get(EngineParam::Octave),
+            edit_step: app.edit_step,
+            capture: app.capture.as_ref().map(|writer| writer.dropped()),
+            pattern: (app.editor.edit_index(), app.editor.patterns().len()),
+            performing: app.performance.is_some(),
+            osc: app.osc.as_ref().map(|server| server.port()),
+            recording: app.record_arm.map(|_| app.live_take.is_some()),
+            keyboard: match app.focus {
+                Focus::Keyboard => Some(app.keyboard.velocity),
+                _ => None,
+            },
+            tuning: match app.tuning {
+                Some(reading) => Some(Some(reading)),
+                None if app.is_tuning() => Some(None),
+                None => None,
+            },
+            lost_device: app.lost_device.clone(),
+            load: app.load,
+            voices: app.engine_params.telemetry.total_voices(),
+            xruns: app.engine_params.telemetry.xruns(),
+            overloads: app.engine_params.telemetry.overloads(),
+            sampling: app.sampling.as_ref().map(|sampling| {
+                let progress = (sampling.is_recording() || sampling.is_done())
+                    .then(|| (sampling.seconds(), sampling.dropped()));
+                (sampling.input, progress)
+            }),
         }
     }
 }
 impl Widget for &StatusLine {
     fn render(self, area: Rect, buf: &mut Buffer) {
-        let s = format!(
-            " *Untitled*    BPM {}    LPB {}    Oct {}",
-            self.bpm, self.lines_per_beat, self.octave
+        let mut s = format!(
+            " {}    {} {}    BPM {}    LPB {}    Oct {}    Step {}",
+            self.name,
+            if self.is_playing { "PLAY" } else { "STOP" },
+            self.position,
+            self.bpm,
+            self.lines_per_beat,
+            self.octave,
+            self.edit_step
         );
+        match self.capture {
+            Some(0) => s.push_str("    REC"),
+            Some(dropped) => s.push_str(&format!("    REC ({} frames dropped)", dropped)),
+            None => {}
+        }
+        if self.pattern.1 > 1 {
+            s.push_str(&format!("    Pat {}/{}", self.pattern.0, self.pattern.1));
+        }
+        if let Some((start, end)) = self.loop_bars {
+            s.push_str(&format!("    LOOP {}-{}", start, end));
+        }
+        if self.performing {
+            s.push_str("    PERF");
+        }
+        if let Some(port) = self.osc {
+            s.push_str(&format!("    OSC :{}", port));
+        }
+        match self.recording {
+            Some(true) => s.push_str("    TAKE"),
+            Some(false) => s.push_str("    ARM"),
+            None => {}
+        }
+        if let Some(velocity) = self.keyboard {
+            s.push_str(&format!("    KEYS vel {}", velocity));
+        }
+        match self.tuning {
+            Some(Some(reading)) => s.push_str(&format!("    Tuner {}", reading)),
+            Some(None) => s.push_str("    Tuner --"),
+            None => {}
+        }
+        match self.sampling {
+            Some((input, None)) => s.push_str(&format!("    SMP in{} armed", input + 1)),
+            Some((input, Some((seconds, 0)))) => {
+                s.push_str(&format!("    SMP in{} {:.1}s", input + 1, seconds))
+            }
+            Some((input, Some((seconds, dropped)))) => s.push_str(&format!(
+                "    SMP in{} {:.1}s ({} frames dropped)",
+                input + 1,
+                seconds,
+                dropped
+            )),
+            None => {}
+        }
+        if let Some(name) = &self.lost_device {
+            s.push_str(&format!("    NO AUDIO ({} lost)", name));
+        }
+        s.push_str(&format!(
+            "    CPU {:.0}%    Voices {}",
+            self.load * 100.0,
+            self.voices
+        ));
+        if self.xruns > 0 {
+            s.push_str(&format!("    XRUN {}", self.xruns));
+        }
+        if self.overloads > 0 {
+            s.push_str(&format!("    LATE {}", self.overloads));
+        }
 
         let offset = s.len();
         buf.set_string(
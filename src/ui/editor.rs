@@ -1,4 +1,4 @@
-use crate::pattern::{Position, TrackView};
+use crate::pattern::{DrumLane, Position, TrackView, NOTE_CUT, NOTE_OFF};
 use crate::{app::App, engine::EngineParam};
 
 use tui::{
@@ -38,9 +38,17 @@ impl<'a> Editor<'a> {
     fn render_track(&self, area: Rect, buf: &mut Buffer, track: &'a TrackView, index: usize) {
         let width = COLUMN_WIDTH;
 
-        // Draw track header
-        let header = format!(" {} ", index);
-        let padding = str::repeat(" ", width - header.len());
+        // Draw track header, with the length of the track when it's not the one of the pattern
+        // and the lines it shifts by at every repeat
+        let mut header = format!(" {}", index);
+        if track.length != track.steps.len() {
+            header += &format!("/{}", track.length);
+        }
+        if track.shift > 0 {
+            header += &format!(" >{}", track.shift);
+        }
+        header += " ";
+        let padding = str::repeat(" ", width.saturating_sub(header.len()));
         let header = format!("{}{}", header, padding);
         buf.set_string(
             area.left(),
@@ -51,30 +59,49 @@ impl<'a> Editor<'a> {
                 .add_modifier(Modifier::BOLD),
         );
 
-        // Draw notes
+        // Draw notes, the ones past the end of the track are dimmed as they're not played
+        let pattern = self.app.editor.current_pattern();
+        let playing = pattern.played_line(index, self.app.current_tick as u64);
         let mut y = area.top() + 1;
         for (line, note) in track.steps.iter().enumerate() {
-            let base_style = self.get_base_style(line);
+            let mut base_style = self.get_base_style(line, playing);
+            if line >= track.length {
+                base_style = base_style.fg(Color::DarkGray);
+            }
             let column = index * 2;
 
-            let pitch_style = self.get_input_style(line, column + 0);
+            let pitch_style = self.get_input_style(line, column + 0, base_style);
             let pitch = match note.pitch {
+                Some(NOTE_OFF) => "OFF",
+                Some(NOTE_CUT) => "CUT",
                 Some(pitch) => &NOTE_NAMES[pitch as usize],
                 None => "---",
             };
 
-            let snd_style = self.get_input_style(line, column + 1);
+            let snd_style = self.get_input_style(line, column + 1, base_style);
             let snd = match note.sound {
                 Some(v) => format!("{:0width$}", v, width = 2),
                 None => String::from("--"),
             };
 
+            // The chord takes the place of the trailing space
+            let chord = note
+                .chord
+                .map_or(String::from(" "), |c| c.symbol().to_string());
+
+            // The offset takes the place of the leading space
+            let offset = match note.offset {
+                0 => " ",
+                offset if offset < 0 => "<",
+                _ => ">",
+            };
+
             let spans = Spans::from(vec![
-                Span::styled(" ", base_style),
+                Span::styled(offset, base_style),
                 Span::styled(pitch, pitch_style),
                 Span::styled(" ", base_style),
                 Span::styled(snd, snd_style),
-                Span::styled(" ", base_style),
+                Span::styled(chord, base_style),
             ]);
 
             buf.set_spans(area.left(), y, &spans, area.width);
@@ -82,16 +109,49 @@ impl<'a> Editor<'a> {
         }
     }
 
-    fn get_input_style(&self, line: usize, col: usize) -> Style {
+    /// Draws a lane of the selected track, with a mark on every line the pad is hit.
+    fn render_lane(&self, area: Rect, buf: &mut Buffer, lane: &DrumLane) {
+        let header = match lane.pad {
+            Some(pad) => format!(" {:02} ", pad),
+            None => String::from(" -- "),
+        };
+        buf.set_string(
+            area.left(),
+            area.top(),
+            &header,
+            Style::default()
+                .add_modifier(Modifier::REVERSED)
+                .add_modifier(Modifier::BOLD),
+        );
+
+        let pattern = self.app.editor.current_pattern();
+        let track = self.app.selected_track;
+        let playing = pattern.played_line(track, self.app.current_tick as u64);
+        for (y, (line, hit)) in (area.top() + 1..).zip(lane.hits.iter().enumerate()) {
+            let style = if self.cursor.line == line {
+                Style::default().bg(Color::Green).fg(Color::Black)
+            } else {
+                self.get_base_style(line, playing)
+            };
+            let mark = match hit {
+                Some(_) => "  x ",
+                None => "  . ",
+            };
+            buf.set_string(area.left(), y, mark, style);
+        }
+    }
+
+    fn get_input_style(&self, line: usize, col: usize, base_style: Style) -> Style {
         if self.cursor.line == line && self.cursor.column == col {
             Style::default().bg(Color::Green).fg(Color::Black)
         } else {
-            self.get_base_style(line)
+            base_style
         }
     }
 
-    fn get_base_style(&self, line: usize) -> Style {
-        if line == self.app.current_line {
+    /// Style of a line of a track, `playing` being the line the track plays.
+    fn get_base_style(&self, line: usize, playing: usize) -> Style {
+        if line == playing {
             Style::default().bg(Color::Blue)
         } else if line % self.lines_per_beat == 0 {
             Style::default().bg(Color::DarkGray)
@@ -152,10 +212,29 @@ impl<'a> StatefulWidget for &Editor<'a> {
             block.render(area, buf);
             self.render_track(inner, buf, &track, i);
         }
+
+        if self.app.drum_lanes {
+            let pattern = self.app.editor.current_pattern();
+            let lanes = pattern.drum_lanes(self.app.selected_track);
+            for lane in &lanes.lanes {
+                let area = Rect {
+                    x,
+                    y: area.y,
+                    width: LANE_WIDTH as u16,
+                    height: (num_lines + 1) as u16,
+                };
+                if area.right() > buf.area().right() {
+                    break;
+                }
+                x += area.width;
+                self.render_lane(area, buf, lane);
+            }
+        }
     }
 }
 
 const COLUMN_WIDTH: usize = " C#4 05 ".len();
+const LANE_WIDTH: usize = " 05 ".len();
 
 lazy_static! {
     static ref NOTE_NAMES: Vec<String> = {
@@ -0,0 +1,421 @@
+use crate::euclid::Euclid;
+use crate::id::{PatternId, TrackId};
+use crate::json::Value;
+use crate::param::ParamKey;
+use crate::pattern::{LengthPolicy, Pattern, Section, SectionOp, Step, Transform};
+use anyhow::Result;
+use camino::Utf8Path;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const MAX_HISTORY: usize = 1000;
+const MAX_LOG: usize = 100_000;
+
+/// A reversible change to the song. Edits refer to ids instead of positions so they stay valid
+/// when tracks are moved around.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Edit {
+    SetStep {
+        pattern: PatternId,
+        track: TrackId,
+        line: usize,
+        before: Step,
+        after: Step,
+    },
+    MoveTrack {
+        from: usize,
+        to: usize,
+    },
+    SetParam {
+        key: ParamKey,
+        before: f32,
+        after: f32,
+    },
+    /// A section of a pattern was rearranged, the pattern as it was is kept to revert it.
+    Rearrange {
+        pattern: PatternId,
+        op: SectionOp,
+        before: Box<Pattern>,
+    },
+    /// The length of a pattern changed, the pattern as it was is kept to revert it.
+    Resize {
+        pattern: PatternId,
+        num_lines: usize,
+        policy: LengthPolicy,
+        before: Box<Pattern>,
+    },
+    /// A track was given its own length, or made to follow the length of the pattern.
+    TrackLength {
+        pattern: PatternId,
+        track: TrackId,
+        before: Option<usize>,
+        after: Option<usize>,
+    },
+    /// A section of a track was transformed, the pattern as it was is kept to revert it.
+    Transform {
+        pattern: PatternId,
+        track: TrackId,
+        section: Section,
+        transform: Transform,
+        before: Box<Pattern>,
+    },
+    /// A track was filled with a Euclidean rhythm of a step, the pattern as it was is kept to
+    /// revert it.
+    Euclid {
+        pattern: PatternId,
+        track: TrackId,
+        euclid: Euclid,
+        step: Step,
+        before: Box<Pattern>,
+    },
+    /// Notes played live were recorded into a track, both versions of the pattern are kept.
+    Record {
+        pattern: PatternId,
+        track: TrackId,
+        before: Box<Pattern>,
+        after: Box<Pattern>,
+    },
+}
+
+impl Edit {
+    fn to_json(&self) -> Value {
+        let step = |step: &Step| {
+            let field = |v: Option<u8>| v.map_or(Value::Null, |v| (v as usize).into());
+            let mut values = vec![field(step.pitch), field(step.sound)];
+            let chord = step.chord.map(|chord| chord.name());
+            if chord.is_some() || step.offset != 0 {
+                values.push(chord.as_deref().map_or(Value::Null, Value::from));
+            }
+            if step.offset != 0 {
+                values.push((step.offset as f64).into());
+            }
+            Value::Array(values)
+        };
+        match self {
+            Edit::SetStep {
+                pattern,
+                track,
+                line,
+                before,
+                after,
+            } => Value::Object(vec![
+                ("type".into(), "set_step".into()),
+                ("pattern".into(), (pattern.0 as usize).into()),
+                ("track".into(), (track.0 as usize).into()),
+                ("line".into(), (*line).into()),
+                ("before".into(), step(before)),
+                ("after".into(), step(after)),
+            ]),
+            Edit::MoveTrack { from, to } => Value::Object(vec![
+                ("type".into(), "move_track".into()),
+                ("from".into(), (*from).into()),
+                ("to".into(), (*to).into()),
+            ]),
+            Edit::SetParam { key, before, after } => Value::Object(vec![
+                ("type".into(), "set_param".into()),
+                ("instrument".into(), (key.instrument.0 as usize).into()),
+                ("name".into(), key.name.as_str().into()),
+                ("before".into(), (*before as f64).into()),
+                ("after".into(), (*after as f64).into()),
+            ]),
+            Edit::Rearrange { pattern, op, .. } => Value::Object(vec![
+                ("type".into(), "rearrange".into()),
+                ("pattern".into(), (pattern.0 as usize).into()),
+                ("op".into(), op.to_string().as_str().into()),
+            ]),
+            Edit::Resize {
+                pattern,
+                num_lines,
+                policy,
+                before,
+            } => Value::Object(vec![
+                ("type".into(), "resize".into()),
+                ("pattern".into(), (pattern.0 as usize).into()),
+                ("before".into(), before.num_lines.into()),
+                ("after".into(), (*num_lines).into()),
+                ("policy".into(), policy.name().into()),
+            ]),
+            Edit::TrackLength {
+                pattern,
+                track,
+                before,
+                after,
+            } => {
+                let length = |length: &Option<usize>| length.map_or(Value::Null, Value::from);
+                Value::Object(vec![
+                    ("type".into(), "track_length".into()),
+                    ("pattern".into(), (pattern.0 as usize).into()),
+                    ("track".into(), (track.0 as usize).into()),
+                    ("before".into(), length(before)),
+                    ("after".into(), length(after)),
+                ])
+            }
+            Edit::Transform {
+                pattern,
+                track,
+                section,
+                transform,
+                ..
+            } => Value::Object(vec![
+                ("type".into(), "transform".into()),
+                ("pattern".into(), (pattern.0 as usize).into()),
+                ("track".into(), (track.0 as usize).into()),
+                ("start".into(), section.start.into()),
+                ("end".into(), section.end.into()),
+                ("transform".into(), transform.to_string().as_str().into()),
+            ]),
+            Edit::Euclid {
+                pattern,
+                track,
+                euclid,
+                step: hit,
+                ..
+            } => Value::Object(vec![
+                ("type".into(), "euclid".into()),
+                ("pattern".into(), (pattern.0 as usize).into()),
+                ("track".into(), (track.0 as usize).into()),
+                ("pulses".into(), euclid.pulses.into()),
+                ("steps".into(), euclid.steps.into()),
+                ("rotation".into(), euclid.rotation.into()),
+                ("shift".into(), euclid.shift.into()),
+                ("step".into(), step(hit)),
+            ]),
+            Edit::Record { pattern, track, .. } => Value::Object(vec![
+                ("type".into(), "record".into()),
+                ("pattern".into(), (pattern.0 as usize).into()),
+                ("track".into(), (track.0 as usize).into()),
+            ]),
+        }
+    }
+}
+
+impl fmt::Display for Edit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Edit::SetStep {
+                pattern,
+                track,
+                line,
+                ..
+            } => write!(
+                f,
+                "set step pattern {} track {} line {}",
+                pattern.0, track.0, line
+            ),
+            Edit::MoveTrack { from, to } => write!(f, "move track {} to {}", from, to),
+            Edit::SetParam { key, before, after } => {
+                write!(f, "set param {} from {} to {}", key, before, after)
+            }
+            Edit::Rearrange { pattern, op, .. } => write!(f, "pattern {} {}", pattern.0, op),
+            Edit::Resize {
+                pattern,
+                num_lines,
+                policy,
+                ..
+            } => write!(
+                f,
+                "resize pattern {} to {} lines ({})",
+                pattern.0,
+                num_lines,
+                policy.name()
+            ),
+            Edit::TrackLength {
+                pattern,
+                track,
+                after,
+                ..
+            } => match after {
+                Some(length) => write!(
+                    f,
+                    "set length of pattern {} track {} to {} lines",
+                    pattern.0, track.0, length
+                ),
+                None => write!(
+                    f,
+                    "make pattern {} track {} follow the pattern length",
+                    pattern.0, track.0
+                ),
+            },
+            Edit::Transform {
+                pattern,
+                track,
+                section,
+                transform,
+                ..
+            } => write!(
+                f,
+                "{} pattern {} track {} lines {}",
+                transform, pattern.0, track.0, section
+            ),
+            Edit::Euclid {
+                pattern,
+                track,
+                euclid,
+                ..
+            } => write!(
+                f,
+                "fill pattern {} track {} with {}",
+                pattern.0, track.0, euclid
+            ),
+            Edit::Record { pattern, track, .. } => {
+                write!(f, "record into pattern {} track {}", pattern.0, track.0)
+            }
+        }
+    }
+}
+
+/// Something that happened during the session.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    Edit(Edit),
+    Undo(Edit),
+    Redo(Edit),
+    /// Changes which can't be undone, e.g. loading an instrument.
+    Note(String),
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Edit(edit) => write!(f, "{}", edit),
+            Event::Undo(edit) => write!(f, "undo {}", edit),
+            Event::Redo(edit) => write!(f, "redo {}", edit),
+            Event::Note(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+pub struct LogEntry {
+    /// Time since the start of the session.
+    pub time: Duration,
+    pub event: Event,
+}
+
+/// Undo and redo stacks, plus a log of everything that happened during the session. The log is
+/// kept when the history is cleared and isn't affected by merging param changes.
+pub struct History {
+    undo: Vec<Edit>,
+    redo: Vec<Edit>,
+    log: VecDeque<LogEntry>,
+    started: Instant,
+    started_at: SystemTime,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            undo: Vec::new(),
+            redo: Vec::new(),
+            log: VecDeque::new(),
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+        }
+    }
+}
+
+impl History {
+    pub fn push(&mut self, edit: Edit) {
+        self.redo.clear();
+        self.record(Event::Edit(edit.clone()));
+
+        // Merge repeated changes to the same param into one edit
+        if let (
+            Some(Edit::SetParam { key, after, .. }),
+            Edit::SetParam {
+                key: new_key,
+                after: new_after,
+                ..
+            },
+        ) = (self.undo.last_mut(), &edit)
+        {
+            if key == new_key {
+                *after = *new_after;
+                return;
+            }
+        }
+
+        if self.undo.len() == MAX_HISTORY {
+            self.undo.remove(0);
+        }
+        self.undo.push(edit);
+    }
+
+    /// Returns the edit to revert.
+    pub fn undo(&mut self) -> Option<Edit> {
+        let edit = self.undo.pop()?;
+        self.redo.push(edit.clone());
+        self.record(Event::Undo(edit.clone()));
+        Some(edit)
+    }
+
+    /// Returns the edit to apply again.
+    pub fn redo(&mut self) -> Option<Edit> {
+        let edit = self.redo.pop()?;
+        self.undo.push(edit.clone());
+        self.record(Event::Redo(edit.clone()));
+        Some(edit)
+    }
+
+    /// Adds a change which can't be undone to the session log.
+    pub fn note<S: Into<String>>(&mut self, message: S) {
+        self.record(Event::Note(message.into()));
+    }
+
+    fn record(&mut self, event: Event) {
+        if self.log.len() == MAX_LOG {
+            self.log.pop_front();
+        }
+        self.log.push_back(LogEntry {
+            time: self.started.elapsed(),
+            event,
+        });
+    }
+
+    /// The last `count` entries of the session log as text, oldest first.
+    pub fn recent(&self, count: usize) -> Vec<String> {
+        let skip = self.log.len().saturating_sub(count);
+        self.log
+            .iter()
+            .skip(skip)
+            .map(|entry| format!("{:>10.3}s {}", entry.time.as_secs_f64(), entry.event))
+            .collect()
+    }
+
+    /// Writes the session log as JSON.
+    pub fn export_log(&self, path: &Utf8Path) -> Result<()> {
+        let started = self
+            .started_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let entries = self
+            .log
+            .iter()
+            .map(|entry| {
+                let mut fields = vec![("time".into(), entry.time.as_secs_f64().into())];
+                let (kind, detail) = match &entry.event {
+                    Event::Edit(edit) => ("edit", ("edit", edit.to_json())),
+                    Event::Undo(edit) => ("undo", ("edit", edit.to_json())),
+                    Event::Redo(edit) => ("redo", ("edit", edit.to_json())),
+                    Event::Note(message) => ("note", ("message", message.as_str().into())),
+                };
+                fields.push(("event".into(), kind.into()));
+                fields.push((detail.0.into(), detail.1));
+                Value::Object(fields)
+            })
+            .collect();
+        let json = Value::Object(vec![
+            ("started".into(), started.into()),
+            ("entries".into(), Value::Array(entries)),
+        ]);
+        fs::write(path, json.to_pretty_string()?)?;
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+}
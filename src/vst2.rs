@@ -0,0 +1,336 @@
+//! VST2 plugins as effects on mixer channels: `:fx add vst2 path=<plugin.so>`, the path being
+//! the plugin library itself (on macOS the binary inside the `.vst` bundle). Plugins are loaded
+//! at runtime, so nothing of VST is needed to build.
+//!
+//! Every param of a plugin shows up as a regular param from 0 to 1, which is how VST2 plugins
+//! take them, and changes are sent to the plugin with the next buffer. Mono plugins are fed the
+//! sum of both channels and play on both. The latency a plugin reports is shown on its mixer
+//! channel. A plugin is resumed at the new sample rate when the engine's changes.
+
+use crate::effect::{Effect, EffectFactory};
+use crate::engine::EngineConfig;
+use crate::instrument::Options;
+use crate::param::Param;
+use crate::MAX_FRAMES_PER_BUFFER;
+use anyhow::{anyhow, Result};
+use atomic_float::AtomicF32;
+use camino::Utf8Path;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// `kEffectMagic`, "VstP".
+const MAGIC: i32 = 0x5673_7450;
+/// Version of the VST2 API the host speaks, 2.4.
+const HOST_VERSION: isize = 2400;
+const FLAG_CAN_REPLACING: i32 = 1 << 4;
+
+const EFF_OPEN: i32 = 0;
+const EFF_CLOSE: i32 = 1;
+const EFF_GET_PARAM_NAME: i32 = 8;
+const EFF_SET_SAMPLE_RATE: i32 = 10;
+const EFF_SET_BLOCK_SIZE: i32 = 11;
+const EFF_MAINS_CHANGED: i32 = 12;
+const EFF_START_PROCESS: i32 = 71;
+const EFF_STOP_PROCESS: i32 = 72;
+
+const AUDIO_MASTER_VERSION: i32 = 1;
+const AUDIO_MASTER_GET_VENDOR_STRING: i32 = 32;
+const AUDIO_MASTER_GET_PRODUCT_STRING: i32 = 33;
+
+/// Room for the strings plugins write. The API limits param names to 8 characters, which
+/// plugins routinely ignore.
+const STRING_SIZE: usize = 256;
+/// Steps params are changed by in the params pane.
+const PARAM_STEP: f32 = 0.01;
+
+type Dispatcher = unsafe extern "C" fn(*mut AEffect, i32, i32, isize, *mut c_void, f32) -> isize;
+type HostCallback = unsafe extern "C" fn(*mut AEffect, i32, i32, isize, *mut c_void, f32) -> isize;
+type ProcessReplacing = unsafe extern "C" fn(*mut AEffect, *const *const f32, *mut *mut f32, i32);
+type Main = unsafe extern "C" fn(HostCallback) -> *mut AEffect;
+
+/// `AEffect`, what a plugin library's entry point returns.
+#[repr(C)]
+struct AEffect {
+    magic: i32,
+    dispatcher: Dispatcher,
+    process: *const c_void,
+    set_parameter: unsafe extern "C" fn(*mut AEffect, i32, f32),
+    get_parameter: unsafe extern "C" fn(*mut AEffect, i32) -> f32,
+    num_programs: i32,
+    num_params: i32,
+    num_inputs: i32,
+    num_outputs: i32,
+    flags: i32,
+    resvd1: isize,
+    resvd2: isize,
+    initial_delay: i32,
+    real_qualities: i32,
+    off_qualities: i32,
+    io_ratio: f32,
+    object: *mut c_void,
+    user: *mut c_void,
+    unique_id: i32,
+    version: i32,
+    process_replacing: Option<ProcessReplacing>,
+    process_double_replacing: *const c_void,
+    future: [c_char; 56],
+}
+
+/// Answers what plugins ask the host. Nothing but the version and names is offered.
+unsafe extern "C" fn host_callback(
+    _effect: *mut AEffect,
+    opcode: i32,
+    _index: i32,
+    _value: isize,
+    ptr: *mut c_void,
+    _opt: f32,
+) -> isize {
+    match opcode {
+        AUDIO_MASTER_VERSION => HOST_VERSION,
+        AUDIO_MASTER_GET_VENDOR_STRING | AUDIO_MASTER_GET_PRODUCT_STRING if !ptr.is_null() => {
+            let name = b"ruis\0";
+            ptr::copy_nonoverlapping(name.as_ptr(), ptr as *mut u8, name.len());
+            1
+        }
+        _ => 0,
+    }
+}
+
+/// A param of a plugin, set by the app and sent to the plugin when it changes.
+struct HostedParam {
+    value: Arc<AtomicF32>,
+    /// Value the plugin has.
+    sent: f32,
+}
+
+pub struct Vst2Effect {
+    effect: *mut AEffect,
+    library: *mut c_void,
+    process_replacing: ProcessReplacing,
+    names: Vec<String>,
+    params: Vec<HostedParam>,
+    sample_rate: f64,
+    inputs: Vec<Vec<f32>>,
+    outputs: Vec<Vec<f32>>,
+    input_ptrs: Vec<*const f32>,
+    output_ptrs: Vec<*mut f32>,
+}
+
+// The plugin is created and dropped on the app thread and processes on the audio thread, the
+// way VST2 hosts use plugins. Nothing else touches it.
+unsafe impl Send for Vst2Effect {}
+
+impl Vst2Effect {
+    fn open(path: &Utf8Path, sample_rate: f64) -> Result<Self> {
+        let c_path = CString::new(path.as_str())?;
+        let library = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if library.is_null() {
+            return Err(anyhow!("unable to load VST2 plugin {}", path));
+        }
+        let main = [&b"VSTPluginMain\0"[..], &b"main\0"[..]]
+            .iter()
+            .map(|name| unsafe { libc::dlsym(library, name.as_ptr() as *const c_char) })
+            .find(|main| !main.is_null());
+        let effect = match main {
+            Some(main) => unsafe { std::mem::transmute::<*mut c_void, Main>(main)(host_callback) },
+            None => ptr::null_mut(),
+        };
+        let process_replacing = unsafe { effect.as_ref() }
+            .filter(|effect| effect.magic == MAGIC && effect.flags & FLAG_CAN_REPLACING != 0)
+            .and_then(|effect| effect.process_replacing);
+        let process_replacing = match process_replacing {
+            Some(process_replacing) => process_replacing,
+            None => {
+                unsafe { libc::dlclose(library) };
+                return Err(anyhow!("{} is not a VST2 plugin this host can use", path));
+            }
+        };
+
+        let mut plugin = unsafe {
+            let dispatch = (*effect).dispatcher;
+            dispatch(effect, EFF_OPEN, 0, 0, ptr::null_mut(), 0.0);
+            let (num_inputs, num_outputs) = ((*effect).num_inputs, (*effect).num_outputs);
+            let buffers =
+                |count: i32| vec![vec![0.0; MAX_FRAMES_PER_BUFFER]; count.max(0) as usize];
+            Self {
+                effect,
+                library,
+                process_replacing,
+                names: Vec::new(),
+                params: Vec::new(),
+                sample_rate,
+                inputs: buffers(num_inputs),
+                outputs: buffers(num_outputs),
+                input_ptrs: Vec::with_capacity(num_inputs.max(0) as usize),
+                output_ptrs: Vec::with_capacity(num_outputs.max(0) as usize),
+            }
+        };
+        plugin.read_params();
+        plugin.resume();
+        Ok(plugin)
+    }
+
+    unsafe fn dispatch(&self, opcode: i32, index: i32, value: isize, ptr: *mut c_void) -> isize {
+        ((*self.effect).dispatcher)(self.effect, opcode, index, value, ptr, 0.0)
+    }
+
+    fn read_params(&mut self) {
+        let count = unsafe { (*self.effect).num_params }.max(0);
+        for i in 0..count {
+            let mut name = [0 as c_char; STRING_SIZE];
+            let value = unsafe {
+                self.dispatch(EFF_GET_PARAM_NAME, i, 0, name.as_mut_ptr() as *mut c_void);
+                ((*self.effect).get_parameter)(self.effect, i)
+            };
+            name[STRING_SIZE - 1] = 0;
+            let name = unsafe { CStr::from_ptr(name.as_ptr()) }.to_string_lossy();
+            self.names.push(match name.trim() {
+                "" => format!("Param {}", i + 1),
+                name => name.to_string(),
+            });
+            self.params.push(HostedParam {
+                value: Arc::new(AtomicF32::new(value)),
+                sent: value,
+            });
+        }
+    }
+
+    /// Switches the plugin on at the current sample rate, ready to process.
+    fn resume(&mut self) {
+        unsafe {
+            ((*self.effect).dispatcher)(
+                self.effect,
+                EFF_SET_SAMPLE_RATE,
+                0,
+                0,
+                ptr::null_mut(),
+                self.sample_rate as f32,
+            );
+            self.dispatch(
+                EFF_SET_BLOCK_SIZE,
+                0,
+                MAX_FRAMES_PER_BUFFER as isize,
+                ptr::null_mut(),
+            );
+            self.dispatch(EFF_MAINS_CHANGED, 0, 1, ptr::null_mut());
+            self.dispatch(EFF_START_PROCESS, 0, 0, ptr::null_mut());
+        }
+    }
+
+    fn suspend(&mut self) {
+        unsafe {
+            self.dispatch(EFF_STOP_PROCESS, 0, 0, ptr::null_mut());
+            self.dispatch(EFF_MAINS_CHANGED, 0, 0, ptr::null_mut());
+        }
+    }
+
+    /// Runs the plugin over at most `MAX_FRAMES_PER_BUFFER` frames in place.
+    fn process_block(&mut self, block: &mut [(f32, f32)]) {
+        let len = block.len();
+        let num_inputs = self.inputs.len();
+        for (i, input) in self.inputs.iter_mut().enumerate() {
+            for (sample, frame) in input.iter_mut().zip(block.iter()) {
+                *sample = match (i, num_inputs) {
+                    (0, 1) => (frame.0 + frame.1) * 0.5,
+                    (0, _) => frame.0,
+                    (1, _) => frame.1,
+                    _ => 0.0,
+                };
+            }
+        }
+        self.input_ptrs.clear();
+        self.input_ptrs
+            .extend(self.inputs.iter().map(|input| input.as_ptr()));
+        self.output_ptrs.clear();
+        self.output_ptrs
+            .extend(self.outputs.iter_mut().map(|output| output.as_mut_ptr()));
+        unsafe {
+            (self.process_replacing)(
+                self.effect,
+                self.input_ptrs.as_ptr(),
+                self.output_ptrs.as_mut_ptr(),
+                len as i32,
+            )
+        };
+        match self.outputs.as_slice() {
+            [] => {}
+            [mono] => {
+                for (frame, sample) in block.iter_mut().zip(mono) {
+                    *frame = (*sample, *sample);
+                }
+            }
+            [left, right, ..] => {
+                for ((frame, l), r) in block.iter_mut().zip(left).zip(right) {
+                    *frame = (*l, *r);
+                }
+            }
+        }
+    }
+}
+
+impl Effect for Vst2Effect {
+    fn process(&mut self, buffer: &mut [(f32, f32)]) {
+        for (i, param) in self.params.iter_mut().enumerate() {
+            let value = param.value.load(Ordering::Relaxed);
+            if value != param.sent {
+                param.sent = value;
+                unsafe { ((*self.effect).set_parameter)(self.effect, i as i32, value) };
+            }
+        }
+        for block in buffer.chunks_mut(MAX_FRAMES_PER_BUFFER) {
+            self.process_block(block);
+        }
+    }
+
+    fn prepare(&mut self, config: &EngineConfig) {
+        if config.sample_rate != self.sample_rate {
+            self.suspend();
+            self.sample_rate = config.sample_rate;
+            self.resume();
+        }
+    }
+
+    fn latency(&self) -> usize {
+        unsafe { (*self.effect).initial_delay }.max(0) as usize
+    }
+
+    fn params(&self) -> Vec<(String, Param)> {
+        self.names
+            .iter()
+            .zip(&self.params)
+            .map(|(name, hosted)| {
+                let param = Param::new(0.0, Arc::clone(&hosted.value), 1.0, PARAM_STEP);
+                (name.clone(), param)
+            })
+            .collect()
+    }
+}
+
+impl Drop for Vst2Effect {
+    fn drop(&mut self) {
+        self.suspend();
+        unsafe {
+            self.dispatch(EFF_CLOSE, 0, 0, ptr::null_mut());
+            libc::dlclose(self.library);
+        }
+    }
+}
+
+pub struct Vst2EffectFactory {
+    pub sample_rate: Arc<AtomicU32>,
+}
+
+impl EffectFactory for Vst2EffectFactory {
+    fn name(&self) -> &'static str {
+        "vst2"
+    }
+
+    fn create(&self, options: &Options) -> Result<Box<dyn Effect>> {
+        let path = Utf8Path::new(options.get("path")?);
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed) as f64;
+        Ok(Box::new(Vst2Effect::open(path, sample_rate)?))
+    }
+}
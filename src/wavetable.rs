@@ -0,0 +1,125 @@
+//! Wavetables in the format of Serum and most other wavetable synths: a WAV file holding
+//! single cycles of 2048 samples one after the other. Every cycle is kept at several
+//! bandwidths so high notes play a version without the harmonics that would alias.
+
+use crate::sampler::Sampler;
+use anyhow::{anyhow, Result};
+use camino::Utf8PathBuf;
+use std::f32::consts::PI;
+
+/// Samples in one cycle of the table.
+pub const FRAME_SIZE: usize = 2048;
+/// Bandwidths kept of every cycle, each with half the harmonics of the previous one, down to
+/// the fundamental alone.
+const NUM_LEVELS: usize = 11;
+
+pub struct Wavetable {
+    num_frames: usize,
+    /// The frames one after the other, for each level.
+    levels: Vec<Vec<f32>>,
+}
+
+impl Wavetable {
+    /// Loads a wavetable file, only the left channel of stereo files is used.
+    pub fn load(path: &Utf8PathBuf) -> Result<Self> {
+        let sound = Sampler::load_sound(path)?;
+        let samples: Vec<f32> = sound.frames().map(|(left, _)| left).collect();
+        if samples.is_empty() || !samples.len().is_multiple_of(FRAME_SIZE) {
+            return Err(anyhow!(
+                "{} isn't a wavetable, its length must be a multiple of {} samples",
+                path,
+                FRAME_SIZE
+            ));
+        }
+        Ok(Self::from_samples(&samples))
+    }
+
+    /// Builds the levels of frames of `FRAME_SIZE` samples.
+    fn from_samples(samples: &[f32]) -> Self {
+        let num_frames = samples.len() / FRAME_SIZE;
+        let mut levels: Vec<Vec<f32>> = (0..NUM_LEVELS)
+            .map(|_| Vec::with_capacity(samples.len()))
+            .collect();
+        let mut spectrum = (vec![0.0; FRAME_SIZE], vec![0.0; FRAME_SIZE]);
+        let mut cycle = (vec![0.0; FRAME_SIZE], vec![0.0; FRAME_SIZE]);
+        for frame in samples.chunks_exact(FRAME_SIZE) {
+            spectrum.0.copy_from_slice(frame);
+            spectrum.1.fill(0.0);
+            fft(&mut spectrum.0, &mut spectrum.1, false);
+            for (level, out) in levels.iter_mut().enumerate() {
+                // Keeps the harmonics up to the limit of the level and their mirror images,
+                // dropping the offset.
+                let harmonics = (FRAME_SIZE / 2) >> level;
+                for bin in 0..FRAME_SIZE {
+                    let harmonic = usize::min(bin, FRAME_SIZE - bin);
+                    let keep = harmonic > 0 && harmonic <= harmonics;
+                    cycle.0[bin] = if keep { spectrum.0[bin] } else { 0.0 };
+                    cycle.1[bin] = if keep { spectrum.1[bin] } else { 0.0 };
+                }
+                fft(&mut cycle.0, &mut cycle.1, true);
+                out.extend(cycle.0.iter().map(|sample| sample / FRAME_SIZE as f32));
+            }
+        }
+        Self { num_frames, levels }
+    }
+
+    /// The sample at `phase`, from 0 to 1 over a cycle, morphing between the frames with
+    /// `position` from 0 to 1. `increment` is the phase moved every sample, which picks the
+    /// level with all the harmonics below Nyquist.
+    pub fn sample(&self, phase: f32, position: f32, increment: f32) -> f32 {
+        let harmonics = 0.5 / increment.max(f32::EPSILON);
+        let level = ((FRAME_SIZE / 2) as f32 / harmonics).log2().ceil();
+        let level = &self.levels[level.clamp(0.0, (NUM_LEVELS - 1) as f32) as usize];
+
+        let position = position.clamp(0.0, 1.0) * (self.num_frames - 1) as f32;
+        let frame = position as usize;
+        let next_frame = usize::min(frame + 1, self.num_frames - 1);
+        let morph = position - frame as f32;
+
+        let index = phase * FRAME_SIZE as f32;
+        let i = index as usize % FRAME_SIZE;
+        let j = (i + 1) % FRAME_SIZE;
+        let weight = index - index.floor();
+        let read = |frame: usize| {
+            let cycle = &level[frame * FRAME_SIZE..(frame + 1) * FRAME_SIZE];
+            cycle[i] + (cycle[j] - cycle[i]) * weight
+        };
+        read(frame) + (read(next_frame) - read(frame)) * morph
+    }
+}
+
+/// In-place radix-2 FFT of a signal whose length is a power of two, unscaled both ways.
+fn fft(re: &mut [f32], im: &mut [f32], inverse: bool) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * 2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * cos - im[b] * sin;
+                let t_im = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
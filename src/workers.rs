@@ -0,0 +1,169 @@
+//! Threads which render instruments along with the audio thread, for songs with more
+//! instruments than one core keeps up with. The engine hands out one job per instrument for
+//! each block and mixes the results itself in the usual order, so the output is the same
+//! whichever thread rendered what.
+//!
+//! Nothing allocates or locks once the threads run. Jobs are claimed from an atomic counter,
+//! the audio thread takes its share too and spins until the last one is done. Idle workers
+//! park, waking them is a syscall but doesn't block the audio thread.
+
+use crate::audio::realtime;
+use crate::crash;
+use crate::engine::MAX_INSTRUMENTS;
+use crate::instrument::Instrument;
+use std::cell::UnsafeCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// Bits of the index of the next job in `Shared::claims`, the number of jobs is above them.
+const INDEX_BITS: usize = 16;
+const INDEX_MASK: usize = (1 << INDEX_BITS) - 1;
+
+/// Times a worker checks for a new block before parking, so consecutive blocks of a buffer
+/// don't wait for it to wake up.
+const SPINS: usize = 2_000;
+
+#[derive(Copy, Clone)]
+struct Job {
+    instrument: *mut dyn Instrument,
+    buffer: *mut [(f32, f32)],
+}
+
+struct Shared {
+    /// Written by the audio thread while no job is claimable.
+    jobs: UnsafeCell<[Option<Job>; MAX_INSTRUMENTS]>,
+    /// Number of jobs of the block and index of the next one to claim, in one word so a
+    /// thread late for a block can't mix up the index of one block with the jobs of the next.
+    /// Every job was claimed by the time the next block starts.
+    claims: AtomicUsize,
+    done: AtomicUsize,
+    /// Bumped for every block, so workers know there's work.
+    block: AtomicUsize,
+    stop: AtomicBool,
+}
+
+// Each job is claimed by exactly one thread, which is the only one to touch its instrument and
+// buffer until it counts the job as done. `render` doesn't return before then.
+unsafe impl Send for Shared {}
+unsafe impl Sync for Shared {}
+
+impl Shared {
+    /// Renders jobs until there are none left to claim.
+    fn run_jobs(&self) {
+        loop {
+            let claim = self.claims.fetch_add(1, Ordering::AcqRel);
+            let index = claim & INDEX_MASK;
+            if index >= claim >> INDEX_BITS {
+                return;
+            }
+            let job = unsafe { (*self.jobs.get())[index] };
+            if let Some(job) = job {
+                // A panicking instrument must still count as done, the audio thread waits for
+                // it. It's silent from then on.
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| unsafe {
+                    (*job.instrument).render(&mut *job.buffer);
+                }));
+            }
+            self.done.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+}
+
+pub struct Workers {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Workers {
+    /// Starts `num_threads` workers. They get a realtime priority if the system allows it.
+    pub fn new(num_threads: usize) -> Self {
+        let shared = Arc::new(Shared {
+            jobs: UnsafeCell::new([None; MAX_INSTRUMENTS]),
+            claims: AtomicUsize::new(0),
+            done: AtomicUsize::new(0),
+            block: AtomicUsize::new(0),
+            stop: AtomicBool::new(false),
+        });
+        let threads = (0..num_threads)
+            .map(|i| {
+                let shared = Arc::clone(&shared);
+                thread::Builder::new()
+                    .name(format!("render-{}", i))
+                    .spawn(move || work(&shared))
+                    .expect("unable to start a render thread")
+            })
+            .collect();
+        Self { shared, threads }
+    }
+
+    /// Renders every instrument into its buffer, spread over the workers and the calling
+    /// thread. Returns once they're all rendered.
+    pub fn render<'a, I>(&mut self, jobs: I)
+    where
+        I: Iterator<Item = (&'a mut Box<dyn Instrument>, &'a mut [(f32, f32)])>,
+    {
+        let shared = &*self.shared;
+        let mut num_jobs = 0;
+        {
+            // No job is claimable, the last block is done and every job of it was claimed.
+            let slots = unsafe { &mut *shared.jobs.get() };
+            for (slot, (instrument, buffer)) in slots.iter_mut().zip(jobs) {
+                *slot = Some(Job {
+                    instrument: instrument.as_mut(),
+                    buffer,
+                });
+                num_jobs += 1;
+            }
+        }
+        shared.done.store(0, Ordering::Relaxed);
+        shared
+            .claims
+            .store(num_jobs << INDEX_BITS, Ordering::Release);
+        shared.block.fetch_add(1, Ordering::Release);
+        for thread in &self.threads {
+            thread.thread().unpark();
+        }
+        shared.run_jobs();
+        while shared.done.load(Ordering::Acquire) < num_jobs {
+            std::hint::spin_loop();
+        }
+        // The borrows end here, leave nothing dangling.
+        let slots = unsafe { &mut *shared.jobs.get() };
+        slots.iter_mut().for_each(|slot| *slot = None);
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Release);
+        for thread in self.threads.drain(..) {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+fn work(shared: &Shared) {
+    let _ = realtime::promote();
+    crash::mark_audio_thread();
+    let mut seen = shared.block.load(Ordering::Acquire);
+    loop {
+        let mut spins = 0;
+        while shared.block.load(Ordering::Acquire) == seen {
+            if shared.stop.load(Ordering::Acquire) {
+                return;
+            }
+            if spins < SPINS {
+                spins += 1;
+                std::hint::spin_loop();
+            } else {
+                thread::park();
+            }
+        }
+        seen = shared.block.load(Ordering::Acquire);
+        let _rendering = realtime::rendering();
+        shared.run_jobs();
+    }
+}